@@ -5,6 +5,9 @@ use ethers::signers::{
     coins_bip39::English, LocalWallet, MnemonicBuilder, Signer, Wallet, WalletError,
 };
 use ethers_core::k256::ecdsa::SigningKey;
+use ethers_core::types::Signature;
+use ethers_core::utils::hash_message;
+use thegraph::types::Address;
 
 /// Build Wallet from Private key or Mnemonic
 pub fn build_wallet(value: &str) -> Result<Wallet<SigningKey>, WalletError> {
@@ -19,3 +22,39 @@ pub fn public_key(value: &str) -> Result<String, WalletError> {
     let addr = format!("{:?}", wallet.address());
     Ok(addr)
 }
+
+pub fn wallet_address(value: &str) -> Result<Address, WalletError> {
+    Ok(Address::from(build_wallet(value)?.address().0))
+}
+
+/// EIP-191 signature of `message` by the wallet of a private key or mnemonic.
+pub fn sign_message(value: &str, message: &[u8]) -> Result<Vec<u8>, WalletError> {
+    Ok(build_wallet(value)?
+        .sign_hash(hash_message(message))?
+        .to_vec())
+}
+
+/// Signer of the EIP-191 signature of `message`.
+pub fn recover_message_signer(message: &[u8], signature: &[u8]) -> anyhow::Result<Address> {
+    let signer = Signature::try_from(signature)?.recover(message)?;
+    Ok(Address::from(signer.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_vectors::INDEXER_OPERATOR_MNEMONIC;
+
+    #[test]
+    fn test_sign_and_recover_message() {
+        let signature = sign_message(&INDEXER_OPERATOR_MNEMONIC, b"hello").unwrap();
+        assert_eq!(
+            recover_message_signer(b"hello", &signature).unwrap(),
+            wallet_address(&INDEXER_OPERATOR_MNEMONIC).unwrap()
+        );
+        assert_ne!(
+            recover_message_signer(b"goodbye", &signature).unwrap(),
+            wallet_address(&INDEXER_OPERATOR_MNEMONIC).unwrap()
+        );
+    }
+}
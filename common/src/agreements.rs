@@ -0,0 +1,208 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Indexing agreements let a sender pay an indexer directly for indexing a deployment, at a
+//! price per entity and per block processed. The indexer-service registers the signed
+//! agreements and accrues fees from the progress reported by the indexer's infrastructure, and
+//! the tap-agent periodically turns the accrued fees into RAVs.
+
+use std::str::FromStr;
+
+use alloy_primitives::{hex::ToHex, Address, FixedBytes};
+use alloy_sol_types::sol;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sqlx::{postgres::PgRow, types::BigDecimal, PgPool, Row};
+use tap_core::signed_message::EIP712SignedMessage;
+
+use crate::types::{AllocationIdHex, GrtWei};
+
+sol! {
+    #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+    struct IndexingAgreement {
+        bytes32 agreementId;
+        address allocationId;
+        bytes32 deploymentId;
+        uint128 pricePerEntity;
+        uint128 pricePerBlock;
+    }
+}
+
+pub type SignedIndexingAgreement = EIP712SignedMessage<IndexingAgreement>;
+
+/// An active agreement, as stored in the database.
+#[derive(Debug, PartialEq, Eq)]
+pub struct StoredAgreement {
+    pub agreement: SignedIndexingAgreement,
+    pub signer: Address,
+    pub accrued_fees: u128,
+}
+
+/// Stores an agreement signed by `signer`. Registering the same agreement twice is a no-op.
+pub async fn store(
+    pgpool: &PgPool,
+    signer: Address,
+    agreement: &SignedIndexingAgreement,
+) -> Result<()> {
+    sqlx::query(
+        r#"
+            INSERT INTO indexing_agreements (
+                agreement_id,
+                signer_address,
+                signature,
+                allocation_id,
+                deployment_id,
+                price_per_entity,
+                price_per_block
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            ON CONFLICT (agreement_id) DO NOTHING
+        "#,
+    )
+    .bind(agreement.message.agreementId.encode_hex::<String>())
+    .bind(signer.encode_hex::<String>())
+    .bind(agreement.signature.to_vec())
+    .bind(AllocationIdHex(agreement.message.allocationId))
+    .bind(agreement.message.deploymentId.encode_hex::<String>())
+    .bind(GrtWei(agreement.message.pricePerEntity))
+    .bind(GrtWei(agreement.message.pricePerBlock))
+    .execute(pgpool)
+    .await?;
+
+    Ok(())
+}
+
+/// Accrues the fees for entities and blocks processed under an active agreement. Returns the
+/// total fees accrued by the agreement, or `None` if there is no such active agreement.
+pub async fn record_progress(
+    pgpool: &PgPool,
+    agreement_id: FixedBytes<32>,
+    entities: u64,
+    blocks: u64,
+) -> Result<Option<u128>> {
+    let accrued_fees: Option<GrtWei> = sqlx::query_scalar(
+        r#"
+            UPDATE indexing_agreements
+            SET
+                entities_processed = entities_processed + $2,
+                blocks_processed = blocks_processed + $3,
+                accrued_fees = accrued_fees + $2 * price_per_entity + $3 * price_per_block,
+                updated_at = NOW()
+            WHERE agreement_id = $1 AND cancelled_at IS NULL
+            RETURNING accrued_fees
+        "#,
+    )
+    .bind(agreement_id.encode_hex::<String>())
+    .bind(BigDecimal::from(entities))
+    .bind(BigDecimal::from(blocks))
+    .fetch_optional(pgpool)
+    .await?;
+
+    Ok(accrued_fees.map(u128::from))
+}
+
+pub async fn active_agreements(pgpool: &PgPool) -> Result<Vec<StoredAgreement>> {
+    let rows = sqlx::query(
+        r#"
+            SELECT
+                agreement_id,
+                signer_address,
+                signature,
+                allocation_id,
+                deployment_id,
+                price_per_entity,
+                price_per_block,
+                accrued_fees
+            FROM indexing_agreements
+            WHERE cancelled_at IS NULL
+        "#,
+    )
+    .fetch_all(pgpool)
+    .await?;
+
+    rows.iter().map(stored_agreement_from_row).collect()
+}
+
+fn stored_agreement_from_row(row: &PgRow) -> Result<StoredAgreement> {
+    let u128_column = |column: &str| -> Result<u128> { Ok(row.try_get::<GrtWei, _>(column)?.0) };
+
+    let message = IndexingAgreement {
+        agreementId: FixedBytes::from_str(row.try_get("agreement_id")?)?,
+        allocationId: row.try_get::<AllocationIdHex, _>("allocation_id")?.0,
+        deploymentId: FixedBytes::from_str(row.try_get("deployment_id")?)?,
+        pricePerEntity: u128_column("price_per_entity")?,
+        pricePerBlock: u128_column("price_per_block")?,
+    };
+    Ok(StoredAgreement {
+        agreement: SignedIndexingAgreement {
+            message,
+            signature: row
+                .try_get::<Vec<u8>, _>("signature")?
+                .as_slice()
+                .try_into()?,
+        },
+        signer: Address::from_str(row.try_get("signer_address")?)?,
+        accrued_fees: u128_column("accrued_fees")?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy_primitives::FixedBytes;
+    use sqlx::PgPool;
+    use tap_core::signed_message::EIP712SignedMessage;
+
+    use crate::test_vectors::{TAP_EIP712_DOMAIN, TAP_SIGNER};
+
+    use super::*;
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_accrue_agreement_fees(pgpool: PgPool) {
+        let agreement = EIP712SignedMessage::new(
+            &TAP_EIP712_DOMAIN,
+            IndexingAgreement {
+                agreementId: FixedBytes::from([0x01u8; 32]),
+                allocationId: Address::from([0x02u8; 20]),
+                deploymentId: FixedBytes::from([0x03u8; 32]),
+                pricePerEntity: 2,
+                pricePerBlock: 10,
+            },
+            &TAP_SIGNER.0,
+        )
+        .unwrap();
+        let signer = agreement.recover_signer(&TAP_EIP712_DOMAIN).unwrap();
+        assert_eq!(signer, TAP_SIGNER.1);
+
+        store(&pgpool, signer, &agreement).await.unwrap();
+        // Registering the agreement again is a no-op.
+        store(&pgpool, signer, &agreement).await.unwrap();
+
+        assert_eq!(
+            record_progress(&pgpool, agreement.message.agreementId, 100, 5)
+                .await
+                .unwrap(),
+            Some(250)
+        );
+        assert_eq!(
+            record_progress(&pgpool, agreement.message.agreementId, 1, 1)
+                .await
+                .unwrap(),
+            Some(262)
+        );
+        assert_eq!(
+            record_progress(&pgpool, FixedBytes::from([0xffu8; 32]), 1, 1)
+                .await
+                .unwrap(),
+            None
+        );
+
+        assert_eq!(
+            active_agreements(&pgpool).await.unwrap(),
+            vec![StoredAgreement {
+                agreement,
+                signer,
+                accrued_fees: 262,
+            }]
+        );
+    }
+}
@@ -0,0 +1,197 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Grace period of the allocations after they are closed, during which their receipts are still
+//! accepted while the closure propagates to the gateways. It can be set per network indexed by
+//! the deployment, and per sender, on top of the default one. An allocation replaced by a new
+//! one on the same deployment can instead be accepted for an overlap after its successor was
+//! created, for both to be accepted while the gateways switch over.
+
+use std::{collections::HashMap, time::Duration};
+
+use thegraph::types::Address;
+
+use super::{rollover, Allocation};
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GracePeriods {
+    pub default: Duration,
+    /// By network indexed by the deployment of the allocation, e.g. `mainnet`.
+    pub per_network: HashMap<String, Duration>,
+    /// By sender of the receipt, taking precedence over the network.
+    pub per_sender: HashMap<Address, Duration>,
+    /// Replaced allocations are accepted for this long after their successor was created,
+    /// instead of for their grace period. The grace period applies if not set.
+    pub rollover_overlap: Option<Duration>,
+}
+
+/// Rejection of a receipt for an allocation closed for longer than its grace period. Kept
+/// distinct from other eligibility failures, so that gateways can tell they need to select
+/// another allocation.
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+#[error(
+    "Allocation `{allocation_id}` was closed {closed_for_secs}s ago, past its grace period of \
+     {grace_period_secs}s"
+)]
+pub struct AllocationClosed {
+    pub allocation_id: Address,
+    pub closed_for_secs: u64,
+    pub grace_period_secs: u64,
+}
+
+impl GracePeriods {
+    /// Longest grace period, for which the closed allocations must be fetched.
+    pub fn max(&self) -> Duration {
+        self.per_network
+            .values()
+            .chain(self.per_sender.values())
+            .chain(self.rollover_overlap.iter())
+            .copied()
+            .fold(self.default, Duration::max)
+    }
+
+    /// When `allocation` was replaced, if it's accepted for the rollover overlap instead of its
+    /// grace period. To be passed to [GracePeriods::check].
+    pub fn replaced_at(
+        &self,
+        allocations: &HashMap<Address, Allocation>,
+        allocation: &Allocation,
+    ) -> Option<u64> {
+        self.rollover_overlap?;
+        allocation.closed_at?;
+        rollover::replaced_at(allocations, allocation)
+    }
+
+    pub fn for_allocation(&self, allocation: &Allocation, sender: Option<&Address>) -> Duration {
+        sender
+            .and_then(|sender| self.per_sender.get(sender))
+            .or_else(|| {
+                allocation
+                    .subgraph_deployment
+                    .network
+                    .as_ref()
+                    .and_then(|network| self.per_network.get(network))
+            })
+            .copied()
+            .unwrap_or(self.default)
+    }
+
+    /// Checks that a receipt of `sender` for `allocation`, replaced at `replaced_at` if it was,
+    /// is still accepted at `now`, in seconds since the UNIX epoch.
+    pub fn check(
+        &self,
+        allocation: &Allocation,
+        replaced_at: Option<u64>,
+        sender: Option<&Address>,
+        now: u64,
+    ) -> Result<(), AllocationClosed> {
+        let Some(closed_at) = allocation.closed_at else {
+            return Ok(());
+        };
+        let grace_period = match (self.rollover_overlap, replaced_at) {
+            // Reported from the closure, like the grace period
+            (Some(overlap), Some(replaced_at)) => {
+                (replaced_at + overlap.as_secs()).saturating_sub(closed_at)
+            }
+            _ => self.for_allocation(allocation, sender).as_secs(),
+        };
+        let closed_for = now.saturating_sub(closed_at);
+        if closed_for > grace_period {
+            return Err(AllocationClosed {
+                allocation_id: allocation.id,
+                closed_for_secs: closed_for,
+                grace_period_secs: grace_period,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_vectors::INDEXER_ALLOCATIONS;
+
+    use super::*;
+
+    #[test]
+    fn test_grace_periods() {
+        let sender = Address::from([0x11; 20]);
+        let periods = GracePeriods {
+            default: Duration::from_secs(3600),
+            per_network: HashMap::from([("mainnet".to_string(), Duration::from_secs(600))]),
+            per_sender: HashMap::from([(sender, Duration::from_secs(7200))]),
+            rollover_overlap: None,
+        };
+        assert_eq!(periods.max(), Duration::from_secs(7200));
+
+        let mut allocation = INDEXER_ALLOCATIONS.values().next().unwrap().clone();
+        allocation.subgraph_deployment.network = Some("mainnet".to_string());
+        allocation.closed_at = Some(10_000);
+
+        assert_eq!(periods.check(&allocation, None, None, 10_600), Ok(()));
+        assert_eq!(
+            periods.check(&allocation, None, None, 10_601),
+            Err(AllocationClosed {
+                allocation_id: allocation.id,
+                closed_for_secs: 601,
+                grace_period_secs: 600,
+            })
+        );
+        // The sender's grace period takes precedence over the network's
+        assert_eq!(
+            periods.check(&allocation, None, Some(&sender), 15_000),
+            Ok(())
+        );
+
+        allocation.subgraph_deployment.network = Some("gnosis".to_string());
+        assert_eq!(periods.check(&allocation, None, None, 13_600), Ok(()));
+
+        allocation.closed_at = None;
+        assert_eq!(periods.check(&allocation, None, None, u64::MAX), Ok(()));
+    }
+
+    #[test]
+    fn test_rollover_overlap() {
+        let mut periods = GracePeriods {
+            default: Duration::from_secs(3600),
+            ..Default::default()
+        };
+        let mut allocation = INDEXER_ALLOCATIONS.values().next().unwrap().clone();
+        allocation.closed_at = Some(10_000);
+        let mut next = allocation.clone();
+        next.id = Address::from([0x22; 20]);
+        next.created_at_epoch = allocation.created_at_epoch + 1;
+        next.created_at = Some(10_100);
+        next.closed_at_epoch = None;
+        next.closed_at = None;
+        allocation.closed_at_epoch = Some(next.created_at_epoch);
+        let allocations =
+            HashMap::from([(allocation.id, allocation.clone()), (next.id, next.clone())]);
+
+        // The grace period applies without an overlap
+        assert_eq!(periods.replaced_at(&allocations, &allocation), None);
+        assert_eq!(periods.check(&allocation, None, None, 13_600), Ok(()));
+
+        periods.rollover_overlap = Some(Duration::from_secs(300));
+        assert_eq!(periods.max(), Duration::from_secs(3600));
+        let replaced_at = periods.replaced_at(&allocations, &allocation);
+        assert_eq!(replaced_at, Some(10_100));
+        assert_eq!(periods.replaced_at(&allocations, &next), None);
+        // Both accepted during the overlap
+        assert_eq!(
+            periods.check(&allocation, replaced_at, None, 10_400),
+            Ok(())
+        );
+        assert_eq!(periods.check(&next, None, None, 10_400), Ok(()));
+        assert_eq!(
+            periods.check(&allocation, replaced_at, None, 10_401),
+            Err(AllocationClosed {
+                allocation_id: allocation.id,
+                closed_for_secs: 401,
+                grace_period_secs: 400,
+            })
+        );
+        // Not replaced, the grace period still applies
+        assert_eq!(periods.check(&allocation, None, None, 13_600), Ok(()));
+    }
+}
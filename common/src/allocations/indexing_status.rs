@@ -0,0 +1,260 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Pauses the paid queries to the deployments of the indexer's allocations that graph-node
+//! reports failed or paused, so that no attestation is signed for data it no longer indexes
+//! correctly. The deployments are resumed as soon as graph-node reports them healthy again.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use eventuals::{join, timer, Eventual, EventualExt};
+use lazy_static::lazy_static;
+use prometheus::{register_int_gauge_vec, IntGaugeVec};
+use reqwest::Url;
+use serde::Deserialize;
+use serde_json::json;
+use thegraph::types::{Address, DeploymentId};
+use thegraph_graphql_http::http_client::ReqwestExt;
+use tracing::{info, warn};
+
+use super::Allocation;
+use crate::monitor_backoff::Backoff;
+use crate::subgraph_client::Query;
+
+lazy_static! {
+    static ref DEPLOYMENT_PAUSED: IntGaugeVec = register_int_gauge_vec!(
+        "indexer_deployment_paused",
+        "Deployments whose paid queries are paused because graph-node reports them failed or paused",
+        &["deployment"]
+    )
+    .unwrap();
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct IndexingStatusesResponse {
+    indexing_statuses: Vec<IndexingStatus>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct IndexingStatus {
+    subgraph: DeploymentId,
+    health: String,
+    /// Not reported by older graph-node versions.
+    paused: Option<bool>,
+    fatal_error: Option<FatalError>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FatalError {
+    message: String,
+}
+
+/// Why the paid queries to the deployment must be paused, if they must.
+fn pause_reason(status: &IndexingStatus) -> Option<String> {
+    if status.health == "failed" {
+        return Some(match &status.fatal_error {
+            Some(error) => format!("Deployment failed: {}", error.message),
+            None => "Deployment failed".to_string(),
+        });
+    }
+    if status.paused == Some(true) {
+        return Some("Deployment is paused in graph-node".to_string());
+    }
+    None
+}
+
+/// The deployments of the indexer's allocations whose paid queries are paused, with the reason.
+pub fn paused_deployments(
+    http_client: reqwest::Client,
+    indexer_allocations: Eventual<HashMap<Address, Allocation>>,
+    status_url: Url,
+    interval: Duration,
+) -> Eventual<HashMap<DeploymentId, String>> {
+    let paused = Arc::new(Mutex::new(HashMap::new()));
+    let backoff = Backoff::new("paused_deployments", interval);
+    let retry_backoff = backoff.clone();
+
+    join((indexer_allocations, timer(interval))).map_with_retry(
+        move |(allocations, _)| {
+            let http_client = http_client.clone();
+            let status_url = status_url.clone();
+            let paused = paused.clone();
+            let backoff = backoff.clone();
+            async move {
+                let deployments: Vec<_> = allocations
+                    .values()
+                    .map(|allocation| allocation.subgraph_deployment.id)
+                    .collect::<HashSet<_>>()
+                    .into_iter()
+                    .collect();
+
+                let statuses = backoff.track(
+                    indexing_statuses(&http_client, status_url, &deployments)
+                        .await
+                        .map_err(|e| e.to_string()),
+                )?;
+                let now_paused: HashMap<_, _> = statuses
+                    .iter()
+                    .filter_map(|status| Some((status.subgraph, pause_reason(status)?)))
+                    .collect();
+
+                report_changes(&mut paused.lock().unwrap(), &now_paused);
+                Ok(now_paused)
+            }
+        },
+        move |err: String| {
+            warn!("Failed to query the indexing status of the allocated deployments: {err}");
+            retry_backoff.failed(&err)
+        },
+    )
+}
+
+async fn indexing_statuses(
+    http_client: &reqwest::Client,
+    status_url: Url,
+    deployments: &[DeploymentId],
+) -> Result<Vec<IndexingStatus>, anyhow::Error> {
+    if deployments.is_empty() {
+        return Ok(vec![]);
+    }
+    let query = Query::new_with_variables(
+        r#"
+            query indexingStatuses($ids: [String!]!) {
+                indexingStatuses(subgraphs: $ids) {
+                    subgraph
+                    health
+                    paused
+                    fatalError {
+                        message
+                    }
+                }
+            }
+        "#,
+        [(
+            "ids",
+            json!(deployments
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()),
+        )],
+    );
+    let response = http_client
+        .post(status_url)
+        .send_graphql::<IndexingStatusesResponse>(query)
+        .await?
+        .map_err(|e| anyhow::anyhow!("{e}"))?;
+    Ok(response.indexing_statuses)
+}
+
+/// Notifies the operator of the deployments paused and resumed since the previous check.
+fn report_changes(
+    previously_paused: &mut HashMap<DeploymentId, String>,
+    paused: &HashMap<DeploymentId, String>,
+) {
+    for (deployment, reason) in paused {
+        if previously_paused.get(deployment) != Some(reason) {
+            warn!(
+                %deployment,
+                reason,
+                "Pausing paid queries to the deployment until graph-node reports it healthy"
+            );
+            DEPLOYMENT_PAUSED
+                .with_label_values(&[&deployment.to_string()])
+                .set(1);
+        }
+    }
+    for deployment in previously_paused.keys() {
+        if !paused.contains_key(deployment) {
+            info!(%deployment, "Resuming paid queries to the deployment");
+            let _ = DEPLOYMENT_PAUSED.remove_label_values(&[&deployment.to_string()]);
+        }
+    }
+    previously_paused.clone_from(paused);
+}
+
+#[cfg(test)]
+mod tests {
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use crate::test_vectors::{INDEXER_ALLOCATIONS, NETWORK_SUBGRAPH_DEPLOYMENT};
+
+    use super::*;
+
+    #[test]
+    fn test_pause_reason() {
+        let status = |health: &str, paused, fatal_error: Option<&str>| IndexingStatus {
+            subgraph: *NETWORK_SUBGRAPH_DEPLOYMENT,
+            health: health.to_string(),
+            paused,
+            fatal_error: fatal_error.map(|message| FatalError {
+                message: message.to_string(),
+            }),
+        };
+
+        assert_eq!(pause_reason(&status("healthy", Some(false), None)), None);
+        // Unhealthy deployments keep indexing, past their non-fatal errors
+        assert_eq!(pause_reason(&status("unhealthy", None, None)), None);
+        assert_eq!(
+            pause_reason(&status("failed", None, Some("Mapping aborted"))),
+            Some("Deployment failed: Mapping aborted".to_string())
+        );
+        assert_eq!(
+            pause_reason(&status("healthy", Some(true), None)),
+            Some("Deployment is paused in graph-node".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_paused_deployments() {
+        let mock_server = MockServer::start().await;
+        let allocations = INDEXER_ALLOCATIONS.clone();
+        let deployments: Vec<_> = allocations
+            .values()
+            .map(|allocation| allocation.subgraph_deployment.id)
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        let failed = deployments[0];
+
+        Mock::given(method("POST"))
+            .and(path("/status"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "data": {
+                    "indexingStatuses": deployments
+                        .iter()
+                        .map(|deployment| json!({
+                            "subgraph": deployment.to_string(),
+                            "health": if *deployment == failed { "failed" } else { "healthy" },
+                            "paused": false,
+                            "fatalError": if *deployment == failed {
+                                json!({ "message": "Mapping aborted" })
+                            } else {
+                                json!(null)
+                            },
+                        }))
+                        .collect::<Vec<_>>()
+                }
+            })))
+            .mount(&mock_server)
+            .await;
+
+        let paused = paused_deployments(
+            reqwest::Client::new(),
+            Eventual::from_value(allocations),
+            Url::parse(&format!("{}/status", mock_server.uri())).unwrap(),
+            Duration::from_secs(60),
+        );
+
+        assert_eq!(
+            paused.value().await.unwrap(),
+            HashMap::from([(failed, "Deployment failed: Mapping aborted".to_string())])
+        );
+    }
+}
@@ -6,7 +6,12 @@ use serde::{Deserialize, Deserializer};
 use thegraph::types::Address;
 use thegraph::types::DeploymentId;
 
+pub mod grace_period;
+pub mod indexing_status;
 pub mod monitor;
+pub mod rollover;
+pub mod sources;
+pub mod virtual_allocations;
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Allocation {
@@ -17,7 +22,11 @@ pub struct Allocation {
     pub allocated_tokens: U256,
     pub created_at_epoch: u64,
     pub created_at_block_hash: String,
+    /// When the allocation was created, in seconds since the UNIX epoch.
+    pub created_at: Option<u64>,
     pub closed_at_epoch: Option<u64>,
+    /// When the allocation was closed, in seconds since the UNIX epoch.
+    pub closed_at: Option<u64>,
     pub closed_at_epoch_start_block_hash: Option<String>,
     pub previous_epoch_start_block_hash: Option<String>,
     pub poi: Option<String>,
@@ -39,6 +48,25 @@ pub struct SubgraphDeployment {
     pub id: DeploymentId,
     #[serde(rename = "deniedAt")]
     pub denied_at: Option<u64>,
+    /// Network indexed by the deployment, e.g. `mainnet`, from its manifest.
+    #[serde(
+        default,
+        rename = "manifest",
+        deserialize_with = "deserialize_manifest_network"
+    )]
+    pub network: Option<String>,
+}
+
+fn deserialize_manifest_network<'d, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'d>,
+{
+    #[derive(Deserialize)]
+    struct Manifest {
+        network: Option<String>,
+    }
+
+    Ok(Option::<Manifest>::deserialize(deserializer)?.and_then(|manifest| manifest.network))
 }
 
 impl<'d> Deserialize<'d> for Allocation {
@@ -60,7 +88,9 @@ impl<'d> Deserialize<'d> for Allocation {
             allocatedTokens: U256,
             createdAtBlockHash: String,
             createdAtEpoch: u64,
+            createdAt: Option<u64>,
             closedAtEpoch: Option<u64>,
+            closedAt: Option<u64>,
         }
 
         let outer = Outer::deserialize(deserializer)?;
@@ -73,7 +103,9 @@ impl<'d> Deserialize<'d> for Allocation {
             allocated_tokens: outer.allocatedTokens,
             created_at_epoch: outer.createdAtEpoch,
             created_at_block_hash: outer.createdAtBlockHash,
+            created_at: outer.createdAt,
             closed_at_epoch: outer.closedAtEpoch,
+            closed_at: outer.closedAt,
             closed_at_epoch_start_block_hash: None,
             previous_epoch_start_block_hash: None,
             poi: None,
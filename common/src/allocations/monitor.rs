@@ -7,12 +7,28 @@ use std::{
 };
 
 use super::Allocation;
+use crate::monitor_backoff::Backoff;
 use crate::prelude::SubgraphClient;
 use eventuals::{timer, Eventual, EventualExt};
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter, register_int_counter_vec, IntCounter, IntCounterVec};
 use thegraph::types::Address;
-use tokio::time::sleep;
 use tracing::warn;
 
+lazy_static! {
+    static ref NETWORK_SUBGRAPH_DISAGREEMENTS: IntCounterVec = register_int_counter_vec!(
+        "indexer_network_subgraph_disagreements_total",
+        "Allocations fetches of a network subgraph endpoint that disagree with the quorum",
+        &["endpoint"]
+    )
+    .unwrap();
+    static ref NETWORK_SUBGRAPH_QUORUM_FAILURES: IntCounter = register_int_counter!(
+        "indexer_network_subgraph_quorum_failures_total",
+        "Allocations fetches for which not enough network subgraph endpoints agreed"
+    )
+    .unwrap();
+}
+
 /// An always up-to-date list of an indexer's active and recently closed allocations.
 pub fn indexer_allocations(
     network_subgraph: &'static SubgraphClient,
@@ -20,16 +36,23 @@ pub fn indexer_allocations(
     interval: Duration,
     recently_closed_allocation_buffer: Duration,
 ) -> Eventual<HashMap<Address, Allocation>> {
+    let backoff = Backoff::new("indexer_allocations", interval);
+    let retry_backoff = backoff.clone();
     // Refresh indexer allocations every now and then
     timer(interval).map_with_retry(
-        move |_| async move {
-            get_allocations(
-                network_subgraph,
-                indexer_address,
-                recently_closed_allocation_buffer,
-            )
-            .await
-            .map_err(|e| e.to_string())
+        move |_| {
+            let backoff = backoff.clone();
+            async move {
+                backoff.track(
+                    get_allocations(
+                        network_subgraph,
+                        indexer_address,
+                        recently_closed_allocation_buffer,
+                    )
+                    .await
+                    .map_err(|e| e.to_string()),
+                )
+            }
         },
         // Need to use string errors here because eventuals `map_with_retry` retries
         // errors that can be cloned
@@ -39,12 +62,146 @@ pub fn indexer_allocations(
                 indexer_address, err
             );
 
-            // Sleep for a bit before we retry
-            sleep(interval.div_f32(2.0))
+            retry_backoff.failed(&err)
         },
     )
 }
 
+/// Smallest strict majority of `endpoints`, the default quorum of the network subgraph endpoints.
+pub fn majority_quorum(endpoints: usize) -> usize {
+    endpoints / 2 + 1
+}
+
+/// Same as [indexer_allocations], but the allocations are fetched from several network subgraph
+/// endpoints, given with their query URL, and only updated when at least `quorum` of them return
+/// the same allocations. Guards against a compromised or buggy endpoint.
+pub fn indexer_allocations_with_quorum(
+    network_subgraphs: Vec<(String, &'static SubgraphClient)>,
+    quorum: usize,
+    indexer_address: Address,
+    interval: Duration,
+    recently_closed_allocation_buffer: Duration,
+) -> Eventual<HashMap<Address, Allocation>> {
+    let backoff = Backoff::new("indexer_allocations", interval);
+    let retry_backoff = backoff.clone();
+    timer(interval).map_with_retry(
+        move |_| {
+            let network_subgraphs = network_subgraphs.clone();
+            let backoff = backoff.clone();
+            async move {
+                let handles = network_subgraphs
+                    .into_iter()
+                    .map(|(query_url, network_subgraph)| {
+                        let handle = tokio::spawn(get_allocations(
+                            network_subgraph,
+                            indexer_address,
+                            recently_closed_allocation_buffer,
+                        ));
+                        (endpoint_label(&query_url), handle)
+                    })
+                    .collect::<Vec<_>>();
+                let mut results = Vec::with_capacity(handles.len());
+                for (endpoint, handle) in handles {
+                    let result = match handle.await {
+                        Ok(result) => result.map_err(|e| e.to_string()),
+                        Err(e) => Err(e.to_string()),
+                    };
+                    results.push((endpoint, result));
+                }
+                backoff.track(allocations_quorum(results, quorum))
+            }
+        },
+        move |err: String| {
+            warn!(
+                "Failed to fetch active or recently closed allocations for indexer {:?}: {}",
+                indexer_address, err
+            );
+
+            retry_backoff.failed(&err)
+        },
+    )
+}
+
+/// Identifies an endpoint in logs and metrics. Only the host is kept, query URLs may contain API
+/// keys.
+fn endpoint_label(query_url: &str) -> String {
+    reqwest::Url::parse(query_url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+        .unwrap_or_else(|| "invalid".to_string())
+}
+
+/// Returns the allocations agreed upon by at least `quorum` endpoints, logging how the other
+/// endpoints differ from them.
+fn allocations_quorum(
+    results: Vec<(String, Result<HashMap<Address, Allocation>, String>)>,
+    quorum: usize,
+) -> Result<HashMap<Address, Allocation>, String> {
+    let mut groups: Vec<(HashMap<Address, Allocation>, Vec<String>)> = Vec::new();
+    for (endpoint, result) in &results {
+        match result {
+            Ok(allocations) => match groups.iter_mut().find(|(other, _)| other == allocations) {
+                Some((_, endpoints)) => endpoints.push(endpoint.clone()),
+                None => groups.push((allocations.clone(), vec![endpoint.clone()])),
+            },
+            Err(e) => warn!(%endpoint, error = %e, "Failed to fetch allocations from endpoint"),
+        }
+    }
+    groups.sort_by_key(|(_, endpoints)| std::cmp::Reverse(endpoints.len()));
+
+    let Some((agreed, agreeing_endpoints)) = groups.first() else {
+        NETWORK_SUBGRAPH_QUORUM_FAILURES.inc();
+        return Err("No network subgraph endpoint returned allocations".to_string());
+    };
+    for (endpoint, result) in &results {
+        if let Ok(allocations) = result {
+            if allocations != agreed {
+                NETWORK_SUBGRAPH_DISAGREEMENTS
+                    .with_label_values(&[endpoint])
+                    .inc();
+                warn!(
+                    %endpoint,
+                    diff = %allocations_diff(agreed, allocations),
+                    "Network subgraph endpoint disagrees on the allocations"
+                );
+            }
+        }
+    }
+
+    if agreeing_endpoints.len() < quorum {
+        NETWORK_SUBGRAPH_QUORUM_FAILURES.inc();
+        return Err(format!(
+            "Only {} network subgraph endpoints agree on the allocations, {} required",
+            agreeing_endpoints.len(),
+            quorum
+        ));
+    }
+    Ok(agreed.clone())
+}
+
+fn allocations_diff(
+    expected: &HashMap<Address, Allocation>,
+    actual: &HashMap<Address, Allocation>,
+) -> String {
+    let missing = expected
+        .keys()
+        .filter(|id| !actual.contains_key(id))
+        .collect::<Vec<_>>();
+    let unexpected = actual
+        .keys()
+        .filter(|id| !expected.contains_key(id))
+        .collect::<Vec<_>>();
+    let different = expected
+        .iter()
+        .filter(|(id, allocation)| actual.get(id).is_some_and(|other| other != *allocation))
+        .map(|(id, _)| id)
+        .collect::<Vec<_>>();
+    format!(
+        "missing: {:?}, unexpected: {:?}, different: {:?}",
+        missing, unexpected, different
+    )
+}
+
 pub async fn get_allocations(
     network_subgraph: &'static SubgraphClient,
     indexer_address: Address,
@@ -83,10 +240,15 @@ pub async fn get_allocations(
                 allocatedTokens
                 createdAtBlockHash
                 createdAtEpoch
+                createdAt
                 closedAtEpoch
+                closedAt
                 subgraphDeployment {{
                     id
                     deniedAt
+                    manifest {{
+                        network
+                    }}
                 }}
             }}
         "#,
@@ -130,6 +292,30 @@ mod test {
         assert!(result.unwrap().len() > 2000)
     }
 
+    #[test]
+    fn test_allocations_quorum() {
+        let allocations = crate::test_vectors::INDEXER_ALLOCATIONS.to_owned();
+        let mut tampered = allocations.clone();
+        let (_, allocation) = tampered.iter_mut().next().unwrap();
+        allocation.closed_at_epoch = Some(1);
+
+        let results = vec![
+            ("a".to_string(), Ok(allocations.clone())),
+            ("b".to_string(), Ok(tampered.clone())),
+            ("c".to_string(), Ok(allocations.clone())),
+            ("d".to_string(), Err("unreachable".to_string())),
+        ];
+        assert_eq!(allocations_quorum(results.clone(), 2), Ok(allocations));
+        assert!(allocations_quorum(results, 3).is_err());
+        assert!(allocations_quorum(vec![], 1).is_err());
+        assert!(allocations_quorum(vec![("a".to_string(), Ok(tampered))], 1).is_ok());
+
+        assert_eq!(majority_quorum(1), 1);
+        assert_eq!(majority_quorum(2), 2);
+        assert_eq!(majority_quorum(3), 2);
+        assert_eq!(majority_quorum(4), 3);
+    }
+
     #[tokio::test]
     async fn test_network_query_empty_response() {
         let result = get_allocations(
@@ -0,0 +1,159 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Allocations closed and replaced by a new allocation on the same deployment. The fees of a
+//! deployment then continue on the new allocation, and the closed one is finalized once it's no
+//! longer accepted: after the recently closed allocations buffer, and the overlap of both
+//! allocations when the closed one is accepted for longer, see
+//! [GracePeriods::rollover_overlap](super::grace_period::GracePeriods::rollover_overlap).
+
+use std::collections::HashMap;
+
+use thegraph::types::{Address, DeploymentId};
+
+use super::Allocation;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AllocationRollover {
+    pub deployment: DeploymentId,
+    pub previous: Address,
+    pub next: Address,
+}
+
+/// The active allocation opened on the same deployment as `closed` at or after its closing
+/// epoch, if any.
+fn successor<'a>(
+    allocations: &'a HashMap<Address, Allocation>,
+    closed: &Allocation,
+) -> Option<&'a Allocation> {
+    let closed_at_epoch = closed.closed_at_epoch?;
+    allocations
+        .values()
+        .filter(|active| {
+            active.closed_at_epoch.is_none()
+                && active.subgraph_deployment.id == closed.subgraph_deployment.id
+                && active.created_at_epoch >= closed_at_epoch
+        })
+        // The oldest successor, several allocations may be opened on the same epoch
+        .min_by_key(|active| (active.created_at_epoch, active.id))
+}
+
+/// Pairs each closed allocation with the active allocation opened on the same deployment at or
+/// after its closing epoch, if any.
+pub fn allocation_rollovers(allocations: &HashMap<Address, Allocation>) -> Vec<AllocationRollover> {
+    let mut rollovers: Vec<_> = allocations
+        .values()
+        .filter_map(|closed| {
+            let next = successor(allocations, closed)?;
+            Some(AllocationRollover {
+                deployment: closed.subgraph_deployment.id,
+                previous: closed.id,
+                next: next.id,
+            })
+        })
+        .collect();
+    rollovers.sort_by_key(|rollover| rollover.previous);
+    rollovers
+}
+
+/// When `allocation` was replaced, i.e. when its successor was created, in seconds since the
+/// UNIX epoch. Its closing time if the creation time of the successor isn't known.
+pub fn replaced_at(
+    allocations: &HashMap<Address, Allocation>,
+    allocation: &Allocation,
+) -> Option<u64> {
+    let next = successor(allocations, allocation)?;
+    next.created_at.or(allocation.closed_at)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use ethers_core::types::U256;
+
+    use crate::prelude::{AllocationStatus, SubgraphDeployment};
+
+    use super::*;
+
+    fn allocation(
+        id: u8,
+        deployment: &DeploymentId,
+        created_at_epoch: u64,
+        closed_at_epoch: Option<u64>,
+    ) -> Allocation {
+        Allocation {
+            id: Address::from([id; 20]),
+            status: AllocationStatus::Null,
+            subgraph_deployment: SubgraphDeployment {
+                id: *deployment,
+                denied_at: None,
+                network: None,
+            },
+            indexer: Address::ZERO,
+            allocated_tokens: U256::zero(),
+            created_at_epoch,
+            created_at_block_hash: String::new(),
+            created_at: None,
+            closed_at_epoch,
+            closed_at: None,
+            closed_at_epoch_start_block_hash: None,
+            previous_epoch_start_block_hash: None,
+            poi: None,
+            query_fee_rebates: None,
+            query_fees_collected: None,
+        }
+    }
+
+    #[test]
+    fn test_allocation_rollovers() {
+        let deployment_0 =
+            DeploymentId::from_str("QmAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA").unwrap();
+        let deployment_1 =
+            DeploymentId::from_str("QmBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBB").unwrap();
+        let allocations: HashMap<_, _> = [
+            // Replaced on the epoch it was closed
+            allocation(1, &deployment_0, 10, Some(20)),
+            allocation(2, &deployment_0, 20, None),
+            allocation(3, &deployment_0, 21, None),
+            // Closed without replacement, the other allocation is older
+            allocation(4, &deployment_1, 10, Some(20)),
+            allocation(5, &deployment_1, 15, None),
+        ]
+        .into_iter()
+        .map(|allocation| (allocation.id, allocation))
+        .collect();
+
+        assert_eq!(
+            allocation_rollovers(&allocations),
+            vec![AllocationRollover {
+                deployment: deployment_0,
+                previous: Address::from([1; 20]),
+                next: Address::from([2; 20]),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_replaced_at() {
+        let deployment =
+            DeploymentId::from_str("QmAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA").unwrap();
+        let mut previous = allocation(1, &deployment, 10, Some(20));
+        previous.closed_at = Some(1_000);
+        let mut next = allocation(2, &deployment, 20, None);
+        next.created_at = Some(1_100);
+        let mut allocations: HashMap<_, _> = [previous.clone(), next.clone()]
+            .into_iter()
+            .map(|allocation| (allocation.id, allocation))
+            .collect();
+
+        assert_eq!(replaced_at(&allocations, &previous), Some(1_100));
+        assert_eq!(replaced_at(&allocations, &next), None);
+
+        allocations.get_mut(&next.id).unwrap().created_at = None;
+        assert_eq!(replaced_at(&allocations, &previous), Some(1_000));
+
+        allocations.remove(&next.id);
+        assert_eq!(replaced_at(&allocations, &previous), None);
+    }
+}
@@ -0,0 +1,229 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Allocations of the private deployments without a Graph network, where the allocations
+//! accepting receipts don't come from the network subgraph, see [AllocationSource]. They are
+//! either listed in the configuration, or registered through the admin API of the tap-agent and
+//! stored in the database, so that the indexer-services see them as well.
+//!
+//! The allocation IDs must still be derived from the operator mnemonic, for the deployment and
+//! at `created_at_epoch`, like the on-chain ones, for the responses to be attested.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::Result;
+use ethers_core::types::U256;
+use eventuals::{timer, Eventual, EventualExt};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Row};
+use thegraph::types::{Address, DeploymentId};
+use tracing::warn;
+
+use super::{Allocation, AllocationStatus, SubgraphDeployment};
+use crate::monitor_backoff::Backoff;
+use crate::types::AllocationIdHex;
+
+/// Where the allocations accepting receipts come from.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AllocationSource {
+    /// The network subgraph.
+    #[default]
+    Network,
+    /// The configuration, see [static_allocations].
+    Static,
+    /// The admin API of the tap-agent, see [registered_allocations].
+    Api,
+}
+
+/// An allocation listed in the configuration.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct StaticAllocation {
+    pub id: Address,
+    pub deployment: DeploymentId,
+    /// Epoch the allocation ID was derived for.
+    pub created_at_epoch: u64,
+}
+
+fn allocation(
+    indexer: Address,
+    id: Address,
+    deployment: DeploymentId,
+    created_at_epoch: u64,
+    created_at: Option<u64>,
+    closed_at: Option<u64>,
+) -> Allocation {
+    Allocation {
+        id,
+        status: match closed_at {
+            Some(_) => AllocationStatus::Closed,
+            None => AllocationStatus::Active,
+        },
+        subgraph_deployment: SubgraphDeployment {
+            id: deployment,
+            denied_at: None,
+            network: None,
+        },
+        indexer,
+        allocated_tokens: U256::zero(),
+        created_at_epoch,
+        created_at_block_hash: String::new(),
+        created_at,
+        closed_at_epoch: None,
+        closed_at,
+        closed_at_epoch_start_block_hash: None,
+        previous_epoch_start_block_hash: None,
+        poi: None,
+        query_fee_rebates: None,
+        query_fees_collected: None,
+    }
+}
+
+/// The allocations listed in the configuration, all active.
+pub fn static_allocations(
+    indexer: Address,
+    allocations: &[StaticAllocation],
+) -> Eventual<HashMap<Address, Allocation>> {
+    Eventual::from_value(
+        allocations
+            .iter()
+            .map(|static_allocation| {
+                (
+                    static_allocation.id,
+                    allocation(
+                        indexer,
+                        static_allocation.id,
+                        static_allocation.deployment,
+                        static_allocation.created_at_epoch,
+                        None,
+                        None,
+                    ),
+                )
+            })
+            .collect(),
+    )
+}
+
+/// The allocations registered through the admin API, active or closed for less than
+/// `recently_closed_allocation_buffer`.
+pub async fn get_registered_allocations(
+    pgpool: &PgPool,
+    indexer: Address,
+    recently_closed_allocation_buffer: Duration,
+) -> Result<HashMap<Address, Allocation>> {
+    let rows = sqlx::query(
+        r#"
+            SELECT
+                allocation_id,
+                deployment,
+                created_at_epoch,
+                EXTRACT(EPOCH FROM created_at)::BIGINT AS created_at,
+                EXTRACT(EPOCH FROM closed_at)::BIGINT AS closed_at
+            FROM registered_allocations
+            WHERE closed_at IS NULL OR closed_at > NOW() - make_interval(secs => $1)
+        "#,
+    )
+    .bind(recently_closed_allocation_buffer.as_secs_f64())
+    .fetch_all(pgpool)
+    .await?;
+
+    rows.iter()
+        .map(|row| {
+            let id = row.try_get::<AllocationIdHex, _>("allocation_id")?.0;
+            Ok((
+                id,
+                allocation(
+                    indexer,
+                    id,
+                    DeploymentId::from_str(row.try_get("deployment")?)?,
+                    row.try_get::<i64, _>("created_at_epoch")? as u64,
+                    Some(row.try_get::<i64, _>("created_at")? as u64),
+                    row.try_get::<Option<i64>, _>("closed_at")?
+                        .map(|closed_at| closed_at as u64),
+                ),
+            ))
+        })
+        .collect()
+}
+
+/// Same as [indexer_allocations](super::monitor::indexer_allocations), for the allocations
+/// registered through the admin API, reloaded from the database every `interval`.
+pub fn registered_allocations(
+    pgpool: PgPool,
+    indexer: Address,
+    interval: Duration,
+    recently_closed_allocation_buffer: Duration,
+) -> Eventual<HashMap<Address, Allocation>> {
+    let backoff = Backoff::new("registered_allocations", interval);
+    let retry_backoff = backoff.clone();
+    timer(interval).map_with_retry(
+        move |_| {
+            let pgpool = pgpool.clone();
+            let backoff = backoff.clone();
+            async move {
+                backoff.track(
+                    get_registered_allocations(&pgpool, indexer, recently_closed_allocation_buffer)
+                        .await
+                        .map_err(|e| e.to_string()),
+                )
+            }
+        },
+        move |err: String| {
+            warn!("Failed to fetch the registered allocations: {}", err);
+            retry_backoff.failed(&err)
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEPLOYMENT: &str = "QmAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA";
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_registered_allocations(pgpool: PgPool) {
+        let indexer = Address::from([0x01; 20]);
+        for (id, closed) in [
+            (Address::from([0x11; 20]), "NULL"),
+            (Address::from([0x22; 20]), "NOW() - INTERVAL '1 minute'"),
+            (Address::from([0x33; 20]), "NOW() - INTERVAL '1 day'"),
+        ] {
+            sqlx::query(&format!(
+                "INSERT INTO registered_allocations \
+                    (allocation_id, deployment, created_at_epoch, closed_at) \
+                 VALUES ($1, $2, 940, {closed})"
+            ))
+            .bind(AllocationIdHex(id))
+            .bind(DEPLOYMENT)
+            .execute(&pgpool)
+            .await
+            .unwrap();
+        }
+
+        let allocations = registered_allocations(
+            pgpool,
+            indexer,
+            Duration::from_secs(60),
+            Duration::from_secs(3600),
+        )
+        .value()
+        .await
+        .unwrap();
+        assert_eq!(allocations.len(), 2);
+        let active = &allocations[&Address::from([0x11; 20])];
+        assert_eq!(active.status, AllocationStatus::Active);
+        assert_eq!(active.indexer, indexer);
+        assert_eq!(active.created_at_epoch, 940);
+        assert_eq!(
+            active.subgraph_deployment.id,
+            DeploymentId::from_str(DEPLOYMENT).unwrap()
+        );
+        assert!(active.created_at.is_some());
+        let closed = &allocations[&Address::from([0x22; 20])];
+        assert_eq!(closed.status, AllocationStatus::Closed);
+        assert!(closed.closed_at.is_some());
+    }
+}
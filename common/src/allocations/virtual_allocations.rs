@@ -0,0 +1,181 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Virtual allocations, declared by the operator for the data services that don't have on-chain
+//! allocations yet. A virtual allocation is an allocation ID signed by the operator key for a
+//! data service, identified by its URL namespace, e.g. `sql`. The receipts for it are accepted
+//! by the services the indexer enabled them for, until the protocol supports allocating to
+//! these services.
+//!
+//! The signatures are verified against the operator key whenever the virtual allocations are
+//! loaded, so rows written to the database by anyone else are ignored.
+
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use alloy_primitives::hex;
+use anyhow::{anyhow, Result};
+use eventuals::{timer, Eventual, EventualExt};
+use sqlx::{PgPool, Row};
+use thegraph::types::Address;
+use tracing::{error, warn};
+
+use crate::address::{recover_message_signer, sign_message};
+use crate::monitor_backoff::Backoff;
+use crate::types::AllocationIdHex;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VirtualAllocation {
+    pub id: Address,
+    /// URL namespace of the data service the allocation is for.
+    pub service: String,
+    /// EIP-191 signature of [`message`] by the operator key.
+    pub signature: String,
+}
+
+/// Message signed by the operator to declare a virtual allocation.
+pub fn message(allocation_id: Address, service: &str) -> String {
+    format!("indexer virtual allocation\n{service}\n{allocation_id}")
+}
+
+/// Signature of a virtual allocation by the operator key, a private key or mnemonic.
+pub fn sign(operator_key: &str, allocation_id: Address, service: &str) -> Result<String> {
+    let signature = sign_message(operator_key, message(allocation_id, service).as_bytes())?;
+    Ok(hex::encode_prefixed(signature))
+}
+
+impl VirtualAllocation {
+    /// Checks that the allocation was signed by `operator`.
+    pub fn verify(&self, operator: Address) -> Result<()> {
+        let signature = hex::decode(&self.signature)?;
+        let signer =
+            recover_message_signer(message(self.id, &self.service).as_bytes(), &signature)?;
+        if signer != operator {
+            return Err(anyhow!(
+                "Virtual allocation `{}` is signed by {signer}, not by the operator {operator}",
+                self.id
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The virtual allocations not revoked, including the ones not signed by the operator.
+pub async fn active_virtual_allocations(pgpool: &PgPool) -> Result<Vec<VirtualAllocation>> {
+    let rows = sqlx::query(
+        r#"
+            SELECT allocation_id, service, signature
+            FROM virtual_allocations
+            WHERE revoked_at IS NULL
+        "#,
+    )
+    .fetch_all(pgpool)
+    .await?;
+
+    rows.iter()
+        .map(|row| {
+            Ok(VirtualAllocation {
+                id: row.try_get::<AllocationIdHex, _>("allocation_id")?.0,
+                service: row.try_get("service")?,
+                signature: row.try_get("signature")?,
+            })
+        })
+        .collect()
+}
+
+/// Virtual allocations signed by `operator` for one of `services`, by allocation ID, reloaded
+/// from the database every `interval`. Always empty if no service accepts them.
+pub fn virtual_allocations(
+    pgpool: PgPool,
+    operator: Address,
+    services: HashSet<String>,
+    interval: Duration,
+) -> Eventual<HashMap<Address, VirtualAllocation>> {
+    if services.is_empty() {
+        return Eventual::from_value(HashMap::new());
+    }
+    let backoff = Backoff::new("virtual_allocations", interval);
+    let retry_backoff = backoff.clone();
+    timer(interval).map_with_retry(
+        move |_| {
+            let pgpool = pgpool.clone();
+            let services = services.clone();
+            let backoff = backoff.clone();
+            async move {
+                let allocations = backoff.track(
+                    active_virtual_allocations(&pgpool)
+                        .await
+                        .map_err(|e| e.to_string()),
+                )?;
+                Ok(allocations
+                    .into_iter()
+                    .filter(|allocation| services.contains(&allocation.service))
+                    .filter(|allocation| match allocation.verify(operator) {
+                        Ok(()) => true,
+                        Err(e) => {
+                            warn!("Ignoring virtual allocation: {}", e);
+                            false
+                        }
+                    })
+                    .map(|allocation| (allocation.id, allocation))
+                    .collect())
+            }
+        },
+        move |err: String| {
+            error!("Failed to fetch the virtual allocations: {}", err);
+            retry_backoff.failed(&err)
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::address::wallet_address;
+    use crate::test_vectors::INDEXER_OPERATOR_MNEMONIC;
+
+    use super::*;
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_virtual_allocations(pgpool: PgPool) {
+        let operator = wallet_address(&INDEXER_OPERATOR_MNEMONIC).unwrap();
+        let signed = |id: Address, service: &str| VirtualAllocation {
+            id,
+            service: service.to_string(),
+            signature: sign(&INDEXER_OPERATOR_MNEMONIC, id, service).unwrap(),
+        };
+        let sql = signed(Address::from([0x11; 20]), "sql");
+        let files = signed(Address::from([0x22; 20]), "files");
+        // Signed for another service than the one it's declared for
+        let forged = VirtualAllocation {
+            service: "sql".to_string(),
+            ..signed(Address::from([0x33; 20]), "files")
+        };
+        assert!(sql.verify(operator).is_ok());
+        assert!(forged.verify(operator).is_err());
+        assert!(sql.verify(Address::ZERO).is_err());
+
+        for allocation in [&sql, &files, &forged] {
+            sqlx::query(
+                "INSERT INTO virtual_allocations (allocation_id, service, signature) \
+                 VALUES ($1, $2, $3)",
+            )
+            .bind(AllocationIdHex(allocation.id))
+            .bind(&allocation.service)
+            .bind(&allocation.signature)
+            .execute(&pgpool)
+            .await
+            .unwrap();
+        }
+
+        let allocations = virtual_allocations(
+            pgpool,
+            operator,
+            HashSet::from(["sql".to_string()]),
+            Duration::from_secs(60),
+        )
+        .value()
+        .await
+        .unwrap();
+        assert_eq!(allocations, HashMap::from([(sql.id, sql)]));
+    }
+}
@@ -6,9 +6,9 @@ use std::time::Duration;
 use eventuals::{timer, Eventual, EventualExt};
 use serde::Deserialize;
 use thegraph::types::Address;
-use tokio::time::sleep;
 use tracing::warn;
 
+use crate::monitor_backoff::Backoff;
 use crate::subgraph_client::{Query, SubgraphClient};
 
 pub fn dispute_manager(
@@ -27,32 +27,36 @@ pub fn dispute_manager(
         dispute_manager: Address,
     }
 
+    let backoff = Backoff::new("dispute_manager", interval);
+    let retry_backoff = backoff.clone();
     timer(interval).map_with_retry(
-        move |_| async move {
-            let response = network_subgraph
-                .query::<DisputeManagerResponse>(Query::new(
-                    r#"
-                        query network {
-                            graphNetwork(id: 1) {
-                                disputeManager
+        move |_| {
+            let backoff = backoff.clone();
+            async move {
+                let response = network_subgraph
+                    .query::<DisputeManagerResponse>(Query::new(
+                        r#"
+                            query network {
+                                graphNetwork(id: 1) {
+                                    disputeManager
+                                }
                             }
-                        }
-                    "#,
-                ))
-                .await
-                .map_err(|e| e.to_string())?;
-
-            response.map_err(|e| e.to_string()).and_then(|data| {
-                data.graph_network
-                    .map(|network| network.dispute_manager)
-                    .ok_or_else(|| "Network 1 not found in network subgraph".to_string())
-            })
+                        "#,
+                    ))
+                    .await
+                    .map_err(|e| e.to_string())?;
+
+                backoff.track(response.map_err(|e| e.to_string()).and_then(|data| {
+                    data.graph_network
+                        .map(|network| network.dispute_manager)
+                        .ok_or_else(|| "Network 1 not found in network subgraph".to_string())
+                }))
+            }
         },
         move |err: String| {
             warn!("Failed to query dispute manager for network: {}", err,);
 
-            // Sleep for a bit before we retry
-            sleep(interval.div_f32(2.0))
+            retry_backoff.failed(&err)
         },
     )
 }
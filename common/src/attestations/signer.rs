@@ -1,15 +1,31 @@
 // Copyright 2023-, GraphOps and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
-use alloy_sol_types::Eip712Domain;
+use alloy_primitives::{hex, B256};
+use alloy_sol_types::{Eip712Domain, SolStruct};
+use anyhow::ensure;
 use ethers::signers::coins_bip39::English;
 use ethers::signers::{MnemonicBuilder, Signer, Wallet};
 use ethers_core::k256::ecdsa::SigningKey;
+use ethers_core::utils::hash_message;
 use thegraph::types::{attestation, Attestation, DeploymentId};
 use thegraph::types::{Address, U256};
 
 use crate::prelude::Allocation;
 
+mod eip712 {
+    use alloy_sol_types::sol;
+
+    sol! {
+        // Message signed in attestations, the name is part of the EIP-712 type hash.
+        struct Receipt {
+            bytes32 requestCID;
+            bytes32 responseCID;
+            bytes32 subgraphDeploymentID;
+        }
+    }
+}
+
 pub fn derive_key_pair(
     indexer_mnemonic: &str,
     epoch: u64,
@@ -64,6 +80,20 @@ impl AttestationSigner {
         })
     }
 
+    /// Disclosure, signed by the allocation's key, that the responses served for the deployment
+    /// are not attested: `disabled; deployment=<id>; signature=<signature>`, the signature being
+    /// the EIP-191 signature of [`attestations_disabled_statement`]. Gateways can recover the
+    /// allocation id from it, as they would from an attestation.
+    pub fn attestations_disabled_disclosure(&self) -> Result<String, anyhow::Error> {
+        let statement = attestations_disabled_statement(&self.deployment);
+        let signature = Wallet::from(self.signer.clone()).sign_hash(hash_message(&statement))?;
+        Ok(format!(
+            "disabled; deployment={}; signature={}",
+            self.deployment,
+            hex::encode_prefixed(signature.to_vec())
+        ))
+    }
+
     pub fn create_attestation(&self, request: &str, response: &str) -> Attestation {
         attestation::create(
             &self.domain,
@@ -89,6 +119,62 @@ impl AttestationSigner {
             response,
         )
     }
+
+    /// Like [`Self::verify`], but against the hashes of the request and response, for callers
+    /// that don't have their contents.
+    pub fn verify_hashes(
+        &self,
+        attestation: &Attestation,
+        request_hash: &B256,
+        response_hash: &B256,
+        expected_signer: &Address,
+    ) -> Result<(), anyhow::Error> {
+        ensure!(
+            attestation.request_cid == *request_hash,
+            "Request hash does not match the attestation"
+        );
+        ensure!(
+            attestation.response_cid == *response_hash,
+            "Response hash does not match the attestation"
+        );
+        ensure!(
+            attestation.deployment == self.deployment.0,
+            "Attestation is for another deployment"
+        );
+        let signer = self.recover_signer(attestation)?;
+        ensure!(
+            signer == *expected_signer,
+            "Attestation is signed by {} instead of {}",
+            signer,
+            expected_signer
+        );
+        Ok(())
+    }
+
+    /// Address of the key that signed the attestation, which is the allocation id for a valid
+    /// attestation.
+    pub fn recover_signer(&self, attestation: &Attestation) -> Result<Address, anyhow::Error> {
+        let signing_hash = eip712::Receipt {
+            requestCID: attestation.request_cid,
+            responseCID: attestation.response_cid,
+            subgraphDeploymentID: attestation.deployment,
+        }
+        .eip712_signing_hash(&self.domain);
+        let signature = ethers_core::types::Signature {
+            r: ethers_core::types::U256::from_big_endian(attestation.r.as_slice()),
+            s: ethers_core::types::U256::from_big_endian(attestation.s.as_slice()),
+            v: attestation.v.into(),
+        };
+        let signer = signature.recover(ethers_core::types::H256::from_slice(
+            signing_hash.as_slice(),
+        ))?;
+        Ok(Address::from_slice(signer.as_bytes()))
+    }
+}
+
+/// Statement signed in the disclosures of the deployments whose responses are not attested.
+pub fn attestations_disabled_statement(deployment: &DeploymentId) -> String {
+    format!("Attestations are disabled for deployment {}", deployment)
 }
 
 fn wallet_for_allocation(
@@ -185,12 +271,15 @@ mod tests {
                 )
                 .unwrap(),
                 denied_at: None,
+                network: None,
             },
             indexer: Address::ZERO,
             allocated_tokens: U256::zero(),
             created_at_epoch: 940,
             created_at_block_hash: "".to_string(),
+            created_at: None,
             closed_at_epoch: None,
+            closed_at: None,
             closed_at_epoch_start_block_hash: None,
             previous_epoch_start_block_hash: None,
             poi: None,
@@ -217,6 +306,107 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_verify_attestation_hashes() {
+        let allocation = Allocation {
+            id: Address::from_str("0xa171cd12c3dde7eb8fe7717a0bcd06f3ffa65658").unwrap(),
+            status: AllocationStatus::Null,
+            subgraph_deployment: SubgraphDeployment {
+                id: DeploymentId::from_str(
+                    "0xbbde25a2c85f55b53b7698b9476610c3d1202d88870e66502ab0076b7218f98a",
+                )
+                .unwrap(),
+                denied_at: None,
+                network: None,
+            },
+            indexer: Address::ZERO,
+            allocated_tokens: U256::zero(),
+            created_at_epoch: 940,
+            created_at_block_hash: "".to_string(),
+            created_at: None,
+            closed_at_epoch: None,
+            closed_at: None,
+            closed_at_epoch_start_block_hash: None,
+            previous_epoch_start_block_hash: None,
+            poi: None,
+            query_fee_rebates: None,
+            query_fees_collected: None,
+        };
+        let signer = AttestationSigner::new(
+            INDEXER_OPERATOR_MNEMONIC,
+            &allocation,
+            U256::from(1),
+            *DISPUTE_MANAGER_ADDRESS,
+        )
+        .unwrap();
+
+        let request = r#"{"query": "{ _meta { block { number } } }"}"#;
+        let response = r#"{"data": {"_meta": {"block": {"number": 1}}}}"#;
+        let attestation = signer.create_attestation(request, response);
+        let request_hash = alloy_primitives::keccak256(request);
+        let response_hash = alloy_primitives::keccak256(response);
+
+        assert_eq!(signer.recover_signer(&attestation).unwrap(), allocation.id);
+        assert!(signer
+            .verify_hashes(&attestation, &request_hash, &response_hash, &allocation.id)
+            .is_ok());
+        assert!(signer
+            .verify_hashes(&attestation, &response_hash, &request_hash, &allocation.id)
+            .is_err());
+        assert!(signer
+            .verify_hashes(&attestation, &request_hash, &response_hash, &Address::ZERO)
+            .is_err());
+    }
+
+    #[test]
+    fn test_attestations_disabled_disclosure() {
+        let allocation = Allocation {
+            id: Address::from_str("0xa171cd12c3dde7eb8fe7717a0bcd06f3ffa65658").unwrap(),
+            status: AllocationStatus::Null,
+            subgraph_deployment: SubgraphDeployment {
+                id: DeploymentId::from_str(
+                    "0xbbde25a2c85f55b53b7698b9476610c3d1202d88870e66502ab0076b7218f98a",
+                )
+                .unwrap(),
+                denied_at: None,
+                network: None,
+            },
+            indexer: Address::ZERO,
+            allocated_tokens: U256::zero(),
+            created_at_epoch: 940,
+            created_at_block_hash: "".to_string(),
+            created_at: None,
+            closed_at_epoch: None,
+            closed_at: None,
+            closed_at_epoch_start_block_hash: None,
+            previous_epoch_start_block_hash: None,
+            poi: None,
+            query_fee_rebates: None,
+            query_fees_collected: None,
+        };
+        let signer = AttestationSigner::new(
+            INDEXER_OPERATOR_MNEMONIC,
+            &allocation,
+            U256::from(1),
+            *DISPUTE_MANAGER_ADDRESS,
+        )
+        .unwrap();
+
+        let disclosure = signer.attestations_disabled_disclosure().unwrap();
+        let (prefix, signature) = disclosure.split_once("; signature=").unwrap();
+        assert_eq!(
+            prefix,
+            format!("disabled; deployment={}", allocation.subgraph_deployment.id)
+        );
+        let signature = ethers_core::types::Signature::from_str(signature).unwrap();
+        let recovered = signature
+            .recover(attestations_disabled_statement(
+                &allocation.subgraph_deployment.id,
+            ))
+            .unwrap();
+        assert_eq!(Address::from_slice(recovered.as_bytes()), allocation.id);
+    }
+
     #[test]
     fn test_attestation_signer_error() {
         // Note that because allocation will try 200 derivations paths, this is a slow test
@@ -231,12 +421,15 @@ mod tests {
                 )
                 .unwrap(),
                 denied_at: None,
+                network: None,
             },
             indexer: Address::ZERO,
             allocated_tokens: U256::zero(),
             created_at_epoch: 940,
             created_at_block_hash: "".to_string(),
+            created_at: None,
             closed_at_epoch: None,
+            closed_at: None,
             closed_at_epoch_start_block_hash: None,
             previous_epoch_start_block_hash: None,
             poi: None,
@@ -10,11 +10,12 @@ use tokio::sync::Mutex;
 use tracing::warn;
 
 use crate::prelude::{Allocation, AttestationSigner};
+use crate::secrets::SealedSecret;
 
 /// An always up-to-date list of attestation signers, one for each of the indexer's allocations.
 pub fn attestation_signers(
     indexer_allocations: Eventual<HashMap<Address, Allocation>>,
-    indexer_mnemonic: String,
+    indexer_mnemonic: SealedSecret,
     chain_id: U256,
     dispute_manager: Eventual<Address>,
 ) -> Eventual<HashMap<Address, AttestationSigner>> {
@@ -36,12 +37,14 @@ pub fn attestation_signers(
             // Create signers for new allocations
             for (id, allocation) in allocations.iter() {
                 if !signers.contains_key(id) {
-                    let signer = AttestationSigner::new(
-                        &indexer_mnemonic,
-                        allocation,
-                        chain_id,
-                        dispute_manager,
-                    );
+                    let signer = indexer_mnemonic.expose(|indexer_mnemonic| {
+                        AttestationSigner::new(
+                            indexer_mnemonic,
+                            allocation,
+                            chain_id,
+                            dispute_manager,
+                        )
+                    });
                     if let Err(e) = signer {
                         warn!(
                             "Failed to establish signer for allocation {}, deployment {}, createdAtEpoch {}: {}",
@@ -76,7 +79,7 @@ mod tests {
 
         let signers = attestation_signers(
             allocations,
-            (*INDEXER_OPERATOR_MNEMONIC).to_string(),
+            SealedSecret::new((*INDEXER_OPERATOR_MNEMONIC).to_string()),
             U256::from(1),
             dispute_manager,
         );
@@ -0,0 +1,150 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Read-your-writes consistency for the receipt reads served by a Postgres replica, for
+//! indexers running in multiple regions against a replicated database. Receipts are written to
+//! the primary, and a read only goes to the replica once it has replayed the primary's WAL up
+//! to a consistency token (a WAL LSN), so that checks don't miss receipts already stored. Reads
+//! fall back to the primary when the replica lags too much, or when no replica is configured.
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::RwLock;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter, IntCounter};
+use sqlx::PgPool;
+use tokio::time::{sleep, Instant};
+use tracing::warn;
+
+/// How often the replica is checked while waiting for it to catch up.
+const REPLAY_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+lazy_static! {
+    static ref REPLICA: RwLock<Option<Replica>> = RwLock::new(None);
+    static ref REPLICA_READ_FALLBACKS: IntCounter = register_int_counter!(
+        "indexer_replica_read_fallbacks_total",
+        "Reads served by the primary because the replica did not catch up in time"
+    )
+    .unwrap();
+}
+
+#[derive(Clone)]
+struct Replica {
+    pgpool: PgPool,
+    max_wait: Duration,
+}
+
+/// A position in the primary's WAL, as a `pg_lsn`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ConsistencyToken(u64);
+
+impl FromStr for ConsistencyToken {
+    type Err = anyhow::Error;
+
+    fn from_str(lsn: &str) -> Result<Self> {
+        let (high, low) = lsn
+            .split_once('/')
+            .ok_or_else(|| anyhow!("Invalid LSN `{}`", lsn))?;
+        Ok(Self(
+            (u64::from_str_radix(high, 16)? << 32) | u64::from_str_radix(low, 16)?,
+        ))
+    }
+}
+
+impl fmt::Display for ConsistencyToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:X}/{:X}", self.0 >> 32, self.0 & 0xFFFF_FFFF)
+    }
+}
+
+/// Serves the consistent reads from `replica` when it has caught up within `max_wait`.
+pub fn set_replica(replica: PgPool, max_wait: Duration) {
+    *REPLICA.write().unwrap() = Some(Replica {
+        pgpool: replica,
+        max_wait,
+    });
+}
+
+/// Token covering every write committed on the primary so far.
+pub async fn write_token(primary: &PgPool) -> Result<ConsistencyToken> {
+    let lsn: String = sqlx::query_scalar("SELECT pg_current_wal_lsn()::TEXT")
+        .fetch_one(primary)
+        .await?;
+    lsn.parse()
+}
+
+/// Position up to which `pgpool` has replayed the primary's WAL, `None` if it is a primary.
+pub async fn replayed_token(pgpool: &PgPool) -> Result<Option<ConsistencyToken>> {
+    let lsn: Option<String> = sqlx::query_scalar("SELECT pg_last_wal_replay_lsn()::TEXT")
+        .fetch_one(pgpool)
+        .await?;
+    lsn.map(|lsn| lsn.parse()).transpose()
+}
+
+/// The pool to read from so that the writes up to `min_token` are seen, or all the writes
+/// committed on the primary so far if not set.
+pub async fn read_pool(primary: &PgPool, min_token: Option<ConsistencyToken>) -> PgPool {
+    let Some(replica) = REPLICA.read().unwrap().clone() else {
+        return primary.clone();
+    };
+    match caught_up(primary, &replica, min_token).await {
+        Ok(true) => replica.pgpool,
+        Ok(false) => {
+            REPLICA_READ_FALLBACKS.inc();
+            primary.clone()
+        }
+        Err(e) => {
+            warn!("Failed to check the replica's replay position: {}", e);
+            REPLICA_READ_FALLBACKS.inc();
+            primary.clone()
+        }
+    }
+}
+
+async fn caught_up(
+    primary: &PgPool,
+    replica: &Replica,
+    min_token: Option<ConsistencyToken>,
+) -> Result<bool> {
+    let min_token = match min_token {
+        Some(token) => token,
+        None => write_token(primary).await?,
+    };
+    let deadline = Instant::now() + replica.max_wait;
+    loop {
+        // Not replicating, the replica is a primary too
+        let Some(replayed) = replayed_token(&replica.pgpool).await? else {
+            return Ok(true);
+        };
+        if replayed >= min_token {
+            return Ok(true);
+        }
+        if Instant::now() >= deadline {
+            return Ok(false);
+        }
+        sleep(REPLAY_POLL_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_consistency_token() {
+        let token: ConsistencyToken = "16/B374D848".parse().unwrap();
+        assert_eq!(token, ConsistencyToken(0x16_B374_D848));
+        assert_eq!(token.to_string(), "16/B374D848");
+        assert!(token < "17/0".parse().unwrap());
+        assert!("16B374D848".parse::<ConsistencyToken>().is_err());
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_primary_tokens(pgpool: PgPool) {
+        assert!(write_token(&pgpool).await.is_ok());
+        assert_eq!(replayed_token(&pgpool).await.unwrap(), None);
+    }
+}
@@ -0,0 +1,125 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Latency of the database queries, labeled by query name, and a log of the slowest ones, to
+//! diagnose the receipts tables' performance in the field. Only the names and timings of the
+//! queries are recorded, never their bind parameters.
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use lazy_static::lazy_static;
+use prometheus::{register_histogram_vec, HistogramVec};
+use serde::Serialize;
+use tracing::warn;
+
+/// Slow queries kept for the debug view, the oldest ones being dropped first.
+const SLOW_QUERY_LOG_CAPACITY: usize = 100;
+
+lazy_static! {
+    static ref DB_QUERY_DURATION: HistogramVec = register_histogram_vec!(
+        "indexer_db_query_duration_seconds",
+        "Duration of the database queries",
+        &["query", "status"],
+        vec![0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0]
+    )
+    .unwrap();
+    static ref SLOW_QUERIES: Mutex<VecDeque<SlowQuery>> =
+        Mutex::new(VecDeque::with_capacity(SLOW_QUERY_LOG_CAPACITY));
+}
+
+static SLOW_QUERY_THRESHOLD_MS: AtomicU64 = AtomicU64::new(1000);
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SlowQuery {
+    pub query: &'static str,
+    pub duration_ms: u64,
+    /// Unix timestamp of the end of the query, in milliseconds.
+    pub finished_at_ms: u64,
+    pub failed: bool,
+}
+
+/// Queries taking longer than `threshold` are logged and kept for the debug view.
+pub fn set_slow_query_threshold(threshold: Duration) {
+    SLOW_QUERY_THRESHOLD_MS.store(threshold.as_millis() as u64, Ordering::Relaxed);
+}
+
+/// Runs a database query, recording its duration under `query`.
+pub async fn timed<T, E>(
+    query: &'static str,
+    future: impl Future<Output = Result<T, E>>,
+) -> Result<T, E> {
+    let start = Instant::now();
+    let result = future.await;
+    record(query, start.elapsed(), result.is_err());
+    result
+}
+
+fn record(query: &'static str, duration: Duration, failed: bool) {
+    DB_QUERY_DURATION
+        .with_label_values(&[query, if failed { "error" } else { "ok" }])
+        .observe(duration.as_secs_f64());
+
+    let duration_ms = duration.as_millis() as u64;
+    if duration_ms < SLOW_QUERY_THRESHOLD_MS.load(Ordering::Relaxed) {
+        return;
+    }
+    warn!(query, duration_ms, failed, "Slow database query");
+
+    let finished_at_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    let mut slow_queries = SLOW_QUERIES.lock().unwrap();
+    if slow_queries.len() == SLOW_QUERY_LOG_CAPACITY {
+        slow_queries.pop_front();
+    }
+    slow_queries.push_back(SlowQuery {
+        query,
+        duration_ms,
+        finished_at_ms,
+        failed,
+    });
+}
+
+/// The last slow queries, from the most recent one.
+pub fn slow_queries() -> Vec<SlowQuery> {
+    SLOW_QUERIES.lock().unwrap().iter().rev().cloned().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_slow_queries() {
+        timed("test_fast_query", async { Ok::<_, ()>(()) })
+            .await
+            .unwrap();
+        record("test_slow_query", Duration::from_secs(2), false);
+        record("test_failed_query", Duration::from_secs(3), true);
+
+        let slow_queries: Vec<_> = slow_queries()
+            .into_iter()
+            .filter(|slow_query| slow_query.query.starts_with("test_"))
+            .map(|slow_query| (slow_query.query, slow_query.duration_ms, slow_query.failed))
+            .collect();
+        assert_eq!(
+            slow_queries,
+            vec![
+                ("test_failed_query", 3000, true),
+                ("test_slow_query", 2000, false),
+            ]
+        );
+        assert_eq!(
+            DB_QUERY_DURATION
+                .with_label_values(&["test_fast_query", "ok"])
+                .get_sample_count(),
+            1
+        );
+    }
+}
@@ -0,0 +1,346 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Sizing of the database connection pools by the time the queries wait for a connection. The
+//! pools open connections on demand up to `max_connections`, and close the idle ones down to
+//! `min_connections`. The receipt writes and the analytics reads are moreover limited to a
+//! number of concurrent queries, raised while they wait longer than the target for their turn
+//! and lowered while they don't. The reads can't take the last of them, so that a burst of
+//! analytics doesn't queue the receipts of the paid queries behind it.
+
+use std::future::Future;
+use std::sync::{Mutex, Once};
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram_vec, register_int_gauge, register_int_gauge_vec, HistogramVec, IntGauge,
+    IntGaugeVec,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{postgres::PgPoolOptions, PgPool};
+use tokio::sync::Notify;
+use tracing::debug;
+
+/// Interval of the adjustments of the limit, and of the sampling of the pools.
+const ADJUSTMENT_INTERVAL: Duration = Duration::from_secs(5);
+
+lazy_static! {
+    static ref DB_POOL_CONNECTIONS: IntGaugeVec = register_int_gauge_vec!(
+        "indexer_db_pool_connections",
+        "Connections of the database pools, in use or idle",
+        &["pool", "state"]
+    )
+    .unwrap();
+    static ref DB_POOL_ACQUIRE_WAIT: HistogramVec = register_histogram_vec!(
+        "indexer_db_pool_acquire_wait_seconds",
+        "Time the prioritized queries waited for their turn",
+        &["priority"],
+        vec![0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0]
+    )
+    .unwrap();
+    static ref DB_POOL_LIMIT: IntGauge = register_int_gauge!(
+        "indexer_db_pool_limit",
+        "Concurrent prioritized queries allowed"
+    )
+    .unwrap();
+    static ref GATE: Gate = Gate::new(PoolSizing::default());
+    static ref POOLS: Mutex<Vec<(&'static str, PgPool)>> = Mutex::new(Vec::new());
+}
+
+static START: Once = Once::new();
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PoolSizing {
+    pub min_connections: u32,
+    pub max_connections: u32,
+    pub target_acquire_wait: Duration,
+    pub idle_timeout: Duration,
+}
+
+impl Default for PoolSizing {
+    fn default() -> Self {
+        Self {
+            min_connections: 5,
+            max_connections: 50,
+            target_acquire_wait: Duration::from_millis(50),
+            idle_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+impl PoolSizing {
+    /// Options of a pool growing and shrinking between the bounds.
+    pub fn pool_options(&self) -> PgPoolOptions {
+        PgPoolOptions::new()
+            .min_connections(self.min_connections.min(self.max_connections))
+            .max_connections(self.max_connections)
+            .idle_timeout(self.idle_timeout)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Priority {
+    /// Receipt inserts, on the paid query path.
+    Write,
+    /// Analytics and other reads that can wait.
+    Read,
+}
+
+impl Priority {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Priority::Write => "write",
+            Priority::Read => "read",
+        }
+    }
+}
+
+struct GateState {
+    sizing: PoolSizing,
+    limit: u32,
+    in_use: u32,
+    /// Since the last adjustment of the limit.
+    peak_in_use: u32,
+    max_wait: Duration,
+}
+
+/// Limit of the concurrent prioritized queries.
+struct Gate {
+    state: Mutex<GateState>,
+    released: Notify,
+}
+
+/// Queries the reads may run concurrently, leaving a fifth of the limit, and at least one
+/// query, to the writes. None with a limit of 1, until their wait raises the limit.
+fn read_limit(limit: u32) -> u32 {
+    limit.saturating_sub((limit / 5).max(1))
+}
+
+/// The limit after an interval during which the queries waited up to `max_wait` for their
+/// turn, and up to `peak_in_use` of them ran at once.
+fn next_limit(sizing: &PoolSizing, limit: u32, max_wait: Duration, peak_in_use: u32) -> u32 {
+    let min = sizing
+        .min_connections
+        .clamp(1, sizing.max_connections.max(1));
+    let max = sizing.max_connections.max(min);
+    if max_wait > sizing.target_acquire_wait {
+        (limit + (limit / 4).max(1)).min(max)
+    } else if max_wait <= sizing.target_acquire_wait / 2 && peak_in_use < limit / 2 {
+        limit.saturating_sub(1).max(min)
+    } else {
+        limit.clamp(min, max)
+    }
+}
+
+impl Gate {
+    fn new(sizing: PoolSizing) -> Self {
+        let limit = sizing.min_connections.max(1);
+        Self {
+            state: Mutex::new(GateState {
+                sizing,
+                limit,
+                in_use: 0,
+                peak_in_use: 0,
+                max_wait: Duration::ZERO,
+            }),
+            released: Notify::new(),
+        }
+    }
+
+    fn configure(&self, sizing: PoolSizing) {
+        let mut state = self.state.lock().unwrap();
+        state.limit = sizing.min_connections.max(1);
+        state.sizing = sizing;
+        self.released.notify_waiters();
+    }
+
+    async fn run<T>(&self, priority: Priority, future: impl Future<Output = T>) -> T {
+        let waiting = Waiting {
+            gate: self,
+            start: Instant::now(),
+        };
+        loop {
+            let released = self.released.notified();
+            tokio::pin!(released);
+            released.as_mut().enable();
+            {
+                let mut state = self.state.lock().unwrap();
+                let limit = match priority {
+                    Priority::Write => state.limit,
+                    Priority::Read => read_limit(state.limit),
+                };
+                if state.in_use < limit {
+                    state.in_use += 1;
+                    state.peak_in_use = state.peak_in_use.max(state.in_use);
+                    break;
+                }
+            }
+            released.await;
+        }
+        DB_POOL_ACQUIRE_WAIT
+            .with_label_values(&[priority.as_str()])
+            .observe(waiting.start.elapsed().as_secs_f64());
+        drop(waiting);
+
+        let _slot = Slot(self);
+        future.await
+    }
+
+    fn release(&self) {
+        self.state.lock().unwrap().in_use -= 1;
+        self.released.notify_waiters();
+    }
+
+    /// Adjusts the limit to the waits since the last adjustment.
+    fn adjust(&self) -> u32 {
+        let mut state = self.state.lock().unwrap();
+        let limit = next_limit(
+            &state.sizing,
+            state.limit,
+            state.max_wait,
+            state.peak_in_use,
+        );
+        if limit != state.limit {
+            debug!(
+                from = state.limit,
+                to = limit,
+                max_wait_ms = state.max_wait.as_millis() as u64,
+                "Adjusting the database query limit"
+            );
+        }
+        state.limit = limit;
+        state.peak_in_use = state.in_use;
+        state.max_wait = Duration::ZERO;
+        self.released.notify_waiters();
+        limit
+    }
+}
+
+/// Records the wait of a query for its turn, including the queries given up on while waiting.
+struct Waiting<'a> {
+    gate: &'a Gate,
+    start: Instant,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        let mut state = self.gate.state.lock().unwrap();
+        state.max_wait = state.max_wait.max(self.start.elapsed());
+    }
+}
+
+/// Frees the slot of a query once it completes, or is dropped.
+struct Slot<'a>(&'a Gate);
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        self.0.release();
+    }
+}
+
+/// Sizes the pools and the limit of the prioritized queries, and starts adjusting the limit.
+pub fn configure(sizing: PoolSizing) {
+    GATE.configure(sizing);
+    START.call_once(|| {
+        tokio::spawn(async {
+            loop {
+                tokio::time::sleep(ADJUSTMENT_INTERVAL).await;
+                DB_POOL_LIMIT.set(GATE.adjust() as i64);
+                for (name, pool) in POOLS.lock().unwrap().iter() {
+                    let idle = pool.num_idle() as i64;
+                    DB_POOL_CONNECTIONS
+                        .with_label_values(&[name, "in_use"])
+                        .set(pool.size() as i64 - idle);
+                    DB_POOL_CONNECTIONS
+                        .with_label_values(&[name, "idle"])
+                        .set(idle);
+                }
+            }
+        });
+    });
+}
+
+/// Reports the connections of `pool` under `name`.
+pub fn monitor(name: &'static str, pool: &PgPool) {
+    POOLS.lock().unwrap().push((name, pool.clone()));
+}
+
+/// Runs a database query once its turn comes, the writes going before the reads.
+pub async fn prioritized<T>(priority: Priority, future: impl Future<Output = T>) -> T {
+    GATE.run(priority, future).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::sync::oneshot;
+    use tokio::time::timeout;
+
+    use super::*;
+
+    fn sizing(min_connections: u32, max_connections: u32) -> PoolSizing {
+        PoolSizing {
+            min_connections,
+            max_connections,
+            target_acquire_wait: Duration::from_millis(10),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_next_limit() {
+        let sizing = sizing(5, 20);
+        let slow = Duration::from_millis(200);
+        let fast = Duration::from_millis(1);
+        assert_eq!(next_limit(&sizing, 5, slow, 5), 6);
+        assert_eq!(next_limit(&sizing, 16, slow, 16), 20);
+        assert_eq!(next_limit(&sizing, 20, slow, 20), 20);
+        // Busy but not waiting
+        assert_eq!(next_limit(&sizing, 10, fast, 8), 10);
+        assert_eq!(next_limit(&sizing, 10, fast, 2), 9);
+        assert_eq!(next_limit(&sizing, 5, fast, 0), 5);
+    }
+
+    #[test]
+    fn test_read_limit() {
+        for (limit, reads) in (1..=5).zip([0, 1, 2, 3, 4]) {
+            assert_eq!(read_limit(limit), reads, "limit {limit}");
+        }
+        assert_eq!(read_limit(10), 8);
+    }
+
+    #[tokio::test]
+    async fn test_writes_before_reads() {
+        let gate = Arc::new(Gate::new(sizing(5, 20)));
+        assert_eq!(read_limit(5), 4);
+
+        // The reads holding all the slots they may take
+        let mut reads = Vec::new();
+        for _ in 0..4 {
+            let (release, released) = oneshot::channel::<()>();
+            let gate = gate.clone();
+            reads.push(release);
+            tokio::spawn(async move {
+                gate.run(Priority::Read, released).await.ok();
+            });
+        }
+        while gate.state.lock().unwrap().in_use < 4 {
+            tokio::task::yield_now().await;
+        }
+
+        let read = gate.run(Priority::Read, async {});
+        assert!(timeout(Duration::from_millis(50), read).await.is_err());
+        let write = gate.run(Priority::Write, async {});
+        assert!(timeout(Duration::from_millis(50), write).await.is_ok());
+
+        // Raised once the reads waited longer than the target
+        assert_eq!(gate.adjust(), 6);
+        assert_eq!(read_limit(6), 5);
+        let read = gate.run(Priority::Read, async {});
+        assert!(timeout(Duration::from_millis(50), read).await.is_ok());
+
+        drop(reads);
+    }
+}
@@ -0,0 +1,776 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Checks of everything the indexer-service and the tap-agent depend on, run by their `doctor`
+//! command before deploying them: the database, graph-node, the network and escrow subgraphs,
+//! the sender aggregators, the operator key and the EIP-712 domain. Some of them are also run
+//! on startup, see [startup_check].
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use alloy_primitives::Address;
+use alloy_sol_types::Eip712Domain;
+use anyhow::bail;
+use ethers::signers::Signer;
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::{migrate::Migrator, postgres::PgPoolOptions, PgPool, Row};
+use thegraph::types::DeploymentId;
+use tracing::{error, info, warn};
+
+use crate::address::build_wallet;
+use crate::schema_drift;
+
+/// Migrations of the schema the indexer-service and the tap-agent were built against.
+pub static MIGRATOR: Migrator = sqlx::migrate!("../migrations");
+
+/// Subgraphs whose latest block is older than this are reported as lagging.
+pub const MAX_SUBGRAPH_LAG: Duration = Duration::from_secs(30 * 60);
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CheckStatus {
+    Ok,
+    Warning,
+    Failed,
+}
+
+impl Display for CheckStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckStatus::Ok => write!(f, "OK"),
+            CheckStatus::Warning => write!(f, "WARN"),
+            CheckStatus::Failed => write!(f, "FAIL"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheckResult {
+    pub name: String,
+    pub status: CheckStatus,
+    pub details: String,
+}
+
+impl CheckResult {
+    pub fn ok(name: impl Into<String>, details: impl Into<String>) -> Self {
+        Self::new(name, CheckStatus::Ok, details)
+    }
+
+    pub fn warning(name: impl Into<String>, details: impl Into<String>) -> Self {
+        Self::new(name, CheckStatus::Warning, details)
+    }
+
+    pub fn failed(name: impl Into<String>, details: impl Into<String>) -> Self {
+        Self::new(name, CheckStatus::Failed, details)
+    }
+
+    fn new(name: impl Into<String>, status: CheckStatus, details: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            status,
+            details: details.into(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct DoctorReport {
+    pub checks: Vec<CheckResult>,
+}
+
+impl DoctorReport {
+    pub fn push(&mut self, check: CheckResult) {
+        self.checks.push(check);
+    }
+
+    pub fn status(&self) -> CheckStatus {
+        self.checks
+            .iter()
+            .map(|check| check.status)
+            .max()
+            .unwrap_or(CheckStatus::Ok)
+    }
+
+    /// Exit code for automation: `0` if all checks passed, `1` if some only raised warnings and
+    /// `2` if any failed.
+    pub fn exit_code(&self) -> i32 {
+        match self.status() {
+            CheckStatus::Ok => 0,
+            CheckStatus::Warning => 1,
+            CheckStatus::Failed => 2,
+        }
+    }
+}
+
+impl Display for DoctorReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for check in &self.checks {
+            writeln!(f, "[{:>4}] {}: {}", check.status, check.name, check.details)?;
+        }
+        let count = |status| {
+            self.checks
+                .iter()
+                .filter(|check| check.status == status)
+                .count()
+        };
+        write!(
+            f,
+            "{} passed, {} warnings, {} failed",
+            count(CheckStatus::Ok),
+            count(CheckStatus::Warning),
+            count(CheckStatus::Failed)
+        )
+    }
+}
+
+/// Connects to the database and checks that the migrations the binary was built against were
+/// applied, and that the tables holding funds didn't drift, see [schema_drift].
+pub async fn check_database(postgres_url: &str) -> CheckResult {
+    let pgpool = match PgPoolOptions::new()
+        .max_connections(1)
+        .acquire_timeout(REQUEST_TIMEOUT)
+        .connect(postgres_url)
+        .await
+    {
+        Ok(pgpool) => pgpool,
+        Err(e) => return CheckResult::failed("database", format!("cannot connect: {}", e)),
+    };
+    let check = check_schema(&pgpool, &MIGRATOR).await;
+    if check.status == CheckStatus::Failed {
+        return check;
+    }
+    match schema_drift::detect(&pgpool).await {
+        Ok(drifts) if drifts.is_empty() => check,
+        Ok(drifts) => CheckResult::failed(
+            "database",
+            format!(
+                "schema drifted, the writes of funds would be refused: {}",
+                drifts
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join("; ")
+            ),
+        ),
+        Err(e) => CheckResult::failed("database", format!("cannot read schema: {}", e)),
+    }
+}
+
+/// Compares the migrations applied to the database, in `_sqlx_migrations`, with the ones of
+/// `migrator`. Fails on the missing, failed or modified migrations, and warns about the ones
+/// unknown to `migrator`, applied by a newer release.
+pub async fn check_schema(pgpool: &PgPool, migrator: &Migrator) -> CheckResult {
+    let applied = match sqlx::query(
+        "SELECT version, success, checksum FROM _sqlx_migrations ORDER BY version",
+    )
+    .fetch_all(pgpool)
+    .await
+    {
+        Ok(applied) => applied,
+        Err(e) => return CheckResult::failed("database", format!("cannot read migrations: {}", e)),
+    };
+    let mut applied = match applied
+        .iter()
+        .map(|row| {
+            Ok((
+                row.try_get::<i64, _>("version")?,
+                (
+                    row.try_get::<bool, _>("success")?,
+                    row.try_get::<Vec<u8>, _>("checksum")?,
+                ),
+            ))
+        })
+        .collect::<Result<BTreeMap<_, _>, sqlx::Error>>()
+    {
+        Ok(applied) => applied,
+        Err(e) => return CheckResult::failed("database", format!("cannot read migrations: {}", e)),
+    };
+
+    let (mut missing, mut failed, mut modified) = (Vec::new(), Vec::new(), Vec::new());
+    let migrations = migrator
+        .iter()
+        .filter(|migration| !migration.migration_type.is_down_migration());
+    for migration in migrations.clone() {
+        let name = format!("{} ({})", migration.version, migration.description);
+        match applied.remove(&migration.version) {
+            None => missing.push(name),
+            Some((false, _)) => failed.push(name),
+            Some((true, checksum)) if checksum != *migration.checksum => modified.push(name),
+            Some(_) => {}
+        }
+    }
+
+    let problems: Vec<String> = [
+        ("missing migrations", missing),
+        ("failed migrations", failed),
+        ("modified migrations", modified),
+    ]
+    .into_iter()
+    .filter(|(_, migrations)| !migrations.is_empty())
+    .map(|(problem, migrations)| format!("{}: {}", problem, migrations.join(", ")))
+    .collect();
+    if !problems.is_empty() {
+        return CheckResult::failed(
+            "database",
+            format!("schema is not up to date, {}", problems.join("; ")),
+        );
+    }
+    if !applied.is_empty() {
+        return CheckResult::warning(
+            "database",
+            format!(
+                "connected, unknown migrations applied by a newer release: {}",
+                applied
+                    .keys()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        );
+    }
+    CheckResult::ok(
+        "database",
+        format!("connected, all {} migrations applied", migrations.count()),
+    )
+}
+
+pub async fn check_graph_node(http_client: &reqwest::Client, status_url: &str) -> CheckResult {
+    #[derive(Deserialize)]
+    struct Version {
+        version: String,
+    }
+    #[derive(Deserialize)]
+    struct Data {
+        version: Version,
+    }
+
+    match graphql::<Data>(http_client, status_url, None, "{ version { version } }").await {
+        Ok(data) => CheckResult::ok(
+            "graph-node",
+            format!("reachable, version {}", data.version.version),
+        ),
+        Err(e) => CheckResult::failed("graph-node", e),
+    }
+}
+
+/// Checks that a subgraph answers queries and that its latest block is recent.
+pub async fn check_subgraph(
+    http_client: &reqwest::Client,
+    name: &str,
+    query_url: &str,
+    auth_token: Option<&str>,
+) -> CheckResult {
+    #[derive(Deserialize)]
+    struct Block {
+        number: u64,
+        timestamp: Option<u64>,
+    }
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Meta {
+        block: Block,
+        has_indexing_errors: bool,
+    }
+    #[derive(Deserialize)]
+    struct Data {
+        #[serde(rename = "_meta")]
+        meta: Meta,
+    }
+
+    let name = format!("{} subgraph", name);
+    let meta = match graphql::<Data>(
+        http_client,
+        query_url,
+        auth_token,
+        "{ _meta { block { number timestamp } hasIndexingErrors } }",
+    )
+    .await
+    {
+        Ok(data) => data.meta,
+        Err(e) => return CheckResult::failed(name, e),
+    };
+
+    if meta.has_indexing_errors {
+        return CheckResult::failed(
+            name,
+            format!("has indexing errors at block {}", meta.block.number),
+        );
+    }
+    let Some(timestamp) = meta.block.timestamp else {
+        return CheckResult::ok(
+            name,
+            format!("at block {}, block timestamp unknown", meta.block.number),
+        );
+    };
+    let lag = subgraph_lag(timestamp);
+    let details = format!("at block {}, {}s behind", meta.block.number, lag.as_secs());
+    if lag > MAX_SUBGRAPH_LAG {
+        CheckResult::warning(name, details)
+    } else {
+        CheckResult::ok(name, details)
+    }
+}
+
+fn subgraph_lag(block_timestamp: u64) -> Duration {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    now.saturating_sub(Duration::from_secs(block_timestamp))
+}
+
+/// Name of the network of a chain id in the subgraph manifests.
+pub fn network_name(chain_id: u64) -> Option<&'static str> {
+    match chain_id {
+        1 => Some("mainnet"),
+        5 => Some("goerli"),
+        11155111 => Some("sepolia"),
+        42161 => Some("arbitrum-one"),
+        421613 => Some("arbitrum-goerli"),
+        421614 => Some("arbitrum-sepolia"),
+        1337 => Some("hardhat"),
+        _ => None,
+    }
+}
+
+/// Checks that the escrow subgraph indexes the network of the chain id of the receipts
+/// verifier, according to graph-node. The deployment is asked to the subgraph if not given.
+/// Only warns when the network can't be found out, e.g. if graph-node doesn't index the
+/// subgraph itself.
+pub async fn check_escrow_network(
+    http_client: &reqwest::Client,
+    status_url: Option<&str>,
+    query_url: &str,
+    auth_token: Option<&str>,
+    deployment: Option<DeploymentId>,
+    chain_id: u64,
+) -> CheckResult {
+    #[derive(Deserialize)]
+    struct Meta {
+        deployment: DeploymentId,
+    }
+    #[derive(Deserialize)]
+    struct MetaData {
+        #[serde(rename = "_meta")]
+        meta: Meta,
+    }
+    #[derive(Deserialize)]
+    struct Chain {
+        network: String,
+    }
+    #[derive(Deserialize)]
+    struct IndexingStatus {
+        chains: Vec<Chain>,
+    }
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct StatusData {
+        indexing_statuses: Vec<IndexingStatus>,
+    }
+
+    let name = "escrow subgraph network";
+    let Some(expected) = network_name(chain_id) else {
+        return CheckResult::warning(name, format!("no known network for chain id {chain_id}"));
+    };
+    let Some(status_url) = status_url else {
+        return CheckResult::warning(name, "unknown, graph-node's status endpoint isn't set");
+    };
+    let deployment = match deployment {
+        Some(deployment) => deployment,
+        None => match graphql::<MetaData>(
+            http_client,
+            query_url,
+            auth_token,
+            "{ _meta { deployment } }",
+        )
+        .await
+        {
+            Ok(data) => data.meta.deployment,
+            Err(e) => return CheckResult::warning(name, format!("unknown deployment, {e}")),
+        },
+    };
+    let networks: Vec<String> = match graphql::<StatusData>(
+        http_client,
+        status_url,
+        None,
+        &format!(
+            "{{ indexingStatuses(subgraphs: [\"{deployment}\"]) {{ chains {{ network }} }} }}"
+        ),
+    )
+    .await
+    {
+        Ok(data) => data
+            .indexing_statuses
+            .into_iter()
+            .flat_map(|status| status.chains)
+            .map(|chain| chain.network)
+            .collect(),
+        Err(e) => return CheckResult::warning(name, format!("unknown, {e}")),
+    };
+
+    match networks.first() {
+        None => CheckResult::warning(
+            name,
+            format!("unknown, graph-node doesn't index the escrow subgraph {deployment}"),
+        ),
+        Some(network) if network == expected => {
+            CheckResult::ok(name, format!("{deployment} indexes {network}"))
+        }
+        Some(network) => CheckResult::failed(
+            name,
+            format!(
+                "{deployment} indexes {network}, but the receipts verifier is on chain id \
+                 {chain_id} ({expected})"
+            ),
+        ),
+    }
+}
+
+/// Logs the result of a check run on startup. Fails on a failed check, unless `allow_failure`.
+pub fn startup_check(check: CheckResult, allow_failure: bool) -> anyhow::Result<()> {
+    match check.status {
+        CheckStatus::Ok => info!("{}: {}", check.name, check.details),
+        CheckStatus::Warning => warn!("{}: {}", check.name, check.details),
+        CheckStatus::Failed if allow_failure => error!(
+            "{}: {}. Starting anyway, as allowed by the configuration",
+            check.name, check.details
+        ),
+        CheckStatus::Failed => bail!("{}: {}", check.name, check.details),
+    }
+    Ok(())
+}
+
+/// Checks that the operator key is valid and that the operator is allowed to act for the
+/// indexer, according to the network subgraph.
+pub async fn check_operator_key(
+    http_client: &reqwest::Client,
+    operator_mnemonic: &str,
+    indexer_address: Address,
+    network_subgraph_url: &str,
+    network_subgraph_auth_token: Option<&str>,
+) -> CheckResult {
+    #[derive(Deserialize)]
+    struct Operator {
+        id: Address,
+    }
+    #[derive(Deserialize)]
+    struct GraphAccount {
+        operators: Vec<Operator>,
+    }
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Data {
+        graph_account: Option<GraphAccount>,
+    }
+
+    let operator = match build_wallet(operator_mnemonic) {
+        Ok(wallet) => Address::from(wallet.address().to_fixed_bytes()),
+        Err(e) => return CheckResult::failed("operator key", format!("invalid: {}", e)),
+    };
+    if operator == indexer_address {
+        return CheckResult::ok(
+            "operator key",
+            format!("derives the indexer address {}", operator),
+        );
+    }
+
+    let query = format!(
+        r#"{{ graphAccount(id: "{}") {{ operators {{ id }} }} }}"#,
+        indexer_address.to_string().to_lowercase()
+    );
+    match graphql::<Data>(
+        http_client,
+        network_subgraph_url,
+        network_subgraph_auth_token,
+        &query,
+    )
+    .await
+    {
+        Ok(Data {
+            graph_account: Some(account),
+        }) if account.operators.iter().any(|o| o.id == operator) => CheckResult::ok(
+            "operator key",
+            format!("operator {} is authorized by the indexer", operator),
+        ),
+        Ok(_) => CheckResult::failed(
+            "operator key",
+            format!(
+                "operator {} is not authorized by the indexer {}",
+                operator, indexer_address
+            ),
+        ),
+        Err(e) => CheckResult::warning(
+            "operator key",
+            format!(
+                "derives operator {}, cannot check its authorization: {}",
+                operator, e
+            ),
+        ),
+    }
+}
+
+pub fn check_eip712_domain(domain: &Eip712Domain) -> CheckResult {
+    match (domain.chain_id, domain.verifying_contract) {
+        (None, _) => CheckResult::failed("EIP-712 domain", "no chain id"),
+        (Some(chain_id), _) if chain_id.is_zero() => {
+            CheckResult::failed("EIP-712 domain", "the chain id is zero")
+        }
+        (_, None) => CheckResult::failed("EIP-712 domain", "no verifying contract"),
+        (_, Some(verifying_contract)) if verifying_contract.is_zero() => CheckResult::failed(
+            "EIP-712 domain",
+            "the verifying contract is the zero address",
+        ),
+        (Some(chain_id), Some(verifying_contract)) => CheckResult::ok(
+            "EIP-712 domain",
+            format!(
+                "chain id {}, verifying contract {}, separator {}",
+                chain_id,
+                verifying_contract,
+                domain.separator()
+            ),
+        ),
+    }
+}
+
+/// Checks that a sender's aggregator answers JSON-RPC requests.
+pub async fn check_aggregator(
+    http_client: &reqwest::Client,
+    sender: Address,
+    url: &str,
+) -> CheckResult {
+    let name = format!("aggregator of {}", sender);
+    let response = http_client
+        .post(url)
+        .timeout(REQUEST_TIMEOUT)
+        .json(&json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "api_versions",
+            "params": [],
+        }))
+        .send()
+        .await;
+    match response {
+        Ok(response) if response.status().is_success() => {
+            CheckResult::ok(name, format!("reachable at {}", url))
+        }
+        Ok(response) => CheckResult::failed(
+            name,
+            format!("{} answered with status {}", url, response.status()),
+        ),
+        Err(e) => CheckResult::failed(name, format!("cannot reach {}: {}", url, e)),
+    }
+}
+
+async fn graphql<T: for<'de> Deserialize<'de>>(
+    http_client: &reqwest::Client,
+    url: &str,
+    auth_token: Option<&str>,
+    query: &str,
+) -> Result<T, String> {
+    #[derive(Deserialize)]
+    struct Response<T> {
+        data: Option<T>,
+        errors: Option<Vec<serde_json::Value>>,
+    }
+
+    let mut request = http_client
+        .post(url)
+        .timeout(REQUEST_TIMEOUT)
+        .json(&json!({ "query": query }));
+    if let Some(token) = auth_token {
+        request = request.bearer_auth(token);
+    }
+    let response = request
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| format!("cannot reach {}: {}", url, e))?
+        .json::<Response<T>>()
+        .await
+        .map_err(|e| format!("invalid response from {}: {}", url, e))?;
+    match (response.data, response.errors) {
+        (Some(data), None) => Ok(data),
+        (_, Some(errors)) => Err(format!("query to {} failed: {:?}", url, errors)),
+        (None, None) => Err(format!("empty response from {}", url)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use alloy_sol_types::eip712_domain;
+    use wiremock::{
+        matchers::{method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use crate::test_vectors::TAP_EIP712_DOMAIN;
+
+    use super::*;
+
+    #[test]
+    fn test_report_exit_code() {
+        let mut report = DoctorReport::default();
+        assert_eq!(report.exit_code(), 0);
+        report.push(CheckResult::ok("a", ""));
+        assert_eq!(report.exit_code(), 0);
+        report.push(CheckResult::warning("b", ""));
+        assert_eq!(report.exit_code(), 1);
+        report.push(CheckResult::failed("c", ""));
+        report.push(CheckResult::ok("d", ""));
+        assert_eq!(report.exit_code(), 2);
+        assert!(report
+            .to_string()
+            .ends_with("2 passed, 1 warnings, 1 failed"));
+    }
+
+    #[test]
+    fn test_check_eip712_domain() {
+        assert_eq!(
+            check_eip712_domain(&TAP_EIP712_DOMAIN).status,
+            CheckStatus::Ok
+        );
+        let domain = eip712_domain! {
+            name: "TAP",
+            version: "1",
+            chain_id: 1,
+            verifying_contract: Address::ZERO,
+        };
+        assert_eq!(check_eip712_domain(&domain).status, CheckStatus::Failed);
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_check_schema(pgpool: PgPool) {
+        assert_eq!(
+            check_schema(&pgpool, &MIGRATOR).await.status,
+            CheckStatus::Ok
+        );
+
+        // Applied by a newer release
+        sqlx::query(
+            r#"
+                INSERT INTO _sqlx_migrations (version, description, success, checksum, execution_time)
+                VALUES (99990101000000, 'newer', TRUE, '\x00', 0)
+            "#,
+        )
+        .execute(&pgpool)
+        .await
+        .unwrap();
+        let result = check_schema(&pgpool, &MIGRATOR).await;
+        assert_eq!(result.status, CheckStatus::Warning);
+        assert!(result.details.contains("99990101000000"));
+
+        let latest = MIGRATOR
+            .iter()
+            .filter(|migration| !migration.migration_type.is_down_migration())
+            .map(|migration| migration.version)
+            .max()
+            .unwrap();
+        sqlx::query("UPDATE _sqlx_migrations SET checksum = '\\x00' WHERE version = $1")
+            .bind(latest)
+            .execute(&pgpool)
+            .await
+            .unwrap();
+        let result = check_schema(&pgpool, &MIGRATOR).await;
+        assert_eq!(result.status, CheckStatus::Failed);
+        assert!(result.details.contains("modified migrations"));
+
+        sqlx::query("DELETE FROM _sqlx_migrations WHERE version = $1")
+            .bind(latest)
+            .execute(&pgpool)
+            .await
+            .unwrap();
+        let result = check_schema(&pgpool, &MIGRATOR).await;
+        assert_eq!(result.status, CheckStatus::Failed);
+        assert!(result.details.contains("missing migrations"));
+    }
+
+    #[tokio::test]
+    async fn test_check_subgraph() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/fresh"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "data": { "_meta": {
+                    "block": { "number": 100, "timestamp": SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() },
+                    "hasIndexingErrors": false,
+                } }
+            })))
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/stale"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "data": { "_meta": {
+                    "block": { "number": 100, "timestamp": 1 },
+                    "hasIndexingErrors": false,
+                } }
+            })))
+            .mount(&mock_server)
+            .await;
+
+        let http_client = reqwest::Client::new();
+        let check = |path: &'static str| {
+            let http_client = http_client.clone();
+            let url = format!("{}{}", mock_server.uri(), path);
+            async move {
+                check_subgraph(&http_client, "network", &url, None)
+                    .await
+                    .status
+            }
+        };
+        assert_eq!(check("/fresh").await, CheckStatus::Ok);
+        assert_eq!(check("/stale").await, CheckStatus::Warning);
+        assert_eq!(check("/missing").await, CheckStatus::Failed);
+    }
+
+    #[tokio::test]
+    async fn test_check_escrow_network() {
+        let deployment =
+            DeploymentId::from_str("Qmb5Ysp5oCUXhLA8NmxmYKDAX2nCMnh7Vvb5uffb9n5vss").unwrap();
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/escrow"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "data": { "_meta": { "deployment": deployment.to_string() } }
+            })))
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/status"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "data": { "indexingStatuses": [{ "chains": [{ "network": "arbitrum-sepolia" }] }] }
+            })))
+            .mount(&mock_server)
+            .await;
+
+        let http_client = reqwest::Client::new();
+        let status_url = format!("{}/status", mock_server.uri());
+        let escrow_url = format!("{}/escrow", mock_server.uri());
+        let check = |deployment, chain_id| {
+            check_escrow_network(
+                &http_client,
+                Some(status_url.as_str()),
+                &escrow_url,
+                None,
+                deployment,
+                chain_id,
+            )
+        };
+        assert_eq!(check(None, 421614).await.status, CheckStatus::Ok);
+        assert_eq!(
+            check(Some(deployment), 421614).await.status,
+            CheckStatus::Ok
+        );
+        assert_eq!(check(None, 42161).await.status, CheckStatus::Failed);
+        assert_eq!(check(None, 10).await.status, CheckStatus::Warning);
+
+        assert!(startup_check(check(None, 42161).await, true).is_ok());
+        assert!(startup_check(check(None, 42161).await, false).is_err());
+    }
+}
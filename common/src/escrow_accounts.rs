@@ -3,19 +3,28 @@
 
 use std::{
     collections::{HashMap, HashSet},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
     time::Duration,
 };
 
 use anyhow::Result;
 use ethers_core::types::U256;
-use eventuals::{timer, Eventual, EventualExt};
+use eventuals::{join, timer, Eventual, EventualExt};
 use serde::Deserialize;
+use serde_json::{json, Value};
+use sqlx::{types::BigDecimal, PgPool, Row};
 use thegraph::types::Address;
 use thiserror::Error;
-use tokio::time::sleep;
+use tokio::sync::Mutex;
 use tracing::{error, warn};
 
+use crate::monitor_backoff::Backoff;
 use crate::prelude::{Query, SubgraphClient};
+use crate::types::SenderAddress;
 
 #[derive(Error, Debug)]
 pub enum EscrowAccountsError {
@@ -27,13 +36,28 @@ pub enum EscrowAccountsError {
     NoSenderFound { signer: Address },
 }
 
-#[derive(Clone, Debug, Default, PartialEq, Eq)]
+/// Versions of the escrow accounts, unique in the process, see [EscrowAccounts::version].
+static NEXT_VERSION: AtomicU64 = AtomicU64::new(1);
+
+/// Cheap to clone, as the accounts are shared between the clones.
+#[derive(Clone, Debug, Default)]
 pub struct EscrowAccounts {
-    senders_balances: HashMap<Address, U256>,
-    signers_to_senders: HashMap<Address, Address>,
-    senders_to_signers: HashMap<Address, Vec<Address>>,
+    version: u64,
+    senders_balances: Arc<HashMap<Address, U256>>,
+    signers_to_senders: Arc<HashMap<Address, Address>>,
+    senders_to_signers: Arc<HashMap<Address, Vec<Address>>>,
+}
+
+/// Compares the accounts, regardless of their versions.
+impl PartialEq for EscrowAccounts {
+    fn eq(&self, other: &Self) -> bool {
+        self.senders_balances == other.senders_balances
+            && self.senders_to_signers == other.senders_to_signers
+    }
 }
 
+impl Eq for EscrowAccounts {}
+
 impl EscrowAccounts {
     pub fn new(
         senders_balances: HashMap<Address, U256>,
@@ -45,12 +69,20 @@ impl EscrowAccounts {
             .collect();
 
         Self {
-            senders_balances,
-            signers_to_senders,
-            senders_to_signers,
+            version: NEXT_VERSION.fetch_add(1, Ordering::Relaxed),
+            senders_balances: Arc::new(senders_balances),
+            signers_to_senders: Arc::new(signers_to_senders),
+            senders_to_signers: Arc::new(senders_to_signers),
         }
     }
 
+    /// Stamp of these accounts, bumped on every update: accounts of the same version are the
+    /// same, while accounts of different versions may still be equal. Zero for the default
+    /// accounts.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
     pub fn get_signers_for_sender(&self, sender: &Address) -> Vec<Address> {
         self.senders_to_signers
             .get(sender)
@@ -86,148 +118,436 @@ impl EscrowAccounts {
     pub fn get_senders(&self) -> HashSet<Address> {
         self.senders_balances.keys().copied().collect()
     }
+
+    /// Replaces the balances and signers of the overridden senders.
+    pub fn with_overrides(&self, overrides: &[EscrowAccountOverride]) -> Self {
+        let mut senders_balances = (*self.senders_balances).clone();
+        let mut senders_to_signers = (*self.senders_to_signers).clone();
+        for escrow_override in overrides {
+            if let Some(balance) = escrow_override.balance {
+                senders_balances.insert(escrow_override.sender, balance);
+            }
+            if let Some(signers) = &escrow_override.signers {
+                senders_to_signers.insert(escrow_override.sender, signers.clone());
+            }
+        }
+        Self::new(senders_balances, senders_to_signers)
+    }
+}
+
+/// Balance and signers of a sender set by the operator, in place of the escrow subgraph's.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EscrowAccountOverride {
+    pub sender: Address,
+    pub balance: Option<U256>,
+    pub signers: Option<Vec<Address>>,
+}
+
+/// The overrides that have not expired yet.
+pub async fn active_escrow_account_overrides(
+    pgpool: &PgPool,
+) -> Result<Vec<EscrowAccountOverride>> {
+    let rows = sqlx::query(
+        r#"
+            SELECT sender_address, balance, signers
+            FROM escrow_account_overrides
+            WHERE expires_at > NOW()
+        "#,
+    )
+    .fetch_all(pgpool)
+    .await?;
+
+    rows.iter()
+        .map(|row| {
+            Ok(EscrowAccountOverride {
+                sender: row.try_get::<SenderAddress, _>("sender_address")?.0,
+                balance: row
+                    .try_get::<Option<BigDecimal>, _>("balance")?
+                    .map(|balance| U256::from_dec_str(&balance.to_string()))
+                    .transpose()?,
+                signers: row
+                    .try_get::<Option<Vec<String>>, _>("signers")?
+                    .map(|signers| {
+                        signers
+                            .iter()
+                            .map(|signer| Address::from_str(signer))
+                            .collect::<Result<Vec<_>, _>>()
+                    })
+                    .transpose()?,
+            })
+        })
+        .collect()
+}
+
+/// Applies the operator's overrides to the escrow accounts, checking for new and expired
+/// overrides every `interval`.
+pub fn escrow_accounts_with_overrides(
+    escrow_accounts: Eventual<EscrowAccounts>,
+    pgpool: PgPool,
+    interval: Duration,
+) -> Eventual<EscrowAccounts> {
+    let backoff = Backoff::new("escrow_account_overrides", interval);
+    let retry_backoff = backoff.clone();
+    let overrides = timer(interval).map_with_retry(
+        move |_| {
+            let pgpool = pgpool.clone();
+            let backoff = backoff.clone();
+            async move {
+                backoff.track(
+                    active_escrow_account_overrides(&pgpool)
+                        .await
+                        .map_err(|e| e.to_string()),
+                )
+            }
+        },
+        move |err: String| {
+            error!("Failed to fetch the escrow account overrides: {}", err);
+            retry_backoff.failed(&err)
+        },
+    );
+
+    join((escrow_accounts, overrides)).map(|(escrow_accounts, overrides)| async move {
+        if overrides.is_empty() {
+            return escrow_accounts;
+        }
+        warn!(
+            senders = ?overrides.iter().map(|o| o.sender).collect::<Vec<_>>(),
+            "Escrow accounts are overridden by the operator"
+        );
+        escrow_accounts.with_overrides(&overrides)
+    })
 }
 
+/// Escrow accounts of the indexer, synced from the escrow subgraph every `interval`.
+///
+/// The accounts and the signers are queried `page_size` at a time, by increasing id and at the
+/// block of the first page, so that they aren't cut at the subgraph's limit on the number of
+/// entities per query. A page still failing after retries doesn't fail the whole sync: the
+/// entities after the last one fetched are kept from the previous sync, if any.
 pub fn escrow_accounts(
     escrow_subgraph: &'static SubgraphClient,
     indexer_address: Address,
     interval: Duration,
     reject_thawing_signers: bool,
+    page_size: usize,
 ) -> Eventual<EscrowAccounts> {
-    // Types for deserializing the network subgraph response
-    #[derive(Deserialize)]
-    #[serde(rename_all = "camelCase")]
-    struct EscrowAccountsResponse {
-        escrow_accounts: Vec<EscrowAccount>,
-    }
-    // Note that U256's serde implementation is based on serializing the internal bytes, not the string decimal
-    // representation. This is why we deserialize them as strings below.
-    #[derive(Deserialize)]
-    #[serde(rename_all = "camelCase")]
-    struct EscrowAccount {
-        balance: String,
-        total_amount_thawing: String,
-        sender: Sender,
-    }
-    #[derive(Deserialize)]
-    #[serde(rename_all = "camelCase")]
-    struct Sender {
-        id: Address,
-        signers: Vec<Signer>,
+    let previous = Arc::new(Mutex::new(None));
+    let backoff = Backoff::new("escrow_accounts", interval);
+    let retry_backoff = backoff.clone();
+
+    timer(interval).map_with_retry(
+        move |_| {
+            let previous = previous.clone();
+            let backoff = backoff.clone();
+            async move {
+                let mut previous = previous.lock().await;
+                backoff.track(
+                    fetch_escrow_accounts(
+                        escrow_subgraph,
+                        indexer_address,
+                        reject_thawing_signers,
+                        page_size,
+                        &mut previous,
+                    )
+                    .await,
+                )
+            }
+        },
+        move |err: String| {
+            error!(
+                "Failed to fetch escrow accounts for indexer {:?}: {}",
+                indexer_address, err
+            );
+
+            retry_backoff.failed(&err)
+        },
+    )
+}
+
+const PAGE_RETRIES: usize = 2;
+
+const ESCROW_ACCOUNTS_QUERY: &str = r#"
+    query ($indexer: ID!, $block: Block_height, $first: Int!, $last: String!) {
+        meta: _meta(block: $block) {
+            block {
+                number
+            }
+        }
+        page: escrowAccounts(
+            block: $block
+            first: $first
+            orderBy: id
+            orderDirection: asc
+            where: {receiver_: {id: $indexer}, id_gt: $last}
+        ) {
+            id
+            balance
+            totalAmountThawing
+            sender {
+                id
+            }
+        }
     }
-    #[derive(Deserialize)]
-    #[serde(rename_all = "camelCase")]
-    struct Signer {
-        id: Address,
+"#;
+
+// thawEndTimestamp == 0 means that the signer is not thawing. This also means
+// that we don't wait for the thawing period to end before stopping serving
+// queries for this signer.
+// isAuthorized == true means that the signer is still authorized to sign
+// payments in the name of the sender.
+const NON_THAWING_SIGNERS_QUERY: &str = r#"
+    query ($block: Block_height, $first: Int!, $last: String!) {
+        meta: _meta(block: $block) {
+            block {
+                number
+            }
+        }
+        page: signers(
+            block: $block
+            first: $first
+            orderBy: id
+            orderDirection: asc
+            where: {thawEndTimestamp: "0", isAuthorized: true, id_gt: $last}
+        ) {
+            id
+            sender {
+                id
+            }
+        }
     }
+"#;
 
-    // thawEndTimestamp == 0 means that the signer is not thawing. This also means
-    // that we don't wait for the thawing period to end before stopping serving
-    // queries for this signer.
-    // isAuthorized == true means that the signer is still authorized to sign
-    // payments in the name of the sender.
-    let query = if reject_thawing_signers {
-        r#"
-        query ($indexer: ID!) {
-            escrowAccounts(where: {receiver_: {id: $indexer}}) {
-                balance
-                totalAmountThawing
-                sender {
-                    id
-                    signers(
-                        where: {thawEndTimestamp: "0", isAuthorized: true}
-                    ) {
-                        id
-                    }
-                }
+const SIGNERS_QUERY: &str = r#"
+    query ($block: Block_height, $first: Int!, $last: String!) {
+        meta: _meta(block: $block) {
+            block {
+                number
             }
         }
-    "#
-    } else {
-        r#"
-        query ($indexer: ID!) {
-            escrowAccounts(where: {receiver_: {id: $indexer}}) {
-                balance
-                totalAmountThawing
-                sender {
-                    id
-                    signers(
-                        where: {isAuthorized: true}
-                    ) {
-                        id
-                    }
-                }
+        page: signers(
+            block: $block
+            first: $first
+            orderBy: id
+            orderDirection: asc
+            where: {isAuthorized: true, id_gt: $last}
+        ) {
+            id
+            sender {
+                id
             }
         }
-    "#
+    }
+"#;
+
+// Types for deserializing the escrow subgraph responses
+#[derive(Deserialize)]
+struct Page<T> {
+    meta: Meta,
+    page: Vec<T>,
+}
+#[derive(Deserialize)]
+struct Meta {
+    block: Block,
+}
+#[derive(Deserialize)]
+struct Block {
+    number: u64,
+}
+// Note that U256's serde implementation is based on serializing the internal bytes, not the string decimal
+// representation. This is why we deserialize them as strings below.
+#[derive(Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EscrowAccount {
+    id: String,
+    balance: String,
+    total_amount_thawing: String,
+    sender: Entity,
+}
+#[derive(Clone, Deserialize)]
+struct Signer {
+    id: String,
+    sender: Entity,
+}
+#[derive(Clone, Deserialize)]
+struct Entity {
+    id: Address,
+}
+
+trait Paged {
+    fn id(&self) -> &str;
+}
+
+impl Paged for EscrowAccount {
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl Paged for Signer {
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+/// Entities of the last sync, to fall back on for the pages that fail to be fetched.
+#[derive(Default)]
+struct EscrowSnapshot {
+    accounts: Vec<EscrowAccount>,
+    signers: Vec<Signer>,
+}
+
+async fn fetch_escrow_accounts(
+    escrow_subgraph: &'static SubgraphClient,
+    indexer_address: Address,
+    reject_thawing_signers: bool,
+    page_size: usize,
+    previous: &mut Option<EscrowSnapshot>,
+) -> Result<EscrowAccounts, String> {
+    let indexer = format!("{:x?}", indexer_address);
+    let accounts = fetch_all_pages::<EscrowAccount>(
+        escrow_subgraph,
+        ESCROW_ACCOUNTS_QUERY,
+        vec![("indexer", indexer.into())],
+        page_size,
+    )
+    .await;
+    let signers_query = if reject_thawing_signers {
+        NON_THAWING_SIGNERS_QUERY
+    } else {
+        SIGNERS_QUERY
     };
+    let signers =
+        fetch_all_pages::<Signer>(escrow_subgraph, signers_query, vec![], page_size).await;
 
-    timer(interval).map_with_retry(
-        move |_| async move {
-            let response = escrow_subgraph
-                .query::<EscrowAccountsResponse>(Query::new_with_variables(
-                    query,
-                    [("indexer", format!("{:x?}", indexer_address).into())],
-                ))
+    let snapshot = EscrowSnapshot {
+        accounts: complete_with_previous(accounts, previous.as_ref().map(|p| &p.accounts[..]))?,
+        signers: complete_with_previous(signers, previous.as_ref().map(|p| &p.signers[..]))?,
+    };
+    let escrow_accounts = snapshot.to_escrow_accounts()?;
+    *previous = Some(snapshot);
+    Ok(escrow_accounts)
+}
+
+impl EscrowSnapshot {
+    fn to_escrow_accounts(&self) -> Result<EscrowAccounts, String> {
+        let senders_balances = self
+            .accounts
+            .iter()
+            .map(|account| {
+                let balance = U256::checked_sub(
+                    U256::from_dec_str(&account.balance)?,
+                    U256::from_dec_str(&account.total_amount_thawing)?,
+                )
+                .unwrap_or_else(|| {
+                    warn!(
+                        "Balance minus total amount thawing underflowed for account {}. \
+                             Setting balance to 0, no queries will be served for this sender.",
+                        account.sender.id
+                    );
+                    U256::from(0)
+                });
+
+                Ok((account.sender.id, balance))
+            })
+            .collect::<Result<HashMap<_, _>, anyhow::Error>>()
+            .map_err(|e| format!("{}", e))?;
+
+        let mut senders_to_signers: HashMap<Address, Vec<Address>> = senders_balances
+            .keys()
+            .map(|sender| (*sender, Vec::new()))
+            .collect();
+        for signer in &self.signers {
+            // Signers of senders without an account with the indexer are not relevant
+            if let Some(signers) = senders_to_signers.get_mut(&signer.sender.id) {
+                signers.push(Address::from_str(&signer.id).map_err(|e| e.to_string())?);
+            }
+        }
+
+        Ok(EscrowAccounts::new(senders_balances, senders_to_signers))
+    }
+}
+
+/// All the entities of a paginated `query`. On a page failing, the entities fetched until then
+/// are returned along with the error.
+async fn fetch_all_pages<T>(
+    escrow_subgraph: &'static SubgraphClient,
+    query: &'static str,
+    variables: Vec<(&'static str, Value)>,
+    page_size: usize,
+) -> Result<Vec<T>, (Vec<T>, String)>
+where
+    T: Paged + for<'de> Deserialize<'de>,
+{
+    let mut entities: Vec<T> = Vec::new();
+    let mut block = Value::Null;
+    loop {
+        let last = entities
+            .last()
+            .map(|e| e.id().to_string())
+            .unwrap_or_default();
+        let mut page_variables = variables.clone();
+        page_variables.extend([
+            ("block", block.clone()),
+            ("first", page_size.into()),
+            ("last", last.into()),
+        ]);
+
+        let mut attempt = 0;
+        let page = loop {
+            let result = escrow_subgraph
+                .query::<Page<T>>(Query::new_with_variables(query, page_variables.clone()))
                 .await
-                .map_err(|e| e.to_string())?;
+                .map_err(|e| e.to_string())
+                .and_then(|response| response);
+            match result {
+                Ok(page) => break page,
+                Err(e) if attempt >= PAGE_RETRIES => return Err((entities, e)),
+                Err(_) => attempt += 1,
+            }
+        };
 
-            let response = response.map_err(|e| e.to_string())?;
+        // Every page is fetched at the block of the first one
+        block = json!({ "number": page.meta.block.number });
+        let done = page.page.len() < page_size;
+        entities.extend(page.page);
+        if done {
+            return Ok(entities);
+        }
+    }
+}
 
-            let senders_balances = response
-                .escrow_accounts
-                .iter()
-                .map(|account| {
-                    let balance = U256::checked_sub(
-                        U256::from_dec_str(&account.balance)?,
-                        U256::from_dec_str(&account.total_amount_thawing)?,
-                    )
-                    .unwrap_or_else(|| {
-                        warn!(
-                            "Balance minus total amount thawing underflowed for account {}. \
-                                 Setting balance to 0, no queries will be served for this sender.",
-                            account.sender.id
-                        );
-                        U256::from(0)
-                    });
-
-                    Ok((account.sender.id, balance))
-                })
-                .collect::<Result<HashMap<_, _>, anyhow::Error>>()
-                .map_err(|e| format!("{}", e))?;
-
-            let senders_to_signers = response
-                .escrow_accounts
-                .iter()
-                .map(|account| {
-                    let sender = account.sender.id;
-                    let signers = account
-                        .sender
-                        .signers
-                        .iter()
-                        .map(|signer| signer.id)
-                        .collect();
-                    (sender, signers)
-                })
-                .collect();
-
-            Ok(EscrowAccounts::new(senders_balances, senders_to_signers))
-        },
-        move |err: String| {
-            error!(
-                "Failed to fetch escrow accounts for indexer {:?}: {}",
-                indexer_address, err
+/// The entities of a sync, completed with those of the `previous` sync after the last one
+/// fetched if a page failed.
+fn complete_with_previous<T: Paged + Clone>(
+    fetched: Result<Vec<T>, (Vec<T>, String)>,
+    previous: Option<&[T]>,
+) -> Result<Vec<T>, String> {
+    match (fetched, previous) {
+        (Ok(entities), _) => Ok(entities),
+        (Err((_, e)), None) => Err(e),
+        (Err((mut entities, e)), Some(previous)) => {
+            let last = entities.last().map(|e| e.id().to_string());
+            warn!(
+                error = %e,
+                fetched = entities.len(),
+                "Failed to fetch a page from the escrow subgraph, keeping the rest from the \
+                previous sync"
             );
-
-            sleep(interval.div_f32(2.0))
-        },
-    )
+            entities.extend(
+                previous
+                    .iter()
+                    .filter(|entity| last.as_deref().map_or(true, |last| entity.id() > last))
+                    .cloned(),
+            );
+            Ok(entities)
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use test_log::test;
-    use wiremock::matchers::{method, path};
+    use wiremock::matchers::{body_partial_json, body_string_contains, method};
     use wiremock::{Mock, MockServer, ResponseTemplate};
 
     use crate::prelude::DeploymentDetails;
@@ -243,14 +563,128 @@ mod tests {
         );
 
         assert_eq!(
-            escrow_accounts.signers_to_senders,
+            *escrow_accounts.signers_to_senders,
             test_vectors::ESCROW_ACCOUNTS_SIGNERS_TO_SENDERS.to_owned()
+        );
+
+        // Every update gets a new version, even if the accounts didn't change
+        let updated = EscrowAccounts::new(
+            test_vectors::ESCROW_ACCOUNTS_BALANCES.to_owned(),
+            test_vectors::ESCROW_ACCOUNTS_SENDERS_TO_SIGNERS.to_owned(),
+        );
+        assert_eq!(updated, escrow_accounts);
+        assert!(updated.version() > escrow_accounts.version());
+        assert_eq!(escrow_accounts.clone().version(), escrow_accounts.version());
+    }
+
+    #[test]
+    fn test_escrow_accounts_with_overrides() {
+        let escrow_accounts = EscrowAccounts::new(
+            test_vectors::ESCROW_ACCOUNTS_BALANCES.to_owned(),
+            test_vectors::ESCROW_ACCOUNTS_SENDERS_TO_SIGNERS.to_owned(),
+        );
+        let sender = *escrow_accounts.get_senders().iter().next().unwrap();
+        let signer = Address::from([0x42; 20]);
+
+        let overridden = escrow_accounts.with_overrides(&[EscrowAccountOverride {
+            sender,
+            balance: Some(U256::from(1234)),
+            signers: Some(vec![signer]),
+        }]);
+        assert_eq!(
+            overridden.get_balance_for_sender(&sender).unwrap(),
+            U256::from(1234)
+        );
+        assert_eq!(overridden.get_signers_for_sender(&sender), vec![signer]);
+        assert_eq!(overridden.get_sender_for_signer(&signer).unwrap(), sender);
+
+        // Only the balance is overridden
+        let overridden = escrow_accounts.with_overrides(&[EscrowAccountOverride {
+            sender,
+            balance: Some(U256::zero()),
+            signers: None,
+        }]);
+        assert_eq!(
+            overridden.get_signers_for_sender(&sender),
+            escrow_accounts.get_signers_for_sender(&sender)
+        );
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_active_escrow_account_overrides(pgpool: PgPool) {
+        sqlx::query(
+            r#"
+                INSERT INTO escrow_account_overrides (
+                    sender_address, balance, signers, reason, expires_at
+                )
+                VALUES
+                    ($1, 1000, NULL, 'outage', NOW() + INTERVAL '1 hour'),
+                    ($2, 2000, NULL, 'outage', NOW() - INTERVAL '1 hour')
+            "#,
         )
+        .bind(SenderAddress(Address::from([0x01; 20])))
+        .bind(SenderAddress(Address::from([0x02; 20])))
+        .execute(&pgpool)
+        .await
+        .unwrap();
+
+        assert_eq!(
+            active_escrow_account_overrides(&pgpool).await.unwrap(),
+            vec![EscrowAccountOverride {
+                sender: Address::from([0x01; 20]),
+                balance: Some(U256::from(1000)),
+                signers: None,
+            }]
+        );
     }
 
-    #[test(tokio::test)]
-    async fn test_current_accounts() {
-        // Set up a mock escrow subgraph
+    const SENDER_0: &str = "0x192c3b6e0184fa0cc5b9d2bddeb6b79fb216a002";
+    const SENDER_1: &str = "0x22d491bde2303f2f43325b2108d26f1eaba1e32b";
+    const SENDER_2: &str = "0x9858effd232b4033e47d90003d41ec34ecaeda94";
+
+    fn page(entities: Value) -> ResponseTemplate {
+        ResponseTemplate::new(200).set_body_json(json!({
+            "data": {
+                "meta": { "block": { "number": 100 } },
+                "page": entities,
+            }
+        }))
+    }
+
+    fn account(sender: &str, balance: u64, thawing: u64) -> Value {
+        json!({
+            "id": sender,
+            "balance": balance.to_string(),
+            "totalAmountThawing": thawing.to_string(),
+            "sender": { "id": sender },
+        })
+    }
+
+    fn signer(id: &str, sender: &str) -> Value {
+        json!({ "id": id, "sender": { "id": sender } })
+    }
+
+    async fn mock_page(
+        mock_server: &MockServer,
+        entity: &str,
+        last: &str,
+        response: ResponseTemplate,
+    ) {
+        let variables = if last.is_empty() {
+            json!({ "last": last, "block": null })
+        } else {
+            // Pages after the first one are fetched at the block of the first one
+            json!({ "last": last, "block": { "number": 100 } })
+        };
+        Mock::given(method("POST"))
+            .and(body_string_contains(entity))
+            .and(body_partial_json(json!({ "variables": variables })))
+            .respond_with(response)
+            .mount(mock_server)
+            .await;
+    }
+
+    async fn mock_escrow_subgraph() -> (MockServer, &'static SubgraphClient) {
         let mock_server = MockServer::start().await;
         let escrow_subgraph = Box::leak(Box::new(SubgraphClient::new(
             reqwest::Client::new(),
@@ -262,31 +696,182 @@ mod tests {
             ))
             .unwrap(),
         )));
+        (mock_server, escrow_subgraph)
+    }
 
-        let mock = Mock::given(method("POST"))
-            .and(path(format!(
-                "/subgraphs/id/{}",
-                *test_vectors::ESCROW_SUBGRAPH_DEPLOYMENT
-            )))
-            .respond_with(
-                ResponseTemplate::new(200)
-                    .set_body_raw(test_vectors::ESCROW_QUERY_RESPONSE, "application/json"),
-            );
-        mock_server.register(mock).await;
+    async fn mock_signer_pages(mock_server: &MockServer) {
+        mock_page(
+            mock_server,
+            "signers(",
+            "",
+            page(json!([
+                signer("0x245059163ff6ee14279aa7b35ea8f0fdb967df6e", SENDER_1),
+                signer("0x2740f6fa9188cf53ffb6729ddd21575721de92ce", SENDER_2),
+            ])),
+        )
+        .await;
+        mock_page(
+            mock_server,
+            "signers(",
+            "0x2740f6fa9188cf53ffb6729ddd21575721de92ce",
+            page(json!([
+                signer("0x533661f0fb14d2e8b26223c86a610dd7d2260892", SENDER_2),
+                // Signer of a sender without an account with the indexer
+                signer(
+                    "0x7777777777777777777777777777777777777777",
+                    "0x1111111111111111111111111111111111111111"
+                ),
+            ])),
+        )
+        .await;
+        mock_page(
+            mock_server,
+            "signers(",
+            "0x7777777777777777777777777777777777777777",
+            page(json!([])),
+        )
+        .await;
+    }
+
+    fn expected_escrow_accounts(sender_0_balance: u64) -> EscrowAccounts {
+        let mut balances = test_vectors::ESCROW_ACCOUNTS_BALANCES.to_owned();
+        balances.insert(
+            Address::from_str(SENDER_0).unwrap(),
+            U256::from(sender_0_balance),
+        );
+        let mut senders_to_signers = test_vectors::ESCROW_ACCOUNTS_SENDERS_TO_SIGNERS.to_owned();
+        // Signers come by increasing id
+        senders_to_signers
+            .values_mut()
+            .for_each(|signers| signers.sort());
+        EscrowAccounts::new(balances, senders_to_signers)
+    }
+
+    #[test(tokio::test)]
+    async fn test_current_accounts() {
+        let (mock_server, escrow_subgraph) = mock_escrow_subgraph().await;
+        mock_page(
+            &mock_server,
+            "escrowAccounts(",
+            "",
+            page(json!([
+                account(SENDER_0, 2987, 12),
+                account(SENDER_1, 42, 0)
+            ])),
+        )
+        .await;
+        mock_page(
+            &mock_server,
+            "escrowAccounts(",
+            SENDER_1,
+            page(json!([account(SENDER_2, 34, 10)])),
+        )
+        .await;
+        mock_signer_pages(&mock_server).await;
 
         let accounts = escrow_accounts(
             escrow_subgraph,
             *test_vectors::INDEXER_ADDRESS,
             Duration::from_secs(60),
             true,
+            2,
         );
 
         assert_eq!(
             accounts.value().await.unwrap(),
-            EscrowAccounts::new(
-                test_vectors::ESCROW_ACCOUNTS_BALANCES.to_owned(),
-                test_vectors::ESCROW_ACCOUNTS_SENDERS_TO_SIGNERS.to_owned(),
-            )
+            expected_escrow_accounts(2975)
+        );
+    }
+
+    async fn sync(
+        escrow_subgraph: &'static SubgraphClient,
+        previous: &mut Option<EscrowSnapshot>,
+    ) -> Result<EscrowAccounts, String> {
+        fetch_escrow_accounts(
+            escrow_subgraph,
+            *test_vectors::INDEXER_ADDRESS,
+            true,
+            2,
+            previous,
+        )
+        .await
+    }
+
+    #[test(tokio::test)]
+    async fn test_failed_page_keeps_previous_accounts() {
+        let (mock_server, escrow_subgraph) = mock_escrow_subgraph().await;
+        let mut previous = None;
+
+        // A failing page fails the first sync, there is nothing to fall back on
+        mock_page(
+            &mock_server,
+            "escrowAccounts(",
+            "",
+            page(json!([
+                account(SENDER_0, 2987, 12),
+                account(SENDER_1, 42, 0)
+            ])),
+        )
+        .await;
+        mock_page(
+            &mock_server,
+            "escrowAccounts(",
+            SENDER_1,
+            ResponseTemplate::new(500),
+        )
+        .await;
+        mock_signer_pages(&mock_server).await;
+        assert!(sync(escrow_subgraph, &mut previous).await.is_err());
+        assert!(previous.is_none());
+
+        mock_server.reset().await;
+        mock_page(
+            &mock_server,
+            "escrowAccounts(",
+            "",
+            page(json!([
+                account(SENDER_0, 2987, 12),
+                account(SENDER_1, 42, 0)
+            ])),
+        )
+        .await;
+        mock_page(
+            &mock_server,
+            "escrowAccounts(",
+            SENDER_1,
+            page(json!([account(SENDER_2, 34, 10)])),
+        )
+        .await;
+        mock_signer_pages(&mock_server).await;
+        assert_eq!(
+            sync(escrow_subgraph, &mut previous).await.unwrap(),
+            expected_escrow_accounts(2975)
+        );
+
+        // The second page fails, its account is kept from the previous sync while the first
+        // page is up to date
+        mock_server.reset().await;
+        mock_page(
+            &mock_server,
+            "escrowAccounts(",
+            "",
+            page(json!([
+                account(SENDER_0, 3000, 0),
+                account(SENDER_1, 42, 0)
+            ])),
+        )
+        .await;
+        mock_page(
+            &mock_server,
+            "escrowAccounts(",
+            SENDER_1,
+            ResponseTemplate::new(500),
+        )
+        .await;
+        mock_signer_pages(&mock_server).await;
+        assert_eq!(
+            sync(escrow_subgraph, &mut previous).await.unwrap(),
+            expected_escrow_accounts(3000)
         );
     }
 }
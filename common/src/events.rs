@@ -0,0 +1,447 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Typed event log shared by indexer-service and tap-agent, so that each reacts to the other's
+//! changes in near real time instead of polling the database. Events are rows of the
+//! `indexer_events` table, published either by the binaries or by database triggers, e.g. on
+//! the denylist and the cost models. The id of every new event is sent on the `indexer_events`
+//! channel, and listeners read the events after the last one they've seen, so that none is lost
+//! while their connection is re-established. Events are kept for [RETENTION], but for the latest
+//! `sender_paused` event of each sender, which tells whether the sender is paused.
+
+use std::collections::{HashSet, VecDeque};
+use std::str::FromStr;
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::{postgres::PgListener, PgPool, Row};
+use thegraph::types::{Address, DeploymentId};
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+
+use crate::notifications::{self, Notification};
+
+lazy_static! {
+    static ref EVENTS_RECEIVED: IntCounterVec = register_int_counter_vec!(
+        "indexer_events_received_total",
+        "Events of the shared event log received, by kind",
+        &["kind"]
+    )
+    .unwrap();
+}
+
+pub const EVENTS_CHANNEL: &str = "indexer_events";
+
+/// Events a listener holds for its subscribers, before the slowest ones start missing some.
+const EVENT_BUS_CAPACITY: usize = 1024;
+
+/// Listeners only read the events published after they connect, older ones are only kept for
+/// troubleshooting.
+pub const RETENTION: Duration = Duration::from_secs(7 * 24 * 3600);
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "payload", rename_all = "snake_case")]
+pub enum Event {
+    /// A sender was added to, or removed from, the denylist.
+    DenylistUpdated { sender: Address, denied: bool },
+    /// The receipts of a sender stopped, or resumed, being accepted.
+    SenderPaused {
+        sender: Address,
+        paused: bool,
+        reason: Option<String>,
+    },
+    /// An allocation about to be created on chain, before the network subgraph reports it.
+    AllocationPreRegistered {
+        allocation_id: Address,
+        deployment: DeploymentId,
+    },
+    /// The cost model of a deployment changed, or the global one if there's no deployment.
+    CostModelChanged { deployment: Option<DeploymentId> },
+}
+
+impl Event {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Event::DenylistUpdated { .. } => "denylist_updated",
+            Event::SenderPaused { .. } => "sender_paused",
+            Event::AllocationPreRegistered { .. } => "allocation_pre_registered",
+            Event::CostModelChanged { .. } => "cost_model_changed",
+        }
+    }
+
+    fn payload(&self) -> Result<Value, serde_json::Error> {
+        Ok(serde_json::to_value(self)?
+            .get_mut("payload")
+            .map(Value::take)
+            .unwrap_or(Value::Null))
+    }
+
+    fn from_parts(kind: &str, payload: &str) -> Result<Self, serde_json::Error> {
+        let payload: Value = serde_json::from_str(payload)?;
+        serde_json::from_value(json!({ "kind": kind, "payload": payload }))
+    }
+}
+
+/// Notification of a new event on [EVENTS_CHANNEL], by the `indexer_events_notify` trigger.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EventNotification {
+    pub id: i64,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct EventNotificationV1 {
+    version: u32,
+    id: i64,
+}
+
+impl Notification for EventNotification {
+    const CHANNEL: &'static str = EVENTS_CHANNEL;
+    const VERSION: u32 = 1;
+
+    fn read(version: u32, payload: &str) -> serde_json::Result<Self> {
+        let id = match version {
+            // The id as text
+            0 => serde_json::from_str(payload)?,
+            _ => serde_json::from_str::<EventNotificationV1>(payload)?.id,
+        };
+        Ok(Self { id })
+    }
+
+    fn write(&self) -> serde_json::Result<String> {
+        serde_json::to_string(&EventNotificationV1 {
+            version: Self::VERSION,
+            id: self.id,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RecordedEvent {
+    pub id: i64,
+    /// Binary that published the event, or `database` for the events of the triggers.
+    pub source: String,
+    pub event: Event,
+}
+
+/// Publishes an event to the listeners of both binaries, returning its id.
+pub async fn publish(pgpool: &PgPool, source: &str, event: &Event) -> anyhow::Result<i64> {
+    let id = sqlx::query_scalar(
+        r#"
+            INSERT INTO indexer_events (kind, payload, source)
+            VALUES ($1, $2::JSONB, $3)
+            RETURNING id
+        "#,
+    )
+    .bind(event.kind())
+    .bind(event.payload()?.to_string())
+    .bind(source)
+    .fetch_one(pgpool)
+    .await?;
+    Ok(id)
+}
+
+/// Senders paused by their latest `sender_paused` event.
+pub async fn paused_senders(pgpool: &PgPool) -> anyhow::Result<HashSet<Address>> {
+    let senders: Vec<String> = sqlx::query_scalar(
+        r#"
+            SELECT sender
+            FROM (
+                SELECT DISTINCT ON (payload->>'sender')
+                    payload->>'sender' AS sender,
+                    (payload->>'paused')::BOOLEAN AS paused
+                FROM indexer_events
+                WHERE kind = 'sender_paused'
+                ORDER BY payload->>'sender', id DESC
+            ) AS latest
+            WHERE paused
+        "#,
+    )
+    .fetch_all(pgpool)
+    .await?;
+    Ok(senders
+        .iter()
+        .map(|sender| Address::from_str(sender))
+        .collect::<Result<_, _>>()?)
+}
+
+/// Deletes the events older than `retention`, but for the latest `sender_paused` event of each
+/// sender, returning the number of deleted events.
+pub async fn prune(pgpool: &PgPool, retention: Duration) -> anyhow::Result<u64> {
+    let deleted = sqlx::query(
+        r#"
+            DELETE FROM indexer_events
+            WHERE created_at < NOW() - make_interval(secs => $1)
+                AND id NOT IN (
+                    SELECT DISTINCT ON (payload->>'sender') id
+                    FROM indexer_events
+                    WHERE kind = 'sender_paused'
+                    ORDER BY payload->>'sender', id DESC
+                )
+        "#,
+    )
+    .bind(retention.as_secs_f64())
+    .execute(pgpool)
+    .await?
+    .rows_affected();
+    Ok(deleted)
+}
+
+pub struct EventListener {
+    pgpool: PgPool,
+    listener: PgListener,
+    last_id: i64,
+    pending: VecDeque<RecordedEvent>,
+}
+
+impl EventListener {
+    /// Listens to the events published from now on.
+    pub async fn connect(pgpool: &PgPool) -> anyhow::Result<Self> {
+        let mut listener = PgListener::connect_with(pgpool).await?;
+        listener.listen(EVENTS_CHANNEL).await?;
+        let last_id = sqlx::query_scalar("SELECT COALESCE(MAX(id), 0) FROM indexer_events")
+            .fetch_one(pgpool)
+            .await?;
+        Ok(Self {
+            pgpool: pgpool.clone(),
+            listener,
+            last_id,
+            pending: VecDeque::new(),
+        })
+    }
+
+    pub async fn recv(&mut self) -> anyhow::Result<RecordedEvent> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Ok(event);
+            }
+            // `None` if the connection was lost and re-established, the notifications sent
+            // meanwhile are lost, but not the events
+            let notified_id = self
+                .listener
+                .try_recv()
+                .await?
+                .and_then(|notification| {
+                    notifications::parse::<EventNotification>(notification.payload())
+                        .inspect_err(|error| warn!(%error, "Ignoring an event notification"))
+                        .ok()
+                })
+                .map(|notification| notification.id);
+            self.read_events(notified_id).await?;
+        }
+    }
+
+    /// Reads the events after the last one seen, along with the notified one. The ids of
+    /// concurrent transactions can be committed out of order, so that the notified one can be
+    /// lower.
+    async fn read_events(&mut self, notified_id: Option<i64>) -> anyhow::Result<()> {
+        let rows = sqlx::query(
+            r#"
+                SELECT id, kind, payload::TEXT AS payload, source
+                FROM indexer_events
+                WHERE id > $1 OR id = $2
+                ORDER BY id
+            "#,
+        )
+        .bind(self.last_id)
+        .bind(notified_id)
+        .fetch_all(&self.pgpool)
+        .await?;
+
+        for row in rows {
+            let id: i64 = row.try_get("id")?;
+            let kind: String = row.try_get("kind")?;
+            self.last_id = self.last_id.max(id);
+            match Event::from_parts(&kind, &row.try_get::<String, _>("payload")?) {
+                Ok(event) => self.pending.push_back(RecordedEvent {
+                    id,
+                    source: row.try_get("source")?,
+                    event,
+                }),
+                // Published by a newer version of the other binary
+                Err(error) => warn!(id, kind, %error, "Skipping an event that can't be read"),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Forwards the events of `listener` to the subscribers of the returned sender, from a spawned
+/// task. Each event is counted and logged.
+pub fn spawn_event_bus(mut listener: EventListener) -> broadcast::Sender<RecordedEvent> {
+    let (sender, _) = broadcast::channel(EVENT_BUS_CAPACITY);
+    let bus = sender.clone();
+    tokio::spawn(async move {
+        loop {
+            match listener.recv().await {
+                Ok(recorded) => {
+                    EVENTS_RECEIVED
+                        .with_label_values(&[recorded.event.kind()])
+                        .inc();
+                    info!(
+                        id = recorded.id,
+                        source = recorded.source,
+                        event = ?recorded.event,
+                        "Received event"
+                    );
+                    // No subscriber is not an error
+                    let _ = bus.send(recorded);
+                }
+                Err(error) => {
+                    warn!(%error, "Failed to receive events, retrying");
+                    tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+                }
+            }
+        }
+    });
+    sender
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    use alloy_primitives::hex::ToHex;
+
+    use crate::test_vectors::TAP_SENDER;
+
+    use super::*;
+
+    async fn recv(listener: &mut EventListener) -> RecordedEvent {
+        tokio::time::timeout(Duration::from_secs(5), listener.recv())
+            .await
+            .expect("an event")
+            .unwrap()
+    }
+
+    #[test]
+    fn test_event_parts() {
+        let event = Event::SenderPaused {
+            sender: TAP_SENDER.1,
+            paused: true,
+            reason: Some("maintenance".to_string()),
+        };
+        let payload = event.payload().unwrap();
+        assert_eq!(payload["paused"], json!(true));
+        assert_eq!(
+            Event::from_parts(event.kind(), &payload.to_string()).unwrap(),
+            event
+        );
+        assert!(Event::from_parts("unknown_kind", "{}").is_err());
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_events(pgpool: PgPool) {
+        // Published before listening, not received
+        publish(
+            &pgpool,
+            "test",
+            &Event::CostModelChanged { deployment: None },
+        )
+        .await
+        .unwrap();
+
+        let mut listener = EventListener::connect(&pgpool).await.unwrap();
+
+        let deployment =
+            DeploymentId::from_str("QmAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA").unwrap();
+        let event = Event::AllocationPreRegistered {
+            allocation_id: Address::from([0x22; 20]),
+            deployment,
+        };
+        let id = publish(&pgpool, "tap-agent", &event).await.unwrap();
+        assert_eq!(
+            recv(&mut listener).await,
+            RecordedEvent {
+                id,
+                source: "tap-agent".to_string(),
+                event,
+            }
+        );
+
+        // Published by the triggers
+        sqlx::query("INSERT INTO scalar_tap_denylist (sender_address) VALUES ($1)")
+            .bind(TAP_SENDER.1.encode_hex::<String>())
+            .execute(&pgpool)
+            .await
+            .unwrap();
+        let recorded = recv(&mut listener).await;
+        assert_eq!(recorded.source, "database");
+        assert_eq!(
+            recorded.event,
+            Event::DenylistUpdated {
+                sender: TAP_SENDER.1,
+                denied: true
+            }
+        );
+
+        sqlx::query(
+            r#"INSERT INTO "CostModels" (deployment, model) VALUES ('global', 'default => 1;')"#,
+        )
+        .execute(&pgpool)
+        .await
+        .unwrap();
+        assert_eq!(
+            recv(&mut listener).await.event,
+            Event::CostModelChanged { deployment: None }
+        );
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_paused_senders(pgpool: PgPool) {
+        let other_sender = Address::from([0x33; 20]);
+        for (sender, paused) in [
+            (TAP_SENDER.1, true),
+            (other_sender, true),
+            (other_sender, false),
+        ] {
+            let event = Event::SenderPaused {
+                sender,
+                paused,
+                reason: None,
+            };
+            publish(&pgpool, "test", &event).await.unwrap();
+        }
+        assert_eq!(
+            paused_senders(&pgpool).await.unwrap(),
+            HashSet::from([TAP_SENDER.1])
+        );
+
+        // Only the latest `sender_paused` event of each sender is kept past the retention
+        publish(
+            &pgpool,
+            "test",
+            &Event::CostModelChanged { deployment: None },
+        )
+        .await
+        .unwrap();
+        sqlx::query("UPDATE indexer_events SET created_at = NOW() - INTERVAL '30 days'")
+            .execute(&pgpool)
+            .await
+            .unwrap();
+        let recent = publish(
+            &pgpool,
+            "test",
+            &Event::CostModelChanged { deployment: None },
+        )
+        .await
+        .unwrap();
+        assert_eq!(prune(&pgpool, RETENTION).await.unwrap(), 2);
+        assert_eq!(
+            paused_senders(&pgpool).await.unwrap(),
+            HashSet::from([TAP_SENDER.1])
+        );
+        let kept: Vec<(i64, String)> =
+            sqlx::query_as("SELECT id, kind FROM indexer_events ORDER BY id")
+                .fetch_all(&pgpool)
+                .await
+                .unwrap();
+        assert_eq!(kept.len(), 3);
+        assert_eq!(kept[2], (recent, "cost_model_changed".to_string()));
+        assert!(kept[..2].iter().all(|(_, kind)| kind == "sender_paused"));
+    }
+}
@@ -0,0 +1,405 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Stats of the whole fleet of indexer-service replicas sharing a database. Each replica only
+//! exports its own metrics, so every replica also counts its requests and paid receipts in
+//! memory, and adds them to its rows of `indexer_service_instance_stats` periodically. Any
+//! replica can then serve the totals of the fleet, broken down by instance and by deployment, at
+//! `/fees/summary`, and export them as metrics labeled by instance.
+//!
+//! Counting is disabled until [`start`] is called, the counters being flushed by the task it
+//! spawns.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::Result;
+use axum::{http::StatusCode, Extension, Json};
+use ethers_core::rand::{thread_rng, RngCore};
+use lazy_static::lazy_static;
+use prometheus::{register_gauge_vec, register_int_gauge_vec, GaugeVec, IntGaugeVec};
+use serde::Serialize;
+use sqlx::{PgPool, Row};
+use thegraph::types::DeploymentId;
+use tokio::task::JoinHandle;
+use tracing::{error, info};
+
+use crate::types::GrtWei;
+
+/// Instances that didn't flush their counters for this many flush intervals are reported stale,
+/// e.g. replicas scaled down.
+const STALE_AFTER_FLUSH_INTERVALS: u32 = 4;
+
+lazy_static! {
+    static ref PENDING: Mutex<HashMap<DeploymentId, Counters>> = Mutex::new(HashMap::new());
+    static ref FLEET_REQUESTS: IntGaugeVec = register_int_gauge_vec!(
+        "indexer_fleet_requests",
+        "Requests served by each instance of the fleet, as last flushed to the database",
+        &["instance"]
+    )
+    .unwrap();
+    static ref FLEET_RECEIPTS: IntGaugeVec = register_int_gauge_vec!(
+        "indexer_fleet_receipts",
+        "Paid receipts accepted by each instance of the fleet, as last flushed to the database",
+        &["instance"]
+    )
+    .unwrap();
+    static ref FLEET_RECEIPT_VALUE: GaugeVec = register_gauge_vec!(
+        "indexer_fleet_receipt_value_grt_wei",
+        "Value of the paid receipts accepted by each instance of the fleet, in GRT wei",
+        &["instance"]
+    )
+    .unwrap();
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct Counters {
+    requests: u64,
+    receipts: u64,
+    receipt_value: u128,
+}
+
+impl Counters {
+    fn add(&mut self, other: &Counters) {
+        self.requests += other.requests;
+        self.receipts += other.receipts;
+        self.receipt_value = self.receipt_value.saturating_add(other.receipt_value);
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InstanceStats {
+    pub instance_id: String,
+    pub requests: u64,
+    pub receipts: u64,
+    pub receipt_value: GrtWei,
+    /// Seconds since the instance last flushed its counters.
+    pub last_flush_secs_ago: f64,
+    /// Whether the instance stopped flushing its counters.
+    pub stale: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeploymentStats {
+    pub deployment: DeploymentId,
+    pub requests: u64,
+    pub receipts: u64,
+    pub receipt_value: GrtWei,
+}
+
+/// Totals of the fleet, since the stats were first enabled.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FleetSummary {
+    pub requests: u64,
+    pub receipts: u64,
+    pub receipt_value: GrtWei,
+    pub instances: Vec<InstanceStats>,
+    pub deployments: Vec<DeploymentStats>,
+}
+
+/// Shared by the handlers of the summary.
+#[derive(Clone)]
+pub struct FleetStats {
+    pub pgpool: PgPool,
+    pub flush_interval: Duration,
+}
+
+/// The configured instance ID, or the host name, which is unique for the replicas of a
+/// container orchestrator, or else a random ID.
+pub fn instance_id(configured: Option<&str>) -> String {
+    configured
+        .map(ToString::to_string)
+        .or_else(|| {
+            std::env::var("HOSTNAME")
+                .ok()
+                .filter(|name| !name.is_empty())
+        })
+        .unwrap_or_else(|| {
+            let mut id = [0u8; 8];
+            thread_rng().fill_bytes(&mut id);
+            format!("indexer-service-{}", alloy_primitives::hex::encode(id))
+        })
+}
+
+/// Counts a request to `deployment`.
+pub fn record_request(deployment: DeploymentId) {
+    record(deployment, |counters| counters.requests += 1);
+}
+
+/// Counts a paid receipt of `value` accepted for `deployment`.
+pub fn record_receipt(deployment: DeploymentId, value: u128) {
+    record(deployment, |counters| {
+        counters.receipts += 1;
+        counters.receipt_value = counters.receipt_value.saturating_add(value);
+    });
+}
+
+fn record(deployment: DeploymentId, update: impl FnOnce(&mut Counters)) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    update(PENDING.lock().unwrap().entry(deployment).or_default());
+}
+
+/// Enables the counting, and flushes the counters of `instance_id` to the database every
+/// `flush_interval`, refreshing the metrics of the fleet.
+pub fn start(pgpool: PgPool, instance_id: String, flush_interval: Duration) -> JoinHandle<()> {
+    info!(
+        instance_id,
+        "Sharing the stats of the instance with the fleet"
+    );
+    ENABLED.store(true, Ordering::Relaxed);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(flush_interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            if let Err(e) = flush(&pgpool, &instance_id).await {
+                error!("Failed to flush the stats of the instance: {}", e);
+                continue;
+            }
+            match summary(&pgpool, flush_interval).await {
+                Ok(summary) => update_metrics(&summary),
+                Err(e) => error!("Failed to read the stats of the fleet: {}", e),
+            }
+        }
+    })
+}
+
+/// Adds the counters since the last flush to the rows of `instance_id`. The counters are kept
+/// for the next flush if it fails.
+async fn flush(pgpool: &PgPool, instance_id: &str) -> Result<()> {
+    let pending = std::mem::take(&mut *PENDING.lock().unwrap());
+    if let Err(e) = write(pgpool, instance_id, &pending).await {
+        let mut counters = PENDING.lock().unwrap();
+        for (deployment, pending) in pending {
+            counters.entry(deployment).or_default().add(&pending);
+        }
+        return Err(e);
+    }
+    Ok(())
+}
+
+async fn write(
+    pgpool: &PgPool,
+    instance_id: &str,
+    pending: &HashMap<DeploymentId, Counters>,
+) -> Result<()> {
+    let mut tx = pgpool.begin().await?;
+    for (deployment, counters) in pending {
+        sqlx::query(
+            r#"
+                INSERT INTO indexer_service_instance_stats
+                    (instance_id, deployment, requests, receipts, receipt_value)
+                VALUES ($1, $2, $3, $4, $5)
+                ON CONFLICT (instance_id, deployment, (COALESCE(indexer_address, '')))
+                DO UPDATE SET
+                    requests = indexer_service_instance_stats.requests + EXCLUDED.requests,
+                    receipts = indexer_service_instance_stats.receipts + EXCLUDED.receipts,
+                    receipt_value =
+                        indexer_service_instance_stats.receipt_value + EXCLUDED.receipt_value,
+                    updated_at = NOW()
+            "#,
+        )
+        .bind(instance_id)
+        .bind(deployment.to_string())
+        .bind(i64::try_from(counters.requests)?)
+        .bind(i64::try_from(counters.receipts)?)
+        .bind(GrtWei(counters.receipt_value))
+        .execute(&mut *tx)
+        .await?;
+    }
+    // Instances without traffic still show they are alive
+    sqlx::query(
+        "UPDATE indexer_service_instance_stats SET updated_at = NOW() WHERE instance_id = $1",
+    )
+    .bind(instance_id)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Stats of the fleet, the instances not flushing for `STALE_AFTER_FLUSH_INTERVALS` times
+/// `flush_interval` being reported stale.
+pub async fn summary(pgpool: &PgPool, flush_interval: Duration) -> Result<FleetSummary> {
+    let instances = sqlx::query(
+        r#"
+            SELECT
+                instance_id,
+                SUM(requests)::BIGINT AS requests,
+                SUM(receipts)::BIGINT AS receipts,
+                SUM(receipt_value) AS receipt_value,
+                EXTRACT(EPOCH FROM NOW() - MAX(updated_at))::DOUBLE PRECISION
+                    AS last_flush_secs_ago,
+                MAX(updated_at) < NOW() - make_interval(secs => $1) AS stale
+            FROM indexer_service_instance_stats
+            GROUP BY instance_id
+            ORDER BY instance_id
+        "#,
+    )
+    .bind((flush_interval * STALE_AFTER_FLUSH_INTERVALS).as_secs_f64())
+    .fetch_all(pgpool)
+    .await?
+    .iter()
+    .map(|row| {
+        Ok(InstanceStats {
+            instance_id: row.try_get("instance_id")?,
+            requests: row.try_get::<i64, _>("requests")?.try_into()?,
+            receipts: row.try_get::<i64, _>("receipts")?.try_into()?,
+            receipt_value: row.try_get("receipt_value")?,
+            last_flush_secs_ago: row.try_get("last_flush_secs_ago")?,
+            stale: row.try_get("stale")?,
+        })
+    })
+    .collect::<Result<Vec<_>>>()?;
+
+    let deployments = sqlx::query(
+        r#"
+            SELECT
+                deployment,
+                SUM(requests)::BIGINT AS requests,
+                SUM(receipts)::BIGINT AS receipts,
+                SUM(receipt_value) AS receipt_value
+            FROM indexer_service_instance_stats
+            GROUP BY deployment
+            ORDER BY deployment
+        "#,
+    )
+    .fetch_all(pgpool)
+    .await?
+    .iter()
+    .map(|row| {
+        Ok(DeploymentStats {
+            deployment: DeploymentId::from_str(row.try_get("deployment")?)?,
+            requests: row.try_get::<i64, _>("requests")?.try_into()?,
+            receipts: row.try_get::<i64, _>("receipts")?.try_into()?,
+            receipt_value: row.try_get("receipt_value")?,
+        })
+    })
+    .collect::<Result<Vec<_>>>()?;
+
+    Ok(FleetSummary {
+        requests: instances.iter().map(|instance| instance.requests).sum(),
+        receipts: instances.iter().map(|instance| instance.receipts).sum(),
+        receipt_value: instances
+            .iter()
+            .map(|instance| instance.receipt_value)
+            .sum(),
+        instances,
+        deployments,
+    })
+}
+
+fn update_metrics(summary: &FleetSummary) {
+    for instance in &summary.instances {
+        let labels = [instance.instance_id.as_str()];
+        FLEET_REQUESTS
+            .with_label_values(&labels)
+            .set(instance.requests as i64);
+        FLEET_RECEIPTS
+            .with_label_values(&labels)
+            .set(instance.receipts as i64);
+        FLEET_RECEIPT_VALUE
+            .with_label_values(&labels)
+            .set(instance.receipt_value.0 as f64);
+    }
+}
+
+pub async fn fees_summary_handler(
+    Extension(fleet_stats): Extension<FleetStats>,
+) -> Result<Json<FleetSummary>, (StatusCode, String)> {
+    summary(&fleet_stats.pgpool, fleet_stats.flush_interval)
+        .await
+        .map(Json)
+        .map_err(|e| {
+            error!("Failed to read the stats of the fleet: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to read the stats of the fleet".to_string(),
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_vectors::{ESCROW_SUBGRAPH_DEPLOYMENT, NETWORK_SUBGRAPH_DEPLOYMENT};
+
+    use super::*;
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_summary(pgpool: PgPool) {
+        let counters = |requests, receipts, receipt_value| Counters {
+            requests,
+            receipts,
+            receipt_value,
+        };
+        write(
+            &pgpool,
+            "replica-1",
+            &HashMap::from([
+                (*NETWORK_SUBGRAPH_DEPLOYMENT, counters(3, 2, 200)),
+                (*ESCROW_SUBGRAPH_DEPLOYMENT, counters(1, 1, 50)),
+            ]),
+        )
+        .await
+        .unwrap();
+        write(
+            &pgpool,
+            "replica-2",
+            &HashMap::from([(*NETWORK_SUBGRAPH_DEPLOYMENT, counters(2, 2, 100))]),
+        )
+        .await
+        .unwrap();
+        // Incremented by the next flush
+        write(
+            &pgpool,
+            "replica-2",
+            &HashMap::from([(*NETWORK_SUBGRAPH_DEPLOYMENT, counters(1, 1, 10))]),
+        )
+        .await
+        .unwrap();
+
+        let summary = summary(&pgpool, Duration::from_secs(15)).await.unwrap();
+        assert_eq!(summary.requests, 7);
+        assert_eq!(summary.receipts, 6);
+        assert_eq!(summary.receipt_value, GrtWei(360));
+        assert_eq!(
+            summary
+                .instances
+                .iter()
+                .map(|instance| (
+                    instance.instance_id.as_str(),
+                    instance.requests,
+                    instance.receipt_value,
+                    instance.stale
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                ("replica-1", 4, GrtWei(250), false),
+                ("replica-2", 3, GrtWei(110), false)
+            ]
+        );
+        let network = summary
+            .deployments
+            .iter()
+            .find(|stats| stats.deployment == *NETWORK_SUBGRAPH_DEPLOYMENT)
+            .unwrap();
+        assert_eq!((network.requests, network.receipts), (6, 5));
+        assert_eq!(network.receipt_value, GrtWei(310));
+    }
+
+    #[test]
+    fn test_instance_id() {
+        assert_eq!(instance_id(Some("replica-1")), "replica-1");
+        assert!(!instance_id(None).is_empty());
+    }
+}
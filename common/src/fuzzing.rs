@@ -0,0 +1,87 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Entry points of the fuzz targets in `fuzz/`, for the parsing of the untrusted input of
+//! paid queries. Run with `cargo fuzz run <target>` from `common/`. The minimized corpora in
+//! `fuzz/corpus` are replayed by the tests below.
+
+use axum::http::HeaderValue;
+use axum_extra::headers::Header;
+use tap_core::receipt::SignedReceipt;
+
+use crate::{indexer_service::http::TapReceipt, test_vectors::TAP_EIP712_DOMAIN};
+
+/// Parses a `Tap-Receipt` header value.
+pub fn tap_receipt_header(data: &[u8]) {
+    let Ok(value) = HeaderValue::from_bytes(data) else {
+        return;
+    };
+    if let Ok(receipts) = TapReceipt::decode(&mut std::iter::once(&value)) {
+        receipts
+            .into_signed_receipts()
+            .into_iter()
+            .for_each(check_receipt);
+    }
+}
+
+/// Deserializes an EIP-712 signed receipt.
+pub fn signed_receipt(data: &[u8]) {
+    if let Ok(receipt) = serde_json::from_slice::<SignedReceipt>(data) {
+        check_receipt(receipt);
+    }
+}
+
+/// Parses a query body, and serializes it again as done for attestations.
+pub fn request_body(data: &[u8]) {
+    if let Ok(request) = serde_json::from_slice::<serde_json::Value>(data) {
+        serde_json::to_string(&request).expect("a parsed request should serialize");
+    }
+}
+
+fn check_receipt(receipt: SignedReceipt) {
+    let _ = receipt.recover_signer(&TAP_EIP712_DOMAIN);
+    let serialized = serde_json::to_vec(&receipt).expect("a parsed receipt should serialize");
+    let deserialized: SignedReceipt =
+        serde_json::from_slice(&serialized).expect("a serialized receipt should parse");
+    assert_eq!(deserialized, receipt);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use super::*;
+
+    fn corpus(target: &str) -> Vec<Vec<u8>> {
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("fuzz/corpus")
+            .join(target);
+        let inputs: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| fs::read(entry.unwrap().path()).unwrap())
+            .collect();
+        assert!(!inputs.is_empty());
+        inputs
+    }
+
+    #[test]
+    fn test_tap_receipt_header_corpus() {
+        for input in corpus("tap_receipt_header") {
+            tap_receipt_header(&input);
+        }
+    }
+
+    #[test]
+    fn test_signed_receipt_corpus() {
+        for input in corpus("signed_receipt") {
+            signed_receipt(&input);
+        }
+    }
+
+    #[test]
+    fn test_request_body_corpus() {
+        for input in corpus("request_body") {
+            request_body(&input);
+        }
+    }
+}
@@ -0,0 +1,86 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::sync::Arc;
+
+use alloy_primitives::B256;
+use anyhow::anyhow;
+use axum::{extract::State, Json};
+use serde::{Deserialize, Serialize};
+use thegraph::types::Attestation;
+
+use super::{
+    indexer_service::{IndexerServiceError, IndexerServiceState},
+    IndexerServiceImpl,
+};
+
+/// Maximum number of attestations verified in a single request.
+const MAX_BATCH_SIZE: usize = 1000;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AttestationToVerify {
+    request_hash: B256,
+    response_hash: B256,
+    attestation: Attestation,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct VerificationResult {
+    valid: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Verifies a batch of attestations against the attestation signers of the indexer's
+/// allocations, so that gateways can cheaply audit samples of the responses they received.
+/// Results are returned in the order of the request.
+pub async fn verify_attestations_handler<I>(
+    State(state): State<Arc<IndexerServiceState<I>>>,
+    Json(batch): Json<Vec<AttestationToVerify>>,
+) -> Result<Json<Vec<VerificationResult>>, IndexerServiceError<I::Error>>
+where
+    I: IndexerServiceImpl + Sync + Send + 'static,
+{
+    if batch.len() > MAX_BATCH_SIZE {
+        return Err(IndexerServiceError::InvalidRequest(anyhow!(
+            "At most {} attestations can be verified at once, got {}",
+            MAX_BATCH_SIZE,
+            batch.len()
+        )));
+    }
+
+    let signers = state
+        .attestation_signers
+        .value_immediate()
+        .ok_or(IndexerServiceError::ServiceNotReady)?;
+    // All signers share the same EIP-712 domain, any of them can recover the allocation id.
+    let Some(any_signer) = signers.values().next() else {
+        return Err(IndexerServiceError::ServiceNotReady);
+    };
+
+    let results = batch
+        .iter()
+        .map(|item| {
+            let result = any_signer
+                .recover_signer(&item.attestation)
+                .and_then(|allocation_id| {
+                    signers
+                        .get(&allocation_id)
+                        .ok_or_else(|| anyhow!("Not signed by an allocation of this indexer"))?
+                        .verify_hashes(
+                            &item.attestation,
+                            &item.request_hash,
+                            &item.response_hash,
+                            &allocation_id,
+                        )
+                });
+            VerificationResult {
+                valid: result.is_ok(),
+                error: result.err().map(|e| e.to_string()),
+            }
+        })
+        .collect();
+
+    Ok(Json(results))
+}
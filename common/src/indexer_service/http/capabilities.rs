@@ -0,0 +1,131 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+use thegraph::types::Address;
+
+use super::{
+    response_envelope::ENVELOPE_V1_MEDIA_TYPE, IndexerServiceConfig, ReceiptAcceptanceWindowAction,
+    ReceiptTransport,
+};
+
+/// Requests of a client IP allowed in a burst, and interval after which one more is allowed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RateLimit {
+    pub burst_size: u32,
+    pub replenish_interval_ms: u64,
+}
+
+/// Limit of the informational routes, e.g. `/info` and `/capabilities`.
+pub(super) const MISC_RATE_LIMIT: RateLimit = RateLimit {
+    burst_size: 10,
+    replenish_interval_ms: 100,
+};
+
+/// Limit of the network and escrow subgraphs, if served.
+pub(super) const STATIC_SUBGRAPH_RATE_LIMIT: RateLimit = RateLimit {
+    burst_size: 50,
+    replenish_interval_ms: 20,
+};
+
+/// EIP-712 domain the receipts are signed for.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReceiptFormat {
+    pub name: &'static str,
+    pub version: &'static str,
+    pub chain_id: u64,
+    pub verifying_contract: Address,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReceiptCapabilities {
+    pub formats: Vec<ReceiptFormat>,
+    /// Whether a receipt can be sent again with a retried query. Every receipt received is
+    /// stored and aggregated, so a retried query needs a new receipt.
+    pub idempotent: bool,
+    /// Accepted for every deployment.
+    pub transports: Vec<&'static str>,
+    /// Accepted for specific deployments besides `transports`.
+    pub transports_per_deployment: BTreeMap<String, Vec<ReceiptTransport>>,
+    /// Values in GRT wei, as strings for the clients parsing JSON numbers as doubles.
+    pub max_value: String,
+    pub min_value: String,
+    pub min_value_per_deployment: BTreeMap<String, String>,
+    /// How far the timestamp of a receipt can be from the clock of the indexer.
+    pub timestamp_tolerance_secs: u64,
+    /// How long before the creation of its allocation a receipt is still accepted, if limited.
+    pub max_age_before_allocation_secs: Option<u64>,
+    /// Whether the receipts must be bound to the query in the `Tap-Query-Binding` header.
+    pub query_binding_required: bool,
+    /// Receipts a query can be paid with together, as a JSON array.
+    pub max_receipts_per_query: usize,
+}
+
+/// Receipt semantics of an indexer-service instance, served at `/capabilities` for the gateways
+/// to configure themselves. Derived from the configuration the instance runs with.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Capabilities {
+    pub receipts: ReceiptCapabilities,
+    /// Media types of the responses besides the legacy `application/json`, negotiated with the
+    /// `Accept` header.
+    pub response_formats: Vec<&'static str>,
+    /// Limits of the routes by client IP. The routes not listed, e.g. the paid queries, aren't
+    /// rate limited.
+    pub rate_limits: BTreeMap<&'static str, RateLimit>,
+}
+
+impl Capabilities {
+    pub fn new(config: &IndexerServiceConfig) -> Self {
+        let tap = &config.tap;
+        let receipts = ReceiptCapabilities {
+            formats: vec![ReceiptFormat {
+                name: "TAP",
+                version: "1",
+                chain_id: tap.chain_id,
+                verifying_contract: tap.receipts_verifier_address,
+            }],
+            idempotent: false,
+            transports: vec!["header"],
+            transports_per_deployment: tap
+                .receipt_transports_per_deployment
+                .iter()
+                .map(|(deployment, transports)| (deployment.to_string(), transports.clone()))
+                .collect(),
+            max_value: tap.receipt_max_value.to_string(),
+            min_value: tap.receipt_min_value.to_string(),
+            min_value_per_deployment: tap
+                .receipt_min_value_per_deployment
+                .iter()
+                .map(|(deployment, value)| (deployment.to_string(), value.to_string()))
+                .collect(),
+            timestamp_tolerance_secs: tap.timestamp_error_tolerance,
+            max_age_before_allocation_secs: tap
+                .receipt_acceptance_window
+                .as_ref()
+                .filter(|window| window.action == ReceiptAcceptanceWindowAction::Reject)
+                .map(|window| window.tolerance.as_secs()),
+            query_binding_required: tap.require_query_binding,
+            max_receipts_per_query: tap.max_receipts_per_query,
+        };
+
+        let mut rate_limits = BTreeMap::from([("misc", MISC_RATE_LIMIT)]);
+        if config.network_subgraph.serve_subgraph {
+            rate_limits.insert("network", STATIC_SUBGRAPH_RATE_LIMIT);
+        }
+        if config.escrow_subgraph.serve_subgraph {
+            rate_limits.insert("escrow", STATIC_SUBGRAPH_RATE_LIMIT);
+        }
+
+        Self {
+            receipts,
+            response_formats: vec![ENVELOPE_V1_MEDIA_TYPE],
+            rate_limits,
+        }
+    }
+}
@@ -1,15 +1,38 @@
 // Copyright 2023-, GraphOps and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::net::SocketAddr;
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    path::PathBuf,
+    time::Duration,
+};
 
 use serde::{Deserialize, Serialize};
 use thegraph::types::Address;
 use thegraph::types::DeploymentId;
 
+use crate::allocations::sources::{AllocationSource, StaticAllocation};
+use crate::db_pool::PoolSizing;
+use crate::http_proxy::ProxySettings;
+use crate::monitor_backoff::MonitorAlarms;
+use crate::secrets::SealedSecret;
+use crate::slo::SloSettings;
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct DatabaseConfig {
     pub postgres_url: String,
+    pub slow_query_threshold_ms: u64,
+    /// Isolates the rows of this indexer from the other ones sharing the database, see
+    /// [crate::tenant].
+    #[serde(default)]
+    pub tenant_isolation: bool,
+    /// Socket the tap-agent is notified of the new receipts through, see
+    /// [crate::receipt_notifications].
+    #[serde(default)]
+    pub receipt_notification_socket: Option<PathBuf>,
+    #[serde(default)]
+    pub pool: PoolSizing,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -23,6 +46,29 @@ pub struct SubgraphConfig {
     pub query_auth_token: Option<String>,
     pub syncing_interval: u64,
     pub recently_closed_allocation_buffer_seconds: u64,
+    /// Buffers taking precedence over `recently_closed_allocation_buffer_seconds`, by network
+    /// indexed by the deployment of the allocation.
+    #[serde(default)]
+    pub recently_closed_allocation_buffer_seconds_per_network: HashMap<String, u64>,
+    /// Buffers taking precedence over the others, by sender of the receipt.
+    #[serde(default)]
+    pub recently_closed_allocation_buffer_seconds_per_sender: HashMap<Address, u64>,
+    #[serde(default)]
+    pub additional_query_urls: Vec<String>,
+    pub quorum: Option<usize>,
+    /// Number of entities queried at a time, for the subgraphs queried by pages.
+    pub page_size: Option<usize>,
+    /// Replaced allocations are accepted for this long after their successor was created,
+    /// instead of for the buffers, see [crate::allocations::grace_period::GracePeriods].
+    #[serde(default)]
+    pub rollover_overlap_seconds: Option<u64>,
+    /// Where the allocations come from, the subgraph isn't queried for them but with
+    /// [AllocationSource::Network].
+    #[serde(default)]
+    pub allocation_source: AllocationSource,
+    /// Allocations of [AllocationSource::Static].
+    #[serde(default)]
+    pub static_allocations: Vec<StaticAllocation>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -31,6 +77,35 @@ pub struct ServerConfig {
     pub metrics_host_and_port: SocketAddr,
     pub url_prefix: String,
     pub free_query_auth_token: Option<String>,
+    /// Bearer token of the routes for the operators, which are refused if not set.
+    #[serde(default)]
+    pub operator_auth_token: Option<String>,
+    /// Whether to pause the paid queries to the deployments graph-node reports failed or paused.
+    #[serde(default)]
+    pub pause_unhealthy_deployments: bool,
+    /// Deployments whose responses are not attested, even if attestable.
+    #[serde(default)]
+    pub attestations_disabled_deployments: HashSet<DeploymentId>,
+    /// Alarms of the monitors failing repeatedly, see [crate::monitor_backoff].
+    #[serde(default)]
+    pub monitor_alarms: MonitorAlarms,
+    /// Sharing of the stats of the instance with the other replicas, see
+    /// [crate::fleet_stats]. Disabled if not set.
+    #[serde(default)]
+    pub fleet_stats: Option<FleetStatsConfig>,
+    /// Service level objectives of the paid queries, see [crate::slo]. Not tracked if not set.
+    #[serde(default)]
+    pub slo: Option<SloSettings>,
+    /// Outbound proxies of the HTTP requests, see [crate::http_proxy].
+    #[serde(default)]
+    pub proxy: ProxySettings,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct FleetStatsConfig {
+    /// Unique across the fleet, see [crate::fleet_stats::instance_id].
+    pub instance_id: Option<String>,
+    pub flush_interval: Duration,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -49,23 +124,125 @@ pub struct IndexerServiceConfig {
 pub struct GraphNodeConfig {
     pub status_url: String,
     pub query_base_url: String,
+    /// Query base URLs of replicas of the graph-node of `query_base_url`.
+    #[serde(default)]
+    pub additional_query_base_urls: Vec<String>,
+    pub health_check_interval_ms: u64,
+    /// Failed queries in a row after which a query base URL is skipped, until its next
+    /// successful health check.
+    pub max_consecutive_failures: u32,
+    /// Deployments always queried on the same query base URL while it's healthy.
+    #[serde(default)]
+    pub sticky_deployments: HashSet<DeploymentId>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct GraphNetworkConfig {
     pub chain_id: u64,
+    /// Dispute manager of the attestations, instead of the one of the network subgraph.
+    #[serde(default)]
+    pub dispute_manager: Option<Address>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct IndexerConfig {
     pub indexer_address: Address,
-    pub operator_mnemonic: String,
+    /// Never serialized, see [crate::secrets].
+    #[serde(skip_serializing)]
+    pub operator_mnemonic: SealedSecret,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct TapConfig {
     pub chain_id: u64,
     pub receipts_verifier_address: Address,
+    /// Only logs an error when the escrow subgraph indexes another network than `chain_id`.
+    #[serde(default)]
+    pub allow_escrow_network_mismatch: bool,
     pub timestamp_error_tolerance: u64,
     pub receipt_max_value: u128,
+    pub receipt_min_value: u128,
+    pub receipt_min_value_per_deployment: HashMap<DeploymentId, u128>,
+    #[serde(default)]
+    pub receipt_queue: Option<ReceiptQueueConfig>,
+    #[serde(default)]
+    pub sender_price_multipliers: HashMap<Address, f64>,
+    /// Transports accepted for the receipt besides the `Tap-Receipt` header, by deployment.
+    #[serde(default)]
+    pub receipt_transports_per_deployment: HashMap<DeploymentId, Vec<ReceiptTransport>>,
+    #[serde(default)]
+    pub read_only_database: Option<ReadOnlyDatabaseConfig>,
+    /// URL namespaces of the data services accepting receipts for virtual allocations.
+    #[serde(default)]
+    pub virtual_allocation_services: HashSet<String>,
+    /// Receipts predating the creation of their allocation are accepted if not set.
+    #[serde(default)]
+    pub receipt_acceptance_window: Option<ReceiptAcceptanceWindowConfig>,
+    /// Whether the receipts of the paid queries must be bound to the query, see
+    /// [super::query_binding].
+    #[serde(default)]
+    pub require_query_binding: bool,
+    /// Receipts a paid query can be paid with together.
+    #[serde(default = "default_max_receipts_per_query")]
+    pub max_receipts_per_query: usize,
+    /// Bearer token the senders register their indexing agreements with. The indexing
+    /// agreements aren't accepted if not set.
+    #[serde(default)]
+    pub agreement_registration_auth_token: Option<String>,
+}
+
+fn default_max_receipts_per_query() -> usize {
+    1
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ReceiptQueueConfig {
+    pub capacity: usize,
+    pub writers: usize,
+    pub batch_size: usize,
+    pub overflow: ReceiptQueueOverflow,
+    pub spill_path: Option<PathBuf>,
+    pub failed_batches_path: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReceiptQueueOverflow {
+    Block,
+    Drop,
+    Spill,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ReadOnlyDatabaseConfig {
+    pub policy: ReadOnlyDatabasePolicy,
+    pub journal_path: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReadOnlyDatabasePolicy {
+    Reject,
+    Journal,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ReceiptAcceptanceWindowConfig {
+    /// How long before the creation of its allocation a receipt is still accepted.
+    pub tolerance: Duration,
+    pub action: ReceiptAcceptanceWindowAction,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReceiptAcceptanceWindowAction {
+    Reject,
+    Flag,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReceiptTransport {
+    QueryString,
+    PostBody,
 }
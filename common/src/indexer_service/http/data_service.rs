@@ -0,0 +1,348 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Data services served next to the main one, e.g. SQL or files next to subgraphs. They sit
+//! behind the same payment layer: their receipts are checked and stored by the same TAP manager,
+//! and their responses attested with the same allocation signers.
+
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
+
+use alloy_sol_types::Eip712Domain;
+use axum::{routing::post, Router};
+use eventuals::Eventual;
+use sqlx::PgPool;
+use tap_core::{manager::Manager, receipt::checks::ReceiptCheck};
+use thegraph::types::{Address, DeploymentId};
+use tokio::sync::broadcast;
+
+use crate::{
+    allocations::virtual_allocations::VirtualAllocation,
+    events::RecordedEvent,
+    prelude::AttestationSigner,
+    query_stats::QueryStatsRecorder,
+    tap::{IndexerTapContext, ReadOnlyDatabase, SenderPricing},
+};
+
+use super::{
+    indexer_service::IndexerServiceState, metrics::IndexerServiceMetrics,
+    request_handler::request_handler, IndexerServiceConfig, IndexerServiceImpl,
+};
+
+pub struct DataServiceOptions<I>
+where
+    I: IndexerServiceImpl + Sync + Send + 'static,
+{
+    pub service_impl: I,
+    /// Requests are served at `/{url_namespace}/id/:id`.
+    pub url_namespace: &'static str,
+    /// Must be unique across the data services, their metrics are registered globally.
+    pub metrics_prefix: &'static str,
+    /// Whether the responses the service marks as attestable are attested. Services whose
+    /// responses can't be disputed on-chain should disable it.
+    pub attestations: bool,
+    /// Routes specific to the service, such as the cost models it is priced with.
+    pub extra_routes: Router<Arc<IndexerServiceState<I>>>,
+}
+
+/// Shared by all data services.
+pub(super) struct PaymentLayer {
+    pub config: IndexerServiceConfig,
+    pub attestation_signers: Eventual<HashMap<Address, AttestationSigner>>,
+    pub tap_manager: Arc<Manager<IndexerTapContext>>,
+    pub sender_pricing: SenderPricing,
+    pub database: PgPool,
+    pub query_stats: QueryStatsRecorder,
+    pub paused_deployments: Eventual<HashMap<DeploymentId, String>>,
+    pub allocation_eligible: ReceiptCheck,
+    pub events: broadcast::Sender<RecordedEvent>,
+    pub read_only_database: Option<Arc<ReadOnlyDatabase>>,
+    pub virtual_allocations: Eventual<HashMap<Address, VirtualAllocation>>,
+    pub domain_separator: Eip712Domain,
+}
+
+impl PaymentLayer {
+    pub fn state<I>(
+        &self,
+        service_impl: I,
+        url_namespace: &'static str,
+        metrics_prefix: &str,
+        attestations: bool,
+    ) -> Arc<IndexerServiceState<I>>
+    where
+        I: IndexerServiceImpl + Sync + Send + 'static,
+    {
+        Arc::new(IndexerServiceState {
+            config: self.config.clone(),
+            attestation_signers: self.attestation_signers.clone(),
+            tap_manager: self.tap_manager.clone(),
+            service_impl: Arc::new(service_impl),
+            metrics: IndexerServiceMetrics::new(metrics_prefix),
+            sender_pricing: self.sender_pricing.clone(),
+            database: self.database.clone(),
+            query_stats: self.query_stats.clone(),
+            paused_deployments: self.paused_deployments.clone(),
+            allocation_eligible: self.allocation_eligible.clone(),
+            events: self.events.clone(),
+            attestations,
+            read_only_database: self.read_only_database.clone(),
+            virtual_allocations: self.virtual_allocations.clone(),
+            url_namespace,
+            domain_separator: self.domain_separator.clone(),
+        })
+    }
+}
+
+/// A data service, with the type of its implementation erased so that services of different
+/// types can be registered together.
+pub struct DataService {
+    pub(super) url_namespace: &'static str,
+    routes: Box<dyn FnOnce(&PaymentLayer) -> Router + Send>,
+}
+
+impl DataService {
+    pub fn new<I>(options: DataServiceOptions<I>) -> Self
+    where
+        I: IndexerServiceImpl + Sync + Send + 'static,
+    {
+        Self {
+            url_namespace: options.url_namespace,
+            routes: Box::new(move |payments| {
+                let state = payments.state(
+                    options.service_impl,
+                    options.url_namespace,
+                    options.metrics_prefix,
+                    options.attestations,
+                );
+                data_routes(&payments.config.server.url_prefix, options.url_namespace)
+                    .merge(options.extra_routes)
+                    .with_state(state)
+            }),
+        }
+    }
+
+    pub(super) fn routes(self, payments: &PaymentLayer) -> Router {
+        (self.routes)(payments)
+    }
+}
+
+pub(super) fn data_routes<I>(
+    url_prefix: &str,
+    url_namespace: &str,
+) -> Router<Arc<IndexerServiceState<I>>>
+where
+    I: IndexerServiceImpl + Sync + Send + 'static,
+{
+    Router::new().route(
+        PathBuf::from(url_prefix)
+            .join(format!("{}/id/:id", url_namespace))
+            .to_str()
+            .expect("Failed to set up `/{url_namespace}/id/:id` route"),
+        post(request_handler::<I>),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::convert::Infallible;
+    use std::str::FromStr;
+    use std::sync::Mutex;
+
+    use anyhow::anyhow;
+    use axum::{
+        body::Body,
+        http::{Request, StatusCode},
+    };
+    use serde_json::{json, Value};
+    use tap_core::receipt::{
+        checks::{Check, CheckResult, Checks},
+        Checking, ReceiptWithState,
+    };
+    use thegraph::types::Attestation;
+    use tower::ServiceExt;
+
+    use crate::{
+        escrow_accounts::EscrowAccounts,
+        query_stats::{self, QueryStatsRecorder},
+        tap::{IndexerTapContext, SenderPricing},
+        test_vectors::{create_signed_receipt, INDEXER_ALLOCATIONS, TAP_EIP712_DOMAIN},
+    };
+
+    use super::super::IndexerServiceResponse;
+    use super::*;
+
+    /// Rejects the receipts it has already seen, on any namespace.
+    #[derive(Default)]
+    struct UniqueReceipts(Mutex<HashSet<u64>>);
+
+    #[async_trait::async_trait]
+    impl Check for UniqueReceipts {
+        async fn check(&self, receipt: &ReceiptWithState<Checking>) -> CheckResult {
+            if self
+                .0
+                .lock()
+                .unwrap()
+                .insert(receipt.signed_receipt().message.nonce)
+            {
+                Ok(())
+            } else {
+                Err(anyhow!("Receipt already used"))
+            }
+        }
+    }
+
+    struct Accept;
+
+    #[async_trait::async_trait]
+    impl Check for Accept {
+        async fn check(&self, _receipt: &ReceiptWithState<Checking>) -> CheckResult {
+            Ok(())
+        }
+    }
+
+    #[derive(Debug)]
+    struct EchoResponse(String);
+
+    impl IndexerServiceResponse for EchoResponse {
+        type Data = String;
+        type Error = Infallible;
+
+        fn is_attestable(&self) -> bool {
+            false
+        }
+
+        fn as_str(&self) -> Result<&str, Self::Error> {
+            Ok(&self.0)
+        }
+
+        fn finalize(self, _attestation: Option<Attestation>) -> Self::Data {
+            self.0
+        }
+    }
+
+    struct Echo;
+
+    #[async_trait::async_trait]
+    impl IndexerServiceImpl for Echo {
+        type Error = Infallible;
+        type Request = Value;
+        type Response = EchoResponse;
+        type State = ();
+
+        async fn process_request(
+            &self,
+            _manifest_id: DeploymentId,
+            request: Self::Request,
+        ) -> Result<(Self::Request, Self::Response), Self::Error> {
+            let response = EchoResponse(request.to_string());
+            Ok((request, response))
+        }
+    }
+
+    fn data_service(url_namespace: &'static str, metrics_prefix: &'static str) -> DataService {
+        DataService::new(DataServiceOptions {
+            service_impl: Echo,
+            url_namespace,
+            metrics_prefix,
+            attestations: false,
+            extra_routes: Router::new(),
+        })
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_data_services_share_receipt_verification(pgpool: PgPool) {
+        let config: IndexerServiceConfig = serde_json::from_value(json!({
+            "indexer": {
+                "indexer_address": "0xd75c4dbcb215a6cf9097cfbcc70aab2596b96a9c",
+                "operator_mnemonic": "",
+            },
+            "server": {
+                "host_and_port": "0.0.0.0:7600",
+                "metrics_host_and_port": "0.0.0.0:7300",
+                "url_prefix": "/",
+            },
+            "database": { "postgres_url": "", "slow_query_threshold_ms": 1000 },
+            "network_subgraph": {
+                "query_url": "",
+                "syncing_interval": 60,
+                "recently_closed_allocation_buffer_seconds": 0,
+            },
+            "escrow_subgraph": {
+                "query_url": "",
+                "syncing_interval": 60,
+                "recently_closed_allocation_buffer_seconds": 0,
+            },
+            "graph_network": { "chain_id": 1 },
+            "tap": {
+                "chain_id": 1,
+                "receipts_verifier_address": "0xfc24cE7a4428A6B89B52645243662A02BA734ECF",
+                "timestamp_error_tolerance": 30,
+                "receipt_max_value": 1_000_000,
+                "receipt_min_value": 0,
+                "receipt_min_value_per_deployment": {},
+            },
+        }))
+        .unwrap();
+        let domain_separator = TAP_EIP712_DOMAIN.clone();
+        let payments = PaymentLayer {
+            config,
+            attestation_signers: Eventual::from_value(HashMap::new()),
+            tap_manager: Arc::new(Manager::new(
+                domain_separator.clone(),
+                IndexerTapContext::new(pgpool.clone(), domain_separator.clone()).await,
+                Checks::new(vec![Arc::new(UniqueReceipts::default())]),
+            )),
+            sender_pricing: SenderPricing::new(
+                HashMap::new(),
+                Eventual::from_value(EscrowAccounts::default()),
+                domain_separator.clone(),
+            ),
+            query_stats: QueryStatsRecorder::spawn(pgpool.clone(), query_stats::RETENTION),
+            database: pgpool.clone(),
+            paused_deployments: Eventual::from_value(HashMap::new()),
+            allocation_eligible: Arc::new(Accept),
+            events: broadcast::channel(1).0,
+            read_only_database: None,
+            virtual_allocations: Eventual::from_value(HashMap::new()),
+            domain_separator,
+        };
+        let router = data_service("subgraphs", "test_shared_subgraphs")
+            .routes(&payments)
+            .merge(data_service("sql", "test_shared_sql").routes(&payments));
+
+        let deployment =
+            DeploymentId::from_str("QmAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA").unwrap();
+        let allocation_id = *INDEXER_ALLOCATIONS.keys().next().unwrap();
+        let request = |url_namespace: &str, nonce: u64| {
+            let router = router.clone();
+            let url = format!("/{}/id/{}", url_namespace, deployment);
+            async move {
+                let receipt = create_signed_receipt(allocation_id, nonce, 1, 100).await;
+                router
+                    .oneshot(
+                        Request::post(url)
+                            .header("tap-receipt", serde_json::to_string(&receipt).unwrap())
+                            .header("content-type", "application/json")
+                            .body(Body::from(r#"{"query": "{ a }"}"#))
+                            .unwrap(),
+                    )
+                    .await
+                    .unwrap()
+                    .status()
+            }
+        };
+
+        // Both namespaces are paid through the same TAP manager, a receipt accepted by one of
+        // them is rejected by the other
+        assert_eq!(request("subgraphs", 1).await, StatusCode::OK);
+        assert_eq!(request("sql", 1).await, StatusCode::BAD_REQUEST);
+        assert_eq!(request("sql", 2).await, StatusCode::OK);
+        assert_eq!(request("subgraphs", 2).await, StatusCode::BAD_REQUEST);
+
+        let stored: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM scalar_tap_receipts")
+            .fetch_one(&pgpool)
+            .await
+            .unwrap();
+        assert_eq!(stored, 2);
+    }
+}
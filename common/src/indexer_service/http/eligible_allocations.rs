@@ -0,0 +1,298 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Lists the allocations the receipts for a deployment can currently be sent for, i.e. the ones
+//! accepted by the allocation eligibility check, so that gateways can sync them before sending
+//! traffic. Responses carry an ETag derived from the listed allocations, for gateways to poll
+//! with `If-None-Match` and only get a body when the set changes. The set can also be
+//! [watched](EligibleAllocations::watch), e.g. by the subscriptions of the services.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::{
+    extract::{Path, Query},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use eventuals::{Eventual, EventualReader};
+use serde::{Deserialize, Serialize};
+use thegraph::types::{Address, DeploymentId};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::{interval, Interval, MissedTickBehavior};
+
+use crate::allocations::{grace_period::GracePeriods, Allocation};
+use crate::events::RecordedEvent;
+
+/// Interval of the checks of a watched deployment for the grace periods running out, which
+/// nothing is notified of.
+const WATCH_RECHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+pub struct EligibleAllocations {
+    pub indexer_allocations: Eventual<HashMap<Address, Allocation>>,
+    pub grace_periods: GracePeriods,
+}
+
+impl EligibleAllocations {
+    /// Allocations of `deployment` accepting the receipts of `sender` at `now`, in seconds since
+    /// the UNIX epoch, sorted. Without a sender, the per-sender grace periods are ignored.
+    pub fn for_deployment(
+        &self,
+        deployment: &DeploymentId,
+        sender: Option<&Address>,
+        now: u64,
+    ) -> Vec<Address> {
+        let allocations = self
+            .indexer_allocations
+            .value_immediate()
+            .unwrap_or_default();
+        let mut eligible: Vec<Address> = allocations
+            .values()
+            .filter(|allocation| allocation.subgraph_deployment.id == *deployment)
+            .filter(|allocation| {
+                let replaced_at = self.grace_periods.replaced_at(&allocations, allocation);
+                self.grace_periods
+                    .check(allocation, replaced_at, sender, now)
+                    .is_ok()
+            })
+            .map(|allocation| allocation.id)
+            .collect();
+        eligible.sort();
+        eligible
+    }
+
+    /// Watches the allocations of `deployment` accepting the receipts of `sender`, re-checked on
+    /// every event of the event log, on every refresh of the allocations, and periodically.
+    pub fn watch(
+        self: Arc<Self>,
+        deployment: DeploymentId,
+        sender: Option<Address>,
+        events: broadcast::Receiver<RecordedEvent>,
+    ) -> EligibleAllocationsWatch {
+        let mut recheck = interval(WATCH_RECHECK_INTERVAL);
+        recheck.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        EligibleAllocationsWatch {
+            allocations: self.indexer_allocations.subscribe(),
+            eligible_allocations: self,
+            deployment,
+            sender,
+            events,
+            recheck,
+            current: None,
+        }
+    }
+}
+
+pub struct EligibleAllocationsWatch {
+    eligible_allocations: Arc<EligibleAllocations>,
+    deployment: DeploymentId,
+    sender: Option<Address>,
+    events: broadcast::Receiver<RecordedEvent>,
+    allocations: EventualReader<HashMap<Address, Allocation>>,
+    recheck: Interval,
+    current: Option<Vec<Address>>,
+}
+
+impl EligibleAllocationsWatch {
+    /// The eligible allocations as of now the first time, then every time they change. `None`
+    /// once the event log or the allocations are no longer updated.
+    pub async fn next(&mut self) -> Option<Vec<Address>> {
+        loop {
+            if self.current.is_some() {
+                tokio::select! {
+                    event = self.events.recv() => match event {
+                        Ok(_) | Err(RecvError::Lagged(_)) => {}
+                        Err(RecvError::Closed) => return None,
+                    },
+                    allocations = self.allocations.next() => {
+                        allocations.ok()?;
+                    }
+                    _ = self.recheck.tick() => {}
+                }
+            }
+
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("Time went backwards")
+                .as_secs();
+            let eligible = self.eligible_allocations.for_deployment(
+                &self.deployment,
+                self.sender.as_ref(),
+                now,
+            );
+            if self.current.as_ref() != Some(&eligible) {
+                self.current = Some(eligible.clone());
+                return Some(eligible);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EligibleAllocationsQuery {
+    pub sender: Option<Address>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct EligibleAllocationsResponse {
+    pub deployment: DeploymentId,
+    pub allocations: Vec<Address>,
+}
+
+fn etag(deployment: &DeploymentId, allocations: &[Address]) -> String {
+    let mut bytes = deployment.0.to_vec();
+    for allocation in allocations {
+        bytes.extend_from_slice(allocation.as_slice());
+    }
+    format!("\"{:x}\"", alloy_primitives::keccak256(bytes))
+}
+
+/// Whether the client's cached copy, per its `If-None-Match` header, is still current.
+fn is_not_modified(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|tag| {
+            let tag = tag.trim();
+            tag == "*" || tag.trim_start_matches("W/") == etag
+        })
+}
+
+pub async fn eligible_allocations_handler(
+    Extension(eligible_allocations): Extension<Arc<EligibleAllocations>>,
+    Path(deployment): Path<DeploymentId>,
+    Query(query): Query<EligibleAllocationsQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs();
+    let allocations = eligible_allocations.for_deployment(&deployment, query.sender.as_ref(), now);
+    let etag = etag(&deployment, &allocations);
+    let etag_header = HeaderValue::from_str(&etag).expect("hex digest is a valid header value");
+    // Cached, but revalidated before every use, the set changing at any time
+    let cache_control = HeaderValue::from_static("no-cache");
+
+    if is_not_modified(&headers, &etag) {
+        return (
+            StatusCode::NOT_MODIFIED,
+            [
+                (header::ETAG, etag_header),
+                (header::CACHE_CONTROL, cache_control),
+            ],
+        )
+            .into_response();
+    }
+    (
+        [
+            (header::ETAG, etag_header),
+            (header::CACHE_CONTROL, cache_control),
+        ],
+        Json(EligibleAllocationsResponse {
+            deployment,
+            allocations,
+        }),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::test_vectors::INDEXER_ALLOCATIONS;
+
+    use super::*;
+
+    #[test]
+    fn test_eligible_allocations() {
+        let sender = Address::from([0x11; 20]);
+        let now = 100_000;
+        let mut allocations = INDEXER_ALLOCATIONS.clone();
+        let mut ids: Vec<Address> = allocations.keys().copied().collect();
+        ids.sort();
+        let deployment = allocations[&ids[0]].subgraph_deployment.id;
+        // Past the default grace period but not the sender's
+        allocations.get_mut(&ids[0]).unwrap().closed_at = Some(now - 3600);
+        let expected: Vec<Address> = ids
+            .iter()
+            .copied()
+            .filter(|id| allocations[id].subgraph_deployment.id == deployment)
+            .collect();
+
+        let eligible_allocations = EligibleAllocations {
+            indexer_allocations: Eventual::from_value(allocations),
+            grace_periods: GracePeriods {
+                default: Duration::from_secs(60),
+                per_network: HashMap::new(),
+                per_sender: HashMap::from([(sender, Duration::from_secs(7200))]),
+                rollover_overlap: None,
+            },
+        };
+        assert_eq!(
+            eligible_allocations.for_deployment(&deployment, Some(&sender), now),
+            expected
+        );
+        assert_eq!(
+            eligible_allocations.for_deployment(&deployment, None, now),
+            expected[1..]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_watch() {
+        let (mut allocations_writer, allocations) = Eventual::new();
+        allocations_writer.write(INDEXER_ALLOCATIONS.clone());
+        let eligible_allocations = Arc::new(EligibleAllocations {
+            indexer_allocations: allocations,
+            grace_periods: GracePeriods::default(),
+        });
+        let deployment = INDEXER_ALLOCATIONS
+            .values()
+            .next()
+            .unwrap()
+            .subgraph_deployment
+            .id;
+        let (events, _) = broadcast::channel(16);
+        let mut watch = eligible_allocations.watch(deployment, None, events.subscribe());
+
+        assert!(!watch.next().await.unwrap().is_empty());
+
+        let mut allocations = INDEXER_ALLOCATIONS.clone();
+        allocations.retain(|_, allocation| allocation.subgraph_deployment.id != deployment);
+        allocations_writer.write(allocations);
+        let changed = tokio::time::timeout(Duration::from_secs(5), watch.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(changed.is_empty());
+    }
+
+    #[test]
+    fn test_etag() {
+        let deployment = INDEXER_ALLOCATIONS
+            .values()
+            .next()
+            .unwrap()
+            .subgraph_deployment
+            .id;
+        let allocations = [Address::from([0x11; 20]), Address::from([0x22; 20])];
+        let etag = etag(&deployment, &allocations);
+        assert_ne!(etag, super::etag(&deployment, &allocations[..1]));
+
+        let mut headers = HeaderMap::new();
+        assert!(!is_not_modified(&headers, &etag));
+        headers.insert(
+            header::IF_NONE_MATCH,
+            HeaderValue::from_str(&format!("\"other\", W/{etag}")).unwrap(),
+        );
+        assert!(is_not_modified(&headers, &etag));
+        headers.insert(header::IF_NONE_MATCH, HeaderValue::from_static("\"other\""));
+        assert!(!is_not_modified(&headers, &etag));
+    }
+}
@@ -2,11 +2,18 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use std::{
-    collections::HashMap, error::Error, fmt::Debug, net::SocketAddr, path::PathBuf, sync::Arc,
+    collections::{HashMap, HashSet},
+    error::Error,
+    fmt::Debug,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
     time::Duration,
 };
 
-use alloy_sol_types::eip712_domain;
+use alloy_sol_types::{eip712_domain, Eip712Domain};
 use anyhow;
 use autometrics::prometheus_exporter;
 use axum::extract::MatchedPath;
@@ -21,32 +28,69 @@ use axum::{
 use axum::{serve, ServiceExt};
 use build_info::BuildInfo;
 use eventuals::Eventual;
-use reqwest::StatusCode;
+use lazy_static::lazy_static;
+use reqwest::{StatusCode, Url};
 use serde::{de::DeserializeOwned, Serialize};
-use sqlx::postgres::PgPoolOptions;
-use tap_core::{manager::Manager, receipt::checks::Checks};
+use sqlx::PgPool;
+use tap_core::{
+    manager::Manager,
+    receipt::checks::{Checks, ReceiptCheck},
+};
 use thegraph::types::Address;
 use thegraph::types::{Attestation, DeploymentId};
 use thiserror::Error;
 use tokio::net::TcpListener;
 use tokio::signal;
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
 use tower_governor::{governor::GovernorConfigBuilder, GovernorLayer};
 use tower_http::{cors, cors::CorsLayer, normalize_path::NormalizePath, trace::TraceLayer};
 use tracing::{info, info_span};
 
 use crate::{
-    address::public_key,
+    address::{public_key, wallet_address},
+    allocations::{
+        grace_period::{AllocationClosed, GracePeriods},
+        sources::{registered_allocations, static_allocations, AllocationSource},
+        virtual_allocations::{virtual_allocations, VirtualAllocation},
+    },
+    db_metrics, db_pool,
+    doctor::{self, check_escrow_network},
+    events::{spawn_event_bus, EventListener, RecordedEvent},
+    fleet_stats::{self, FleetStats},
+    http_proxy,
     indexer_service::http::{
         metrics::IndexerServiceMetrics, static_subgraph::static_subgraph_request_handler,
     },
+    monitor_backoff,
     prelude::{
-        attestation_signers, dispute_manager, escrow_accounts, indexer_allocations,
+        attestation_signers, dispute_manager, escrow_accounts, escrow_accounts_with_overrides,
+        indexer_allocations, indexer_allocations_with_quorum, majority_quorum, paused_deployments,
         AttestationSigner, DeploymentDetails, SubgraphClient,
     },
-    tap::IndexerTapContext,
+    query_stats::{self, QueryStatsRecorder},
+    readiness::Readiness,
+    receipt_notifications, schema_drift, slo,
+    tap::{
+        IndexerTapContext, ReadOnlyDatabase, ReceiptQueue, ReceiptValidator, ReceiptValueLimits,
+        SenderPricing, ALLOCATION_ELIGIBLE_CHECK,
+    },
+    tenant,
 };
 
-use super::{request_handler::request_handler, IndexerServiceConfig};
+use super::{
+    attestations::verify_attestations_handler,
+    capabilities::{Capabilities, MISC_RATE_LIMIT, STATIC_SUBGRAPH_RATE_LIMIT},
+    data_service::{data_routes, DataService, PaymentLayer},
+    eligible_allocations::{eligible_allocations_handler, EligibleAllocations},
+    query_binding::QueryBindingError,
+    receipt_digest::{receipt_digest_challenge_handler, receipt_digest_handler, ReceiptDigests},
+    receipt_payment::ReceiptPaymentError,
+    receipt_transport::ReceiptTransportError,
+    receipt_validation::validate_receipt_handler,
+    runtime_info::{schema_version, RuntimeInfo},
+    IndexerServiceConfig,
+};
 
 pub trait IndexerServiceResponse {
     type Data: IntoResponse;
@@ -55,6 +99,12 @@ pub trait IndexerServiceResponse {
     fn is_attestable(&self) -> bool;
     fn as_str(&self) -> Result<&str, Self::Error>;
     fn finalize(self, attestation: Option<Attestation>) -> Self::Data;
+
+    /// Time the backend took to execute the request, recorded along with the receipt that paid
+    /// for it. `None` if unknown.
+    fn execution_time(&self) -> Option<Duration> {
+        None
+    }
 }
 
 #[async_trait]
@@ -71,6 +121,10 @@ pub trait IndexerServiceImpl {
     ) -> Result<(Self::Request, Self::Response), Self::Error>;
 }
 
+const DEFAULT_ESCROW_PAGE_SIZE: usize = 1000;
+/// How often graph-node is asked for the health of the allocated deployments.
+const INDEXING_STATUS_INTERVAL: Duration = Duration::from_secs(30);
+
 #[derive(Debug, Error)]
 pub enum IndexerServiceError<E>
 where
@@ -78,8 +132,22 @@ where
 {
     #[error("Issues with provided receipt: {0}")]
     ReceiptError(tap_core::Error),
+    #[error("{0}")]
+    InvalidReceiptTransport(ReceiptTransportError),
+    #[error("{0}")]
+    InvalidQueryBinding(QueryBindingError),
+    #[error("{0}")]
+    InvalidReceiptPayment(ReceiptPaymentError),
+    #[error("Failed to store the receipts paying for the query: {0}")]
+    FailedToStoreReceipts(anyhow::Error),
     #[error("Service is not ready yet, try again in a moment")]
     ServiceNotReady,
+    #[error("{0}")]
+    ClosedAllocation(AllocationClosed),
+    #[error("Paid queries to deployment `{0}` are paused: {1}")]
+    DeploymentPaused(DeploymentId, String),
+    #[error("Allocation `{0}` is a virtual allocation of the `{1}` service")]
+    VirtualAllocationOfOtherService(Address, String),
     #[error("No attestation signer found for allocation `{0}`")]
     NoSignerForAllocation(Address),
     #[error("No attestation signer found for manifest `{0}`")]
@@ -94,6 +162,8 @@ where
     InvalidFreeQueryAuthToken,
     #[error("Failed to sign attestation")]
     FailedToSignAttestation,
+    #[error("Failed to wrap the response in an envelope")]
+    FailedToBuildEnvelope,
     #[error("Failed to query subgraph: {0}")]
     FailedToQueryStaticSubgraph(anyhow::Error),
 }
@@ -111,15 +181,23 @@ where
         }
 
         let status = match self {
-            ServiceNotReady => StatusCode::SERVICE_UNAVAILABLE,
+            ServiceNotReady | DeploymentPaused(..) => StatusCode::SERVICE_UNAVAILABLE,
 
             Unauthorized => StatusCode::UNAUTHORIZED,
 
-            NoSignerForAllocation(_) | NoSignerForManifest(_) | FailedToSignAttestation => {
-                StatusCode::INTERNAL_SERVER_ERROR
-            }
+            ClosedAllocation(_) => StatusCode::GONE,
+
+            NoSignerForAllocation(_)
+            | NoSignerForManifest(_)
+            | FailedToSignAttestation
+            | FailedToBuildEnvelope
+            | FailedToStoreReceipts(_) => StatusCode::INTERNAL_SERVER_ERROR,
 
             ReceiptError(_)
+            | InvalidReceiptTransport(_)
+            | InvalidQueryBinding(_)
+            | InvalidReceiptPayment(_)
+            | VirtualAllocationOfOtherService(..)
             | InvalidRequest(_)
             | InvalidFreeQueryAuthToken
             | ProcessingError(_) => StatusCode::BAD_REQUEST,
@@ -140,13 +218,29 @@ where
 #[derive(Clone, Serialize)]
 pub struct IndexerServiceRelease {
     version: String,
+    git_sha: Option<String>,
     dependencies: HashMap<String, String>,
 }
 
+impl IndexerServiceRelease {
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    pub fn git_sha(&self) -> Option<&str> {
+        self.git_sha.as_deref()
+    }
+}
+
 impl From<&BuildInfo> for IndexerServiceRelease {
     fn from(value: &BuildInfo) -> Self {
         Self {
             version: value.crate_info.version.to_string(),
+            git_sha: value
+                .version_control
+                .as_ref()
+                .and_then(|version_control| version_control.git())
+                .map(|git| git.commit_id.clone()),
             dependencies: HashMap::from_iter(
                 value
                     .crate_info
@@ -167,7 +261,12 @@ where
     pub release: IndexerServiceRelease,
     pub url_namespace: &'static str,
     pub metrics_prefix: &'static str,
+    /// Routes specific to the service. They can extract the events of the event log, as an
+    /// `Extension<broadcast::Sender<RecordedEvent>>`, and the eligible allocations, as an
+    /// `Extension<Arc<EligibleAllocations>>`.
     pub extra_routes: Router<Arc<IndexerServiceState<I>>>,
+    /// Other data services to serve behind the same payment layer, under their own namespaces.
+    pub data_services: Vec<DataService>,
 }
 
 pub struct IndexerServiceState<I>
@@ -176,9 +275,28 @@ where
 {
     pub config: IndexerServiceConfig,
     pub attestation_signers: Eventual<HashMap<Address, AttestationSigner>>,
-    pub tap_manager: Manager<IndexerTapContext>,
+    pub tap_manager: Arc<Manager<IndexerTapContext>>,
     pub service_impl: Arc<I>,
     pub metrics: IndexerServiceMetrics,
+    pub sender_pricing: SenderPricing,
+    pub database: PgPool,
+    /// Stats of the paid queries, stored in the background.
+    pub query_stats: QueryStatsRecorder,
+    /// Deployments whose paid queries are paused, with the reason.
+    pub paused_deployments: Eventual<HashMap<DeploymentId, String>>,
+    /// Run ahead of the other receipt checks, to tell closed allocations apart.
+    pub allocation_eligible: ReceiptCheck,
+    /// Events published by the tap-agent and the database, for the services to react to.
+    pub events: broadcast::Sender<RecordedEvent>,
+    /// Whether to attest the responses marked as attestable.
+    pub attestations: bool,
+    pub read_only_database: Option<Arc<ReadOnlyDatabase>>,
+    /// Virtual allocations of the services accepting them, by allocation ID.
+    pub virtual_allocations: Eventual<HashMap<Address, VirtualAllocation>>,
+    /// URL namespace of the service, the one its virtual allocations are declared for.
+    pub url_namespace: &'static str,
+    /// EIP-712 domain the receipts are signed for.
+    pub domain_separator: Eip712Domain,
 }
 
 pub struct IndexerService {}
@@ -188,50 +306,190 @@ impl IndexerService {
     where
         I: IndexerServiceImpl + Sync + Send + 'static,
     {
-        let metrics = IndexerServiceMetrics::new(options.metrics_prefix);
+        let mut url_namespaces = HashSet::from([options.url_namespace]);
+        for data_service in &options.data_services {
+            if !url_namespaces.insert(data_service.url_namespace) {
+                anyhow::bail!(
+                    "Data service namespace `{}` is registered more than once",
+                    data_service.url_namespace
+                );
+            }
+        }
 
-        let http_client = reqwest::Client::builder()
+        let http_client = http_proxy::client_builder(&options.config.server.proxy)
             .tcp_nodelay(true)
             .timeout(Duration::from_secs(30))
             .build()
             .expect("Failed to init HTTP client");
 
-        let network_subgraph: &'static SubgraphClient = Box::leak(Box::new(SubgraphClient::new(
+        monitor_backoff::configure(
+            options.config.server.monitor_alarms.clone(),
             http_client.clone(),
-            options
-                .config
-                .graph_node
-                .as_ref()
-                .zip(options.config.network_subgraph.deployment)
-                .map(|(graph_node, deployment)| {
-                    DeploymentDetails::for_graph_node(
-                        &graph_node.status_url,
-                        &graph_node.query_base_url,
-                        deployment,
-                    )
-                })
-                .transpose()?,
-            DeploymentDetails::for_query_url_with_token(
-                &options.config.network_subgraph.query_url,
-                options.config.network_subgraph.query_auth_token.clone(),
-            )?,
-        )));
+        );
+
+        // Receipts checked against the escrow accounts of another network are all rejected
+        doctor::startup_check(
+            check_escrow_network(
+                &http_client,
+                options
+                    .config
+                    .graph_node
+                    .as_ref()
+                    .map(|graph_node| graph_node.status_url.as_str()),
+                options.config.escrow_subgraph.query_url.as_str(),
+                options.config.escrow_subgraph.query_auth_token.as_deref(),
+                options.config.escrow_subgraph.deployment,
+                options.config.tap.chain_id,
+            )
+            .await,
+            options.config.tap.allow_escrow_network_mismatch,
+        )?;
+
+        // Establish Database connection necessary for serving indexer management
+        // requests with defined schema
+        // Note: Typically, you'd call `sqlx::migrate!();` here to sync the models
+        // which defaults to files in  "./migrations" to sync the database;
+        // however, this can cause conflicts with the migrations run by indexer
+        // agent. Hence we leave syncing and migrating entirely to the agent and
+        // assume the models are up to date in the service.
+        db_metrics::set_slow_query_threshold(Duration::from_millis(
+            options.config.database.slow_query_threshold_ms,
+        ));
+        db_pool::configure(options.config.database.pool.clone());
+        let pool_options = options
+            .config
+            .database
+            .pool
+            .pool_options()
+            .acquire_timeout(Duration::from_secs(30));
+        let database = if options.config.database.tenant_isolation {
+            tenant::isolate(pool_options, options.config.indexer.indexer_address)
+                .connect(&options.config.database.postgres_url)
+                .await?
+        } else {
+            pool_options
+                .connect(&options.config.database.postgres_url)
+                .await?
+        };
+        db_pool::monitor("receipts", &database);
+        // The receipts are refused while the schema drifted
+        schema_drift::start(database.clone(), schema_drift::SCHEMA_CHECK_INTERVAL).await;
+
+        let network_subgraph: &'static SubgraphClient = Box::leak(Box::new(
+            SubgraphClient::new(
+                http_client.clone(),
+                options
+                    .config
+                    .graph_node
+                    .as_ref()
+                    .zip(options.config.network_subgraph.deployment)
+                    .map(|(graph_node, deployment)| {
+                        DeploymentDetails::for_graph_node(
+                            &graph_node.status_url,
+                            &graph_node.query_base_url,
+                            deployment,
+                        )
+                    })
+                    .transpose()?,
+                DeploymentDetails::for_query_url_with_token(
+                    &options.config.network_subgraph.query_url,
+                    options.config.network_subgraph.query_auth_token.clone(),
+                )?,
+            )
+            .with_name("network"),
+        ));
 
         // Identify the dispute manager for the configured network
-        let dispute_manager = dispute_manager(network_subgraph, Duration::from_secs(3600));
+        let dispute_manager = match options.config.graph_network.dispute_manager {
+            Some(dispute_manager) => Eventual::from_value(dispute_manager),
+            None => dispute_manager(network_subgraph, Duration::from_secs(3600)),
+        };
 
         // Monitor the indexer's own allocations
-        let allocations = indexer_allocations(
-            network_subgraph,
-            options.config.indexer.indexer_address,
-            Duration::from_secs(options.config.network_subgraph.syncing_interval),
-            Duration::from_secs(
-                options
+        let allocations_interval =
+            Duration::from_secs(options.config.network_subgraph.syncing_interval);
+        let network_subgraph_config = &options.config.network_subgraph;
+        let grace_periods = GracePeriods {
+            default: Duration::from_secs(
+                network_subgraph_config.recently_closed_allocation_buffer_seconds,
+            ),
+            per_network: network_subgraph_config
+                .recently_closed_allocation_buffer_seconds_per_network
+                .iter()
+                .map(|(network, secs)| (network.clone(), Duration::from_secs(*secs)))
+                .collect(),
+            per_sender: network_subgraph_config
+                .recently_closed_allocation_buffer_seconds_per_sender
+                .iter()
+                .map(|(sender, secs)| (*sender, Duration::from_secs(*secs)))
+                .collect(),
+            rollover_overlap: network_subgraph_config
+                .rollover_overlap_seconds
+                .map(Duration::from_secs),
+        };
+        // Closed allocations are fetched for as long as any of them may still accept receipts
+        let recently_closed_allocation_buffer = grace_periods.max();
+        let allocation_source = options.config.network_subgraph.allocation_source;
+        info!(?allocation_source, "Allocations accepting receipts");
+        let allocations = match allocation_source {
+            AllocationSource::Static => static_allocations(
+                options.config.indexer.indexer_address,
+                &options.config.network_subgraph.static_allocations,
+            ),
+            AllocationSource::Api => registered_allocations(
+                database.clone(),
+                options.config.indexer.indexer_address,
+                allocations_interval,
+                recently_closed_allocation_buffer,
+            ),
+            AllocationSource::Network
+                if options
                     .config
                     .network_subgraph
-                    .recently_closed_allocation_buffer_seconds,
-            ),
-        );
+                    .additional_query_urls
+                    .is_empty() =>
+            {
+                indexer_allocations(
+                    network_subgraph,
+                    options.config.indexer.indexer_address,
+                    allocations_interval,
+                    recently_closed_allocation_buffer,
+                )
+            }
+            AllocationSource::Network => {
+                let mut network_subgraphs = vec![(
+                    options.config.network_subgraph.query_url.clone(),
+                    network_subgraph,
+                )];
+                for query_url in &options.config.network_subgraph.additional_query_urls {
+                    let client: &'static SubgraphClient = Box::leak(Box::new(
+                        SubgraphClient::new(
+                            http_client.clone(),
+                            None,
+                            // Mirrors of the same subgraph, behind the same gateway auth
+                            DeploymentDetails::for_query_url_with_token(
+                                query_url,
+                                options.config.network_subgraph.query_auth_token.clone(),
+                            )?,
+                        )
+                        .with_name("network"),
+                    ));
+                    network_subgraphs.push((query_url.clone(), client));
+                }
+                let quorum = options
+                    .config
+                    .network_subgraph
+                    .quorum
+                    .unwrap_or(majority_quorum(network_subgraphs.len()));
+                indexer_allocations_with_quorum(
+                    network_subgraphs,
+                    quorum,
+                    options.config.indexer.indexer_address,
+                    allocations_interval,
+                    recently_closed_allocation_buffer,
+                )
+            }
+        };
 
         // Maintain an up-to-date set of attestation signers, one for each
         // allocation
@@ -242,46 +500,90 @@ impl IndexerService {
             dispute_manager,
         );
 
-        let escrow_subgraph: &'static SubgraphClient = Box::leak(Box::new(SubgraphClient::new(
-            http_client,
-            options
-                .config
-                .graph_node
-                .as_ref()
-                .zip(options.config.escrow_subgraph.deployment)
-                .map(|(graph_node, deployment)| {
-                    DeploymentDetails::for_graph_node(
-                        &graph_node.status_url,
-                        &graph_node.query_base_url,
-                        deployment,
-                    )
-                })
-                .transpose()?,
-            DeploymentDetails::for_query_url_with_token(
-                &options.config.escrow_subgraph.query_url,
-                options.config.escrow_subgraph.query_auth_token.clone(),
-            )?,
-        )));
+        let escrow_subgraph: &'static SubgraphClient = Box::leak(Box::new(
+            SubgraphClient::new(
+                http_client.clone(),
+                options
+                    .config
+                    .graph_node
+                    .as_ref()
+                    .zip(options.config.escrow_subgraph.deployment)
+                    .map(|(graph_node, deployment)| {
+                        DeploymentDetails::for_graph_node(
+                            &graph_node.status_url,
+                            &graph_node.query_base_url,
+                            deployment,
+                        )
+                    })
+                    .transpose()?,
+                DeploymentDetails::for_query_url_with_token(
+                    &options.config.escrow_subgraph.query_url,
+                    options.config.escrow_subgraph.query_auth_token.clone(),
+                )?,
+            )
+            .with_name("escrow"),
+        ));
 
         let escrow_accounts = escrow_accounts(
             escrow_subgraph,
             options.config.indexer.indexer_address,
             Duration::from_secs(options.config.escrow_subgraph.syncing_interval),
             true, // Reject thawing signers eagerly
+            options
+                .config
+                .escrow_subgraph
+                .page_size
+                .unwrap_or(DEFAULT_ESCROW_PAGE_SIZE),
         );
 
-        // Establish Database connection necessary for serving indexer management
-        // requests with defined schema
-        // Note: Typically, you'd call `sqlx::migrate!();` here to sync the models
-        // which defaults to files in  "./migrations" to sync the database;
-        // however, this can cause conflicts with the migrations run by indexer
-        // agent. Hence we leave syncing and migrating entirely to the agent and
-        // assume the models are up to date in the service.
-        let database = PgPoolOptions::new()
-            .max_connections(50)
-            .acquire_timeout(Duration::from_secs(30))
-            .connect(&options.config.database.postgres_url)
-            .await?;
+        if let Some(socket_path) = &options.config.database.receipt_notification_socket {
+            info!(
+                socket = %socket_path.display(),
+                "Notifying the tap-agent of the new receipts through its socket when listening"
+            );
+            receipt_notifications::configure(socket_path.clone());
+        }
+        let events = spawn_event_bus(EventListener::connect(&database).await?);
+        let virtual_allocations = virtual_allocations(
+            database.clone(),
+            options
+                .config
+                .indexer
+                .operator_mnemonic
+                .expose(wallet_address)?,
+            options.config.tap.virtual_allocation_services.clone(),
+            allocations_interval,
+        );
+        let escrow_accounts = escrow_accounts_with_overrides(
+            escrow_accounts,
+            database.clone(),
+            Duration::from_secs(options.config.escrow_subgraph.syncing_interval),
+        );
+
+        let readiness = Readiness::new();
+        readiness.wait_for("allocations", allocations.clone());
+        readiness.wait_for("escrow_accounts", escrow_accounts.clone());
+        #[cfg(feature = "systemd")]
+        crate::systemd::start(
+            readiness.clone(),
+            vec![
+                Arc::new(crate::systemd::DatabaseCheck(database.clone())),
+                Arc::new(crate::systemd::ReadinessCheck(readiness.clone())),
+            ],
+        );
+
+        // Pause the paid queries to the deployments graph-node can't serve correctly
+        let paused_deployments = match &options.config.graph_node {
+            Some(graph_node) if options.config.server.pause_unhealthy_deployments => {
+                paused_deployments(
+                    http_client.clone(),
+                    allocations.clone(),
+                    Url::parse(&graph_node.status_url)?,
+                    INDEXING_STATUS_INTERVAL,
+                )
+            }
+            _ => Eventual::from_value(HashMap::new()),
+        };
 
         let domain_separator = eip712_domain! {
             name: "TAP",
@@ -289,32 +591,109 @@ impl IndexerService {
             chain_id: options.config.tap.chain_id,
             verifying_contract: options.config.tap.receipts_verifier_address,
         };
-        let indexer_context =
+        let mut indexer_context =
             IndexerTapContext::new(database.clone(), domain_separator.clone()).await;
+        let read_only_database = options
+            .config
+            .tap
+            .read_only_database
+            .as_ref()
+            .map(|config| {
+                info!(policy = ?config.policy, "Handling a read only database");
+                ReadOnlyDatabase::start(database.clone(), config)
+            })
+            .transpose()?;
+        if let Some(read_only_database) = &read_only_database {
+            indexer_context = indexer_context.with_read_only_database(read_only_database.clone());
+        }
+        let receipt_queue = options
+            .config
+            .tap
+            .receipt_queue
+            .as_ref()
+            .map(|config| {
+                info!(
+                    capacity = config.capacity,
+                    writers = config.writers,
+                    overflow = ?config.overflow,
+                    "Storing receipts through a queue"
+                );
+                ReceiptQueue::start(database.clone(), config, read_only_database.clone())
+                    .map(Arc::new)
+            })
+            .transpose()?;
+        if let Some(receipt_queue) = &receipt_queue {
+            indexer_context = indexer_context.with_receipt_queue(receipt_queue.clone());
+        }
         let timestamp_error_tolerance =
             Duration::from_secs(options.config.tap.timestamp_error_tolerance);
 
-        let receipt_max_value = options.config.tap.receipt_max_value;
+        let sender_pricing = SenderPricing::new(
+            options.config.tap.sender_price_multipliers.clone(),
+            escrow_accounts.clone(),
+            domain_separator.clone(),
+        );
+        let value_limits = ReceiptValueLimits {
+            max_value: options.config.tap.receipt_max_value,
+            min_value: options.config.tap.receipt_min_value,
+            min_value_per_deployment: options.config.tap.receipt_min_value_per_deployment.clone(),
+            sender_pricing: sender_pricing.clone(),
+        };
 
-        let checks = IndexerTapContext::get_checks(
-            database,
+        let receipt_digests = Arc::new(ReceiptDigests::new(
+            database.clone(),
+            escrow_accounts.clone(),
+        ));
+        let eligible_allocations = Arc::new(EligibleAllocations {
+            indexer_allocations: allocations.clone(),
+            grace_periods: grace_periods.clone(),
+        });
+        let checks = IndexerTapContext::get_named_checks(
+            database.clone(),
             allocations,
+            virtual_allocations.clone(),
             escrow_accounts,
             domain_separator.clone(),
             timestamp_error_tolerance,
-            receipt_max_value,
+            value_limits,
+            grace_periods,
+            options.config.tap.receipt_acceptance_window.clone(),
+            &events,
         )
         .await;
+        let allocation_eligible = checks
+            .iter()
+            .find(|(name, _)| *name == ALLOCATION_ELIGIBLE_CHECK)
+            .map(|(_, check)| check.clone())
+            .expect("The allocation eligibility check is always run");
 
-        let tap_manager = Manager::new(domain_separator, indexer_context, Checks::new(checks));
+        let tap_manager = Manager::new(
+            domain_separator.clone(),
+            indexer_context,
+            Checks::new(checks.iter().map(|(_, check)| check.clone()).collect()),
+        );
+        let receipt_validator = Arc::new(ReceiptValidator::new(domain_separator.clone(), checks));
 
-        let state = Arc::new(IndexerServiceState {
+        let payments = PaymentLayer {
             config: options.config.clone(),
             attestation_signers,
-            tap_manager,
-            service_impl: Arc::new(options.service_impl),
-            metrics,
-        });
+            tap_manager: Arc::new(tap_manager),
+            sender_pricing,
+            query_stats: QueryStatsRecorder::spawn(database.clone(), query_stats::RETENTION),
+            database,
+            paused_deployments,
+            allocation_eligible,
+            events,
+            read_only_database,
+            virtual_allocations,
+            domain_separator,
+        };
+        let state = payments.state(
+            options.service_impl,
+            options.url_namespace,
+            options.metrics_prefix,
+            true,
+        );
 
         // Rate limits by allowing bursts of 10 requests and requiring 100ms of
         // time between consecutive requests after that, effectively rate
@@ -322,22 +701,87 @@ impl IndexerService {
         let misc_rate_limiter = GovernorLayer {
             config: Box::leak(Box::new(
                 GovernorConfigBuilder::default()
-                    .per_millisecond(100)
-                    .burst_size(10)
+                    .per_millisecond(MISC_RATE_LIMIT.replenish_interval_ms)
+                    .burst_size(MISC_RATE_LIMIT.burst_size)
                     .finish()
                     .expect("Failed to set up rate limiting"),
             )),
         };
 
-        let operator_address = Json(
-            serde_json::json!({ "publicKey": public_key(&options.config.indexer.operator_mnemonic)?}),
+        let runtime_info = RuntimeInfo::new(
+            &options.config,
+            &options.release,
+            options
+                .config
+                .indexer
+                .operator_mnemonic
+                .expose(public_key)?,
+            std::iter::once(options.url_namespace)
+                .chain(
+                    options
+                        .data_services
+                        .iter()
+                        .map(|data_service| data_service.url_namespace),
+                )
+                .collect(),
+            schema_version(&payments.database).await,
         );
+        runtime_info.log();
 
         let mut misc_routes = Router::new()
             .route("/", get("Service is up and running"))
             .route("/version", get(Json(options.release)))
-            .route("/info", get(operator_address))
-            .layer(misc_rate_limiter);
+            .route("/info", get(Json(runtime_info)))
+            .route(
+                "/capabilities",
+                get(Json(Capabilities::new(&options.config))),
+            )
+            .route(
+                "/attestations/verify",
+                post(verify_attestations_handler::<I>),
+            )
+            .route(
+                "/receipts/validate",
+                post(validate_receipt_handler).route_layer(Extension(receipt_validator)),
+            )
+            .route(
+                "/deployments/:id/allocations",
+                get(eligible_allocations_handler)
+                    .route_layer(Extension(eligible_allocations.clone())),
+            )
+            .route(
+                "/senders/:sender/receipts/challenge",
+                post(receipt_digest_challenge_handler)
+                    .route_layer(Extension(receipt_digests.clone())),
+            )
+            .route(
+                "/senders/:sender/receipts/digest",
+                get(receipt_digest_handler).route_layer(Extension(receipt_digests)),
+            )
+            .layer(misc_rate_limiter.clone());
+        // Not rate limited, for the probes of container orchestrators
+        misc_routes = misc_routes.merge(readiness.router());
+
+        if let Some(fleet_stats_config) = &options.config.server.fleet_stats {
+            fleet_stats::start(
+                payments.database.clone(),
+                fleet_stats::instance_id(fleet_stats_config.instance_id.as_deref()),
+                fleet_stats_config.flush_interval,
+            );
+            misc_routes = misc_routes.route(
+                "/fees/summary",
+                get(fleet_stats::fees_summary_handler)
+                    .route_layer(Extension(FleetStats {
+                        pgpool: payments.database.clone(),
+                        flush_interval: fleet_stats_config.flush_interval,
+                    }))
+                    .route_layer(misc_rate_limiter),
+            );
+        }
+
+        if let Some(slo_settings) = &options.config.server.slo {
+            slo::start(slo_settings.clone(), http_client.clone());
+        }
 
         // Rate limits by allowing bursts of 50 requests and requiring 20ms of
         // time between consecutive requests after that, effectively rate
@@ -345,8 +789,8 @@ impl IndexerService {
         let static_subgraph_rate_limiter = GovernorLayer {
             config: Box::leak(Box::new(
                 GovernorConfigBuilder::default()
-                    .per_millisecond(20)
-                    .burst_size(50)
+                    .per_millisecond(STATIC_SUBGRAPH_RATE_LIMIT.replenish_interval_ms)
+                    .burst_size(STATIC_SUBGRAPH_RATE_LIMIT.burst_size)
                     .finish()
                     .expect("Failed to set up rate limiting"),
             )),
@@ -380,20 +824,26 @@ impl IndexerService {
 
         misc_routes = misc_routes.with_state(state.clone());
 
-        let data_routes = Router::new()
-            .route(
-                PathBuf::from(options.config.server.url_prefix)
-                    .join(format!("{}/id/:id", options.url_namespace))
-                    .to_str()
-                    .expect("Failed to set up `/{url_namespace}/id/:id` route"),
-                post(request_handler::<I>),
-            )
-            .with_state(state.clone());
+        let mut data_routes =
+            data_routes::<I>(&options.config.server.url_prefix, options.url_namespace)
+                .with_state(state.clone());
+        for data_service in options.data_services {
+            info!(
+                "Serving data service at /{}/id/:id",
+                data_service.url_namespace
+            );
+            data_routes = data_routes.merge(data_service.routes(&payments));
+        }
 
         let router = NormalizePath::trim_trailing_slash(
             misc_routes
                 .merge(data_routes)
-                .merge(options.extra_routes)
+                .merge(
+                    options
+                        .extra_routes
+                        .layer(Extension(payments.events.clone()))
+                        .layer(Extension(eligible_allocations)),
+                )
                 .layer(
                     CorsLayer::new()
                         .allow_origin(cors::Any)
@@ -437,22 +887,34 @@ impl IndexerService {
             .await
             .expect("Failed to bind to indexer-service port");
 
-        Ok(serve(
+        SERVING.store(true, Ordering::SeqCst);
+        serve(
             listener,
             ServiceExt::<ExtractRequest>::into_make_service_with_connect_info::<SocketAddr>(router),
         )
         .with_graceful_shutdown(shutdown_signal())
-        .await?)
+        .await?;
+
+        // The receipts of the queries served are written before exiting
+        if let Some(receipt_queue) = receipt_queue {
+            receipt_queue.shutdown().await;
+        }
+        Ok(())
     }
 
     fn serve_metrics(host_and_port: SocketAddr) {
         info!(address = %host_and_port, "Serving prometheus metrics");
 
         tokio::spawn(async move {
-            let router = Router::new().route(
-                "/metrics",
-                get(|| async { prometheus_exporter::encode_http_response() }),
-            );
+            let router = Router::new()
+                .route(
+                    "/metrics",
+                    get(|| async { prometheus_exporter::encode_http_response() }),
+                )
+                .route(
+                    "/debug/slow-queries",
+                    get(|| async { Json(db_metrics::slow_queries()) }),
+                );
 
             serve(
                 TcpListener::bind(host_and_port)
@@ -466,24 +928,40 @@ impl IndexerService {
     }
 }
 
+lazy_static! {
+    static ref SHUTDOWN: CancellationToken = CancellationToken::new();
+    static ref SERVING: AtomicBool = AtomicBool::new(false);
+}
+
+/// Handles SIGINT and SIGTERM from now on. Running as PID 1 in a container, the kernel ignores
+/// the signals that have no handler, so they are to be handled before anything that can block,
+/// rather than once serving requests.
+pub fn handle_shutdown_signals() -> Result<(), anyhow::Error> {
+    let mut sigint = signal::unix::signal(signal::unix::SignalKind::interrupt())?;
+    let mut sigterm = signal::unix::signal(signal::unix::SignalKind::terminate())?;
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = sigint.recv() => {},
+            _ = sigterm.recv() => {},
+        }
+        info!("Signal received, starting graceful shutdown");
+        #[cfg(feature = "systemd")]
+        crate::systemd::notify_stopping();
+        SHUTDOWN.cancel();
+    });
+    Ok(())
+}
+
+/// Resolves once a shutdown signal is received, see [`handle_shutdown_signals`].
 pub async fn shutdown_signal() {
-    let ctrl_c = async {
-        signal::ctrl_c()
-            .await
-            .expect("Failed to install Ctrl+C handler");
-    };
-
-    let terminate = async {
-        signal::unix::signal(signal::unix::SignalKind::terminate())
-            .expect("Failed to install signal handler")
-            .recv()
-            .await;
-    };
-
-    tokio::select! {
-        _ = ctrl_c => {},
-        _ = terminate => {},
-    }
+    SHUTDOWN.cancelled().await
+}
 
-    info!("Signal received, starting graceful shutdown");
+/// Resolves if a shutdown signal is received before serving requests. There is nothing to shut
+/// down gracefully then, and starting up may be stuck waiting on e.g. the database.
+pub async fn shutdown_before_serving() {
+    shutdown_signal().await;
+    if SERVING.load(Ordering::SeqCst) {
+        std::future::pending::<()>().await;
+    }
 }
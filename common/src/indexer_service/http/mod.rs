@@ -1,18 +1,41 @@
 // Copyright 2023-, GraphOps and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
+mod attestations;
+mod capabilities;
 mod config;
+mod data_service;
+mod eligible_allocations;
 mod indexer_service;
 mod metrics;
+mod query_binding;
+mod receipt_digest;
+mod receipt_payment;
+mod receipt_transport;
+mod receipt_validation;
 mod request_handler;
+mod response_envelope;
+mod runtime_info;
 mod static_subgraph;
 mod tap_receipt_header;
 
+pub use capabilities::{Capabilities, RateLimit, ReceiptCapabilities, ReceiptFormat};
 pub use config::{
-    DatabaseConfig, GraphNetworkConfig, GraphNodeConfig, IndexerConfig, IndexerServiceConfig,
-    ServerConfig, SubgraphConfig, TapConfig,
+    DatabaseConfig, FleetStatsConfig, GraphNetworkConfig, GraphNodeConfig, IndexerConfig,
+    IndexerServiceConfig, ReadOnlyDatabaseConfig, ReadOnlyDatabasePolicy,
+    ReceiptAcceptanceWindowAction, ReceiptAcceptanceWindowConfig, ReceiptQueueConfig,
+    ReceiptQueueOverflow, ReceiptTransport, ServerConfig, SubgraphConfig, TapConfig,
 };
+pub use data_service::{DataService, DataServiceOptions};
+pub use eligible_allocations::{EligibleAllocations, EligibleAllocationsWatch};
 pub use indexer_service::{
-    IndexerService, IndexerServiceImpl, IndexerServiceOptions, IndexerServiceRelease,
-    IndexerServiceResponse,
+    handle_shutdown_signals, shutdown_before_serving, IndexerService, IndexerServiceImpl,
+    IndexerServiceOptions, IndexerServiceRelease, IndexerServiceResponse,
 };
+pub use query_binding::{binding_message, QueryBinding, QUERY_BINDING_HEADER};
+pub use receipt_digest::{
+    challenge_message, merkle_root, ReceiptDigest, CHALLENGE_HEADER, SIGNATURE_HEADER,
+};
+pub use response_envelope::{ENVELOPE_V1_MEDIA_TYPE, REQUEST_ID_HEADER};
+pub use runtime_info::RuntimeInfo;
+pub use tap_receipt_header::TapReceipt;
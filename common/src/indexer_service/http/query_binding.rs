@@ -0,0 +1,170 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Binding of the receipts to the queries they pay for, for the setups where a receipt must not
+//! be usable with another query than the one it was sent with.
+//!
+//! The gateway hashes the request body it sends with keccak256, and signs the hash along with
+//! the hash of the receipt signature, see [`binding_message`], with the key the receipt was
+//! signed with. Both are sent in the `Tap-Query-Binding` header, as
+//! `{"queryHash": "0x...", "signature": "0x..."}`. In strict mode, the paid queries without a
+//! binding, or with a binding of another receipt or another query, are rejected before their
+//! receipt is stored.
+
+use alloy_primitives::{hex, keccak256, B256};
+use axum::http::HeaderMap;
+use serde::{Deserialize, Serialize};
+use tap_core::receipt::SignedReceipt;
+use thegraph::types::Address;
+use thiserror::Error;
+
+use crate::address::recover_message_signer;
+
+pub const QUERY_BINDING_HEADER: &str = "tap-query-binding";
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryBinding {
+    /// keccak256 hash of the request body.
+    pub query_hash: B256,
+    /// Hex encoded EIP-191 signature of the [`binding_message`].
+    pub signature: String,
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum QueryBindingError {
+    #[error("Paid queries require the receipt to be bound to the query, in the `{QUERY_BINDING_HEADER}` header")]
+    Missing,
+    #[error("Invalid query binding: {0}")]
+    Invalid(String),
+    #[error("The receipt is bound to the query {bound}, not to the query served {served}")]
+    QueryMismatch { bound: B256, served: B256 },
+    #[error("The query binding is signed by {0}, not by the signer of the receipt")]
+    SignerMismatch(Address),
+}
+
+/// Message signed by the signer of `receipt` to bind it to the query hashed as `query_hash`.
+pub fn binding_message(receipt: &SignedReceipt, query_hash: &B256) -> String {
+    format!(
+        "tap query binding\n{}\n{query_hash}",
+        keccak256(receipt.signature.to_vec())
+    )
+}
+
+/// Checks that `receipt`, signed by `receipt_signer`, is bound to the request `body`.
+pub fn verify_query_binding(
+    headers: &HeaderMap,
+    receipt: &SignedReceipt,
+    receipt_signer: Address,
+    body: &[u8],
+) -> Result<(), QueryBindingError> {
+    let header = headers
+        .get(QUERY_BINDING_HEADER)
+        .ok_or(QueryBindingError::Missing)?
+        .to_str()
+        .map_err(|e| QueryBindingError::Invalid(e.to_string()))?;
+    let binding: QueryBinding =
+        serde_json::from_str(header).map_err(|e| QueryBindingError::Invalid(e.to_string()))?;
+
+    let served = keccak256(body);
+    if binding.query_hash != served {
+        return Err(QueryBindingError::QueryMismatch {
+            bound: binding.query_hash,
+            served,
+        });
+    }
+
+    let signature =
+        hex::decode(&binding.signature).map_err(|e| QueryBindingError::Invalid(e.to_string()))?;
+    let signer = recover_message_signer(
+        binding_message(receipt, &binding.query_hash).as_bytes(),
+        &signature,
+    )
+    .map_err(|e| QueryBindingError::Invalid(e.to_string()))?;
+    if signer != receipt_signer {
+        return Err(QueryBindingError::SignerMismatch(signer));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+    use ethers::signers::Signer;
+
+    use crate::test_vectors::{create_signed_receipt, TAP_SENDER, TAP_SIGNER};
+
+    use super::*;
+
+    const REQUEST: &[u8] = br#"{"query":"{ _meta { block { number } } }"}"#;
+
+    async fn headers(
+        wallet: &ethers::signers::LocalWallet,
+        receipt: &SignedReceipt,
+        body: &[u8],
+    ) -> HeaderMap {
+        let query_hash = keccak256(body);
+        let signature = wallet
+            .sign_message(binding_message(receipt, &query_hash))
+            .await
+            .unwrap();
+        let binding = QueryBinding {
+            query_hash,
+            signature: hex::encode_prefixed(signature.to_vec()),
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            QUERY_BINDING_HEADER,
+            HeaderValue::from_str(&serde_json::to_string(&binding).unwrap()).unwrap(),
+        );
+        headers
+    }
+
+    #[tokio::test]
+    async fn test_verify_query_binding() {
+        let receipt = create_signed_receipt(Address::ZERO, 1, 1, 100).await;
+        let other_receipt = create_signed_receipt(Address::ZERO, 2, 1, 100).await;
+        let signer = TAP_SIGNER.1;
+
+        assert_eq!(
+            verify_query_binding(&HeaderMap::new(), &receipt, signer, REQUEST),
+            Err(QueryBindingError::Missing)
+        );
+
+        let bound = headers(&TAP_SIGNER.0, &receipt, REQUEST).await;
+        assert_eq!(
+            verify_query_binding(&bound, &receipt, signer, REQUEST),
+            Ok(())
+        );
+
+        // Another query
+        let other_request = br#"{"query":"{ tokens { id } }"}"#;
+        assert_eq!(
+            verify_query_binding(&bound, &receipt, signer, other_request),
+            Err(QueryBindingError::QueryMismatch {
+                bound: keccak256(REQUEST),
+                served: keccak256(other_request),
+            })
+        );
+
+        // Another receipt
+        assert!(matches!(
+            verify_query_binding(&bound, &other_receipt, signer, REQUEST),
+            Err(QueryBindingError::SignerMismatch(_))
+        ));
+
+        // Another signer
+        let bound = headers(&TAP_SENDER.0, &receipt, REQUEST).await;
+        assert_eq!(
+            verify_query_binding(&bound, &receipt, signer, REQUEST),
+            Err(QueryBindingError::SignerMismatch(TAP_SENDER.1))
+        );
+
+        let mut invalid = HeaderMap::new();
+        invalid.insert(QUERY_BINDING_HEADER, HeaderValue::from_static("{}"));
+        assert!(matches!(
+            verify_query_binding(&invalid, &receipt, signer, REQUEST),
+            Err(QueryBindingError::Invalid(_))
+        ));
+    }
+}
@@ -0,0 +1,499 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Digests of the receipts of a sender still held by the indexer, for the sender to reconcile
+//! them against its own records. A sender first gets a single-use challenge, then requests the
+//! digest with the EIP-191 signature of the challenge by one of its signers, so that it can only
+//! see its own receipts.
+//!
+//! The digest has, for every hour, the number and total value of the receipts, and the Merkle
+//! root of a sample of them: the receipts with a nonce divisible by the sample rate. The leaves
+//! are the keccak256 hashes of the receipt signatures, sorted, and every node is the keccak256
+//! hash of its two children, a node without a sibling being carried over to the level above.
+//! The sender can compute the same root from the receipts it sent to tell, hour by hour, whether
+//! receipts are missing on either side.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use alloy_primitives::{hex, hex::ToHex, keccak256, B256};
+use axum::{
+    extract::{Path, Query},
+    http::{HeaderMap, StatusCode},
+    Extension, Json,
+};
+use ethers_core::rand::{thread_rng, RngCore};
+use eventuals::Eventual;
+use serde::{Deserialize, Serialize};
+use sqlx::{types::BigDecimal, PgPool, Row};
+use thegraph::types::Address;
+
+use crate::address::recover_message_signer;
+use crate::escrow_accounts::EscrowAccounts;
+use crate::types::GrtWei;
+
+pub const CHALLENGE_HEADER: &str = "x-sender-challenge";
+pub const SIGNATURE_HEADER: &str = "x-sender-signature";
+
+/// Time to sign and use a challenge.
+const CHALLENGE_TTL_SECS: u64 = 300;
+/// Challenges issued and not used yet, to bound the memory taken by unused ones.
+const MAX_PENDING_CHALLENGES: usize = 10_000;
+const DEFAULT_DIGEST_RANGE_SECS: u64 = 24 * 3600;
+const MAX_DIGEST_RANGE_SECS: u64 = 7 * 24 * 3600;
+const HOUR_NS: u64 = 3600 * 1_000_000_000;
+
+type DigestError = (StatusCode, String);
+
+/// Message signed by a signer of `sender` to answer `challenge`.
+pub fn challenge_message(sender: &Address, challenge: &B256) -> String {
+    format!("indexer receipts digest\n{sender}\n{challenge}")
+}
+
+/// Merkle root of the leaves, in the order given. `None` without leaves.
+pub fn merkle_root(mut leaves: Vec<B256>) -> Option<B256> {
+    while leaves.len() > 1 {
+        leaves = leaves
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => keccak256([left.as_slice(), right.as_slice()].concat()),
+                [single] => *single,
+                _ => unreachable!(),
+            })
+            .collect();
+    }
+    leaves.pop()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
+}
+
+pub struct ReceiptDigests {
+    pub database: PgPool,
+    pub escrow_accounts: Eventual<EscrowAccounts>,
+    /// Sender and expiry, in seconds since the UNIX epoch, of the challenges not used yet.
+    challenges: Mutex<HashMap<B256, (Address, u64)>>,
+}
+
+impl ReceiptDigests {
+    pub fn new(database: PgPool, escrow_accounts: Eventual<EscrowAccounts>) -> Self {
+        Self {
+            database,
+            escrow_accounts,
+            challenges: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn signers(&self, sender: &Address) -> Vec<Address> {
+        self.escrow_accounts
+            .value_immediate()
+            .unwrap_or_default()
+            .get_signers_for_sender(sender)
+    }
+
+    /// Issues a challenge for `sender` at `now`, if it has signers.
+    fn challenge(&self, sender: Address, now: u64) -> Result<Challenge, DigestError> {
+        if self.signers(&sender).is_empty() {
+            return Err((
+                StatusCode::NOT_FOUND,
+                format!("{sender} has no signers in the escrow accounts"),
+            ));
+        }
+        let mut challenges = self.challenges.lock().unwrap();
+        challenges.retain(|_, (_, expires_at)| *expires_at > now);
+        if challenges.len() >= MAX_PENDING_CHALLENGES {
+            return Err((
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Too many challenges pending, try again later".to_string(),
+            ));
+        }
+        let mut challenge = B256::ZERO;
+        thread_rng().fill_bytes(challenge.as_mut_slice());
+        let expires_at = now + CHALLENGE_TTL_SECS;
+        challenges.insert(challenge, (sender, expires_at));
+        Ok(Challenge {
+            challenge,
+            expires_at,
+        })
+    }
+
+    /// Checks the signature of a challenge issued for `sender`, at `now`, using the challenge up.
+    /// Returns the signer.
+    fn verify(
+        &self,
+        sender: Address,
+        headers: &HeaderMap,
+        now: u64,
+    ) -> Result<Address, DigestError> {
+        let unauthorized = |message: &str| (StatusCode::UNAUTHORIZED, message.to_string());
+        let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+        let (Some(challenge), Some(signature)) =
+            (header(CHALLENGE_HEADER), header(SIGNATURE_HEADER))
+        else {
+            return Err(unauthorized(&format!(
+                "Digests require a signed challenge, with the `{CHALLENGE_HEADER}` and \
+                 `{SIGNATURE_HEADER}` headers"
+            )));
+        };
+        let challenge: B256 = challenge
+            .parse()
+            .map_err(|_| unauthorized("Invalid challenge"))?;
+        match self.challenges.lock().unwrap().remove(&challenge) {
+            Some((challenged, expires_at)) if challenged == sender && expires_at > now => {}
+            _ => return Err(unauthorized("Unknown or expired challenge")),
+        }
+
+        let signature = hex::decode(signature).map_err(|_| unauthorized("Invalid signature"))?;
+        let signer = recover_message_signer(
+            challenge_message(&sender, &challenge).as_bytes(),
+            &signature,
+        )
+        .map_err(|_| unauthorized("Invalid signature"))?;
+        if !self.signers(&sender).contains(&signer) {
+            return Err((
+                StatusCode::FORBIDDEN,
+                format!("{signer} isn't a signer of {sender}"),
+            ));
+        }
+        Ok(signer)
+    }
+
+    /// Digest of the receipts of `sender` with a timestamp in `[from, to)`, in seconds since the
+    /// UNIX epoch.
+    pub async fn digest(
+        &self,
+        sender: Address,
+        from: u64,
+        to: u64,
+        sample_rate: u64,
+    ) -> anyhow::Result<ReceiptDigest> {
+        let signers: Vec<String> = self
+            .signers(&sender)
+            .iter()
+            .map(|signer| signer.encode_hex::<String>())
+            .collect();
+        let from_ns = BigDecimal::from(from.saturating_mul(1_000_000_000));
+        let to_ns = BigDecimal::from(to.saturating_mul(1_000_000_000));
+
+        let mut hours = BTreeMap::<u64, HourDigest>::new();
+        let totals = sqlx::query(
+            r#"
+                SELECT
+                    div(timestamp_ns, $4)::BIGINT AS hour,
+                    COUNT(*) AS receipts,
+                    SUM(value) AS value
+                FROM scalar_tap_receipts
+                WHERE signer_address = ANY($1) AND timestamp_ns >= $2 AND timestamp_ns < $3
+                GROUP BY hour
+            "#,
+        )
+        .bind(&signers)
+        .bind(&from_ns)
+        .bind(&to_ns)
+        .bind(BigDecimal::from(HOUR_NS))
+        .fetch_all(&self.database)
+        .await?;
+        for row in totals {
+            let hour = row.try_get::<i64, _>("hour")? as u64;
+            hours.insert(
+                hour,
+                HourDigest {
+                    hour_start: hour * 3600,
+                    receipts: row.try_get::<i64, _>("receipts")? as u64,
+                    value: row.try_get("value")?,
+                    sampled_receipts: 0,
+                    merkle_root: None,
+                },
+            );
+        }
+
+        let sampled = sqlx::query(
+            r#"
+                SELECT div(timestamp_ns, $4)::BIGINT AS hour, signature
+                FROM scalar_tap_receipts
+                WHERE signer_address = ANY($1) AND timestamp_ns >= $2 AND timestamp_ns < $3
+                    AND mod(nonce, $5) = 0
+            "#,
+        )
+        .bind(&signers)
+        .bind(&from_ns)
+        .bind(&to_ns)
+        .bind(BigDecimal::from(HOUR_NS))
+        .bind(BigDecimal::from(sample_rate))
+        .fetch_all(&self.database)
+        .await?;
+        let mut leaves = BTreeMap::<u64, Vec<B256>>::new();
+        for row in sampled {
+            let hour = row.try_get::<i64, _>("hour")? as u64;
+            let signature: Vec<u8> = row.try_get("signature")?;
+            leaves.entry(hour).or_default().push(keccak256(signature));
+        }
+        for (hour, mut leaves) in leaves {
+            leaves.sort();
+            if let Some(digest) = hours.get_mut(&hour) {
+                digest.sampled_receipts = leaves.len() as u64;
+                digest.merkle_root = merkle_root(leaves);
+            }
+        }
+
+        Ok(ReceiptDigest {
+            sender,
+            from,
+            to,
+            sample_rate,
+            hours: hours.into_values().collect(),
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Challenge {
+    pub challenge: B256,
+    pub expires_at: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReceiptDigestQuery {
+    /// In seconds since the UNIX epoch, a day before `to` by default.
+    pub from: Option<u64>,
+    /// In seconds since the UNIX epoch, excluded, now by default.
+    pub to: Option<u64>,
+    /// One in how many receipts, by nonce, are in the Merkle roots, all of them by default.
+    pub sample_rate: Option<u64>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HourDigest {
+    /// In seconds since the UNIX epoch.
+    pub hour_start: u64,
+    pub receipts: u64,
+    pub value: GrtWei,
+    pub sampled_receipts: u64,
+    pub merkle_root: Option<B256>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReceiptDigest {
+    pub sender: Address,
+    pub from: u64,
+    pub to: u64,
+    pub sample_rate: u64,
+    /// The hours with receipts, in order.
+    pub hours: Vec<HourDigest>,
+}
+
+pub async fn receipt_digest_challenge_handler(
+    Extension(digests): Extension<Arc<ReceiptDigests>>,
+    Path(sender): Path<Address>,
+) -> Result<Json<Challenge>, DigestError> {
+    Ok(Json(digests.challenge(sender, now())?))
+}
+
+pub async fn receipt_digest_handler(
+    Extension(digests): Extension<Arc<ReceiptDigests>>,
+    Path(sender): Path<Address>,
+    Query(query): Query<ReceiptDigestQuery>,
+    headers: HeaderMap,
+) -> Result<Json<ReceiptDigest>, DigestError> {
+    let now = now();
+    let to = query.to.unwrap_or(now);
+    let from = query
+        .from
+        .unwrap_or(to.saturating_sub(DEFAULT_DIGEST_RANGE_SECS));
+    let sample_rate = query.sample_rate.unwrap_or(1);
+    if from >= to || to - from > MAX_DIGEST_RANGE_SECS {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("The range must be non-empty and at most {MAX_DIGEST_RANGE_SECS} seconds"),
+        ));
+    }
+    if sample_rate == 0 {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "The sample rate must be at least 1".to_string(),
+        ));
+    }
+
+    digests.verify(sender, &headers, now)?;
+    let digest = digests
+        .digest(sender, from, to, sample_rate)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(Json(digest))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+    use ethers::signers::Signer;
+
+    use crate::test_vectors::{
+        ESCROW_ACCOUNTS_BALANCES, ESCROW_ACCOUNTS_SENDERS_TO_SIGNERS, TAP_SENDER, TAP_SIGNER,
+    };
+
+    use super::*;
+
+    fn digests(pgpool: PgPool) -> ReceiptDigests {
+        ReceiptDigests::new(
+            pgpool,
+            Eventual::from_value(EscrowAccounts::new(
+                ESCROW_ACCOUNTS_BALANCES.clone(),
+                ESCROW_ACCOUNTS_SENDERS_TO_SIGNERS.clone(),
+            )),
+        )
+    }
+
+    async fn signed_headers(sender: &Address, challenge: &B256) -> HeaderMap {
+        let signature = TAP_SIGNER
+            .0
+            .sign_message(challenge_message(sender, challenge))
+            .await
+            .unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            CHALLENGE_HEADER,
+            HeaderValue::from_str(&challenge.to_string()).unwrap(),
+        );
+        headers.insert(
+            SIGNATURE_HEADER,
+            HeaderValue::from_str(&hex::encode_prefixed(signature.to_vec())).unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn test_merkle_root() {
+        let leaves: Vec<B256> = (0u8..3).map(|i| keccak256([i])).collect();
+        assert_eq!(merkle_root(vec![]), None);
+        assert_eq!(merkle_root(leaves[..1].to_vec()), Some(leaves[0]));
+        let left = keccak256([leaves[0].as_slice(), leaves[1].as_slice()].concat());
+        assert_eq!(
+            merkle_root(leaves.clone()),
+            Some(keccak256([left.as_slice(), leaves[2].as_slice()].concat()))
+        );
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_challenge(pgpool: PgPool) {
+        let digests = digests(pgpool);
+        let now = 1_000_000;
+
+        assert_eq!(
+            digests
+                .challenge(Address::from([0x99; 20]), now)
+                .unwrap_err()
+                .0,
+            StatusCode::NOT_FOUND
+        );
+
+        let challenge = digests.challenge(TAP_SENDER.1, now).unwrap().challenge;
+        let headers = signed_headers(&TAP_SENDER.1, &challenge).await;
+        assert_eq!(
+            digests.verify(TAP_SENDER.1, &headers, now + 10).unwrap(),
+            TAP_SIGNER.1
+        );
+        // Single use
+        assert_eq!(
+            digests
+                .verify(TAP_SENDER.1, &headers, now + 10)
+                .unwrap_err()
+                .0,
+            StatusCode::UNAUTHORIZED
+        );
+
+        let challenge = digests.challenge(TAP_SENDER.1, now).unwrap().challenge;
+        let headers = signed_headers(&TAP_SENDER.1, &challenge).await;
+        assert_eq!(
+            digests
+                .verify(TAP_SENDER.1, &headers, now + CHALLENGE_TTL_SECS)
+                .unwrap_err()
+                .0,
+            StatusCode::UNAUTHORIZED
+        );
+
+        // Signed by the sender itself, which isn't one of its signers
+        let challenge = digests.challenge(TAP_SENDER.1, now).unwrap().challenge;
+        let signature = TAP_SENDER
+            .0
+            .sign_message(challenge_message(&TAP_SENDER.1, &challenge))
+            .await
+            .unwrap();
+        let mut headers = signed_headers(&TAP_SENDER.1, &challenge).await;
+        headers.insert(
+            SIGNATURE_HEADER,
+            HeaderValue::from_str(&hex::encode_prefixed(signature.to_vec())).unwrap(),
+        );
+        assert_eq!(
+            digests.verify(TAP_SENDER.1, &headers, now).unwrap_err().0,
+            StatusCode::FORBIDDEN
+        );
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_digest(pgpool: PgPool) {
+        let hour = 500_000u64;
+        let receipts = [
+            // Signer, hour, nonce, value
+            (TAP_SIGNER.1, hour, 2u64, 10u128),
+            (TAP_SIGNER.1, hour, 3, 20),
+            (TAP_SIGNER.1, hour, 4, 30),
+            (TAP_SIGNER.1, hour + 2, 6, 40),
+            // Another sender's
+            (Address::from([0x77; 20]), hour, 8, 1000),
+        ];
+        for (i, (signer, hour, nonce, value)) in receipts.iter().enumerate() {
+            sqlx::query(
+                r#"
+                    INSERT INTO scalar_tap_receipts
+                        (signer_address, signature, allocation_id, timestamp_ns, nonce, value)
+                    VALUES ($1, $2, $3, $4, $5, $6)
+                "#,
+            )
+            .bind(signer.encode_hex::<String>())
+            .bind(vec![i as u8; 65])
+            .bind(Address::from([0x55; 20]).encode_hex::<String>())
+            .bind(BigDecimal::from(hour * HOUR_NS + i as u64))
+            .bind(BigDecimal::from(*nonce))
+            .bind(BigDecimal::from(*value as u64))
+            .execute(&pgpool)
+            .await
+            .unwrap();
+        }
+
+        let from = hour * 3600;
+        let digest = digests(pgpool)
+            .digest(TAP_SENDER.1, from, from + 3 * 3600, 2)
+            .await
+            .unwrap();
+        let mut leaves = vec![keccak256([0u8; 65]), keccak256([2u8; 65])];
+        leaves.sort();
+        assert_eq!(
+            digest.hours,
+            vec![
+                HourDigest {
+                    hour_start: from,
+                    receipts: 3,
+                    value: GrtWei(60),
+                    sampled_receipts: 2,
+                    merkle_root: merkle_root(leaves),
+                },
+                HourDigest {
+                    hour_start: from + 2 * 3600,
+                    receipts: 1,
+                    value: GrtWei(40),
+                    sampled_receipts: 1,
+                    merkle_root: Some(keccak256([3u8; 65])),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,117 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Queries paid with several receipts, for the gateways splitting the price of an expensive
+//! query over smaller receipts. The receipts are checked here as a whole, before each goes
+//! through the TAP checks within a [payment scope](crate::tap::payment_scope).
+
+use std::collections::HashSet;
+
+use tap_core::receipt::SignedReceipt;
+use thegraph::types::Address;
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum ReceiptPaymentError {
+    #[error("A query can be paid with at most {max} receipts, not {count}")]
+    TooManyReceipts { count: usize, max: usize },
+    #[error(
+        "The receipts paying for a query must be for a single allocation, not for `{0}` and `{1}`"
+    )]
+    SeveralAllocations(Address, Address),
+    #[error("The same receipt was sent twice to pay for the query")]
+    DuplicateReceipt,
+    #[error("The combined value of the receipts overflows")]
+    ValueOverflow,
+}
+
+/// Checks the `receipts` paying for a query together, returning their combined value. They are
+/// all for the same allocation, whose attestation signer signs the response.
+pub fn check_payment(
+    receipts: &[SignedReceipt],
+    max_receipts: usize,
+) -> Result<u128, ReceiptPaymentError> {
+    if receipts.len() > max_receipts {
+        return Err(ReceiptPaymentError::TooManyReceipts {
+            count: receipts.len(),
+            max: max_receipts,
+        });
+    }
+
+    let Some(allocation_id) = receipts
+        .first()
+        .map(|receipt| receipt.message.allocation_id)
+    else {
+        return Ok(0);
+    };
+    let mut signatures = HashSet::with_capacity(receipts.len());
+    let mut value = 0u128;
+    for receipt in receipts {
+        if receipt.message.allocation_id != allocation_id {
+            return Err(ReceiptPaymentError::SeveralAllocations(
+                allocation_id,
+                receipt.message.allocation_id,
+            ));
+        }
+        if !signatures.insert(receipt.signature.to_vec()) {
+            return Err(ReceiptPaymentError::DuplicateReceipt);
+        }
+        value = value
+            .checked_add(receipt.message.value)
+            .ok_or(ReceiptPaymentError::ValueOverflow)?;
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_vectors::create_signed_receipt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_check_payment() {
+        let allocation = Address::from([0x01; 20]);
+        let receipts = vec![
+            create_signed_receipt(allocation, 1, 1, 10).await,
+            create_signed_receipt(allocation, 2, 1, 20).await,
+            create_signed_receipt(allocation, 3, 1, 30).await,
+        ];
+
+        assert_eq!(check_payment(&receipts[..1], 1), Ok(10));
+        assert_eq!(check_payment(&receipts, 3), Ok(60));
+        assert_eq!(
+            check_payment(&receipts, 2),
+            Err(ReceiptPaymentError::TooManyReceipts { count: 3, max: 2 })
+        );
+
+        let other_allocation = Address::from([0x02; 20]);
+        assert_eq!(
+            check_payment(
+                &[
+                    receipts[0].clone(),
+                    create_signed_receipt(other_allocation, 4, 1, 10).await
+                ],
+                3
+            ),
+            Err(ReceiptPaymentError::SeveralAllocations(
+                allocation,
+                other_allocation
+            ))
+        );
+        assert_eq!(
+            check_payment(&[receipts[0].clone(), receipts[0].clone()], 3),
+            Err(ReceiptPaymentError::DuplicateReceipt)
+        );
+        assert_eq!(
+            check_payment(
+                &[
+                    receipts[0].clone(),
+                    create_signed_receipt(allocation, 5, 1, u128::MAX).await
+                ],
+                3
+            ),
+            Err(ReceiptPaymentError::ValueOverflow)
+        );
+    }
+}
@@ -0,0 +1,265 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Receipts supplied outside of the `Tap-Receipt` header, for HTTP clients that can't set custom
+//! headers. The receipt is then either passed as JSON in the `tap-receipt` query parameter, or
+//! wrapped with the request in a `{"tapReceipt": ..., "request": ...}` envelope as the body.
+//! Both are disabled unless enabled for the deployment. The receipt being signed, it needs no
+//! more protection in a URL than it does in a header. Like in the header, the receipts of a
+//! query paid with several receipts are passed as a JSON array.
+
+use axum::body::Bytes;
+use serde::Deserialize;
+use serde_json::Value;
+use tap_core::receipt::SignedReceipt;
+use thiserror::Error;
+
+use super::{tap_receipt_header::parse_receipts, ReceiptTransport};
+
+const RECEIPT_ENVELOPE_FIELD: &str = "tapReceipt";
+
+#[derive(Debug, Deserialize)]
+pub struct ReceiptQuery {
+    #[serde(rename = "tap-receipt")]
+    tap_receipt: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct ReceiptEnvelope {
+    tap_receipt: Value,
+    request: Value,
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum ReceiptTransportError {
+    #[error("Receipts in the {0} are not accepted for this deployment")]
+    NotEnabled(&'static str),
+    #[error("Invalid receipt in the {0}: {1}")]
+    InvalidReceipt(&'static str, String),
+    #[error("A receipt was supplied in both the {0} and the {1}")]
+    Ambiguous(&'static str, &'static str),
+}
+
+fn transport_name(transport: ReceiptTransport) -> &'static str {
+    match transport {
+        ReceiptTransport::QueryString => "query string",
+        ReceiptTransport::PostBody => "request body",
+    }
+}
+
+/// Receipts of the request, from the header or from one of the `transports` enabled for the
+/// deployment, along with the request body stripped of its receipt envelope if any.
+pub fn extract_receipt(
+    header: Vec<SignedReceipt>,
+    query: ReceiptQuery,
+    body: Bytes,
+    transports: &[ReceiptTransport],
+) -> Result<(Vec<SignedReceipt>, Bytes), ReceiptTransportError> {
+    let mut receipts = Vec::new();
+    if !header.is_empty() {
+        receipts.push(("header", header));
+    }
+
+    if let Some(raw_receipt) = query.tap_receipt {
+        let transport = ReceiptTransport::QueryString;
+        ensure_enabled(transport, transports)?;
+        let receipt = parse_receipts(&raw_receipt).map_err(|e| {
+            ReceiptTransportError::InvalidReceipt(transport_name(transport), e.to_string())
+        })?;
+        receipts.push((transport_name(transport), receipt));
+    }
+
+    let body = match unwrap_envelope(&body) {
+        None => body,
+        Some(envelope) => {
+            let transport = ReceiptTransport::PostBody;
+            ensure_enabled(transport, transports)?;
+            let invalid = |e: serde_json::Error| {
+                ReceiptTransportError::InvalidReceipt(transport_name(transport), e.to_string())
+            };
+            let envelope = envelope.map_err(invalid)?;
+            let receipt = parse_receipts(&envelope.tap_receipt.to_string()).map_err(invalid)?;
+            receipts.push((transport_name(transport), receipt));
+            // Re-serializing the request can't fail, it was just deserialized
+            Bytes::from(serde_json::to_vec(&envelope.request).unwrap_or_default())
+        }
+    };
+
+    let mut receipts = receipts.into_iter();
+    let receipt = receipts.next();
+    if let (Some((first, _)), Some((second, _))) = (&receipt, receipts.next()) {
+        return Err(ReceiptTransportError::Ambiguous(first, second));
+    }
+    Ok((
+        receipt.map(|(_, receipt)| receipt).unwrap_or_default(),
+        body,
+    ))
+}
+
+fn ensure_enabled(
+    transport: ReceiptTransport,
+    transports: &[ReceiptTransport],
+) -> Result<(), ReceiptTransportError> {
+    if transports.contains(&transport) {
+        Ok(())
+    } else {
+        Err(ReceiptTransportError::NotEnabled(transport_name(transport)))
+    }
+}
+
+/// `None` if the body is not a receipt envelope, i.e. a JSON object with a `tapReceipt` field.
+fn unwrap_envelope(body: &[u8]) -> Option<serde_json::Result<ReceiptEnvelope>> {
+    let value: Value = serde_json::from_slice(body).ok()?;
+    value.get(RECEIPT_ENVELOPE_FIELD)?;
+    Some(serde_json::from_value(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Serialize;
+    use serde_json::json;
+    use thegraph::types::Address;
+
+    use crate::test_vectors::create_signed_receipt;
+
+    use super::*;
+
+    const REQUEST: &str = r#"{"query":"{ _meta { block { number } } }"}"#;
+
+    fn query(receipt: Option<&SignedReceipt>) -> ReceiptQuery {
+        ReceiptQuery {
+            tap_receipt: receipt.map(|receipt| serde_json::to_string(receipt).unwrap()),
+        }
+    }
+
+    fn envelope(receipt: impl Serialize) -> Bytes {
+        Bytes::from(
+            serde_json::to_vec(&json!({
+                "tapReceipt": receipt,
+                "request": serde_json::from_str::<Value>(REQUEST).unwrap(),
+            }))
+            .unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_receipt_from_header_only_by_default() {
+        let receipt = create_signed_receipt(Address::ZERO, 1, 1, 100).await;
+
+        assert_eq!(
+            extract_receipt(
+                vec![receipt.clone()],
+                query(None),
+                Bytes::from(REQUEST),
+                &[]
+            ),
+            Ok((vec![receipt.clone()], Bytes::from(REQUEST)))
+        );
+        assert_eq!(
+            extract_receipt(vec![], query(None), Bytes::from(REQUEST), &[]),
+            Ok((vec![], Bytes::from(REQUEST)))
+        );
+        assert_eq!(
+            extract_receipt(vec![], query(Some(&receipt)), Bytes::from(REQUEST), &[]),
+            Err(ReceiptTransportError::NotEnabled("query string"))
+        );
+        assert_eq!(
+            extract_receipt(vec![], query(None), envelope(&receipt), &[]),
+            Err(ReceiptTransportError::NotEnabled("request body"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_receipt_from_query_string() {
+        let receipt = create_signed_receipt(Address::ZERO, 1, 1, 100).await;
+        let transports = [ReceiptTransport::QueryString];
+
+        assert_eq!(
+            extract_receipt(
+                vec![],
+                query(Some(&receipt)),
+                Bytes::from(REQUEST),
+                &transports
+            ),
+            Ok((vec![receipt.clone()], Bytes::from(REQUEST)))
+        );
+        assert!(matches!(
+            extract_receipt(
+                vec![],
+                ReceiptQuery {
+                    tap_receipt: Some("not a receipt".to_string())
+                },
+                Bytes::from(REQUEST),
+                &transports
+            ),
+            Err(ReceiptTransportError::InvalidReceipt("query string", _))
+        ));
+        assert_eq!(
+            extract_receipt(
+                vec![receipt.clone()],
+                query(Some(&receipt)),
+                Bytes::from(REQUEST),
+                &transports
+            ),
+            Err(ReceiptTransportError::Ambiguous("header", "query string"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_receipt_from_body_envelope() {
+        let receipt = create_signed_receipt(Address::ZERO, 1, 1, 100).await;
+        let transports = [ReceiptTransport::PostBody];
+
+        let (extracted, body) =
+            extract_receipt(vec![], query(None), envelope(&receipt), &transports).unwrap();
+        assert_eq!(extracted, vec![receipt.clone()]);
+        assert_eq!(
+            serde_json::from_slice::<Value>(&body).unwrap(),
+            serde_json::from_str::<Value>(REQUEST).unwrap()
+        );
+
+        assert!(matches!(
+            extract_receipt(
+                vec![],
+                query(None),
+                Bytes::from(r#"{"tapReceipt":{},"request":{}}"#),
+                &transports
+            ),
+            Err(ReceiptTransportError::InvalidReceipt("request body", _))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_several_receipts() {
+        let receipts = vec![
+            create_signed_receipt(Address::ZERO, 1, 1, 100).await,
+            create_signed_receipt(Address::ZERO, 2, 1, u128::MAX).await,
+        ];
+        let transports = [ReceiptTransport::QueryString, ReceiptTransport::PostBody];
+
+        let (extracted, _) = extract_receipt(
+            vec![],
+            ReceiptQuery {
+                tap_receipt: Some(serde_json::to_string(&receipts).unwrap()),
+            },
+            Bytes::from(REQUEST),
+            &transports,
+        )
+        .unwrap();
+        assert_eq!(extracted, receipts);
+
+        let (extracted, body) =
+            extract_receipt(vec![], query(None), envelope(&receipts), &transports).unwrap();
+        assert_eq!(extracted, receipts);
+        assert_eq!(
+            serde_json::from_slice::<Value>(&body).unwrap(),
+            serde_json::from_str::<Value>(REQUEST).unwrap()
+        );
+
+        assert!(matches!(
+            extract_receipt(vec![], query(None), envelope(json!([])), &transports),
+            Err(ReceiptTransportError::InvalidReceipt("request body", _))
+        ));
+    }
+}
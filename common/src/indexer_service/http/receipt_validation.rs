@@ -0,0 +1,19 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::sync::Arc;
+
+use axum::{Extension, Json};
+use tap_core::receipt::SignedReceipt;
+
+use crate::tap::{ReceiptValidation, ReceiptValidator};
+
+/// Runs a receipt, sent in the same JSON format as the `Tap-Receipt` header, through all the
+/// receipt checks without storing it. Results are returned for every check, so that gateways
+/// can debug their integration.
+pub async fn validate_receipt_handler(
+    Extension(validator): Extension<Arc<ReceiptValidator>>,
+    Json(receipt): Json<SignedReceipt>,
+) -> Json<ReceiptValidation> {
+    Json(validator.validate(receipt).await)
+}
@@ -2,73 +2,267 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use std::sync::Arc;
+use std::time::Instant;
 
 use axum::{
     body::Bytes,
-    extract::{Path, State},
-    http::HeaderMap,
-    response::IntoResponse,
+    extract::{Path, Query, State},
+    http::{HeaderMap, HeaderValue},
+    response::{IntoResponse, Response},
 };
 use axum_extra::TypedHeader;
 use reqwest::StatusCode;
+use tap_core::receipt::{checks::Check, ReceiptWithState};
 use thegraph::types::DeploymentId;
 use tracing::trace;
 
-use crate::{indexer_service::http::IndexerServiceResponse, prelude::AttestationSigner};
+use crate::{
+    allocations::grace_period::AllocationClosed,
+    fleet_stats,
+    indexer_service::http::IndexerServiceResponse,
+    prelude::AttestationSigner,
+    query_stats::{self, QueryStats},
+    request_timing::{self, Phase, DEBUG_TIMING_HEADER},
+    slo::{self, PaidQueryTimer},
+    tap::{payment_scope, recover_signer, store_payment},
+};
 
 use super::{
     indexer_service::{IndexerServiceError, IndexerServiceState},
+    query_binding::{verify_query_binding, QueryBindingError},
+    receipt_payment::check_payment,
+    receipt_transport::{extract_receipt, ReceiptQuery},
+    response_envelope::{
+        block_constraints, request_id, Degradation, ReceiptValidationMode, ResponseEnvelope,
+        ResponseFormat,
+    },
     tap_receipt_header::TapReceipt,
     IndexerServiceImpl,
 };
 
+/// Carries the signed disclosure of the deployments whose responses are not attested, see
+/// [`AttestationSigner::attestations_disabled_disclosure`].
+const ATTESTATION_DISCLOSURE_HEADER: &str = "attestation-disclosure";
+
 #[autometrics::autometrics]
 pub async fn request_handler<I>(
     Path(manifest_id): Path<DeploymentId>,
     TypedHeader(receipt): TypedHeader<TapReceipt>,
+    Query(receipt_query): Query<ReceiptQuery>,
     State(state): State<Arc<IndexerServiceState<I>>>,
     headers: HeaderMap,
     body: Bytes,
-) -> Result<impl IntoResponse, IndexerServiceError<I::Error>>
+) -> Result<Response, IndexerServiceError<I::Error>>
+where
+    I: IndexerServiceImpl + Sync + Send + 'static,
+{
+    // The breakdown tells about the load of the indexer, only the authorized callers get it
+    let debug_timing = headers
+        .get(DEBUG_TIMING_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|token| Some(token) == state.config.server.free_query_auth_token.as_deref());
+    if !debug_timing {
+        return handle_request(manifest_id, receipt, receipt_query, state, headers, body).await;
+    }
+
+    let (response, timing) = request_timing::scope(handle_request(
+        manifest_id,
+        receipt,
+        receipt_query,
+        state,
+        headers,
+        body,
+    ))
+    .await;
+    let mut response = response?;
+    if let Ok(server_timing) = HeaderValue::from_str(&timing.server_timing()) {
+        response
+            .headers_mut()
+            .insert("server-timing", server_timing);
+    }
+    Ok(response)
+}
+
+async fn handle_request<I>(
+    manifest_id: DeploymentId,
+    receipt: TapReceipt,
+    receipt_query: ReceiptQuery,
+    state: Arc<IndexerServiceState<I>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, IndexerServiceError<I::Error>>
 where
     I: IndexerServiceImpl + Sync + Send + 'static,
 {
     trace!("Handling request for deployment `{manifest_id}`");
+    let mut paid_query = PaidQueryTimer::start();
+
+    // Bound to the body as sent, before unwrapping a receipt envelope
+    let query_body = state.config.tap.require_query_binding.then(|| body.clone());
 
     state
         .metrics
         .requests
         .with_label_values(&[&manifest_id.to_string()])
         .inc();
+    fleet_stats::record_request(manifest_id);
+
+    let transports = state
+        .config
+        .tap
+        .receipt_transports_per_deployment
+        .get(&manifest_id)
+        .map_or(&[][..], Vec::as_slice);
+    let (receipts, body) = extract_receipt(
+        receipt.into_signed_receipts(),
+        receipt_query,
+        body,
+        transports,
+    )
+    .map_err(IndexerServiceError::InvalidReceiptTransport)?;
+    let format = ResponseFormat::negotiate(&headers);
+    let request_id = request_id(&headers, receipts.first());
 
     let request =
         serde_json::from_slice(&body).map_err(|e| IndexerServiceError::InvalidRequest(e.into()))?;
 
     let mut attestation_signer: Option<AttestationSigner> = None;
+    let mut paid_receipt = None;
+    let mut receipt_validation = ReceiptValidationMode::None;
+
+    if let Some(receipt) = receipts.first().cloned() {
+        let payment_value = check_payment(&receipts, state.config.tap.max_receipts_per_query)
+            .map_err(IndexerServiceError::InvalidReceiptPayment)?;
+
+        if let Some(reason) = state
+            .paused_deployments
+            .value_immediate()
+            .and_then(|paused| paused.get(&manifest_id).cloned())
+        {
+            return Err(IndexerServiceError::DeploymentPaused(manifest_id, reason));
+        }
+
+        // Receipts for allocations closed past their grace period get their own error, for
+        // gateways to select another allocation. The receipts of a payment share the allocation
+        if let Err(e) = request_timing::timed(
+            Phase::ReceiptVerification,
+            state
+                .allocation_eligible
+                .check(&ReceiptWithState::new(receipt.clone())),
+        )
+        .await
+        {
+            if let Some(closed) = e.downcast_ref::<AllocationClosed>() {
+                return Err(IndexerServiceError::ClosedAllocation(closed.clone()));
+            }
+        }
+
+        // Checked before storing the receipt, a receipt bound to another query isn't a payment
+        // for this one
+        if let Some(query_body) = &query_body {
+            let started = Instant::now();
+            let binding = recover_signer(&receipt, &state.domain_separator)
+                .map_err(|e| QueryBindingError::Invalid(e.to_string()))
+                .and_then(|signer| verify_query_binding(&headers, &receipt, signer, query_body));
+            request_timing::record(Phase::ReceiptVerification, started.elapsed());
+            binding.map_err(IndexerServiceError::InvalidQueryBinding)?;
+        }
 
-    if let Some(receipt) = receipt.into_signed_receipt() {
         let allocation_id = receipt.message.allocation_id;
+        // Virtual allocations are only valid for the service they were declared for
+        if let Some(virtual_allocation) = state
+            .virtual_allocations
+            .value_immediate()
+            .and_then(|allocations| allocations.get(&allocation_id).cloned())
+        {
+            if virtual_allocation.service != state.url_namespace {
+                return Err(IndexerServiceError::VirtualAllocationOfOtherService(
+                    allocation_id,
+                    virtual_allocation.service,
+                ));
+            }
+        }
+        let price_multiplier = state.sender_pricing.multiplier_for_receipt(&receipt);
+        let mut paid = receipt.message.clone();
+        paid.value = payment_value;
+        paid_receipt = Some((paid, price_multiplier));
 
-        // Verify the receipt and store it in the database
-        // TODO update checks
-        state
-            .tap_manager
-            .verify_and_store_receipt(receipt)
+        // Bypassing the receipt queue, see below
+        let paid_together = receipts.len() > 1;
+        let accepted = if !paid_together {
+            // Verify the receipt and store it in the database
+            // TODO update checks
+            request_timing::timed_excluding(
+                Phase::ReceiptVerification,
+                Phase::DatabaseWrite,
+                state.tap_manager.verify_and_store_receipt(receipt),
+            )
             .await
-            .map_err(IndexerServiceError::ReceiptError)?;
+            .map_err(IndexerServiceError::ReceiptError)
+        } else {
+            // Verify the receipts one by one, then store them together, linked to the request
+            let (verified, checked_receipts) = request_timing::timed_excluding(
+                Phase::ReceiptVerification,
+                Phase::DatabaseWrite,
+                payment_scope(payment_value, async {
+                    for receipt in receipts {
+                        state.tap_manager.verify_and_store_receipt(receipt).await?;
+                    }
+                    Ok::<_, tap_core::Error>(())
+                }),
+            )
+            .await;
+            match verified {
+                Ok(()) => store_payment(
+                    &state.database,
+                    state.read_only_database.as_ref(),
+                    &request_id,
+                    checked_receipts,
+                )
+                .await
+                .map_err(IndexerServiceError::FailedToStoreReceipts),
+                Err(e) => Err(IndexerServiceError::ReceiptError(e)),
+            }
+        };
+        // The receipts rejected as invalid, e.g. by their signature or the denylist, are the
+        // senders' doing and don't burn the error budget of the indexer
+        match &accepted {
+            Ok(_) => slo::record_receipt_acceptance(true),
+            Err(e) if is_indexer_failure(e) => slo::record_receipt_acceptance(false),
+            Err(_) => {}
+        }
+        accepted?;
+        paid_query.accepted();
+        if let Some((receipt, _)) = &paid_receipt {
+            fleet_stats::record_receipt(manifest_id, receipt.value);
+        }
+        receipt_validation = if state
+            .read_only_database
+            .as_ref()
+            .is_some_and(|database| database.is_read_only())
+        {
+            ReceiptValidationMode::Journaled
+        } else if state.config.tap.receipt_queue.is_some() && !paid_together {
+            ReceiptValidationMode::Queued
+        } else {
+            ReceiptValidationMode::Synchronous
+        };
 
-        // Check if we have an attestation signer for the allocation the receipt was created for
-        let signers = state
-            .attestation_signers
-            .value_immediate()
-            .ok_or_else(|| IndexerServiceError::ServiceNotReady)?;
-
-        attestation_signer = Some(
-            signers
-                .get(&allocation_id)
-                .cloned()
-                .ok_or_else(|| (IndexerServiceError::NoSignerForAllocation(allocation_id)))?,
-        );
+        // Check if we have an attestation signer for the allocation the receipt was created for.
+        // Also needed for deployments with attestations disabled, to sign their disclosure
+        if state.attestations {
+            let signers = state
+                .attestation_signers
+                .value_immediate()
+                .ok_or_else(|| IndexerServiceError::ServiceNotReady)?;
+
+            attestation_signer = Some(
+                signers
+                    .get(&allocation_id)
+                    .cloned()
+                    .ok_or_else(|| (IndexerServiceError::NoSignerForAllocation(allocation_id)))?,
+            );
+        }
     } else {
         match headers
             .get("authorization")
@@ -85,26 +279,113 @@ where
         }
     }
 
-    let (request, response) = state
-        .service_impl
-        .process_request(manifest_id, request)
-        .await
-        .map_err(IndexerServiceError::ProcessingError)?;
+    let (request, response) = request_timing::timed(
+        Phase::Execution,
+        state.service_impl.process_request(manifest_id, request),
+    )
+    .await
+    .map_err(IndexerServiceError::ProcessingError)?;
 
-    let attestation = match (response.is_attestable(), attestation_signer) {
+    let attestations_disabled = state
+        .config
+        .server
+        .attestations_disabled_deployments
+        .contains(&manifest_id);
+    let mut response_headers = HeaderMap::new();
+    if attestations_disabled {
+        if let Some(signer) = attestation_signer.take() {
+            let started = Instant::now();
+            let disclosure = signer
+                .attestations_disabled_disclosure()
+                .ok()
+                .and_then(|disclosure| HeaderValue::from_str(&disclosure).ok())
+                .ok_or(IndexerServiceError::FailedToSignAttestation)?;
+            request_timing::record(Phase::AttestationSigning, started.elapsed());
+            response_headers.insert(ATTESTATION_DISCLOSURE_HEADER, disclosure);
+        }
+    }
+
+    let attestable = state.attestations && !attestations_disabled && response.is_attestable();
+    let attestation = match (attestable, attestation_signer) {
         (false, _) => None,
         (true, None) => return Err(IndexerServiceError::NoSignerForManifest(manifest_id)),
         (true, Some(signer)) => {
+            let started = Instant::now();
             let req = serde_json::to_string(&request)
                 .map_err(|_| IndexerServiceError::FailedToSignAttestation)?;
             let res = response
                 .as_str()
                 .map_err(|_| IndexerServiceError::FailedToSignAttestation)?;
-            Some(signer.create_attestation(&req, res))
+            let attestation = signer.create_attestation(&req, res);
+            request_timing::record(Phase::AttestationSigning, started.elapsed());
+            Some(attestation)
         }
     };
 
+    let (query, variables) = serde_json::to_value(&request)
+        .map(|request| query_stats::graphql_query(&request))
+        .unwrap_or_default();
+    let block_constraints = match (format, &query) {
+        (ResponseFormat::EnvelopeV1, Some(query)) => block_constraints(
+            query,
+            variables
+                .as_deref()
+                .and_then(|variables| serde_json::from_str(variables).ok())
+                .as_ref(),
+        ),
+        _ => Vec::new(),
+    };
+
+    // Record the resources used to serve the paid query, without delaying the response
+    if let Some((receipt, price_multiplier)) = paid_receipt {
+        let stats = QueryStats {
+            deployment: manifest_id,
+            allocation_id: receipt.allocation_id,
+            timestamp_ns: receipt.timestamp_ns,
+            nonce: receipt.nonce,
+            value: receipt.value,
+            response_bytes: response.as_str().map_or(0, |res| res.len() as u64),
+            execution_time: response.execution_time(),
+            price_multiplier,
+            query,
+            variables,
+        };
+        state.query_stats.record(stats);
+    }
+
+    paid_query.served();
+    if format == ResponseFormat::EnvelopeV1 {
+        let envelope = ResponseEnvelope {
+            version: 1,
+            request_id,
+            deployment: manifest_id,
+            response: response
+                .as_str()
+                .map_err(|_| IndexerServiceError::FailedToBuildEnvelope)?,
+            attestation,
+            block_constraints,
+            degradation: Degradation {
+                receipt_validation,
+                attestations_disabled: attestations_disabled && response.is_attestable(),
+                cache_hit: false,
+            },
+        };
+        return Ok((StatusCode::OK, response_headers, envelope).into_response());
+    }
     let response = response.finalize(attestation);
 
-    Ok((StatusCode::OK, response))
+    Ok((StatusCode::OK, response_headers, response).into_response())
+}
+
+/// Whether the receipts failed to be accepted on the indexer's side, e.g. failing to be stored,
+/// rather than for being invalid.
+fn is_indexer_failure<E>(error: &IndexerServiceError<E>) -> bool
+where
+    E: std::error::Error,
+{
+    matches!(
+        error,
+        IndexerServiceError::FailedToStoreReceipts(_)
+            | IndexerServiceError::ReceiptError(tap_core::Error::AdapterError { .. })
+    )
 }
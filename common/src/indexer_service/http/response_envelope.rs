@@ -0,0 +1,261 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Versioned envelope of the query responses, carrying along with the response its attestation,
+//! an id of the request, the block constraints of the query, and whether it was served in a
+//! degraded mode. Gateways opt in with the envelope media type in their `Accept` header, the
+//! other requests keep getting the legacy format of the service.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use alloy_primitives::keccak256;
+use axum::{
+    http::{header, HeaderMap, HeaderValue},
+    response::{IntoResponse, Response},
+};
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::Serialize;
+use serde_json::Value;
+use tap_core::receipt::SignedReceipt;
+use thegraph::types::{Attestation, DeploymentId};
+
+pub const ENVELOPE_V1_MEDIA_TYPE: &str = "application/vnd.graph-indexer.response.v1+json";
+/// Id of the request, as set by the gateway or made up by the indexer-service.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+lazy_static! {
+    static ref BLOCK_ARGUMENT: Regex = Regex::new(r"\bblock\s*:\s*(\{[^}]*\}|\$\w+)").unwrap();
+    static ref BLOCK_FIELD: Regex =
+        Regex::new(r#"\b(number_gte|number|hash)\s*:\s*("[^"]*"|\$\w+|\d+)"#).unwrap();
+}
+
+static LOCAL_REQUESTS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResponseFormat {
+    Legacy,
+    EnvelopeV1,
+}
+
+impl ResponseFormat {
+    /// The envelope if accepted by the client, the legacy format otherwise.
+    pub fn negotiate(headers: &HeaderMap) -> Self {
+        let accepts_envelope = headers
+            .get_all(header::ACCEPT)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|media_range| {
+                let mut params = media_range.split(';').map(str::trim);
+                params.next() == Some(ENVELOPE_V1_MEDIA_TYPE)
+                    && !params.any(|param| {
+                        param.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()) == Some(0.0)
+                    })
+            });
+        if accepts_envelope {
+            ResponseFormat::EnvelopeV1
+        } else {
+            ResponseFormat::Legacy
+        }
+    }
+}
+
+/// Block a query is constrained to, as given by its `block` arguments.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum BlockConstraint {
+    Number(u64),
+    NumberGte(u64),
+    Hash(String),
+}
+
+/// How receipts are validated before the query is served.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ReceiptValidationMode {
+    /// Stored before serving the query.
+    Synchronous,
+    /// Checked before serving the query, stored after it through the receipt queue.
+    Queued,
+    /// Appended to the journal on disk, the database being read only.
+    Journaled,
+    /// Free query, without a receipt.
+    None,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Degradation {
+    pub receipt_validation: ReceiptValidationMode,
+    /// The response is attestable, but the deployment opted out of attestations.
+    pub attestations_disabled: bool,
+    /// The responses aren't cached by the indexer-service yet, so this is always false.
+    pub cache_hit: bool,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ResponseEnvelope<'a> {
+    pub version: u32,
+    pub request_id: String,
+    pub deployment: DeploymentId,
+    /// Response of the service, as attested.
+    pub response: &'a str,
+    pub attestation: Option<Attestation>,
+    pub block_constraints: Vec<BlockConstraint>,
+    pub degradation: Degradation,
+}
+
+impl IntoResponse for ResponseEnvelope<'_> {
+    fn into_response(self) -> Response {
+        let request_id = HeaderValue::from_str(&self.request_id).ok();
+        let body = serde_json::to_string(&self).expect("the envelope serializes to JSON");
+        let mut response = (
+            [
+                (
+                    header::CONTENT_TYPE,
+                    HeaderValue::from_static(ENVELOPE_V1_MEDIA_TYPE),
+                ),
+                (header::VARY, HeaderValue::from_static("accept")),
+            ],
+            body,
+        )
+            .into_response();
+        if let Some(request_id) = request_id {
+            response.headers_mut().insert(REQUEST_ID_HEADER, request_id);
+        }
+        response
+    }
+}
+
+/// The id set by the gateway if any, otherwise derived from the receipt of a paid query, which
+/// is unique, or from the local time and a counter for a free query.
+pub fn request_id(headers: &HeaderMap, receipt: Option<&SignedReceipt>) -> String {
+    if let Some(request_id) = headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| !value.is_empty())
+    {
+        return request_id.to_string();
+    }
+    let seed = match receipt {
+        Some(receipt) => receipt.signature.to_vec(),
+        None => {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("Time went backwards")
+                .as_nanos();
+            let count = LOCAL_REQUESTS.fetch_add(1, Ordering::Relaxed);
+            [now.to_be_bytes().as_slice(), &count.to_be_bytes()].concat()
+        }
+    };
+    keccak256(seed).to_string()
+}
+
+/// Block constraints of a GraphQL query, with the ones given by variables resolved. Unresolved
+/// and invalid constraints are skipped.
+pub fn block_constraints(query: &str, variables: Option<&Value>) -> Vec<BlockConstraint> {
+    let variable = |name: &str| variables.and_then(|variables| variables.get(name));
+    let mut constraints = Vec::new();
+    for argument in BLOCK_ARGUMENT.captures_iter(query) {
+        let argument = &argument[1];
+        if let Some(name) = argument.strip_prefix('$') {
+            if let Some(Value::Object(block)) = variable(name) {
+                for (field, value) in block {
+                    constraints.extend(constraint(field, value));
+                }
+            }
+            continue;
+        }
+        for field in BLOCK_FIELD.captures_iter(argument) {
+            let value = &field[2];
+            let value = match value.strip_prefix('$') {
+                Some(name) => variable(name).cloned(),
+                None => serde_json::from_str(value).ok(),
+            };
+            constraints.extend(value.and_then(|value| constraint(&field[1], &value)));
+        }
+    }
+    constraints
+}
+
+fn constraint(field: &str, value: &Value) -> Option<BlockConstraint> {
+    // Block numbers may be given as strings in variables
+    let number = || match value {
+        Value::Number(number) => number.as_u64(),
+        Value::String(number) => number.parse().ok(),
+        _ => None,
+    };
+    match field {
+        "number" => number().map(BlockConstraint::Number),
+        "number_gte" => number().map(BlockConstraint::NumberGte),
+        "hash" => value
+            .as_str()
+            .map(|hash| BlockConstraint::Hash(hash.to_string())),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        let format = |accept: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT, HeaderValue::from_str(accept).unwrap());
+            ResponseFormat::negotiate(&headers)
+        };
+        assert_eq!(
+            ResponseFormat::negotiate(&HeaderMap::new()),
+            ResponseFormat::Legacy
+        );
+        assert_eq!(format("application/json"), ResponseFormat::Legacy);
+        assert_eq!(
+            format(&format!(
+                "application/json, {ENVELOPE_V1_MEDIA_TYPE}; q=0.9"
+            )),
+            ResponseFormat::EnvelopeV1
+        );
+        assert_eq!(
+            format(&format!("{ENVELOPE_V1_MEDIA_TYPE};q=0")),
+            ResponseFormat::Legacy
+        );
+    }
+
+    #[test]
+    fn test_block_constraints() {
+        assert_eq!(
+            block_constraints(
+                r#"{ a: tokens(block: { number: 10 }) { id } b: pairs(block: { hash: "0xab" }) { id } }"#,
+                None
+            ),
+            vec![
+                BlockConstraint::Number(10),
+                BlockConstraint::Hash("0xab".to_string())
+            ]
+        );
+        assert_eq!(
+            block_constraints(
+                "query($min: Int, $at: Block_height) { a: tokens(block: { number_gte: $min }) { id } b: pairs(block: $at) { id } }",
+                Some(&json!({ "min": 5, "at": { "number": "7" } }))
+            ),
+            vec![BlockConstraint::NumberGte(5), BlockConstraint::Number(7)]
+        );
+        assert!(block_constraints("{ tokens(block: $missing) { id } }", None).is_empty());
+        assert!(block_constraints("{ tokens { id } }", None).is_empty());
+    }
+
+    #[test]
+    fn test_request_id() {
+        let mut headers = HeaderMap::new();
+        assert_ne!(request_id(&headers, None), request_id(&headers, None));
+        headers.insert(REQUEST_ID_HEADER, HeaderValue::from_static("gateway-id"));
+        assert_eq!(request_id(&headers, None), "gateway-id");
+    }
+}
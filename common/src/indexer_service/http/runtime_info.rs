@@ -0,0 +1,155 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+use sqlx::PgPool;
+use thegraph::types::Address;
+use tracing::{debug, info};
+
+use super::{IndexerServiceConfig, IndexerServiceRelease};
+
+/// What an indexer-service instance runs and how it is set up, served at `/info` for fleet
+/// inventory tooling and logged on startup. Only public information is included.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RuntimeInfo {
+    /// Operator address, kept under this name for the existing consumers of `/info`.
+    pub public_key: String,
+    pub version: String,
+    pub git_sha: Option<String>,
+    pub indexer_address: Address,
+    pub graph_network_chain_id: u64,
+    pub tap_chain_id: u64,
+    pub receipts_verifier_address: Address,
+    /// URL namespaces of the data services served.
+    pub data_services: Vec<&'static str>,
+    pub features: BTreeMap<&'static str, bool>,
+    /// Latest database migration applied, if known.
+    pub schema_version: Option<i64>,
+}
+
+impl RuntimeInfo {
+    pub fn new(
+        config: &IndexerServiceConfig,
+        release: &IndexerServiceRelease,
+        public_key: String,
+        data_services: Vec<&'static str>,
+        schema_version: Option<i64>,
+    ) -> Self {
+        let features = BTreeMap::from([
+            (
+                "serve_network_subgraph",
+                config.network_subgraph.serve_subgraph,
+            ),
+            (
+                "serve_escrow_subgraph",
+                config.escrow_subgraph.serve_subgraph,
+            ),
+            (
+                "free_queries",
+                config.server.free_query_auth_token.is_some(),
+            ),
+            (
+                "network_subgraph_quorum",
+                config.network_subgraph.quorum.is_some(),
+            ),
+            ("receipt_queue", config.tap.receipt_queue.is_some()),
+            (
+                "read_only_database",
+                config.tap.read_only_database.is_some(),
+            ),
+            (
+                "alternative_receipt_transports",
+                !config.tap.receipt_transports_per_deployment.is_empty(),
+            ),
+            (
+                "attestation_opt_out",
+                !config.server.attestations_disabled_deployments.is_empty(),
+            ),
+            (
+                "pause_unhealthy_deployments",
+                config.server.pause_unhealthy_deployments,
+            ),
+            ("graph_node", config.graph_node.is_some()),
+            ("tenant_isolation", config.database.tenant_isolation),
+            (
+                "direct_receipt_notifications",
+                config.database.receipt_notification_socket.is_some(),
+            ),
+            (
+                "virtual_allocations",
+                !config.tap.virtual_allocation_services.is_empty(),
+            ),
+            ("query_binding", config.tap.require_query_binding),
+            ("fleet_stats", config.server.fleet_stats.is_some()),
+            ("slo", config.server.slo.is_some()),
+        ]);
+
+        Self {
+            public_key,
+            version: release.version().to_string(),
+            git_sha: release.git_sha().map(ToString::to_string),
+            indexer_address: config.indexer.indexer_address,
+            graph_network_chain_id: config.graph_network.chain_id,
+            tap_chain_id: config.tap.chain_id,
+            receipts_verifier_address: config.tap.receipts_verifier_address,
+            data_services,
+            features,
+            schema_version,
+        }
+    }
+
+    pub fn log(&self) {
+        let enabled_features: Vec<_> = self
+            .features
+            .iter()
+            .filter(|(_, enabled)| **enabled)
+            .map(|(feature, _)| *feature)
+            .collect();
+        info!(
+            version = %self.version,
+            git_sha = self.git_sha.as_deref(),
+            indexer_address = %self.indexer_address,
+            operator_address = %self.public_key,
+            graph_network_chain_id = self.graph_network_chain_id,
+            tap_chain_id = self.tap_chain_id,
+            receipts_verifier_address = %self.receipts_verifier_address,
+            data_services = ?self.data_services,
+            features = ?enabled_features,
+            schema_version = self.schema_version,
+            "Starting indexer-service"
+        );
+    }
+}
+
+/// Latest migration recorded by sqlx, `None` when the migrations are managed elsewhere.
+pub async fn schema_version(pgpool: &PgPool) -> Option<i64> {
+    sqlx::query_scalar("SELECT MAX(version) FROM _sqlx_migrations")
+        .fetch_one(pgpool)
+        .await
+        .map_err(|e| debug!("Could not read the database schema version: {}", e))
+        .ok()
+        .flatten()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_schema_version(pgpool: PgPool) {
+        let latest: i64 = sqlx::query_scalar("SELECT MAX(version) FROM _sqlx_migrations")
+            .fetch_one(&pgpool)
+            .await
+            .unwrap();
+        assert_eq!(schema_version(&pgpool).await, Some(latest));
+
+        sqlx::query("DROP TABLE _sqlx_migrations")
+            .execute(&pgpool)
+            .await
+            .unwrap();
+        assert_eq!(schema_version(&pgpool).await, None);
+    }
+}
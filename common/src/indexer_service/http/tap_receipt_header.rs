@@ -5,19 +5,22 @@ use std::ops::Deref;
 
 use axum_extra::headers::{self, Header, HeaderName, HeaderValue};
 use lazy_static::lazy_static;
+use serde::de::Error;
 use tap_core::receipt::SignedReceipt;
 
+/// Receipts of the `Tap-Receipt` header, either a single receipt, or a JSON array of the
+/// receipts paying for the query together. Empty without the header.
 #[derive(Debug, PartialEq)]
-pub struct TapReceipt(Option<SignedReceipt>);
+pub struct TapReceipt(Vec<SignedReceipt>);
 
 impl TapReceipt {
-    pub fn into_signed_receipt(self) -> Option<SignedReceipt> {
+    pub fn into_signed_receipts(self) -> Vec<SignedReceipt> {
         self.0
     }
 }
 
 impl Deref for TapReceipt {
-    type Target = Option<SignedReceipt>;
+    type Target = [SignedReceipt];
 
     fn deref(&self) -> &Self::Target {
         &self.0
@@ -42,11 +45,11 @@ impl Header for TapReceipt {
             .map(|value| value.to_str())
             .transpose()
             .map_err(|_| headers::Error::invalid())?;
-        let parsed_receipt = raw_receipt
-            .map(serde_json::from_str)
+        let parsed_receipts = raw_receipt
+            .map(parse_receipts)
             .transpose()
             .map_err(|_| headers::Error::invalid())?;
-        Ok(TapReceipt(parsed_receipt))
+        Ok(TapReceipt(parsed_receipts.unwrap_or_default()))
     }
 
     fn encode<E>(&self, _values: &mut E)
@@ -57,6 +60,18 @@ impl Header for TapReceipt {
     }
 }
 
+/// Parses a single receipt, or a non-empty JSON array of receipts.
+pub fn parse_receipts(raw: &str) -> serde_json::Result<Vec<SignedReceipt>> {
+    if !raw.trim_start().starts_with('[') {
+        return serde_json::from_str(raw).map(|receipt| vec![receipt]);
+    }
+    let receipts: Vec<SignedReceipt> = serde_json::from_str(raw)?;
+    if receipts.is_empty() {
+        return Err(serde_json::Error::custom("empty array of receipts"));
+    }
+    Ok(receipts)
+}
+
 #[cfg(test)]
 mod test {
     use std::str::FromStr;
@@ -80,7 +95,24 @@ mod test {
         let decoded_receipt = TapReceipt::decode(&mut header_values.into_iter())
             .expect("tap receipt header value should be valid");
 
-        assert_eq!(decoded_receipt, TapReceipt(Some(original_receipt.clone())));
+        assert_eq!(decoded_receipt, TapReceipt(vec![original_receipt.clone()]));
+    }
+
+    #[tokio::test]
+    async fn test_decode_tap_receipt_array_header() {
+        let allocation = Address::from_str("0xdeadbeefcafebabedeadbeefcafebabedeadbeef").unwrap();
+        let receipts = vec![
+            create_signed_receipt(allocation, 1, 1, 10).await,
+            create_signed_receipt(allocation, 2, 1, u128::MAX).await,
+        ];
+        let header_value =
+            HeaderValue::from_str(&serde_json::to_string(&receipts).unwrap()).unwrap();
+        let decoded_receipts = TapReceipt::decode(&mut std::iter::once(&header_value))
+            .expect("tap receipt header value should be valid");
+        assert_eq!(decoded_receipts, TapReceipt(receipts));
+
+        let header_value = HeaderValue::from_static(" []");
+        assert!(TapReceipt::decode(&mut std::iter::once(&header_value)).is_err());
     }
 
     #[test]
@@ -2,28 +2,51 @@
 // SPDX-License-Identifier: Apache-2.0
 
 pub mod address;
+pub mod agreements;
 pub mod allocations;
 pub mod attestations;
+pub mod db_consistency;
+pub mod db_metrics;
+pub mod db_pool;
+pub mod doctor;
 pub mod escrow_accounts;
+pub mod events;
+pub mod fleet_stats;
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing;
 pub mod graphql;
+pub mod http_proxy;
 pub mod indexer_errors;
 pub mod indexer_service;
 pub mod metrics;
+pub mod monitor_backoff;
+pub mod notifications;
+pub mod query_stats;
+pub mod readiness;
+pub mod receipt_notifications;
+pub mod request_timing;
+pub mod schema_drift;
+pub mod secrets;
 pub mod signature_verification;
+pub mod slo;
 pub mod subgraph_client;
+#[cfg(feature = "systemd")]
+pub mod systemd;
 pub mod tap;
-
-#[cfg(test)]
-mod test_vectors;
+pub mod tenant;
+pub mod test_vectors;
+pub mod types;
 
 pub mod prelude {
     pub use super::allocations::{
-        monitor::indexer_allocations, Allocation, AllocationStatus, SubgraphDeployment,
+        indexing_status::paused_deployments,
+        monitor::{indexer_allocations, indexer_allocations_with_quorum, majority_quorum},
+        Allocation, AllocationStatus, SubgraphDeployment,
     };
     pub use super::attestations::{
         dispute_manager::dispute_manager, signer::AttestationSigner, signers::attestation_signers,
     };
-    pub use super::escrow_accounts::escrow_accounts;
+    pub use super::escrow_accounts::{escrow_accounts, escrow_accounts_with_overrides};
     pub use super::indexer_errors;
     pub use super::subgraph_client::{DeploymentDetails, Query, QueryVariables, SubgraphClient};
     pub use super::tap::IndexerTapContext;
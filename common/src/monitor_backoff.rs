@@ -0,0 +1,222 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Retries of the monitors polling the subgraphs, graph-node and the database through eventuals'
+//! `map_with_retry`, e.g. the allocations and the escrow accounts. The retries back off
+//! exponentially from an eighth of the polling interval up to four intervals, with jitter so
+//! that the monitors of a fleet of instances don't retry in lockstep against a recovering
+//! endpoint. A monitor failing [MonitorAlarms::after_failures] times in a row raises
+//! the `indexer_monitor_alarm` metric, and notifies the webhook if configured, until it
+//! succeeds again.
+
+use std::future::Future;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use arc_swap::ArcSwap;
+use ethers_core::rand::{thread_rng, Rng};
+use lazy_static::lazy_static;
+use prometheus::{register_int_gauge_vec, IntGaugeVec};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{error, info, warn};
+
+lazy_static! {
+    static ref ALARMS: ArcSwap<Alarms> = ArcSwap::from_pointee(Alarms::default());
+    static ref MONITOR_CONSECUTIVE_FAILURES: IntGaugeVec = register_int_gauge_vec!(
+        "indexer_monitor_consecutive_failures",
+        "Consecutive failures of the monitors, by monitor",
+        &["monitor"]
+    )
+    .unwrap();
+    static ref MONITOR_ALARM: IntGaugeVec = register_int_gauge_vec!(
+        "indexer_monitor_alarm",
+        "Whether a monitor failed as many times in a row as the alarm threshold, by monitor",
+        &["monitor"]
+    )
+    .unwrap();
+}
+
+/// When the failures of a monitor are alarming.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MonitorAlarms {
+    /// Consecutive failures of a monitor raising its alarm.
+    pub after_failures: u32,
+    /// Notified with a JSON body when an alarm is raised, if set.
+    pub webhook_url: Option<String>,
+}
+
+impl Default for MonitorAlarms {
+    fn default() -> Self {
+        Self {
+            after_failures: 5,
+            webhook_url: None,
+        }
+    }
+}
+
+/// The alarms of the monitors, along with the client notifying their webhook.
+#[derive(Default)]
+struct Alarms {
+    settings: MonitorAlarms,
+    http_client: reqwest::Client,
+}
+
+/// Raises the alarms of the monitors by `alarms` from now on, notifying the webhook through
+/// `http_client`.
+pub fn configure(alarms: MonitorAlarms, http_client: reqwest::Client) {
+    ALARMS.store(Arc::new(Alarms {
+        settings: alarms,
+        http_client,
+    }));
+}
+
+/// Retry delays of a monitor, shared by its `map_with_retry` closures: the successes are
+/// [tracked](Backoff::track) by the mapping, and the failures are waited for by the retry.
+pub struct Backoff {
+    monitor: &'static str,
+    initial: Duration,
+    max: Duration,
+    consecutive_failures: AtomicU32,
+}
+
+impl Backoff {
+    /// Backoff of a monitor polling every `interval`.
+    pub fn new(monitor: &'static str, interval: Duration) -> Arc<Self> {
+        Self::with_bounds(monitor, interval.div_f32(8.0), interval.saturating_mul(4))
+    }
+
+    pub fn with_bounds(monitor: &'static str, initial: Duration, max: Duration) -> Arc<Self> {
+        Arc::new(Self {
+            monitor,
+            initial,
+            max,
+            consecutive_failures: AtomicU32::new(0),
+        })
+    }
+
+    /// Resets the backoff, and the alarm, once `result` is a success.
+    pub fn track<T, E>(&self, result: Result<T, E>) -> Result<T, E> {
+        if result.is_ok() {
+            let failures = self.consecutive_failures.swap(0, Ordering::SeqCst);
+            if failures == 0 {
+                return result;
+            }
+            MONITOR_CONSECUTIVE_FAILURES
+                .with_label_values(&[self.monitor])
+                .set(0);
+            if failures >= ALARMS.load().settings.after_failures {
+                MONITOR_ALARM.with_label_values(&[self.monitor]).set(0);
+                info!(
+                    monitor = self.monitor,
+                    failures, "Monitor recovered after failing repeatedly"
+                );
+            }
+        }
+        result
+    }
+
+    /// Waits before the next retry of the monitor, which just failed with `error`.
+    pub fn failed(&self, error: &str) -> impl Future<Output = ()> + Send + 'static {
+        tokio::time::sleep(self.record_failure(error))
+    }
+
+    /// Counts the failure and raises the alarm if needed, returning the delay before the next
+    /// retry.
+    fn record_failure(&self, error: &str) -> Duration {
+        let failures = self.consecutive_failures.fetch_add(1, Ordering::SeqCst) + 1;
+        MONITOR_CONSECUTIVE_FAILURES
+            .with_label_values(&[self.monitor])
+            .set(failures.into());
+
+        let alarms = ALARMS.load();
+        if failures == alarms.settings.after_failures {
+            MONITOR_ALARM.with_label_values(&[self.monitor]).set(1);
+            error!(
+                monitor = self.monitor,
+                failures, "Monitor keeps failing: {}", error
+            );
+            if let Some(webhook_url) = alarms.settings.webhook_url.clone() {
+                let body = json!({
+                    "monitor": self.monitor,
+                    "consecutiveFailures": failures,
+                    "error": error,
+                });
+                let http_client = alarms.http_client.clone();
+                tokio::spawn(async move {
+                    let result = http_client
+                        .post(webhook_url)
+                        .header(reqwest::header::CONTENT_TYPE, "application/json")
+                        .body(body.to_string())
+                        .send()
+                        .await
+                        .and_then(|response| response.error_for_status());
+                    if let Err(e) = result {
+                        warn!("Failed to notify the monitor alarm webhook: {}", e);
+                    }
+                });
+            }
+        }
+        self.delay(failures)
+    }
+
+    /// Doubles with every failure up to the cap, less up to half of it at random.
+    fn delay(&self, failures: u32) -> Duration {
+        let exponential = self
+            .initial
+            .saturating_mul(2u32.saturating_pow(failures.saturating_sub(1)))
+            .min(self.max);
+        exponential.mul_f64(1.0 - thread_rng().gen::<f64>() / 2.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay() {
+        let backoff = Backoff::new("test_delay", Duration::from_secs(8));
+        for (failures, max) in [(1, 1), (2, 2), (3, 4), (4, 8), (6, 32), (7, 32), (40, 32)] {
+            let delay = backoff.delay(failures);
+            let max = Duration::from_secs(max);
+            assert!(
+                delay <= max && delay >= max / 2,
+                "{delay:?} after {failures} failures"
+            );
+        }
+    }
+
+    #[test]
+    fn test_alarm() {
+        let backoff = Backoff::new("test_alarm", Duration::from_secs(8));
+        let alarm = || MONITOR_ALARM.with_label_values(&["test_alarm"]).get();
+
+        for _ in 1..MonitorAlarms::default().after_failures {
+            backoff.record_failure("unreachable");
+        }
+        assert_eq!(alarm(), 0);
+        backoff.record_failure("unreachable");
+        assert_eq!(alarm(), 1);
+        assert_eq!(
+            MONITOR_CONSECUTIVE_FAILURES
+                .with_label_values(&["test_alarm"])
+                .get(),
+            5
+        );
+
+        // A failure doesn't reset the backoff
+        assert!(backoff.track(Err::<(), _>("unreachable")).is_err());
+        assert_eq!(alarm(), 1);
+
+        assert!(backoff.track(Ok::<_, ()>(())).is_ok());
+        assert_eq!(alarm(), 0);
+        assert_eq!(
+            MONITOR_CONSECUTIVE_FAILURES
+                .with_label_values(&["test_alarm"])
+                .get(),
+            0
+        );
+    }
+}
@@ -0,0 +1,275 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Typed payloads of the PG NOTIFY channels. The payloads are written by the triggers of the
+//! migrations, and by the indexer-service for the receipts it notifies directly, so that the
+//! database and a binary of another release can disagree on their fields. Each payload carries
+//! the `version` of its schema: the payloads of an older version are read through a shim, and
+//! the ones of a newer version, or that don't match their schema, are rejected rather than
+//! half read. The payloads predating the versioning have no `version`, and are version 0.
+//!
+//! The payloads are defined next to their channel, see
+//! [ReceiptNotification](crate::receipt_notifications::ReceiptNotification),
+//! [DenylistNotification](crate::tap::DenylistNotification) and
+//! [EventNotification](crate::events::EventNotification).
+
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use serde::Deserialize;
+use thiserror::Error;
+
+lazy_static! {
+    static ref NOTIFICATIONS_REJECTED: IntCounterVec = register_int_counter_vec!(
+        "indexer_notifications_rejected_total",
+        "PG NOTIFY payloads that couldn't be read, by channel and reason",
+        &["channel", "reason"]
+    )
+    .unwrap();
+}
+
+/// Payload of a PG NOTIFY channel.
+pub trait Notification: Sized {
+    /// Channel the payloads are sent on.
+    const CHANNEL: &'static str;
+    /// Version of the payloads written by the current migrations.
+    const VERSION: u32;
+
+    /// Reads a payload of `version`, at most [Self::VERSION].
+    fn read(version: u32, payload: &str) -> serde_json::Result<Self>;
+
+    /// Writes the payload, with the current version.
+    fn write(&self) -> serde_json::Result<String>;
+}
+
+#[derive(Debug, Error)]
+pub enum NotificationError {
+    #[error(
+        "Notification of version {version} on `{channel}`, newer than the supported version \
+        {supported}"
+    )]
+    UnsupportedVersion {
+        channel: &'static str,
+        version: u32,
+        supported: u32,
+    },
+    #[error("Malformed notification of version {version} on `{channel}`: {source}")]
+    Malformed {
+        channel: &'static str,
+        version: u32,
+        #[source]
+        source: serde_json::Error,
+    },
+}
+
+/// Reads a payload received on [Notification::CHANNEL].
+pub fn parse<T: Notification>(payload: &str) -> Result<T, NotificationError> {
+    #[derive(Deserialize)]
+    struct Version {
+        #[serde(default)]
+        version: u32,
+    }
+
+    let malformed = |version, source| NotificationError::Malformed {
+        channel: T::CHANNEL,
+        version,
+        source,
+    };
+    let result = match serde_json::from_str::<Version>(payload) {
+        Ok(Version { version }) => Ok(version),
+        // The unversioned payloads aren't all JSON objects, e.g. the ids of the events
+        Err(_) if !payload.trim_start().starts_with('{') => Ok(0),
+        Err(source) => Err(malformed(0, source)),
+    }
+    .and_then(|version| {
+        if version > T::VERSION {
+            return Err(NotificationError::UnsupportedVersion {
+                channel: T::CHANNEL,
+                version,
+                supported: T::VERSION,
+            });
+        }
+        T::read(version, payload).map_err(|source| malformed(version, source))
+    });
+
+    if let Err(e) = &result {
+        let reason = match e {
+            NotificationError::UnsupportedVersion { .. } => "unsupported_version",
+            NotificationError::Malformed { .. } => "malformed",
+        };
+        NOTIFICATIONS_REJECTED
+            .with_label_values(&[T::CHANNEL, reason])
+            .inc();
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::{postgres::PgListener, PgPool};
+    use thegraph::types::Address;
+
+    use super::*;
+    use crate::events::{publish, Event, EventNotification, EVENTS_CHANNEL};
+    use crate::receipt_notifications::{ReceiptNotification, PG_NOTIFY_CHANNEL};
+    use crate::tap::{DenylistNotification, DenylistOperation, DENYLIST_CHANNEL};
+
+    #[test]
+    fn test_versions() {
+        let receipt = ReceiptNotification {
+            id: 1,
+            allocation_id: Address::from([0x03; 20]),
+            signer_address: Address::from([0x01; 20]),
+            timestamp_ns: 2,
+            value: u128::MAX,
+            indexer_address: Some(Address::from([0x05; 20])),
+        };
+        assert_eq!(
+            parse::<ReceiptNotification>(&receipt.write().unwrap()).unwrap(),
+            receipt
+        );
+
+        // Before the tenant isolation, and before the versioning
+        assert_eq!(
+            parse::<ReceiptNotification>(
+                r#"{"id": 1, "allocation_id": "0303030303030303030303030303030303030303",
+                    "signer_address": "0101010101010101010101010101010101010101",
+                    "timestamp_ns": 2, "value": 340282366920938463463374607431768211455}"#
+            )
+            .unwrap(),
+            ReceiptNotification {
+                indexer_address: None,
+                ..receipt.clone()
+            }
+        );
+
+        // Newer than this binary
+        assert!(matches!(
+            parse::<ReceiptNotification>(r#"{"version": 2, "id": 1}"#),
+            Err(NotificationError::UnsupportedVersion { version: 2, .. })
+        ));
+        // A field the schema doesn't know of
+        let mut payload: serde_json::Value =
+            serde_json::from_str(&receipt.write().unwrap()).unwrap();
+        payload["nonce"] = 1.into();
+        assert!(matches!(
+            parse::<ReceiptNotification>(&payload.to_string()),
+            Err(NotificationError::Malformed { version: 1, .. })
+        ));
+        // A field missing
+        assert!(matches!(
+            parse::<ReceiptNotification>(r#"{"version": 1, "id": 1}"#),
+            Err(NotificationError::Malformed { version: 1, .. })
+        ));
+        assert!(matches!(
+            parse::<ReceiptNotification>(r#"{"version": "1"}"#),
+            Err(NotificationError::Malformed { version: 0, .. })
+        ));
+
+        assert_eq!(
+            parse::<DenylistNotification>(r#"{"tg_op": "UPDATE", "sender_address": null}"#)
+                .unwrap(),
+            DenylistNotification {
+                operation: DenylistOperation::Update,
+                sender_address: None,
+            }
+        );
+        assert!(parse::<DenylistNotification>(r#"{"version": 1, "tg_op": "UPSERT"}"#).is_err());
+
+        assert_eq!(
+            parse::<EventNotification>("42").unwrap(),
+            EventNotification { id: 42 }
+        );
+        assert!(parse::<EventNotification>("forty-two").is_err());
+    }
+
+    /// The payloads written by the triggers of the migrations match their schema.
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_trigger_payloads(pgpool: PgPool) {
+        let mut listener = PgListener::connect_with(&pgpool).await.unwrap();
+        listener
+            .listen_all([PG_NOTIFY_CHANNEL, DENYLIST_CHANNEL, EVENTS_CHANNEL])
+            .await
+            .unwrap();
+
+        sqlx::query(
+            r#"
+                INSERT INTO scalar_tap_receipts
+                    (signer_address, signature, allocation_id, timestamp_ns, nonce, value)
+                VALUES ($1, $2, $3, 2, 3, 340282366920938463463374607431768211455)
+            "#,
+        )
+        .bind("0101010101010101010101010101010101010101")
+        .bind(vec![0u8; 65])
+        .bind("0303030303030303030303030303030303030303")
+        .execute(&pgpool)
+        .await
+        .unwrap();
+        let notification = listener.recv().await.unwrap();
+        assert_eq!(notification.channel(), PG_NOTIFY_CHANNEL);
+        let receipt = parse::<ReceiptNotification>(notification.payload()).unwrap();
+        assert_eq!(receipt.allocation_id, Address::from([0x03; 20]));
+        assert_eq!(receipt.signer_address, Address::from([0x01; 20]));
+        assert_eq!(receipt.timestamp_ns, 2);
+        assert_eq!(receipt.value, u128::MAX);
+        assert_eq!(receipt.indexer_address, None);
+
+        let sender = Address::from([0x07; 20]);
+        for (query, operation) in [
+            (
+                "INSERT INTO scalar_tap_denylist (sender_address) VALUES ($1)",
+                DenylistOperation::Insert,
+            ),
+            (
+                "DELETE FROM scalar_tap_denylist WHERE sender_address = $1",
+                DenylistOperation::Delete,
+            ),
+        ] {
+            sqlx::query(query)
+                .bind("0707070707070707070707070707070707070707")
+                .execute(&pgpool)
+                .await
+                .unwrap();
+            // The denylist triggers publish events as well
+            let mut denylist = None;
+            while denylist.is_none() {
+                let notification = listener.recv().await.unwrap();
+                match notification.channel() {
+                    DENYLIST_CHANNEL => {
+                        denylist =
+                            Some(parse::<DenylistNotification>(notification.payload()).unwrap())
+                    }
+                    EVENTS_CHANNEL => {
+                        parse::<EventNotification>(notification.payload()).unwrap();
+                    }
+                    channel => panic!("Unexpected notification on {channel}"),
+                }
+            }
+            assert_eq!(
+                denylist.unwrap(),
+                DenylistNotification {
+                    operation,
+                    sender_address: Some(sender),
+                }
+            );
+        }
+
+        let id = publish(
+            &pgpool,
+            "test",
+            &Event::CostModelChanged { deployment: None },
+        )
+        .await
+        .unwrap();
+        loop {
+            let notification = listener.recv().await.unwrap();
+            if notification.channel() == EVENTS_CHANNEL
+                && parse::<EventNotification>(notification.payload())
+                    .unwrap()
+                    .id
+                    == id
+            {
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,496 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Resources used to serve paid queries: the size of the response and the time graph-node took
+//! to answer it, stored along with the receipt that paid for the query. Aggregated per
+//! deployment, they let indexers check whether their cost models reflect the actual work done.
+//!
+//! The stats are recorded off the query path by [QueryStatsRecorder], through a bounded queue
+//! stored in batches, and kept for [RETENTION]. When the database can't keep up, the stats of the
+//! queries overflowing the queue are dropped and counted rather than delaying the responses.
+
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use alloy_primitives::Address;
+use anyhow::Result;
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter, IntCounter};
+use serde::Serialize;
+use serde_json::Value;
+use sqlx::{types::BigDecimal, PgPool, Postgres, QueryBuilder, Row};
+use thegraph::types::DeploymentId;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::time::{interval, interval_at, Instant, MissedTickBehavior};
+use tracing::warn;
+
+use crate::types::{AllocationIdHex, GrtWei};
+
+/// Stats of the paid queries waiting to be stored.
+const QUEUE_CAPACITY: usize = 10_000;
+/// Stats stored in a single insert.
+const MAX_BATCH_SIZE: usize = 500;
+/// Longest time the stats wait to be stored when there are fewer than [MAX_BATCH_SIZE].
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+/// How long the stats are kept, they are pruned once older.
+pub const RETENTION: Duration = Duration::from_secs(30 * 24 * 3600);
+const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+
+lazy_static! {
+    static ref DROPPED_QUERY_STATS: IntCounter = register_int_counter!(
+        "indexer_query_stats_dropped_total",
+        "Stats of paid queries dropped, because the queue was full or they failed to be stored"
+    )
+    .unwrap();
+}
+
+/// What was served for a single paid query.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryStats {
+    pub deployment: DeploymentId,
+    pub allocation_id: Address,
+    pub timestamp_ns: u64,
+    pub nonce: u64,
+    pub value: u128,
+    pub response_bytes: u64,
+    pub execution_time: Option<Duration>,
+    /// Price multiplier of the sender that paid for the query.
+    pub price_multiplier: f64,
+    /// GraphQL query and variables, if the request was a GraphQL request.
+    pub query: Option<String>,
+    pub variables: Option<String>,
+}
+
+/// A paid query sampled from the stored stats, to be priced again.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SampledQuery {
+    pub query: String,
+    /// Variables as JSON, `None` if the request had none.
+    pub variables: Option<String>,
+    pub value: GrtWei,
+    pub price_multiplier: f64,
+}
+
+/// GraphQL query and variables of a `{"query": ..., "variables": ...}` request, `None` for other
+/// requests.
+pub fn graphql_query(request: &Value) -> (Option<String>, Option<String>) {
+    let Some(query) = request.get("query").and_then(Value::as_str) else {
+        return (None, None);
+    };
+    let variables = request
+        .get("variables")
+        .filter(|variables| !variables.is_null())
+        .map(Value::to_string);
+    (Some(query.to_string()), variables)
+}
+
+/// Aggregated query stats of a deployment.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeploymentQueryStats {
+    pub deployment: DeploymentId,
+    pub queries: u64,
+    /// Sum of the receipts' values, in GRT wei.
+    pub total_value: String,
+    pub total_response_bytes: u64,
+    pub avg_response_bytes: f64,
+    /// Average over the queries whose execution time is known, `None` if there is none.
+    pub avg_execution_time_ms: Option<f64>,
+}
+
+/// Records the stats of the paid queries in the background, see the module docs.
+#[derive(Clone)]
+pub struct QueryStatsRecorder {
+    queue: mpsc::Sender<QueryStats>,
+}
+
+impl QueryStatsRecorder {
+    /// Starts storing the recorded stats into `pgpool`, pruning the ones older than `retention`.
+    pub fn spawn(pgpool: PgPool, retention: Duration) -> Self {
+        let (queue, receiver) = mpsc::channel(QUEUE_CAPACITY);
+        tokio::spawn(store_queued(pgpool.clone(), receiver));
+        tokio::spawn(prune_periodically(pgpool, retention));
+        Self { queue }
+    }
+
+    /// Queues the stats of a paid query to be stored, dropping them if the queue is full.
+    pub fn record(&self, stats: QueryStats) {
+        match self.queue.try_send(stats) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => DROPPED_QUERY_STATS.inc(),
+            Err(TrySendError::Closed(_)) => {
+                DROPPED_QUERY_STATS.inc();
+                warn!("The query stats are no longer stored");
+            }
+        }
+    }
+}
+
+/// Stores the queued stats in batches, until the recorders are dropped.
+async fn store_queued(pgpool: PgPool, mut receiver: mpsc::Receiver<QueryStats>) {
+    let mut flush = interval_at(Instant::now() + FLUSH_INTERVAL, FLUSH_INTERVAL);
+    flush.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut batch = Vec::with_capacity(MAX_BATCH_SIZE);
+    loop {
+        tokio::select! {
+            received = receiver.recv() => match received {
+                Some(stats) => {
+                    batch.push(stats);
+                    if batch.len() < MAX_BATCH_SIZE {
+                        continue;
+                    }
+                }
+                None => {
+                    store_batch(&pgpool, &mut batch).await;
+                    return;
+                }
+            },
+            _ = flush.tick() => {}
+        }
+        store_batch(&pgpool, &mut batch).await;
+    }
+}
+
+async fn store_batch(pgpool: &PgPool, batch: &mut Vec<QueryStats>) {
+    if batch.is_empty() {
+        return;
+    }
+    if let Err(e) = store(pgpool, batch).await {
+        warn!(
+            queries = batch.len(),
+            "Failed to store the query stats of paid queries: {}", e
+        );
+        DROPPED_QUERY_STATS.inc_by(batch.len() as u64);
+    }
+    batch.clear();
+}
+
+async fn prune_periodically(pgpool: PgPool, retention: Duration) {
+    let mut prune_interval = interval(PRUNE_INTERVAL);
+    prune_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        prune_interval.tick().await;
+        if let Err(e) = prune(&pgpool, retention).await {
+            warn!("Failed to prune the query stats: {}", e);
+        }
+    }
+}
+
+/// Stores the stats of paid queries in a single insert.
+pub async fn store(pgpool: &PgPool, stats: &[QueryStats]) -> Result<()> {
+    let rows = stats
+        .iter()
+        .map(|stats| {
+            Ok((
+                stats,
+                i64::try_from(stats.response_bytes)?,
+                stats
+                    .execution_time
+                    .map(|execution_time| i64::try_from(execution_time.as_millis()))
+                    .transpose()?,
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let mut query = QueryBuilder::<Postgres>::new(
+        r#"
+            INSERT INTO scalar_tap_receipt_query_stats (
+                deployment_id,
+                allocation_id,
+                timestamp_ns,
+                nonce,
+                value,
+                response_bytes,
+                execution_time_ms,
+                price_multiplier,
+                query,
+                variables
+            )
+        "#,
+    );
+    query.push_values(
+        rows,
+        |mut row, (stats, response_bytes, execution_time_ms)| {
+            row.push_bind(stats.deployment.to_string())
+                .push_bind(AllocationIdHex(stats.allocation_id))
+                .push_bind(BigDecimal::from(stats.timestamp_ns))
+                .push_bind(BigDecimal::from(stats.nonce))
+                .push_bind(GrtWei(stats.value))
+                .push_bind(response_bytes)
+                .push_bind(execution_time_ms)
+                .push_bind(stats.price_multiplier)
+                .push_bind(stats.query.clone())
+                .push_bind(stats.variables.clone());
+        },
+    );
+    query.build().execute(pgpool).await?;
+
+    Ok(())
+}
+
+/// Deletes the stats of the queries served longer than `retention` ago, returning how many.
+pub async fn prune(pgpool: &PgPool, retention: Duration) -> Result<u64> {
+    let deleted = sqlx::query(
+        r#"
+            DELETE FROM scalar_tap_receipt_query_stats
+            WHERE created_at < NOW() - make_interval(secs => $1)
+        "#,
+    )
+    .bind(retention.as_secs_f64())
+    .execute(pgpool)
+    .await?
+    .rows_affected();
+    Ok(deleted)
+}
+
+/// Query stats aggregated per deployment, over the queries served within the `window` ending
+/// at `as_of` if set, or over all the queries served until then otherwise. `as_of` defaults to
+/// now, an earlier time reports the stats as they were then.
+pub async fn per_deployment(
+    pgpool: &PgPool,
+    window: Option<Duration>,
+    as_of: Option<SystemTime>,
+) -> Result<Vec<DeploymentQueryStats>> {
+    let as_of = as_of
+        .map(|as_of| as_of.duration_since(UNIX_EPOCH).map(|t| t.as_secs_f64()))
+        .transpose()?;
+    let rows = sqlx::query(
+        r#"
+            SELECT
+                deployment_id,
+                COUNT(*) AS queries,
+                SUM(value) AS total_value,
+                SUM(response_bytes)::BIGINT AS total_response_bytes,
+                AVG(response_bytes)::DOUBLE PRECISION AS avg_response_bytes,
+                AVG(execution_time_ms)::DOUBLE PRECISION AS avg_execution_time_ms
+            FROM scalar_tap_receipt_query_stats
+            WHERE created_at <= COALESCE(to_timestamp($2::DOUBLE PRECISION), NOW())
+                AND (
+                    $1::DOUBLE PRECISION IS NULL
+                    OR created_at >= COALESCE(to_timestamp($2), NOW()) - make_interval(secs => $1)
+                )
+            GROUP BY deployment_id
+            ORDER BY deployment_id
+        "#,
+    )
+    .bind(window.map(|window| window.as_secs_f64()))
+    .bind(as_of)
+    .fetch_all(pgpool)
+    .await?;
+
+    rows.iter()
+        .map(|row| {
+            Ok(DeploymentQueryStats {
+                deployment: DeploymentId::from_str(row.try_get("deployment_id")?)?,
+                queries: row.try_get::<i64, _>("queries")?.try_into()?,
+                total_value: row.try_get::<BigDecimal, _>("total_value")?.to_string(),
+                total_response_bytes: row.try_get::<i64, _>("total_response_bytes")?.try_into()?,
+                avg_response_bytes: row.try_get("avg_response_bytes")?,
+                avg_execution_time_ms: row.try_get("avg_execution_time_ms")?,
+            })
+        })
+        .collect()
+}
+
+/// The `limit` most recent paid GraphQL queries of `deployment`.
+pub async fn recent_queries(
+    pgpool: &PgPool,
+    deployment: &DeploymentId,
+    limit: u32,
+) -> Result<Vec<SampledQuery>> {
+    let rows = sqlx::query(
+        r#"
+            SELECT query, variables, value, price_multiplier
+            FROM scalar_tap_receipt_query_stats
+            WHERE deployment_id = $1 AND query IS NOT NULL
+            ORDER BY id DESC
+            LIMIT $2
+        "#,
+    )
+    .bind(deployment.to_string())
+    .bind(i64::from(limit))
+    .fetch_all(pgpool)
+    .await?;
+
+    rows.iter()
+        .map(|row| {
+            Ok(SampledQuery {
+                query: row.try_get("query")?,
+                variables: row.try_get("variables")?,
+                value: row.try_get("value")?,
+                price_multiplier: row.try_get("price_multiplier")?,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_vectors::NETWORK_SUBGRAPH_DEPLOYMENT;
+
+    use super::*;
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_query_stats_per_deployment(pgpool: PgPool) {
+        let deployment_0 =
+            DeploymentId::from_str("QmAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA").unwrap();
+        let deployment_1 = *NETWORK_SUBGRAPH_DEPLOYMENT;
+        let stats =
+            |deployment, nonce, response_bytes, execution_time_ms: Option<u64>| QueryStats {
+                deployment,
+                allocation_id: Address::from([0x01u8; 20]),
+                timestamp_ns: 1,
+                nonce,
+                value: 10,
+                response_bytes,
+                execution_time: execution_time_ms.map(Duration::from_millis),
+                price_multiplier: 1.0,
+                query: None,
+                variables: None,
+            };
+
+        store(
+            &pgpool,
+            &[
+                stats(deployment_0, 1, 100, Some(10)),
+                stats(deployment_0, 2, 300, None),
+                stats(deployment_1, 3, 50, Some(4)),
+            ],
+        )
+        .await
+        .unwrap();
+
+        let mut aggregates = per_deployment(&pgpool, None, None).await.unwrap();
+        aggregates.sort_by_key(|stats| stats.deployment != deployment_0);
+        assert_eq!(
+            aggregates,
+            vec![
+                DeploymentQueryStats {
+                    deployment: deployment_0,
+                    queries: 2,
+                    total_value: "20".to_string(),
+                    total_response_bytes: 400,
+                    avg_response_bytes: 200.0,
+                    avg_execution_time_ms: Some(10.0),
+                },
+                DeploymentQueryStats {
+                    deployment: deployment_1,
+                    queries: 1,
+                    total_value: "10".to_string(),
+                    total_response_bytes: 50,
+                    avg_response_bytes: 50.0,
+                    avg_execution_time_ms: Some(4.0),
+                },
+            ]
+        );
+
+        assert!(per_deployment(&pgpool, Some(Duration::ZERO), None)
+            .await
+            .unwrap()
+            .is_empty());
+
+        // None served yet an hour ago, all of them within the hour
+        let hour = Duration::from_secs(3600);
+        let an_hour_ago = SystemTime::now() - hour;
+        assert!(per_deployment(&pgpool, None, Some(an_hour_ago))
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            per_deployment(&pgpool, Some(hour), Some(SystemTime::now() + hour))
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+
+        // Served within the retention, then pruned once older
+        assert_eq!(prune(&pgpool, hour).await.unwrap(), 0);
+        sqlx::query(
+            "UPDATE scalar_tap_receipt_query_stats SET created_at = NOW() - INTERVAL '2 hours' \
+             WHERE nonce = 1",
+        )
+        .execute(&pgpool)
+        .await
+        .unwrap();
+        assert_eq!(prune(&pgpool, hour).await.unwrap(), 1);
+        assert_eq!(
+            per_deployment(&pgpool, None, None).await.unwrap()[0].queries,
+            1
+        );
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_recorder(pgpool: PgPool) {
+        let recorder = QueryStatsRecorder::spawn(pgpool.clone(), RETENTION);
+        for nonce in 0..3 {
+            recorder.record(QueryStats {
+                deployment: *NETWORK_SUBGRAPH_DEPLOYMENT,
+                allocation_id: Address::from([0x01u8; 20]),
+                timestamp_ns: 1,
+                nonce,
+                value: 10,
+                response_bytes: 100,
+                execution_time: None,
+                price_multiplier: 1.0,
+                query: None,
+                variables: None,
+            });
+        }
+        // Stored along with the next flush
+        loop {
+            let stats = per_deployment(&pgpool, None, None).await.unwrap();
+            if stats.first().is_some_and(|stats| stats.queries == 3) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_recent_queries(pgpool: PgPool) {
+        let deployment = *NETWORK_SUBGRAPH_DEPLOYMENT;
+        let request = serde_json::json!({
+            "query": "query($id: ID!) { token(id: $id) { id } }",
+            "variables": { "id": "0x01" },
+        });
+        let (query, variables) = graphql_query(&request);
+        assert_eq!(variables.as_deref(), Some(r#"{"id":"0x01"}"#));
+        assert_eq!(
+            graphql_query(&serde_json::json!({ "method": "eth_call" })),
+            (None, None)
+        );
+
+        for (nonce, query) in [(1, None), (2, query.clone()), (3, query.clone())] {
+            store(
+                &pgpool,
+                &[QueryStats {
+                    deployment,
+                    allocation_id: Address::from([0x01u8; 20]),
+                    timestamp_ns: 1,
+                    nonce,
+                    value: 10 * nonce as u128,
+                    response_bytes: 100,
+                    execution_time: None,
+                    price_multiplier: 1.5,
+                    query,
+                    variables: variables.clone(),
+                }],
+            )
+            .await
+            .unwrap();
+        }
+
+        let sampled = recent_queries(&pgpool, &deployment, 10).await.unwrap();
+        assert_eq!(
+            sampled.iter().map(|query| query.value).collect::<Vec<_>>(),
+            vec![GrtWei(30), GrtWei(20)]
+        );
+        assert_eq!(sampled[0].query, query.unwrap());
+        assert_eq!(sampled[0].variables, variables);
+        assert_eq!(sampled[0].price_multiplier, 1.5);
+        assert_eq!(
+            recent_queries(&pgpool, &deployment, 1).await.unwrap().len(),
+            1
+        );
+    }
+}
@@ -0,0 +1,95 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Readiness of the binaries, for container orchestrators. `GET /ready` answers 503 until the
+//! initial syncs, e.g. of the indexer's allocations and of the escrow accounts, have completed,
+//! so that no traffic is routed to an instance that would reject every receipt meanwhile.
+
+use std::collections::BTreeSet;
+use std::sync::{Arc, RwLock};
+
+use axum::{http::StatusCode, routing::get, Extension, Json, Router};
+use eventuals::{Eventual, Value};
+use serde_json::{json, Value as JsonValue};
+use tracing::info;
+
+#[derive(Clone, Debug, Default)]
+pub struct Readiness {
+    pending: Arc<RwLock<BTreeSet<&'static str>>>,
+}
+
+impl Readiness {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Unready until `eventual` has its first value.
+    pub fn wait_for<T: Value>(&self, sync: &'static str, eventual: Eventual<T>) {
+        self.pending.write().unwrap().insert(sync);
+        let pending = self.pending.clone();
+        tokio::spawn(async move {
+            // A closed eventual will never sync, the binary stays unready
+            if eventual.value().await.is_ok() {
+                info!(sync, "Initial sync completed");
+                pending.write().unwrap().remove(sync);
+            }
+        });
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.pending.read().unwrap().is_empty()
+    }
+
+    /// Initial syncs not completed yet.
+    pub fn pending(&self) -> Vec<&'static str> {
+        self.pending.read().unwrap().iter().copied().collect()
+    }
+
+    pub fn router<S>(&self) -> Router<S>
+    where
+        S: Clone + Send + Sync + 'static,
+    {
+        Router::new()
+            .route("/ready", get(ready))
+            .layer(Extension(self.clone()))
+    }
+}
+
+async fn ready(Extension(readiness): Extension<Readiness>) -> (StatusCode, Json<JsonValue>) {
+    let pending = readiness.pending();
+    let status = if pending.is_empty() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(json!({ "pendingSyncs": pending })))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use eventuals::Eventual;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_ready_after_initial_syncs() {
+        let readiness = Readiness::new();
+        assert!(readiness.is_ready());
+
+        let (mut allocations_writer, allocations) = Eventual::<u64>::new();
+        let (mut escrow_writer, escrow_accounts) = Eventual::<u64>::new();
+        readiness.wait_for("allocations", allocations);
+        readiness.wait_for("escrow_accounts", escrow_accounts);
+        assert_eq!(readiness.pending(), vec!["allocations", "escrow_accounts"]);
+
+        allocations_writer.write(1);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(readiness.pending(), vec!["escrow_accounts"]);
+
+        escrow_writer.write(1);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(readiness.is_ready());
+    }
+}
@@ -0,0 +1,370 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Direct notification of the new receipts from the indexer-service to the tap-agent, through a
+//! unix socket, when both run on the same host. It spares the receipts the round trip through
+//! the PG NOTIFY infrastructure of the database, and the database the notifications.
+//!
+//! The tap-agent [listen]s on the socket, the indexer-service connects to it once
+//! [configure]d. The receipts stored while connected are written in transactions that
+//! [skip_pg_notify], and are sent as newline-delimited JSON once committed, with the same
+//! [payload](crate::notifications) as the PG NOTIFY. While the tap-agent isn't listening, or when a send fails, the
+//! receipts are notified with PG NOTIFY instead, so that none is left unnotified.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use arc_swap::ArcSwapOption;
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgConnection, PgPool};
+use thegraph::types::Address;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{mpsc, Mutex};
+use tracing::{debug, info, warn};
+
+use crate::notifications::Notification;
+
+/// PG NOTIFY channel of the new receipts.
+pub const PG_NOTIFY_CHANNEL: &str = "scalar_tap_receipt_notification";
+
+/// Delay between the attempts to connect to a socket the tap-agent isn't listening on.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+/// How long a send waits for the tap-agent to read the notifications, not to hold the paid
+/// queries up when it's stuck.
+const SEND_TIMEOUT: Duration = Duration::from_millis(100);
+/// Notifications read from the socket waiting to be handled by the tap-agent.
+const LISTENER_CAPACITY: usize = 10_000;
+
+lazy_static! {
+    static ref NOTIFIER: ArcSwapOption<ReceiptNotifier> = ArcSwapOption::empty();
+    static ref RECEIPT_NOTIFICATIONS: IntCounterVec = register_int_counter_vec!(
+        "indexer_receipt_notifications_total",
+        "Receipts notified to the tap-agent, by channel",
+        &["channel"]
+    )
+    .unwrap();
+}
+
+/// A new receipt, as notified by the `scalar_tap_receipt_notify` trigger.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReceiptNotification {
+    pub id: u64,
+    pub allocation_id: Address,
+    pub signer_address: Address,
+    pub timestamp_ns: u64,
+    pub value: u128,
+    /// Indexer owning the receipt, see [crate::tenant].
+    pub indexer_address: Option<Address>,
+}
+
+/// Payload of the triggers predating the versioning, without an `indexer_address` before the
+/// tenant isolation.
+#[derive(Deserialize)]
+struct ReceiptNotificationV0 {
+    id: u64,
+    allocation_id: Address,
+    signer_address: Address,
+    timestamp_ns: u64,
+    value: u128,
+    #[serde(default)]
+    indexer_address: Option<Address>,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct ReceiptNotificationV1 {
+    version: u32,
+    id: u64,
+    allocation_id: Address,
+    signer_address: Address,
+    timestamp_ns: u64,
+    value: u128,
+    indexer_address: Option<Address>,
+}
+
+impl Notification for ReceiptNotification {
+    const CHANNEL: &'static str = PG_NOTIFY_CHANNEL;
+    const VERSION: u32 = 1;
+
+    fn read(version: u32, payload: &str) -> serde_json::Result<Self> {
+        Ok(match version {
+            0 => {
+                let v0: ReceiptNotificationV0 = serde_json::from_str(payload)?;
+                Self {
+                    id: v0.id,
+                    allocation_id: v0.allocation_id,
+                    signer_address: v0.signer_address,
+                    timestamp_ns: v0.timestamp_ns,
+                    value: v0.value,
+                    indexer_address: v0.indexer_address,
+                }
+            }
+            _ => {
+                let v1: ReceiptNotificationV1 = serde_json::from_str(payload)?;
+                Self {
+                    id: v1.id,
+                    allocation_id: v1.allocation_id,
+                    signer_address: v1.signer_address,
+                    timestamp_ns: v1.timestamp_ns,
+                    value: v1.value,
+                    indexer_address: v1.indexer_address,
+                }
+            }
+        })
+    }
+
+    fn write(&self) -> serde_json::Result<String> {
+        serde_json::to_string(&ReceiptNotificationV1 {
+            version: Self::VERSION,
+            id: self.id,
+            allocation_id: self.allocation_id,
+            signer_address: self.signer_address,
+            timestamp_ns: self.timestamp_ns,
+            value: self.value,
+            indexer_address: self.indexer_address,
+        })
+    }
+}
+
+/// Connection of the indexer-service to the socket of the tap-agent.
+pub struct ReceiptNotifier {
+    socket_path: PathBuf,
+    stream: Mutex<Option<UnixStream>>,
+    last_attempt: Mutex<Option<Instant>>,
+}
+
+impl ReceiptNotifier {
+    fn new(socket_path: PathBuf) -> Self {
+        Self {
+            socket_path,
+            stream: Mutex::new(None),
+            last_attempt: Mutex::new(None),
+        }
+    }
+
+    /// Whether the tap-agent is listening, connecting to it if not connected yet.
+    pub async fn is_connected(&self) -> bool {
+        let mut stream = self.stream.lock().await;
+        if stream.is_some() {
+            return true;
+        }
+
+        let mut last_attempt = self.last_attempt.lock().await;
+        if last_attempt.is_some_and(|last_attempt| last_attempt.elapsed() < RECONNECT_INTERVAL) {
+            return false;
+        }
+        *last_attempt = Some(Instant::now());
+        match UnixStream::connect(&self.socket_path).await {
+            Ok(connected) => {
+                info!(
+                    socket = %self.socket_path.display(),
+                    "Notifying the tap-agent of the new receipts through its socket"
+                );
+                *stream = Some(connected);
+                true
+            }
+            Err(e) => {
+                debug!(
+                    socket = %self.socket_path.display(),
+                    "The tap-agent isn't listening for receipt notifications: {}", e
+                );
+                false
+            }
+        }
+    }
+
+    /// Sends the notifications, disconnecting on failure.
+    async fn send(&self, notifications: &[ReceiptNotification]) -> Result<()> {
+        let mut lines = Vec::new();
+        for notification in notifications {
+            lines.extend(notification.write()?.into_bytes());
+            lines.push(b'\n');
+        }
+
+        let mut stream = self.stream.lock().await;
+        let connected = stream
+            .as_mut()
+            .ok_or_else(|| anyhow!("Not connected to the tap-agent"))?;
+        let result = match tokio::time::timeout(SEND_TIMEOUT, connected.write_all(&lines)).await {
+            Ok(result) => result.map_err(Into::into),
+            Err(_) => Err(anyhow!("Timed out writing to the tap-agent")),
+        };
+        if result.is_err() {
+            // A partial line was maybe written, the tap-agent drops it with the connection
+            *stream = None;
+        }
+        result
+    }
+
+    /// Sends the notifications of receipts stored in a transaction that [skip_pg_notify], or
+    /// notifies them with PG NOTIFY if they can't be sent.
+    pub async fn notify(&self, pgpool: &PgPool, notifications: &[ReceiptNotification]) {
+        if notifications.is_empty() {
+            return;
+        }
+        let Err(e) = self.send(notifications).await else {
+            RECEIPT_NOTIFICATIONS
+                .with_label_values(&["direct"])
+                .inc_by(notifications.len() as u64);
+            return;
+        };
+        warn!(
+            receipts = notifications.len(),
+            "Failed to notify the tap-agent of new receipts, notifying them with PG NOTIFY: {}", e
+        );
+        if let Err(e) = pg_notify(pgpool, notifications).await {
+            // The tap-agent accounts for them once it reloads the receipts of the allocation
+            warn!(
+                receipts = notifications.len(),
+                "Failed to notify the tap-agent of new receipts: {}", e
+            );
+            return;
+        }
+        RECEIPT_NOTIFICATIONS
+            .with_label_values(&["pg_notify"])
+            .inc_by(notifications.len() as u64);
+    }
+}
+
+/// Notifies the tap-agent of the new receipts through `socket_path` from now on.
+pub fn configure(socket_path: PathBuf) {
+    NOTIFIER.store(Some(Arc::new(ReceiptNotifier::new(socket_path))));
+}
+
+/// The connection to the tap-agent, if [configure]d.
+pub fn notifier() -> Option<Arc<ReceiptNotifier>> {
+    NOTIFIER.load_full()
+}
+
+/// Keeps the `scalar_tap_receipt_notify` trigger from notifying the receipts stored by the
+/// current transaction, notified through the socket instead.
+pub async fn skip_pg_notify(connection: &mut PgConnection) -> Result<()> {
+    sqlx::query("SELECT set_config('indexer.receipt_notification', 'direct', true)")
+        .execute(connection)
+        .await?;
+    Ok(())
+}
+
+async fn pg_notify(pgpool: &PgPool, notifications: &[ReceiptNotification]) -> Result<()> {
+    let payloads = notifications
+        .iter()
+        .map(ReceiptNotification::write)
+        .collect::<Result<Vec<_>, _>>()?;
+    sqlx::query("SELECT pg_notify($1, payload) FROM UNNEST($2::TEXT[]) AS payload")
+        .bind(PG_NOTIFY_CHANNEL)
+        .bind(payloads)
+        .execute(pgpool)
+        .await?;
+    Ok(())
+}
+
+/// Listens on `socket_path` for the notifications of the indexer-services, replacing the
+/// socket left over by a previous run. Returns the JSON payloads, as notified by PG NOTIFY.
+pub fn listen(socket_path: &Path) -> Result<mpsc::Receiver<String>> {
+    match std::fs::remove_file(socket_path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    let listener = UnixListener::bind(socket_path)?;
+    info!(
+        socket = %socket_path.display(),
+        "Listening for receipt notifications"
+    );
+
+    let (sender, receiver) = mpsc::channel(LISTENER_CAPACITY);
+    tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!("Failed to accept a receipt notifications connection: {}", e);
+                    continue;
+                }
+            };
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                let mut lines = BufReader::new(stream).lines();
+                loop {
+                    match lines.next_line().await {
+                        Ok(Some(line)) => {
+                            if sender.send(line).await.is_err() {
+                                return;
+                            }
+                        }
+                        Ok(None) => return,
+                        Err(e) => {
+                            warn!("Failed to read receipt notifications: {}", e);
+                            return;
+                        }
+                    }
+                }
+            });
+            if sender.is_closed() {
+                return;
+            }
+        }
+    });
+    Ok(receiver)
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::postgres::PgListener;
+
+    use super::*;
+
+    fn notification(id: u64) -> ReceiptNotification {
+        ReceiptNotification {
+            id,
+            allocation_id: Address::from([0x03u8; 20]),
+            signer_address: Address::from([0x01u8; 20]),
+            timestamp_ns: 1,
+            value: 10,
+            indexer_address: None,
+        }
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_notify(pgpool: PgPool) {
+        let socket_path = std::env::temp_dir().join(format!(
+            "indexer-receipt-notifications-{}.sock",
+            std::process::id()
+        ));
+        let notifier = ReceiptNotifier::new(socket_path.clone());
+        let mut pglistener = PgListener::connect_with(&pgpool).await.unwrap();
+        pglistener.listen(PG_NOTIFY_CHANNEL).await.unwrap();
+
+        // Not listening yet, notified with PG NOTIFY
+        assert!(!notifier.is_connected().await);
+        notifier.notify(&pgpool, &[notification(1)]).await;
+        let payload = pglistener.recv().await.unwrap();
+        assert_eq!(payload.payload(), notification(1).write().unwrap());
+
+        let mut receiver = listen(&socket_path).unwrap();
+        tokio::time::sleep(RECONNECT_INTERVAL).await;
+        assert!(notifier.is_connected().await);
+        notifier
+            .notify(&pgpool, &[notification(2), notification(3)])
+            .await;
+        assert_eq!(
+            receiver.recv().await.unwrap(),
+            notification(2).write().unwrap()
+        );
+        assert_eq!(
+            receiver.recv().await.unwrap(),
+            notification(3).write().unwrap()
+        );
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), pglistener.recv())
+                .await
+                .is_err()
+        );
+
+        let _ = std::fs::remove_file(&socket_path);
+    }
+}
@@ -0,0 +1,199 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Breakdown of the time spent serving a request, for the callers debugging slow queries.
+//!
+//! The phases are timed within a [`scope`] around the request. Phases timed deeper in the call
+//! stack, e.g. the receipt insert, are recorded with [`record`], which does nothing outside of a
+//! scope, so the requests not asking for the breakdown don't pay for it.
+
+use std::cell::RefCell;
+use std::fmt::Write;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+/// Request header enabling the breakdown, with the free query auth token as value.
+pub const DEBUG_TIMING_HEADER: &str = "x-debug-timing";
+
+tokio::task_local! {
+    static TIMING: RefCell<RequestTiming>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// Checking the receipt, without storing it.
+    ReceiptVerification,
+    /// Storing the receipt, or queueing it.
+    DatabaseWrite,
+    /// Processing the request, by graph-node for subgraphs.
+    Execution,
+    AttestationSigning,
+}
+
+impl Phase {
+    fn name(&self) -> &'static str {
+        match self {
+            Phase::ReceiptVerification => "receipt",
+            Phase::DatabaseWrite => "db",
+            Phase::Execution => "execution",
+            Phase::AttestationSigning => "attestation",
+        }
+    }
+
+    fn description(&self) -> &'static str {
+        match self {
+            Phase::ReceiptVerification => "Receipt verification",
+            Phase::DatabaseWrite => "Database write",
+            Phase::Execution => "Execution",
+            Phase::AttestationSigning => "Attestation signing",
+        }
+    }
+}
+
+/// Time spent in each phase, in milliseconds.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RequestTiming {
+    pub receipt_verification_ms: f64,
+    pub database_write_ms: f64,
+    pub execution_ms: f64,
+    pub attestation_signing_ms: f64,
+    pub total_ms: f64,
+}
+
+impl RequestTiming {
+    fn add(&mut self, phase: Phase, ms: f64) {
+        match phase {
+            Phase::ReceiptVerification => self.receipt_verification_ms += ms,
+            Phase::DatabaseWrite => self.database_write_ms += ms,
+            Phase::Execution => self.execution_ms += ms,
+            Phase::AttestationSigning => self.attestation_signing_ms += ms,
+        }
+    }
+
+    fn get(&self, phase: Phase) -> f64 {
+        match phase {
+            Phase::ReceiptVerification => self.receipt_verification_ms,
+            Phase::DatabaseWrite => self.database_write_ms,
+            Phase::Execution => self.execution_ms,
+            Phase::AttestationSigning => self.attestation_signing_ms,
+        }
+    }
+
+    /// Value of the `Server-Timing` response header.
+    pub fn server_timing(&self) -> String {
+        let mut value = String::new();
+        for phase in [
+            Phase::ReceiptVerification,
+            Phase::DatabaseWrite,
+            Phase::Execution,
+            Phase::AttestationSigning,
+        ] {
+            let _ = write!(
+                value,
+                "{};dur={:.3};desc=\"{}\", ",
+                phase.name(),
+                self.get(phase),
+                phase.description()
+            );
+        }
+        let _ = write!(value, "total;dur={:.3}", self.total_ms);
+        value
+    }
+}
+
+/// Runs `future` timing its phases, returning the breakdown along with its output.
+pub async fn scope<F: Future>(future: F) -> (F::Output, RequestTiming) {
+    let started = Instant::now();
+    let (output, timing) = TIMING
+        .scope(RefCell::new(RequestTiming::default()), async {
+            let output = future.await;
+            (output, TIMING.with(|timing| timing.borrow().clone()))
+        })
+        .await;
+    (
+        output,
+        RequestTiming {
+            total_ms: started.elapsed().as_secs_f64() * 1000.0,
+            ..timing
+        },
+    )
+}
+
+/// Records `duration` spent in `phase`, if the current request is timed.
+pub fn record(phase: Phase, duration: Duration) {
+    let _ = TIMING.try_with(|timing| {
+        timing
+            .borrow_mut()
+            .add(phase, duration.as_secs_f64() * 1000.0)
+    });
+}
+
+/// Awaits `future`, recording the time spent in `phase`.
+pub async fn timed<F: Future>(phase: Phase, future: F) -> F::Output {
+    let started = Instant::now();
+    let output = future.await;
+    record(phase, started.elapsed());
+    output
+}
+
+/// Awaits `future`, recording the time spent in `phase`, apart from the time `future` spent in
+/// the `nested` phase.
+pub async fn timed_excluding<F: Future>(phase: Phase, nested: Phase, future: F) -> F::Output {
+    let started = Instant::now();
+    let nested_before = elapsed_ms(nested);
+    let output = future.await;
+    let nested_ms = elapsed_ms(nested) - nested_before;
+    let _ = TIMING.try_with(|timing| {
+        timing.borrow_mut().add(
+            phase,
+            (started.elapsed().as_secs_f64() * 1000.0 - nested_ms).max(0.0),
+        )
+    });
+    output
+}
+
+/// Milliseconds spent in `phase` so far, zero outside of a scope.
+fn elapsed_ms(phase: Phase) -> f64 {
+    TIMING
+        .try_with(|timing| timing.borrow().get(phase))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_scope() {
+        // Ignored outside of a scope
+        record(Phase::Execution, Duration::from_secs(1));
+        assert_eq!(elapsed_ms(Phase::Execution), 0.0);
+
+        let (output, timing) = scope(async {
+            record(Phase::DatabaseWrite, Duration::from_millis(2));
+            timed(Phase::Execution, async {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            })
+            .await;
+            timed_excluding(Phase::ReceiptVerification, Phase::DatabaseWrite, async {
+                record(Phase::DatabaseWrite, Duration::from_millis(1));
+            })
+            .await;
+            elapsed_ms(Phase::DatabaseWrite)
+        })
+        .await;
+        assert!((output - 3.0).abs() < 1e-9);
+        assert_eq!(timing.database_write_ms, output);
+        assert!(timing.execution_ms >= 5.0);
+        assert!(timing.total_ms >= timing.execution_ms);
+        // The nested database write isn't counted twice
+        assert!(timing.receipt_verification_ms < 1.0);
+
+        let server_timing = timing.server_timing();
+        assert!(server_timing.starts_with("receipt;dur="));
+        assert!(server_timing.contains("db;dur=3.000;desc=\"Database write\", "));
+    }
+}
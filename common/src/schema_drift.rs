@@ -0,0 +1,442 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Detection of the drift of the database schema from the one the migrations create, for the
+//! databases shared with other tools, where a column type can be altered or an index dropped
+//! without the migration version telling. The shape of the tables holding the receipts, the
+//! RAVs and the state of the unaggregated fees is checked on startup and periodically, see
+//! [start]. While it drifted, the writes to these tables are refused, see [ensure_writable],
+//! rather than risking them being silently truncated or duplicated, until the schema is fixed.
+
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::sync::RwLock;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
+use prometheus::{register_int_gauge, IntGauge};
+use serde::Serialize;
+use sqlx::{PgPool, Row};
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+
+/// How often the schema is checked after startup.
+pub const SCHEMA_CHECK_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Column of a table holding funds, with its type as formatted by Postgres' `format_type`.
+struct ExpectedColumn {
+    table: &'static str,
+    column: &'static str,
+    data_type: &'static str,
+    not_null: bool,
+}
+
+/// Index a table holding funds relies on, for its uniqueness or for the tap-agent's queries.
+struct ExpectedIndex {
+    table: &'static str,
+    columns: &'static [&'static str],
+    unique: bool,
+}
+
+const fn column(
+    table: &'static str,
+    column: &'static str,
+    data_type: &'static str,
+) -> ExpectedColumn {
+    ExpectedColumn {
+        table,
+        column,
+        data_type,
+        not_null: true,
+    }
+}
+
+const EXPECTED_COLUMNS: &[ExpectedColumn] = &[
+    column("scalar_tap_receipts", "id", "bigint"),
+    column("scalar_tap_receipts", "signer_address", "character(40)"),
+    column("scalar_tap_receipts", "signature", "bytea"),
+    column("scalar_tap_receipts", "allocation_id", "character(40)"),
+    column("scalar_tap_receipts", "timestamp_ns", "numeric(20,0)"),
+    column("scalar_tap_receipts", "nonce", "numeric(20,0)"),
+    column("scalar_tap_receipts", "value", "numeric(39,0)"),
+    ExpectedColumn {
+        not_null: false,
+        ..column("scalar_tap_receipts", "request_id", "text")
+    },
+    column("scalar_tap_ravs", "sender_address", "character(40)"),
+    column("scalar_tap_ravs", "signature", "bytea"),
+    column("scalar_tap_ravs", "allocation_id", "character(40)"),
+    column("scalar_tap_ravs", "timestamp_ns", "numeric(20,0)"),
+    column("scalar_tap_ravs", "value_aggregate", "numeric(39,0)"),
+    column("scalar_tap_ravs", "last", "boolean"),
+    column("scalar_tap_ravs", "final", "boolean"),
+    column(
+        "scalar_tap_agent_allocation_checkpoints",
+        "sender_address",
+        "character(40)",
+    ),
+    column(
+        "scalar_tap_agent_allocation_checkpoints",
+        "allocation_id",
+        "character(40)",
+    ),
+    column(
+        "scalar_tap_agent_allocation_checkpoints",
+        "last_receipt_id",
+        "bigint",
+    ),
+    column(
+        "scalar_tap_agent_allocation_checkpoints",
+        "unaggregated_value",
+        "numeric(39,0)",
+    ),
+    column("scalar_tap_denylist", "sender_address", "character(40)"),
+];
+
+const EXPECTED_INDEXES: &[ExpectedIndex] = &[
+    ExpectedIndex {
+        table: "scalar_tap_receipts",
+        columns: &["id"],
+        unique: true,
+    },
+    ExpectedIndex {
+        table: "scalar_tap_receipts",
+        columns: &["allocation_id"],
+        unique: false,
+    },
+    ExpectedIndex {
+        table: "scalar_tap_receipts",
+        columns: &["timestamp_ns"],
+        unique: false,
+    },
+    ExpectedIndex {
+        table: "scalar_tap_ravs",
+        columns: &["allocation_id", "sender_address"],
+        unique: true,
+    },
+    ExpectedIndex {
+        table: "scalar_tap_agent_allocation_checkpoints",
+        columns: &["sender_address", "allocation_id"],
+        unique: true,
+    },
+    ExpectedIndex {
+        table: "scalar_tap_denylist",
+        columns: &["sender_address"],
+        unique: true,
+    },
+];
+
+lazy_static! {
+    static ref SCHEMA_DRIFTS: IntGauge = register_int_gauge!(
+        "indexer_schema_drifts",
+        "Differences between the database schema and the expected one, the writes of funds \
+        being refused while there are any"
+    )
+    .unwrap();
+    /// Drifts found by the last check.
+    static ref DRIFTS: RwLock<Vec<Drift>> = RwLock::new(Vec::new());
+}
+
+/// A difference between the database schema and the one created by the migrations.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum Drift {
+    MissingTable {
+        table: String,
+    },
+    MissingColumn {
+        table: String,
+        column: String,
+    },
+    ColumnType {
+        table: String,
+        column: String,
+        expected: String,
+        found: String,
+    },
+    NullableColumn {
+        table: String,
+        column: String,
+    },
+    /// No index on exactly these columns, or one that isn't unique.
+    MissingIndex {
+        table: String,
+        columns: Vec<String>,
+        unique: bool,
+    },
+}
+
+impl Display for Drift {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Drift::MissingTable { table } => write!(f, "table `{table}` is missing"),
+            Drift::MissingColumn { table, column } => {
+                write!(f, "column `{table}.{column}` is missing")
+            }
+            Drift::ColumnType {
+                table,
+                column,
+                expected,
+                found,
+            } => write!(
+                f,
+                "column `{table}.{column}` is of type `{found}` instead of `{expected}`"
+            ),
+            Drift::NullableColumn { table, column } => {
+                write!(f, "column `{table}.{column}` lost its NOT NULL constraint")
+            }
+            Drift::MissingIndex {
+                table,
+                columns,
+                unique,
+            } => write!(
+                f,
+                "{}index of `{table}` on ({}) is missing",
+                if *unique { "unique " } else { "" },
+                columns.join(", ")
+            ),
+        }
+    }
+}
+
+/// Compares the schema of the tables holding funds with the expected one.
+pub async fn detect(pgpool: &PgPool) -> Result<Vec<Drift>, sqlx::Error> {
+    let mut tables: Vec<&str> = EXPECTED_COLUMNS.iter().map(|c| c.table).collect();
+    tables.dedup();
+
+    let rows = sqlx::query(
+        r#"
+            SELECT
+                c.relname::TEXT AS table_name,
+                a.attname::TEXT AS column_name,
+                format_type(a.atttypid, a.atttypmod) AS data_type,
+                a.attnotnull AS not_null
+            FROM pg_class c
+            JOIN pg_namespace n ON n.oid = c.relnamespace
+            JOIN pg_attribute a ON a.attrelid = c.oid AND a.attnum > 0 AND NOT a.attisdropped
+            WHERE n.nspname = current_schema()
+                AND c.relkind IN ('r', 'p')
+                AND c.relname = ANY($1)
+        "#,
+    )
+    .bind(&tables)
+    .fetch_all(pgpool)
+    .await?;
+    let mut columns: HashMap<(String, String), (String, bool)> = HashMap::new();
+    for row in rows {
+        columns.insert(
+            (row.try_get("table_name")?, row.try_get("column_name")?),
+            (row.try_get("data_type")?, row.try_get("not_null")?),
+        );
+    }
+
+    let rows = sqlx::query(
+        r#"
+            SELECT
+                t.relname::TEXT AS table_name,
+                ix.indisunique AS is_unique,
+                ARRAY(
+                    SELECT a.attname::TEXT
+                    FROM unnest(ix.indkey) WITH ORDINALITY AS k(attnum, position)
+                    JOIN pg_attribute a ON a.attrelid = t.oid AND a.attnum = k.attnum
+                    ORDER BY k.position
+                ) AS columns
+            FROM pg_index ix
+            JOIN pg_class t ON t.oid = ix.indrelid
+            JOIN pg_namespace n ON n.oid = t.relnamespace
+            WHERE n.nspname = current_schema()
+                AND ix.indisvalid
+                AND ix.indpred IS NULL
+                AND t.relname = ANY($1)
+        "#,
+    )
+    .bind(&tables)
+    .fetch_all(pgpool)
+    .await?;
+    let mut indexes: Vec<(String, Vec<String>, bool)> = Vec::new();
+    for row in rows {
+        indexes.push((
+            row.try_get("table_name")?,
+            row.try_get("columns")?,
+            row.try_get("is_unique")?,
+        ));
+    }
+
+    let mut drifts = Vec::new();
+    for table in &tables {
+        if !columns.keys().any(|(name, _)| name == table) {
+            drifts.push(Drift::MissingTable {
+                table: table.to_string(),
+            });
+        }
+    }
+    for expected in EXPECTED_COLUMNS {
+        // Already reported with its table
+        if drifts.contains(&Drift::MissingTable {
+            table: expected.table.to_string(),
+        }) {
+            continue;
+        }
+        let key = (expected.table.to_string(), expected.column.to_string());
+        match columns.get(&key) {
+            None => drifts.push(Drift::MissingColumn {
+                table: key.0,
+                column: key.1,
+            }),
+            Some((data_type, _)) if data_type != expected.data_type => {
+                drifts.push(Drift::ColumnType {
+                    table: key.0,
+                    column: key.1,
+                    expected: expected.data_type.to_string(),
+                    found: data_type.clone(),
+                })
+            }
+            Some((_, not_null)) if expected.not_null && !not_null => {
+                drifts.push(Drift::NullableColumn {
+                    table: key.0,
+                    column: key.1,
+                })
+            }
+            Some(_) => {}
+        }
+    }
+    for expected in EXPECTED_INDEXES {
+        if drifts.contains(&Drift::MissingTable {
+            table: expected.table.to_string(),
+        }) {
+            continue;
+        }
+        let found = indexes.iter().any(|(table, columns, unique)| {
+            table == expected.table && columns == expected.columns && (*unique || !expected.unique)
+        });
+        if !found {
+            drifts.push(Drift::MissingIndex {
+                table: expected.table.to_string(),
+                columns: expected.columns.iter().map(ToString::to_string).collect(),
+                unique: expected.unique,
+            });
+        }
+    }
+    Ok(drifts)
+}
+
+/// Checks the schema, refusing the writes of funds from now on if it drifted, or allowing them
+/// again if it was fixed.
+pub async fn check(pgpool: &PgPool) -> Result<Vec<Drift>, sqlx::Error> {
+    let drifts = detect(pgpool).await?;
+    let previous = std::mem::replace(&mut *DRIFTS.write().unwrap(), drifts.clone());
+    SCHEMA_DRIFTS.set(drifts.len() as i64);
+    if drifts != previous {
+        if drifts.is_empty() {
+            info!("The database schema is back to the expected one, accepting the writes of funds");
+        } else {
+            error!(
+                report = %report(&drifts),
+                "The database schema drifted from the expected one, refusing the writes of funds \
+                until it's fixed"
+            );
+        }
+    }
+    Ok(drifts)
+}
+
+/// Drifts found by the last check.
+pub fn drifts() -> Vec<Drift> {
+    DRIFTS.read().unwrap().clone()
+}
+
+/// Fails while the schema of the tables holding funds drifted, before writing to them.
+pub fn ensure_writable() -> Result<()> {
+    let drifts = DRIFTS.read().unwrap();
+    if drifts.is_empty() {
+        return Ok(());
+    }
+    Err(anyhow!(
+        "The write was refused, the database schema drifted: {}",
+        report(&drifts)
+    ))
+}
+
+fn report(drifts: &[Drift]) -> String {
+    drifts
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+/// Checks the schema before returning, so before the first write, then every `interval`.
+pub async fn start(pgpool: PgPool, interval: Duration) -> JoinHandle<()> {
+    if let Err(e) = check(&pgpool).await {
+        warn!("Failed to check the database schema: {}", e);
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        // Checked right above
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            if let Err(e) = check(&pgpool).await {
+                warn!("Failed to check the database schema: {}", e);
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_detect(pgpool: PgPool) {
+        assert_eq!(detect(&pgpool).await.unwrap(), vec![]);
+
+        sqlx::query("DROP INDEX scalar_tap_receipts_timestamp_ns_idx")
+            .execute(&pgpool)
+            .await
+            .unwrap();
+        sqlx::query("ALTER TABLE scalar_tap_ravs ALTER COLUMN value_aggregate TYPE NUMERIC(20)")
+            .execute(&pgpool)
+            .await
+            .unwrap();
+        sqlx::query("ALTER TABLE scalar_tap_receipts ALTER COLUMN nonce DROP NOT NULL")
+            .execute(&pgpool)
+            .await
+            .unwrap();
+        sqlx::query("DROP TABLE scalar_tap_denylist CASCADE")
+            .execute(&pgpool)
+            .await
+            .unwrap();
+
+        let drifts = detect(&pgpool).await.unwrap();
+        assert_eq!(
+            drifts,
+            vec![
+                Drift::MissingTable {
+                    table: "scalar_tap_denylist".to_string()
+                },
+                Drift::NullableColumn {
+                    table: "scalar_tap_receipts".to_string(),
+                    column: "nonce".to_string(),
+                },
+                Drift::ColumnType {
+                    table: "scalar_tap_ravs".to_string(),
+                    column: "value_aggregate".to_string(),
+                    expected: "numeric(39,0)".to_string(),
+                    found: "numeric(20,0)".to_string(),
+                },
+                Drift::MissingIndex {
+                    table: "scalar_tap_receipts".to_string(),
+                    columns: vec!["timestamp_ns".to_string()],
+                    unique: false,
+                },
+            ]
+        );
+        assert_eq!(
+            drifts[2].to_string(),
+            "column `scalar_tap_ravs.value_aggregate` is of type `numeric(20,0)` instead of \
+            `numeric(39,0)`"
+        );
+    }
+}
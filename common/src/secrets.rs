@@ -0,0 +1,285 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Protection of the secrets at rest, in the memory of the processes and in the database.
+//!
+//! # Threat model
+//!
+//! - [SealedSecret] keeps a secret, e.g. the operator mnemonic once the startup derived what it
+//!   needed from it, masked with a key drawn at random by the process. It keeps the secret out
+//!   of the core dumps, swapped out pages and heap snapshots scanned for mnemonics or keys, and
+//!   out of the logs through its `Debug` implementation, and zeroes it once dropped. It does
+//!   *not* protect against an attacker reading the whole memory of the running process, or
+//!   attaching a debugger to it: the masking key is in the same memory, and the secret is in
+//!   the clear while [SealedSecret::expose] runs.
+//! - The database key encrypts the sensitive blobs stored in the database, e.g. the failed RAV
+//!   requests holding the responses of the gateways, with `pgp_sym_encrypt` from pgcrypto. It
+//!   protects them in the backups, dumps and replicas of the database, and from the roles
+//!   that can read the tables but don't have the key. The key is sent to the database with the
+//!   queries as a bind parameter, so it does *not* protect against the database server itself,
+//!   nor against logging the parameters of the statements (`log_statement`,
+//!   `auto_explain.log_parameter_max_length`), which should be off on the databases relying on
+//!   it. The key is supplied by a [KeyProvider], e.g. from the environment or by decrypting it
+//!   with a KMS, and never written to the configuration files.
+
+use std::process::Command;
+use std::sync::atomic::{compiler_fence, Ordering};
+use std::sync::Arc;
+
+use alloy_primitives::keccak256;
+use anyhow::{anyhow, bail, Result};
+use arc_swap::ArcSwapOption;
+use async_trait::async_trait;
+use ethers_core::rand::{thread_rng, RngCore};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Deserializer};
+
+lazy_static! {
+    /// Masking key of the [SealedSecret]s, for the lifetime of the process.
+    static ref PROCESS_KEY: [u8; 32] = {
+        let mut key = [0u8; 32];
+        thread_rng().fill_bytes(&mut key);
+        key
+    };
+    static ref DATABASE_KEY: ArcSwapOption<SealedSecret> = ArcSwapOption::empty();
+}
+
+/// Overwrites `bytes` in a way the compiler can't elide as a dead store.
+fn zeroize(bytes: &mut [u8]) {
+    for byte in bytes.iter_mut() {
+        // SAFETY: `byte` is a valid, aligned and exclusive reference.
+        unsafe { std::ptr::write_volatile(byte, 0) };
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
+/// XORs `bytes` with the keystream of `nonce`, masking or unmasking them.
+fn apply_keystream(nonce: &[u8; 32], bytes: &mut [u8]) {
+    for (counter, chunk) in bytes.chunks_mut(32).enumerate() {
+        let mut block = [0u8; 72];
+        block[..32].copy_from_slice(&*PROCESS_KEY);
+        block[32..64].copy_from_slice(nonce);
+        block[64..].copy_from_slice(&(counter as u64).to_be_bytes());
+        let mut keystream = keccak256(block).0;
+        for (byte, mask) in chunk.iter_mut().zip(keystream.iter()) {
+            *byte ^= mask;
+        }
+        zeroize(&mut block);
+        zeroize(&mut keystream);
+    }
+}
+
+/// Secret kept masked in memory, see the [module documentation](self) for what it does and
+/// doesn't protect against.
+pub struct SealedSecret {
+    nonce: [u8; 32],
+    masked: Box<[u8]>,
+}
+
+impl SealedSecret {
+    /// Masks `secret`, zeroing the string it was given in.
+    pub fn new(secret: String) -> Self {
+        let mut nonce = [0u8; 32];
+        thread_rng().fill_bytes(&mut nonce);
+        let mut bytes = secret.into_bytes();
+        let mut masked = bytes.clone().into_boxed_slice();
+        zeroize(&mut bytes);
+        apply_keystream(&nonce, &mut masked);
+        Self { nonce, masked }
+    }
+
+    /// Calls `f` with the secret in the clear, zeroing it once `f` returns. Whatever `f` derives
+    /// from the secret should be as short lived.
+    pub fn expose<T>(&self, f: impl FnOnce(&str) -> T) -> T {
+        let mut bytes = self.masked.to_vec();
+        apply_keystream(&self.nonce, &mut bytes);
+        // Only ever masked from a `String`
+        let result = f(std::str::from_utf8(&bytes).expect("the secret is valid UTF-8"));
+        zeroize(&mut bytes);
+        result
+    }
+}
+
+impl Clone for SealedSecret {
+    fn clone(&self) -> Self {
+        Self {
+            nonce: self.nonce,
+            masked: self.masked.clone(),
+        }
+    }
+}
+
+impl Default for SealedSecret {
+    fn default() -> Self {
+        Self::new(String::new())
+    }
+}
+
+impl std::fmt::Debug for SealedSecret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SealedSecret(..)")
+    }
+}
+
+impl<'de> Deserialize<'de> for SealedSecret {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        String::deserialize(deserializer).map(Self::new)
+    }
+}
+
+impl Drop for SealedSecret {
+    fn drop(&mut self) {
+        zeroize(&mut self.masked);
+    }
+}
+
+/// Supplies the key encrypting the sensitive blobs stored in the database. Implemented by the
+/// sources of [KeySource], and by the deployments fetching the key from elsewhere.
+#[async_trait]
+pub trait KeyProvider: Send + Sync {
+    async fn key(&self) -> Result<SealedSecret>;
+}
+
+/// Reads the key from an environment variable.
+pub struct EnvKeyProvider {
+    pub variable: String,
+}
+
+#[async_trait]
+impl KeyProvider for EnvKeyProvider {
+    async fn key(&self) -> Result<SealedSecret> {
+        let key = std::env::var(&self.variable)
+            .map_err(|e| anyhow!("Failed to read the key from `{}`: {}", self.variable, e))?;
+        if key.is_empty() {
+            bail!("The key in `{}` is empty", self.variable);
+        }
+        Ok(SealedSecret::new(key))
+    }
+}
+
+/// Reads the key from the output of a command, e.g. decrypting it with a KMS.
+pub struct CommandKeyProvider {
+    pub command: Vec<String>,
+}
+
+#[async_trait]
+impl KeyProvider for CommandKeyProvider {
+    async fn key(&self) -> Result<SealedSecret> {
+        let Some((program, args)) = self.command.split_first() else {
+            bail!("The key command is empty");
+        };
+        let program = program.clone();
+        let args = args.to_vec();
+        let output = tokio::task::spawn_blocking(move || Command::new(program).args(args).output())
+            .await?
+            .map_err(|e| anyhow!("Failed to run the key command: {}", e))?;
+        if !output.status.success() {
+            bail!("The key command failed with {}", output.status);
+        }
+        let mut stdout = output.stdout;
+        let key = String::from_utf8(stdout.clone())
+            .map_err(|_| anyhow!("The key command printed a key that isn't UTF-8"));
+        zeroize(&mut stdout);
+        let mut key = key?;
+        let trimmed = key.trim_end_matches(['\r', '\n']).to_string();
+        // SAFETY: zeroes aren't a broken UTF-8 sequence.
+        zeroize(unsafe { key.as_bytes_mut() });
+        if trimmed.is_empty() {
+            bail!("The key command printed an empty key");
+        }
+        Ok(SealedSecret::new(trimmed))
+    }
+}
+
+/// Where the database key comes from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KeySource {
+    Env { variable: String },
+    Command { command: Vec<String> },
+}
+
+impl KeySource {
+    pub fn provider(&self) -> Box<dyn KeyProvider> {
+        match self {
+            KeySource::Env { variable } => Box::new(EnvKeyProvider {
+                variable: variable.clone(),
+            }),
+            KeySource::Command { command } => Box::new(CommandKeyProvider {
+                command: command.clone(),
+            }),
+        }
+    }
+}
+
+/// Encrypts the sensitive blobs stored from now on with the key of `provider`.
+pub async fn set_database_key(provider: &dyn KeyProvider) -> Result<()> {
+    DATABASE_KEY.store(Some(Arc::new(provider.key().await?)));
+    Ok(())
+}
+
+/// Key to bind to the `pgp_sym_encrypt` and `pgp_sym_decrypt` calls of the queries, if the
+/// sensitive blobs are encrypted. The blobs are stored and read in the clear otherwise.
+pub fn database_key() -> Option<String> {
+    DATABASE_KEY
+        .load()
+        .as_ref()
+        .map(|key| key.expose(str::to_string))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sealed_secret() {
+        let mnemonic = "celery smart tip orange scare van steel radio dragon joy alarm crane";
+        let sealed = SealedSecret::new(mnemonic.to_string());
+
+        assert_ne!(&*sealed.masked, mnemonic.as_bytes());
+        assert!(!format!("{:?}", sealed).contains("celery"));
+        assert_eq!(sealed.expose(str::to_string), mnemonic);
+        assert_eq!(sealed.clone().expose(str::len), mnemonic.len());
+
+        // Masked differently every time
+        let other = SealedSecret::new(mnemonic.to_string());
+        assert_ne!(sealed.masked, other.masked);
+        assert_eq!(other.expose(str::to_string), mnemonic);
+    }
+
+    #[tokio::test]
+    async fn test_key_providers() {
+        std::env::set_var("INDEXER_TEST_DATABASE_KEY", "env key");
+        let key = KeySource::Env {
+            variable: "INDEXER_TEST_DATABASE_KEY".to_string(),
+        }
+        .provider()
+        .key()
+        .await
+        .unwrap();
+        assert_eq!(key.expose(str::to_string), "env key");
+
+        let key = KeySource::Command {
+            command: vec!["echo".to_string(), "command key".to_string()],
+        }
+        .provider()
+        .key()
+        .await
+        .unwrap();
+        assert_eq!(key.expose(str::to_string), "command key");
+
+        assert!(KeySource::Env {
+            variable: "INDEXER_TEST_MISSING_DATABASE_KEY".to_string(),
+        }
+        .provider()
+        .key()
+        .await
+        .is_err());
+        assert!(KeySource::Command {
+            command: vec!["false".to_string()],
+        }
+        .provider()
+        .key()
+        .await
+        .is_err());
+    }
+}
@@ -0,0 +1,547 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Service level objectives of the paid queries, tracked in-process so that the operators get
+//! actionable alerts without external SLO tooling. Each paid query is an event of the objectives
+//! of its indicators, good or bad: its valid receipts stored or not, and once they are, the query
+//! served within the latency threshold or not. The events are counted in buckets of
+//! [BUCKET_SECS] seconds, over the longest window of the alerts.
+//!
+//! An objective burns its error budget, 1 less its target, at the ratio of bad events over a
+//! window divided by the budget: at a burn rate of 1, the budget is used up right at the end of
+//! the objective's period. An alert fires when the burn rate exceeds its threshold over both its
+//! long and its short window, and resolves once it no longer does, notifying the webhook each
+//! time. The `indexer_slo_*` metrics export the compliance and the burn rates of the objectives
+//! over the windows of the alerts, and the alerts firing.
+//!
+//! Tracking is disabled until [start] is called.
+
+use std::collections::{HashSet, VecDeque};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+use prometheus::{
+    register_gauge_vec, register_int_counter_vec, register_int_gauge_vec, GaugeVec, IntCounterVec,
+    IntGaugeVec,
+};
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+
+/// Width of the buckets counting the events, in seconds.
+const BUCKET_SECS: u64 = 10;
+
+lazy_static! {
+    static ref SLO_EVENTS: IntCounterVec = register_int_counter_vec!(
+        "indexer_slo_events",
+        "Events of the service level objectives, by objective and outcome",
+        &["objective", "outcome"]
+    )
+    .unwrap();
+    static ref SLO_COMPLIANCE: GaugeVec = register_gauge_vec!(
+        "indexer_slo_compliance",
+        "Ratio of good events of the service level objectives, by objective and window",
+        &["objective", "window"]
+    )
+    .unwrap();
+    static ref SLO_BURN_RATE: GaugeVec = register_gauge_vec!(
+        "indexer_slo_burn_rate",
+        "Rate at which the service level objectives burn their error budget, by objective and \
+        window",
+        &["objective", "window"]
+    )
+    .unwrap();
+    static ref SLO_ALERT: IntGaugeVec = register_int_gauge_vec!(
+        "indexer_slo_alert",
+        "Whether a burn rate alert of a service level objective is firing, by objective and \
+        severity",
+        &["objective", "severity"]
+    )
+    .unwrap();
+}
+
+static TRACKER: OnceLock<Tracker> = OnceLock::new();
+
+/// Objectives tracked, and when they alert.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SloSettings {
+    pub objectives: Vec<Objective>,
+    pub alerts: Vec<BurnRateAlert>,
+    /// Events in the long window of an alert before it can fire.
+    pub min_events: u64,
+    pub evaluation_interval: Duration,
+    /// Notified with a JSON body when an alert fires or resolves, if set.
+    pub webhook_url: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Objective {
+    pub name: String,
+    pub indicator: Indicator,
+    /// Ratio of good events.
+    pub target: f64,
+}
+
+/// What the events of an objective are.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Indicator {
+    /// Paid queries whose receipts were accepted, good if served within `threshold`.
+    PaidQueryLatency { threshold: Duration },
+    /// Paid queries whose receipts were valid, good if they were stored. The receipts rejected
+    /// as invalid are not events, so that a misbehaving sender can't burn the error budget.
+    ReceiptAcceptance,
+}
+
+/// Fires when an objective burns its error budget faster than `burn_rate` over both windows.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BurnRateAlert {
+    pub severity: String,
+    pub long_window: Duration,
+    pub short_window: Duration,
+    pub burn_rate: f64,
+}
+
+/// Tracks the objectives of `settings` from now on, evaluating their alerts every
+/// `evaluation_interval` and notifying the webhook through `http_client`.
+pub fn start(settings: SloSettings, http_client: reqwest::Client) -> JoinHandle<()> {
+    info!(
+        objectives = settings.objectives.len(),
+        alerts = settings.alerts.len(),
+        "Tracking the service level objectives"
+    );
+    let tracker = TRACKER.get_or_init(|| Tracker::new(settings));
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(tracker.settings.evaluation_interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let mut firing = HashSet::new();
+        loop {
+            interval.tick().await;
+            for notification in tracker.evaluate(tracker.now_secs(), &mut firing) {
+                match notification.status {
+                    AlertStatus::Firing => error!(
+                        objective = %notification.objective,
+                        severity = %notification.severity,
+                        long_window_burn_rate = notification.long_window_burn_rate,
+                        short_window_burn_rate = notification.short_window_burn_rate,
+                        "Service level objective burning its error budget too fast"
+                    ),
+                    AlertStatus::Resolved => info!(
+                        objective = %notification.objective,
+                        severity = %notification.severity,
+                        "Service level objective no longer burning its error budget too fast"
+                    ),
+                }
+                if let Some(webhook_url) = tracker.settings.webhook_url.clone() {
+                    tokio::spawn(notify(http_client.clone(), webhook_url, notification));
+                }
+            }
+        }
+    })
+}
+
+/// Records whether the valid receipts of a paid query were accepted, or failed to be on the
+/// indexer's side.
+pub fn record_receipt_acceptance(accepted: bool) {
+    record(|indicator| match indicator {
+        Indicator::ReceiptAcceptance => Some(accepted),
+        _ => None,
+    });
+}
+
+/// Records a paid query whose receipts were accepted, served or failed after `latency`.
+pub fn record_paid_query(served: bool, latency: Duration) {
+    record(|indicator| match indicator {
+        Indicator::PaidQueryLatency { threshold } => Some(served && latency <= *threshold),
+        _ => None,
+    });
+}
+
+fn record(outcome: impl Fn(&Indicator) -> Option<bool>) {
+    if let Some(tracker) = TRACKER.get() {
+        tracker.record(outcome);
+    }
+}
+
+/// Times a paid query from its start. Once its receipts are [accepted](Self::accepted), it's
+/// recorded when dropped, served if [served](Self::served) was called, failed otherwise, e.g.
+/// when the handler returns early with an error.
+pub struct PaidQueryTimer {
+    started: Instant,
+    accepted: bool,
+    served: bool,
+}
+
+impl PaidQueryTimer {
+    pub fn start() -> Self {
+        Self {
+            started: Instant::now(),
+            accepted: false,
+            served: false,
+        }
+    }
+
+    pub fn accepted(&mut self) {
+        self.accepted = true;
+    }
+
+    pub fn served(&mut self) {
+        self.served = true;
+    }
+}
+
+impl Drop for PaidQueryTimer {
+    fn drop(&mut self) {
+        if self.accepted {
+            record_paid_query(self.served, self.started.elapsed());
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Counts {
+    good: u64,
+    bad: u64,
+}
+
+impl Counts {
+    fn total(&self) -> u64 {
+        self.good + self.bad
+    }
+
+    /// Ratio of bad events over the error budget of `target`, 0 without any event.
+    fn burn_rate(&self, target: f64) -> f64 {
+        if self.total() == 0 {
+            return 0.0;
+        }
+        (self.bad as f64 / self.total() as f64) / (1.0 - target)
+    }
+}
+
+/// Events of an objective, by bucket of [BUCKET_SECS] since the start of the tracker.
+#[derive(Debug, Default)]
+struct Events {
+    buckets: VecDeque<(u64, Counts)>,
+}
+
+impl Events {
+    fn record(&mut self, now_secs: u64, good: bool) {
+        let bucket = now_secs / BUCKET_SECS;
+        let counts = match self.buckets.back_mut() {
+            // Recorded after a later event by a concurrent request
+            Some((last, counts)) if *last >= bucket => counts,
+            _ => {
+                self.buckets.push_back((bucket, Counts::default()));
+                &mut self.buckets.back_mut().unwrap().1
+            }
+        };
+        if good {
+            counts.good += 1;
+        } else {
+            counts.bad += 1;
+        }
+    }
+
+    /// Counts of the events within `window` of `now_secs`, along with the rest of the bucket
+    /// the window starts in.
+    fn counts(&self, now_secs: u64, window: Duration) -> Counts {
+        let since = now_secs.saturating_sub(window.as_secs()) / BUCKET_SECS;
+        self.buckets
+            .iter()
+            .rev()
+            .take_while(|(bucket, _)| *bucket >= since)
+            .fold(Counts::default(), |total, (_, counts)| Counts {
+                good: total.good + counts.good,
+                bad: total.bad + counts.bad,
+            })
+    }
+
+    /// Forgets the events out of `window` of `now_secs`.
+    fn prune(&mut self, now_secs: u64, window: Duration) {
+        let since = now_secs.saturating_sub(window.as_secs()) / BUCKET_SECS;
+        while self
+            .buckets
+            .front()
+            .is_some_and(|(bucket, _)| *bucket < since)
+        {
+            self.buckets.pop_front();
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum AlertStatus {
+    Firing,
+    Resolved,
+}
+
+/// Body of the webhook notifications.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AlertNotification {
+    objective: String,
+    severity: String,
+    status: AlertStatus,
+    target: f64,
+    burn_rate_threshold: f64,
+    long_window_secs: u64,
+    long_window_burn_rate: f64,
+    short_window_secs: u64,
+    short_window_burn_rate: f64,
+}
+
+struct Tracker {
+    settings: SloSettings,
+    started: Instant,
+    /// Events of each objective of the settings, in the same order.
+    events: Vec<Mutex<Events>>,
+}
+
+impl Tracker {
+    fn new(settings: SloSettings) -> Self {
+        let events = settings
+            .objectives
+            .iter()
+            .map(|_| Mutex::new(Events::default()))
+            .collect();
+        Self {
+            settings,
+            started: Instant::now(),
+            events,
+        }
+    }
+
+    fn now_secs(&self) -> u64 {
+        self.started.elapsed().as_secs()
+    }
+
+    fn record(&self, outcome: impl Fn(&Indicator) -> Option<bool>) {
+        for (objective, events) in self.settings.objectives.iter().zip(&self.events) {
+            let Some(good) = outcome(&objective.indicator) else {
+                continue;
+            };
+            SLO_EVENTS
+                .with_label_values(&[&objective.name, if good { "good" } else { "bad" }])
+                .inc();
+            // Read within the lock, for the buckets to be recorded in order
+            let mut events = events.lock().unwrap();
+            events.record(self.now_secs(), good);
+        }
+    }
+
+    /// Updates the metrics of the objectives at `now_secs`, and the alerts `firing`, by index of
+    /// objective and index of alert. Returns the alerts that fired or resolved.
+    fn evaluate(
+        &self,
+        now_secs: u64,
+        firing: &mut HashSet<(usize, usize)>,
+    ) -> Vec<AlertNotification> {
+        let longest_window = self
+            .settings
+            .alerts
+            .iter()
+            .map(|alert| alert.long_window)
+            .max()
+            .unwrap_or_default();
+        let mut notifications = Vec::new();
+        for (i, (objective, events)) in self
+            .settings
+            .objectives
+            .iter()
+            .zip(&self.events)
+            .enumerate()
+        {
+            let mut events = events.lock().unwrap();
+            events.prune(now_secs, longest_window);
+            for (j, alert) in self.settings.alerts.iter().enumerate() {
+                let long = events.counts(now_secs, alert.long_window);
+                let short = events.counts(now_secs, alert.short_window);
+                for (window, counts) in [(alert.long_window, long), (alert.short_window, short)] {
+                    let labels = [objective.name.as_str(), &window_label(window)];
+                    SLO_BURN_RATE
+                        .with_label_values(&labels)
+                        .set(counts.burn_rate(objective.target));
+                    SLO_COMPLIANCE
+                        .with_label_values(&labels)
+                        .set(match counts.total() {
+                            0 => 1.0,
+                            total => counts.good as f64 / total as f64,
+                        });
+                }
+
+                let fires = long.total() >= self.settings.min_events
+                    && long.burn_rate(objective.target) > alert.burn_rate
+                    && short.burn_rate(objective.target) > alert.burn_rate;
+                let changed = if fires {
+                    firing.insert((i, j))
+                } else {
+                    firing.remove(&(i, j))
+                };
+                if !changed {
+                    continue;
+                }
+                SLO_ALERT
+                    .with_label_values(&[&objective.name, &alert.severity])
+                    .set(fires.into());
+                notifications.push(AlertNotification {
+                    objective: objective.name.clone(),
+                    severity: alert.severity.clone(),
+                    status: if fires {
+                        AlertStatus::Firing
+                    } else {
+                        AlertStatus::Resolved
+                    },
+                    target: objective.target,
+                    burn_rate_threshold: alert.burn_rate,
+                    long_window_secs: alert.long_window.as_secs(),
+                    long_window_burn_rate: long.burn_rate(objective.target),
+                    short_window_secs: alert.short_window.as_secs(),
+                    short_window_burn_rate: short.burn_rate(objective.target),
+                });
+            }
+        }
+        notifications
+    }
+}
+
+/// Label of a window in the metrics, e.g. `5m` or `6h`.
+fn window_label(window: Duration) -> String {
+    match window.as_secs() {
+        secs if secs % 3600 == 0 => format!("{}h", secs / 3600),
+        secs if secs % 60 == 0 => format!("{}m", secs / 60),
+        secs => format!("{}s", secs),
+    }
+}
+
+async fn notify(
+    http_client: reqwest::Client,
+    webhook_url: String,
+    notification: AlertNotification,
+) {
+    let body = match serde_json::to_string(&notification) {
+        Ok(body) => body,
+        Err(e) => {
+            warn!("Failed to serialize the SLO alert: {}", e);
+            return;
+        }
+    };
+    let result = http_client
+        .post(webhook_url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(body)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status());
+    if let Err(e) = result {
+        warn!("Failed to notify the SLO alert webhook: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(events: &mut Events, now_secs: u64, good: u64, bad: u64) {
+        for _ in 0..good {
+            events.record(now_secs, true);
+        }
+        for _ in 0..bad {
+            events.record(now_secs, false);
+        }
+    }
+
+    #[test]
+    fn test_events() {
+        let mut events = Events::default();
+        record(&mut events, 0, 5, 1);
+        record(&mut events, 65, 3, 0);
+        // Out of order, counted in the last bucket
+        record(&mut events, 55, 0, 2);
+        record(&mut events, 125, 10, 0);
+
+        assert_eq!(
+            events.counts(125, Duration::from_secs(10)),
+            Counts { good: 10, bad: 0 }
+        );
+        assert_eq!(
+            events.counts(125, Duration::from_secs(60)),
+            Counts { good: 13, bad: 2 }
+        );
+        assert_eq!(
+            events.counts(125, Duration::from_secs(3600)),
+            Counts { good: 18, bad: 3 }
+        );
+        assert_eq!(events.buckets.len(), 3);
+
+        events.prune(125, Duration::from_secs(60));
+        assert_eq!(events.buckets.len(), 2);
+        assert_eq!(
+            events.counts(125, Duration::from_secs(3600)),
+            Counts { good: 13, bad: 2 }
+        );
+
+        assert_eq!(Counts { good: 3, bad: 1 }.burn_rate(0.5), 0.5);
+        assert_eq!(Counts { good: 0, bad: 0 }.burn_rate(0.5), 0.0);
+    }
+
+    #[test]
+    fn test_alerts() {
+        let tracker = Tracker::new(SloSettings {
+            objectives: vec![Objective {
+                name: "test_alerts".to_string(),
+                indicator: Indicator::ReceiptAcceptance,
+                target: 0.75,
+            }],
+            alerts: vec![BurnRateAlert {
+                severity: "page".to_string(),
+                long_window: Duration::from_secs(3600),
+                short_window: Duration::from_secs(300),
+                burn_rate: 2.0,
+            }],
+            min_events: 100,
+            evaluation_interval: Duration::from_secs(30),
+            webhook_url: None,
+        });
+        let mut firing = HashSet::new();
+        let alert = || SLO_ALERT.with_label_values(&["test_alerts", "page"]).get();
+
+        // Burning too fast, but too few events
+        record(&mut tracker.events[0].lock().unwrap(), 0, 5, 15);
+        assert!(tracker.evaluate(0, &mut firing).is_empty());
+
+        // Burning too fast over both windows
+        record(&mut tracker.events[0].lock().unwrap(), 600, 40, 60);
+        let notifications = tracker.evaluate(600, &mut firing);
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0].status, AlertStatus::Firing);
+        assert_eq!(notifications[0].long_window_burn_rate, 2.5);
+        assert!(notifications[0].short_window_burn_rate > 2.0);
+        assert_eq!(alert(), 1);
+        assert_eq!(
+            SLO_COMPLIANCE
+                .with_label_values(&["test_alerts", "1h"])
+                .get(),
+            0.375
+        );
+        // Still firing
+        assert!(tracker.evaluate(610, &mut firing).is_empty());
+
+        // Recovered over the short window, while the long one still burns too fast
+        record(&mut tracker.events[0].lock().unwrap(), 1200, 20, 0);
+        let notifications = tracker.evaluate(1200, &mut firing);
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0].status, AlertStatus::Resolved);
+        assert!(notifications[0].long_window_burn_rate > 2.0);
+        assert_eq!(notifications[0].short_window_burn_rate, 0.0);
+        assert_eq!(alert(), 0);
+    }
+
+    #[test]
+    fn test_window_label() {
+        assert_eq!(window_label(Duration::from_secs(300)), "5m");
+        assert_eq!(window_label(Duration::from_secs(6 * 3600)), "6h");
+        assert_eq!(window_label(Duration::from_secs(90)), "90s");
+    }
+}
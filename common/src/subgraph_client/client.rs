@@ -1,6 +1,9 @@
 // Copyright 2023-, GraphOps and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
+use std::time::Instant;
+
+use super::metrics::{ErrorClass, QueryMetrics};
 use super::monitor::{monitor_deployment_status, DeploymentStatus};
 use anyhow::anyhow;
 use axum::body::Bytes;
@@ -120,15 +123,23 @@ impl DeploymentDetails {
     }
 }
 
+/// Label of the metrics of the clients not given a name.
+const UNNAMED_SUBGRAPH: &str = "unnamed";
+
 struct DeploymentClient {
     pub http_client: reqwest::Client,
     pub subgraph_client: Mutex<GraphCoreSubgraphClient>,
     pub status: Option<Eventual<DeploymentStatus>>,
     pub query_url: Url,
+    pub metrics: QueryMetrics,
 }
 
 impl DeploymentClient {
-    pub fn new(http_client: reqwest::Client, details: DeploymentDetails) -> Self {
+    pub fn new(
+        http_client: reqwest::Client,
+        details: DeploymentDetails,
+        source: &'static str,
+    ) -> Self {
         let subgraph_client = Mutex::new(
             GraphCoreSubgraphClient::builder(http_client.clone(), details.query_url.clone())
                 .with_auth_token(details.query_auth_token)
@@ -140,26 +151,39 @@ impl DeploymentClient {
             status: details
                 .deployment
                 .zip(details.status_url)
-                .map(|(deployment, url)| monitor_deployment_status(deployment, url)),
+                .map(|(deployment, url)| {
+                    monitor_deployment_status(http_client.clone(), deployment, url)
+                }),
             query_url: details.query_url,
+            metrics: QueryMetrics {
+                subgraph: UNNAMED_SUBGRAPH,
+                source,
+            },
         }
     }
 
-    pub async fn query<T: for<'de> Deserialize<'de>>(
-        &self,
-        query: impl IntoRequestParameters + Send,
-    ) -> Result<Result<T, String>, anyhow::Error> {
+    async fn check_status(&self, start: Instant) -> Result<(), anyhow::Error> {
         if let Some(ref status) = self.status {
             let deployment_status = status.value().await.expect("reading deployment status");
 
             if !deployment_status.synced || &deployment_status.health != "healthy" {
+                self.metrics.record(start, Err(ErrorClass::Unhealthy));
                 return Err(anyhow!(
                     "Deployment `{}` is not ready or healthy to be queried",
                     self.query_url
                 ));
             }
         }
-        Ok(self
+        Ok(())
+    }
+
+    pub async fn query<T: for<'de> Deserialize<'de>>(
+        &self,
+        query: impl IntoRequestParameters + Send,
+    ) -> Result<Result<T, String>, anyhow::Error> {
+        let start = Instant::now();
+        self.check_status(start).await?;
+        let response = self
             .subgraph_client
             .lock()
             .await
@@ -170,7 +194,12 @@ impl DeploymentClient {
                     "Failed to query subgraph deployment `{}`: {}",
                     self.query_url, err
                 );
-            }))
+            });
+        self.metrics.record(
+            start,
+            response.as_ref().map(|_| ()).map_err(|_| ErrorClass::Query),
+        );
+        Ok(response)
     }
 
     pub async fn paginated_query<T: for<'de> Deserialize<'de>>(
@@ -178,50 +207,54 @@ impl DeploymentClient {
         query: String,
         items_per_page: usize,
     ) -> Result<Vec<T>, anyhow::Error> {
-        if let Some(ref status) = self.status {
-            let deployment_status = status.value().await.expect("reading deployment status");
-
-            if !deployment_status.synced || &deployment_status.health != "healthy" {
-                return Err(anyhow!(
-                    "Deployment `{}` is not ready or healthy to be queried",
-                    self.query_url
-                ));
-            }
-        }
-        self.subgraph_client
+        let start = Instant::now();
+        self.check_status(start).await?;
+        let response = self
+            .subgraph_client
             .lock()
             .await
             .paginated_query::<T>(query, items_per_page)
-            .await
-            .map_err(|err| {
-                warn!(
-                    "Failed to query subgraph deployment `{}`: {}",
-                    self.query_url, err
-                );
-                anyhow!(err)
-            })
+            .await;
+        self.metrics.record(
+            start,
+            response.as_ref().map(|_| ()).map_err(|_| ErrorClass::Query),
+        );
+        response.map_err(|err| {
+            warn!(
+                "Failed to query subgraph deployment `{}`: {}",
+                self.query_url, err
+            );
+            anyhow!(err)
+        })
     }
 
     pub async fn query_raw(&self, body: Bytes) -> Result<reqwest::Response, anyhow::Error> {
-        if let Some(ref status) = self.status {
-            let deployment_status = status.value().await.expect("reading deployment status");
+        let start = Instant::now();
+        self.check_status(start).await?;
 
-            if !deployment_status.synced || &deployment_status.health != "healthy" {
-                return Err(anyhow!(
-                    "Deployment `{}` is not ready or healthy to be queried",
-                    self.query_url
-                ));
-            }
-        }
-
-        Ok(self
+        self.metrics.record_payload("request", body.len() as u64);
+        let response = self
             .http_client
             .post(self.query_url.as_ref())
             .header(header::USER_AGENT, "indexer-common")
             .header(header::CONTENT_TYPE, "application/json")
             .body(body)
             .send()
-            .await?)
+            .await
+            .inspect_err(|err| {
+                self.metrics
+                    .record(start, Err(ErrorClass::of_request_error(err)))
+            })?;
+        if let Some(length) = response.content_length() {
+            self.metrics.record_payload("response", length);
+        }
+        let result = if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(ErrorClass::HttpStatus)
+        };
+        self.metrics.record(start, result);
+        Ok(response)
     }
 }
 
@@ -238,9 +271,19 @@ impl SubgraphClient {
         remote_deployment: DeploymentDetails,
     ) -> Self {
         Self {
-            local_client: local_deployment.map(|d| DeploymentClient::new(http_client.clone(), d)),
-            remote_client: DeploymentClient::new(http_client, remote_deployment),
+            local_client: local_deployment
+                .map(|d| DeploymentClient::new(http_client.clone(), d, "local")),
+            remote_client: DeploymentClient::new(http_client, remote_deployment, "remote"),
+        }
+    }
+
+    /// Names the subgraph in the metrics of its queries, e.g. `network` or `escrow`.
+    pub fn with_name(mut self, name: &'static str) -> Self {
+        if let Some(local_client) = &mut self.local_client {
+            local_client.metrics.subgraph = name;
         }
+        self.remote_client.metrics.subgraph = name;
+        self
     }
 
     pub async fn query<T: for<'de> Deserialize<'de>>(
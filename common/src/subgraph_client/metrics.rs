@@ -0,0 +1,154 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Health of the queries to the subgraphs, labeled by subgraph, e.g. `network` or `escrow`, and
+//! by source, the local graph-node deployment or the remote query URL, so that operators can
+//! tell which upstream receipt rejections come from. The age of the last successful query is
+//! `time() - indexer_subgraph_last_success_timestamp_seconds`.
+
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use lazy_static::lazy_static;
+use prometheus::{
+    exponential_buckets, register_histogram_vec, register_int_counter_vec, register_int_gauge_vec,
+    HistogramVec, IntCounterVec, IntGaugeVec,
+};
+
+lazy_static! {
+    static ref SUBGRAPH_QUERIES: IntCounterVec = register_int_counter_vec!(
+        "indexer_subgraph_queries_total",
+        "Queries to the subgraphs",
+        &["subgraph", "source"]
+    )
+    .unwrap();
+    static ref SUBGRAPH_QUERY_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "indexer_subgraph_query_errors_total",
+        "Failed queries to the subgraphs, by class of error",
+        &["subgraph", "source", "class"]
+    )
+    .unwrap();
+    static ref SUBGRAPH_QUERY_DURATION: HistogramVec = register_histogram_vec!(
+        "indexer_subgraph_query_duration_seconds",
+        "Duration of the queries to the subgraphs",
+        &["subgraph", "source"],
+        vec![0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0]
+    )
+    .unwrap();
+    static ref SUBGRAPH_LAST_SUCCESS: IntGaugeVec = register_int_gauge_vec!(
+        "indexer_subgraph_last_success_timestamp_seconds",
+        "Time of the last successful query to the subgraphs, in seconds since the UNIX epoch",
+        &["subgraph"]
+    )
+    .unwrap();
+    static ref SUBGRAPH_PAYLOAD_SIZE: HistogramVec = register_histogram_vec!(
+        "indexer_subgraph_payload_size_bytes",
+        "Size of the raw queries to the subgraphs, and of their responses",
+        &["subgraph", "direction"],
+        exponential_buckets(256.0, 4.0, 10).unwrap()
+    )
+    .unwrap();
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// The local deployment isn't synced or healthy, and wasn't queried.
+    Unhealthy,
+    Timeout,
+    /// The request didn't get any response.
+    Transport,
+    /// The response has an error status.
+    HttpStatus,
+    /// The response has GraphQL errors, or doesn't have the expected data.
+    Query,
+}
+
+impl ErrorClass {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorClass::Unhealthy => "unhealthy",
+            ErrorClass::Timeout => "timeout",
+            ErrorClass::Transport => "transport",
+            ErrorClass::HttpStatus => "http_status",
+            ErrorClass::Query => "query",
+        }
+    }
+
+    pub fn of_request_error(error: &reqwest::Error) -> Self {
+        if error.is_timeout() {
+            ErrorClass::Timeout
+        } else if error.is_status() {
+            ErrorClass::HttpStatus
+        } else {
+            ErrorClass::Transport
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct QueryMetrics {
+    pub subgraph: &'static str,
+    /// `local` or `remote`.
+    pub source: &'static str,
+}
+
+impl QueryMetrics {
+    /// Records a query started at `start`.
+    pub fn record(&self, start: Instant, result: Result<(), ErrorClass>) {
+        let labels = [self.subgraph, self.source];
+        SUBGRAPH_QUERIES.with_label_values(&labels).inc();
+        SUBGRAPH_QUERY_DURATION
+            .with_label_values(&labels)
+            .observe(start.elapsed().as_secs_f64());
+        match result {
+            Ok(()) => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .expect("Time went backwards")
+                    .as_secs();
+                SUBGRAPH_LAST_SUCCESS
+                    .with_label_values(&[self.subgraph])
+                    .set(now as i64);
+            }
+            Err(class) => SUBGRAPH_QUERY_ERRORS
+                .with_label_values(&[self.subgraph, self.source, class.as_str()])
+                .inc(),
+        }
+    }
+
+    /// Records the size of a request, or of a response, for `direction` `request` or `response`.
+    pub fn record_payload(&self, direction: &'static str, bytes: u64) {
+        SUBGRAPH_PAYLOAD_SIZE
+            .with_label_values(&[self.subgraph, direction])
+            .observe(bytes as f64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let metrics = QueryMetrics {
+            subgraph: "test_record",
+            source: "remote",
+        };
+        metrics.record(Instant::now(), Ok(()));
+        metrics.record(Instant::now(), Err(ErrorClass::Query));
+
+        let labels = ["test_record", "remote"];
+        assert_eq!(SUBGRAPH_QUERIES.with_label_values(&labels).get(), 2);
+        assert_eq!(
+            SUBGRAPH_QUERY_ERRORS
+                .with_label_values(&["test_record", "remote", "query"])
+                .get(),
+            1
+        );
+        assert!(
+            SUBGRAPH_LAST_SUCCESS
+                .with_label_values(&["test_record"])
+                .get()
+                > 0
+        );
+    }
+}
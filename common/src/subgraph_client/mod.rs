@@ -2,6 +2,7 @@
 // SPDX-License-Identifier: Apache-2.0
 
 mod client;
+mod metrics;
 mod monitor;
 
 pub use client::{DeploymentDetails, Query, QueryVariables, SubgraphClient};
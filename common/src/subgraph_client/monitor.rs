@@ -12,10 +12,10 @@ use thegraph_graphql_http::{
     http::request::IntoRequestParameters,
     http_client::{ReqwestExt, ResponseResult},
 };
-use tokio::time::sleep;
 use tracing::warn;
 
 use super::Query;
+use crate::monitor_backoff::Backoff;
 
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -30,19 +30,26 @@ pub struct DeploymentStatus {
 }
 
 async fn query<T: for<'de> Deserialize<'de>>(
+    http_client: &reqwest::Client,
     url: Url,
     query: impl IntoRequestParameters + Send,
 ) -> Result<ResponseResult<T>, anyhow::Error> {
-    Ok(reqwest::Client::new().post(url).send_graphql(query).await?)
+    Ok(http_client.post(url).send_graphql(query).await?)
 }
 
 pub fn monitor_deployment_status(
+    http_client: reqwest::Client,
     deployment: DeploymentId,
     status_url: Url,
 ) -> Eventual<DeploymentStatus> {
-    timer(Duration::from_secs(30)).map_with_retry(
+    let interval = Duration::from_secs(30);
+    let backoff = Backoff::new("deployment_status", interval);
+    let retry_backoff = backoff.clone();
+    timer(interval).map_with_retry(
         move |_| {
+            let http_client = http_client.clone();
             let status_url = status_url.clone();
+            let backoff = backoff.clone();
 
             async move {
                 let body = Query::new_with_variables(
@@ -57,25 +64,25 @@ pub fn monitor_deployment_status(
                     [("ids", json!([deployment.to_string()]))],
                 );
 
-                let response = query::<DeploymentStatusResponse>(status_url, body)
+                let response = query::<DeploymentStatusResponse>(&http_client, status_url, body)
                     .await
                     .map_err(|e| {
                         format!("Failed to query status of deployment `{deployment}`: {e}")
                     })?;
 
-                response.map_err(|e| format!("{e}")).and_then(|data| {
+                backoff.track(response.map_err(|e| format!("{e}")).and_then(|data| {
                     data.indexing_statuses
                         .and_then(|statuses| statuses.first().cloned())
                         .ok_or_else(|| format!("Deployment `{deployment}` not found"))
-                })
+                }))
             }
         },
-        move |err: String| async move {
+        move |err: String| {
             warn!(
                 "Error querying deployment status for `{}`: {}",
                 deployment, err
             );
-            sleep(Duration::from_secs(15)).await
+            retry_backoff.failed(&err)
         },
     )
 }
@@ -116,7 +123,7 @@ mod tests {
             .mount(&mock_server)
             .await;
 
-        let status = monitor_deployment_status(deployment, status_url);
+        let status = monitor_deployment_status(reqwest::Client::new(), deployment, status_url);
 
         assert_eq!(
             status.value().await.unwrap(),
@@ -154,7 +161,7 @@ mod tests {
             .mount(&mock_server)
             .await;
 
-        let status = monitor_deployment_status(deployment, status_url);
+        let status = monitor_deployment_status(reqwest::Client::new(), deployment, status_url);
 
         assert_eq!(
             status.value().await.unwrap(),
@@ -192,7 +199,7 @@ mod tests {
             .mount(&mock_server)
             .await;
 
-        let status = monitor_deployment_status(deployment, status_url);
+        let status = monitor_deployment_status(reqwest::Client::new(), deployment, status_url);
 
         assert_eq!(
             status.value().await.unwrap(),
@@ -230,7 +237,7 @@ mod tests {
             .mount(&mock_server)
             .await;
 
-        let status = monitor_deployment_status(deployment, status_url);
+        let status = monitor_deployment_status(reqwest::Client::new(), deployment, status_url);
 
         assert_eq!(
             status.value().await.unwrap(),
@@ -0,0 +1,227 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Integration with systemd `Type=notify` units, speaking the `sd_notify` protocol over the
+//! socket given in `NOTIFY_SOCKET`. The binaries notify `READY=1` once their initial syncs have
+//! completed, then, if the unit has `WatchdogSec=` set, send watchdog keepalives as long as their
+//! health checks pass, so that systemd restarts a process that got wedged. Nothing is sent when
+//! not running under systemd.
+
+use std::env;
+use std::io;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use sqlx::PgPool;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout};
+use tracing::{info, warn};
+
+use crate::readiness::Readiness;
+
+/// Longest a health check may take, the watchdog interval permitting.
+const MAX_CHECK_DURATION: Duration = Duration::from_secs(10);
+const READINESS_POLLING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Sends `state`, e.g. `READY=1`, to systemd. Returns whether it was sent, i.e. whether the
+/// process runs under a unit expecting notifications.
+pub fn notify(state: &str) -> io::Result<bool> {
+    let Some(socket_path) = env::var_os("NOTIFY_SOCKET") else {
+        return Ok(false);
+    };
+    let socket_path = socket_path.to_string_lossy();
+    let address = match socket_path.strip_prefix('@') {
+        // Abstract socket, Linux only as is systemd
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            SocketAddr::from_abstract_name(name.as_bytes())?
+        }
+        None => SocketAddr::from_pathname(&*socket_path)?,
+    };
+    let socket = UnixDatagram::unbound()?;
+    socket.send_to_addr(state.as_bytes(), &address)?;
+    Ok(true)
+}
+
+/// Interval of the watchdog keepalives, half the `WatchdogSec=` of the unit as recommended by
+/// systemd. `None` if the watchdog isn't enabled for this process.
+pub fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec) / 2)
+}
+
+/// Check of the health of a process, the watchdog being kept alive only while all of them pass.
+#[async_trait]
+pub trait HealthCheck: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    async fn check(&self) -> Result<()>;
+}
+
+/// The database is reachable.
+pub struct DatabaseCheck(pub PgPool);
+
+#[async_trait]
+impl HealthCheck for DatabaseCheck {
+    fn name(&self) -> &'static str {
+        "database"
+    }
+
+    async fn check(&self) -> Result<()> {
+        sqlx::query("SELECT 1").execute(&self.0).await?;
+        Ok(())
+    }
+}
+
+/// The initial syncs have completed. Their eventuals only change with the data they sync, so
+/// they can't tell a stalled sync apart from unchanged data, the syncs report their own errors.
+pub struct ReadinessCheck(pub Readiness);
+
+#[async_trait]
+impl HealthCheck for ReadinessCheck {
+    fn name(&self) -> &'static str {
+        "readiness"
+    }
+
+    async fn check(&self) -> Result<()> {
+        if self.0.is_ready() {
+            Ok(())
+        } else {
+            Err(anyhow!("Pending syncs: {}", self.0.pending().join(", ")))
+        }
+    }
+}
+
+/// The checks that failed, or didn't complete in `max_duration`, with the reason.
+pub async fn failed_checks(
+    checks: &[Arc<dyn HealthCheck>],
+    max_duration: Duration,
+) -> Vec<(&'static str, String)> {
+    let mut failed = Vec::new();
+    for check in checks {
+        match timeout(max_duration, check.check()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => failed.push((check.name(), e.to_string())),
+            Err(_) => failed.push((check.name(), "Timed out".to_string())),
+        }
+    }
+    failed
+}
+
+/// Notifies systemd once `readiness` is reached, then keeps the watchdog alive while all the
+/// `checks` pass.
+pub fn start(readiness: Readiness, checks: Vec<Arc<dyn HealthCheck>>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        while !readiness.is_ready() {
+            sleep(READINESS_POLLING_INTERVAL).await;
+        }
+        match notify("READY=1") {
+            Ok(true) => info!("Notified systemd of the readiness"),
+            Ok(false) => return,
+            Err(e) => warn!("Failed to notify systemd of the readiness: {}", e),
+        }
+
+        let Some(interval) = watchdog_interval() else {
+            return;
+        };
+        info!(?interval, "Keeping the systemd watchdog alive");
+        let max_check_duration = (interval / 2).min(MAX_CHECK_DURATION);
+        loop {
+            let failed = failed_checks(&checks, max_check_duration).await;
+            let state = if failed.is_empty() {
+                "WATCHDOG=1\nSTATUS=Healthy".to_string()
+            } else {
+                // Left for systemd to restart the process once the watchdog times out
+                for (check, error) in &failed {
+                    warn!(check, %error, "Health check failed, not feeding the watchdog");
+                }
+                let failed: Vec<_> = failed.iter().map(|(check, _)| *check).collect();
+                format!("STATUS=Unhealthy: {}", failed.join(", "))
+            };
+            if let Err(e) = notify(&state) {
+                warn!("Failed to notify systemd: {}", e);
+            }
+            sleep(interval).await;
+        }
+    })
+}
+
+/// Tells systemd the process is shutting down.
+pub fn notify_stopping() {
+    if let Err(e) = notify("STOPPING=1") {
+        warn!("Failed to notify systemd of the shutdown: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Failing;
+
+    #[async_trait]
+    impl HealthCheck for Failing {
+        fn name(&self) -> &'static str {
+            "failing"
+        }
+
+        async fn check(&self) -> Result<()> {
+            Err(anyhow!("Broken"))
+        }
+    }
+
+    struct Stuck;
+
+    #[async_trait]
+    impl HealthCheck for Stuck {
+        fn name(&self) -> &'static str {
+            "stuck"
+        }
+
+        async fn check(&self) -> Result<()> {
+            std::future::pending().await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_checks() {
+        let readiness = Readiness::new();
+        let checks: Vec<Arc<dyn HealthCheck>> = vec![
+            Arc::new(ReadinessCheck(readiness)),
+            Arc::new(Failing),
+            Arc::new(Stuck),
+        ];
+        assert_eq!(
+            failed_checks(&checks, Duration::from_millis(50)).await,
+            vec![
+                ("failing", "Broken".to_string()),
+                ("stuck", "Timed out".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_notify() {
+        let dir = env::temp_dir().join(format!("indexer-systemd-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let socket_path = dir.join("notify.sock");
+        let socket = UnixDatagram::bind(&socket_path).unwrap();
+        env::set_var("NOTIFY_SOCKET", &socket_path);
+        assert!(notify("READY=1").unwrap());
+        let mut buffer = [0; 64];
+        let received = socket.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..received], b"READY=1");
+
+        env::remove_var("NOTIFY_SOCKET");
+        assert!(!notify("READY=1").unwrap());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,12 +1,18 @@
 // Copyright 2023-, GraphOps and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
+use crate::allocations::grace_period::GracePeriods;
+use crate::allocations::virtual_allocations::VirtualAllocation;
+use crate::indexer_service::http::ReceiptAcceptanceWindowConfig;
+use crate::tap::checks::acceptance_window_check::AcceptanceWindowCheck;
 use crate::tap::checks::allocation_eligible::AllocationEligible;
 use crate::tap::checks::deny_list_check::DenyListCheck;
 use crate::tap::checks::receipt_max_val_check::ReceiptMaxValueCheck;
+use crate::tap::checks::receipt_min_val_check::ReceiptMinValueCheck;
 use crate::tap::checks::sender_balance_check::SenderBalanceCheck;
+use crate::tap::checks::sender_paused_check::SenderPausedCheck;
 use crate::tap::checks::timestamp_check::TimestampCheck;
-use crate::{escrow_accounts::EscrowAccounts, prelude::Allocation};
+use crate::{escrow_accounts::EscrowAccounts, events::RecordedEvent, prelude::Allocation};
 use alloy_sol_types::Eip712Domain;
 use eventuals::Eventual;
 use sqlx::PgPool;
@@ -14,16 +20,51 @@ use std::fmt::Debug;
 use std::time::Duration;
 use std::{collections::HashMap, sync::Arc};
 use tap_core::receipt::checks::ReceiptCheck;
-use thegraph::types::Address;
+use thegraph::types::{Address, DeploymentId};
+use tokio::sync::broadcast;
 use tracing::error;
 
 mod checks;
+mod domain_diagnostic;
+mod read_only_database;
+mod receipt_payment;
+mod receipt_queue;
 mod receipt_store;
+mod receipt_validation;
+mod sender_pricing;
+mod signer_cache;
+
+pub use checks::deny_list_check::{DenylistNotification, DenylistOperation, DENYLIST_CHANNEL};
+pub use read_only_database::ReadOnlyDatabase;
+pub use receipt_payment::{payment_scope, store_payment};
+pub use receipt_queue::{QueuedReceipt, ReceiptQueue};
+pub use receipt_validation::{CheckOutcome, ReceiptValidation, ReceiptValidator};
+pub use sender_pricing::{apply_multiplier, SenderPricing};
+pub use signer_cache::{recover_signer, SignerRecoveryError};
+
+/// Name of the check rejecting the receipts of allocations that aren't the indexer's, or that
+/// were closed past their grace period.
+pub const ALLOCATION_ELIGIBLE_CHECK: &str = "allocation_eligible";
 
 #[derive(Clone)]
 pub struct IndexerTapContext {
     pgpool: PgPool,
     domain_separator: Arc<Eip712Domain>,
+    /// When set, receipts are stored through the queue instead of directly.
+    receipt_queue: Option<Arc<ReceiptQueue>>,
+    /// When set, receipts are handled by its policy while the database is read only.
+    read_only_database: Option<Arc<ReadOnlyDatabase>>,
+}
+
+/// Bounds on the value of the receipts accepted, all in GRT wei.
+#[derive(Clone)]
+pub struct ReceiptValueLimits {
+    pub max_value: u128,
+    pub min_value: u128,
+    /// Floors of specific deployments, taking precedence over `min_value`.
+    pub min_value_per_deployment: HashMap<DeploymentId, u128>,
+    /// Scales the floors of the senders with a price multiplier.
+    pub sender_pricing: SenderPricing,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -33,30 +74,135 @@ pub enum AdapterError {
 }
 
 impl IndexerTapContext {
+    #[allow(clippy::too_many_arguments)]
     pub async fn get_checks(
         pgpool: PgPool,
         indexer_allocations: Eventual<HashMap<Address, Allocation>>,
+        virtual_allocations: Eventual<HashMap<Address, VirtualAllocation>>,
         escrow_accounts: Eventual<EscrowAccounts>,
         domain_separator: Eip712Domain,
         timestamp_error_tolerance: Duration,
-        receipt_max_value: u128,
+        value_limits: ReceiptValueLimits,
+        grace_periods: GracePeriods,
+        acceptance_window: Option<ReceiptAcceptanceWindowConfig>,
+        events: &broadcast::Sender<RecordedEvent>,
     ) -> Vec<ReceiptCheck> {
-        vec![
-            Arc::new(AllocationEligible::new(indexer_allocations)),
-            Arc::new(SenderBalanceCheck::new(
-                escrow_accounts.clone(),
-                domain_separator.clone(),
-            )),
-            Arc::new(TimestampCheck::new(timestamp_error_tolerance)),
-            Arc::new(DenyListCheck::new(pgpool, escrow_accounts, domain_separator).await),
-            Arc::new(ReceiptMaxValueCheck::new(receipt_max_value)),
-        ]
+        Self::get_named_checks(
+            pgpool,
+            indexer_allocations,
+            virtual_allocations,
+            escrow_accounts,
+            domain_separator,
+            timestamp_error_tolerance,
+            value_limits,
+            grace_periods,
+            acceptance_window,
+            events,
+        )
+        .await
+        .into_iter()
+        .map(|(_, check)| check)
+        .collect()
+    }
+
+    /// Same checks as [`Self::get_checks`], along with their names for reporting.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn get_named_checks(
+        pgpool: PgPool,
+        indexer_allocations: Eventual<HashMap<Address, Allocation>>,
+        virtual_allocations: Eventual<HashMap<Address, VirtualAllocation>>,
+        escrow_accounts: Eventual<EscrowAccounts>,
+        domain_separator: Eip712Domain,
+        timestamp_error_tolerance: Duration,
+        value_limits: ReceiptValueLimits,
+        grace_periods: GracePeriods,
+        acceptance_window: Option<ReceiptAcceptanceWindowConfig>,
+        events: &broadcast::Sender<RecordedEvent>,
+    ) -> Vec<(&'static str, ReceiptCheck)> {
+        let mut checks: Vec<(&'static str, ReceiptCheck)> = vec![
+            (
+                ALLOCATION_ELIGIBLE_CHECK,
+                Arc::new(AllocationEligible::new(
+                    indexer_allocations.clone(),
+                    virtual_allocations,
+                    grace_periods,
+                    escrow_accounts.clone(),
+                    domain_separator.clone(),
+                )),
+            ),
+            (
+                "sender_balance",
+                Arc::new(SenderBalanceCheck::new(
+                    escrow_accounts.clone(),
+                    domain_separator.clone(),
+                )),
+            ),
+            (
+                "timestamp",
+                Arc::new(TimestampCheck::new(timestamp_error_tolerance)),
+            ),
+            (
+                "sender_paused",
+                Arc::new(
+                    SenderPausedCheck::new(
+                        pgpool.clone(),
+                        events,
+                        escrow_accounts.clone(),
+                        domain_separator.clone(),
+                    )
+                    .await,
+                ),
+            ),
+            (
+                "deny_list",
+                Arc::new(DenyListCheck::new(pgpool, escrow_accounts, domain_separator).await),
+            ),
+            (
+                "max_value",
+                Arc::new(ReceiptMaxValueCheck::new(value_limits.max_value)),
+            ),
+            (
+                "min_value",
+                Arc::new(ReceiptMinValueCheck::new(
+                    indexer_allocations.clone(),
+                    value_limits.min_value,
+                    value_limits.min_value_per_deployment,
+                    value_limits.sender_pricing,
+                )),
+            ),
+        ];
+        if let Some(acceptance_window) = acceptance_window {
+            checks.push((
+                "acceptance_window",
+                Arc::new(AcceptanceWindowCheck::new(
+                    indexer_allocations,
+                    acceptance_window,
+                )),
+            ));
+        }
+        checks
     }
 
     pub async fn new(pgpool: PgPool, domain_separator: Eip712Domain) -> Self {
         Self {
             pgpool,
             domain_separator: Arc::new(domain_separator),
+            receipt_queue: None,
+            read_only_database: None,
+        }
+    }
+
+    pub fn with_receipt_queue(self, receipt_queue: Arc<ReceiptQueue>) -> Self {
+        Self {
+            receipt_queue: Some(receipt_queue),
+            ..self
+        }
+    }
+
+    pub fn with_read_only_database(self, read_only_database: Arc<ReadOnlyDatabase>) -> Self {
+        Self {
+            read_only_database: Some(read_only_database),
+            ..self
         }
     }
 }
@@ -1,8 +1,11 @@
 // Copyright 2023-, GraphOps and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
+pub mod acceptance_window_check;
 pub mod allocation_eligible;
 pub mod deny_list_check;
 pub mod receipt_max_val_check;
+pub mod receipt_min_val_check;
 pub mod sender_balance_check;
+pub mod sender_paused_check;
 pub mod timestamp_check;
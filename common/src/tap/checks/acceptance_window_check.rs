@@ -0,0 +1,155 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Receipts predating the creation of their allocation, by more than the tolerance for the clock
+//! skew between the senders and the chain, are suspicious: either the sender's clock is wrong,
+//! or the receipt was made up for an allocation it wasn't meant for.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use alloy_primitives::Address;
+use anyhow::anyhow;
+use eventuals::Eventual;
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use tap_core::receipt::{
+    checks::{Check, CheckResult},
+    Checking, ReceiptWithState,
+};
+use tracing::warn;
+
+use crate::indexer_service::http::{ReceiptAcceptanceWindowAction, ReceiptAcceptanceWindowConfig};
+use crate::prelude::Allocation;
+
+lazy_static! {
+    static ref RECEIPTS_OUTSIDE_ACCEPTANCE_WINDOW: IntCounterVec = register_int_counter_vec!(
+        "indexer_receipts_outside_acceptance_window",
+        "Receipts predating the creation of their allocation, rejected or flagged",
+        &["allocation", "action"]
+    )
+    .unwrap();
+}
+
+pub struct AcceptanceWindowCheck {
+    indexer_allocations: Eventual<HashMap<Address, Allocation>>,
+    tolerance: Duration,
+    action: ReceiptAcceptanceWindowAction,
+}
+
+impl AcceptanceWindowCheck {
+    pub fn new(
+        indexer_allocations: Eventual<HashMap<Address, Allocation>>,
+        config: ReceiptAcceptanceWindowConfig,
+    ) -> Self {
+        Self {
+            indexer_allocations,
+            tolerance: config.tolerance,
+            action: config.action,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Check for AcceptanceWindowCheck {
+    async fn check(&self, receipt: &ReceiptWithState<Checking>) -> CheckResult {
+        let receipt = &receipt.signed_receipt().message;
+        // Allocations not synced yet from the network subgraph, or without their creation
+        // time, are left to the allocation eligibility check
+        let Some(created_at) = self
+            .indexer_allocations
+            .value_immediate()
+            .and_then(|allocations| allocations.get(&receipt.allocation_id)?.created_at)
+        else {
+            return Ok(());
+        };
+
+        let receipt_timestamp = Duration::from_nanos(receipt.timestamp_ns);
+        let window_start = Duration::from_secs(created_at).saturating_sub(self.tolerance);
+        if receipt_timestamp >= window_start {
+            return Ok(());
+        }
+
+        let action = match self.action {
+            ReceiptAcceptanceWindowAction::Reject => "reject",
+            ReceiptAcceptanceWindowAction::Flag => "flag",
+        };
+        RECEIPTS_OUTSIDE_ACCEPTANCE_WINDOW
+            .with_label_values(&[&receipt.allocation_id.to_string(), action])
+            .inc();
+        let error = anyhow!(
+            "Receipt timestamp `{}` predates the creation of allocation `{}` at `{}`",
+            receipt_timestamp.as_secs(),
+            receipt.allocation_id,
+            created_at
+        );
+        match self.action {
+            ReceiptAcceptanceWindowAction::Reject => Err(error),
+            ReceiptAcceptanceWindowAction::Flag => {
+                warn!(%error, "Accepting a receipt outside of the acceptance window");
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_vectors::{self, create_signed_receipt};
+
+    const CREATED_AT: u64 = 1_700_000_000;
+
+    fn check(
+        action: ReceiptAcceptanceWindowAction,
+        created_at: Option<u64>,
+    ) -> AcceptanceWindowCheck {
+        let mut allocations = test_vectors::INDEXER_ALLOCATIONS.clone();
+        for allocation in allocations.values_mut() {
+            allocation.created_at = created_at;
+        }
+        AcceptanceWindowCheck::new(
+            Eventual::from_value(allocations),
+            ReceiptAcceptanceWindowConfig {
+                tolerance: Duration::from_secs(60),
+                action,
+            },
+        )
+    }
+
+    async fn receipt_at(timestamp_secs: u64) -> ReceiptWithState<Checking> {
+        let allocation_id = *test_vectors::INDEXER_ALLOCATIONS.keys().next().unwrap();
+        ReceiptWithState::new(
+            create_signed_receipt(allocation_id, 1, timestamp_secs * 1_000_000_000, 1).await,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_acceptance_window() {
+        let reject = check(ReceiptAcceptanceWindowAction::Reject, Some(CREATED_AT));
+        assert!(reject.check(&receipt_at(CREATED_AT + 10)).await.is_ok());
+        // Sender's clock a bit behind the chain's
+        assert!(reject.check(&receipt_at(CREATED_AT - 30)).await.is_ok());
+        assert!(reject.check(&receipt_at(CREATED_AT - 3600)).await.is_err());
+
+        let flag = check(ReceiptAcceptanceWindowAction::Flag, Some(CREATED_AT));
+        assert!(flag.check(&receipt_at(CREATED_AT - 3600)).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_unknown_creation() {
+        // Without the creation time, e.g. from an older network subgraph
+        let reject = check(ReceiptAcceptanceWindowAction::Reject, None);
+        assert!(reject.check(&receipt_at(0)).await.is_ok());
+
+        // Allocation not synced yet, the network subgraph lagging behind the chain
+        let reject = AcceptanceWindowCheck::new(
+            Eventual::from_value(HashMap::new()),
+            ReceiptAcceptanceWindowConfig {
+                tolerance: Duration::from_secs(60),
+                action: ReceiptAcceptanceWindowAction::Reject,
+            },
+        );
+        assert!(reject.check(&receipt_at(0)).await.is_ok());
+    }
+}
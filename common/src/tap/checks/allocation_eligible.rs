@@ -2,8 +2,10 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use alloy_primitives::Address;
+use alloy_sol_types::Eip712Domain;
 use anyhow::anyhow;
 use eventuals::Eventual;
 
@@ -12,15 +14,36 @@ use tap_core::receipt::{
     Checking, ReceiptWithState,
 };
 
+use crate::allocations::grace_period::GracePeriods;
+use crate::allocations::virtual_allocations::VirtualAllocation;
+use crate::escrow_accounts::EscrowAccounts;
 use crate::prelude::Allocation;
+use crate::tap::recover_signer;
+
 pub struct AllocationEligible {
     indexer_allocations: Eventual<HashMap<Address, Allocation>>,
+    /// Accepted besides the on-chain allocations, for the services they are enabled for.
+    virtual_allocations: Eventual<HashMap<Address, VirtualAllocation>>,
+    grace_periods: GracePeriods,
+    /// To find the sender of the receipt, for the senders with their own grace period.
+    escrow_accounts: Eventual<EscrowAccounts>,
+    domain_separator: Eip712Domain,
 }
 
 impl AllocationEligible {
-    pub fn new(indexer_allocations: Eventual<HashMap<Address, Allocation>>) -> Self {
+    pub fn new(
+        indexer_allocations: Eventual<HashMap<Address, Allocation>>,
+        virtual_allocations: Eventual<HashMap<Address, VirtualAllocation>>,
+        grace_periods: GracePeriods,
+        escrow_accounts: Eventual<EscrowAccounts>,
+        domain_separator: Eip712Domain,
+    ) -> Self {
         Self {
             indexer_allocations,
+            virtual_allocations,
+            grace_periods,
+            escrow_accounts,
+            domain_separator,
         }
     }
 }
@@ -28,18 +51,41 @@ impl AllocationEligible {
 impl Check for AllocationEligible {
     async fn check(&self, receipt: &ReceiptWithState<Checking>) -> CheckResult {
         let allocation_id = receipt.signed_receipt().message.allocation_id;
-        if !self
-            .indexer_allocations
-            .value()
-            .await
-            .map(|allocations| allocations.contains_key(&allocation_id))
-            .unwrap_or(false)
-        {
+        let allocations = self.indexer_allocations.value().await.unwrap_or_default();
+        let Some(allocation) = allocations.get(&allocation_id) else {
+            // Virtual allocations are never closed
+            if self
+                .virtual_allocations
+                .value_immediate()
+                .is_some_and(|allocations| allocations.contains_key(&allocation_id))
+            {
+                return Ok(());
+            }
             return Err(anyhow!(
                 "Receipt allocation ID `{}` is not eligible for this indexer",
                 allocation_id
             ));
-        }
+        };
+
+        let sender = if self.grace_periods.per_sender.is_empty() {
+            None
+        } else {
+            recover_signer(receipt.signed_receipt(), &self.domain_separator)
+                .ok()
+                .and_then(|signer| {
+                    self.escrow_accounts
+                        .value_immediate()?
+                        .get_sender_for_signer(&signer)
+                        .ok()
+                })
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_secs();
+        let replaced_at = self.grace_periods.replaced_at(&allocations, allocation);
+        self.grace_periods
+            .check(allocation, replaced_at, sender.as_ref(), now)?;
         Ok(())
     }
 }
@@ -2,8 +2,11 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use crate::escrow_accounts::EscrowAccounts;
+use crate::notifications::{self, Notification};
+use crate::tap::recover_signer;
 use alloy_sol_types::Eip712Domain;
 use eventuals::Eventual;
+use serde::{Deserialize, Serialize};
 use sqlx::postgres::PgListener;
 use sqlx::PgPool;
 use std::collections::HashSet;
@@ -14,7 +17,72 @@ use tap_core::receipt::{
     Checking, ReceiptWithState,
 };
 use thegraph::types::Address;
-use tracing::error;
+use tracing::{error, warn};
+
+/// PG NOTIFY channel of the changes of the denylist.
+pub const DENYLIST_CHANNEL: &str = "scalar_tap_deny_notification";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum DenylistOperation {
+    Insert,
+    Delete,
+    Update,
+    Truncate,
+}
+
+/// A change of the denylist, as notified by the `scalar_tap_deny_notify` trigger. There's no
+/// sender for the operations other than insertions and deletions.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DenylistNotification {
+    pub operation: DenylistOperation,
+    pub sender_address: Option<Address>,
+}
+
+/// Payload of the trigger predating the versioning.
+#[derive(Deserialize)]
+struct DenylistNotificationV0 {
+    tg_op: DenylistOperation,
+    sender_address: Option<Address>,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct DenylistNotificationV1 {
+    version: u32,
+    tg_op: DenylistOperation,
+    sender_address: Option<Address>,
+}
+
+impl Notification for DenylistNotification {
+    const CHANNEL: &'static str = DENYLIST_CHANNEL;
+    const VERSION: u32 = 1;
+
+    fn read(version: u32, payload: &str) -> serde_json::Result<Self> {
+        let (tg_op, sender_address) = match version {
+            0 => {
+                let v0: DenylistNotificationV0 = serde_json::from_str(payload)?;
+                (v0.tg_op, v0.sender_address)
+            }
+            _ => {
+                let v1: DenylistNotificationV1 = serde_json::from_str(payload)?;
+                (v1.tg_op, v1.sender_address)
+            }
+        };
+        Ok(Self {
+            operation: tg_op,
+            sender_address,
+        })
+    }
+
+    fn write(&self) -> serde_json::Result<String> {
+        serde_json::to_string(&DenylistNotificationV1 {
+            version: Self::VERSION,
+            tg_op: self.operation,
+            sender_address: self.sender_address,
+        })
+    }
+}
 
 pub struct DenyListCheck {
     escrow_accounts: Eventual<EscrowAccounts>,
@@ -33,13 +101,10 @@ impl DenyListCheck {
         // Listen to pg_notify events. We start it before updating the sender_denylist so that we
         // don't miss any updates. PG will buffer the notifications until we start consuming them.
         let mut pglistener = PgListener::connect_with(&pgpool.clone()).await.unwrap();
-        pglistener
-            .listen("scalar_tap_deny_notification")
-            .await
-            .expect(
-                "should be able to subscribe to Postgres Notify events on the channel \
+        pglistener.listen(DENYLIST_CHANNEL).await.expect(
+            "should be able to subscribe to Postgres Notify events on the channel \
                 'scalar_tap_deny_notification'",
-            );
+        );
 
         // Fetch the denylist from the DB
         let sender_denylist = Arc::new(RwLock::new(HashSet::new()));
@@ -90,12 +155,6 @@ impl DenyListCheck {
         denylist: Arc<RwLock<HashSet<Address>>>,
         cancel_token: tokio_util::sync::CancellationToken,
     ) {
-        #[derive(serde::Deserialize)]
-        struct DenylistNotification {
-            tg_op: String,
-            sender_address: Address,
-        }
-
         loop {
             tokio::select! {
                 _ = cancel_token.cancelled() => {
@@ -108,32 +167,40 @@ impl DenyListCheck {
                     'scalar_tap_deny_notification'",
                     );
 
-                    let denylist_notification: DenylistNotification =
-                        serde_json::from_str(pg_notification.payload()).expect(
-                            "should be able to deserialize the Postgres Notify event payload as a \
-                            DenylistNotification",
-                        );
-
-                    match denylist_notification.tg_op.as_str() {
-                        "INSERT" => {
-                            denylist
-                                .write()
-                                .unwrap()
-                                .insert(denylist_notification.sender_address);
+                    let denylist_notification = notifications::parse::<DenylistNotification>(
+                        pg_notification.payload(),
+                    )
+                    .inspect_err(|e| {
+                        warn!(
+                            payload = pg_notification.payload(),
+                            "Unreadable denylist notification, reloading the entire denylist: {}",
+                            e
+                        )
+                    })
+                    .ok();
+
+                    match denylist_notification {
+                        Some(DenylistNotification {
+                            operation: DenylistOperation::Insert,
+                            sender_address: Some(sender_address),
+                        }) => {
+                            denylist.write().unwrap().insert(sender_address);
                         }
-                        "DELETE" => {
-                            denylist
-                                .write()
-                                .unwrap()
-                                .remove(&denylist_notification.sender_address);
+                        Some(DenylistNotification {
+                            operation: DenylistOperation::Delete,
+                            sender_address: Some(sender_address),
+                        }) => {
+                            denylist.write().unwrap().remove(&sender_address);
                         }
                         // UPDATE and TRUNCATE are not expected to happen. Reload the entire denylist.
                         _ => {
-                            error!(
-                                "Received an unexpected denylist table notification: {}. Reloading entire \
-                                denylist.",
-                                denylist_notification.tg_op
-                            );
+                            if let Some(denylist_notification) = denylist_notification {
+                                error!(
+                                    "Received an unexpected denylist table notification: {:?}. \
+                                    Reloading entire denylist.",
+                                    denylist_notification.operation
+                                );
+                            }
 
                             Self::sender_denylist_reload(pgpool.clone(), denylist.clone())
                                 .await
@@ -149,9 +216,7 @@ impl DenyListCheck {
 #[async_trait::async_trait]
 impl Check for DenyListCheck {
     async fn check(&self, receipt: &ReceiptWithState<Checking>) -> CheckResult {
-        let receipt_signer = receipt
-            .signed_receipt()
-            .recover_signer(&self.domain_separator)
+        let receipt_signer = recover_signer(receipt.signed_receipt(), &self.domain_separator)
             .inspect_err(|e| {
                 error!("Failed to recover receipt signer: {}", e);
             })?;
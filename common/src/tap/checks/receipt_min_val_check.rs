@@ -0,0 +1,202 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::HashMap;
+
+use eventuals::Eventual;
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use tap_core::receipt::{
+    checks::{Check, CheckResult},
+    Checking, ReceiptWithState,
+};
+use thegraph::types::{Address, DeploymentId};
+
+use crate::prelude::Allocation;
+use crate::tap::receipt_payment;
+use crate::tap::sender_pricing::{apply_multiplier, SenderPricing};
+
+lazy_static! {
+    static ref RECEIPTS_BELOW_FLOOR: IntCounterVec = register_int_counter_vec!(
+        "indexer_receipts_below_floor_total",
+        "Receipts rejected because their value is below the receipt value floor",
+        &["deployment"]
+    )
+    .unwrap();
+}
+
+/// Rejection of a receipt whose value is too low to be worth aggregating. Kept distinct from
+/// other check failures so that gateways can tell they need to raise their prices.
+#[derive(Debug, thiserror::Error)]
+#[error("Receipt value `{value}` is below the floor `{floor}` for deployment `{deployment}`")]
+pub struct ReceiptBelowFloor {
+    pub value: u128,
+    pub floor: u128,
+    pub deployment: String,
+}
+
+/// Rejects receipts below a minimum value, configurable per deployment and scaled by the
+/// price multiplier of the sender. The receipts of a query paid with several receipts are held
+/// to the floor together, by their combined value.
+pub struct ReceiptMinValueCheck {
+    indexer_allocations: Eventual<HashMap<Address, Allocation>>,
+    default_floor: u128,
+    deployment_floors: HashMap<DeploymentId, u128>,
+    sender_pricing: SenderPricing,
+}
+
+impl ReceiptMinValueCheck {
+    pub fn new(
+        indexer_allocations: Eventual<HashMap<Address, Allocation>>,
+        default_floor: u128,
+        deployment_floors: HashMap<DeploymentId, u128>,
+        sender_pricing: SenderPricing,
+    ) -> Self {
+        Self {
+            indexer_allocations,
+            default_floor,
+            deployment_floors,
+            sender_pricing,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Check for ReceiptMinValueCheck {
+    async fn check(&self, receipt: &ReceiptWithState<Checking>) -> CheckResult {
+        let multiplier = self
+            .sender_pricing
+            .multiplier_for_receipt(receipt.signed_receipt());
+        let receipt = &receipt.signed_receipt().message;
+        let value = receipt_payment::combined_value().unwrap_or(receipt.value);
+
+        let deployment = self
+            .indexer_allocations
+            .value_immediate()
+            .and_then(|allocations| {
+                allocations
+                    .get(&receipt.allocation_id)
+                    .map(|allocation| allocation.subgraph_deployment.id)
+            });
+        let floor = deployment
+            .and_then(|deployment| self.deployment_floors.get(&deployment))
+            .copied()
+            .unwrap_or(self.default_floor);
+        let floor = apply_multiplier(floor, multiplier);
+
+        if value >= floor {
+            return Ok(());
+        }
+
+        let deployment = deployment
+            .map(|deployment| deployment.to_string())
+            .unwrap_or_default();
+        RECEIPTS_BELOW_FLOOR.with_label_values(&[&deployment]).inc();
+        Err(ReceiptBelowFloor {
+            value,
+            floor,
+            deployment,
+        }
+        .into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use tap_core::receipt::ReceiptWithState;
+
+    use crate::escrow_accounts::EscrowAccounts;
+    use crate::tap::payment_scope;
+    use crate::test_vectors::{
+        create_signed_receipt, ESCROW_ACCOUNTS_BALANCES, ESCROW_ACCOUNTS_SENDERS_TO_SIGNERS,
+        INDEXER_ALLOCATIONS, TAP_EIP712_DOMAIN, TAP_SENDER,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_receipt_value_floors() {
+        // Deployment 0xbbde25a2... of allocation 0xfa44c72b... has its own floor.
+        let allocation_with_floor =
+            Address::from_str("0xfa44c72b753a66591f241c7dc04e8178c30e13af").unwrap();
+        let allocation_without_floor =
+            Address::from_str("0xdd975e30aafebb143e54d215db8a3e8fd916a701").unwrap();
+        let check = ReceiptMinValueCheck::new(
+            Eventual::from_value(INDEXER_ALLOCATIONS.to_owned()),
+            10,
+            HashMap::from([(
+                INDEXER_ALLOCATIONS[&allocation_with_floor]
+                    .subgraph_deployment
+                    .id,
+                100,
+            )]),
+            SenderPricing::new(
+                HashMap::new(),
+                Eventual::from_value(EscrowAccounts::default()),
+                TAP_EIP712_DOMAIN.clone(),
+            ),
+        );
+
+        let receipt = |allocation_id, value| async move {
+            ReceiptWithState::new(create_signed_receipt(allocation_id, 1, 1, value).await)
+        };
+
+        assert!(check
+            .check(&receipt(allocation_with_floor, 100).await)
+            .await
+            .is_ok());
+        let error = check
+            .check(&receipt(allocation_with_floor, 99).await)
+            .await
+            .unwrap_err();
+        assert!(error.downcast_ref::<ReceiptBelowFloor>().is_some());
+
+        assert!(check
+            .check(&receipt(allocation_without_floor, 10).await)
+            .await
+            .is_ok());
+        assert!(check
+            .check(&receipt(allocation_without_floor, 9).await)
+            .await
+            .is_err());
+
+        // Paying for a query along with other receipts, the combined value is held to the floor
+        let small_receipt = receipt(allocation_with_floor, 40).await;
+        let (result, _) = payment_scope(100, check.check(&small_receipt)).await;
+        assert!(result.is_ok());
+        let (result, _) = payment_scope(80, check.check(&small_receipt)).await;
+        assert_eq!(
+            result
+                .unwrap_err()
+                .downcast_ref::<ReceiptBelowFloor>()
+                .unwrap()
+                .value,
+            80
+        );
+
+        // The sender of the receipts gets a discount, lowering its floor too
+        let discounted_check = ReceiptMinValueCheck::new(
+            Eventual::from_value(INDEXER_ALLOCATIONS.to_owned()),
+            10,
+            HashMap::new(),
+            SenderPricing::new(
+                HashMap::from([(TAP_SENDER.1, 0.5)]),
+                Eventual::from_value(EscrowAccounts::new(
+                    ESCROW_ACCOUNTS_BALANCES.to_owned(),
+                    ESCROW_ACCOUNTS_SENDERS_TO_SIGNERS.to_owned(),
+                )),
+                TAP_EIP712_DOMAIN.clone(),
+            ),
+        );
+        assert!(discounted_check
+            .check(&receipt(allocation_without_floor, 5).await)
+            .await
+            .is_ok());
+        assert!(discounted_check
+            .check(&receipt(allocation_without_floor, 4).await)
+            .await
+            .is_err());
+    }
+}
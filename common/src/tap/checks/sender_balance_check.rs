@@ -2,6 +2,8 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use crate::escrow_accounts::EscrowAccounts;
+use crate::tap::domain_diagnostic::DomainDiagnostic;
+use crate::tap::recover_signer;
 use alloy_sol_types::Eip712Domain;
 use anyhow::anyhow;
 use ethers_core::types::U256;
@@ -16,12 +18,14 @@ pub struct SenderBalanceCheck {
     escrow_accounts: Eventual<EscrowAccounts>,
 
     domain_separator: Eip712Domain,
+    domain_diagnostic: DomainDiagnostic,
 }
 
 impl SenderBalanceCheck {
     pub fn new(escrow_accounts: Eventual<EscrowAccounts>, domain_separator: Eip712Domain) -> Self {
         Self {
             escrow_accounts,
+            domain_diagnostic: DomainDiagnostic::new(domain_separator.clone()),
             domain_separator,
         }
     }
@@ -32,16 +36,20 @@ impl Check for SenderBalanceCheck {
     async fn check(&self, receipt: &ReceiptWithState<Checking>) -> CheckResult {
         let escrow_accounts_snapshot = self.escrow_accounts.value_immediate().unwrap_or_default();
 
-        let receipt_signer = receipt
-            .signed_receipt()
-            .recover_signer(&self.domain_separator)
+        let receipt_signer = recover_signer(receipt.signed_receipt(), &self.domain_separator)
             .inspect_err(|e| {
                 error!("Failed to recover receipt signer: {}", e);
             })?;
 
         // We bail if the receipt signer does not have a corresponding sender in the escrow
         // accounts.
-        let receipt_sender = escrow_accounts_snapshot.get_sender_for_signer(&receipt_signer)?;
+        let receipt_sender = escrow_accounts_snapshot
+            .get_sender_for_signer(&receipt_signer)
+            .inspect_err(|_| {
+                // An unknown signer is also what a mis-configured domain separator looks like.
+                self.domain_diagnostic
+                    .record_failure(receipt.signed_receipt(), &escrow_accounts_snapshot);
+            })?;
 
         // Check that the sender has a non-zero balance -- more advanced accounting is done in
         // `tap-agent`.
@@ -0,0 +1,173 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
+
+use alloy_sol_types::Eip712Domain;
+use eventuals::Eventual;
+use sqlx::PgPool;
+use tap_core::receipt::{
+    checks::{Check, CheckResult},
+    Checking, ReceiptWithState,
+};
+use thegraph::types::Address;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{error, info, warn};
+
+use crate::escrow_accounts::EscrowAccounts;
+use crate::events::{paused_senders, Event, RecordedEvent};
+use crate::tap::recover_signer;
+
+/// Rejects the receipts of the senders paused by the tap-agent, e.g. for reaching the hard cap
+/// on their unaggregated fees, until it resumes them. Follows the `sender_paused` events of the
+/// shared event log.
+pub struct SenderPausedCheck {
+    escrow_accounts: Eventual<EscrowAccounts>,
+    domain_separator: Eip712Domain,
+    paused_senders: Arc<RwLock<HashSet<Address>>>,
+}
+
+impl SenderPausedCheck {
+    pub async fn new(
+        pgpool: PgPool,
+        events: &broadcast::Sender<RecordedEvent>,
+        escrow_accounts: Eventual<EscrowAccounts>,
+        domain_separator: Eip712Domain,
+    ) -> Self {
+        // Subscribed before loading the paused senders, not to miss any event in between
+        let receiver = events.subscribe();
+        let senders = paused_senders(&pgpool)
+            .await
+            .expect("should be able to fetch the paused senders from the DB on startup");
+        let paused_senders = Arc::new(RwLock::new(senders));
+        tokio::spawn(Self::watch(pgpool, receiver, paused_senders.clone()));
+        Self {
+            escrow_accounts,
+            domain_separator,
+            paused_senders,
+        }
+    }
+
+    async fn watch(
+        pgpool: PgPool,
+        mut receiver: broadcast::Receiver<RecordedEvent>,
+        paused_senders_lock: Arc<RwLock<HashSet<Address>>>,
+    ) {
+        loop {
+            match receiver.recv().await {
+                Ok(RecordedEvent {
+                    event:
+                        Event::SenderPaused {
+                            sender,
+                            paused,
+                            reason,
+                        },
+                    ..
+                }) => {
+                    info!(%sender, paused, ?reason, "Sender pause updated");
+                    let mut senders = paused_senders_lock.write().unwrap();
+                    if paused {
+                        senders.insert(sender);
+                    } else {
+                        senders.remove(&sender);
+                    }
+                }
+                Ok(_) => {}
+                // Some events were missed, the paused senders are read again
+                Err(RecvError::Lagged(missed)) => {
+                    warn!(missed, "Missed events, reloading the paused senders");
+                    match paused_senders(&pgpool).await {
+                        Ok(senders) => *paused_senders_lock.write().unwrap() = senders,
+                        Err(e) => error!("Failed to reload the paused senders: {}", e),
+                    }
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Check for SenderPausedCheck {
+    async fn check(&self, receipt: &ReceiptWithState<Checking>) -> CheckResult {
+        let receipt_signer = recover_signer(receipt.signed_receipt(), &self.domain_separator)
+            .inspect_err(|e| {
+                error!("Failed to recover receipt signer: {}", e);
+            })?;
+        let escrow_accounts_snapshot = self.escrow_accounts.value_immediate().unwrap_or_default();
+        let receipt_sender = escrow_accounts_snapshot.get_sender_for_signer(&receipt_signer)?;
+
+        if self
+            .paused_senders
+            .read()
+            .unwrap()
+            .contains(&receipt_sender)
+        {
+            return Err(anyhow::anyhow!(
+                "Received a receipt from a paused sender: {}",
+                receipt_sender
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    use tap_core::receipt::ReceiptWithState;
+
+    use crate::events::publish;
+    use crate::test_vectors::{self, create_signed_receipt, TAP_SENDER};
+
+    use super::*;
+
+    const ALLOCATION_ID: &str = "0xdeadbeefcafebabedeadbeefcafebabedeadbeef";
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_paused_sender(pgpool: PgPool) {
+        let escrow_accounts = Eventual::from_value(EscrowAccounts::new(
+            test_vectors::ESCROW_ACCOUNTS_BALANCES.to_owned(),
+            test_vectors::ESCROW_ACCOUNTS_SENDERS_TO_SIGNERS.to_owned(),
+        ));
+        let (events, _) = broadcast::channel(16);
+        let check = SenderPausedCheck::new(
+            pgpool.clone(),
+            &events,
+            escrow_accounts,
+            test_vectors::TAP_EIP712_DOMAIN.to_owned(),
+        )
+        .await;
+        let receipt = ReceiptWithState::new(
+            create_signed_receipt(
+                Address::from_str(ALLOCATION_ID).unwrap(),
+                u64::MAX,
+                u64::MAX,
+                u128::MAX,
+            )
+            .await,
+        );
+        assert!(check.check(&receipt).await.is_ok());
+
+        for paused in [true, false] {
+            let event = Event::SenderPaused {
+                sender: TAP_SENDER.1,
+                paused,
+                reason: None,
+            };
+            let id = publish(&pgpool, "tap-agent", &event).await.unwrap();
+            events
+                .send(RecordedEvent {
+                    id,
+                    source: "tap-agent".to_string(),
+                    event,
+                })
+                .unwrap();
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            assert_eq!(check.check(&receipt).await.is_err(), paused);
+        }
+    }
+}
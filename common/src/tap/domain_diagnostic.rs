@@ -0,0 +1,193 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use alloy_primitives::U256;
+use alloy_sol_types::Eip712Domain;
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use tap_core::receipt::SignedReceipt;
+use tracing::{info, warn};
+
+use crate::escrow_accounts::EscrowAccounts;
+
+lazy_static! {
+    static ref DOMAIN_MISMATCH_DETECTED: IntCounterVec = register_int_counter_vec!(
+        "indexer_tap_domain_mismatch_detected_total",
+        "Receipts that failed verification under the configured EIP-712 domain but verify under a known alternate chain id",
+        &["configured_chain_id", "detected_chain_id"]
+    )
+    .unwrap();
+}
+
+/// Chain ids the TAP verifier is known to be deployed on, or that are commonly used in testing.
+const KNOWN_CHAIN_IDS: &[u64] = &[1, 5, 1337, 42161, 421613, 421614, 11155111];
+
+/// Number of signer lookup failures within `FAILURE_WINDOW` that we consider a burst.
+const FAILURE_BURST_THRESHOLD: usize = 10;
+const FAILURE_WINDOW: Duration = Duration::from_secs(60);
+/// Minimum time between two probes, so that a misconfigured service does not spam the logs.
+const PROBE_COOLDOWN: Duration = Duration::from_secs(600);
+
+struct FailureWindow {
+    started_at: Instant,
+    failures: usize,
+    last_probe: Option<Instant>,
+}
+
+/// Detects a mis-configured `receipts_verifier_chain_id` / `receipts_verifier_address`.
+///
+/// When the domain separator does not match the one used by the gateway, every receipt recovers
+/// to a random signer that is not found in the escrow accounts, and all receipts get rejected.
+/// On a burst of such failures, we try to recover the signer under a small set of alternate
+/// domains and log a hint if one of them matches a known signer.
+pub struct DomainDiagnostic {
+    domain_separator: Eip712Domain,
+    window: Mutex<FailureWindow>,
+}
+
+impl DomainDiagnostic {
+    pub fn new(domain_separator: Eip712Domain) -> Self {
+        let configured_chain_id = chain_id_of(&domain_separator);
+        info!(
+            chain_id = ?configured_chain_id,
+            verifying_contract = ?domain_separator.verifying_contract,
+            "Verifying TAP receipts under EIP-712 domain",
+        );
+        if !configured_chain_id.is_some_and(|chain_id| KNOWN_CHAIN_IDS.contains(&chain_id)) {
+            warn!(
+                "Receipts verifier chain id {:?} is not a chain id the TAP verifier is known to be \
+                deployed on, double check `receipts_verifier_chain_id` in your config",
+                configured_chain_id
+            );
+        }
+
+        Self {
+            domain_separator,
+            window: Mutex::new(FailureWindow {
+                started_at: Instant::now(),
+                failures: 0,
+                last_probe: None,
+            }),
+        }
+    }
+
+    /// Records a receipt whose signer could not be matched to a sender. Returns the chain id
+    /// under which the receipt verifies, if a burst of failures triggered a probe and one of
+    /// the alternate domains matched.
+    pub fn record_failure(
+        &self,
+        receipt: &SignedReceipt,
+        escrow_accounts: &EscrowAccounts,
+    ) -> Option<u64> {
+        if !self.should_probe() {
+            return None;
+        }
+
+        let configured_chain_id = chain_id_of(&self.domain_separator);
+        for chain_id in KNOWN_CHAIN_IDS
+            .iter()
+            .filter(|chain_id| Some(**chain_id) != configured_chain_id)
+        {
+            let mut alternate_domain = self.domain_separator.clone();
+            alternate_domain.chain_id = Some(U256::from(*chain_id));
+
+            let Ok(signer) = receipt.recover_signer(&alternate_domain) else {
+                continue;
+            };
+            if escrow_accounts.get_sender_for_signer(&signer).is_ok() {
+                warn!(
+                    "Receipts verify under chain id {} — check your config \
+                    (`receipts_verifier_chain_id` is {:?})",
+                    chain_id, configured_chain_id
+                );
+                DOMAIN_MISMATCH_DETECTED
+                    .with_label_values(&[
+                        &configured_chain_id
+                            .map(|id| id.to_string())
+                            .unwrap_or_default(),
+                        &chain_id.to_string(),
+                    ])
+                    .inc();
+                return Some(*chain_id);
+            }
+        }
+
+        warn!(
+            "Burst of receipts from unknown signers, and none of them verify under a known \
+            alternate chain id. If all receipts are being rejected, check \
+            `receipts_verifier_address` in your config (currently {:?})",
+            self.domain_separator.verifying_contract
+        );
+        None
+    }
+
+    fn should_probe(&self) -> bool {
+        let mut window = self.window.lock().unwrap();
+        let now = Instant::now();
+
+        if now.duration_since(window.started_at) > FAILURE_WINDOW {
+            window.started_at = now;
+            window.failures = 0;
+        }
+        window.failures += 1;
+
+        if window.failures < FAILURE_BURST_THRESHOLD {
+            return false;
+        }
+        if window
+            .last_probe
+            .is_some_and(|last_probe| now.duration_since(last_probe) < PROBE_COOLDOWN)
+        {
+            return false;
+        }
+        window.last_probe = Some(now);
+        true
+    }
+}
+
+fn chain_id_of(domain: &Eip712Domain) -> Option<u64> {
+    domain
+        .chain_id
+        .and_then(|chain_id| chain_id.try_into().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy_sol_types::eip712_domain;
+    use thegraph::types::Address;
+
+    use crate::test_vectors::{
+        create_signed_receipt, ESCROW_ACCOUNTS_BALANCES, ESCROW_ACCOUNTS_SENDERS_TO_SIGNERS,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_detects_mismatched_chain_id() {
+        // The test vectors sign receipts with chain id 1.
+        let diagnostic = DomainDiagnostic::new(eip712_domain! {
+            name: "TAP",
+            version: "1",
+            chain_id: 42161,
+            verifying_contract: Address::from([0x11u8; 20]),
+        });
+        let escrow_accounts = EscrowAccounts::new(
+            ESCROW_ACCOUNTS_BALANCES.to_owned(),
+            ESCROW_ACCOUNTS_SENDERS_TO_SIGNERS.to_owned(),
+        );
+        let receipt = create_signed_receipt(Address::from([0x22u8; 20]), 1, 1, 1).await;
+
+        for _ in 0..FAILURE_BURST_THRESHOLD - 1 {
+            assert_eq!(diagnostic.record_failure(&receipt, &escrow_accounts), None);
+        }
+        assert_eq!(
+            diagnostic.record_failure(&receipt, &escrow_accounts),
+            Some(1)
+        );
+        // Cooldown prevents probing again right away.
+        assert_eq!(diagnostic.record_failure(&receipt, &escrow_accounts), None);
+    }
+}
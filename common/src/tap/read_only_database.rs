@@ -0,0 +1,288 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Degradation of the receipt storage while the database is read only, e.g. during the failover
+//! to a replica. The first write rejected for that reason switches the receipts to the configured
+//! [`ReadOnlyDatabasePolicy`]: they are either rejected right away, sparing the database and the
+//! logs a failing insert per query, or appended to a journal on disk so that the paid queries
+//! keep being served. The database is then checked until it's writable again, at which point the
+//! journal is replayed.
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
+use prometheus::{register_int_gauge, IntGauge};
+use sqlx::PgPool;
+use tokio::fs;
+use tokio::sync::Mutex;
+use tracing::{error, info, warn};
+
+use super::receipt_queue::{spill, store_batch};
+use super::QueuedReceipt;
+use crate::indexer_service::http::{ReadOnlyDatabaseConfig, ReadOnlyDatabasePolicy};
+
+/// How often a read only database is checked for being writable again.
+const WRITABLE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// SQLSTATE of the `read_only_sql_transaction` errors.
+const READ_ONLY_SQL_TRANSACTION: &str = "25006";
+
+lazy_static! {
+    static ref DATABASE_READ_ONLY: IntGauge = register_int_gauge!(
+        "indexer_database_read_only",
+        "Whether the database is read only, the receipts being handled by the read only policy"
+    )
+    .unwrap();
+}
+
+pub fn is_read_only_error(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref::<sqlx::Error>(),
+        Some(sqlx::Error::Database(e)) if e.code().as_deref() == Some(READ_ONLY_SQL_TRANSACTION)
+    )
+}
+
+pub struct ReadOnlyDatabase {
+    pgpool: PgPool,
+    /// Set with the `journal` policy only.
+    journal_path: Option<PathBuf>,
+    read_only: AtomicBool,
+    /// Serializes the appends to, and the replay of, the journal.
+    journal_lock: Mutex<()>,
+}
+
+impl ReadOnlyDatabase {
+    /// Also replays the journal left over by a previous run, with the `journal` policy, as if the
+    /// database had just been found read only.
+    pub fn start(pgpool: PgPool, config: &ReadOnlyDatabaseConfig) -> Result<Arc<Self>> {
+        let journal_path = match config.policy {
+            ReadOnlyDatabasePolicy::Journal => {
+                Some(config.journal_path.clone().ok_or_else(|| {
+                    anyhow!("A journal path is required by the `journal` read only policy")
+                })?)
+            }
+            ReadOnlyDatabasePolicy::Reject => None,
+        };
+        let read_only_database = Arc::new(Self {
+            pgpool,
+            journal_path,
+            read_only: AtomicBool::new(false),
+            journal_lock: Mutex::new(()),
+        });
+
+        if let Some(journal_path) = &read_only_database.journal_path {
+            if journal_path.exists() {
+                info!(
+                    journal = %journal_path.display(),
+                    "Replaying the receipts journal of a previous run"
+                );
+                read_only_database.mark_read_only();
+                read_only_database.spawn_writable_check();
+            }
+        }
+        Ok(read_only_database)
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::SeqCst)
+    }
+
+    /// Fails while the database is read only, unless the receipts are journaled.
+    pub fn check_accepting(&self) -> Result<()> {
+        if self.journal_path.is_none() && self.is_read_only() {
+            return Err(anyhow!(
+                "The database is read only, the receipt was rejected"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Stores the receipts in the database, or in the journal while the database is read only.
+    pub async fn store(self: &Arc<Self>, receipts: &[QueuedReceipt]) -> Result<()> {
+        loop {
+            if !self.is_read_only() {
+                match store_batch(&self.pgpool, receipts).await {
+                    Err(e) if is_read_only_error(&e) => self.set_read_only(),
+                    result => return result.map(|_| ()),
+                }
+            }
+
+            let Some(journal_path) = &self.journal_path else {
+                return Err(anyhow!(
+                    "The database is read only, the receipt was rejected"
+                ));
+            };
+            let _lock = self.journal_lock.lock().await;
+            // Not to append receipts to a journal already replayed
+            if self.is_read_only() {
+                return spill(journal_path, receipts).await;
+            }
+        }
+    }
+
+    /// Returns whether the database wasn't already known to be read only.
+    fn mark_read_only(&self) -> bool {
+        let was_read_only = self.read_only.swap(true, Ordering::SeqCst);
+        DATABASE_READ_ONLY.set(1);
+        !was_read_only
+    }
+
+    fn set_read_only(self: &Arc<Self>) {
+        if !self.mark_read_only() {
+            return;
+        }
+        warn!(
+            journal = self.journal_path.is_some(),
+            "The database is read only, handling the receipts by the read only policy until it's \
+             writable again"
+        );
+        self.spawn_writable_check();
+    }
+
+    /// Replays the journal once the database is writable again.
+    fn spawn_writable_check(self: &Arc<Self>) {
+        let read_only_database = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(WRITABLE_CHECK_INTERVAL).await;
+                match read_only_database.is_writable().await {
+                    Ok(true) => {}
+                    Ok(false) => continue,
+                    Err(e) => {
+                        warn!("Failed to check whether the database is writable: {}", e);
+                        continue;
+                    }
+                }
+                match read_only_database.replay_journal().await {
+                    Ok(()) => return,
+                    Err(e) => error!("Failed to replay the receipts journal: {}", e),
+                }
+            }
+        });
+    }
+
+    async fn is_writable(&self) -> Result<bool> {
+        Ok(sqlx::query_scalar::<_, bool>(
+            "SELECT NOT pg_is_in_recovery() AND current_setting('transaction_read_only') = 'off'",
+        )
+        .fetch_one(&self.pgpool)
+        .await?)
+    }
+
+    /// Stores the journaled receipts, then goes back to storing them in the database directly.
+    async fn replay_journal(&self) -> Result<()> {
+        let _lock = self.journal_lock.lock().await;
+        if let Some(journal_path) = &self.journal_path {
+            match fs::read_to_string(journal_path).await {
+                Ok(contents) => {
+                    let receipts = contents
+                        .lines()
+                        .filter(|line| !line.is_empty())
+                        .map(serde_json::from_str)
+                        .collect::<Result<Vec<QueuedReceipt>, _>>()?;
+                    if !receipts.is_empty() {
+                        store_batch(&self.pgpool, &receipts).await?;
+                    }
+                    fs::remove_file(journal_path).await?;
+                    info!(receipts = receipts.len(), "Replayed the receipts journal");
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        if self.read_only.swap(false, Ordering::SeqCst) {
+            info!("The database is writable again");
+        }
+        DATABASE_READ_ONLY.set(0);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::Row;
+    use thegraph::types::Address;
+
+    use super::*;
+
+    fn receipt(nonce: u64) -> QueuedReceipt {
+        QueuedReceipt {
+            signer: Address::from([0x01u8; 20]),
+            signature: vec![0x02; 65],
+            allocation_id: Address::from([0x03u8; 20]),
+            timestamp_ns: 1,
+            nonce,
+            value: 10,
+            request_id: None,
+        }
+    }
+
+    async fn stored_receipts(pgpool: &PgPool) -> i64 {
+        sqlx::query("SELECT COUNT(*) AS count FROM scalar_tap_receipts")
+            .fetch_one(pgpool)
+            .await
+            .unwrap()
+            .try_get("count")
+            .unwrap()
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_journal(pgpool: PgPool) {
+        let journal_path = std::env::temp_dir().join(format!(
+            "indexer-receipts-journal-{}.jsonl",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&journal_path);
+        let read_only_database = ReadOnlyDatabase::start(
+            pgpool.clone(),
+            &ReadOnlyDatabaseConfig {
+                policy: ReadOnlyDatabasePolicy::Journal,
+                journal_path: Some(journal_path.clone()),
+            },
+        )
+        .unwrap();
+        read_only_database.store(&[receipt(1)]).await.unwrap();
+        assert_eq!(stored_receipts(&pgpool).await, 1);
+
+        assert!(read_only_database.mark_read_only());
+        assert!(read_only_database.check_accepting().is_ok());
+        read_only_database
+            .store(&[receipt(2), receipt(3)])
+            .await
+            .unwrap();
+        assert_eq!(stored_receipts(&pgpool).await, 1);
+        assert!(journal_path.exists());
+
+        assert!(read_only_database.is_writable().await.unwrap());
+        read_only_database.replay_journal().await.unwrap();
+        assert!(!read_only_database.is_read_only());
+        assert!(!journal_path.exists());
+        assert_eq!(stored_receipts(&pgpool).await, 3);
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_reject(pgpool: PgPool) {
+        let read_only_database = ReadOnlyDatabase::start(
+            pgpool.clone(),
+            &ReadOnlyDatabaseConfig {
+                policy: ReadOnlyDatabasePolicy::Reject,
+                journal_path: None,
+            },
+        )
+        .unwrap();
+        assert!(read_only_database.mark_read_only());
+        assert!(!read_only_database.mark_read_only());
+        assert!(read_only_database.check_accepting().is_err());
+        assert!(read_only_database.store(&[receipt(1)]).await.is_err());
+        assert_eq!(stored_receipts(&pgpool).await, 0);
+
+        read_only_database.replay_journal().await.unwrap();
+        assert!(read_only_database.check_accepting().is_ok());
+        read_only_database.store(&[receipt(1)]).await.unwrap();
+        assert_eq!(stored_receipts(&pgpool).await, 1);
+    }
+}
@@ -0,0 +1,155 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Payment of a single query with several receipts, for the gateways splitting the price of an
+//! expensive query. The receipts go through the checks of the TAP manager one by one, within a
+//! [`payment_scope`] where the value floor applies to their combined value instead of to each receipt,
+//! and where they are collected instead of stored. Once they all passed, they are stored with
+//! [`store_payment`], in a single insert linking them to the request they paid for.
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::sync::Arc;
+
+use anyhow::Result;
+use sqlx::PgPool;
+
+use super::receipt_queue::store_batch;
+use super::{QueuedReceipt, ReadOnlyDatabase};
+use crate::request_timing::{self, Phase};
+
+tokio::task_local! {
+    static PAYMENT: RefCell<Payment>;
+}
+
+struct Payment {
+    value: u128,
+    receipts: Vec<QueuedReceipt>,
+}
+
+/// Runs `future`, checking the receipts of a payment of `value` in total. Returns the receipts
+/// that passed the checks along with its output, to be stored with [`store_payment`].
+pub async fn payment_scope<F: Future>(value: u128, future: F) -> (F::Output, Vec<QueuedReceipt>) {
+    let payment = Payment {
+        value,
+        receipts: Vec::new(),
+    };
+    PAYMENT
+        .scope(RefCell::new(payment), async {
+            let output = future.await;
+            let receipts =
+                PAYMENT.with(|payment| std::mem::take(&mut payment.borrow_mut().receipts));
+            (output, receipts)
+        })
+        .await
+}
+
+/// Combined value of the receipts of the current payment, if any.
+pub(super) fn combined_value() -> Option<u128> {
+    PAYMENT.try_with(|payment| payment.borrow().value).ok()
+}
+
+/// Collects `receipt` into the current payment. Gives it back outside of a payment.
+pub(super) fn collect(receipt: QueuedReceipt) -> Option<QueuedReceipt> {
+    let mut receipt = Some(receipt);
+    let _ = PAYMENT.try_with(|payment| payment.borrow_mut().receipts.extend(receipt.take()));
+    receipt
+}
+
+/// Stores the receipts of a payment for the request `request_id`, all or none. They bypass the
+/// receipt queue, whose writers could split them over several batches, and are journaled like
+/// the other receipts while the database is read only.
+pub async fn store_payment(
+    pgpool: &PgPool,
+    read_only_database: Option<&Arc<ReadOnlyDatabase>>,
+    request_id: &str,
+    mut receipts: Vec<QueuedReceipt>,
+) -> Result<()> {
+    for receipt in &mut receipts {
+        receipt.request_id = Some(request_id.to_string());
+    }
+    request_timing::timed(Phase::DatabaseWrite, async {
+        match read_only_database {
+            Some(read_only_database) => read_only_database.store(&receipts).await,
+            None => store_batch(pgpool, &receipts).await.map(|_| ()),
+        }
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::Row;
+    use thegraph::types::Address;
+
+    use super::*;
+
+    fn receipt(nonce: u64, value: u128) -> QueuedReceipt {
+        QueuedReceipt {
+            signer: Address::from([0x01; 20]),
+            signature: vec![nonce as u8; 65],
+            allocation_id: Address::from([0x03; 20]),
+            timestamp_ns: 1,
+            nonce,
+            value,
+            request_id: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_scope() {
+        assert_eq!(combined_value(), None);
+        assert!(collect(receipt(1, 10)).is_some());
+
+        let (output, receipts) = payment_scope(30, async {
+            assert_eq!(combined_value(), Some(30));
+            assert!(collect(receipt(1, 10)).is_none());
+            assert!(collect(receipt(2, 20)).is_none());
+            "done"
+        })
+        .await;
+        assert_eq!(output, "done");
+        assert_eq!(receipts, vec![receipt(1, 10), receipt(2, 20)]);
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_store_payment(pgpool: PgPool) {
+        store_payment(
+            &pgpool,
+            None,
+            "request-1",
+            vec![receipt(1, 10), receipt(2, 20)],
+        )
+        .await
+        .unwrap();
+        store_batch(&pgpool, &[receipt(3, 30)]).await.unwrap();
+
+        let rows = sqlx::query(
+            r#"
+                SELECT nonce::BIGINT AS nonce, request_id
+                FROM scalar_tap_receipts
+                ORDER BY nonce
+            "#,
+        )
+        .fetch_all(&pgpool)
+        .await
+        .unwrap();
+        let stored = rows
+            .iter()
+            .map(|row| {
+                (
+                    row.get::<i64, _>("nonce"),
+                    row.get::<Option<String>, _>("request_id"),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            stored,
+            vec![
+                (1, Some("request-1".to_string())),
+                (2, Some("request-1".to_string())),
+                (3, None),
+            ]
+        );
+    }
+}
@@ -0,0 +1,652 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Optional decoupling of the paid query path from the database: receipts are pushed to a
+//! bounded in-memory queue, and written to the database in batches by a pool of writer tasks.
+//! A database latency spike then fills the queue instead of slowing queries down. What happens
+//! once the queue is full is set by the [`ReceiptQueueOverflow`] policy.
+//!
+//! A batch still failing to be written after [MAX_WRITE_ATTEMPTS] is set aside in the failed
+//! batches file, rather than holding its writer forever. On shutdown, the queue stops accepting
+//! receipts and the writers write the ones left in it, setting aside the batches that fail in
+//! the spill file so that they are put back in the queue at the next start.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use alloy_primitives::hex::ToHex;
+use anyhow::{anyhow, Result};
+use bigdecimal::ToPrimitive;
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter, register_int_counter_vec, IntCounter, IntCounterVec};
+use serde::{Deserialize, Serialize};
+use sqlx::{types::BigDecimal, PgPool, Row};
+use thegraph::types::Address;
+use tokio::fs::{self, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+use super::ReadOnlyDatabase;
+use crate::db_metrics;
+use crate::db_pool::{self, Priority};
+use crate::indexer_service::http::{ReceiptQueueConfig, ReceiptQueueOverflow};
+use crate::receipt_notifications::{self, ReceiptNotification};
+use crate::schema_drift;
+use crate::types::{AllocationIdHex, GrtWei};
+
+/// Delay before retrying to write a batch the database failed to store.
+const WRITE_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// Attempts to write a batch before setting it aside.
+const MAX_WRITE_ATTEMPTS: u32 = 5;
+/// How often the spill file is checked for receipts to put back in the queue.
+const SPILL_DRAIN_INTERVAL: Duration = Duration::from_secs(5);
+
+lazy_static! {
+    static ref RECEIPT_QUEUE_OVERFLOWS: IntCounterVec = register_int_counter_vec!(
+        "indexer_receipt_queue_overflows_total",
+        "Receipts that found the receipt queue full, by overflow policy",
+        &["policy"]
+    )
+    .unwrap();
+    static ref RECEIPT_QUEUE_SET_ASIDE: IntCounterVec = register_int_counter_vec!(
+        "indexer_receipt_queue_set_aside_total",
+        "Queued receipts set aside instead of being written, by reason",
+        &["reason"]
+    )
+    .unwrap();
+    static ref RECEIPT_QUEUE_MALFORMED_SPILLS: IntCounter = register_int_counter!(
+        "indexer_receipt_queue_malformed_spills_total",
+        "Lines of the spill file skipped because they don't hold a receipt"
+    )
+    .unwrap();
+}
+
+/// A receipt ready to be inserted in `scalar_tap_receipts`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueuedReceipt {
+    pub signer: Address,
+    pub signature: Vec<u8>,
+    pub allocation_id: Address,
+    pub timestamp_ns: u64,
+    pub nonce: u64,
+    pub value: u128,
+    /// Request the receipt paid for along with other receipts, see
+    /// [`store_payment`](super::store_payment).
+    #[serde(default)]
+    pub request_id: Option<String>,
+}
+
+/// Why queued receipts are set aside instead of being written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SetAsideReason {
+    WriteFailed,
+    Shutdown,
+}
+
+impl SetAsideReason {
+    fn as_str(self) -> &'static str {
+        match self {
+            SetAsideReason::WriteFailed => "write_failed",
+            SetAsideReason::Shutdown => "shutdown",
+        }
+    }
+}
+
+/// Files the receipts that can't be written are set aside in.
+#[derive(Clone)]
+struct SetAside {
+    spill_path: Option<PathBuf>,
+    failed_batches_path: Option<PathBuf>,
+    spill_lock: Arc<Mutex<()>>,
+}
+
+impl SetAside {
+    /// Appends the receipts to the failed batches file, or to the spill file on shutdown so that
+    /// they are put back in the queue at the next start. Only logged, along with the receipts,
+    /// without such a file or when it can't be written.
+    async fn set_aside(&self, receipts: &[QueuedReceipt], reason: SetAsideReason) {
+        RECEIPT_QUEUE_SET_ASIDE
+            .with_label_values(&[reason.as_str()])
+            .inc_by(receipts.len() as u64);
+        let path = match reason {
+            SetAsideReason::WriteFailed => self.failed_batches_path.as_ref(),
+            SetAsideReason::Shutdown => self
+                .spill_path
+                .as_ref()
+                .or(self.failed_batches_path.as_ref()),
+        };
+        if let Some(path) = path {
+            let _lock = self.spill_lock.lock().await;
+            match spill(path, receipts).await {
+                Ok(()) => {
+                    warn!(
+                        receipts = receipts.len(),
+                        path = %path.display(),
+                        reason = reason.as_str(),
+                        "Set aside queued receipts that couldn't be written"
+                    );
+                    return;
+                }
+                Err(e) => error!(
+                    path = %path.display(),
+                    "Failed to set aside queued receipts: {}", e
+                ),
+            }
+        }
+        error!(
+            receipts = %serde_json::to_string(receipts).unwrap_or_default(),
+            reason = reason.as_str(),
+            "Lost queued receipts that couldn't be written"
+        );
+    }
+}
+
+pub struct ReceiptQueue {
+    sender: mpsc::Sender<QueuedReceipt>,
+    overflow: ReceiptQueueOverflow,
+    spill_path: Option<PathBuf>,
+    /// Serializes the appends to, and the draining of, the spill file.
+    spill_lock: Arc<Mutex<()>>,
+    shutdown: CancellationToken,
+    writers: Mutex<JoinSet<()>>,
+}
+
+impl ReceiptQueue {
+    /// Starts the writer tasks, and the draining of the spill file with the `spill` policy. The
+    /// batches are stored through `read_only_database` when set.
+    pub fn start(
+        pgpool: PgPool,
+        config: &ReceiptQueueConfig,
+        read_only_database: Option<Arc<ReadOnlyDatabase>>,
+    ) -> Result<Self> {
+        let spill_path = match config.overflow {
+            ReceiptQueueOverflow::Spill => Some(config.spill_path.clone().ok_or_else(|| {
+                anyhow!("A spill path is required by the `spill` receipt queue overflow policy")
+            })?),
+            _ => None,
+        };
+
+        let spill_lock = Arc::new(Mutex::new(()));
+        let set_aside = SetAside {
+            spill_path: spill_path.clone(),
+            failed_batches_path: config.failed_batches_path.clone(),
+            spill_lock: spill_lock.clone(),
+        };
+        let shutdown = CancellationToken::new();
+        let (sender, receiver) = mpsc::channel(config.capacity.max(1));
+        let receiver = Arc::new(Mutex::new(receiver));
+        let mut writers = JoinSet::new();
+        for _ in 0..config.writers.max(1) {
+            writers.spawn(write_batches(
+                pgpool.clone(),
+                read_only_database.clone(),
+                receiver.clone(),
+                config.batch_size.max(1),
+                set_aside.clone(),
+                shutdown.clone(),
+            ));
+        }
+
+        if let Some(spill_path) = &spill_path {
+            let (sender, spill_path, spill_lock, shutdown) = (
+                sender.clone(),
+                spill_path.clone(),
+                spill_lock.clone(),
+                shutdown.clone(),
+            );
+            tokio::spawn(async move {
+                loop {
+                    tokio::select! {
+                        result = drain_spill_file(&spill_path, &spill_lock, &sender) => {
+                            if let Err(e) = result {
+                                error!("Failed to put spilled receipts back in the queue: {}", e);
+                            }
+                        }
+                        // Left in the spill file for the next start
+                        _ = shutdown.cancelled() => return,
+                    }
+                    tokio::select! {
+                        _ = tokio::time::sleep(SPILL_DRAIN_INTERVAL) => {}
+                        _ = shutdown.cancelled() => return,
+                    }
+                }
+            });
+        }
+
+        Ok(Self {
+            sender,
+            overflow: config.overflow,
+            spill_path,
+            spill_lock,
+            shutdown,
+            writers: Mutex::new(writers),
+        })
+    }
+
+    pub async fn enqueue(&self, receipt: QueuedReceipt) -> Result<()> {
+        let receipt = match self.sender.try_send(receipt) {
+            Ok(()) => return Ok(()),
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                return Err(anyhow!("The receipt queue is closed"))
+            }
+            Err(mpsc::error::TrySendError::Full(receipt)) => receipt,
+        };
+
+        let policy = match self.overflow {
+            ReceiptQueueOverflow::Block => "block",
+            ReceiptQueueOverflow::Drop => "drop",
+            ReceiptQueueOverflow::Spill => "spill",
+        };
+        RECEIPT_QUEUE_OVERFLOWS.with_label_values(&[policy]).inc();
+        match (self.overflow, &self.spill_path) {
+            (ReceiptQueueOverflow::Drop, _) => Err(anyhow!(
+                "The receipt queue is full, the receipt was dropped"
+            )),
+            (ReceiptQueueOverflow::Spill, Some(spill_path)) => {
+                let _lock = self.spill_lock.lock().await;
+                spill(spill_path, &[receipt]).await
+            }
+            _ => self
+                .sender
+                .send(receipt)
+                .await
+                .map_err(|_| anyhow!("The receipt queue is closed")),
+        }
+    }
+
+    /// Stops accepting receipts, and waits for the writers to write the ones left in the queue.
+    /// To be called once the paid queries are no longer served.
+    pub async fn shutdown(&self) {
+        info!("Writing the receipts left in the receipt queue");
+        self.shutdown.cancel();
+        let mut writers = self.writers.lock().await;
+        while let Some(result) = writers.join_next().await {
+            if let Err(e) = result {
+                error!("A receipt queue writer failed: {}", e);
+            }
+        }
+    }
+}
+
+async fn write_batches(
+    pgpool: PgPool,
+    read_only_database: Option<Arc<ReadOnlyDatabase>>,
+    receiver: Arc<Mutex<mpsc::Receiver<QueuedReceipt>>>,
+    batch_size: usize,
+    set_aside: SetAside,
+    shutdown: CancellationToken,
+) {
+    let mut batch = Vec::with_capacity(batch_size);
+    loop {
+        // Writers take turns at the queue, and write their batches concurrently
+        let received = {
+            let mut receiver = receiver.lock().await;
+            tokio::select! {
+                received = receiver.recv_many(&mut batch, batch_size) => received,
+                // No receipt is accepted anymore, the ones left are written until none is
+                _ = shutdown.cancelled() => {
+                    receiver.close();
+                    receiver.recv_many(&mut batch, batch_size).await
+                }
+            }
+        };
+        if received == 0 {
+            return;
+        }
+        for attempt in 1..=MAX_WRITE_ATTEMPTS {
+            let result = match &read_only_database {
+                Some(read_only_database) => read_only_database.store(&batch).await,
+                None => store_batch(&pgpool, &batch).await.map(|_| ()),
+            };
+            let Err(e) = result else {
+                batch.clear();
+                break;
+            };
+            // Already reported when the database was found read only
+            if !read_only_database
+                .as_ref()
+                .is_some_and(|read_only_database| read_only_database.is_read_only())
+            {
+                warn!(
+                    receipts = batch.len(),
+                    attempt, "Failed to write a batch of queued receipts: {}", e
+                );
+            }
+            if attempt == MAX_WRITE_ATTEMPTS || shutdown.is_cancelled() {
+                break;
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(WRITE_RETRY_DELAY) => {}
+                _ = shutdown.cancelled() => {}
+            }
+        }
+        if !batch.is_empty() {
+            let reason = if shutdown.is_cancelled() {
+                SetAsideReason::Shutdown
+            } else {
+                SetAsideReason::WriteFailed
+            };
+            set_aside.set_aside(&batch, reason).await;
+            batch.clear();
+        }
+    }
+}
+
+/// Stores the receipts, notifying the tap-agent of them directly when it listens for
+/// notifications, see [crate::receipt_notifications]. Returns the ids of the stored receipts.
+pub async fn store_batch(pgpool: &PgPool, receipts: &[QueuedReceipt]) -> Result<Vec<u64>> {
+    schema_drift::ensure_writable()?;
+    let query = sqlx::query(
+        r#"
+            INSERT INTO scalar_tap_receipts (
+                signer_address, signature, allocation_id, timestamp_ns, nonce, value, request_id
+            )
+            SELECT * FROM UNNEST(
+                $1::CHAR(40)[],
+                $2::BYTEA[],
+                $3::CHAR(40)[],
+                $4::NUMERIC(20)[],
+                $5::NUMERIC(20)[],
+                $6::NUMERIC(39)[],
+                $7::TEXT[]
+            )
+            RETURNING id, signer_address, allocation_id, timestamp_ns, value, indexer_address
+        "#,
+    )
+    .bind(
+        receipts
+            .iter()
+            .map(|receipt| receipt.signer.encode_hex::<String>())
+            .collect::<Vec<_>>(),
+    )
+    .bind(
+        receipts
+            .iter()
+            .map(|receipt| receipt.signature.clone())
+            .collect::<Vec<_>>(),
+    )
+    .bind(
+        receipts
+            .iter()
+            .map(|receipt| AllocationIdHex(receipt.allocation_id))
+            .collect::<Vec<_>>(),
+    )
+    .bind(
+        receipts
+            .iter()
+            .map(|receipt| BigDecimal::from(receipt.timestamp_ns))
+            .collect::<Vec<_>>(),
+    )
+    .bind(
+        receipts
+            .iter()
+            .map(|receipt| BigDecimal::from(receipt.nonce))
+            .collect::<Vec<_>>(),
+    )
+    .bind(
+        receipts
+            .iter()
+            .map(|receipt| GrtWei(receipt.value))
+            .collect::<Vec<_>>(),
+    )
+    .bind(
+        receipts
+            .iter()
+            .map(|receipt| receipt.request_id.clone())
+            .collect::<Vec<_>>(),
+    );
+
+    let notifier = match receipt_notifications::notifier() {
+        Some(notifier) if notifier.is_connected().await => notifier,
+        _ => {
+            let rows = db_pool::prioritized(
+                Priority::Write,
+                db_metrics::timed("store_receipt_batch", query.fetch_all(pgpool)),
+            )
+            .await?;
+            return rows
+                .iter()
+                .map(|row| Ok(row.try_get::<i64, _>("id")? as u64))
+                .collect();
+        }
+    };
+
+    let rows = db_pool::prioritized(
+        Priority::Write,
+        db_metrics::timed("store_receipt_batch", async {
+            let mut tx = pgpool.begin().await?;
+            receipt_notifications::skip_pg_notify(&mut *tx).await?;
+            let rows = query.fetch_all(&mut *tx).await?;
+            tx.commit().await?;
+            Ok::<_, anyhow::Error>(rows)
+        }),
+    )
+    .await?;
+    let notifications = rows
+        .iter()
+        .map(|row| {
+            Ok(ReceiptNotification {
+                id: row.try_get::<i64, _>("id")? as u64,
+                signer_address: row
+                    .try_get::<&str, _>("signer_address")?
+                    .trim_end()
+                    .parse()?,
+                allocation_id: row.try_get::<AllocationIdHex, _>("allocation_id")?.0,
+                timestamp_ns: row
+                    .try_get::<BigDecimal, _>("timestamp_ns")?
+                    .to_u64()
+                    .ok_or_else(|| anyhow!("Invalid receipt timestamp"))?,
+                value: row.try_get::<GrtWei, _>("value")?.0,
+                indexer_address: row
+                    .try_get::<Option<AllocationIdHex>, _>("indexer_address")?
+                    .map(|indexer_address| indexer_address.0),
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    notifier.notify(pgpool, &notifications).await;
+    Ok(notifications
+        .iter()
+        .map(|notification| notification.id)
+        .collect())
+}
+
+/// Appends receipts to the spill file, one JSON receipt per line.
+pub(super) async fn spill(spill_path: &Path, receipts: &[QueuedReceipt]) -> Result<()> {
+    let mut lines = Vec::new();
+    for receipt in receipts {
+        serde_json::to_writer(&mut lines, receipt)?;
+        lines.push(b'\n');
+    }
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(spill_path)
+        .await?;
+    file.write_all(&lines).await?;
+    file.flush().await?;
+    Ok(())
+}
+
+/// Puts the spilled receipts back in the queue, waiting for room if needed. The spill file is
+/// only cut once they are all in the queue, keeping the receipts spilled in the meantime, so
+/// that no receipt is lost if the queue closes or the service stops halfway. The lines that
+/// don't hold a receipt, e.g. cut short by a crash, are skipped.
+async fn drain_spill_file(
+    spill_path: &Path,
+    spill_lock: &Mutex<()>,
+    sender: &mpsc::Sender<QueuedReceipt>,
+) -> Result<()> {
+    let contents = {
+        let _lock = spill_lock.lock().await;
+        match fs::read_to_string(spill_path).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+    };
+    if contents.is_empty() {
+        return Ok(());
+    }
+
+    for (line_number, line) in contents.lines().enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        let receipt: QueuedReceipt = match serde_json::from_str(line) {
+            Ok(receipt) => receipt,
+            Err(e) => {
+                warn!(
+                    path = %spill_path.display(),
+                    line = line_number + 1,
+                    "Skipping a malformed line of the spill file: {}", e
+                );
+                RECEIPT_QUEUE_MALFORMED_SPILLS.inc();
+                continue;
+            }
+        };
+        sender
+            .send(receipt)
+            .await
+            .map_err(|_| anyhow!("The receipt queue is closed"))?;
+    }
+
+    // Only appended to since it was read, the receipts spilled in the meantime are kept
+    let _lock = spill_lock.lock().await;
+    let current = fs::read(spill_path).await?;
+    let remaining = current.get(contents.len()..).unwrap_or_default();
+    if remaining.is_empty() {
+        fs::remove_file(spill_path).await?;
+    } else {
+        let draining_path = spill_path.with_extension("draining");
+        fs::write(&draining_path, remaining).await?;
+        fs::rename(&draining_path, spill_path).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn receipt(nonce: u64) -> QueuedReceipt {
+        QueuedReceipt {
+            signer: Address::from([0x01u8; 20]),
+            signature: vec![0x02; 65],
+            allocation_id: Address::from([0x03u8; 20]),
+            timestamp_ns: 1,
+            nonce,
+            value: 10,
+            request_id: None,
+        }
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_store_batch(pgpool: PgPool) {
+        store_batch(&pgpool, &[receipt(1), receipt(2), receipt(3)])
+            .await
+            .unwrap();
+
+        let row =
+            sqlx::query("SELECT COUNT(*) AS count, SUM(value) AS value FROM scalar_tap_receipts")
+                .fetch_one(&pgpool)
+                .await
+                .unwrap();
+        assert_eq!(row.try_get::<i64, _>("count").unwrap(), 3);
+        assert_eq!(
+            row.try_get::<BigDecimal, _>("value").unwrap(),
+            BigDecimal::from(30)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_spill_and_drain() {
+        let spill_path = std::env::temp_dir().join(format!(
+            "indexer-receipt-queue-spill-{}.jsonl",
+            std::process::id()
+        ));
+        let spill_lock = Mutex::new(());
+        spill(&spill_path, &[receipt(1)]).await.unwrap();
+        // Cut short by a crash
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&spill_path)
+            .await
+            .unwrap()
+            .write_all(b"{\"signer\":\n")
+            .await
+            .unwrap();
+        spill(&spill_path, &[receipt(2)]).await.unwrap();
+
+        let (sender, mut receiver) = mpsc::channel(10);
+        drain_spill_file(&spill_path, &spill_lock, &sender)
+            .await
+            .unwrap();
+        assert_eq!(receiver.recv().await, Some(receipt(1)));
+        assert_eq!(receiver.recv().await, Some(receipt(2)));
+        assert!(!spill_path.exists());
+
+        // Nothing left to drain
+        drain_spill_file(&spill_path, &spill_lock, &sender)
+            .await
+            .unwrap();
+        assert!(receiver.try_recv().is_err());
+
+        // Kept while the receipts can't be put back in the queue
+        spill(&spill_path, &[receipt(3)]).await.unwrap();
+        drop(receiver);
+        assert!(drain_spill_file(&spill_path, &spill_lock, &sender)
+            .await
+            .is_err());
+        let contents = fs::read_to_string(&spill_path).await.unwrap();
+        assert_eq!(
+            serde_json::from_str::<QueuedReceipt>(contents.trim_end()).unwrap(),
+            receipt(3)
+        );
+        fs::remove_file(&spill_path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_set_aside() {
+        let spill_path = std::env::temp_dir().join(format!(
+            "indexer-receipt-queue-set-aside-spill-{}.jsonl",
+            std::process::id()
+        ));
+        let failed_batches_path = std::env::temp_dir().join(format!(
+            "indexer-receipt-queue-set-aside-failed-{}.jsonl",
+            std::process::id()
+        ));
+        let set_aside = SetAside {
+            spill_path: Some(spill_path.clone()),
+            failed_batches_path: Some(failed_batches_path.clone()),
+            spill_lock: Arc::new(Mutex::new(())),
+        };
+
+        set_aside
+            .set_aside(&[receipt(1), receipt(2)], SetAsideReason::WriteFailed)
+            .await;
+        set_aside
+            .set_aside(&[receipt(3)], SetAsideReason::Shutdown)
+            .await;
+
+        let read = |path: PathBuf| async move {
+            fs::read_to_string(path)
+                .await
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str::<QueuedReceipt>(line).unwrap())
+                .collect::<Vec<_>>()
+        };
+        // Put back in the queue at the next start
+        assert_eq!(read(spill_path.clone()).await, vec![receipt(3)]);
+        assert_eq!(
+            read(failed_batches_path.clone()).await,
+            vec![receipt(1), receipt(2)]
+        );
+        fs::remove_file(&spill_path).await.unwrap();
+        fs::remove_file(&failed_batches_path).await.unwrap();
+    }
+}
@@ -11,7 +11,18 @@ use tap_core::{
 };
 use tracing::error;
 
-use super::{AdapterError, IndexerTapContext};
+use super::receipt_payment;
+use super::receipt_queue::store_batch;
+use super::{recover_signer, AdapterError, IndexerTapContext, QueuedReceipt};
+use crate::db_metrics;
+use crate::db_pool::{self, Priority};
+use crate::receipt_notifications;
+use crate::request_timing::{self, Phase};
+
+/// Id returned for the receipts whose storage is deferred, to a receipt queue writer, to the
+/// rest of their payment or to the journal of a read only database. Never the id of a stored
+/// receipt, as `scalar_tap_receipts` ids are positive `BIGINT`s.
+pub const DEFERRED_RECEIPT_ID: u64 = u64::MAX;
 
 #[async_trait::async_trait]
 impl ReceiptStore for IndexerTapContext {
@@ -21,38 +32,94 @@ impl ReceiptStore for IndexerTapContext {
         &self,
         receipt: ReceiptWithState<Checking>,
     ) -> Result<u64, Self::AdapterError> {
+        request_timing::timed(Phase::DatabaseWrite, self.store(receipt)).await
+    }
+}
+
+impl IndexerTapContext {
+    async fn store(&self, receipt: ReceiptWithState<Checking>) -> Result<u64, AdapterError> {
         let receipt = receipt.signed_receipt();
         let allocation_id = receipt.message.allocation_id;
         let encoded_signature = receipt.signature.to_vec();
 
-        let receipt_signer = receipt
-            .recover_signer(self.domain_separator.as_ref())
-            .map_err(|e| {
-                error!("Failed to recover receipt signer: {}", e);
-                anyhow!(e)
+        let receipt_signer = recover_signer(receipt, &self.domain_separator).map_err(|e| {
+            error!("Failed to recover receipt signer: {}", e);
+            anyhow!(e)
+        })?;
+
+        if let Some(read_only_database) = &self.read_only_database {
+            read_only_database.check_accepting()?;
+        }
+        let queued_receipt = QueuedReceipt {
+            signer: receipt_signer,
+            signature: encoded_signature.clone(),
+            allocation_id,
+            timestamp_ns: receipt.message.timestamp_ns,
+            nonce: receipt.message.nonce,
+            value: receipt.message.value,
+            request_id: None,
+        };
+
+        // Stored along with the other receipts of the payment, once they all passed the checks
+        let Some(queued_receipt) = receipt_payment::collect(queued_receipt) else {
+            return Ok(DEFERRED_RECEIPT_ID);
+        };
+
+        if let Some(receipt_queue) = &self.receipt_queue {
+            receipt_queue.enqueue(queued_receipt).await.map_err(|e| {
+                error!("Failed to queue receipt: {}", e);
+                e
             })?;
+            return Ok(DEFERRED_RECEIPT_ID);
+        }
+
+        // Journaled instead of stored while the database is read only.
+        if let Some(read_only_database) = &self.read_only_database {
+            read_only_database
+                .store(&[queued_receipt])
+                .await
+                .map_err(|e| {
+                    error!("Failed to store receipt: {}", e);
+                    e
+                })?;
+            return Ok(DEFERRED_RECEIPT_ID);
+        }
+
+        // Through the insert notifying the tap-agent directly, while it listens for them
+        if receipt_notifications::notifier().is_some() {
+            let ids = store_batch(&self.pgpool, &[queued_receipt])
+                .await
+                .map_err(|e| {
+                    error!("Failed to store receipt: {}", e);
+                    e
+                })?;
+            return ids
+                .first()
+                .copied()
+                .ok_or_else(|| anyhow!("The receipt wasn't stored").into());
+        }
 
-        // TODO: consider doing this in another async task to avoid slowing down the paid query flow.
-        sqlx::query!(
+        // Without a queue, the receipt is stored on the paid query path.
+        let id: i64 = db_pool::prioritized(Priority::Write, db_metrics::timed("store_receipt", sqlx::query_scalar(
             r#"
                 INSERT INTO scalar_tap_receipts (signer_address, signature, allocation_id, timestamp_ns, nonce, value)
                 VALUES ($1, $2, $3, $4, $5, $6)
+                RETURNING id
             "#,
-            receipt_signer.encode_hex::<String>(),
-            encoded_signature,
-            allocation_id.encode_hex::<String>(),
-            BigDecimal::from(receipt.message.timestamp_ns),
-            BigDecimal::from(receipt.message.nonce),
-            BigDecimal::from(BigInt::from(receipt.message.value)),
         )
-        .execute(&self.pgpool)
+        .bind(receipt_signer.encode_hex::<String>())
+        .bind(encoded_signature)
+        .bind(allocation_id.encode_hex::<String>())
+        .bind(BigDecimal::from(receipt.message.timestamp_ns))
+        .bind(BigDecimal::from(receipt.message.nonce))
+        .bind(BigDecimal::from(BigInt::from(receipt.message.value)))
+        .fetch_one(&self.pgpool)))
         .await
         .map_err(|e| {
             error!("Failed to store receipt: {}", e);
             anyhow!(e)
         })?;
 
-        // We don't need receipt_ids
-        Ok(0)
+        Ok(id as u64)
     }
 }
@@ -0,0 +1,120 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Runs a candidate receipt through every check a paid query's receipt goes through, reporting
+//! each result instead of stopping at the first failure, and without storing the receipt. Lets
+//! gateways debug their integration before going live.
+
+use alloy_sol_types::Eip712Domain;
+use serde::Serialize;
+use tap_core::receipt::{
+    checks::{Check, ReceiptCheck},
+    ReceiptWithState, SignedReceipt,
+};
+use thegraph::types::Address;
+
+use super::recover_signer;
+
+/// Name of the signature check, which runs before the named receipt checks.
+const SIGNATURE_CHECK: &str = "signature";
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CheckOutcome {
+    pub check: &'static str,
+    pub passed: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReceiptValidation {
+    /// Whether the receipt passes all the checks, and would be accepted.
+    pub valid: bool,
+    /// Signer recovered from the receipt's signature under the indexer's EIP-712 domain.
+    pub signer: Option<Address>,
+    pub checks: Vec<CheckOutcome>,
+}
+
+pub struct ReceiptValidator {
+    domain_separator: Eip712Domain,
+    checks: Vec<(&'static str, ReceiptCheck)>,
+}
+
+impl ReceiptValidator {
+    /// `checks` should be the ones the TAP manager runs, so that the results match.
+    pub fn new(domain_separator: Eip712Domain, checks: Vec<(&'static str, ReceiptCheck)>) -> Self {
+        Self {
+            domain_separator,
+            checks,
+        }
+    }
+
+    pub async fn validate(&self, receipt: SignedReceipt) -> ReceiptValidation {
+        let mut checks = Vec::with_capacity(self.checks.len() + 1);
+
+        let signer = recover_signer(&receipt, &self.domain_separator);
+        checks.push(CheckOutcome {
+            check: SIGNATURE_CHECK,
+            passed: signer.is_ok(),
+            error: signer.as_ref().err().map(ToString::to_string),
+        });
+
+        let receipt = ReceiptWithState::new(receipt);
+        for (name, check) in &self.checks {
+            let result = check.check(&receipt).await;
+            checks.push(CheckOutcome {
+                check: name,
+                passed: result.is_ok(),
+                error: result.err().map(|e| e.to_string()),
+            });
+        }
+
+        ReceiptValidation {
+            valid: checks.iter().all(|outcome| outcome.passed),
+            signer: signer.ok(),
+            checks,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{
+        tap::checks::receipt_max_val_check::ReceiptMaxValueCheck,
+        test_vectors::{create_signed_receipt, TAP_EIP712_DOMAIN, TAP_SIGNER},
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_validate_reports_every_check() {
+        let validator = ReceiptValidator::new(
+            TAP_EIP712_DOMAIN.clone(),
+            vec![
+                ("max_value", Arc::new(ReceiptMaxValueCheck::new(100))),
+                ("max_value_strict", Arc::new(ReceiptMaxValueCheck::new(10))),
+            ],
+        );
+        let receipt = create_signed_receipt(Address::ZERO, 1, 1, 50).await;
+
+        let validation = validator.validate(receipt).await;
+        assert!(!validation.valid);
+        assert_eq!(validation.signer, Some(TAP_SIGNER.1));
+        assert_eq!(
+            validation
+                .checks
+                .iter()
+                .map(|outcome| (outcome.check, outcome.passed))
+                .collect::<Vec<_>>(),
+            vec![
+                ("signature", true),
+                ("max_value", true),
+                ("max_value_strict", false)
+            ]
+        );
+        assert!(validation.checks[2].error.is_some());
+    }
+}
@@ -0,0 +1,101 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Per-sender price multipliers, applied on top of the deployment cost models so that
+//! indexers can e.g. give a preferred gateway a discount.
+
+use std::collections::HashMap;
+
+use alloy_sol_types::Eip712Domain;
+use eventuals::Eventual;
+use tap_core::receipt::SignedReceipt;
+use thegraph::types::Address;
+
+use super::recover_signer;
+use crate::escrow_accounts::EscrowAccounts;
+
+#[derive(Clone)]
+pub struct SenderPricing {
+    multipliers: HashMap<Address, f64>,
+    escrow_accounts: Eventual<EscrowAccounts>,
+    domain_separator: Eip712Domain,
+}
+
+impl SenderPricing {
+    pub fn new(
+        multipliers: HashMap<Address, f64>,
+        escrow_accounts: Eventual<EscrowAccounts>,
+        domain_separator: Eip712Domain,
+    ) -> Self {
+        Self {
+            multipliers,
+            escrow_accounts,
+            domain_separator,
+        }
+    }
+
+    pub fn multiplier(&self, sender: &Address) -> f64 {
+        self.multipliers.get(sender).copied().unwrap_or(1.0)
+    }
+
+    /// Multiplier of the sender of `receipt`, 1 if the sender is unknown.
+    pub fn multiplier_for_receipt(&self, receipt: &SignedReceipt) -> f64 {
+        // Spares recovering the signer when there is nothing to apply
+        if self.multipliers.is_empty() {
+            return 1.0;
+        }
+        recover_signer(receipt, &self.domain_separator)
+            .ok()
+            .and_then(|signer| {
+                self.escrow_accounts
+                    .value_immediate()?
+                    .get_sender_for_signer(&signer)
+                    .ok()
+            })
+            .map_or(1.0, |sender| self.multiplier(&sender))
+    }
+}
+
+/// Scales a GRT wei amount by a price multiplier.
+pub fn apply_multiplier(value: u128, multiplier: f64) -> u128 {
+    if multiplier == 1.0 {
+        return value;
+    }
+    (value as f64 * multiplier).round() as u128
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_vectors::{
+        create_signed_receipt, ESCROW_ACCOUNTS_BALANCES, ESCROW_ACCOUNTS_SENDERS_TO_SIGNERS,
+        TAP_EIP712_DOMAIN, TAP_SENDER,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_multiplier_for_receipt() {
+        let escrow_accounts = Eventual::from_value(EscrowAccounts::new(
+            ESCROW_ACCOUNTS_BALANCES.to_owned(),
+            ESCROW_ACCOUNTS_SENDERS_TO_SIGNERS.to_owned(),
+        ));
+        let pricing = SenderPricing::new(
+            HashMap::from([(TAP_SENDER.1, 0.9)]),
+            escrow_accounts.clone(),
+            TAP_EIP712_DOMAIN.clone(),
+        );
+        let receipt = create_signed_receipt(Address::ZERO, 1, 1, 100).await;
+        assert_eq!(pricing.multiplier_for_receipt(&receipt), 0.9);
+        assert_eq!(pricing.multiplier(&Address::ZERO), 1.0);
+
+        let no_pricing =
+            SenderPricing::new(HashMap::new(), escrow_accounts, TAP_EIP712_DOMAIN.clone());
+        assert_eq!(no_pricing.multiplier_for_receipt(&receipt), 1.0);
+    }
+
+    #[test]
+    fn test_apply_multiplier() {
+        assert_eq!(apply_multiplier(1000, 0.9), 900);
+        assert_eq!(apply_multiplier(u128::MAX, 1.0), u128::MAX);
+    }
+}
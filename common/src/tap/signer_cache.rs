@@ -0,0 +1,183 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Cache of the signers recovered from receipts. Every receipt has its signer recovered by
+//! several checks, and gateways retry identical receipts, so at high QPS most recoveries are
+//! repeated. Recovered signers are cached by signing hash and signature, which covers the
+//! EIP-712 domain, and the signatures that failed to recover are cached separately so that
+//! they are rejected right away.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Mutex;
+
+use alloy_primitives::B256;
+use alloy_sol_types::{Eip712Domain, SolStruct};
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use tap_core::receipt::SignedReceipt;
+use thegraph::types::Address;
+use thiserror::Error;
+
+const RECOVERED_CAPACITY: usize = 100_000;
+const FAILED_CAPACITY: usize = 10_000;
+
+lazy_static! {
+    static ref SIGNER_CACHE: Mutex<SignerCache> =
+        Mutex::new(SignerCache::new(RECOVERED_CAPACITY, FAILED_CAPACITY));
+    static ref SIGNER_CACHE_LOOKUPS: IntCounterVec = register_int_counter_vec!(
+        "indexer_receipt_signer_cache_lookups_total",
+        "Receipt signer recoveries, by whether the signer or the failure was cached",
+        &["result"]
+    )
+    .unwrap();
+}
+
+#[derive(Clone, Debug, Error, PartialEq)]
+#[error("{0}")]
+pub struct SignerRecoveryError(String);
+
+type Key = (B256, Vec<u8>);
+
+/// Signer of `receipt` under `domain_separator`, recovered once per receipt.
+pub fn recover_signer(
+    receipt: &SignedReceipt,
+    domain_separator: &Eip712Domain,
+) -> Result<Address, SignerRecoveryError> {
+    let key = (
+        receipt.message.eip712_signing_hash(domain_separator),
+        receipt.signature.to_vec(),
+    );
+    if let Some(result) = SIGNER_CACHE.lock().unwrap().get(&key) {
+        let label = if result.is_ok() { "hit" } else { "failed_hit" };
+        SIGNER_CACHE_LOOKUPS.with_label_values(&[label]).inc();
+        return result;
+    }
+    SIGNER_CACHE_LOOKUPS.with_label_values(&["miss"]).inc();
+
+    // Recovered outside of the lock, concurrent misses on the same receipt just race
+    let result = receipt
+        .recover_signer(domain_separator)
+        .map_err(|e| SignerRecoveryError(e.to_string()));
+    SIGNER_CACHE.lock().unwrap().insert(key, result.clone());
+    result
+}
+
+struct SignerCache {
+    recovered: Generations<Key, Address>,
+    failed: Generations<Key, SignerRecoveryError>,
+}
+
+impl SignerCache {
+    fn new(recovered_capacity: usize, failed_capacity: usize) -> Self {
+        Self {
+            recovered: Generations::new(recovered_capacity),
+            failed: Generations::new(failed_capacity),
+        }
+    }
+
+    fn get(&mut self, key: &Key) -> Option<Result<Address, SignerRecoveryError>> {
+        self.recovered
+            .get(key)
+            .map(Ok)
+            .or_else(|| self.failed.get(key).map(Err))
+    }
+
+    fn insert(&mut self, key: Key, result: Result<Address, SignerRecoveryError>) {
+        match result {
+            Ok(signer) => self.recovered.insert(key, signer),
+            Err(e) => self.failed.insert(key, e),
+        }
+    }
+}
+
+/// Bounded map evicting the least recently used entries, approximately: entries are inserted
+/// in the current generation, which becomes the previous one once full, dropping the entries
+/// not used since the previous rotation.
+struct Generations<K, V> {
+    current: HashMap<K, V>,
+    previous: HashMap<K, V>,
+    generation_capacity: usize,
+}
+
+impl<K: Eq + Hash, V: Clone> Generations<K, V> {
+    fn new(capacity: usize) -> Self {
+        Self {
+            current: HashMap::new(),
+            previous: HashMap::new(),
+            generation_capacity: (capacity / 2).max(1),
+        }
+    }
+
+    fn get(&mut self, key: &K) -> Option<V> {
+        if let Some(value) = self.current.get(key) {
+            return Some(value.clone());
+        }
+        let (key, value) = self.previous.remove_entry(key)?;
+        self.insert(key, value.clone());
+        Some(value)
+    }
+
+    fn insert(&mut self, key: K, value: V) {
+        if self.current.len() >= self.generation_capacity {
+            self.previous = std::mem::take(&mut self.current);
+        }
+        self.current.insert(key, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_vectors::{create_signed_receipt, TAP_EIP712_DOMAIN, TAP_SIGNER};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_recover_signer() {
+        let receipt = create_signed_receipt(Address::ZERO, 1, 1, 100).await;
+        assert_eq!(
+            recover_signer(&receipt, &TAP_EIP712_DOMAIN),
+            Ok(TAP_SIGNER.1)
+        );
+        let hits = SIGNER_CACHE_LOOKUPS.with_label_values(&["hit"]).get();
+        assert_eq!(
+            recover_signer(&receipt, &TAP_EIP712_DOMAIN),
+            Ok(TAP_SIGNER.1)
+        );
+        assert!(SIGNER_CACHE_LOOKUPS.with_label_values(&["hit"]).get() > hits);
+
+        // Same signature over another message
+        let mut tampered = receipt.clone();
+        tampered.message.value += 1;
+        assert_ne!(
+            recover_signer(&tampered, &TAP_EIP712_DOMAIN),
+            Ok(TAP_SIGNER.1)
+        );
+    }
+
+    #[test]
+    fn test_failed_recoveries_are_cached_separately() {
+        let mut cache = SignerCache::new(10, 10);
+        let key = (B256::ZERO, vec![0; 65]);
+        let error = SignerRecoveryError("invalid signature".to_string());
+        cache.insert(key.clone(), Err(error.clone()));
+        assert_eq!(cache.get(&key), Some(Err(error)));
+        assert_eq!(cache.recovered.current.len(), 0);
+    }
+
+    #[test]
+    fn test_generations_eviction() {
+        let mut generations = Generations::new(4);
+        generations.insert(1, 1);
+        generations.insert(2, 2);
+        // Rotates, 1 and 2 are now in the previous generation
+        generations.insert(3, 3);
+        // Promotes 1 to the current generation
+        assert_eq!(generations.get(&1), Some(1));
+        // Rotates again, dropping 2 which was not used since the last rotation
+        generations.insert(4, 4);
+        assert_eq!(generations.get(&2), None);
+        assert_eq!(generations.get(&1), Some(1));
+        assert_eq!(generations.get(&3), Some(3));
+    }
+}
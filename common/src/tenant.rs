@@ -0,0 +1,235 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Isolation of the indexers sharing a database. The tables with rows owned by an indexer have
+//! an `indexer_address` column, filled from the `indexer.address` setting of the session writing
+//! the rows, and row-level security policies limiting the sessions with that setting to the rows
+//! of their indexer. Sessions without it still see all the rows, and write rows without an owner
+//! that no indexer sees: the other components writing these tables, e.g. the indexer-agent, must
+//! use a role with the setting, e.g. `ALTER ROLE indexer_agent SET indexer.address = '...'`.
+//!
+//! The rows written before the isolation was enabled are assigned once to the indexer with
+//! [claim_unowned_rows], through the `claim-unowned-rows` command of the tap-agent.
+//!
+//! Row-level security doesn't apply to superusers, nor to roles with `BYPASSRLS`, the database
+//! role of the indexer components must have neither for the isolation to be enforced.
+
+use alloy_primitives::hex::ToHex;
+use anyhow::Result;
+use sqlx::{postgres::PgPoolOptions, PgPool};
+use thegraph::types::Address;
+use tracing::info;
+
+/// Session setting holding the indexer address, without the `0x` prefix.
+pub const INDEXER_ADDRESS_SETTING: &str = "indexer.address";
+
+/// Tables with rows owned by an indexer.
+pub const ISOLATED_TABLES: [&str; 32] = [
+    "scalar_tap_receipts",
+    "scalar_tap_receipts_invalid",
+    "scalar_tap_ravs",
+    "scalar_tap_rav_history",
+    "scalar_tap_rav_requests_failed",
+    "scalar_tap_receipt_query_stats",
+    "scalar_tap_agent_checkpoint",
+    "scalar_tap_denylist",
+    "escrow_account_overrides",
+    "operator_audit_log",
+    "indexing_agreements",
+    "scalar_tap_allocation_closures",
+    "CostModels",
+    "cost_model_history",
+    "cost_model_templates",
+    "cost_model_template_deployments",
+    "accounting_statements",
+    "sender_statements",
+    "scalar_tap_fee_overflows",
+    "scalar_tap_vanished_allocations",
+    "scalar_tap_poi_gate_overrides",
+    "scalar_tap_trace_bundles",
+    "scalar_tap_redemption_advice",
+    "virtual_allocations",
+    "registered_allocations",
+    "admin_signature_nonces",
+    "indexer_events",
+    "indexing_agreement_ravs",
+    "scalar_tap_agent_allocation_checkpoints",
+    "scalar_tap_agent_shard_leases",
+    "scalar_tap_allocation_closure_batches",
+    "indexer_service_instance_stats",
+];
+
+/// Sets the indexer address on every connection of the pool.
+pub fn isolate(options: PgPoolOptions, indexer_address: Address) -> PgPoolOptions {
+    let indexer_address: String = indexer_address.encode_hex();
+    options.after_connect(move |conn, _| {
+        let indexer_address = indexer_address.clone();
+        Box::pin(async move {
+            sqlx::query("SELECT set_config($1, $2, false)")
+                .bind(INDEXER_ADDRESS_SETTING)
+                .bind(indexer_address)
+                .execute(conn)
+                .await?;
+            Ok(())
+        })
+    })
+}
+
+/// Assigns the rows written before the isolation was enabled, i.e. without an owner, to
+/// `indexer_address`, in a single transaction. The pool must not be isolated, as the sessions
+/// of an indexer don't see the rows without an owner. Only one indexer must be using the
+/// database when enabling it.
+pub async fn claim_unowned_rows(pgpool: &PgPool, indexer_address: Address) -> Result<u64> {
+    let indexer_address: String = indexer_address.encode_hex();
+    let mut tx = pgpool.begin().await?;
+    let mut claimed = 0;
+    for table in ISOLATED_TABLES {
+        claimed += sqlx::query(&format!(
+            r#"UPDATE "{table}" SET indexer_address = $1 WHERE indexer_address IS NULL"#
+        ))
+        .bind(&indexer_address)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+    }
+    tx.commit().await?;
+    info!(
+        claimed,
+        indexer_address, "Assigned the rows without an owner to the indexer"
+    );
+    Ok(claimed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use sqlx::postgres::PgConnectOptions;
+
+    use crate::events::{paused_senders, publish, Event};
+
+    use super::*;
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_claim_unowned_rows(
+        _pool_options: PgPoolOptions,
+        connect_options: PgConnectOptions,
+    ) {
+        let pgpool = PgPoolOptions::new()
+            .connect_with(connect_options.clone())
+            .await
+            .unwrap();
+        let indexer = Address::from([0x11; 20]);
+        let isolated = isolate(PgPoolOptions::new(), indexer)
+            .connect_with(connect_options.clone())
+            .await
+            .unwrap();
+        let other_isolated = isolate(PgPoolOptions::new(), Address::from([0x22; 20]))
+            .connect_with(connect_options)
+            .await
+            .unwrap();
+        let save_checkpoint = |pgpool: PgPool| async move {
+            sqlx::query(
+                r#"
+                    INSERT INTO scalar_tap_agent_checkpoint (last_receipt_id) VALUES (1)
+                    ON CONFLICT ((COALESCE(indexer_address, ''))) DO NOTHING
+                "#,
+            )
+            .execute(&pgpool)
+            .await
+            .unwrap()
+            .rows_affected()
+        };
+
+        // Written before the isolation, then by another indexer
+        assert_eq!(save_checkpoint(pgpool.clone()).await, 1);
+        assert_eq!(save_checkpoint(other_isolated).await, 1);
+
+        // Hidden from the indexers until it's claimed
+        let checkpoints = |pgpool: PgPool| async move {
+            sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM scalar_tap_agent_checkpoint")
+                .fetch_one(&pgpool)
+                .await
+                .unwrap()
+        };
+        assert_eq!(checkpoints(isolated.clone()).await, 0);
+        assert_eq!(claim_unowned_rows(&pgpool, indexer).await.unwrap(), 1);
+        assert_eq!(claim_unowned_rows(&pgpool, indexer).await.unwrap(), 0);
+        assert_eq!(checkpoints(isolated).await, 1);
+
+        let mut owners: Vec<Option<String>> =
+            sqlx::query_scalar("SELECT indexer_address FROM scalar_tap_agent_checkpoint")
+                .fetch_all(&pgpool)
+                .await
+                .unwrap();
+        owners.sort();
+        assert_eq!(
+            owners,
+            vec![
+                Some(indexer.encode_hex()),
+                Some(Address::from([0x22; 20]).encode_hex())
+            ]
+        );
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_isolated_events_and_checkpoints(
+        _pool_options: PgPoolOptions,
+        connect_options: PgConnectOptions,
+    ) {
+        let isolated = isolate(PgPoolOptions::new(), Address::from([0x11; 20]))
+            .connect_with(connect_options.clone())
+            .await
+            .unwrap();
+        let other_isolated = isolate(PgPoolOptions::new(), Address::from([0x22; 20]))
+            .connect_with(connect_options)
+            .await
+            .unwrap();
+        let sender = Address::from([0x33; 20]);
+
+        // Written by the first indexer
+        let event = Event::SenderPaused {
+            sender,
+            paused: true,
+            reason: None,
+        };
+        publish(&isolated, "tap-agent", &event).await.unwrap();
+        sqlx::query(
+            r#"
+                INSERT INTO scalar_tap_agent_allocation_checkpoints (
+                    sender_address, allocation_id, version, signers, last_receipt_id,
+                    unaggregated_value
+                )
+                VALUES ($1, $2, 1, '{}', 1, 10)
+            "#,
+        )
+        .bind(sender.encode_hex::<String>())
+        .bind(Address::from([0x44; 20]).encode_hex::<String>())
+        .execute(&isolated)
+        .await
+        .unwrap();
+
+        let rows = |pgpool: PgPool, table: &'static str| async move {
+            sqlx::query_scalar::<_, i64>(&format!("SELECT COUNT(*) FROM {table}"))
+                .fetch_one(&pgpool)
+                .await
+                .unwrap()
+        };
+        assert_eq!(
+            paused_senders(&isolated).await.unwrap(),
+            HashSet::from([sender])
+        );
+        assert_eq!(
+            rows(isolated, "scalar_tap_agent_allocation_checkpoints").await,
+            1
+        );
+
+        // Not seen by the second one, whose services keep accepting the sender
+        assert!(paused_senders(&other_isolated).await.unwrap().is_empty());
+        assert_eq!(rows(other_isolated.clone(), "indexer_events").await, 0);
+        assert_eq!(
+            rows(other_isolated, "scalar_tap_agent_allocation_checkpoints").await,
+            0
+        );
+    }
+}
@@ -1,13 +1,20 @@
 // Copyright 2023-, GraphOps and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
+//! Deterministic fixtures shared by the tests of this workspace, and golden EIP-712 vectors
+//! that downstream implementations (e.g. gateways) can check their signing against to stay
+//! byte-compatible with the indexer.
+
 use std::{collections::HashMap, str::FromStr};
 
+use alloy_primitives::{Bytes, B256};
 use alloy_sol_types::{eip712_domain, Eip712Domain};
 use ethers::signers::{coins_bip39::English, LocalWallet, MnemonicBuilder, Signer};
 use ethers_core::types::U256;
 use lazy_static::lazy_static;
+use serde::{Deserialize, Deserializer};
 use tap_core::{
+    rav::ReceiptAggregateVoucher,
     receipt::{Receipt, SignedReceipt},
     signed_message::EIP712SignedMessage,
 };
@@ -27,50 +34,6 @@ use crate::prelude::{Allocation, AllocationStatus, SubgraphDeployment};
 ///
 /// Using https://github.com/graphprotocol/indexer/blob/f8786c979a8ed0fae93202e499f5ce25773af473/packages/indexer-common/src/allocations/keys.ts#L41-L71
 
-pub const ESCROW_QUERY_RESPONSE: &str = r#"
-    {
-        "data": {
-            "escrowAccounts": [
-                {
-                    "balance": "34",
-                    "totalAmountThawing": "10",
-                    "sender": {
-                        "id": "0x9858EfFD232B4033E47d90003D41EC34EcaEda94",
-                        "signers": [
-                            {
-                                "id": "0x533661F0fb14d2E8B26223C86a610Dd7D2260892"
-                            },
-                            {
-                                "id": "0x2740f6fA9188cF53ffB6729DDD21575721dE92ce"
-                            }
-                        ]
-                    }
-                },
-                {
-                    "balance": "42",
-                    "totalAmountThawing": "0",
-                    "sender": {
-                        "id": "0x22d491bde2303f2f43325b2108d26f1eaba1e32b",
-                        "signers": [
-                            {
-                                "id": "0x245059163ff6ee14279aa7b35ea8f0fdb967df6e"
-                            }
-                        ]
-                    }
-                },
-                {
-                    "balance": "2987",
-                    "totalAmountThawing": "12",
-                    "sender": {
-                        "id": "0x192c3B6e0184Fa0Cc5B9D2bDDEb6B79Fb216a002",
-                        "signers": []
-                    }
-                }
-            ]
-        }
-    }
-"#;
-
 lazy_static! {
     pub static ref NETWORK_SUBGRAPH_DEPLOYMENT: DeploymentId = DeploymentId::from_str("QmU7zqJyHSyUP3yFii8sBtHT8FaJn2WmUnRvwjAUTjwMBP").unwrap();
     pub static ref ESCROW_SUBGRAPH_DEPLOYMENT: DeploymentId = DeploymentId::from_str("Qmb5Ysp5oCUXhLA8NmxmYKDAX2nCMnh7Vvb5uffb9n5vss").unwrap();
@@ -97,12 +60,15 @@ lazy_static! {
                 created_at_block_hash:
                     "0x99d3fbdc0105f7ccc0cd5bb287b82657fe92db4ea8fb58242dafb90b1c6e2adf".to_string(),
                 created_at_epoch: 953,
+                created_at: None,
                 closed_at_epoch: None,
+                closed_at: None,
                 subgraph_deployment: SubgraphDeployment {
                     id: DeploymentId::from_str(
                         "0xbbde25a2c85f55b53b7698b9476610c3d1202d88870e66502ab0076b7218f98a"
                     ).unwrap(),
                     denied_at: Some(0),
+                    network: None,
                 },
                 status: AllocationStatus::Null,
                 closed_at_epoch_start_block_hash: None,
@@ -121,12 +87,15 @@ lazy_static! {
                 created_at_block_hash:
                     "0x99d3fbdc0105f7ccc0cd5bb287b82657fe92db4ea8fb58242dafb90b1c6e2adf".to_string(),
                 created_at_epoch: 953,
+                created_at: None,
                 closed_at_epoch: None,
+                closed_at: None,
                 subgraph_deployment: SubgraphDeployment {
                     id: DeploymentId::from_str(
                         "0xcda7fa0405d6fd10721ed13d18823d24b535060d8ff661f862b26c23334f13bf"
                     ).unwrap(),
                     denied_at: Some(0),
+                    network: None,
                 },
                 status: AllocationStatus::Null,
                 closed_at_epoch_start_block_hash: None,
@@ -145,12 +114,15 @@ lazy_static! {
                 created_at_block_hash:
                     "0x6e7b7100c37f659236a029f87ce18914643995120f55ab5d01631f11f40fd887".to_string(),
                 created_at_epoch: 940,
+                created_at: None,
                 closed_at_epoch: Some(953),
+                closed_at: None,
                 subgraph_deployment: SubgraphDeployment {
                     id: DeploymentId::from_str(
                         "0xbbde25a2c85f55b53b7698b9476610c3d1202d88870e66502ab0076b7218f98a"
                     ).unwrap(),
                     denied_at: Some(0),
+                    network: None,
                 },
                 status: AllocationStatus::Null,
                 closed_at_epoch_start_block_hash: None,
@@ -169,12 +141,15 @@ lazy_static! {
                 created_at_block_hash:
                     "0x6e7b7100c37f659236a029f87ce18914643995120f55ab5d01631f11f40fd887".to_string(),
                 created_at_epoch: 940,
+                created_at: None,
                 closed_at_epoch: Some(953),
+                closed_at: None,
                 subgraph_deployment: SubgraphDeployment {
                     id: DeploymentId::from_str(
                         "0xc064c354bc21dd958b1d41b67b8ef161b75d2246b425f68ed4c74964ae705cbd"
                     ).unwrap(),
                     denied_at: Some(0),
+                    network: None,
                 },
                 status: AllocationStatus::Null,
                 closed_at_epoch_start_block_hash: None,
@@ -279,3 +254,137 @@ pub async fn create_signed_receipt(
     )
     .unwrap()
 }
+
+/// Golden EIP-712 vectors: receipts and RAVs signed by [`TAP_SIGNER`] under
+/// [`TAP_EIP712_DOMAIN`], with their expected signing hashes and signatures. Integers are
+/// encoded as decimal strings so that the file can be consumed from any language.
+pub const TAP_EIP712_GOLDEN_VECTORS: &str = include_str!("../test_vectors/tap_eip712.json");
+
+#[derive(Debug, Deserialize)]
+pub struct GoldenVectors {
+    pub domain: GoldenDomain,
+    pub signer: Address,
+    pub receipts: Vec<GoldenVector<GoldenReceipt>>,
+    pub ravs: Vec<GoldenVector<GoldenRav>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GoldenDomain {
+    pub name: String,
+    pub version: String,
+    #[serde(deserialize_with = "from_decimal_str")]
+    pub chain_id: u64,
+    pub verifying_contract: Address,
+    pub separator: B256,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GoldenVector<M> {
+    pub message: M,
+    pub signing_hash: B256,
+    /// 65 bytes signature: r, s and v (27 or 28).
+    pub signature: Bytes,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GoldenReceipt {
+    pub allocation_id: Address,
+    #[serde(deserialize_with = "from_decimal_str")]
+    pub timestamp_ns: u64,
+    #[serde(deserialize_with = "from_decimal_str")]
+    pub nonce: u64,
+    #[serde(deserialize_with = "from_decimal_str")]
+    pub value: u128,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GoldenRav {
+    pub allocation_id: Address,
+    #[serde(deserialize_with = "from_decimal_str")]
+    pub timestamp_ns: u64,
+    #[serde(deserialize_with = "from_decimal_str")]
+    pub value_aggregate: u128,
+}
+
+impl From<&GoldenReceipt> for Receipt {
+    fn from(value: &GoldenReceipt) -> Self {
+        Receipt {
+            allocation_id: value.allocation_id,
+            timestamp_ns: value.timestamp_ns,
+            nonce: value.nonce,
+            value: value.value,
+        }
+    }
+}
+
+impl From<&GoldenRav> for ReceiptAggregateVoucher {
+    fn from(value: &GoldenRav) -> Self {
+        ReceiptAggregateVoucher {
+            allocationId: value.allocation_id,
+            timestampNs: value.timestamp_ns,
+            valueAggregate: value.value_aggregate,
+        }
+    }
+}
+
+impl GoldenDomain {
+    pub fn eip712_domain(&self) -> Eip712Domain {
+        Eip712Domain::new(
+            Some(self.name.clone().into()),
+            Some(self.version.clone().into()),
+            Some(alloy_primitives::U256::from(self.chain_id)),
+            Some(self.verifying_contract),
+            None,
+        )
+    }
+}
+
+pub fn tap_eip712_golden_vectors() -> GoldenVectors {
+    serde_json::from_str(TAP_EIP712_GOLDEN_VECTORS).expect("Invalid golden vectors")
+}
+
+fn from_decimal_str<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    String::deserialize(deserializer)?
+        .parse()
+        .map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy_sol_types::SolStruct;
+
+    use super::*;
+
+    #[test]
+    fn test_tap_eip712_golden_vectors() {
+        let golden = tap_eip712_golden_vectors();
+        let domain = golden.domain.eip712_domain();
+        assert_eq!(domain, *TAP_EIP712_DOMAIN);
+        assert_eq!(domain.separator(), golden.domain.separator);
+        assert_eq!(TAP_SIGNER.1, golden.signer);
+
+        for vector in &golden.receipts {
+            let receipt = Receipt::from(&vector.message);
+            assert_eq!(receipt.eip712_signing_hash(&domain), vector.signing_hash);
+
+            let signed = EIP712SignedMessage::new(&domain, receipt, &TAP_SIGNER.0).unwrap();
+            assert_eq!(signed.signature.to_vec(), vector.signature.to_vec());
+            assert_eq!(signed.recover_signer(&domain).unwrap(), golden.signer);
+        }
+
+        for vector in &golden.ravs {
+            let rav = ReceiptAggregateVoucher::from(&vector.message);
+            assert_eq!(rav.eip712_signing_hash(&domain), vector.signing_hash);
+
+            let signed = EIP712SignedMessage::new(&domain, rav, &TAP_SIGNER.0).unwrap();
+            assert_eq!(signed.signature.to_vec(), vector.signature.to_vec());
+            assert_eq!(signed.recover_signer(&domain).unwrap(), golden.signer);
+        }
+    }
+}
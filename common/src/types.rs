@@ -0,0 +1,321 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Newtypes for the amounts and addresses stored in the database. Amounts are GRT wei in
+//! `NUMERIC` columns and addresses are lowercase hex without `0x` in `CHAR(40)` columns. Binding
+//! and reading them through these types, rather than converting by hand at every query, keeps
+//! the encoding in one place and prevents e.g. binding an allocation id where a sender is
+//! expected.
+
+use std::fmt;
+use std::iter::Sum;
+use std::ops::{Add, AddAssign, Deref};
+use std::str::FromStr;
+
+use alloy_primitives::hex::ToHex;
+use bigdecimal::num_bigint::BigInt;
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    encode::IsNull,
+    error::BoxDynError,
+    postgres::{PgArgumentBuffer, PgHasArrayType, PgTypeInfo, PgValueRef},
+    types::BigDecimal,
+    Decode, Encode, Postgres, Type,
+};
+use thegraph::types::Address;
+
+/// An amount of GRT, in wei.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct GrtWei(pub u128);
+
+impl GrtWei {
+    pub const ZERO: Self = Self(0);
+
+    pub fn checked_add(self, other: Self) -> Option<Self> {
+        self.0.checked_add(other.0).map(Self)
+    }
+
+    pub fn saturating_sub(self, other: Self) -> Self {
+        Self(self.0.saturating_sub(other.0))
+    }
+}
+
+impl From<u128> for GrtWei {
+    fn from(value: u128) -> Self {
+        Self(value)
+    }
+}
+
+impl From<GrtWei> for u128 {
+    fn from(value: GrtWei) -> Self {
+        value.0
+    }
+}
+
+impl From<GrtWei> for BigDecimal {
+    fn from(value: GrtWei) -> Self {
+        BigDecimal::from(BigInt::from(value.0))
+    }
+}
+
+impl TryFrom<&BigDecimal> for GrtWei {
+    type Error = BoxDynError;
+
+    fn try_from(value: &BigDecimal) -> Result<Self, Self::Error> {
+        if !value.is_integer() {
+            return Err(format!("GRT wei amount `{}` is not an integer", value).into());
+        }
+        Ok(Self(value.with_scale(0).to_string().parse()?))
+    }
+}
+
+impl FromStr for GrtWei {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(Self)
+    }
+}
+
+impl fmt::Display for GrtWei {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl Add for GrtWei {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self(self.0 + other.0)
+    }
+}
+
+impl AddAssign for GrtWei {
+    fn add_assign(&mut self, other: Self) {
+        self.0 += other.0;
+    }
+}
+
+impl Sum for GrtWei {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(Self::ZERO, Add::add)
+    }
+}
+
+/// Serialized as a decimal string, JSON numbers can't hold every `u128`.
+impl Serialize for GrtWei {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for GrtWei {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl Type<Postgres> for GrtWei {
+    fn type_info() -> PgTypeInfo {
+        <BigDecimal as Type<Postgres>>::type_info()
+    }
+
+    fn compatible(ty: &PgTypeInfo) -> bool {
+        <BigDecimal as Type<Postgres>>::compatible(ty)
+    }
+}
+
+impl PgHasArrayType for GrtWei {
+    fn array_type_info() -> PgTypeInfo {
+        <BigDecimal as PgHasArrayType>::array_type_info()
+    }
+}
+
+impl Encode<'_, Postgres> for GrtWei {
+    fn encode_by_ref(&self, buf: &mut PgArgumentBuffer) -> IsNull {
+        <BigDecimal as Encode<Postgres>>::encode(BigDecimal::from(*self), buf)
+    }
+}
+
+impl<'r> Decode<'r, Postgres> for GrtWei {
+    fn decode(value: PgValueRef<'r>) -> Result<Self, BoxDynError> {
+        GrtWei::try_from(&<BigDecimal as Decode<Postgres>>::decode(value)?)
+    }
+}
+
+macro_rules! address_newtype {
+    ($(#[$meta:meta])* $name:ident) => {
+        $(#[$meta])*
+        #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        pub struct $name(pub Address);
+
+        impl $name {
+            /// Lowercase hex without `0x`, as stored in the database.
+            pub fn to_hex(&self) -> String {
+                self.0.encode_hex::<String>()
+            }
+        }
+
+        impl From<Address> for $name {
+            fn from(address: Address) -> Self {
+                Self(address)
+            }
+        }
+
+        impl From<$name> for Address {
+            fn from(value: $name) -> Self {
+                value.0
+            }
+        }
+
+        impl Deref for $name {
+            type Target = Address;
+
+            fn deref(&self) -> &Address {
+                &self.0
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = <Address as FromStr>::Err;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                Address::from_str(s.trim_end()).map(Self)
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                self.0.fmt(f)
+            }
+        }
+
+        impl Type<Postgres> for $name {
+            fn type_info() -> PgTypeInfo {
+                <String as Type<Postgres>>::type_info()
+            }
+
+            fn compatible(ty: &PgTypeInfo) -> bool {
+                <String as Type<Postgres>>::compatible(ty)
+            }
+        }
+
+        impl PgHasArrayType for $name {
+            fn array_type_info() -> PgTypeInfo {
+                <String as PgHasArrayType>::array_type_info()
+            }
+
+            fn array_compatible(ty: &PgTypeInfo) -> bool {
+                <String as PgHasArrayType>::array_compatible(ty)
+            }
+        }
+
+        impl Encode<'_, Postgres> for $name {
+            fn encode_by_ref(&self, buf: &mut PgArgumentBuffer) -> IsNull {
+                <String as Encode<Postgres>>::encode(self.to_hex(), buf)
+            }
+        }
+
+        impl<'r> Decode<'r, Postgres> for $name {
+            fn decode(value: PgValueRef<'r>) -> Result<Self, BoxDynError> {
+                Ok(<&str as Decode<Postgres>>::decode(value)?.parse()?)
+            }
+        }
+    };
+}
+
+address_newtype!(
+    /// Id of an allocation, i.e. the address of its allocation key.
+    AllocationIdHex
+);
+
+address_newtype!(
+    /// Address of a sender, i.e. the owner of an escrow account, as opposed to its signers.
+    SenderAddress
+);
+
+#[cfg(test)]
+mod tests {
+    use sqlx::{PgPool, Row};
+
+    use super::*;
+
+    #[test]
+    fn test_grt_wei_conversions() {
+        let value = GrtWei(u128::MAX);
+        assert_eq!(
+            GrtWei::try_from(&BigDecimal::from(value)).unwrap(),
+            GrtWei(u128::MAX)
+        );
+        assert_eq!(
+            GrtWei::try_from(&BigDecimal::from_str("100.000").unwrap()).unwrap(),
+            GrtWei(100)
+        );
+        assert!(GrtWei::try_from(&BigDecimal::from_str("1.5").unwrap()).is_err());
+        assert!(GrtWei::try_from(&BigDecimal::from(-1)).is_err());
+
+        assert_eq!(
+            serde_json::to_string(&value).unwrap(),
+            format!("\"{}\"", u128::MAX)
+        );
+        assert_eq!(
+            serde_json::from_str::<GrtWei>("\"42\"").unwrap(),
+            GrtWei(42)
+        );
+        assert_eq!(
+            [GrtWei(1), GrtWei(2)].into_iter().sum::<GrtWei>(),
+            GrtWei(3)
+        );
+    }
+
+    #[test]
+    fn test_address_conversions() {
+        let address = Address::from_str("0xfa44c72b753a66591f241c7dc04e8178c30e13af").unwrap();
+        let allocation_id = AllocationIdHex::from(address);
+        assert_eq!(
+            allocation_id.to_hex(),
+            "fa44c72b753a66591f241c7dc04e8178c30e13af"
+        );
+        assert_eq!(
+            AllocationIdHex::from_str("fa44c72b753a66591f241c7dc04e8178c30e13af").unwrap(),
+            allocation_id
+        );
+        assert_eq!(Address::from(SenderAddress::from(address)), address);
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_database_round_trip(pgpool: PgPool) {
+        let sender = SenderAddress(Address::from([0x11; 20]));
+        let allocation_id = AllocationIdHex(Address::from([0x22; 20]));
+        let value = GrtWei(u128::MAX);
+
+        let row = sqlx::query(
+            r#"
+                SELECT $1::CHAR(40) AS sender, $2::CHAR(40) AS allocation_id,
+                    $3::NUMERIC(39) AS value, $4::CHAR(40)[] AS senders
+            "#,
+        )
+        .bind(sender)
+        .bind(allocation_id)
+        .bind(value)
+        .bind(vec![sender, sender])
+        .fetch_one(&pgpool)
+        .await
+        .unwrap();
+
+        assert_eq!(row.get::<String, _>("sender"), "11".repeat(20));
+        assert_eq!(row.get::<SenderAddress, _>("sender"), sender);
+        assert_eq!(
+            row.get::<AllocationIdHex, _>("allocation_id"),
+            allocation_id
+        );
+        assert_eq!(row.get::<GrtWei, _>("value"), value);
+        assert_eq!(
+            row.get::<Vec<SenderAddress>, _>("senders"),
+            vec![sender, sender]
+        );
+    }
+}
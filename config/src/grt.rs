@@ -2,6 +2,11 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use bigdecimal::{BigDecimal, ToPrimitive};
+use schemars::{
+    gen::SchemaGenerator,
+    schema::{InstanceType, Schema, SchemaObject},
+    JsonSchema,
+};
 use serde::{de::Error, Deserialize};
 
 #[derive(Debug, PartialEq)]
@@ -33,6 +38,25 @@ impl<'de> Deserialize<'de> for NonZeroGRT {
     }
 }
 
+impl JsonSchema for NonZeroGRT {
+    fn schema_name() -> String {
+        "NonZeroGRT".to_string()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        decimal_schema(gen)
+    }
+}
+
+/// Decimal values, given as strings to prevent rounding errors, or as numbers.
+pub(crate) fn decimal_schema(_: &mut SchemaGenerator) -> Schema {
+    SchemaObject {
+        instance_type: Some(vec![InstanceType::String, InstanceType::Number].into()),
+        ..Default::default()
+    }
+    .into()
+}
+
 #[cfg(test)]
 mod tests {
     use serde_test::{assert_de_tokens, assert_de_tokens_error, Token};
@@ -3,6 +3,8 @@
 
 mod config;
 mod grt;
+mod schema;
 
 pub use config::*;
 pub use grt::*;
+pub use schema::*;
@@ -0,0 +1,133 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! JSON Schema of the configuration file, generated from the types it's deserialized into so
+//! that it can't drift from them, for the deployment tooling to validate the configurations
+//! before rolling them out, e.g. the values of a Helm chart.
+
+use figment::{providers::Toml, Figment};
+use schemars::gen::SchemaSettings;
+use serde_json::Value;
+
+use crate::Config;
+
+/// JSON Schema of the configuration file of both the indexer-service and the tap-agent.
+///
+/// The values of `default_values.toml` are not required, and are given as the defaults of
+/// their properties.
+pub fn config_schema() -> Value {
+    let generator = SchemaSettings::draft07()
+        .with(|settings| {
+            // Self-contained, the deployment tooling doesn't have to resolve references
+            settings.inline_subschemas = true;
+        })
+        .into_generator();
+    let mut schema = serde_json::to_value(generator.into_root_schema_for::<Config>())
+        .expect("the configuration schema serializes to JSON");
+
+    let defaults: Value = Figment::new()
+        .merge(Toml::string(include_str!("../default_values.toml")))
+        .extract()
+        .expect("the default values are valid TOML");
+    apply_defaults(&mut schema, &defaults);
+    schema
+}
+
+fn apply_defaults(schema: &mut Value, defaults: &Value) {
+    let Some(defaults) = defaults.as_object() else {
+        return;
+    };
+    for (key, default) in defaults {
+        let Some(property) = schema.pointer_mut(&format!("/properties/{key}")) else {
+            continue;
+        };
+        if default.is_object() {
+            apply_defaults(property, default);
+            // Still required if only some of its properties have defaults
+            if property.get("required").is_some() {
+                continue;
+            }
+        } else if let Some(property) = property.as_object_mut() {
+            property.insert("default".to_string(), default.clone());
+        }
+        if let Some(required) = schema.get_mut("required").and_then(Value::as_array_mut) {
+            required.retain(|required| required.as_str() != Some(key));
+        }
+    }
+    if schema
+        .get("required")
+        .and_then(Value::as_array)
+        .is_some_and(Vec::is_empty)
+    {
+        schema.as_object_mut().unwrap().remove("required");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use serde_json::{json, Value};
+
+    use super::config_schema;
+
+    /// Whether the properties of `value` are all in `schema`, trying each of the variants of
+    /// the optional sections and of the enums.
+    fn has_properties(schema: &Value, value: &Value) -> bool {
+        if let Some(variants) = schema.get("anyOf").or_else(|| schema.get("oneOf")) {
+            return variants
+                .as_array()
+                .unwrap()
+                .iter()
+                .any(|variant| has_properties(variant, value));
+        }
+        let Some(object) = value.as_object() else {
+            return true;
+        };
+        object.iter().all(|(key, value)| {
+            match schema
+                .pointer(&format!("/properties/{key}"))
+                .or_else(|| schema.get("additionalProperties").filter(|s| s.is_object()))
+            {
+                Some(property) => has_properties(property, value),
+                None => false,
+            }
+        })
+    }
+
+    #[test]
+    fn test_config_schema() {
+        let schema = config_schema();
+
+        let required = &schema["required"];
+        assert!(required.as_array().unwrap().contains(&json!("indexer")));
+        // Only some of its properties have defaults
+        assert!(required.as_array().unwrap().contains(&json!("graph_node")));
+        // Only has defaults
+        assert!(!required.as_array().unwrap().contains(&json!("metrics")));
+        assert_eq!(
+            schema["properties"]["metrics"]["properties"]["port"]["default"],
+            7300
+        );
+        assert_eq!(
+            schema["properties"]["database"]["required"],
+            json!(["postgres_url"])
+        );
+        assert_eq!(
+            schema["properties"]["subgraphs"]["properties"]["network"]["properties"]
+                ["syncing_interval_secs"]["type"],
+            "number"
+        );
+    }
+
+    #[test]
+    fn test_config_schema_covers_maximal_config() {
+        let maximal_config: Value = toml::from_str(
+            fs::read_to_string("maximal-config-example.toml")
+                .unwrap()
+                .as_str(),
+        )
+        .unwrap();
+        assert!(has_properties(&config_schema(), &maximal_config));
+    }
+}
@@ -0,0 +1 @@
+DROP TABLE IF EXISTS scalar_tap_agent_checkpoint CASCADE;
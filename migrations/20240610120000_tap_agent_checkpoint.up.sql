@@ -0,0 +1,8 @@
+-- Single row table holding the id of the last receipt processed by the tap-agent, so
+-- that receipts stored by the indexer-service while the agent was down can be caught up
+-- on at startup.
+CREATE TABLE IF NOT EXISTS scalar_tap_agent_checkpoint (
+    id BOOLEAN PRIMARY KEY DEFAULT TRUE CHECK (id),
+    last_receipt_id BIGINT NOT NULL,
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS indexing_agreement_ravs CASCADE;
+DROP TABLE IF EXISTS indexing_agreements CASCADE;
@@ -0,0 +1,36 @@
+-- Indexing agreements signed by senders to pay indexers directly for indexing a deployment,
+-- along with the fees accrued from the progress reported by the indexer.
+CREATE TABLE IF NOT EXISTS indexing_agreements (
+    agreement_id CHAR(64) PRIMARY KEY,
+    signer_address CHAR(40) NOT NULL,
+
+    -- Values below are the individual fields of the EIP-712 agreement
+    signature BYTEA NOT NULL,
+    allocation_id CHAR(40) NOT NULL,
+    deployment_id CHAR(64) NOT NULL,
+    price_per_entity NUMERIC(39) NOT NULL,
+    price_per_block NUMERIC(39) NOT NULL,
+
+    entities_processed NUMERIC(20) NOT NULL DEFAULT 0,
+    blocks_processed NUMERIC(20) NOT NULL DEFAULT 0,
+    accrued_fees NUMERIC(39) NOT NULL DEFAULT 0,
+
+    cancelled_at TIMESTAMP WITH TIME ZONE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+-- Latest RAV received for each indexing agreement.
+CREATE TABLE IF NOT EXISTS indexing_agreement_ravs (
+    agreement_id CHAR(64) PRIMARY KEY REFERENCES indexing_agreements (agreement_id),
+    sender_address CHAR(40) NOT NULL,
+
+    -- Values below are the individual fields of the EIP-712 RAV
+    signature BYTEA NOT NULL,
+    allocation_id CHAR(40) NOT NULL,
+    timestamp_ns NUMERIC(20) NOT NULL,
+    value_aggregate NUMERIC(39) NOT NULL,
+
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS scalar_tap_agent_allocation_checkpoints CASCADE;
+ALTER TABLE scalar_tap_agent_checkpoint DROP COLUMN IF EXISTS version;
@@ -0,0 +1,21 @@
+-- Format version of the checkpoint, checkpoints written with another version are discarded.
+ALTER TABLE scalar_tap_agent_checkpoint ADD COLUMN IF NOT EXISTS version INTEGER NOT NULL DEFAULT 1;
+
+-- State of each (sender, allocation) pair of the tap-agent, saved on shutdown so that the
+-- unaggregated fees can be resumed from it instead of being recomputed from all the receipts.
+CREATE TABLE IF NOT EXISTS scalar_tap_agent_allocation_checkpoints (
+    sender_address CHAR(40) NOT NULL,
+    allocation_id CHAR(40) NOT NULL,
+    version INTEGER NOT NULL,
+    -- Signers of the sender the unaggregated fees were computed for.
+    signers TEXT[] NOT NULL,
+    last_receipt_id BIGINT NOT NULL,
+    unaggregated_value NUMERIC(39) NOT NULL,
+    -- Timestamp of the last RAV when the checkpoint was saved, if any.
+    rav_timestamp_ns NUMERIC(20),
+    -- Set while a RAV request is running, so that an interrupted request invalidates the
+    -- checkpoint.
+    rav_request_in_flight BOOLEAN NOT NULL DEFAULT FALSE,
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (sender_address, allocation_id)
+);
@@ -0,0 +1,21 @@
+-- Resources used to serve each paid query, stored next to the receipt that paid for it so that
+-- indexers can compare the fees they charge against the work actually done. Kept in a separate
+-- table since receipts are deleted once aggregated into a RAV.
+CREATE TABLE IF NOT EXISTS scalar_tap_receipt_query_stats (
+    id BIGSERIAL PRIMARY KEY,
+    deployment_id CHAR(46) NOT NULL,
+
+    -- Identify the receipt that paid for the query
+    allocation_id CHAR(40) NOT NULL,
+    timestamp_ns NUMERIC(20) NOT NULL,
+    nonce NUMERIC(20) NOT NULL,
+    value NUMERIC(39) NOT NULL,
+
+    response_bytes BIGINT NOT NULL,
+    -- Time graph-node took to answer the query, if known
+    execution_time_ms BIGINT,
+
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS scalar_tap_receipt_query_stats_deployment_idx ON scalar_tap_receipt_query_stats (deployment_id);
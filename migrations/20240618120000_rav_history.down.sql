@@ -0,0 +1,4 @@
+ALTER TABLE scalar_tap_rav_requests_failed DROP COLUMN IF EXISTS created_at;
+DROP TRIGGER IF EXISTS scalar_tap_rav_history_trigger ON scalar_tap_ravs;
+DROP FUNCTION IF EXISTS scalar_tap_rav_history_record;
+DROP TABLE IF EXISTS scalar_tap_rav_history CASCADE;
@@ -0,0 +1,48 @@
+-- Every RAV stored in `scalar_tap_ravs`, which only keeps the latest one of each allocation and
+-- sender. Filled by a trigger so that all the writers of `scalar_tap_ravs` are covered, including
+-- the indexer-agent when it marks RAVs as final.
+CREATE TABLE IF NOT EXISTS scalar_tap_rav_history (
+    id BIGSERIAL PRIMARY KEY,
+    sender_address CHAR(40) NOT NULL,
+
+    -- Values below are the individual fields of the EIP-712 RAV
+    signature BYTEA NOT NULL,
+    allocation_id CHAR(40) NOT NULL,
+    timestamp_ns NUMERIC(20) NOT NULL,
+    value_aggregate NUMERIC(39) NOT NULL,
+
+    last BOOLEAN NOT NULL,
+    final BOOLEAN NOT NULL,
+    recorded_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS scalar_tap_rav_history_sender_idx ON scalar_tap_rav_history (sender_address, id);
+
+CREATE OR REPLACE FUNCTION scalar_tap_rav_history_record()
+RETURNS TRIGGER AS $$
+BEGIN
+    INSERT INTO scalar_tap_rav_history (
+        sender_address, signature, allocation_id, timestamp_ns, value_aggregate, last, final
+    )
+    VALUES (
+        NEW.sender_address, NEW.signature, NEW.allocation_id, NEW.timestamp_ns,
+        NEW.value_aggregate, NEW.last, NEW.final
+    );
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER scalar_tap_rav_history_trigger
+    AFTER INSERT OR UPDATE ON scalar_tap_ravs
+    FOR EACH ROW
+    EXECUTE FUNCTION scalar_tap_rav_history_record();
+
+INSERT INTO scalar_tap_rav_history (
+    sender_address, signature, allocation_id, timestamp_ns, value_aggregate, last, final
+)
+SELECT sender_address, signature, allocation_id, timestamp_ns, value_aggregate, last, final
+FROM scalar_tap_ravs;
+
+-- To filter failed RAV requests by time
+ALTER TABLE scalar_tap_rav_requests_failed
+    ADD COLUMN IF NOT EXISTS created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW();
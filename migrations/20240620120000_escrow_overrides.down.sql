@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS operator_audit_log;
+DROP TABLE IF EXISTS escrow_account_overrides;
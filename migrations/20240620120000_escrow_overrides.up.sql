@@ -0,0 +1,22 @@
+-- Operator overrides of the escrow balance and signers of a sender, set when the escrow
+-- subgraph can't be trusted and the balances were verified out-of-band. An override only
+-- applies until it expires.
+CREATE TABLE IF NOT EXISTS escrow_account_overrides (
+    sender_address CHAR(40) PRIMARY KEY,
+    -- NULL keeps the value from the escrow subgraph
+    balance NUMERIC(78),
+    signers CHAR(40)[],
+    reason TEXT NOT NULL,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+-- Operator actions changing how the indexer handles payments, with the reason given.
+CREATE TABLE IF NOT EXISTS operator_audit_log (
+    id BIGSERIAL PRIMARY KEY,
+    action TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    reason TEXT NOT NULL,
+    details JSONB NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1 @@
+ALTER TABLE scalar_tap_receipt_query_stats DROP COLUMN IF EXISTS price_multiplier;
@@ -0,0 +1,3 @@
+-- Price multiplier of the sender that paid for the query, see `sender_price_multipliers`
+ALTER TABLE scalar_tap_receipt_query_stats
+    ADD COLUMN IF NOT EXISTS price_multiplier DOUBLE PRECISION NOT NULL DEFAULT 1;
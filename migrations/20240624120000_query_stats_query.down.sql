@@ -0,0 +1,3 @@
+ALTER TABLE scalar_tap_receipt_query_stats
+    DROP COLUMN IF EXISTS query,
+    DROP COLUMN IF EXISTS variables;
@@ -0,0 +1,5 @@
+-- GraphQL query and variables of each paid query, replayed against candidate cost models to
+-- simulate their effect before deploying them
+ALTER TABLE scalar_tap_receipt_query_stats
+    ADD COLUMN IF NOT EXISTS query TEXT,
+    ADD COLUMN IF NOT EXISTS variables TEXT;
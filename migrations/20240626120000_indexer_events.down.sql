@@ -0,0 +1,7 @@
+DROP TRIGGER IF EXISTS cost_model_events ON "CostModels";
+DROP FUNCTION IF EXISTS indexer_events_cost_models;
+DROP TRIGGER IF EXISTS denylist_events ON scalar_tap_denylist;
+DROP FUNCTION IF EXISTS indexer_events_denylist;
+DROP TRIGGER IF EXISTS indexer_events_insert ON indexer_events;
+DROP FUNCTION IF EXISTS indexer_events_notify;
+DROP TABLE IF EXISTS indexer_events;
@@ -0,0 +1,70 @@
+-- Typed events shared by indexer-service and tap-agent, for each to react to the other's
+-- changes without polling. Listeners are notified of the id of every new event on the
+-- `indexer_events` channel and read the events from the table, so that none is lost while
+-- they reconnect.
+CREATE TABLE IF NOT EXISTS indexer_events (
+    id BIGSERIAL PRIMARY KEY,
+    kind TEXT NOT NULL,
+    payload JSONB NOT NULL,
+    -- Process that published the event, or `database` for the events of the triggers below
+    source TEXT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE FUNCTION indexer_events_notify()
+RETURNS trigger AS
+$$
+BEGIN
+    PERFORM pg_notify('indexer_events', NEW.id::text);
+    RETURN NEW;
+END;
+$$ LANGUAGE 'plpgsql';
+
+CREATE TRIGGER indexer_events_insert AFTER INSERT
+    ON indexer_events
+    FOR EACH ROW EXECUTE PROCEDURE indexer_events_notify();
+
+CREATE FUNCTION indexer_events_denylist()
+RETURNS trigger AS
+$$
+BEGIN
+    IF TG_OP = 'DELETE' THEN
+        INSERT INTO indexer_events (kind, payload, source)
+        VALUES ('denylist_updated', json_build_object('sender', '0x' || OLD.sender_address, 'denied', false), 'database');
+        RETURN OLD;
+    END IF;
+    INSERT INTO indexer_events (kind, payload, source)
+    VALUES ('denylist_updated', json_build_object('sender', '0x' || NEW.sender_address, 'denied', true), 'database');
+    RETURN NEW;
+END;
+$$ LANGUAGE 'plpgsql';
+
+CREATE TRIGGER denylist_events AFTER INSERT OR DELETE
+    ON scalar_tap_denylist
+    FOR EACH ROW EXECUTE PROCEDURE indexer_events_denylist();
+
+-- The global cost model is reported without a deployment
+CREATE FUNCTION indexer_events_cost_models()
+RETURNS trigger AS
+$$
+DECLARE
+    changed_deployment VARCHAR;
+BEGIN
+    IF TG_OP = 'DELETE' THEN
+        changed_deployment := OLD.deployment;
+    ELSE
+        changed_deployment := NEW.deployment;
+    END IF;
+    INSERT INTO indexer_events (kind, payload, source)
+    VALUES (
+        'cost_model_changed',
+        json_build_object('deployment', NULLIF(changed_deployment, 'global')),
+        'database'
+    );
+    RETURN NULL;
+END;
+$$ LANGUAGE 'plpgsql';
+
+CREATE TRIGGER cost_model_events AFTER INSERT OR UPDATE OR DELETE
+    ON "CostModels"
+    FOR EACH ROW EXECUTE PROCEDURE indexer_events_cost_models();
@@ -0,0 +1,11 @@
+-- Accounting statements exported for ERP systems. A period is locked once exported: exporting it
+-- again returns the stored statement, and no overlapping period can be exported, so that the
+-- statements never change after being booked.
+CREATE TABLE IF NOT EXISTS accounting_statements (
+    id BIGSERIAL PRIMARY KEY,
+    period_start TIMESTAMP WITH TIME ZONE NOT NULL,
+    period_end TIMESTAMP WITH TIME ZONE NOT NULL,
+    statement JSONB NOT NULL,
+    exported_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    UNIQUE (period_start, period_end)
+);
@@ -0,0 +1 @@
+ALTER TABLE scalar_tap_rav_requests_failed DROP COLUMN IF EXISTS anomaly;
@@ -0,0 +1,4 @@
+-- Class of the anomaly for the aggregator responses rejected before verifying the RAV, e.g.
+-- `malformed` or `value_regression`, in which case `rav_response` is the raw response. NULL for
+-- the RAVs that failed verification.
+ALTER TABLE scalar_tap_rav_requests_failed ADD COLUMN IF NOT EXISTS anomaly TEXT;
@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS scalar_tap_allocation_closures;
+DROP TABLE IF EXISTS scalar_tap_allocation_closure_batches;
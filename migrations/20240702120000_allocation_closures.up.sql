@@ -0,0 +1,26 @@
+-- Progress of the closures of the allocations, the last RAV request of each of their senders.
+-- Closures started while others are still running are grouped in the same batch, whose summary
+-- is stored once all of its closures are done. Persisted so that closures interrupted by a
+-- restart resume in their batch.
+CREATE TABLE IF NOT EXISTS scalar_tap_allocation_closure_batches (
+    id BIGSERIAL PRIMARY KEY,
+    started_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    finished_at TIMESTAMP WITH TIME ZONE,
+    summary JSONB
+);
+
+CREATE TABLE IF NOT EXISTS scalar_tap_allocation_closures (
+    sender_address CHAR(40) NOT NULL,
+    allocation_id CHAR(40) NOT NULL,
+    batch_id BIGINT NOT NULL REFERENCES scalar_tap_allocation_closure_batches (id),
+    -- `pending`, `requesting_rav`, `marking_last` or `closed`
+    state TEXT NOT NULL,
+    failed_attempts INT NOT NULL DEFAULT 0,
+    last_error TEXT,
+    -- Value of the last RAV, once closed
+    final_value NUMERIC(39),
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (sender_address, allocation_id)
+);
+
+CREATE INDEX IF NOT EXISTS scalar_tap_allocation_closures_batch_idx ON scalar_tap_allocation_closures (batch_id);
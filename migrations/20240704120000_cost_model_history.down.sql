@@ -0,0 +1,3 @@
+DROP TRIGGER IF EXISTS cost_model_history ON "CostModels";
+DROP FUNCTION IF EXISTS cost_model_history_record;
+DROP TABLE IF EXISTS cost_model_history;
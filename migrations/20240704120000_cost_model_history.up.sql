@@ -0,0 +1,50 @@
+-- Every change of the cost models, recorded by a trigger so that the changes of indexer-agent
+-- are tracked along with the rollbacks of indexer-service. The author of a change is the
+-- `indexer.actor` setting of its transaction if set, or else the database user.
+CREATE TABLE IF NOT EXISTS cost_model_history (
+    id BIGSERIAL PRIMARY KEY,
+    deployment VARCHAR NOT NULL,
+    -- `insert`, `update` or `delete`
+    change TEXT NOT NULL,
+    old_model TEXT,
+    old_variables JSONB,
+    new_model TEXT,
+    new_variables JSONB,
+    changed_by TEXT NOT NULL,
+    changed_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS cost_model_history_deployment
+    ON cost_model_history (deployment, id);
+
+CREATE FUNCTION cost_model_history_record()
+RETURNS trigger AS
+$$
+DECLARE
+    author TEXT := COALESCE(NULLIF(current_setting('indexer.actor', true), ''), current_user);
+BEGIN
+    IF TG_OP = 'INSERT' THEN
+        INSERT INTO cost_model_history (deployment, change, new_model, new_variables, changed_by)
+        VALUES (NEW.deployment, 'insert', NEW.model, NEW.variables, author);
+    ELSIF TG_OP = 'UPDATE' THEN
+        IF OLD.model IS NOT DISTINCT FROM NEW.model
+            AND OLD.variables IS NOT DISTINCT FROM NEW.variables THEN
+            RETURN NULL;
+        END IF;
+        INSERT INTO cost_model_history (
+            deployment, change, old_model, old_variables, new_model, new_variables, changed_by
+        )
+        VALUES (
+            NEW.deployment, 'update', OLD.model, OLD.variables, NEW.model, NEW.variables, author
+        );
+    ELSE
+        INSERT INTO cost_model_history (deployment, change, old_model, old_variables, changed_by)
+        VALUES (OLD.deployment, 'delete', OLD.model, OLD.variables, author);
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE 'plpgsql';
+
+CREATE TRIGGER cost_model_history AFTER INSERT OR UPDATE OR DELETE
+    ON "CostModels"
+    FOR EACH ROW EXECUTE PROCEDURE cost_model_history_record();
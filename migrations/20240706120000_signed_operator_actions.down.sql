@@ -0,0 +1,3 @@
+ALTER TABLE operator_audit_log
+    DROP COLUMN IF EXISTS signer,
+    DROP COLUMN IF EXISTS signature;
@@ -0,0 +1,4 @@
+-- Key that signed an operator action, and its EIP-191 signature, when signatures are required.
+ALTER TABLE operator_audit_log
+    ADD COLUMN IF NOT EXISTS signer CHAR(40),
+    ADD COLUMN IF NOT EXISTS signature TEXT;
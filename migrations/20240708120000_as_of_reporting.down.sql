@@ -0,0 +1,2 @@
+DROP INDEX IF EXISTS scalar_tap_receipt_query_stats_created_at_idx;
+DROP INDEX IF EXISTS scalar_tap_rav_history_allocation_idx;
@@ -0,0 +1,3 @@
+-- To reconstruct the RAVs and the query stats as of a past point in time
+CREATE INDEX IF NOT EXISTS scalar_tap_rav_history_allocation_idx ON scalar_tap_rav_history (sender_address, allocation_id, id) INCLUDE (recorded_at);
+CREATE INDEX IF NOT EXISTS scalar_tap_receipt_query_stats_created_at_idx ON scalar_tap_receipt_query_stats (created_at);
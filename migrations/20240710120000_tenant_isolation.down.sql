@@ -0,0 +1,50 @@
+CREATE OR REPLACE FUNCTION scalar_tap_receipt_notify()
+RETURNS trigger AS
+$$
+BEGIN
+    PERFORM pg_notify('scalar_tap_receipt_notification', format('{"id": %s, "allocation_id": "%s", "signer_address": "%s", "timestamp_ns": %s, "value": %s}', NEW.id, NEW.allocation_id, NEW.signer_address, NEW.timestamp_ns, NEW.value));
+    RETURN NEW;
+END;
+$$ LANGUAGE 'plpgsql';
+
+CREATE OR REPLACE FUNCTION scalar_tap_rav_history_record()
+RETURNS TRIGGER AS $$
+BEGIN
+    INSERT INTO scalar_tap_rav_history (
+        sender_address, signature, allocation_id, timestamp_ns, value_aggregate, last, final
+    )
+    VALUES (
+        NEW.sender_address, NEW.signature, NEW.allocation_id, NEW.timestamp_ns,
+        NEW.value_aggregate, NEW.last, NEW.final
+    );
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+-- Only the checkpoint of a single indexer can be kept
+DROP INDEX IF EXISTS scalar_tap_agent_checkpoint_indexer;
+DELETE FROM scalar_tap_agent_checkpoint
+WHERE ctid NOT IN (SELECT ctid FROM scalar_tap_agent_checkpoint ORDER BY updated_at DESC LIMIT 1);
+ALTER TABLE scalar_tap_agent_checkpoint ADD PRIMARY KEY (id);
+ALTER TABLE scalar_tap_agent_checkpoint ADD CHECK (id);
+
+DO $$
+DECLARE
+    isolated_table TEXT;
+BEGIN
+    FOREACH isolated_table IN ARRAY ARRAY[
+        'scalar_tap_receipts',
+        'scalar_tap_receipts_invalid',
+        'scalar_tap_ravs',
+        'scalar_tap_rav_history',
+        'scalar_tap_rav_requests_failed',
+        'scalar_tap_receipt_query_stats',
+        'scalar_tap_agent_checkpoint'
+    ]
+    LOOP
+        EXECUTE format('DROP POLICY IF EXISTS indexer_isolation ON %I', isolated_table);
+        EXECUTE format('ALTER TABLE %I NO FORCE ROW LEVEL SECURITY', isolated_table);
+        EXECUTE format('ALTER TABLE %I DISABLE ROW LEVEL SECURITY', isolated_table);
+        EXECUTE format('ALTER TABLE %I DROP COLUMN IF EXISTS indexer_address', isolated_table);
+    END LOOP;
+END $$;
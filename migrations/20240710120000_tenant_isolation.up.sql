@@ -0,0 +1,76 @@
+-- Indexer owning each row of the TAP tables, for several indexers to share a database. Filled
+-- from the `indexer.address` setting of the session writing the row, set by the indexer-service
+-- and the tap-agent when `database.tenant_isolation` is enabled. The rows written before are
+-- assigned to the indexer of the first of them to start.
+DO $$
+DECLARE
+    isolated_table TEXT;
+BEGIN
+    FOREACH isolated_table IN ARRAY ARRAY[
+        'scalar_tap_receipts',
+        'scalar_tap_receipts_invalid',
+        'scalar_tap_ravs',
+        'scalar_tap_rav_history',
+        'scalar_tap_rav_requests_failed',
+        'scalar_tap_receipt_query_stats',
+        'scalar_tap_agent_checkpoint'
+    ]
+    LOOP
+        EXECUTE format(
+            'ALTER TABLE %I ADD COLUMN IF NOT EXISTS indexer_address CHAR(40) '
+            'DEFAULT NULLIF(current_setting(''indexer.address'', true), '''')',
+            isolated_table
+        );
+        EXECUTE format(
+            'CREATE INDEX IF NOT EXISTS %I ON %I (indexer_address)',
+            isolated_table || '_indexer_idx', isolated_table
+        );
+        -- Sessions of an indexer only see its rows, and the ones without an owner yet
+        EXECUTE format('ALTER TABLE %I ENABLE ROW LEVEL SECURITY', isolated_table);
+        EXECUTE format('ALTER TABLE %I FORCE ROW LEVEL SECURITY', isolated_table);
+        EXECUTE format(
+            'CREATE POLICY indexer_isolation ON %I '
+            'USING ('
+            '    NULLIF(current_setting(''indexer.address'', true), '''') IS NULL '
+            '    OR indexer_address IS NULL '
+            '    OR indexer_address = current_setting(''indexer.address'', true)'
+            ') '
+            'WITH CHECK ('
+            '    NULLIF(current_setting(''indexer.address'', true), '''') IS NULL '
+            '    OR indexer_address = current_setting(''indexer.address'', true)'
+            ')',
+            isolated_table
+        );
+    END LOOP;
+END $$;
+
+-- One checkpoint per indexer
+ALTER TABLE scalar_tap_agent_checkpoint DROP CONSTRAINT IF EXISTS scalar_tap_agent_checkpoint_pkey;
+ALTER TABLE scalar_tap_agent_checkpoint DROP CONSTRAINT IF EXISTS scalar_tap_agent_checkpoint_id_check;
+CREATE UNIQUE INDEX IF NOT EXISTS scalar_tap_agent_checkpoint_indexer
+    ON scalar_tap_agent_checkpoint ((COALESCE(indexer_address, '')));
+
+-- The history and the notifications carry the owner of the rows
+CREATE OR REPLACE FUNCTION scalar_tap_rav_history_record()
+RETURNS TRIGGER AS $$
+BEGIN
+    INSERT INTO scalar_tap_rav_history (
+        sender_address, signature, allocation_id, timestamp_ns, value_aggregate, last, final,
+        indexer_address
+    )
+    VALUES (
+        NEW.sender_address, NEW.signature, NEW.allocation_id, NEW.timestamp_ns,
+        NEW.value_aggregate, NEW.last, NEW.final, NEW.indexer_address
+    );
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION scalar_tap_receipt_notify()
+RETURNS trigger AS
+$$
+BEGIN
+    PERFORM pg_notify('scalar_tap_receipt_notification', format('{"id": %s, "allocation_id": "%s", "signer_address": "%s", "timestamp_ns": %s, "value": %s, "indexer_address": %s}', NEW.id, NEW.allocation_id, NEW.signer_address, NEW.timestamp_ns, NEW.value, to_json(NEW.indexer_address)));
+    RETURN NEW;
+END;
+$$ LANGUAGE 'plpgsql';
@@ -0,0 +1,10 @@
+-- Allocation IDs declared by the operator for the data services without on-chain allocations
+-- yet, signed by the operator key. Revoked allocations are kept for the audit of their RAVs.
+CREATE TABLE IF NOT EXISTS virtual_allocations (
+    allocation_id CHAR(40) PRIMARY KEY,
+    -- URL namespace of the data service, e.g. `sql`
+    service TEXT NOT NULL,
+    signature TEXT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    revoked_at TIMESTAMP WITH TIME ZONE
+);
@@ -0,0 +1,2 @@
+DROP INDEX IF EXISTS scalar_tap_receipts_invalid_error_log_idx;
+ALTER TABLE scalar_tap_receipts_invalid DROP COLUMN IF EXISTS error_log;
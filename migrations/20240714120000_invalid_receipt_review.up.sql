@@ -0,0 +1,7 @@
+-- Why a receipt failed its checks, to review the invalid receipts by failure reason. Empty for
+-- the receipts stored before it was recorded.
+ALTER TABLE scalar_tap_receipts_invalid
+    ADD COLUMN IF NOT EXISTS error_log TEXT NOT NULL DEFAULT '';
+
+CREATE INDEX IF NOT EXISTS scalar_tap_receipts_invalid_error_log_idx
+    ON scalar_tap_receipts_invalid (error_log);
@@ -0,0 +1,5 @@
+DROP TRIGGER IF EXISTS cost_model_template_deployment_events ON cost_model_template_deployments;
+DROP TRIGGER IF EXISTS cost_model_template_events ON cost_model_templates;
+DROP FUNCTION IF EXISTS indexer_events_cost_model_templates;
+DROP TABLE IF EXISTS cost_model_template_deployments;
+DROP TABLE IF EXISTS cost_model_templates;
@@ -0,0 +1,41 @@
+-- Named cost models shared by deployments. A deployment referencing a template is priced with
+-- the model of the template, and its variables overridden by the ones of the deployment, so that
+-- updating a template updates all the deployments referencing it.
+CREATE TABLE IF NOT EXISTS cost_model_templates (
+    name VARCHAR PRIMARY KEY,
+    model TEXT NOT NULL,
+    variables JSONB,
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE IF NOT EXISTS cost_model_template_deployments (
+    deployment VARCHAR PRIMARY KEY,
+    -- Templates can't be deleted while deployments reference them
+    template VARCHAR NOT NULL REFERENCES cost_model_templates (name) ON UPDATE CASCADE,
+    -- Top-level keys overriding the ones of the variables of the template
+    variables JSONB
+);
+
+CREATE INDEX IF NOT EXISTS cost_model_template_deployments_template
+    ON cost_model_template_deployments (template);
+
+-- The cost models of all the deployments referencing a template change along with it
+CREATE FUNCTION indexer_events_cost_model_templates()
+RETURNS trigger AS
+$$
+BEGIN
+    INSERT INTO indexer_events (kind, payload, source)
+    SELECT 'cost_model_changed', json_build_object('deployment', deployment), 'database'
+    FROM cost_model_template_deployments
+    WHERE template = NEW.name;
+    RETURN NULL;
+END;
+$$ LANGUAGE 'plpgsql';
+
+CREATE TRIGGER cost_model_template_events AFTER UPDATE
+    ON cost_model_templates
+    FOR EACH ROW EXECUTE PROCEDURE indexer_events_cost_model_templates();
+
+CREATE TRIGGER cost_model_template_deployment_events AFTER INSERT OR UPDATE OR DELETE
+    ON cost_model_template_deployments
+    FOR EACH ROW EXECUTE PROCEDURE indexer_events_cost_models();
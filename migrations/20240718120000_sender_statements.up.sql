@@ -0,0 +1,12 @@
+-- Monthly statements of each sender, signed by the operator key for the senders to verify them.
+-- Archived once generated: generating the statement of a month again returns the stored one.
+CREATE TABLE IF NOT EXISTS sender_statements (
+    id BIGSERIAL PRIMARY KEY,
+    sender_address CHAR(40) NOT NULL,
+    period_start TIMESTAMP WITH TIME ZONE NOT NULL,
+    period_end TIMESTAMP WITH TIME ZONE NOT NULL,
+    -- EIP-712 signed statement
+    statement JSONB NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    UNIQUE (sender_address, period_start)
+);
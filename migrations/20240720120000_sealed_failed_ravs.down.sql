@@ -0,0 +1,5 @@
+-- The encrypted requests can't be decrypted without the key
+DELETE FROM scalar_tap_rav_requests_failed WHERE sealed_payload IS NOT NULL;
+ALTER TABLE scalar_tap_rav_requests_failed ALTER COLUMN rav_response SET NOT NULL;
+ALTER TABLE scalar_tap_rav_requests_failed ALTER COLUMN expected_rav SET NOT NULL;
+ALTER TABLE scalar_tap_rav_requests_failed DROP COLUMN IF EXISTS sealed_payload;
@@ -0,0 +1,8 @@
+CREATE EXTENSION IF NOT EXISTS pgcrypto;
+
+-- The expected RAV and the response of the failed RAV requests, encrypted with
+-- `pgp_sym_encrypt` as `{"expected_rav": ..., "rav_response": ...}` when a database key is
+-- configured, in which case the columns in the clear are left NULL.
+ALTER TABLE scalar_tap_rav_requests_failed ADD COLUMN IF NOT EXISTS sealed_payload BYTEA;
+ALTER TABLE scalar_tap_rav_requests_failed ALTER COLUMN expected_rav DROP NOT NULL;
+ALTER TABLE scalar_tap_rav_requests_failed ALTER COLUMN rav_response DROP NOT NULL;
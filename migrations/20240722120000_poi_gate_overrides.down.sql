@@ -0,0 +1 @@
+DROP TABLE IF EXISTS scalar_tap_poi_gate_overrides;
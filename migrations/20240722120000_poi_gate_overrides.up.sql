@@ -0,0 +1,9 @@
+-- Operator overrides of the POI gate of the closed allocations, which holds back marking their
+-- last RAVs until the POI of the close is posted on-chain. `release` marks them right away,
+-- `hold` keeps waiting for the POI past the timeout.
+CREATE TABLE IF NOT EXISTS scalar_tap_poi_gate_overrides (
+    allocation_id CHAR(40) PRIMARY KEY,
+    action TEXT NOT NULL,
+    reason TEXT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,8 @@
+CREATE OR REPLACE FUNCTION scalar_tap_receipt_notify()
+RETURNS trigger AS
+$$
+BEGIN
+    PERFORM pg_notify('scalar_tap_receipt_notification', format('{"id": %s, "allocation_id": "%s", "signer_address": "%s", "timestamp_ns": %s, "value": %s, "indexer_address": %s}', NEW.id, NEW.allocation_id, NEW.signer_address, NEW.timestamp_ns, NEW.value, to_json(NEW.indexer_address)));
+    RETURN NEW;
+END;
+$$ LANGUAGE 'plpgsql';
@@ -0,0 +1,13 @@
+-- The receipts the indexer-service notifies the tap-agent of through its socket aren't
+-- notified again with PG NOTIFY. The transactions storing them set `indexer.receipt_notification`
+-- to `direct`.
+CREATE OR REPLACE FUNCTION scalar_tap_receipt_notify()
+RETURNS trigger AS
+$$
+BEGIN
+    IF current_setting('indexer.receipt_notification', true) IS DISTINCT FROM 'direct' THEN
+        PERFORM pg_notify('scalar_tap_receipt_notification', format('{"id": %s, "allocation_id": "%s", "signer_address": "%s", "timestamp_ns": %s, "value": %s, "indexer_address": %s}', NEW.id, NEW.allocation_id, NEW.signer_address, NEW.timestamp_ns, NEW.value, to_json(NEW.indexer_address)));
+    END IF;
+    RETURN NEW;
+END;
+$$ LANGUAGE 'plpgsql';
@@ -0,0 +1,12 @@
+-- Overflows of the fee accounting of the allocations. The fees of an overflowed allocation stop
+-- being counted, and its sender is paused, until the operator acknowledges the overflow.
+CREATE TABLE IF NOT EXISTS scalar_tap_fee_overflows (
+    sender_address CHAR(40) NOT NULL,
+    allocation_id CHAR(40) NOT NULL,
+    -- Unaggregated fees of the allocation, and the value that couldn't be added to them
+    unaggregated_fees NUMERIC(39) NOT NULL,
+    value NUMERIC(39) NOT NULL,
+    detected_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    acknowledged_at TIMESTAMP WITH TIME ZONE,
+    PRIMARY KEY (sender_address, allocation_id)
+);
@@ -0,0 +1,13 @@
+-- Request and receipt counters of the indexer-service replicas sharing the database, incremented
+-- by every replica with what it served since its last flush, for any of them to report the
+-- stats of the whole fleet.
+CREATE TABLE IF NOT EXISTS indexer_service_instance_stats (
+    instance_id VARCHAR NOT NULL,
+    deployment VARCHAR NOT NULL,
+    requests BIGINT NOT NULL DEFAULT 0,
+    receipts BIGINT NOT NULL DEFAULT 0,
+    -- Sum of the values of the receipts, in GRT wei
+    receipt_value NUMERIC(39) NOT NULL DEFAULT 0,
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (instance_id, deployment)
+);
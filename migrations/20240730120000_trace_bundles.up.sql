@@ -0,0 +1,15 @@
+-- Bundles of anomalous RAV and receipt flows exported to object storage by the tap-agent, to
+-- rate limit the exports and delete the bundles past their retention.
+CREATE TABLE IF NOT EXISTS scalar_tap_trace_bundles (
+    object_key TEXT PRIMARY KEY,
+    kind VARCHAR(32) NOT NULL,
+    sender_address CHAR(40) NOT NULL,
+    allocation_id CHAR(40) NOT NULL,
+    reason TEXT NOT NULL,
+    uploaded_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS scalar_tap_trace_bundles_allocation_idx
+    ON scalar_tap_trace_bundles (sender_address, allocation_id, kind, uploaded_at);
+CREATE INDEX IF NOT EXISTS scalar_tap_trace_bundles_uploaded_at_idx
+    ON scalar_tap_trace_bundles (uploaded_at);
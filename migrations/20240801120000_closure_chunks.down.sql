@@ -0,0 +1,3 @@
+ALTER TABLE scalar_tap_allocation_closures
+    DROP COLUMN IF EXISTS chunks,
+    DROP COLUMN IF EXISTS aggregated_value;
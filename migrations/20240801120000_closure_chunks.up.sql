@@ -0,0 +1,5 @@
+-- Progress of the last RAV of a closure, aggregated in successive requests. Each request stores
+-- its RAV, which the next one, possibly after a restart, resumes from.
+ALTER TABLE scalar_tap_allocation_closures
+    ADD COLUMN IF NOT EXISTS chunks INT NOT NULL DEFAULT 0,
+    ADD COLUMN IF NOT EXISTS aggregated_value NUMERIC(39);
@@ -0,0 +1 @@
+DROP TABLE IF EXISTS scalar_tap_redemption_advice;
@@ -0,0 +1,22 @@
+-- History of the advice on which last RAVs to redeem at the gas price of the time, logged
+-- periodically by the tap-agent.
+CREATE TABLE IF NOT EXISTS scalar_tap_redemption_advice (
+    id BIGSERIAL PRIMARY KEY,
+    chain_id BIGINT NOT NULL,
+    sender_address CHAR(40) NOT NULL,
+    allocation_id CHAR(40) NOT NULL,
+    value_aggregate NUMERIC(39) NOT NULL,
+    -- In wei of the native token of the network
+    gas_price NUMERIC(39) NOT NULL,
+    -- Cost of redeeming the RAV, in GRT wei
+    redemption_cost NUMERIC(39) NOT NULL,
+    -- `redeem_now` or `batch_later`
+    action TEXT NOT NULL,
+    reason TEXT NOT NULL,
+    advised_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS scalar_tap_redemption_advice_advised_at_idx
+    ON scalar_tap_redemption_advice (advised_at);
+CREATE INDEX IF NOT EXISTS scalar_tap_redemption_advice_allocation_idx
+    ON scalar_tap_redemption_advice (allocation_id, advised_at);
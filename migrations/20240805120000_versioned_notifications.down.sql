@@ -0,0 +1,36 @@
+CREATE OR REPLACE FUNCTION scalar_tap_receipt_notify()
+RETURNS trigger AS
+$$
+BEGIN
+    IF current_setting('indexer.receipt_notification', true) IS DISTINCT FROM 'direct' THEN
+        PERFORM pg_notify('scalar_tap_receipt_notification', format('{"id": %s, "allocation_id": "%s", "signer_address": "%s", "timestamp_ns": %s, "value": %s, "indexer_address": %s}', NEW.id, NEW.allocation_id, NEW.signer_address, NEW.timestamp_ns, NEW.value, to_json(NEW.indexer_address)));
+    END IF;
+    RETURN NEW;
+END;
+$$ LANGUAGE 'plpgsql';
+
+CREATE OR REPLACE FUNCTION scalar_tap_deny_notify()
+RETURNS trigger AS
+$$
+BEGIN
+    IF TG_OP = 'DELETE' THEN
+        PERFORM pg_notify('scalar_tap_deny_notification', format('{"tg_op": "DELETE", "sender_address": "%s"}', OLD.sender_address));
+        RETURN OLD;
+    ELSIF TG_OP = 'INSERT' THEN
+        PERFORM pg_notify('scalar_tap_deny_notification', format('{"tg_op": "INSERT", "sender_address": "%s"}', NEW.sender_address));
+        RETURN NEW;
+    ELSE -- UPDATE OR TRUNCATE, should never happen
+        PERFORM pg_notify('scalar_tap_deny_notification', format('{"tg_op": "%s", "sender_address": null}', TG_OP, NEW.sender_address));
+        RETURN NEW;
+    END IF;
+END;
+$$ LANGUAGE 'plpgsql';
+
+CREATE OR REPLACE FUNCTION indexer_events_notify()
+RETURNS trigger AS
+$$
+BEGIN
+    PERFORM pg_notify('indexer_events', NEW.id::text);
+    RETURN NEW;
+END;
+$$ LANGUAGE 'plpgsql';
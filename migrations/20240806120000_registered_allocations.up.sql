@@ -0,0 +1,9 @@
+-- Allocations registered through the admin API of the tap-agent, for the deployments whose
+-- allocations don't come from the network subgraph.
+CREATE TABLE IF NOT EXISTS registered_allocations (
+    allocation_id CHAR(40) PRIMARY KEY,
+    deployment TEXT NOT NULL,
+    created_at_epoch BIGINT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    closed_at TIMESTAMP WITH TIME ZONE
+);
@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS scalar_tap_receipts_request_id_idx;
+
+ALTER TABLE scalar_tap_receipts DROP COLUMN IF EXISTS request_id;
@@ -0,0 +1,7 @@
+-- Id of the request a receipt paid for along with other receipts, when a query is paid with
+-- several receipts. NULL for the receipts paying for a query on their own.
+ALTER TABLE scalar_tap_receipts ADD COLUMN IF NOT EXISTS request_id TEXT;
+
+CREATE INDEX IF NOT EXISTS scalar_tap_receipts_request_id_idx
+    ON scalar_tap_receipts (request_id)
+    WHERE request_id IS NOT NULL;
@@ -0,0 +1,16 @@
+-- Sender allocations closed because their allocation vanished from the allocation source
+-- without being closed, e.g. after a reorg. Their last RAVs aren't marked as last, but flagged
+-- here until the operator reviews them, marking them as last or dismissing them.
+CREATE TABLE IF NOT EXISTS scalar_tap_vanished_allocations (
+    sender_address CHAR(40) NOT NULL,
+    allocation_id CHAR(40) NOT NULL,
+    -- Value of the last RAV once the last RAV request was attempted, and the fees left
+    -- unaggregated
+    rav_value NUMERIC(39),
+    unaggregated_fees NUMERIC(39) NOT NULL,
+    -- Error of the last RAV request, if it failed
+    error TEXT,
+    flagged_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    reviewed_at TIMESTAMP WITH TIME ZONE,
+    PRIMARY KEY (sender_address, allocation_id)
+);
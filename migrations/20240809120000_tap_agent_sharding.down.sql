@@ -0,0 +1,8 @@
+-- Only the unsharded checkpoints can be kept
+DROP INDEX IF EXISTS scalar_tap_agent_checkpoint_indexer;
+DELETE FROM scalar_tap_agent_checkpoint WHERE shard <> '';
+ALTER TABLE scalar_tap_agent_checkpoint DROP COLUMN IF EXISTS shard;
+CREATE UNIQUE INDEX IF NOT EXISTS scalar_tap_agent_checkpoint_indexer
+    ON scalar_tap_agent_checkpoint ((COALESCE(indexer_address, '')));
+
+DROP TABLE IF EXISTS scalar_tap_agent_shard_leases;
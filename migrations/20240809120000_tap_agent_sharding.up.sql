@@ -0,0 +1,17 @@
+-- Leases of the shards of the senders, see `tap.sharding`, so that two tap-agent instances of
+-- the same indexer can't manage the same shard. Renewed by their holder until it stops.
+CREATE TABLE IF NOT EXISTS scalar_tap_agent_shard_leases (
+    indexer_address CHAR(40) NOT NULL,
+    shard_index INTEGER NOT NULL,
+    shard_count INTEGER NOT NULL,
+    holder TEXT NOT NULL,
+    acquired_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    PRIMARY KEY (indexer_address, shard_index)
+);
+
+-- One checkpoint per indexer and shard, the unsharded agents using an empty shard
+ALTER TABLE scalar_tap_agent_checkpoint ADD COLUMN IF NOT EXISTS shard TEXT NOT NULL DEFAULT '';
+DROP INDEX IF EXISTS scalar_tap_agent_checkpoint_indexer;
+CREATE UNIQUE INDEX IF NOT EXISTS scalar_tap_agent_checkpoint_indexer
+    ON scalar_tap_agent_checkpoint ((COALESCE(indexer_address, '')), shard);
@@ -0,0 +1,7 @@
+-- Nonces of the admin signatures already used, so that a signed admin action can't be replayed,
+-- even to another tap-agent instance or after a restart. Pruned once the signatures expire.
+CREATE TABLE IF NOT EXISTS admin_signature_nonces (
+    nonce TEXT PRIMARY KEY,
+    signer CHAR(40) NOT NULL,
+    used_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,97 @@
+-- Only the rows of a single indexer can be kept per key
+DROP INDEX IF EXISTS indexer_service_instance_stats_indexer;
+DELETE FROM indexer_service_instance_stats a USING indexer_service_instance_stats b
+WHERE a.instance_id = b.instance_id AND a.deployment = b.deployment AND a.ctid > b.ctid;
+ALTER TABLE indexer_service_instance_stats ADD PRIMARY KEY (instance_id, deployment);
+
+DROP INDEX IF EXISTS sender_statements_indexer;
+DELETE FROM sender_statements a USING sender_statements b
+WHERE a.sender_address = b.sender_address AND a.period_start = b.period_start AND a.id > b.id;
+ALTER TABLE sender_statements ADD UNIQUE (sender_address, period_start);
+
+DROP INDEX IF EXISTS accounting_statements_indexer;
+DELETE FROM accounting_statements a USING accounting_statements b
+WHERE a.period_start = b.period_start AND a.period_end = b.period_end AND a.id > b.id;
+ALTER TABLE accounting_statements ADD UNIQUE (period_start, period_end);
+
+DROP INDEX IF EXISTS escrow_account_overrides_indexer;
+DELETE FROM escrow_account_overrides a USING escrow_account_overrides b
+WHERE a.sender_address = b.sender_address AND a.ctid > b.ctid;
+ALTER TABLE escrow_account_overrides ADD PRIMARY KEY (sender_address);
+
+DROP INDEX IF EXISTS scalar_tap_denylist_indexer;
+DELETE FROM scalar_tap_denylist a USING scalar_tap_denylist b
+WHERE a.sender_address = b.sender_address AND a.ctid > b.ctid;
+ALTER TABLE scalar_tap_denylist ADD PRIMARY KEY (sender_address);
+
+DO $$
+DECLARE
+    isolated_table TEXT;
+BEGIN
+    FOREACH isolated_table IN ARRAY ARRAY[
+        'scalar_tap_denylist',
+        'escrow_account_overrides',
+        'operator_audit_log',
+        'indexing_agreements',
+        'scalar_tap_allocation_closures',
+        'CostModels',
+        'cost_model_history',
+        'cost_model_templates',
+        'cost_model_template_deployments',
+        'accounting_statements',
+        'sender_statements',
+        'scalar_tap_fee_overflows',
+        'scalar_tap_vanished_allocations',
+        'scalar_tap_poi_gate_overrides',
+        'scalar_tap_trace_bundles',
+        'scalar_tap_redemption_advice',
+        'virtual_allocations',
+        'registered_allocations',
+        'admin_signature_nonces',
+        'indexer_events',
+        'indexing_agreement_ravs',
+        'scalar_tap_agent_allocation_checkpoints',
+        'scalar_tap_agent_shard_leases',
+        'scalar_tap_allocation_closure_batches',
+        'indexer_service_instance_stats'
+    ]
+    LOOP
+        EXECUTE format('DROP POLICY IF EXISTS indexer_isolation ON %I', isolated_table);
+        EXECUTE format('ALTER TABLE %I NO FORCE ROW LEVEL SECURITY', isolated_table);
+        EXECUTE format('ALTER TABLE %I DISABLE ROW LEVEL SECURITY', isolated_table);
+        EXECUTE format('DROP INDEX IF EXISTS %I', lower(isolated_table) || '_indexer_idx');
+        -- The shard leases were keyed by indexer from the start
+        IF isolated_table <> 'scalar_tap_agent_shard_leases' THEN
+            EXECUTE format(
+                'ALTER TABLE %I DROP COLUMN IF EXISTS indexer_address', isolated_table
+            );
+        END IF;
+    END LOOP;
+
+    -- The rows without an owner are seen by every indexer again
+    FOREACH isolated_table IN ARRAY ARRAY[
+        'scalar_tap_receipts',
+        'scalar_tap_receipts_invalid',
+        'scalar_tap_ravs',
+        'scalar_tap_rav_history',
+        'scalar_tap_rav_requests_failed',
+        'scalar_tap_receipt_query_stats',
+        'scalar_tap_agent_checkpoint'
+    ]
+    LOOP
+        EXECUTE format('DROP POLICY IF EXISTS indexer_isolation ON %I', isolated_table);
+        EXECUTE format(
+            'CREATE POLICY indexer_isolation ON %I '
+            'USING ('
+            '    NULLIF(current_setting(''indexer.address'', true), '''') IS NULL '
+            '    OR indexer_address IS NULL '
+            '    OR indexer_address = current_setting(''indexer.address'', true)'
+            ') '
+            'WITH CHECK ('
+            '    NULLIF(current_setting(''indexer.address'', true), '''') IS NULL '
+            '    OR indexer_address = current_setting(''indexer.address'', true)'
+            ')',
+            isolated_table
+        );
+    END LOOP;
+END $$;
@@ -0,0 +1,128 @@
+-- The other tables with rows owned by an indexer are isolated like the TAP tables, including the
+-- events, e.g. a sender paused by an indexer stays accepted by the others. The sessions
+-- of an indexer no longer see the rows without an owner either: the rows written before the
+-- isolation was enabled are assigned once with the `claim-unowned-rows` command of the
+-- tap-agent, and the other components writing these tables, e.g. the indexer-agent, must use a
+-- role with the `indexer.address` setting for their rows to be owned.
+DO $$
+DECLARE
+    isolated_table TEXT;
+BEGIN
+    FOREACH isolated_table IN ARRAY ARRAY[
+        'scalar_tap_denylist',
+        'escrow_account_overrides',
+        'operator_audit_log',
+        'indexing_agreements',
+        'scalar_tap_allocation_closures',
+        'CostModels',
+        'cost_model_history',
+        'cost_model_templates',
+        'cost_model_template_deployments',
+        'accounting_statements',
+        'sender_statements',
+        'scalar_tap_fee_overflows',
+        'scalar_tap_vanished_allocations',
+        'scalar_tap_poi_gate_overrides',
+        'scalar_tap_trace_bundles',
+        'scalar_tap_redemption_advice',
+        'virtual_allocations',
+        'registered_allocations',
+        'admin_signature_nonces',
+        'indexer_events',
+        'indexing_agreement_ravs',
+        'scalar_tap_agent_allocation_checkpoints',
+        'scalar_tap_agent_shard_leases',
+        'scalar_tap_allocation_closure_batches',
+        'indexer_service_instance_stats'
+    ]
+    LOOP
+        EXECUTE format(
+            'ALTER TABLE %I ADD COLUMN IF NOT EXISTS indexer_address CHAR(40) '
+            'DEFAULT NULLIF(current_setting(''indexer.address'', true), '''')',
+            isolated_table
+        );
+        EXECUTE format(
+            'CREATE INDEX IF NOT EXISTS %I ON %I (indexer_address)',
+            lower(isolated_table) || '_indexer_idx', isolated_table
+        );
+        EXECUTE format('ALTER TABLE %I ENABLE ROW LEVEL SECURITY', isolated_table);
+        EXECUTE format('ALTER TABLE %I FORCE ROW LEVEL SECURITY', isolated_table);
+    END LOOP;
+
+    FOREACH isolated_table IN ARRAY ARRAY[
+        'scalar_tap_receipts',
+        'scalar_tap_receipts_invalid',
+        'scalar_tap_ravs',
+        'scalar_tap_rav_history',
+        'scalar_tap_rav_requests_failed',
+        'scalar_tap_receipt_query_stats',
+        'scalar_tap_agent_checkpoint',
+        'scalar_tap_denylist',
+        'escrow_account_overrides',
+        'operator_audit_log',
+        'indexing_agreements',
+        'scalar_tap_allocation_closures',
+        'CostModels',
+        'cost_model_history',
+        'cost_model_templates',
+        'cost_model_template_deployments',
+        'accounting_statements',
+        'sender_statements',
+        'scalar_tap_fee_overflows',
+        'scalar_tap_vanished_allocations',
+        'scalar_tap_poi_gate_overrides',
+        'scalar_tap_trace_bundles',
+        'scalar_tap_redemption_advice',
+        'virtual_allocations',
+        'registered_allocations',
+        'admin_signature_nonces',
+        'indexer_events',
+        'indexing_agreement_ravs',
+        'scalar_tap_agent_allocation_checkpoints',
+        'scalar_tap_agent_shard_leases',
+        'scalar_tap_allocation_closure_batches',
+        'indexer_service_instance_stats'
+    ]
+    LOOP
+        -- Sessions of an indexer only see its rows
+        EXECUTE format('DROP POLICY IF EXISTS indexer_isolation ON %I', isolated_table);
+        EXECUTE format(
+            'CREATE POLICY indexer_isolation ON %I '
+            'USING ('
+            '    NULLIF(current_setting(''indexer.address'', true), '''') IS NULL '
+            '    OR indexer_address = current_setting(''indexer.address'', true)'
+            ') '
+            'WITH CHECK ('
+            '    NULLIF(current_setting(''indexer.address'', true), '''') IS NULL '
+            '    OR indexer_address = current_setting(''indexer.address'', true)'
+            ')',
+            isolated_table
+        );
+    END LOOP;
+END $$;
+
+-- Keyed per indexer where the indexers sharing the database use the same keys. The cost models
+-- stay keyed by deployment, as the indexer-agent writes them by that key.
+ALTER TABLE scalar_tap_denylist DROP CONSTRAINT IF EXISTS scalar_tap_denylist_pkey;
+CREATE UNIQUE INDEX IF NOT EXISTS scalar_tap_denylist_indexer
+    ON scalar_tap_denylist (sender_address, (COALESCE(indexer_address, '')));
+
+ALTER TABLE escrow_account_overrides DROP CONSTRAINT IF EXISTS escrow_account_overrides_pkey;
+CREATE UNIQUE INDEX IF NOT EXISTS escrow_account_overrides_indexer
+    ON escrow_account_overrides (sender_address, (COALESCE(indexer_address, '')));
+
+ALTER TABLE accounting_statements
+    DROP CONSTRAINT IF EXISTS accounting_statements_period_start_period_end_key;
+CREATE UNIQUE INDEX IF NOT EXISTS accounting_statements_indexer
+    ON accounting_statements (period_start, period_end, (COALESCE(indexer_address, '')));
+
+ALTER TABLE sender_statements
+    DROP CONSTRAINT IF EXISTS sender_statements_sender_address_period_start_key;
+CREATE UNIQUE INDEX IF NOT EXISTS sender_statements_indexer
+    ON sender_statements (sender_address, period_start, (COALESCE(indexer_address, '')));
+
+-- The instance IDs default to the host name, the same for the instances of different indexers
+ALTER TABLE indexer_service_instance_stats
+    DROP CONSTRAINT IF EXISTS indexer_service_instance_stats_pkey;
+CREATE UNIQUE INDEX IF NOT EXISTS indexer_service_instance_stats_indexer
+    ON indexer_service_instance_stats (instance_id, deployment, (COALESCE(indexer_address, '')));
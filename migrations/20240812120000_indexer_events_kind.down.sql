@@ -0,0 +1 @@
+DROP INDEX IF EXISTS indexer_events_kind_id_idx;
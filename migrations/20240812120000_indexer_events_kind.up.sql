@@ -0,0 +1,3 @@
+-- For the latest events of a kind, e.g. the `sender_paused` ones telling the paused senders
+-- and kept when the events are pruned
+CREATE INDEX IF NOT EXISTS indexer_events_kind_id_idx ON indexer_events (kind, id);
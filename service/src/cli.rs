@@ -3,12 +3,59 @@
 
 use std::path::PathBuf;
 
-use clap::Parser;
+use clap::{Args, Parser, Subcommand};
 
 #[derive(Parser)]
 pub struct Cli {
     /// Path to the configuration file.
     /// See https://github.com/graphprotocol/indexer-rs/tree/main/service for examples.
-    #[arg(long, value_name = "FILE", verbatim_doc_comment)]
-    pub config: PathBuf,
+    /// Use `-` to read the configuration from stdin.
+    #[arg(
+        long,
+        value_name = "FILE",
+        verbatim_doc_comment,
+        required_unless_present = "config_schema"
+    )]
+    config: Option<PathBuf>,
+
+    /// Print the JSON Schema of the configuration file and exit, e.g. to validate the values of
+    /// a deployment before rolling it out.
+    #[arg(long, exclusive = true)]
+    pub config_schema: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+impl Cli {
+    /// Path to the configuration file, only missing with `--config-schema`.
+    pub fn config(&self) -> &PathBuf {
+        self.config
+            .as_ref()
+            .expect("`--config` is required unless `--config-schema` is set")
+    }
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Carry over the cost models and settings of a TypeScript indexer-service deployment.
+    MigrateFromTs(MigrateFromTsArgs),
+    /// Check the connectivity to the database, graph-node and subgraphs, the operator key and
+    /// the EIP-712 domain. Exits with 0 if all checks pass, 1 on warnings and 2 on failures.
+    Doctor,
+}
+
+#[derive(Args)]
+pub struct MigrateFromTsArgs {
+    /// Postgres URL of the database used by the TypeScript indexer-service.
+    #[arg(long, value_name = "URL")]
+    pub source_postgres_url: String,
+
+    /// Env file holding the `INDEXER_SERVICE_*` settings of the TypeScript indexer-service.
+    #[arg(long, value_name = "FILE")]
+    pub ts_env_file: Option<PathBuf>,
+
+    /// Only print the differences, without writing anything.
+    #[arg(long)]
+    pub dry_run: bool,
 }
@@ -1,12 +1,21 @@
 // Copyright 2023-, GraphOps and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
 
+use indexer_common::allocations::sources::{AllocationSource, StaticAllocation};
+use indexer_common::db_pool::PoolSizing;
+use indexer_common::http_proxy::{Destination, ProxySettings};
 use indexer_common::indexer_service::http::{
-    DatabaseConfig, GraphNetworkConfig, GraphNodeConfig, IndexerConfig, IndexerServiceConfig,
-    ServerConfig, SubgraphConfig, TapConfig,
+    DatabaseConfig, FleetStatsConfig, GraphNetworkConfig, GraphNodeConfig, IndexerConfig,
+    IndexerServiceConfig, ReadOnlyDatabaseConfig, ReadOnlyDatabasePolicy,
+    ReceiptAcceptanceWindowAction, ReceiptAcceptanceWindowConfig, ReceiptQueueConfig,
+    ReceiptQueueOverflow, ReceiptTransport, ServerConfig, SubgraphConfig, TapConfig,
 };
+use indexer_common::monitor_backoff::MonitorAlarms;
+use indexer_common::secrets::SealedSecret;
+use indexer_common::slo::{BurnRateAlert, Indicator, Objective, SloSettings};
 use indexer_config::Config as MainConfig;
 use serde::{Deserialize, Serialize};
 
@@ -15,26 +24,113 @@ pub struct Config(pub IndexerServiceConfig);
 
 impl From<MainConfig> for Config {
     fn from(value: MainConfig) -> Self {
+        let proxy = proxy_settings(&value);
         Self(IndexerServiceConfig {
             indexer: IndexerConfig {
                 indexer_address: value.indexer.indexer_address,
-                operator_mnemonic: value.indexer.operator_mnemonic.to_string(),
+                operator_mnemonic: SealedSecret::new(value.indexer.operator_mnemonic.to_string()),
             },
             server: ServerConfig {
                 host_and_port: value.service.host_and_port,
-                metrics_host_and_port: SocketAddr::V4(SocketAddrV4::new(
-                    Ipv4Addr::new(0, 0, 0, 0),
+                metrics_host_and_port: SocketAddr::new(
+                    value
+                        .metrics
+                        .address
+                        .unwrap_or(Ipv4Addr::UNSPECIFIED.into()),
                     value.metrics.port,
-                )),
+                ),
                 url_prefix: value.service.url_prefix,
                 free_query_auth_token: value.service.free_query_auth_token,
+                operator_auth_token: value.service.operator_auth_token,
+                pause_unhealthy_deployments: value.service.pause_unhealthy_deployments,
+                attestations_disabled_deployments: value
+                    .service
+                    .attestations_disabled_deployments
+                    .into_iter()
+                    .collect(),
+                monitor_alarms: MonitorAlarms {
+                    after_failures: value.metrics.monitor_alarm_after_failures,
+                    webhook_url: value.metrics.monitor_alarm_webhook_url.map(Into::into),
+                },
+                fleet_stats: value
+                    .service
+                    .fleet_stats
+                    .map(|fleet_stats| FleetStatsConfig {
+                        instance_id: fleet_stats.instance_id,
+                        flush_interval: fleet_stats.flush_interval_secs,
+                    }),
+                slo: value.service.slo.map(|slo| SloSettings {
+                    objectives: slo
+                        .objectives
+                        .into_iter()
+                        .map(|objective| Objective {
+                            indicator: match objective.indicator {
+                                indexer_config::SloIndicator::PaidQueryLatency => {
+                                    Indicator::PaidQueryLatency {
+                                        // Required by the validation of the config
+                                        threshold: objective
+                                            .latency_threshold_secs
+                                            .unwrap_or_default(),
+                                    }
+                                }
+                                indexer_config::SloIndicator::ReceiptAcceptance => {
+                                    Indicator::ReceiptAcceptance
+                                }
+                            },
+                            name: objective.name,
+                            target: objective.target,
+                        })
+                        .collect(),
+                    alerts: slo
+                        .alerts
+                        .into_iter()
+                        .map(|alert| BurnRateAlert {
+                            severity: alert.severity,
+                            long_window: alert.long_window_secs,
+                            short_window: alert.short_window_secs,
+                            burn_rate: alert.burn_rate,
+                        })
+                        .collect(),
+                    min_events: slo.min_events,
+                    evaluation_interval: slo.evaluation_interval_secs,
+                    webhook_url: slo.webhook_url.map(Into::into),
+                }),
+                proxy,
             },
             database: DatabaseConfig {
                 postgres_url: value.database.postgres_url.into(),
+                slow_query_threshold_ms: value.database.slow_query_threshold_secs.as_millis()
+                    as u64,
+                tenant_isolation: value.database.tenant_isolation,
+                receipt_notification_socket: value.database.receipt_notification_socket,
+                pool: PoolSizing {
+                    min_connections: value.database.pool.min_connections,
+                    max_connections: value.database.pool.max_connections,
+                    target_acquire_wait: value.database.pool.target_acquire_wait_secs,
+                    idle_timeout: value.database.pool.idle_timeout_secs,
+                },
             },
             graph_node: Some(GraphNodeConfig {
                 status_url: value.graph_node.status_url.into(),
                 query_base_url: value.graph_node.query_url.into(),
+                additional_query_base_urls: value
+                    .graph_node
+                    .additional_query_urls
+                    .into_iter()
+                    .map(String::from)
+                    .collect(),
+                health_check_interval_ms: value
+                    .graph_node
+                    .routing
+                    .health_check_interval_secs
+                    .as_millis() as u64,
+                max_consecutive_failures: value.graph_node.routing.max_consecutive_failures,
+                sticky_deployments: value
+                    .graph_node
+                    .routing
+                    .sticky_deployments
+                    .into_iter()
+                    .collect(),
             }),
             network_subgraph: SubgraphConfig {
                 serve_subgraph: value.service.serve_network_subgraph,
@@ -53,6 +149,50 @@ impl From<MainConfig> for Config {
                     .network
                     .recently_closed_allocation_buffer_secs
                     .as_secs(),
+                recently_closed_allocation_buffer_seconds_per_network: value
+                    .subgraphs
+                    .network
+                    .recently_closed_allocation_buffer_secs_per_network
+                    .iter()
+                    .map(|(network, buffer)| (network.clone(), buffer.as_secs()))
+                    .collect(),
+                recently_closed_allocation_buffer_seconds_per_sender: value
+                    .subgraphs
+                    .network
+                    .recently_closed_allocation_buffer_secs_per_sender
+                    .iter()
+                    .map(|(sender, buffer)| (*sender, buffer.as_secs()))
+                    .collect(),
+                additional_query_urls: value
+                    .subgraphs
+                    .network
+                    .additional_query_urls
+                    .iter()
+                    .map(|url| url.to_string())
+                    .collect(),
+                quorum: value.subgraphs.network.quorum,
+                page_size: None,
+                rollover_overlap_seconds: value
+                    .subgraphs
+                    .network
+                    .rollover_overlap_secs
+                    .map(|overlap| overlap.as_secs()),
+                allocation_source: match value.subgraphs.network.allocation_source {
+                    indexer_config::AllocationSource::Network => AllocationSource::Network,
+                    indexer_config::AllocationSource::Static => AllocationSource::Static,
+                    indexer_config::AllocationSource::Api => AllocationSource::Api,
+                },
+                static_allocations: value
+                    .subgraphs
+                    .network
+                    .static_allocations
+                    .iter()
+                    .map(|allocation| StaticAllocation {
+                        id: allocation.id,
+                        deployment: allocation.deployment,
+                        created_at_epoch: allocation.created_at_epoch,
+                    })
+                    .collect(),
             },
             escrow_subgraph: SubgraphConfig {
                 serve_subgraph: value.service.serve_escrow_subgraph,
@@ -67,16 +207,189 @@ impl From<MainConfig> for Config {
                     .syncing_interval_secs
                     .as_secs(),
                 recently_closed_allocation_buffer_seconds: 0,
+                recently_closed_allocation_buffer_seconds_per_network: HashMap::new(),
+                recently_closed_allocation_buffer_seconds_per_sender: HashMap::new(),
+                additional_query_urls: Vec::new(),
+                quorum: None,
+                page_size: Some(value.subgraphs.escrow.page_size),
+                rollover_overlap_seconds: None,
+                allocation_source: AllocationSource::Network,
+                static_allocations: Vec::new(),
             },
             graph_network: GraphNetworkConfig {
                 chain_id: value.blockchain.chain_id.clone() as u64,
+                dispute_manager: value.blockchain.dispute_manager,
             },
             tap: TapConfig {
                 chain_id: value.blockchain.chain_id as u64,
                 receipts_verifier_address: value.blockchain.receipts_verifier_address,
+                allow_escrow_network_mismatch: value.blockchain.allow_escrow_network_mismatch,
                 timestamp_error_tolerance: value.tap.rav_request.timestamp_buffer_secs.as_secs(),
                 receipt_max_value: value.service.tap.max_receipt_value_grt.get_value(),
+                receipt_min_value: value
+                    .service
+                    .tap
+                    .min_receipt_value_grt
+                    .map_or(0, |floor| floor.get_value()),
+                receipt_min_value_per_deployment: value
+                    .service
+                    .tap
+                    .min_receipt_value_grt_per_deployment
+                    .into_iter()
+                    .map(|(deployment, floor)| (deployment, floor.get_value()))
+                    .collect(),
+                sender_price_multipliers: value.service.tap.sender_price_multipliers,
+                receipt_transports_per_deployment: value
+                    .service
+                    .tap
+                    .receipt_transports_per_deployment
+                    .into_iter()
+                    .map(|(deployment, transports)| {
+                        let transports = transports
+                            .into_iter()
+                            .map(|transport| match transport {
+                                indexer_config::ReceiptTransport::QueryString => {
+                                    ReceiptTransport::QueryString
+                                }
+                                indexer_config::ReceiptTransport::PostBody => {
+                                    ReceiptTransport::PostBody
+                                }
+                            })
+                            .collect();
+                        (deployment, transports)
+                    })
+                    .collect(),
+                receipt_queue: value
+                    .service
+                    .tap
+                    .receipt_queue
+                    .map(|queue| ReceiptQueueConfig {
+                        capacity: queue.capacity,
+                        writers: queue.writers,
+                        batch_size: queue.batch_size,
+                        overflow: match queue.overflow {
+                            indexer_config::ReceiptQueueOverflow::Block => {
+                                ReceiptQueueOverflow::Block
+                            }
+                            indexer_config::ReceiptQueueOverflow::Drop => {
+                                ReceiptQueueOverflow::Drop
+                            }
+                            indexer_config::ReceiptQueueOverflow::Spill => {
+                                ReceiptQueueOverflow::Spill
+                            }
+                        },
+                        spill_path: queue.spill_path,
+                        failed_batches_path: queue.failed_batches_path,
+                    }),
+                read_only_database: value.service.tap.read_only_database.map(|read_only| {
+                    ReadOnlyDatabaseConfig {
+                        policy: match read_only.policy {
+                            indexer_config::ReadOnlyDatabasePolicy::Reject => {
+                                ReadOnlyDatabasePolicy::Reject
+                            }
+                            indexer_config::ReadOnlyDatabasePolicy::Journal => {
+                                ReadOnlyDatabasePolicy::Journal
+                            }
+                        },
+                        journal_path: read_only.journal_path,
+                    }
+                }),
+                virtual_allocation_services: value
+                    .tap
+                    .virtual_allocation_services
+                    .into_iter()
+                    .collect(),
+                receipt_acceptance_window: value.service.tap.receipt_acceptance_window.map(
+                    |window| ReceiptAcceptanceWindowConfig {
+                        tolerance: window.tolerance_secs,
+                        action: match window.action {
+                            indexer_config::ReceiptAcceptanceWindowAction::Reject => {
+                                ReceiptAcceptanceWindowAction::Reject
+                            }
+                            indexer_config::ReceiptAcceptanceWindowAction::Flag => {
+                                ReceiptAcceptanceWindowAction::Flag
+                            }
+                        },
+                    },
+                ),
+                require_query_binding: value.service.tap.require_query_binding,
+                max_receipts_per_query: value.service.tap.max_receipts_per_query,
+                agreement_registration_auth_token: value
+                    .tap
+                    .indexing_agreements
+                    .map(|agreements| agreements.registration_auth_token),
             },
         })
     }
 }
+
+/// Proxies of the HTTP requests, the destinations being told apart by their endpoints.
+pub fn proxy_settings(config: &MainConfig) -> ProxySettings {
+    let Some(proxy) = &config.proxy else {
+        return ProxySettings::default();
+    };
+    let graph_node = &config.graph_node;
+    let network = &config.subgraphs.network;
+    let endpoints = [&graph_node.query_url, &graph_node.status_url]
+        .into_iter()
+        .chain(&graph_node.additional_query_urls)
+        .map(|url| (Destination::GraphNode, url.to_string()))
+        .chain(
+            [
+                &network.config.query_url,
+                &config.subgraphs.escrow.config.query_url,
+            ]
+            .into_iter()
+            .chain(&network.additional_query_urls)
+            .map(|url| (Destination::Subgraphs, url.to_string())),
+        )
+        .collect();
+    ProxySettings {
+        url: proxy.url.as_ref().map(ToString::to_string),
+        destinations: [
+            (Destination::GraphNode, &proxy.graph_node_url),
+            (Destination::Subgraphs, &proxy.subgraphs_url),
+            (Destination::Aggregators, &proxy.aggregators_url),
+        ]
+        .into_iter()
+        .filter_map(|(destination, url)| Some((destination, url.as_ref()?.to_string())))
+        .collect(),
+        endpoints,
+        no_proxy: proxy.no_proxy.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use indexer_common::indexer_service::http::Capabilities;
+    use indexer_config::ConfigPrefix;
+
+    use super::*;
+
+    #[test]
+    fn test_capabilities_follow_the_config() {
+        let config: Config = MainConfig::parse(
+            ConfigPrefix::Service,
+            &PathBuf::from("../config/minimal-config-example.toml"),
+        )
+        .unwrap()
+        .into();
+        let capabilities = serde_json::to_value(Capabilities::new(&config.0)).unwrap();
+
+        let receipts = &capabilities["receipts"];
+        assert_eq!(receipts["idempotent"], false);
+        assert_eq!(receipts["formats"][0]["chainId"], 1337);
+        assert_eq!(
+            receipts["maxValue"],
+            config.0.tap.receipt_max_value.to_string()
+        );
+        assert_eq!(
+            receipts["maxAgeBeforeAllocationSecs"],
+            serde_json::Value::Null
+        );
+        assert_eq!(capabilities["rateLimits"]["misc"]["burstSize"], 10);
+        assert!(capabilities["rateLimits"].get("network").is_none());
+    }
+}
@@ -4,21 +4,28 @@
 use std::time::Duration;
 use std::{collections::HashSet, str::FromStr};
 
+use anyhow::anyhow;
+use indexer_common::{db_pool::PoolSizing, tenant};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use sqlx::{postgres::PgPoolOptions, PgPool};
-use thegraph::types::{DeploymentId, DeploymentIdError};
+use sqlx::{PgPool, Row};
+use thegraph::types::{Address, DeploymentId, DeploymentIdError};
 use tracing::debug;
 
-pub async fn connect(url: &str) -> PgPool {
+/// With a `tenant`, the connections only see the rows of that indexer, see [tenant].
+pub async fn connect(url: &str, tenant: Option<Address>, sizing: &PoolSizing) -> PgPool {
     debug!("Connecting to database");
 
-    PgPoolOptions::new()
-        .max_connections(50)
-        .acquire_timeout(Duration::from_secs(3))
-        .connect(url)
-        .await
-        .expect("Should be able to connect to the database")
+    let options = sizing
+        .pool_options()
+        .acquire_timeout(Duration::from_secs(3));
+    match tenant {
+        Some(indexer_address) => tenant::isolate(options, indexer_address),
+        None => options,
+    }
+    .connect(url)
+    .await
+    .expect("Should be able to connect to the database")
 }
 
 /// Internal cost model representation as stored in the database.
@@ -106,6 +113,21 @@ pub async fn cost_models(
     .map(CostModel::try_from)
     .collect::<Result<Vec<_>, _>>()?;
 
+    // Deployments referencing a template are priced with it rather than their own cost model
+    let templated = templated_cost_models(
+        pool,
+        (!deployments.is_empty()).then_some(hex_ids.as_slice()),
+    )
+    .await?;
+    if !templated.is_empty() {
+        let templated_deployments = templated
+            .iter()
+            .map(|model| model.deployment)
+            .collect::<HashSet<_>>();
+        models.retain(|model| !templated_deployments.contains(&model.deployment));
+        models.extend(templated);
+    }
+
     let deployments_with_models = models
         .iter()
         .map(|model| &model.deployment)
@@ -143,20 +165,25 @@ pub async fn cost_model(
     pool: &PgPool,
     deployment: &DeploymentId,
 ) -> Result<Option<CostModel>, anyhow::Error> {
-    let model = sqlx::query_as!(
-        DbCostModel,
-        r#"
-        SELECT deployment, model, variables
-        FROM "CostModels"
-        WHERE deployment = $1
-        AND deployment != 'global'
-        "#,
-        format!("{:#x}", deployment),
-    )
-    .fetch_optional(pool)
-    .await?
-    .map(CostModel::try_from)
-    .transpose()?;
+    let hex_id = format!("{:#x}", deployment);
+    let templated = templated_cost_models(pool, Some(std::slice::from_ref(&hex_id))).await?;
+    let model = match templated.into_iter().next() {
+        Some(model) => Some(model),
+        None => sqlx::query_as!(
+            DbCostModel,
+            r#"
+            SELECT deployment, model, variables
+            FROM "CostModels"
+            WHERE deployment = $1
+            AND deployment != 'global'
+            "#,
+            hex_id,
+        )
+        .fetch_optional(pool)
+        .await?
+        .map(CostModel::try_from)
+        .transpose()?,
+    };
 
     let global_model = global_cost_model(pool).await?;
 
@@ -201,11 +228,340 @@ fn merge_global(model: CostModel, global_model: &DbCostModel) -> CostModel {
     }
 }
 
+/// Overrides the top-level keys of the `template` variables with the ones of `overrides`.
+/// Variables that aren't objects are replaced altogether.
+fn merge_variables(template: Option<Value>, overrides: Option<Value>) -> Option<Value> {
+    match (template, overrides) {
+        (Some(Value::Object(mut template)), Some(Value::Object(overrides))) => {
+            template.extend(overrides);
+            Some(Value::Object(template))
+        }
+        (template, None) => template,
+        (_, overrides) => overrides,
+    }
+}
+
+/// Cost models of the deployments referencing a template, among `hex_ids`, or all of them.
+async fn templated_cost_models(
+    pool: &PgPool,
+    hex_ids: Option<&[String]>,
+) -> Result<Vec<CostModel>, anyhow::Error> {
+    let rows = sqlx::query(
+        r#"
+        SELECT
+            deployments.deployment,
+            templates.model,
+            templates.variables,
+            deployments.variables AS overrides
+        FROM cost_model_template_deployments deployments
+        JOIN cost_model_templates templates ON templates.name = deployments.template
+        WHERE $1::VARCHAR[] IS NULL OR deployments.deployment = ANY($1)
+        ORDER BY deployments.deployment ASC
+        "#,
+    )
+    .bind(hex_ids)
+    .fetch_all(pool)
+    .await?;
+
+    rows.iter()
+        .map(|row| {
+            Ok(CostModel {
+                deployment: DeploymentId::from_str(row.try_get("deployment")?)?,
+                model: Some(row.try_get("model")?),
+                variables: merge_variables(row.try_get("variables")?, row.try_get("overrides")?),
+            })
+        })
+        .collect()
+}
+
+/// A cost model shared by the deployments referencing it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CostModelTemplate {
+    pub name: String,
+    pub model: String,
+    pub variables: Option<Value>,
+}
+
+/// A deployment priced with a template, with the variables overriding the ones of the template.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CostModelTemplateDeployment {
+    pub deployment: DeploymentId,
+    pub template: String,
+    pub variables: Option<Value>,
+}
+
+pub async fn cost_model_templates(pool: &PgPool) -> Result<Vec<CostModelTemplate>, anyhow::Error> {
+    let rows = sqlx::query(
+        r#"
+        SELECT name, model, variables
+        FROM cost_model_templates
+        ORDER BY name ASC
+        "#,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    rows.iter()
+        .map(|row| {
+            Ok(CostModelTemplate {
+                name: row.try_get("name")?,
+                model: row.try_get("model")?,
+                variables: row.try_get("variables")?,
+            })
+        })
+        .collect()
+}
+
+/// The deployments referencing `template`, or any template.
+pub async fn cost_model_template_deployments(
+    pool: &PgPool,
+    template: Option<&str>,
+) -> Result<Vec<CostModelTemplateDeployment>, anyhow::Error> {
+    let rows = sqlx::query(
+        r#"
+        SELECT deployment, template, variables
+        FROM cost_model_template_deployments
+        WHERE $1::VARCHAR IS NULL OR template = $1
+        ORDER BY deployment ASC
+        "#,
+    )
+    .bind(template)
+    .fetch_all(pool)
+    .await?;
+
+    rows.iter()
+        .map(|row| {
+            Ok(CostModelTemplateDeployment {
+                deployment: DeploymentId::from_str(row.try_get("deployment")?)?,
+                template: row.try_get("template")?,
+                variables: row.try_get("variables")?,
+            })
+        })
+        .collect()
+}
+
+/// Creates or updates a template, updating the cost models of the deployments referencing it.
+pub async fn set_cost_model_template(
+    pool: &PgPool,
+    template: &CostModelTemplate,
+) -> Result<(), anyhow::Error> {
+    sqlx::query(
+        r#"
+        INSERT INTO cost_model_templates (name, model, variables)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (name)
+        DO UPDATE SET model = $2, variables = $3, updated_at = NOW()
+        "#,
+    )
+    .bind(&template.name)
+    .bind(&template.model)
+    .bind(&template.variables)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Deletes a template no deployment references anymore. Returns whether it existed.
+pub async fn delete_cost_model_template(pool: &PgPool, name: &str) -> Result<bool, anyhow::Error> {
+    let mut tx = pool.begin().await?;
+    let referenced: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM cost_model_template_deployments WHERE template = $1",
+    )
+    .bind(name)
+    .fetch_one(&mut *tx)
+    .await?;
+    if referenced > 0 {
+        return Err(anyhow!(
+            "Template `{name}` is still referenced by {referenced} deployments"
+        ));
+    }
+    let deleted = sqlx::query("DELETE FROM cost_model_templates WHERE name = $1")
+        .bind(name)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected()
+        > 0;
+    tx.commit().await?;
+    Ok(deleted)
+}
+
+/// Prices `deployment` with `template`, its `variables` overriding the ones of the template.
+pub async fn assign_cost_model_template(
+    pool: &PgPool,
+    deployment: &DeploymentId,
+    template: &str,
+    variables: Option<&Value>,
+) -> Result<(), anyhow::Error> {
+    let assigned = sqlx::query(
+        r#"
+        INSERT INTO cost_model_template_deployments (deployment, template, variables)
+        SELECT $1, name, $3
+        FROM cost_model_templates
+        WHERE name = $2
+        ON CONFLICT (deployment)
+        DO UPDATE SET template = $2, variables = $3
+        "#,
+    )
+    .bind(format!("{deployment:#x}"))
+    .bind(template)
+    .bind(variables)
+    .execute(pool)
+    .await?
+    .rows_affected();
+    if assigned == 0 {
+        return Err(anyhow!("No cost model template `{template}`"));
+    }
+    Ok(())
+}
+
+/// Prices `deployment` with its own cost model again. Returns whether it referenced a template.
+pub async fn unassign_cost_model_template(
+    pool: &PgPool,
+    deployment: &DeploymentId,
+) -> Result<bool, anyhow::Error> {
+    let deleted = sqlx::query("DELETE FROM cost_model_template_deployments WHERE deployment = $1")
+        .bind(format!("{deployment:#x}"))
+        .execute(pool)
+        .await?
+        .rows_affected();
+    Ok(deleted > 0)
+}
+
+/// A change of the cost model of a deployment, or of the global one.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CostModelChange {
+    /// Version of the cost model resulting from the change.
+    pub version: i64,
+    pub deployment: String,
+    /// `insert`, `update` or `delete`.
+    pub change: String,
+    pub old_model: Option<String>,
+    pub old_variables: Option<Value>,
+    pub new_model: Option<String>,
+    pub new_variables: Option<Value>,
+    pub changed_by: String,
+    /// RFC 3339 timestamp, in UTC.
+    pub changed_at: String,
+}
+
+/// Key of the cost model of `deployment` in the database, `global` for the global one.
+pub fn cost_model_key(deployment: &str) -> Result<String, DeploymentIdError> {
+    if deployment == "global" {
+        return Ok(deployment.to_string());
+    }
+    Ok(format!("{:#x}", DeploymentId::from_str(deployment)?))
+}
+
+/// Latest changes of the cost model of `deployment`, newest first.
+pub async fn cost_model_history(
+    pool: &PgPool,
+    deployment: &str,
+    limit: i64,
+) -> Result<Vec<CostModelChange>, anyhow::Error> {
+    let rows = sqlx::query(
+        r#"
+        SELECT
+            id,
+            deployment,
+            change,
+            old_model,
+            old_variables,
+            new_model,
+            new_variables,
+            changed_by,
+            to_char(changed_at AT TIME ZONE 'UTC', 'YYYY-MM-DD"T"HH24:MI:SS.US"Z"') AS changed_at
+        FROM cost_model_history
+        WHERE deployment = $1
+        ORDER BY id DESC
+        LIMIT $2
+        "#,
+    )
+    .bind(cost_model_key(deployment)?)
+    .bind(limit)
+    .fetch_all(pool)
+    .await?;
+
+    rows.iter()
+        .map(|row| {
+            Ok(CostModelChange {
+                version: row.try_get("id")?,
+                deployment: row.try_get("deployment")?,
+                change: row.try_get("change")?,
+                old_model: row.try_get("old_model")?,
+                old_variables: row.try_get("old_variables")?,
+                new_model: row.try_get("new_model")?,
+                new_variables: row.try_get("new_variables")?,
+                changed_by: row.try_get("changed_by")?,
+                changed_at: row.try_get("changed_at")?,
+            })
+        })
+        .collect()
+}
+
+/// Restores the cost model of `deployment` as of `version`, one of its changes, recording
+/// `author` as the author of the rollback. Returns the restored cost model, `None` if the
+/// version is a deletion.
+pub async fn rollback_cost_model(
+    pool: &PgPool,
+    deployment: &str,
+    version: i64,
+    author: &str,
+) -> Result<Option<(Option<String>, Option<Value>)>, anyhow::Error> {
+    let key = cost_model_key(deployment)?;
+    let mut tx = pool.begin().await?;
+    let row = sqlx::query(
+        r#"
+        SELECT change, new_model, new_variables
+        FROM cost_model_history
+        WHERE id = $1 AND deployment = $2
+        "#,
+    )
+    .bind(version)
+    .bind(&key)
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or_else(|| anyhow!("No version {version} of the cost model of {deployment}"))?;
+
+    // Recorded as the author by the history trigger
+    sqlx::query("SELECT set_config('indexer.actor', $1, true)")
+        .bind(author)
+        .execute(&mut *tx)
+        .await?;
+
+    let restored = if row.try_get::<String, _>("change")? == "delete" {
+        sqlx::query(r#"DELETE FROM "CostModels" WHERE deployment = $1"#)
+            .bind(&key)
+            .execute(&mut *tx)
+            .await?;
+        None
+    } else {
+        let model: Option<String> = row.try_get("new_model")?;
+        let variables: Option<Value> = row.try_get("new_variables")?;
+        sqlx::query(
+            r#"
+            INSERT INTO "CostModels" (deployment, model, variables)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (deployment)
+            DO UPDATE SET model = $2, variables = $3
+            "#,
+        )
+        .bind(&key)
+        .bind(&model)
+        .bind(&variables)
+        .execute(&mut *tx)
+        .await?;
+        Some((model, variables))
+    };
+    tx.commit().await?;
+    Ok(restored)
+}
+
 #[cfg(test)]
 mod test {
 
     use std::str::FromStr;
 
+    use serde_json::json;
     use sqlx::PgPool;
 
     use super::*;
@@ -225,6 +581,25 @@ mod test {
         .execute(pool)
         .await
         .expect("Create test instance in db");
+
+        // Looked up along with the cost models
+        sqlx::query(
+            r#"
+            CREATE TABLE cost_model_templates(
+                name VARCHAR PRIMARY KEY,
+                model TEXT NOT NULL,
+                variables JSONB
+            );
+            CREATE TABLE cost_model_template_deployments(
+                deployment VARCHAR PRIMARY KEY,
+                template VARCHAR NOT NULL REFERENCES cost_model_templates (name),
+                variables JSONB
+            );
+            "#,
+        )
+        .execute(pool)
+        .await
+        .expect("Create test instance in db");
     }
 
     async fn add_cost_models(pool: &PgPool, models: Vec<DbCostModel>) {
@@ -497,4 +872,117 @@ mod test {
         assert_eq!(model.deployment, missing_deployment);
         assert_eq!(model.model, global_model.model);
     }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_cost_model_history_and_rollback(pool: PgPool) {
+        let deployment = "Qmb5Ysp5oCUXhLA8NmxmYKDAX2nCMnh7Vvb5uffb9n5vss";
+        let key = cost_model_key(deployment).unwrap();
+        for model in ["default => 0.1;", "default => 0.2;"] {
+            sqlx::query(
+                r#"
+                INSERT INTO "CostModels" (deployment, model) VALUES ($1, $2)
+                ON CONFLICT (deployment) DO UPDATE SET model = $2
+                "#,
+            )
+            .bind(&key)
+            .bind(model)
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+
+        let history = cost_model_history(&pool, deployment, 10).await.unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].change, "update");
+        assert_eq!(history[0].old_model.as_deref(), Some("default => 0.1;"));
+        assert_eq!(history[0].new_model.as_deref(), Some("default => 0.2;"));
+        assert_eq!(history[1].change, "insert");
+
+        let restored = rollback_cost_model(&pool, deployment, history[1].version, "operator")
+            .await
+            .unwrap();
+        assert_eq!(restored, Some((Some("default => 0.1;".to_string()), None)));
+        let model = cost_model(&pool, &DeploymentId::from_str(deployment).unwrap())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(model.model.as_deref(), Some("default => 0.1;"));
+
+        let history = cost_model_history(&pool, deployment, 1).await.unwrap();
+        assert_eq!(history[0].changed_by, "operator");
+        assert_eq!(history[0].new_model.as_deref(), Some("default => 0.1;"));
+
+        // Versions of other deployments can't be restored
+        assert!(
+            rollback_cost_model(&pool, "global", history[0].version, "operator")
+                .await
+                .is_err()
+        );
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_cost_model_templates(pool: PgPool) {
+        let templated = test_data()[0].deployment;
+        let overridden = test_data()[1].deployment;
+        let own = test_data()[2].deployment;
+        add_cost_models(&pool, to_db_models(test_data())).await;
+
+        let template = CostModelTemplate {
+            name: "entities".to_string(),
+            model: "query { entities } => $ENTITY_PRICE;".to_string(),
+            variables: Some(json!({ "ENTITY_PRICE": 0.1, "DEFAULT_PRICE": 0.01 })),
+        };
+        set_cost_model_template(&pool, &template).await.unwrap();
+        assign_cost_model_template(&pool, &templated, "entities", None)
+            .await
+            .unwrap();
+        assign_cost_model_template(
+            &pool,
+            &overridden,
+            "entities",
+            Some(&json!({ "ENTITY_PRICE": 0.2 })),
+        )
+        .await
+        .unwrap();
+        assert!(assign_cost_model_template(&pool, &own, "missing", None)
+            .await
+            .is_err());
+
+        let model = cost_model(&pool, &overridden).await.unwrap().unwrap();
+        assert_eq!(model.model, Some(template.model.clone()));
+        assert_eq!(
+            model.variables,
+            Some(json!({ "ENTITY_PRICE": 0.2, "DEFAULT_PRICE": 0.01 }))
+        );
+
+        // Updating the template updates all the deployments referencing it
+        let updated = CostModelTemplate {
+            model: "default => $DEFAULT_PRICE;".to_string(),
+            ..template
+        };
+        set_cost_model_template(&pool, &updated).await.unwrap();
+        let models = cost_models(&pool, &[]).await.unwrap();
+        assert_eq!(models.len(), 3);
+        for model in &models {
+            if model.deployment == own {
+                assert_eq!(model.model.as_deref(), Some("default => 0.00012;"));
+            } else {
+                assert_eq!(model.model, Some(updated.model.clone()));
+            }
+        }
+        let models = cost_models(&pool, &[templated]).await.unwrap();
+        assert_eq!(models.len(), 1);
+        assert_eq!(models[0].variables, updated.variables);
+
+        // Referenced templates can't be deleted
+        assert!(delete_cost_model_template(&pool, "entities").await.is_err());
+        for deployment in [templated, overridden] {
+            assert!(unassign_cost_model_template(&pool, &deployment)
+                .await
+                .unwrap());
+        }
+        assert!(delete_cost_model_template(&pool, "entities").await.unwrap());
+        let model = cost_model(&pool, &overridden).await.unwrap().unwrap();
+        assert_eq!(model.model.as_deref(), Some("default => 0.00025;"));
+    }
 }
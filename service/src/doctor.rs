@@ -0,0 +1,73 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! `doctor` command, checking everything the indexer-service depends on.
+
+use alloy_sol_types::eip712_domain;
+use indexer_common::doctor::{
+    check_database, check_eip712_domain, check_escrow_network, check_graph_node,
+    check_operator_key, check_subgraph, DoctorReport,
+};
+use indexer_common::http_proxy;
+use indexer_config::Config as MainConfig;
+
+use crate::config::proxy_settings;
+
+pub async fn run(config: &MainConfig) -> DoctorReport {
+    let http_client = http_proxy::client(&proxy_settings(config));
+    let network = &config.subgraphs.network.config;
+    let escrow = &config.subgraphs.escrow.config;
+
+    let mut report = DoctorReport::default();
+    report.push(check_database(config.database.postgres_url.as_str()).await);
+    report.push(check_graph_node(&http_client, config.graph_node.status_url.as_str()).await);
+    report.push(
+        check_subgraph(
+            &http_client,
+            "network",
+            network.query_url.as_str(),
+            network.query_auth_token.as_deref(),
+        )
+        .await,
+    );
+    for query_url in &config.subgraphs.network.additional_query_urls {
+        report.push(check_subgraph(&http_client, "network", query_url.as_str(), None).await);
+    }
+    report.push(
+        check_subgraph(
+            &http_client,
+            "escrow",
+            escrow.query_url.as_str(),
+            escrow.query_auth_token.as_deref(),
+        )
+        .await,
+    );
+    report.push(
+        check_escrow_network(
+            &http_client,
+            Some(config.graph_node.status_url.as_str()),
+            escrow.query_url.as_str(),
+            escrow.query_auth_token.as_deref(),
+            escrow.deployment_id,
+            config.blockchain.chain_id.clone() as u64,
+        )
+        .await,
+    );
+    report.push(
+        check_operator_key(
+            &http_client,
+            &config.indexer.operator_mnemonic.to_string(),
+            config.indexer.indexer_address,
+            network.query_url.as_str(),
+            network.query_auth_token.as_deref(),
+        )
+        .await,
+    );
+    report.push(check_eip712_domain(&eip712_domain! {
+        name: "TAP",
+        version: "1",
+        chain_id: config.blockchain.chain_id.clone() as u64,
+        verifying_contract: config.blockchain.receipts_verifier_address,
+    }));
+    report
+}
@@ -1,6 +1,7 @@
 // Copyright 2023-, GraphOps and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
+use alloy_primitives::FixedBytes;
 use anyhow::Error;
 use axum::response::{IntoResponse, Response};
 use reqwest::StatusCode;
@@ -19,6 +20,20 @@ pub enum SubgraphServiceError {
     InvalidDeployment(DeploymentId),
     #[error("Failed to process query: {0}")]
     QueryForwardingError(reqwest::Error),
+    #[error("Invalid indexing agreement: {0}")]
+    InvalidAgreement(Error),
+    #[error("Unknown or cancelled indexing agreement: {0}")]
+    AgreementNotFound(FixedBytes<32>),
+    #[error("Failed to store indexing agreement: {0}")]
+    AgreementStorageError(Error),
+    #[error("Failed to get query stats: {0}")]
+    QueryStatsError(Error),
+    #[error("Failed to get cost model: {0}")]
+    CostModelError(Error),
+    #[error("Invalid cost model: {0}")]
+    InvalidCostModel(String),
+    #[error("Unauthorized")]
+    Unauthorized,
 }
 
 impl From<&SubgraphServiceError> for StatusCode {
@@ -30,6 +45,13 @@ impl From<&SubgraphServiceError> for StatusCode {
             StatusQueryError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             InvalidDeployment(_) => StatusCode::BAD_REQUEST,
             QueryForwardingError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            InvalidAgreement(_) => StatusCode::BAD_REQUEST,
+            AgreementNotFound(_) => StatusCode::NOT_FOUND,
+            AgreementStorageError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            QueryStatsError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            CostModelError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            InvalidCostModel(_) => StatusCode::BAD_REQUEST,
+            Unauthorized => StatusCode::UNAUTHORIZED,
         }
     }
 }
@@ -0,0 +1,380 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Routing of the queries between the replicas of graph-node. Each query goes to the healthy
+//! replica with the lowest expected latency, the moving average of its response times weighted
+//! by the queries it's already serving. A query that fails is retried on the next replica.
+//! Sticky deployments are always routed to the same healthy replica instead, by rendezvous
+//! hashing, so that a replica going down only moves the deployments it was serving.
+//!
+//! A replica is skipped after failing too many queries in a row, until it passes the periodic
+//! health check again. If no replica is healthy, the queries are routed between all of them.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use indexer_common::indexer_service::http::GraphNodeConfig;
+use thegraph::types::DeploymentId;
+use tracing::{info, warn};
+
+/// Weight of the latest response time in the moving average of an endpoint.
+const LATENCY_SMOOTHING: f64 = 0.3;
+
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Execution time graph-node reports for a query in the `Server-Timing` header of its response,
+/// the duration of its first metric. Unknown without it, the time the response took to arrive
+/// also counting the network and the queueing in between.
+pub fn reported_execution_time(headers: &reqwest::header::HeaderMap) -> Option<Duration> {
+    let server_timing = headers.get("server-timing")?.to_str().ok()?;
+    let metric = server_timing.split(',').next()?;
+    let millis: f64 = metric
+        .split(';')
+        .skip(1)
+        .find_map(|param| param.trim().strip_prefix("dur="))?
+        .trim()
+        .parse()
+        .ok()?;
+    (millis.is_finite() && millis >= 0.0).then(|| Duration::from_secs_f64(millis / 1000.0))
+}
+
+struct EndpointState {
+    /// Moving average of the response times, unknown until the first response.
+    latency: Option<Duration>,
+    in_flight: u32,
+    consecutive_failures: u32,
+    healthy: bool,
+}
+
+struct Endpoint {
+    base_url: String,
+    state: Mutex<EndpointState>,
+}
+
+impl Endpoint {
+    fn new(base_url: String) -> Self {
+        Self {
+            base_url,
+            state: Mutex::new(EndpointState {
+                latency: None,
+                in_flight: 0,
+                consecutive_failures: 0,
+                healthy: true,
+            }),
+        }
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.state.lock().unwrap().healthy
+    }
+
+    /// Expected latency of one more query. Endpoints without any response yet are tried first.
+    fn cost(&self) -> f64 {
+        let state = self.state.lock().unwrap();
+        state.latency.map_or(0.0, |latency| latency.as_secs_f64()) * (state.in_flight + 1) as f64
+    }
+
+    /// Rendezvous hashing score of the endpoint for `deployment`.
+    fn score(&self, deployment: &DeploymentId) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        deployment.hash(&mut hasher);
+        self.base_url.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn start(&self) {
+        self.state.lock().unwrap().in_flight += 1;
+    }
+
+    fn succeeded(&self, elapsed: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.in_flight -= 1;
+        state.consecutive_failures = 0;
+        state.latency = Some(match state.latency {
+            Some(latency) => {
+                latency.mul_f64(1.0 - LATENCY_SMOOTHING) + elapsed.mul_f64(LATENCY_SMOOTHING)
+            }
+            None => elapsed,
+        });
+        if !state.healthy {
+            info!(
+                endpoint = self.base_url,
+                "Graph node query endpoint recovered"
+            );
+            state.healthy = true;
+        }
+    }
+
+    fn failed(&self, max_consecutive_failures: u32) {
+        let mut state = self.state.lock().unwrap();
+        state.in_flight -= 1;
+        state.consecutive_failures += 1;
+        if state.healthy && state.consecutive_failures >= max_consecutive_failures {
+            warn!(
+                endpoint = self.base_url,
+                failures = state.consecutive_failures,
+                "Graph node query endpoint is unhealthy, skipping it until its next health check"
+            );
+            state.healthy = false;
+        }
+    }
+
+    fn set_healthy(&self, healthy: bool) {
+        let mut state = self.state.lock().unwrap();
+        if healthy {
+            state.consecutive_failures = 0;
+        }
+        if healthy != state.healthy {
+            if healthy {
+                info!(
+                    endpoint = self.base_url,
+                    "Graph node query endpoint recovered"
+                );
+            } else {
+                warn!(
+                    endpoint = self.base_url,
+                    "Graph node query endpoint failed its health check"
+                );
+            }
+            state.healthy = healthy;
+        }
+    }
+}
+
+pub struct GraphNodeRouter {
+    endpoints: Vec<Endpoint>,
+    sticky_deployments: HashSet<DeploymentId>,
+    max_consecutive_failures: u32,
+}
+
+impl GraphNodeRouter {
+    pub fn new(
+        base_urls: Vec<String>,
+        sticky_deployments: HashSet<DeploymentId>,
+        max_consecutive_failures: u32,
+    ) -> Self {
+        assert!(!base_urls.is_empty(), "No graph node query endpoint");
+        Self {
+            endpoints: base_urls.into_iter().map(Endpoint::new).collect(),
+            sticky_deployments,
+            max_consecutive_failures,
+        }
+    }
+
+    pub fn from_config(config: &GraphNodeConfig) -> Self {
+        Self::new(
+            std::iter::once(config.query_base_url.clone())
+                .chain(config.additional_query_base_urls.iter().cloned())
+                .collect(),
+            config.sticky_deployments.clone(),
+            config.max_consecutive_failures,
+        )
+    }
+
+    /// Picks the endpoint to query `deployment` on, out of the ones not tried yet.
+    fn pick(&self, deployment: &DeploymentId, tried: &[usize]) -> Option<usize> {
+        let candidates: Vec<usize> = (0..self.endpoints.len())
+            .filter(|index| !tried.contains(index))
+            .collect();
+        let healthy: Vec<usize> = candidates
+            .iter()
+            .copied()
+            .filter(|index| self.endpoints[*index].is_healthy())
+            .collect();
+        // Any endpoint is better than failing the query
+        let candidates = if healthy.is_empty() {
+            candidates
+        } else {
+            healthy
+        };
+
+        if self.sticky_deployments.contains(deployment) {
+            candidates
+                .into_iter()
+                .max_by_key(|index| self.endpoints[*index].score(deployment))
+        } else {
+            candidates.into_iter().min_by(|a, b| {
+                self.endpoints[*a]
+                    .cost()
+                    .total_cmp(&self.endpoints[*b].cost())
+            })
+        }
+    }
+
+    /// Runs `query` against the base URL of the endpoint picked for `deployment`, retrying it on
+    /// the next endpoint as long as it fails.
+    pub async fn forward<F, Fut, T, E>(&self, deployment: &DeploymentId, query: F) -> Result<T, E>
+    where
+        F: Fn(String) -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+        E: std::fmt::Display,
+    {
+        let mut tried = Vec::new();
+        let mut last_error = None;
+        while let Some(index) = self.pick(deployment, &tried) {
+            let endpoint = &self.endpoints[index];
+            endpoint.start();
+            let start = Instant::now();
+            match query(endpoint.base_url.clone()).await {
+                Ok(response) => {
+                    endpoint.succeeded(start.elapsed());
+                    return Ok(response);
+                }
+                Err(error) => {
+                    endpoint.failed(self.max_consecutive_failures);
+                    warn!(
+                        endpoint = endpoint.base_url,
+                        %deployment,
+                        %error,
+                        "Failed to query graph node"
+                    );
+                    tried.push(index);
+                    last_error = Some(error);
+                }
+            }
+        }
+        Err(last_error.expect("at least one endpoint"))
+    }
+
+    /// Checks the health of every endpoint every `interval`, from a spawned task. Any response
+    /// other than a server error means the endpoint is up.
+    pub fn spawn_health_checks(self: &Arc<Self>, client: reqwest::Client, interval: Duration) {
+        // Nothing to pick from
+        if self.endpoints.len() == 1 {
+            return;
+        }
+        let router = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                for endpoint in &router.endpoints {
+                    let healthy = client
+                        .get(&endpoint.base_url)
+                        .timeout(HEALTH_CHECK_TIMEOUT)
+                        .send()
+                        .await
+                        .is_ok_and(|response| !response.status().is_server_error());
+                    endpoint.set_healthy(healthy);
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    fn deployment(n: u8) -> DeploymentId {
+        DeploymentId::from_str(&format!("0x{}", alloy_primitives::hex::encode([n; 32]))).unwrap()
+    }
+
+    fn router(sticky_deployments: HashSet<DeploymentId>) -> GraphNodeRouter {
+        GraphNodeRouter::new(
+            vec!["http://a".to_string(), "http://b".to_string()],
+            sticky_deployments,
+            2,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_routes_by_latency() {
+        let router = router(HashSet::new());
+        router.endpoints[0].start();
+        router.endpoints[0].succeeded(Duration::from_millis(100));
+        router.endpoints[1].start();
+        router.endpoints[1].succeeded(Duration::from_millis(10));
+        assert_eq!(router.pick(&deployment(1), &[]), Some(1));
+
+        // Busy enough to be slower than the other
+        for _ in 0..10 {
+            router.endpoints[1].start();
+        }
+        assert_eq!(router.pick(&deployment(1), &[]), Some(0));
+        assert_eq!(router.pick(&deployment(1), &[0, 1]), None);
+    }
+
+    #[tokio::test]
+    async fn test_retries_and_skips_unhealthy_endpoints() {
+        let router = router(HashSet::new());
+        // `a` is picked first, not having any latency yet either
+        let query = |base_url: String| async move {
+            if base_url == "http://a" {
+                Err("connection refused")
+            } else {
+                Ok(base_url)
+            }
+        };
+        for _ in 0..2 {
+            let response = router.forward(&deployment(1), query).await.unwrap();
+            assert_eq!(response, "http://b");
+        }
+        assert!(!router.endpoints[0].is_healthy());
+
+        // Back after a health check
+        router.endpoints[0].set_healthy(true);
+        assert!(router.endpoints[0].is_healthy());
+
+        // Queried anyway when no endpoint is healthy
+        router.endpoints[0].set_healthy(false);
+        router.endpoints[1].set_healthy(false);
+        let failing = |_| async { Err::<(), _>("connection refused") };
+        assert_eq!(
+            router.forward(&deployment(1), failing).await.unwrap_err(),
+            "connection refused"
+        );
+    }
+
+    #[test]
+    fn test_reported_execution_time() {
+        let headers = |server_timing: &str| {
+            let mut headers = reqwest::header::HeaderMap::new();
+            headers.insert("server-timing", server_timing.parse().unwrap());
+            headers
+        };
+        assert_eq!(
+            reported_execution_time(&headers("query;dur=12.5")),
+            Some(Duration::from_micros(12_500))
+        );
+        assert_eq!(
+            reported_execution_time(&headers(r#"query;desc="execution";dur=3, db;dur=1"#)),
+            Some(Duration::from_millis(3))
+        );
+        assert_eq!(reported_execution_time(&headers("query")), None);
+        assert_eq!(reported_execution_time(&headers("query;dur=-1")), None);
+        assert_eq!(
+            reported_execution_time(&reqwest::header::HeaderMap::new()),
+            None
+        );
+    }
+
+    #[test]
+    fn test_sticky_deployments() {
+        let deployments: Vec<DeploymentId> = (0..8).map(deployment).collect();
+        let router = router(deployments.iter().copied().collect());
+
+        let picks: Vec<usize> = deployments
+            .iter()
+            .map(|deployment| router.pick(deployment, &[]).unwrap())
+            .collect();
+        // Unaffected by the latency
+        router.endpoints[0].start();
+        router.endpoints[0].succeeded(Duration::from_secs(10));
+        for (deployment, pick) in deployments.iter().zip(&picks) {
+            assert_eq!(router.pick(deployment, &[]), Some(*pick));
+        }
+
+        // Only the deployments of the unhealthy endpoint move
+        router.endpoints[0].set_healthy(false);
+        for deployment in &deployments {
+            assert_eq!(router.pick(deployment, &[]), Some(1));
+        }
+    }
+}
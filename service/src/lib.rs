@@ -4,6 +4,11 @@
 mod cli;
 mod config;
 mod database;
+mod doctor;
 mod error;
+mod graph_node;
+mod migrate_from_ts;
+mod operator_auth;
 mod routes;
 pub mod service;
+mod sql;
@@ -3,14 +3,32 @@
 
 use std::process::ExitCode;
 
-use service::service::run;
+use indexer_common::indexer_service::http::{handle_shutdown_signals, shutdown_before_serving};
+use indexer_config::{EXIT_CODE_CONFIG_ERROR, EXIT_CODE_RUNTIME_ERROR};
+use service::service::{run, InvalidConfig};
+use tracing::info;
 
 #[tokio::main]
 async fn main() -> ExitCode {
     tracing_subscriber::fmt::init();
-    if let Err(e) = run().await {
-        tracing::error!("Indexer service error: {e}");
-        return ExitCode::from(1);
+    if let Err(e) = handle_shutdown_signals() {
+        tracing::error!("Failed to install signal handlers: {e}");
+        return ExitCode::from(EXIT_CODE_RUNTIME_ERROR);
+    }
+
+    let result = tokio::select! {
+        result = run() => result,
+        _ = shutdown_before_serving() => {
+            info!("Shut down while starting up");
+            Ok(())
+        }
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) if e.is::<InvalidConfig>() => ExitCode::from(EXIT_CODE_CONFIG_ERROR),
+        Err(e) => {
+            tracing::error!("Indexer service error: {e}");
+            ExitCode::from(EXIT_CODE_RUNTIME_ERROR)
+        }
     }
-    ExitCode::SUCCESS
 }
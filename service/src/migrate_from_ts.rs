@@ -0,0 +1,505 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Carries over the state of a TypeScript indexer-service deployment.
+//!
+//! Cost models are copied from the `CostModels` table of the old database, normalizing the
+//! deployment IDs to the hex format used by this crate. The TypeScript indexer-service was
+//! configured through `INDEXER_SERVICE_*` environment variables. They are read from an env file
+//! rather than from the environment, where they would be picked up as overrides of this crate's
+//! configuration. The ones with an equivalent in the configuration file are compared with it so
+//! that the operator can carry them over, and the command fails on the other ones, e.g. the
+//! allowlists, rather than dropping them silently.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt, fs,
+    str::FromStr,
+};
+
+use anyhow::{anyhow, Result};
+use indexer_common::db_pool::PoolSizing;
+use indexer_config::Config as MainConfig;
+use reqwest::Url;
+use serde_json::Value;
+use sqlx::{PgPool, Row};
+use thegraph::types::{Address, DeploymentId};
+
+use crate::{cli::MigrateFromTsArgs, database};
+
+#[derive(Debug, Clone, PartialEq)]
+struct CostModelRow {
+    model: Option<String>,
+    variables: Option<Value>,
+}
+
+#[derive(Debug, PartialEq)]
+enum CostModelChange {
+    Added(CostModelRow),
+    Changed {
+        from: CostModelRow,
+        to: CostModelRow,
+    },
+}
+
+/// Settings of the TypeScript indexer-service without an equivalent to carry over, as they are
+/// configured differently in this crate, e.g. the database connection.
+const NOT_CARRIED_OVER: &[&str] = &[
+    "INDEXER_SERVICE_PORT",
+    "INDEXER_SERVICE_METRICS_PORT",
+    "INDEXER_SERVICE_LOG_LEVEL",
+    "INDEXER_SERVICE_QUERY_TIMING_LOGS",
+    "INDEXER_SERVICE_GCLOUD_PROFILING",
+    "INDEXER_SERVICE_ETHEREUM",
+    "INDEXER_SERVICE_ETHEREUM_NETWORK",
+    "INDEXER_SERVICE_MNEMONIC",
+    "INDEXER_SERVICE_POSTGRES_HOST",
+    "INDEXER_SERVICE_POSTGRES_PORT",
+    "INDEXER_SERVICE_POSTGRES_USERNAME",
+    "INDEXER_SERVICE_POSTGRES_PASSWORD",
+    "INDEXER_SERVICE_POSTGRES_DATABASE",
+    "INDEXER_SERVICE_ALLOCATION_SYNCING_INTERVAL",
+];
+
+/// Settings compared with the configuration file, see [diff_settings].
+const CARRIED_OVER: &[&str] = &[
+    "INDEXER_SERVICE_INDEXER_ADDRESS",
+    "INDEXER_SERVICE_GRAPH_NODE_QUERY_ENDPOINT",
+    "INDEXER_SERVICE_GRAPH_NODE_STATUS_ENDPOINT",
+    "INDEXER_SERVICE_NETWORK_SUBGRAPH_ENDPOINT",
+    "INDEXER_SERVICE_NETWORK_SUBGRAPH_DEPLOYMENT",
+    "INDEXER_SERVICE_NETWORK_SUBGRAPH_AUTH_TOKEN",
+    "INDEXER_SERVICE_SERVE_NETWORK_SUBGRAPH",
+    "INDEXER_SERVICE_FREE_QUERY_AUTH_TOKEN",
+];
+
+/// Settings whose values aren't printed.
+const SECRET_SETTINGS: &[&str] = &[
+    "subgraphs.network.query_auth_token",
+    "service.free_query_auth_token",
+];
+
+/// A setting of the TypeScript indexer-service that differs from the configuration file.
+#[derive(Debug, PartialEq, Eq)]
+struct SettingDiff {
+    env_var: &'static str,
+    config_key: &'static str,
+    ts_value: String,
+    config_value: Option<String>,
+}
+
+impl fmt::Display for SettingDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if SECRET_SETTINGS.contains(&self.config_key) {
+            return write!(
+                f,
+                "{} = <redacted> (from {}), configured: {}",
+                self.config_key,
+                self.env_var,
+                if self.config_value.is_some() {
+                    "<redacted>"
+                } else {
+                    "<unset>"
+                }
+            );
+        }
+        write!(
+            f,
+            "{} = {:?} (from {}), configured: {}",
+            self.config_key,
+            self.ts_value,
+            self.env_var,
+            self.config_value.as_deref().unwrap_or("<unset>")
+        )
+    }
+}
+
+pub async fn run(config: &MainConfig, args: &MigrateFromTsArgs) -> Result<()> {
+    // Cost models aren't owned by an indexer
+    let sizing = PoolSizing::default();
+    let source = database::connect(&args.source_postgres_url, None, &sizing).await;
+    let target = database::connect(config.database.postgres_url.as_str(), None, &sizing).await;
+
+    let changes = diff_cost_models(
+        load_cost_models(&source).await?,
+        load_cost_models(&target).await?,
+    );
+    if changes.is_empty() {
+        println!("Cost models: up to date");
+    }
+    for (deployment, change) in &changes {
+        match change {
+            CostModelChange::Added(row) => println!("+ cost model {deployment}: {row:?}"),
+            CostModelChange::Changed { from, to } => {
+                println!("~ cost model {deployment}: {from:?} -> {to:?}")
+            }
+        }
+    }
+
+    let ts_env = match &args.ts_env_file {
+        Some(path) => parse_env_file(&fs::read_to_string(path)?),
+        None => HashMap::new(),
+    };
+    let unsupported = unsupported_settings(&ts_env);
+    if !unsupported.is_empty() {
+        return Err(anyhow!(
+            "Settings of the TypeScript indexer-service that can't be carried over: {}. \
+            Configure their equivalent by hand, or remove them from the env file",
+            unsupported.join(", ")
+        ));
+    }
+    let settings = diff_settings(config, |name| ts_env.get(name).cloned())?;
+    if settings.is_empty() {
+        println!("Settings: up to date");
+    }
+    for setting in &settings {
+        println!("~ setting {setting}");
+    }
+
+    if args.dry_run {
+        println!("Dry run, nothing was written");
+        return Ok(());
+    }
+
+    let mut transaction = target.begin().await?;
+    for (deployment, change) in &changes {
+        let row = match change {
+            CostModelChange::Added(row) | CostModelChange::Changed { to: row, .. } => row,
+        };
+        sqlx::query(
+            r#"
+                INSERT INTO "CostModels" (deployment, model, variables)
+                VALUES ($1, $2, $3)
+                ON CONFLICT (deployment) DO UPDATE
+                SET model = EXCLUDED.model, variables = EXCLUDED.variables
+            "#,
+        )
+        .bind(deployment)
+        .bind(&row.model)
+        .bind(&row.variables)
+        .execute(&mut *transaction)
+        .await?;
+    }
+    transaction.commit().await?;
+    println!("Migrated {} cost models", changes.len());
+    if !settings.is_empty() {
+        println!("The settings above need to be updated in the configuration file");
+    }
+
+    Ok(())
+}
+
+/// Parses the `KEY=VALUE` lines of an env file, as used with docker compose or systemd.
+fn parse_env_file(content: &str) -> HashMap<String, String> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let line = line.strip_prefix("export ").unwrap_or(line);
+            let (key, value) = line.split_once('=')?;
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|value| value.strip_suffix('"'))
+                .or_else(|| {
+                    value
+                        .strip_prefix('\'')
+                        .and_then(|value| value.strip_suffix('\''))
+                })
+                .unwrap_or(value);
+            Some((key.trim().to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+/// The `INDEXER_SERVICE_*` settings neither carried over nor known not to need it.
+fn unsupported_settings(env: &HashMap<String, String>) -> Vec<&str> {
+    let mut unsupported: Vec<&str> = env
+        .keys()
+        .map(String::as_str)
+        .filter(|name| name.starts_with("INDEXER_SERVICE_"))
+        .filter(|name| !CARRIED_OVER.contains(name) && !NOT_CARRIED_OVER.contains(name))
+        .collect();
+    unsupported.sort();
+    unsupported
+}
+
+async fn load_cost_models(pool: &PgPool) -> Result<BTreeMap<String, CostModelRow>> {
+    let rows = sqlx::query(
+        r#"
+            SELECT deployment, model, variables
+            FROM "CostModels"
+        "#,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    rows.iter()
+        .map(|row| {
+            Ok((
+                normalize_deployment(row.try_get("deployment")?)?,
+                CostModelRow {
+                    model: row.try_get("model")?,
+                    variables: row.try_get("variables")?,
+                },
+            ))
+        })
+        .collect()
+}
+
+/// The TypeScript indexer-service accepted both IPFS hashes and hex deployment IDs.
+fn normalize_deployment(deployment: &str) -> Result<String> {
+    if deployment == "global" {
+        return Ok(deployment.to_string());
+    }
+    let deployment = DeploymentId::from_str(deployment)
+        .map_err(|e| anyhow!("Invalid cost model deployment `{deployment}`: {e}"))?;
+    Ok(format!("{deployment:#x}"))
+}
+
+fn diff_cost_models(
+    source: BTreeMap<String, CostModelRow>,
+    target: BTreeMap<String, CostModelRow>,
+) -> BTreeMap<String, CostModelChange> {
+    source
+        .into_iter()
+        .filter_map(|(deployment, row)| {
+            let change = match target.get(&deployment) {
+                None => CostModelChange::Added(row),
+                Some(existing) if *existing != row => CostModelChange::Changed {
+                    from: existing.clone(),
+                    to: row,
+                },
+                Some(_) => return None,
+            };
+            Some((deployment, change))
+        })
+        .collect()
+}
+
+fn diff_settings(
+    config: &MainConfig,
+    env: impl Fn(&str) -> Option<String>,
+) -> Result<Vec<SettingDiff>> {
+    let mut diffs = Vec::new();
+    let mut compare = |env_var: &'static str,
+                       config_key: &'static str,
+                       config_value: Option<String>,
+                       normalize: fn(&str) -> Result<String>|
+     -> Result<()> {
+        let Some(ts_value) = env(env_var) else {
+            return Ok(());
+        };
+        let ts_value = normalize(ts_value.trim())
+            .map_err(|e| anyhow!("Invalid value for `{env_var}`: {e}"))?;
+        if config_value.as_ref() != Some(&ts_value) {
+            diffs.push(SettingDiff {
+                env_var,
+                config_key,
+                ts_value,
+                config_value,
+            });
+        }
+        Ok(())
+    };
+
+    let as_is: fn(&str) -> Result<String> = |value| Ok(value.to_string());
+    let url: fn(&str) -> Result<String> = |value| Ok(Url::parse(value)?.to_string());
+    let address: fn(&str) -> Result<String> = |value| Ok(Address::from_str(value)?.to_string());
+    let deployment: fn(&str) -> Result<String> =
+        |value| Ok(DeploymentId::from_str(value)?.to_string());
+    let boolean: fn(&str) -> Result<String> = |value| Ok(bool::from_str(value)?.to_string());
+    // Several tokens could be given to the TypeScript indexer-service
+    let single_token: fn(&str) -> Result<String> = |value| {
+        if value.contains([',', ' ']) {
+            return Err(anyhow!(
+                "several tokens, only a single free query auth token is supported"
+            ));
+        }
+        Ok(value.to_string())
+    };
+
+    compare(
+        "INDEXER_SERVICE_INDEXER_ADDRESS",
+        "indexer.indexer_address",
+        Some(config.indexer.indexer_address.to_string()),
+        address,
+    )?;
+    compare(
+        "INDEXER_SERVICE_GRAPH_NODE_QUERY_ENDPOINT",
+        "graph_node.query_url",
+        Some(config.graph_node.query_url.to_string()),
+        url,
+    )?;
+    compare(
+        "INDEXER_SERVICE_GRAPH_NODE_STATUS_ENDPOINT",
+        "graph_node.status_url",
+        Some(config.graph_node.status_url.to_string()),
+        url,
+    )?;
+    compare(
+        "INDEXER_SERVICE_NETWORK_SUBGRAPH_ENDPOINT",
+        "subgraphs.network.query_url",
+        Some(config.subgraphs.network.config.query_url.to_string()),
+        url,
+    )?;
+    compare(
+        "INDEXER_SERVICE_NETWORK_SUBGRAPH_DEPLOYMENT",
+        "subgraphs.network.deployment_id",
+        config
+            .subgraphs
+            .network
+            .config
+            .deployment_id
+            .map(|deployment| deployment.to_string()),
+        deployment,
+    )?;
+    compare(
+        "INDEXER_SERVICE_NETWORK_SUBGRAPH_AUTH_TOKEN",
+        "subgraphs.network.query_auth_token",
+        config.subgraphs.network.config.query_auth_token.clone(),
+        as_is,
+    )?;
+    compare(
+        "INDEXER_SERVICE_SERVE_NETWORK_SUBGRAPH",
+        "service.serve_network_subgraph",
+        Some(config.service.serve_network_subgraph.to_string()),
+        boolean,
+    )?;
+    compare(
+        "INDEXER_SERVICE_FREE_QUERY_AUTH_TOKEN",
+        "service.free_query_auth_token",
+        config.service.free_query_auth_token.clone(),
+        single_token,
+    )?;
+
+    Ok(diffs)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_diff_cost_models() {
+        let row = |model: &str| CostModelRow {
+            model: Some(model.to_string()),
+            variables: Some(json!({})),
+        };
+        let deployment =
+            normalize_deployment("QmWmyoMoctfbAaiEs2G46gpeUmhqFRDW6KWo64y5r581Vz").unwrap();
+        assert!(deployment.starts_with("0x") && deployment.len() == 66);
+        assert_eq!(normalize_deployment(&deployment).unwrap(), deployment);
+        assert_eq!(normalize_deployment("global").unwrap(), "global");
+        assert!(normalize_deployment("not a deployment").is_err());
+        let other_deployment = format!("0x{}", "0a".repeat(32));
+
+        let source = BTreeMap::from([
+            ("global".to_string(), row("default => 1;")),
+            (deployment.clone(), row("default => 2;")),
+            (other_deployment.clone(), row("default => 3;")),
+        ]);
+        let target = BTreeMap::from([
+            ("global".to_string(), row("default => 1;")),
+            (deployment.clone(), row("default => 0;")),
+        ]);
+
+        assert_eq!(
+            diff_cost_models(source, target),
+            BTreeMap::from([
+                (
+                    deployment,
+                    CostModelChange::Changed {
+                        from: row("default => 0;"),
+                        to: row("default => 2;"),
+                    }
+                ),
+                (
+                    other_deployment,
+                    CostModelChange::Added(row("default => 3;"))
+                ),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_env_file() {
+        let env = parse_env_file(
+            r#"
+                # TypeScript indexer-service
+                INDEXER_SERVICE_FREE_QUERY_AUTH_TOKEN="secret"
+                export INDEXER_SERVICE_SERVE_NETWORK_SUBGRAPH=false
+            "#,
+        );
+        assert_eq!(
+            env,
+            HashMap::from([
+                (
+                    "INDEXER_SERVICE_FREE_QUERY_AUTH_TOKEN".to_string(),
+                    "secret".to_string()
+                ),
+                (
+                    "INDEXER_SERVICE_SERVE_NETWORK_SUBGRAPH".to_string(),
+                    "false".to_string()
+                ),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_diff_settings() {
+        let config = MainConfig::parse(
+            indexer_config::ConfigPrefix::Service,
+            &std::path::PathBuf::from("../config/minimal-config-example.toml"),
+        )
+        .unwrap();
+        let env = HashMap::from([
+            (
+                "INDEXER_SERVICE_INDEXER_ADDRESS",
+                config.indexer.indexer_address.to_string().to_lowercase(),
+            ),
+            (
+                "INDEXER_SERVICE_FREE_QUERY_AUTH_TOKEN",
+                "secret".to_string(),
+            ),
+        ]);
+
+        let diffs = diff_settings(&config, |name| env.get(name).cloned()).unwrap();
+
+        assert_eq!(
+            diffs,
+            vec![SettingDiff {
+                env_var: "INDEXER_SERVICE_FREE_QUERY_AUTH_TOKEN",
+                config_key: "service.free_query_auth_token",
+                ts_value: "secret".to_string(),
+                config_value: None,
+            }]
+        );
+        assert!(!diffs[0].to_string().contains("secret"));
+
+        let env = HashMap::from([(
+            "INDEXER_SERVICE_FREE_QUERY_AUTH_TOKEN",
+            "secret,other-secret".to_string(),
+        )]);
+        assert!(diff_settings(&config, |name| env.get(name).cloned()).is_err());
+    }
+
+    #[test]
+    fn test_unsupported_settings() {
+        let env = parse_env_file(
+            r#"
+                INDEXER_SERVICE_FREE_QUERY_AUTH_TOKEN=secret
+                INDEXER_SERVICE_POSTGRES_HOST=localhost
+                INDEXER_SERVICE_ALLOWED_GATEWAYS=0x0000000000000000000000000000000000000001
+                OTHER_SERVICE_SETTING=1
+            "#,
+        );
+        assert_eq!(
+            unsupported_settings(&env),
+            vec!["INDEXER_SERVICE_ALLOWED_GATEWAYS"]
+        );
+    }
+}
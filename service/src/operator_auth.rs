@@ -0,0 +1,105 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Authentication of the routes meant for the indexer's own operators and infrastructure: the
+//! cost model mutations and simulations, the query analytics and the agreement progress reports.
+//! They require `service.operator_auth_token` as a bearer token, rather than the free query auth
+//! token that may be handed out to consumers, and are refused when it isn't set. The senders
+//! register their indexing agreements with a token of their own instead,
+//! `tap.indexing_agreements.registration_auth_token`.
+
+use std::sync::Arc;
+
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{header::AUTHORIZATION, request::Parts, HeaderMap},
+};
+use subtle::ConstantTimeEq;
+
+use crate::{error::SubgraphServiceError, service::SubgraphServiceState};
+
+/// Extracted from the requests carrying the operator auth token, rejecting the others.
+pub struct OperatorAuth;
+
+/// Whether `headers` carry `expected` as a bearer token, compared in constant time.
+fn authorized(headers: &HeaderMap, expected: Option<&str>) -> bool {
+    let token = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match (token, expected) {
+        (Some(token), Some(expected)) => token.as_bytes().ct_eq(expected.as_bytes()).into(),
+        _ => false,
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<Arc<SubgraphServiceState>> for OperatorAuth {
+    type Rejection = SubgraphServiceError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<SubgraphServiceState>,
+    ) -> Result<Self, Self::Rejection> {
+        if authorized(
+            &parts.headers,
+            state.config.0.server.operator_auth_token.as_deref(),
+        ) {
+            Ok(OperatorAuth)
+        } else {
+            Err(SubgraphServiceError::Unauthorized)
+        }
+    }
+}
+
+/// Extracted from the requests carrying the agreement registration auth token, rejecting the
+/// others.
+pub struct AgreementRegistrationAuth;
+
+#[async_trait]
+impl FromRequestParts<Arc<SubgraphServiceState>> for AgreementRegistrationAuth {
+    type Rejection = SubgraphServiceError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<SubgraphServiceState>,
+    ) -> Result<Self, Self::Rejection> {
+        if authorized(
+            &parts.headers,
+            state
+                .config
+                .0
+                .tap
+                .agreement_registration_auth_token
+                .as_deref(),
+        ) {
+            Ok(AgreementRegistrationAuth)
+        } else {
+            Err(SubgraphServiceError::Unauthorized)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(authorization: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, authorization.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_authorized() {
+        assert!(authorized(&headers("Bearer operator"), Some("operator")));
+        assert!(!authorized(&headers("Bearer free-query"), Some("operator")));
+        assert!(!authorized(&headers("Bearer operator-2"), Some("operator")));
+        assert!(!authorized(&headers("operator"), Some("operator")));
+        assert!(!authorized(&HeaderMap::new(), Some("operator")));
+        // Refused when not configured
+        assert!(!authorized(&headers("Bearer "), None));
+        assert!(!authorized(&headers("Bearer operator"), None));
+    }
+}
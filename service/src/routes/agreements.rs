@@ -0,0 +1,73 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::str::FromStr;
+use std::sync::Arc;
+
+use alloy_primitives::FixedBytes;
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use indexer_common::agreements::{self, SignedIndexingAgreement};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{
+    error::SubgraphServiceError,
+    operator_auth::{AgreementRegistrationAuth, OperatorAuth},
+    service::SubgraphServiceState,
+};
+
+#[derive(Deserialize)]
+pub struct AgreementProgress {
+    entities: u64,
+    blocks: u64,
+}
+
+/// Registers an indexing agreement signed by one of the sender's signers. Requires the agreement
+/// registration auth token.
+pub async fn register_agreement(
+    State(state): State<Arc<SubgraphServiceState>>,
+    _: AgreementRegistrationAuth,
+    Json(agreement): Json<SignedIndexingAgreement>,
+) -> Result<Json<Value>, SubgraphServiceError> {
+    let signer = agreement
+        .recover_signer(&state.agreement_domain)
+        .map_err(|e| SubgraphServiceError::InvalidAgreement(e.into()))?;
+
+    agreements::store(&state.database, signer, &agreement)
+        .await
+        .map_err(SubgraphServiceError::AgreementStorageError)?;
+
+    Ok(Json(json!({
+        "agreementId": agreement.message.agreementId,
+        "signer": signer,
+    })))
+}
+
+/// Accrues fees for the entities and blocks processed under an agreement. Progress is reported
+/// by the indexer's own infrastructure, so this requires the operator auth token.
+pub async fn report_progress(
+    State(state): State<Arc<SubgraphServiceState>>,
+    _: OperatorAuth,
+    Path(agreement_id): Path<String>,
+    Json(progress): Json<AgreementProgress>,
+) -> Result<Json<Value>, SubgraphServiceError> {
+    let agreement_id = FixedBytes::<32>::from_str(&agreement_id)
+        .map_err(|e| SubgraphServiceError::InvalidAgreement(e.into()))?;
+    let accrued_fees = agreements::record_progress(
+        &state.database,
+        agreement_id,
+        progress.entities,
+        progress.blocks,
+    )
+    .await
+    .map_err(SubgraphServiceError::AgreementStorageError)?
+    .ok_or(SubgraphServiceError::AgreementNotFound(agreement_id))?;
+
+    Ok(Json(json!({
+        "agreementId": agreement_id,
+        "accruedFees": accrued_fees.to_string(),
+    })))
+}
@@ -0,0 +1,47 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+
+use axum::{
+    extract::{Query, State},
+    Json,
+};
+use indexer_common::db_pool::{self, Priority};
+use indexer_common::query_stats::{self, DeploymentQueryStats};
+use serde::Deserialize;
+
+use crate::{
+    error::SubgraphServiceError, operator_auth::OperatorAuth, service::SubgraphServiceState,
+};
+
+#[derive(Deserialize)]
+pub struct QueryStatsParams {
+    /// Only aggregate the queries served within the last `window_secs` seconds.
+    window_secs: Option<u64>,
+    /// Report the stats as of this time, in seconds since the UNIX epoch, instead of now.
+    as_of: Option<u64>,
+}
+
+/// Response sizes and execution times of the paid queries, aggregated per deployment, to
+/// compare the fees charged against the resources used. Requires the operator auth token.
+pub async fn deployment_query_stats(
+    State(state): State<Arc<SubgraphServiceState>>,
+    _: OperatorAuth,
+    Query(params): Query<QueryStatsParams>,
+) -> Result<Json<Vec<DeploymentQueryStats>>, SubgraphServiceError> {
+    db_pool::prioritized(
+        Priority::Read,
+        query_stats::per_deployment(
+            &state.database,
+            params.window_secs.map(Duration::from_secs),
+            params
+                .as_of
+                .map(|as_of| UNIX_EPOCH + Duration::from_secs(as_of)),
+        ),
+    )
+    .await
+    .map(Json)
+    .map_err(SubgraphServiceError::QueryStatsError)
+}
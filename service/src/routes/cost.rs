@@ -1,17 +1,35 @@
 // Copyright 2023-, GraphOps and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
+use std::collections::VecDeque;
 use std::str::FromStr;
 use std::sync::Arc;
 
-use async_graphql::{Context, EmptyMutation, EmptySubscription, Object, Schema, SimpleObject};
-use async_graphql_axum::{GraphQLRequest, GraphQLResponse};
-use axum::extract::State;
+use async_graphql::{Context, Data, Object, Schema, SimpleObject};
+use async_graphql_axum::{
+    GraphQLProtocol, GraphQLRequest, GraphQLResponse, GraphQLWebSocket, ALL_WEBSOCKET_PROTOCOLS,
+};
+use axum::extract::{Query as QueryParams, State, WebSocketUpgrade};
+use axum::response::Response;
+use axum::{Extension, Json};
+use futures_util::{stream, Stream};
+use indexer_common::events::{Event, RecordedEvent};
+use indexer_common::indexer_service::http::EligibleAllocations;
+use indexer_common::tap::apply_multiplier;
+use indexer_common::types::GrtWei;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use thegraph::types::DeploymentId;
+use sqlx::PgPool;
+use thegraph::types::{Address, DeploymentId};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::warn;
 
-use crate::database::{self, CostModel};
+use crate::database::{
+    self, CostModel, CostModelChange, CostModelTemplate, CostModelTemplateDeployment,
+};
+use crate::error::SubgraphServiceError;
+use crate::operator_auth::OperatorAuth;
+use crate::routes::cost_simulation::compile;
 use crate::service::SubgraphServiceState;
 
 #[derive(Clone, Debug, Serialize, Deserialize, SimpleObject)]
@@ -31,6 +49,82 @@ impl From<CostModel> for GraphQlCostModel {
     }
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize, SimpleObject)]
+pub struct GraphQlCostModelChange {
+    pub version: i64,
+    pub deployment: String,
+    pub change: String,
+    pub old_model: Option<String>,
+    pub old_variables: Option<Value>,
+    pub new_model: Option<String>,
+    pub new_variables: Option<Value>,
+    pub changed_by: String,
+    pub changed_at: String,
+}
+
+impl From<CostModelChange> for GraphQlCostModelChange {
+    fn from(change: CostModelChange) -> Self {
+        Self {
+            version: change.version,
+            deployment: change.deployment,
+            change: change.change,
+            old_model: change.old_model,
+            old_variables: change.old_variables,
+            new_model: change.new_model,
+            new_variables: change.new_variables,
+            changed_by: change.changed_by,
+            changed_at: change.changed_at,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, SimpleObject)]
+pub struct GraphQlCostModelTemplate {
+    pub name: String,
+    pub model: String,
+    pub variables: Option<Value>,
+}
+
+impl From<CostModelTemplate> for GraphQlCostModelTemplate {
+    fn from(template: CostModelTemplate) -> Self {
+        Self {
+            name: template.name,
+            model: template.model,
+            variables: template.variables,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, SimpleObject)]
+pub struct GraphQlCostModelTemplateDeployment {
+    pub deployment: String,
+    pub template: String,
+    /// Variables overriding the ones of the template.
+    pub variables: Option<Value>,
+}
+
+impl From<CostModelTemplateDeployment> for GraphQlCostModelTemplateDeployment {
+    fn from(deployment: CostModelTemplateDeployment) -> Self {
+        Self {
+            deployment: deployment.deployment.to_string(),
+            template: deployment.template,
+            variables: deployment.variables,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, SimpleObject)]
+pub struct GraphQlEligibleAllocations {
+    pub deployment: String,
+    pub allocations: Vec<String>,
+}
+
+/// Changes listed by default by `costModelHistory`.
+const DEFAULT_HISTORY_LIMIT: i64 = 100;
+
+/// Whether the request carries the operator auth token, required by the mutations.
+struct Authorized(bool);
+
 #[derive(Default)]
 pub struct Query;
 
@@ -61,21 +155,350 @@ impl Query {
             .await
             .map(|model_opt| model_opt.map(GraphQlCostModel::from))
     }
+
+    /// Latest changes of the cost model of a deployment, or of the global one with `global`,
+    /// newest first.
+    async fn cost_model_history(
+        &self,
+        ctx: &Context<'_>,
+        deployment: String,
+        limit: Option<i64>,
+    ) -> Result<Vec<GraphQlCostModelChange>, anyhow::Error> {
+        let pool = &ctx.data_unchecked::<Arc<SubgraphServiceState>>().database;
+        let history =
+            database::cost_model_history(pool, &deployment, limit.unwrap_or(DEFAULT_HISTORY_LIMIT))
+                .await?;
+        Ok(history.into_iter().map(Into::into).collect())
+    }
+
+    async fn cost_model_templates(
+        &self,
+        ctx: &Context<'_>,
+    ) -> Result<Vec<GraphQlCostModelTemplate>, anyhow::Error> {
+        let pool = &ctx.data_unchecked::<Arc<SubgraphServiceState>>().database;
+        let templates = database::cost_model_templates(pool).await?;
+        Ok(templates.into_iter().map(Into::into).collect())
+    }
+
+    /// The deployments priced with `template`, or with any template.
+    async fn cost_model_template_deployments(
+        &self,
+        ctx: &Context<'_>,
+        template: Option<String>,
+    ) -> Result<Vec<GraphQlCostModelTemplateDeployment>, anyhow::Error> {
+        let pool = &ctx.data_unchecked::<Arc<SubgraphServiceState>>().database;
+        let deployments =
+            database::cost_model_template_deployments(pool, template.as_deref()).await?;
+        Ok(deployments.into_iter().map(Into::into).collect())
+    }
+}
+
+#[derive(Default)]
+pub struct Mutation;
+
+#[Object]
+impl Mutation {
+    /// Restores the cost model of a deployment, or the global one, as of one of its versions
+    /// listed by `costModelHistory`. Requires the free query auth token.
+    async fn rollback_cost_model(
+        &self,
+        ctx: &Context<'_>,
+        deployment: String,
+        version: i64,
+        author: Option<String>,
+    ) -> Result<Option<GraphQlCostModel>, anyhow::Error> {
+        if !ctx.data_unchecked::<Authorized>().0 {
+            return Err(SubgraphServiceError::Unauthorized.into());
+        }
+        let pool = &ctx.data_unchecked::<Arc<SubgraphServiceState>>().database;
+        let author = author.unwrap_or_else(|| "indexer-service".to_string());
+        let restored = database::rollback_cost_model(pool, &deployment, version, &author).await?;
+        Ok(restored.map(|(model, variables)| GraphQlCostModel {
+            deployment,
+            model,
+            variables,
+        }))
+    }
+
+    /// Creates or updates a cost model template, updating the cost models of all the deployments
+    /// referencing it. Requires the free query auth token.
+    async fn set_cost_model_template(
+        &self,
+        ctx: &Context<'_>,
+        name: String,
+        model: String,
+        variables: Option<Value>,
+    ) -> Result<GraphQlCostModelTemplate, anyhow::Error> {
+        if !ctx.data_unchecked::<Authorized>().0 {
+            return Err(SubgraphServiceError::Unauthorized.into());
+        }
+        compile(&model, variables.as_ref()).map_err(SubgraphServiceError::InvalidCostModel)?;
+        let pool = &ctx.data_unchecked::<Arc<SubgraphServiceState>>().database;
+        let template = CostModelTemplate {
+            name,
+            model,
+            variables,
+        };
+        database::set_cost_model_template(pool, &template).await?;
+        Ok(template.into())
+    }
+
+    /// Deletes a cost model template no deployment references anymore. Requires the free query
+    /// auth token.
+    async fn delete_cost_model_template(
+        &self,
+        ctx: &Context<'_>,
+        name: String,
+    ) -> Result<bool, anyhow::Error> {
+        if !ctx.data_unchecked::<Authorized>().0 {
+            return Err(SubgraphServiceError::Unauthorized.into());
+        }
+        let pool = &ctx.data_unchecked::<Arc<SubgraphServiceState>>().database;
+        database::delete_cost_model_template(pool, &name).await
+    }
+
+    /// Prices a deployment with a template, its `variables` overriding the ones of the
+    /// template, over its own cost model. Returns the resulting cost model. Requires the free
+    /// query auth token.
+    async fn assign_cost_model_template(
+        &self,
+        ctx: &Context<'_>,
+        deployment: String,
+        template: String,
+        variables: Option<Value>,
+    ) -> Result<Option<GraphQlCostModel>, anyhow::Error> {
+        if !ctx.data_unchecked::<Authorized>().0 {
+            return Err(SubgraphServiceError::Unauthorized.into());
+        }
+        let deployment_id = DeploymentId::from_str(&deployment)?;
+        let pool = &ctx.data_unchecked::<Arc<SubgraphServiceState>>().database;
+        database::assign_cost_model_template(pool, &deployment_id, &template, variables.as_ref())
+            .await?;
+        database::cost_model(pool, &deployment_id)
+            .await
+            .map(|model_opt| model_opt.map(GraphQlCostModel::from))
+    }
+
+    /// Prices a deployment with its own cost model again. Requires the free query auth token.
+    async fn unassign_cost_model_template(
+        &self,
+        ctx: &Context<'_>,
+        deployment: String,
+    ) -> Result<bool, anyhow::Error> {
+        if !ctx.data_unchecked::<Authorized>().0 {
+            return Err(SubgraphServiceError::Unauthorized.into());
+        }
+        let deployment_id = DeploymentId::from_str(&deployment)?;
+        let pool = &ctx.data_unchecked::<Arc<SubgraphServiceState>>().database;
+        database::unassign_cost_model_template(pool, &deployment_id).await
+    }
+}
+
+#[derive(Default)]
+pub struct Subscription;
+
+#[async_graphql::Subscription]
+impl Subscription {
+    /// Cost models of `deployments` every time they change, including through the global cost
+    /// model, pushed instead of polling `costModels`. A deployment without a cost model anymore
+    /// is pushed without `model` and `variables`.
+    async fn cost_model_changes(
+        &self,
+        ctx: &Context<'_>,
+        deployments: Vec<String>,
+    ) -> Result<impl Stream<Item = GraphQlCostModel>, anyhow::Error> {
+        let deployments = deployments
+            .into_iter()
+            .map(|s| DeploymentId::from_str(&s))
+            .collect::<Result<Vec<DeploymentId>, _>>()?;
+        let pool = ctx
+            .data_unchecked::<Arc<SubgraphServiceState>>()
+            .database
+            .clone();
+        let events = ctx
+            .data_unchecked::<broadcast::Sender<RecordedEvent>>()
+            .subscribe();
+        Ok(stream::unfold(
+            CostModelChanges {
+                pool,
+                deployments,
+                events,
+                pending: VecDeque::new(),
+            },
+            |mut changes| async move { changes.next().await.map(|model| (model, changes)) },
+        ))
+    }
+
+    /// Allocations of `deployment` accepting the receipts of `sender`, as listed by
+    /// `/deployments/:id/allocations`, first as of now and then every time they change.
+    async fn eligible_allocations(
+        &self,
+        ctx: &Context<'_>,
+        deployment: String,
+        sender: Option<String>,
+    ) -> Result<impl Stream<Item = GraphQlEligibleAllocations>, anyhow::Error> {
+        let deployment_id = DeploymentId::from_str(&deployment)?;
+        let sender = sender.map(|s| Address::from_str(&s)).transpose()?;
+        let events = ctx
+            .data_unchecked::<broadcast::Sender<RecordedEvent>>()
+            .subscribe();
+        let watch = ctx
+            .data_unchecked::<Arc<EligibleAllocations>>()
+            .clone()
+            .watch(deployment_id, sender, events);
+        Ok(stream::unfold(watch, move |mut watch| {
+            let deployment = deployment.clone();
+            async move {
+                let allocations = watch.next().await?;
+                let allocations = GraphQlEligibleAllocations {
+                    deployment,
+                    allocations: allocations.iter().map(ToString::to_string).collect(),
+                };
+                Some((allocations, watch))
+            }
+        }))
+    }
+}
+
+/// Cost models of the deployments of a `costModelChanges` subscription, as the events of the
+/// event log report them changed.
+struct CostModelChanges {
+    pool: PgPool,
+    deployments: Vec<DeploymentId>,
+    events: broadcast::Receiver<RecordedEvent>,
+    pending: VecDeque<GraphQlCostModel>,
 }
 
-pub type CostSchema = Schema<Query, EmptyMutation, EmptySubscription>;
+impl CostModelChanges {
+    async fn next(&mut self) -> Option<GraphQlCostModel> {
+        loop {
+            if let Some(model) = self.pending.pop_front() {
+                return Some(model);
+            }
+            let changed = match self.events.recv().await {
+                Ok(RecordedEvent {
+                    event: Event::CostModelChanged { deployment },
+                    ..
+                }) => match deployment {
+                    // Applies to the deployments without their own
+                    None => self.deployments.clone(),
+                    Some(deployment) if self.deployments.contains(&deployment) => {
+                        vec![deployment]
+                    }
+                    Some(_) => continue,
+                },
+                Ok(_) => continue,
+                // The missed events may have been changes
+                Err(RecvError::Lagged(_)) => self.deployments.clone(),
+                Err(RecvError::Closed) => return None,
+            };
+            for deployment in changed {
+                match database::cost_model(&self.pool, &deployment).await {
+                    Ok(model) => self.pending.push_back(model.map_or_else(
+                        || GraphQlCostModel {
+                            deployment: deployment.to_string(),
+                            model: None,
+                            variables: None,
+                        },
+                        Into::into,
+                    )),
+                    Err(e) => warn!(%deployment, "Failed to read the changed cost model: {}", e),
+                }
+            }
+        }
+    }
+}
+
+pub type CostSchema = Schema<Query, Mutation, Subscription>;
 
 pub async fn build_schema() -> CostSchema {
-    Schema::build(Query, EmptyMutation, EmptySubscription).finish()
+    Schema::build(Query, Mutation, Subscription).finish()
 }
 
 pub async fn cost(
     State(state): State<Arc<SubgraphServiceState>>,
+    operator: Option<OperatorAuth>,
     req: GraphQLRequest,
 ) -> GraphQLResponse {
+    let authorized = operator.is_some();
     state
         .cost_schema
-        .execute(req.into_inner().data(state.clone()))
+        .execute(
+            req.into_inner()
+                .data(state.clone())
+                .data(Authorized(authorized)),
+        )
         .await
         .into()
 }
+
+/// Serves the subscriptions of the cost schema over WebSocket, with either the
+/// `graphql-transport-ws` or the `graphql-ws` protocol.
+pub async fn cost_subscriptions(
+    State(state): State<Arc<SubgraphServiceState>>,
+    Extension(events): Extension<broadcast::Sender<RecordedEvent>>,
+    Extension(eligible_allocations): Extension<Arc<EligibleAllocations>>,
+    protocol: GraphQLProtocol,
+    websocket: WebSocketUpgrade,
+) -> Response {
+    let mut data = Data::default();
+    data.insert(state.clone());
+    data.insert(events);
+    data.insert(eligible_allocations);
+    // The mutations aren't served over WebSocket
+    data.insert(Authorized(false));
+    websocket
+        .protocols(ALL_WEBSOCKET_PROTOCOLS)
+        .on_upgrade(move |stream| {
+            GraphQLWebSocket::new(stream, state.cost_schema.clone(), protocol)
+                .with_data(data)
+                .serve()
+        })
+}
+
+#[derive(Deserialize)]
+pub struct QuoteParams {
+    deployment: DeploymentId,
+    sender: Option<Address>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CostQuote {
+    pub deployment: DeploymentId,
+    pub model: Option<String>,
+    pub variables: Option<Value>,
+    /// Multiplier applied on top of the cost model for the sender, 1 if none is set.
+    pub price_multiplier: f64,
+    /// Smallest receipt value accepted from the sender, in GRT wei.
+    pub min_receipt_value: GrtWei,
+}
+
+/// Cost model of a deployment, as priced for a given sender.
+pub async fn quote(
+    State(state): State<Arc<SubgraphServiceState>>,
+    QueryParams(params): QueryParams<QuoteParams>,
+) -> Result<Json<CostQuote>, SubgraphServiceError> {
+    let cost_model = database::cost_model(&state.database, &params.deployment)
+        .await
+        .map_err(SubgraphServiceError::CostModelError)?;
+
+    let tap_config = &state.config.0.tap;
+    let price_multiplier = params
+        .sender
+        .and_then(|sender| tap_config.sender_price_multipliers.get(&sender).copied())
+        .unwrap_or(1.0);
+    let min_receipt_value = tap_config
+        .receipt_min_value_per_deployment
+        .get(&params.deployment)
+        .copied()
+        .unwrap_or(tap_config.receipt_min_value);
+
+    Ok(Json(CostQuote {
+        deployment: params.deployment,
+        model: cost_model.as_ref().and_then(|m| m.model.clone()),
+        variables: cost_model.and_then(|m| m.variables),
+        price_multiplier,
+        min_receipt_value: GrtWei(apply_multiplier(min_receipt_value, price_multiplier)),
+    }))
+}
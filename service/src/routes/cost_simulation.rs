@@ -0,0 +1,251 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! What-if simulation of a candidate cost model. The recent paid queries of the deployment are
+//! priced again with both the current and the candidate model, with the Agora evaluator, to
+//! tell which queries the candidate would have rejected and how their prices would have moved,
+//! before deploying it.
+
+use std::sync::Arc;
+
+use axum::{extract::State, Json};
+use cost_model::CostModel as AgoraModel;
+use indexer_common::query_stats::{self, SampledQuery};
+use indexer_common::tap::apply_multiplier;
+use indexer_common::types::GrtWei;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use thegraph::types::DeploymentId;
+
+use crate::database;
+use crate::{
+    error::SubgraphServiceError, operator_auth::OperatorAuth, service::SubgraphServiceState,
+};
+
+const DEFAULT_SAMPLE_SIZE: u32 = 100;
+const MAX_SAMPLE_SIZE: u32 = 1000;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SimulationRequest {
+    deployment: DeploymentId,
+    model: String,
+    variables: Option<Value>,
+    /// Number of recent paid queries to replay, 100 by default and at most 1000.
+    sample_size: Option<u32>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SimulationReport {
+    pub deployment: DeploymentId,
+    pub sampled_queries: usize,
+    /// Outcome under the cost model currently deployed, `None` if there is none.
+    pub current: Option<PricingOutcome>,
+    pub candidate: PricingOutcome,
+    /// Sum of the candidate prices minus the current ones, in GRT wei, over the queries priced
+    /// by both models.
+    pub total_price_delta: String,
+    pub queries: Vec<SimulatedQuery>,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PricingOutcome {
+    pub accepted: u64,
+    /// Queries the model doesn't price, e.g. matching no statement.
+    pub rejected_unpriced: u64,
+    /// Queries paid less than the model's price.
+    pub rejected_underpaid: u64,
+    /// Sum of the prices of the accepted queries.
+    pub total_price: GrtWei,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SimulatedQuery {
+    pub query: String,
+    pub paid_value: GrtWei,
+    pub current_price: Option<GrtWei>,
+    pub candidate_price: Option<GrtWei>,
+    /// Why the candidate doesn't price the query, if it doesn't.
+    pub candidate_error: Option<String>,
+}
+
+/// Prices the recent paid queries of a deployment with a candidate cost model. Requires the
+/// operator auth token.
+pub async fn simulate(
+    State(state): State<Arc<SubgraphServiceState>>,
+    _: OperatorAuth,
+    Json(request): Json<SimulationRequest>,
+) -> Result<Json<SimulationReport>, SubgraphServiceError> {
+    let sample_size = request
+        .sample_size
+        .unwrap_or(DEFAULT_SAMPLE_SIZE)
+        .min(MAX_SAMPLE_SIZE);
+    let queries = query_stats::recent_queries(&state.database, &request.deployment, sample_size)
+        .await
+        .map_err(SubgraphServiceError::QueryStatsError)?;
+    let current = database::cost_model(&state.database, &request.deployment)
+        .await
+        .map_err(SubgraphServiceError::CostModelError)?;
+
+    let candidate = compile(&request.model, request.variables.as_ref())
+        .map_err(SubgraphServiceError::InvalidCostModel)?;
+    let current = current
+        .and_then(|current| Some((current.model?, current.variables)))
+        .map(|(model, variables)| compile(&model, variables.as_ref()))
+        .transpose()
+        .map_err(|e| SubgraphServiceError::CostModelError(anyhow::anyhow!(e)))?;
+
+    Ok(Json(simulate_pricing(
+        request.deployment,
+        current.as_ref(),
+        &candidate,
+        queries,
+    )))
+}
+
+/// Compiles `model` with its `variables` as the globals.
+pub(crate) fn compile(model: &str, variables: Option<&Value>) -> Result<AgoraModel, String> {
+    let globals = variables.map_or_else(|| "{}".to_string(), Value::to_string);
+    AgoraModel::compile(model, &globals).map_err(|e| format!("{:?}", e))
+}
+
+/// Price of `query` for its sender under `model`, in GRT wei.
+fn price(model: &AgoraModel, query: &SampledQuery) -> Result<GrtWei, String> {
+    let cost = model
+        .cost(&query.query, query.variables.as_deref().unwrap_or("{}"))
+        .map_err(|e| format!("{:?}", e))?;
+    let cost = cost
+        .to_string()
+        .parse()
+        .map_err(|_| format!("Price `{}` doesn't fit in 128 bits", cost))?;
+    Ok(GrtWei(apply_multiplier(cost, query.price_multiplier)))
+}
+
+fn simulate_pricing(
+    deployment: DeploymentId,
+    current: Option<&AgoraModel>,
+    candidate: &AgoraModel,
+    queries: Vec<SampledQuery>,
+) -> SimulationReport {
+    let mut current_outcome = current.map(|_| PricingOutcome::default());
+    let mut candidate_outcome = PricingOutcome::default();
+    let mut total_price_delta = 0i128;
+
+    let queries: Vec<_> = queries
+        .into_iter()
+        .map(|query| {
+            let current_price = current.and_then(|current| price(current, &query).ok());
+            let candidate_price = price(candidate, &query);
+
+            if let Some(outcome) = &mut current_outcome {
+                outcome.record(current_price, query.value);
+            }
+            candidate_outcome.record(candidate_price.as_ref().ok().copied(), query.value);
+            if let (Some(current_price), Ok(candidate_price)) = (current_price, &candidate_price) {
+                total_price_delta += candidate_price.0 as i128 - current_price.0 as i128;
+            }
+
+            SimulatedQuery {
+                query: query.query,
+                paid_value: query.value,
+                current_price,
+                candidate_price: candidate_price.as_ref().ok().copied(),
+                candidate_error: candidate_price.err(),
+            }
+        })
+        .collect();
+
+    SimulationReport {
+        deployment,
+        sampled_queries: queries.len(),
+        current: current_outcome,
+        candidate: candidate_outcome,
+        total_price_delta: total_price_delta.to_string(),
+        queries,
+    }
+}
+
+impl PricingOutcome {
+    fn record(&mut self, price: Option<GrtWei>, paid_value: GrtWei) {
+        match price {
+            None => self.rejected_unpriced += 1,
+            Some(price) if paid_value < price => self.rejected_underpaid += 1,
+            Some(price) => {
+                self.accepted += 1;
+                self.total_price += price;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    const GRT_MILLI: u128 = 1_000_000_000_000_000;
+
+    fn sampled(query: &str, value: u128) -> SampledQuery {
+        SampledQuery {
+            query: query.to_string(),
+            variables: None,
+            value: GrtWei(value),
+            price_multiplier: 1.0,
+        }
+    }
+
+    #[test]
+    fn test_simulate_pricing() {
+        let deployment =
+            DeploymentId::from_str("QmAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA").unwrap();
+        let current = compile("default => 0.001;", None).unwrap();
+        let candidate = compile(
+            "query { tokens } => $price;",
+            Some(&serde_json::json!({ "price": 0.002 })),
+        )
+        .unwrap();
+
+        let report = simulate_pricing(
+            deployment,
+            Some(&current),
+            &candidate,
+            vec![
+                sampled("{ tokens { id } }", 3 * GRT_MILLI),
+                sampled("{ tokens { id } }", GRT_MILLI),
+                sampled("{ pairs { id } }", GRT_MILLI),
+            ],
+        );
+
+        assert_eq!(report.sampled_queries, 3);
+        assert_eq!(
+            report.current,
+            Some(PricingOutcome {
+                accepted: 3,
+                rejected_unpriced: 0,
+                rejected_underpaid: 0,
+                total_price: GrtWei(3 * GRT_MILLI),
+            })
+        );
+        assert_eq!(
+            report.candidate,
+            PricingOutcome {
+                accepted: 1,
+                rejected_unpriced: 1,
+                rejected_underpaid: 1,
+                total_price: GrtWei(2 * GRT_MILLI),
+            }
+        );
+        assert_eq!(report.total_price_delta, (2 * GRT_MILLI).to_string());
+        assert_eq!(report.queries[2].candidate_price, None);
+        assert!(report.queries[2].candidate_error.is_some());
+    }
+
+    #[test]
+    fn test_invalid_candidate_model() {
+        assert!(compile("query { tokens } =>", None).is_err());
+    }
+}
@@ -1,7 +1,10 @@
 // Copyright 2023-, GraphOps and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
+pub mod agreements;
+pub mod analytics;
 pub mod cost;
+pub mod cost_simulation;
 mod status;
 
 pub use status::status;
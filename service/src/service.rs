@@ -5,16 +5,28 @@ use std::sync::Arc;
 use std::time::Duration;
 
 use super::{config::Config, error::SubgraphServiceError, routes};
+use alloy_sol_types::{eip712_domain, Eip712Domain};
 use anyhow::anyhow;
-use axum::{async_trait, routing::post, Json, Router};
+use axum::{
+    async_trait,
+    routing::{get, post},
+    Json, Router,
+};
 use indexer_common::indexer_service::http::{IndexerServiceImpl, IndexerServiceResponse};
+use indexer_common::{db_pool, http_proxy};
 use indexer_config::Config as MainConfig;
 use reqwest::Url;
 use serde_json::{json, Value};
 use sqlx::PgPool;
 use thegraph::types::{Attestation, DeploymentId};
+use thiserror::Error;
 
-use crate::{cli::Cli, database};
+use crate::{
+    cli::{Cli, Command},
+    database, doctor,
+    graph_node::{reported_execution_time, GraphNodeRouter},
+    migrate_from_ts, sql,
+};
 
 use clap::Parser;
 use indexer_common::indexer_service::http::{
@@ -26,11 +38,16 @@ use tracing::error;
 struct SubgraphServiceResponse {
     inner: String,
     attestable: bool,
+    execution_time: Option<Duration>,
 }
 
 impl SubgraphServiceResponse {
-    pub fn new(inner: String, attestable: bool) -> Self {
-        Self { inner, attestable }
+    pub fn new(inner: String, attestable: bool, execution_time: Option<Duration>) -> Self {
+        Self {
+            inner,
+            attestable,
+            execution_time,
+        }
     }
 }
 
@@ -52,6 +69,10 @@ impl IndexerServiceResponse for SubgraphServiceResponse {
             "attestation": attestation
         }))
     }
+
+    fn execution_time(&self) -> Option<Duration> {
+        self.execution_time
+    }
 }
 
 pub struct SubgraphServiceState {
@@ -60,7 +81,9 @@ pub struct SubgraphServiceState {
     pub cost_schema: routes::cost::CostSchema,
     pub graph_node_client: reqwest::Client,
     pub graph_node_status_url: String,
-    pub graph_node_query_base_url: String,
+    pub graph_node_router: Arc<GraphNodeRouter>,
+    /// Domain indexing agreements are signed under, the same as for receipts.
+    pub agreement_domain: Eip712Domain,
 }
 
 struct SubgraphService {
@@ -85,55 +108,86 @@ impl IndexerServiceImpl for SubgraphService {
         deployment: DeploymentId,
         request: Self::Request,
     ) -> Result<(Self::Request, Self::Response), Self::Error> {
-        let deployment_url = Url::parse(&format!(
-            "{}/subgraphs/id/{}",
-            &self.state.graph_node_query_base_url, deployment
+        let state = &self.state;
+        let request_body = &request;
+        let (attestable, body, execution_time) = state
+            .graph_node_router
+            .forward(&deployment, move |base_url| async move {
+                let deployment_url =
+                    Url::parse(&format!("{}/subgraphs/id/{}", base_url, deployment))
+                        .map_err(|_| SubgraphServiceError::InvalidDeployment(deployment))?;
+
+                let response = state
+                    .graph_node_client
+                    .post(deployment_url)
+                    .json(request_body)
+                    .send()
+                    .await
+                    .map_err(SubgraphServiceError::QueryForwardingError)?;
+
+                let attestable = response
+                    .headers()
+                    .get("graph-attestable")
+                    .map_or(false, |value| {
+                        value.to_str().map(|value| value == "true").unwrap_or(false)
+                    });
+
+                let execution_time = reported_execution_time(response.headers());
+
+                let body = response
+                    .text()
+                    .await
+                    .map_err(SubgraphServiceError::QueryForwardingError)?;
+                Ok((attestable, body, execution_time))
+            })
+            .await?;
+
+        Ok((
+            request,
+            SubgraphServiceResponse::new(body, attestable, execution_time),
         ))
-        .map_err(|_| SubgraphServiceError::InvalidDeployment(deployment))?;
-
-        let response = self
-            .state
-            .graph_node_client
-            .post(deployment_url)
-            .json(&request)
-            .send()
-            .await
-            .map_err(SubgraphServiceError::QueryForwardingError)?;
-
-        let attestable = response
-            .headers()
-            .get("graph-attestable")
-            .map_or(false, |value| {
-                value.to_str().map(|value| value == "true").unwrap_or(false)
-            });
-
-        let body = response
-            .text()
-            .await
-            .map_err(SubgraphServiceError::QueryForwardingError)?;
-
-        Ok((request, SubgraphServiceResponse::new(body, attestable)))
     }
 }
 
+/// Failure to load the configuration, as opposed to failing once running.
+#[derive(Debug, Error)]
+#[error("{0}")]
+pub struct InvalidConfig(String);
+
 /// Run the subgraph indexer service
 pub async fn run() -> anyhow::Result<()> {
     // Parse command line and environment arguments
     let cli = Cli::parse();
+    if cli.config_schema {
+        println!("{:#}", indexer_config::config_schema());
+        return Ok(());
+    }
 
     // Load the json-rpc service configuration, which is a combination of the
     // general configuration options for any indexer service and specific
     // options added for JSON-RPC
-    let config =
-        MainConfig::parse(indexer_config::ConfigPrefix::Service, &cli.config).map_err(|e| {
+    let mut config =
+        MainConfig::parse(indexer_config::ConfigPrefix::Service, cli.config()).map_err(|e| {
             error!(
                 "Invalid configuration file `{}`: {}",
-                cli.config.display(),
+                cli.config().display(),
                 e
             );
-            anyhow!(e)
+            anyhow!(InvalidConfig(e))
         })?;
 
+    match &cli.command {
+        Some(Command::MigrateFromTs(args)) => return migrate_from_ts::run(&config, args).await,
+        Some(Command::Doctor) => {
+            let report = doctor::run(&config).await;
+            println!("{report}");
+            std::process::exit(report.exit_code());
+        }
+        None => {}
+    }
+
+    // Specific to the SQL service, not part of the configuration of the indexer service
+    let sql_config = config.service.sql.take();
     let config: Config = config.into();
 
     // Parse basic configurations
@@ -145,9 +199,18 @@ pub async fn run() -> anyhow::Result<()> {
     // that is involved in serving requests
     let state = Arc::new(SubgraphServiceState {
         config: config.clone(),
-        database: database::connect(&config.0.database.postgres_url).await,
+        database: database::connect(
+            &config.0.database.postgres_url,
+            config
+                .0
+                .database
+                .tenant_isolation
+                .then_some(config.0.indexer.indexer_address),
+            &config.0.database.pool,
+        )
+        .await,
         cost_schema: routes::cost::build_schema().await,
-        graph_node_client: reqwest::ClientBuilder::new()
+        graph_node_client: http_proxy::client_builder(&config.0.server.proxy)
             .tcp_nodelay(true)
             .timeout(Duration::from_secs(30))
             .build()
@@ -159,25 +222,74 @@ pub async fn run() -> anyhow::Result<()> {
             .expect("Config must have `common.graph_node.status_url` set")
             .status_url
             .clone(),
-        graph_node_query_base_url: config
-            .0
-            .graph_node
-            .as_ref()
-            .expect("config must have `common.graph_node.query_url` set")
-            .query_base_url
-            .clone(),
+        graph_node_router: Arc::new(GraphNodeRouter::from_config(
+            config
+                .0
+                .graph_node
+                .as_ref()
+                .expect("config must have `common.graph_node.query_url` set"),
+        )),
+        agreement_domain: eip712_domain! {
+            name: "TAP",
+            version: "1",
+            chain_id: config.0.tap.chain_id,
+            verifying_contract: config.0.tap.receipts_verifier_address,
+        },
     });
 
+    db_pool::monitor("service", &state.database);
+    state.graph_node_router.spawn_health_checks(
+        state.graph_node_client.clone(),
+        Duration::from_millis(
+            config
+                .0
+                .graph_node
+                .as_ref()
+                .map_or(10_000, |graph_node| graph_node.health_check_interval_ms),
+        ),
+    );
+
+    let mut extra_routes = Router::new()
+        .route(
+            "/cost",
+            post(routes::cost::cost).get(routes::cost::cost_subscriptions),
+        )
+        .route("/cost/quote", get(routes::cost::quote))
+        .route("/cost/simulate", post(routes::cost_simulation::simulate))
+        .route("/status", post(routes::status))
+        .route(
+            "/analytics/deployments",
+            get(routes::analytics::deployment_query_stats),
+        );
+    // Experimental, see `tap.indexing_agreements`
+    if config.0.tap.agreement_registration_auth_token.is_some() {
+        extra_routes = extra_routes
+            .route("/agreements", post(routes::agreements::register_agreement))
+            .route(
+                "/agreements/:agreement_id/progress",
+                post(routes::agreements::report_progress),
+            );
+    }
+
+    // Served behind the same payment layer as the subgraph queries, see `service.sql`
+    let mut data_services = Vec::new();
+    if let Some(sql_config) = &sql_config {
+        let client = http_proxy::client_builder(&config.0.server.proxy)
+            .tcp_nodelay(true)
+            .timeout(Duration::from_secs(30))
+            .build()
+            .expect("Failed to init HTTP client for the SQL queries");
+        data_services.push(sql::data_service(sql_config, client)?);
+    }
+
     IndexerService::run(IndexerServiceOptions {
         release,
         config: config.0.clone(),
         url_namespace: "subgraphs",
         metrics_prefix: "subgraph",
         service_impl: SubgraphService::new(state.clone()),
-        extra_routes: Router::new()
-            .route("/cost", post(routes::cost::cost))
-            .route("/status", post(routes::status))
-            .with_state(state),
+        extra_routes: extra_routes.with_state(state),
+        data_services,
     })
     .await
 }
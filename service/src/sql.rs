@@ -0,0 +1,179 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! SQL queries of the deployments, served at `/sql/id/:id` next to the subgraph queries and
+//! paid with the same receipts. They are priced with their own cost model, and their responses
+//! are never attested, as they can't be disputed on-chain.
+
+use std::time::Duration;
+
+use anyhow::anyhow;
+use axum::{async_trait, routing::get, Json, Router};
+use indexer_common::indexer_service::http::{
+    DataService, DataServiceOptions, IndexerServiceImpl, IndexerServiceResponse,
+};
+use indexer_config::SqlServiceConfig;
+use reqwest::Url;
+use serde_json::{json, Value};
+use thegraph::types::{Attestation, DeploymentId};
+
+use crate::{
+    error::SubgraphServiceError, graph_node::reported_execution_time,
+    routes::cost_simulation::compile,
+};
+
+pub const URL_NAMESPACE: &str = "sql";
+
+#[derive(Debug)]
+pub struct SqlServiceResponse {
+    inner: String,
+    execution_time: Option<Duration>,
+}
+
+impl IndexerServiceResponse for SqlServiceResponse {
+    type Data = Json<Value>;
+    type Error = SubgraphServiceError; // not used
+
+    fn is_attestable(&self) -> bool {
+        false
+    }
+
+    fn as_str(&self) -> Result<&str, Self::Error> {
+        Ok(self.inner.as_str())
+    }
+
+    fn finalize(self, _attestation: Option<Attestation>) -> Self::Data {
+        Json(json!({ "sqlResponse": self.inner }))
+    }
+
+    fn execution_time(&self) -> Option<Duration> {
+        self.execution_time
+    }
+}
+
+pub struct SqlService {
+    query_url: Url,
+    client: reqwest::Client,
+}
+
+impl SqlService {
+    pub fn new(query_url: Url, client: reqwest::Client) -> Self {
+        Self { query_url, client }
+    }
+
+    fn deployment_url(&self, deployment: &DeploymentId) -> Result<Url, SubgraphServiceError> {
+        let mut url = self.query_url.clone();
+        url.path_segments_mut()
+            .map_err(|_| SubgraphServiceError::InvalidDeployment(*deployment))?
+            .pop_if_empty()
+            .push(&deployment.to_string());
+        Ok(url)
+    }
+}
+
+#[async_trait]
+impl IndexerServiceImpl for SqlService {
+    type Error = SubgraphServiceError;
+    type Request = Value;
+    type Response = SqlServiceResponse;
+    type State = ();
+
+    async fn process_request(
+        &self,
+        deployment: DeploymentId,
+        request: Self::Request,
+    ) -> Result<(Self::Request, Self::Response), Self::Error> {
+        let response = self
+            .client
+            .post(self.deployment_url(&deployment)?)
+            .json(&request)
+            .send()
+            .await
+            .map_err(SubgraphServiceError::QueryForwardingError)?;
+        let execution_time = reported_execution_time(response.headers());
+        let inner = response
+            .text()
+            .await
+            .map_err(SubgraphServiceError::QueryForwardingError)?;
+        Ok((
+            request,
+            SqlServiceResponse {
+                inner,
+                execution_time,
+            },
+        ))
+    }
+}
+
+/// The SQL data service, with its cost model served at `/sql/cost`. Fails if the cost model
+/// doesn't compile.
+pub fn data_service(
+    config: &SqlServiceConfig,
+    client: reqwest::Client,
+) -> anyhow::Result<DataService> {
+    compile(&config.cost_model, None)
+        .map_err(|e| anyhow!("Invalid `service.sql.cost_model`: {}", e))?;
+    let cost_model = Json(json!({ "model": config.cost_model, "variables": null }));
+    Ok(DataService::new(DataServiceOptions {
+        service_impl: SqlService::new(config.query_url.clone(), client),
+        url_namespace: URL_NAMESPACE,
+        metrics_prefix: "sql",
+        attestations: false,
+        extra_routes: Router::new().route(
+            &format!("/{URL_NAMESPACE}/cost"),
+            get(move || std::future::ready(cost_model.clone())),
+        ),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use wiremock::matchers::{body_json, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sql_service() {
+        let deployment =
+            DeploymentId::from_str("QmAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA").unwrap();
+        let mock_server = MockServer::start().await;
+        let request = json!({ "query": "SELECT id FROM tokens LIMIT 1" });
+        mock_server
+            .register(
+                Mock::given(method("POST"))
+                    .and(path(format!("/sql/{}", deployment)))
+                    .and(body_json(&request))
+                    .respond_with(
+                        ResponseTemplate::new(200)
+                            .insert_header("graph-attestable", "true")
+                            .set_body_string(r#"{"rows":[{"id":"0x01"}]}"#),
+                    ),
+            )
+            .await;
+
+        let service = SqlService::new(
+            Url::parse(&format!("{}/sql/", mock_server.uri())).unwrap(),
+            reqwest::Client::new(),
+        );
+        let (_, response) = service
+            .process_request(deployment, request)
+            .await
+            .unwrap();
+        assert_eq!(response.as_str().unwrap(), r#"{"rows":[{"id":"0x01"}]}"#);
+        // Whatever the endpoint says
+        assert!(!response.is_attestable());
+    }
+
+    #[test]
+    fn test_invalid_cost_model() {
+        let config = |cost_model: &str| SqlServiceConfig {
+            query_url: Url::parse("http://graph-node:8000/sql").unwrap(),
+            cost_model: cost_model.to_string(),
+        };
+        assert!(data_service(&config("default => 0.00001;"), reqwest::Client::new()).is_ok());
+        assert!(data_service(&config("query { tokens } =>"), reqwest::Client::new()).is_err());
+    }
+}
@@ -0,0 +1,627 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Accounting statements of a period, for ERP systems: the fees earned per sender and
+//! deployment, the RAVs issued and redeemed, and the receivables still outstanding at the end of
+//! the period. Fees are booked once aggregated into a RAV, from the RAV history. A period is
+//! locked once exported, exporting it again returns the same statement.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use axum::{
+    extract::State,
+    http::{header, StatusCode},
+    middleware,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use indexer_common::types::SenderAddress;
+use serde::{Deserialize, Serialize};
+use sqlx::{types::BigDecimal, PgPool, Row};
+use thegraph::types::{Address, DeploymentId};
+use tracing::{error, info};
+
+use crate::admin_signature::{require_signature, AdminSigners};
+use crate::escrow_overrides::AdminError;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ExportError {
+    #[error("Invalid period: {0}")]
+    InvalidPeriod(String),
+    #[error("The period overlaps the exported period from {0} to {1}")]
+    OverlapsExportedPeriod(DateTime<Utc>, DateTime<Utc>),
+    #[error(transparent)]
+    Database(#[from] sqlx::Error),
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum StatementFormat {
+    #[default]
+    Json,
+    Csv,
+    /// OFX 1.x statement, with the fees earned as credits, in GRT.
+    Ofx,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Statement {
+    pub period_start: DateTime<Utc>,
+    pub period_end: DateTime<Utc>,
+    pub exported_at: DateTime<Utc>,
+    pub fees: Vec<FeeLine>,
+    pub senders: Vec<SenderSummary>,
+}
+
+/// Amounts are in GRT wei.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FeeLine {
+    pub sender: Address,
+    /// `None` if no query stats tell which deployment the allocations were for.
+    pub deployment: Option<DeploymentId>,
+    pub fees_earned: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SenderSummary {
+    pub sender: Address,
+    pub fees_earned: String,
+    pub ravs_issued: u64,
+    pub ravs_redeemed: u64,
+    pub value_redeemed: String,
+    /// Value of the RAVs not redeemed at the end of the period.
+    pub outstanding_receivables: String,
+}
+
+/// Exports the statement of the period from `from` to `to`, excluded, locking the period.
+pub async fn export_statement(
+    pgpool: &PgPool,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<Statement, ExportError> {
+    if from >= to {
+        return Err(ExportError::InvalidPeriod(
+            "The period must end after it starts".to_string(),
+        ));
+    }
+    // RAVs can still be received for a period that isn't over
+    if to > Utc::now() {
+        return Err(ExportError::InvalidPeriod(
+            "The period must be over".to_string(),
+        ));
+    }
+
+    let mut tx = pgpool.begin().await?;
+    // Serializes the exports, so that no two overlapping periods are locked concurrently
+    sqlx::query("LOCK TABLE accounting_statements IN EXCLUSIVE MODE")
+        .execute(&mut *tx)
+        .await?;
+    let locked = sqlx::query(
+        r#"
+            SELECT period_start, period_end, statement
+            FROM accounting_statements
+            WHERE period_start < $2 AND period_end > $1
+            ORDER BY period_start
+            LIMIT 1
+        "#,
+    )
+    .bind(from)
+    .bind(to)
+    .fetch_optional(&mut *tx)
+    .await?;
+    if let Some(row) = locked {
+        let (period_start, period_end) = (row.try_get("period_start")?, row.try_get("period_end")?);
+        if (period_start, period_end) != (from, to) {
+            return Err(ExportError::OverlapsExportedPeriod(
+                period_start,
+                period_end,
+            ));
+        }
+        let statement = row.try_get::<serde_json::Value, _>("statement")?;
+        return Ok(serde_json::from_value(statement).map_err(anyhow::Error::from)?);
+    }
+
+    let statement = build_statement(&mut tx, from, to).await?;
+    sqlx::query(
+        r#"
+            INSERT INTO accounting_statements (period_start, period_end, statement)
+            VALUES ($1, $2, $3)
+        "#,
+    )
+    .bind(from)
+    .bind(to)
+    .bind(serde_json::to_value(&statement).map_err(anyhow::Error::from)?)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    info!(%from, %to, "Exported and locked the accounting statement");
+    Ok(statement)
+}
+
+pub(crate) async fn build_statement(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<Statement, ExportError> {
+    // Each row of the history is a new RAV, or a change of its flags. The deployment of an
+    // allocation is only known from the stats of the queries paid to it.
+    let rows = sqlx::query(
+        r#"
+            WITH history AS (
+                SELECT
+                    sender_address, allocation_id, value_aggregate, final, recorded_at,
+                    LAG(value_aggregate) OVER allocation_history AS previous_value,
+                    COALESCE(LAG(final) OVER allocation_history, FALSE) AS previously_final
+                FROM scalar_tap_rav_history
+                WHERE recorded_at < $2
+                WINDOW allocation_history AS (
+                    PARTITION BY sender_address, allocation_id ORDER BY id
+                )
+            ),
+            deployments AS (
+                SELECT DISTINCT ON (allocation_id) allocation_id, deployment_id
+                FROM scalar_tap_receipt_query_stats
+                ORDER BY allocation_id
+            )
+            SELECT
+                sender_address,
+                deployment_id,
+                SUM(GREATEST(value_aggregate - COALESCE(previous_value, 0), 0)) AS fees_earned,
+                COUNT(*) FILTER (
+                    WHERE value_aggregate IS DISTINCT FROM previous_value
+                ) AS ravs_issued,
+                COUNT(*) FILTER (WHERE final AND NOT previously_final) AS ravs_redeemed,
+                COALESCE(
+                    SUM(value_aggregate) FILTER (WHERE final AND NOT previously_final), 0
+                ) AS value_redeemed
+            FROM history
+            LEFT JOIN deployments USING (allocation_id)
+            WHERE recorded_at >= $1
+            GROUP BY sender_address, deployment_id
+        "#,
+    )
+    .bind(from)
+    .bind(to)
+    .fetch_all(&mut **tx)
+    .await?;
+
+    let mut fees = Vec::new();
+    let mut senders = BTreeMap::<Address, SenderTotals>::new();
+    for row in rows {
+        let sender = row.try_get::<SenderAddress, _>("sender_address")?.0;
+        let deployment = row
+            .try_get::<Option<String>, _>("deployment_id")?
+            .map(|deployment| DeploymentId::from_str(deployment.trim()))
+            .transpose()
+            .map_err(|e| anyhow::anyhow!("Invalid deployment in the query stats: {e}"))?;
+        let fees_earned: BigDecimal = row.try_get("fees_earned")?;
+
+        let totals = senders.entry(sender).or_default();
+        totals.fees_earned += &fees_earned;
+        totals.ravs_issued += row.try_get::<i64, _>("ravs_issued")? as u64;
+        totals.ravs_redeemed += row.try_get::<i64, _>("ravs_redeemed")? as u64;
+        totals.value_redeemed += row.try_get::<BigDecimal, _>("value_redeemed")?;
+        if fees_earned > BigDecimal::from(0) {
+            fees.push(FeeLine {
+                sender,
+                deployment,
+                fees_earned: fees_earned.to_string(),
+            });
+        }
+    }
+
+    let outstanding = sqlx::query(
+        r#"
+            SELECT sender_address, SUM(value_aggregate) AS outstanding
+            FROM (
+                SELECT DISTINCT ON (sender_address, allocation_id)
+                    sender_address, value_aggregate, final
+                FROM scalar_tap_rav_history
+                WHERE recorded_at < $1
+                ORDER BY sender_address, allocation_id, id DESC
+            ) latest
+            WHERE NOT final
+            GROUP BY sender_address
+        "#,
+    )
+    .bind(to)
+    .fetch_all(&mut **tx)
+    .await?;
+    for row in outstanding {
+        senders
+            .entry(row.try_get::<SenderAddress, _>("sender_address")?.0)
+            .or_default()
+            .outstanding_receivables = row.try_get("outstanding")?;
+    }
+
+    fees.sort_by(|a, b| {
+        (a.sender, a.deployment.map(|d| d.to_string()))
+            .cmp(&(b.sender, b.deployment.map(|d| d.to_string())))
+    });
+    Ok(Statement {
+        period_start: from,
+        period_end: to,
+        exported_at: Utc::now(),
+        fees,
+        senders: senders
+            .into_iter()
+            .map(|(sender, totals)| SenderSummary {
+                sender,
+                fees_earned: totals.fees_earned.to_string(),
+                ravs_issued: totals.ravs_issued,
+                ravs_redeemed: totals.ravs_redeemed,
+                value_redeemed: totals.value_redeemed.to_string(),
+                outstanding_receivables: totals.outstanding_receivables.to_string(),
+            })
+            .collect(),
+    })
+}
+
+#[derive(Default)]
+struct SenderTotals {
+    fees_earned: BigDecimal,
+    ravs_issued: u64,
+    ravs_redeemed: u64,
+    value_redeemed: BigDecimal,
+    outstanding_receivables: BigDecimal,
+}
+
+impl StatementFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            StatementFormat::Json => "application/json",
+            StatementFormat::Csv => "text/csv",
+            StatementFormat::Ofx => "application/x-ofx",
+        }
+    }
+
+    pub fn render(&self, statement: &Statement) -> anyhow::Result<String> {
+        Ok(match self {
+            StatementFormat::Json => serde_json::to_string_pretty(statement)?,
+            StatementFormat::Csv => render_csv(statement),
+            StatementFormat::Ofx => render_ofx(statement)?,
+        })
+    }
+}
+
+/// One entry per line, with amounts in GRT wei.
+fn render_csv(statement: &Statement) -> String {
+    let mut csv = "period_start,period_end,sender,deployment,entry,amount\n".to_string();
+    let mut line = |sender: &Address, deployment: Option<&DeploymentId>, entry, amount| {
+        let _ = writeln!(
+            csv,
+            "{},{},{},{},{},{}",
+            statement.period_start.to_rfc3339(),
+            statement.period_end.to_rfc3339(),
+            sender,
+            deployment.map(ToString::to_string).unwrap_or_default(),
+            entry,
+            amount
+        );
+    };
+    for fee in &statement.fees {
+        line(
+            &fee.sender,
+            fee.deployment.as_ref(),
+            "fees_earned",
+            fee.fees_earned.clone(),
+        );
+    }
+    for sender in &statement.senders {
+        line(
+            &sender.sender,
+            None,
+            "ravs_issued",
+            sender.ravs_issued.to_string(),
+        );
+        line(
+            &sender.sender,
+            None,
+            "ravs_redeemed",
+            sender.ravs_redeemed.to_string(),
+        );
+        line(
+            &sender.sender,
+            None,
+            "value_redeemed",
+            sender.value_redeemed.clone(),
+        );
+        line(
+            &sender.sender,
+            None,
+            "outstanding_receivables",
+            sender.outstanding_receivables.clone(),
+        );
+    }
+    csv
+}
+
+fn grt(wei: &str) -> anyhow::Result<String> {
+    let (digits, exponent) = BigDecimal::from_str(wei)?.into_bigint_and_exponent();
+    Ok(BigDecimal::new(digits, exponent + 18).to_string())
+}
+
+fn ofx_date(date: &DateTime<Utc>) -> String {
+    date.format("%Y%m%d%H%M%S").to_string()
+}
+
+/// The fees earned are credits, and the outstanding receivables the ledger balance.
+fn render_ofx(statement: &Statement) -> anyhow::Result<String> {
+    let (start, end) = (
+        ofx_date(&statement.period_start),
+        ofx_date(&statement.period_end),
+    );
+    let mut ofx = String::new();
+    writeln!(
+        ofx,
+        "OFXHEADER:100\nDATA:OFXSGML\nVERSION:102\nSECURITY:NONE"
+    )?;
+    writeln!(ofx, "ENCODING:USASCII\nCHARSET:1252\nCOMPRESSION:NONE")?;
+    writeln!(ofx, "OLDFILEUID:NONE\nNEWFILEUID:NONE\n")?;
+    writeln!(
+        ofx,
+        "<OFX>\n<BANKMSGSRSV1>\n<STMTTRNRS>\n<STMTRS>\n<CURDEF>GRT"
+    )?;
+    writeln!(ofx, "<BANKTRANLIST>\n<DTSTART>{start}\n<DTEND>{end}")?;
+    for fee in &statement.fees {
+        let deployment = fee
+            .deployment
+            .map(|deployment| deployment.to_string())
+            .unwrap_or_else(|| "unknown".to_string());
+        writeln!(ofx, "<STMTTRN>\n<TRNTYPE>CREDIT\n<DTPOSTED>{end}")?;
+        writeln!(ofx, "<TRNAMT>{}", grt(&fee.fees_earned)?)?;
+        writeln!(ofx, "<FITID>{start}-{end}-{}-{deployment}", fee.sender)?;
+        writeln!(
+            ofx,
+            "<NAME>{}\n<MEMO>Query fees of {deployment}",
+            fee.sender
+        )?;
+        writeln!(ofx, "</STMTTRN>")?;
+    }
+    let outstanding = statement.senders.iter().try_fold(
+        BigDecimal::from(0),
+        |total, sender| -> anyhow::Result<_> {
+            Ok(total + BigDecimal::from_str(&sender.outstanding_receivables)?)
+        },
+    )?;
+    writeln!(ofx, "</BANKTRANLIST>")?;
+    writeln!(
+        ofx,
+        "<LEDGERBAL>\n<BALAMT>{}",
+        grt(&outstanding.to_string())?
+    )?;
+    writeln!(ofx, "<DTASOF>{end}\n</LEDGERBAL>")?;
+    writeln!(ofx, "</STMTRS>\n</STMTTRNRS>\n</BANKMSGSRSV1>\n</OFX>")?;
+    Ok(ofx)
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportedPeriod {
+    pub period_start: DateTime<Utc>,
+    pub period_end: DateTime<Utc>,
+    pub exported_at: DateTime<Utc>,
+}
+
+/// The locked periods, from the most recent one.
+pub async fn exported_periods(pgpool: &PgPool) -> anyhow::Result<Vec<ExportedPeriod>> {
+    let rows = sqlx::query(
+        r#"
+            SELECT period_start, period_end, exported_at
+            FROM accounting_statements
+            ORDER BY period_start DESC
+        "#,
+    )
+    .fetch_all(pgpool)
+    .await?;
+    rows.iter()
+        .map(|row| {
+            Ok(ExportedPeriod {
+                period_start: row.try_get("period_start")?,
+                period_end: row.try_get("period_end")?,
+                exported_at: row.try_get("exported_at")?,
+            })
+        })
+        .collect()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportRequest {
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    #[serde(default)]
+    pub format: StatementFormat,
+}
+
+struct AccountingState {
+    pgpool: PgPool,
+}
+
+fn internal_error(e: impl std::fmt::Display) -> AdminError {
+    error!("Error while exporting accounting statements: {}", e);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Error while exporting accounting statements: {}", e),
+    )
+}
+
+async fn handler_export_statement(
+    State(state): State<Arc<AccountingState>>,
+    Json(request): Json<ExportRequest>,
+) -> Result<Response, AdminError> {
+    let statement = export_statement(&state.pgpool, request.from, request.to)
+        .await
+        .map_err(|e| match e {
+            ExportError::InvalidPeriod(_) => (StatusCode::BAD_REQUEST, e.to_string()),
+            ExportError::OverlapsExportedPeriod(..) => (StatusCode::CONFLICT, e.to_string()),
+            e => internal_error(e),
+        })?;
+    let body = request.format.render(&statement).map_err(internal_error)?;
+    Ok((
+        [(header::CONTENT_TYPE, request.format.content_type())],
+        body,
+    )
+        .into_response())
+}
+
+async fn handler_exported_periods(
+    State(state): State<Arc<AccountingState>>,
+) -> Result<Json<Vec<ExportedPeriod>>, AdminError> {
+    exported_periods(&state.pgpool)
+        .await
+        .map(Json)
+        .map_err(internal_error)
+}
+
+/// Admin routes exporting the accounting statements, to be mounted on the tap-agent HTTP server.
+/// Exporting a statement requires a signature from one of `admin_signers` if set.
+pub fn router(pgpool: PgPool, admin_signers: Arc<AdminSigners>) -> Router {
+    Router::new()
+        .route(
+            "/admin/accounting/statements",
+            post(handler_export_statement),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            admin_signers,
+            require_signature,
+        ))
+        .route(
+            "/admin/accounting/statements",
+            get(handler_exported_periods),
+        )
+        .with_state(Arc::new(AccountingState { pgpool }))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+    use indexer_common::types::AllocationIdHex;
+
+    use super::*;
+    use crate::tap::test_utils::{
+        create_rav, store_rav, ALLOCATION_ID_0, ALLOCATION_ID_1, SENDER, SIGNER,
+    };
+
+    async fn set_value(pgpool: &PgPool, allocation_id: Address, value: u64, is_final: bool) {
+        sqlx::query(
+            r#"
+                UPDATE scalar_tap_ravs
+                SET value_aggregate = $1, final = $2
+                WHERE allocation_id = $3 AND sender_address = $4
+            "#,
+        )
+        .bind(BigDecimal::from(value))
+        .bind(is_final)
+        .bind(AllocationIdHex(allocation_id))
+        .bind(SenderAddress(SENDER.1))
+        .execute(pgpool)
+        .await
+        .unwrap();
+    }
+
+    /// Moves the latest history entries, recorded now, to `at`.
+    async fn record_at(pgpool: &PgPool, at: DateTime<Utc>) {
+        sqlx::query(
+            r#"
+                UPDATE scalar_tap_rav_history
+                SET recorded_at = $1
+                WHERE recorded_at > NOW() - INTERVAL '1 hour'
+            "#,
+        )
+        .bind(at)
+        .execute(pgpool)
+        .await
+        .unwrap();
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_export_statement(pgpool: PgPool) {
+        let start = Utc::now() - Duration::days(30);
+        let day = |days| start + Duration::days(days);
+
+        // 100 earned before the period
+        store_rav(
+            &pgpool,
+            create_rav(*ALLOCATION_ID_0, SIGNER.0.clone(), 1, 100),
+            SENDER.1,
+        )
+        .await
+        .unwrap();
+        record_at(&pgpool, day(0)).await;
+
+        // 150 more during the period, and the RAV of allocation 0 redeemed
+        set_value(&pgpool, *ALLOCATION_ID_0, 200, false).await;
+        store_rav(
+            &pgpool,
+            create_rav(*ALLOCATION_ID_1, SIGNER.0.clone(), 1, 50),
+            SENDER.1,
+        )
+        .await
+        .unwrap();
+        set_value(&pgpool, *ALLOCATION_ID_0, 200, true).await;
+        record_at(&pgpool, day(2)).await;
+
+        let statement = export_statement(&pgpool, day(1), day(3)).await.unwrap();
+        assert_eq!(
+            statement.fees,
+            vec![FeeLine {
+                sender: SENDER.1,
+                deployment: None,
+                fees_earned: "150".to_string(),
+            }]
+        );
+        assert_eq!(
+            statement.senders,
+            vec![SenderSummary {
+                sender: SENDER.1,
+                fees_earned: "150".to_string(),
+                ravs_issued: 2,
+                ravs_redeemed: 1,
+                value_redeemed: "200".to_string(),
+                outstanding_receivables: "50".to_string(),
+            }]
+        );
+
+        // Locked, later changes don't alter the statement
+        set_value(&pgpool, *ALLOCATION_ID_1, 80, false).await;
+        record_at(&pgpool, day(2)).await;
+        assert_eq!(
+            export_statement(&pgpool, day(1), day(3)).await.unwrap(),
+            statement
+        );
+        assert!(matches!(
+            export_statement(&pgpool, day(2), day(4)).await,
+            Err(ExportError::OverlapsExportedPeriod(..))
+        ));
+        assert!(matches!(
+            export_statement(&pgpool, day(3), Utc::now() + Duration::days(1)).await,
+            Err(ExportError::InvalidPeriod(_))
+        ));
+        assert_eq!(exported_periods(&pgpool).await.unwrap().len(), 1);
+
+        let csv = StatementFormat::Csv.render(&statement).unwrap();
+        assert_eq!(csv.lines().count(), 6);
+        assert!(csv
+            .lines()
+            .nth(1)
+            .unwrap()
+            .ends_with(&format!("{},,fees_earned,150", SENDER.1)));
+    }
+
+    #[test]
+    fn test_grt() {
+        assert_eq!(grt("1500000000000000000").unwrap(), "1.500000000000000000");
+        assert_eq!(grt("150").unwrap(), "0.000000000000000150");
+    }
+}
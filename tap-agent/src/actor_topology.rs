@@ -0,0 +1,224 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Snapshot of the actor tree of the agent, the sender accounts under the manager and their
+//! allocations, with the key state of each actor, to debug a stuck aggregation without
+//! attaching a debugger.
+//!
+//! ractor doesn't expose the length of the mailboxes, so each actor reports the messages it
+//! handled, and how long it took to answer the snapshot request, i.e. to get through the
+//! messages queued before it. An actor not answering in time is reported as unresponsive.
+
+use std::sync::Arc;
+use std::time::Instant;
+
+use axum::{extract::State, routing::get, Json, Router};
+use ractor::{call_t, ActorRef, ActorStatus};
+use serde::Serialize;
+use serde_json::Value;
+use thegraph::types::Address;
+
+use crate::agent::sender_account::SenderAccountMessage;
+use crate::agent::sender_accounts_manager::SenderAccountsManagerMessage;
+use crate::agent::sender_allocation::SenderAllocationMessage;
+use crate::escrow_overrides::AdminError;
+
+const SNAPSHOT_TIMEOUT_MS: u64 = 2000;
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ManagerSnapshot {
+    pub last_receipt_id: u64,
+    pub messages_handled: u64,
+    /// Names of the sender account actors.
+    #[serde(skip)]
+    pub sender_accounts: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SenderAccountSnapshot {
+    pub sender: Address,
+    pub denied: bool,
+    pub paused: bool,
+    /// Amounts in GRT wei.
+    pub balance: String,
+    pub unaggregated_fees: String,
+    pub pending_ravs: String,
+    pub invalid_receipts_fees: String,
+    pub rav_request_trigger_value: String,
+    /// Whether a RAV request is scheduled to be retried.
+    pub rav_request_retry_scheduled: bool,
+    pub messages_handled: u64,
+    /// Names of the sender allocation actors, running or not.
+    #[serde(skip)]
+    pub sender_allocations: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SenderAllocationSnapshot {
+    pub allocation_id: Address,
+    /// Amounts in GRT wei.
+    pub unaggregated_fees: String,
+    pub last_receipt_id: u64,
+    pub invalid_receipts_fees: String,
+    pub last_rav_timestamp_ns: Option<u64>,
+    pub last_rav_value: Option<String>,
+    pub rav_request_receipt_limit: u64,
+    /// RAV requests that failed in a row, after their retries.
+    pub rav_request_failures: u32,
+    /// Whether the unaggregated fees overflowed, until acknowledged by the operator.
+    pub fee_overflow: bool,
+    pub idle_secs: u64,
+    pub messages_handled: u64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ActorNode {
+    pub name: String,
+    /// `running`, `unresponsive`, `stopped` when the actor isn't running, e.g. an idle
+    /// allocation, or the ractor status of an actor starting or stopping.
+    pub status: String,
+    pub response_ms: Option<u64>,
+    pub state: Option<Value>,
+    pub error: Option<String>,
+    pub children: Vec<ActorNode>,
+}
+
+impl ActorNode {
+    fn stopped(name: String) -> Self {
+        Self {
+            name,
+            status: "stopped".to_string(),
+            response_ms: None,
+            state: None,
+            error: None,
+            children: Vec::new(),
+        }
+    }
+}
+
+/// Asks `actor` for its snapshot, along with the node describing it.
+async fn snapshot<M, T, F>(name: String, actor: &ActorRef<M>, message: F) -> (ActorNode, Option<T>)
+where
+    M: ractor::Message,
+    T: Serialize + Send + 'static,
+    F: FnOnce(ractor::RpcReplyPort<T>) -> M,
+{
+    let mut node = ActorNode::stopped(name);
+    let status = actor.get_status();
+    if status != ActorStatus::Running {
+        node.status = format!("{status:?}").to_lowercase();
+        return (node, None);
+    }
+    let start = Instant::now();
+    match call_t!(actor, message, SNAPSHOT_TIMEOUT_MS) {
+        Ok(snapshot) => {
+            node.status = "running".to_string();
+            node.response_ms = Some(start.elapsed().as_millis() as u64);
+            node.state = serde_json::to_value(&snapshot).ok();
+            (node, Some(snapshot))
+        }
+        Err(e) => {
+            node.status = "unresponsive".to_string();
+            node.error = Some(e.to_string());
+            (node, None)
+        }
+    }
+}
+
+/// Snapshot of the actor tree under `manager`. The actors are asked one at a time, none of them
+/// waiting on another, so that a stuck actor doesn't hide the state of the others.
+pub async fn actor_tree(manager: &ActorRef<SenderAccountsManagerMessage>) -> ActorNode {
+    let name = manager
+        .get_name()
+        .unwrap_or_else(|| "sender_accounts_manager".to_string());
+    let (mut root, manager_snapshot) =
+        snapshot(name, manager, SenderAccountsManagerMessage::GetSnapshot).await;
+    let Some(manager_snapshot) = manager_snapshot else {
+        return root;
+    };
+
+    for sender_account_name in manager_snapshot.sender_accounts {
+        let Some(sender_account) =
+            ActorRef::<SenderAccountMessage>::where_is(sender_account_name.clone())
+        else {
+            root.children.push(ActorNode::stopped(sender_account_name));
+            continue;
+        };
+        let (mut account_node, account_snapshot) = snapshot(
+            sender_account_name,
+            &sender_account,
+            SenderAccountMessage::GetSnapshot,
+        )
+        .await;
+        for sender_allocation_name in account_snapshot
+            .map(|snapshot| snapshot.sender_allocations)
+            .unwrap_or_default()
+        {
+            let node =
+                match ActorRef::<SenderAllocationMessage>::where_is(sender_allocation_name.clone())
+                {
+                    Some(sender_allocation) => {
+                        snapshot::<_, SenderAllocationSnapshot, _>(
+                            sender_allocation_name,
+                            &sender_allocation,
+                            SenderAllocationMessage::GetSnapshot,
+                        )
+                        .await
+                        .0
+                    }
+                    None => ActorNode::stopped(sender_allocation_name),
+                };
+            account_node.children.push(node);
+        }
+        root.children.push(account_node);
+    }
+    root
+}
+
+/// The sender accounts manager is running and answers in time, for the systemd watchdog. The
+/// manager only waits on the sender accounts when saving the checkpoints.
+#[cfg(feature = "systemd")]
+pub struct ActorsCheck(pub ActorRef<SenderAccountsManagerMessage>);
+
+#[cfg(feature = "systemd")]
+#[async_trait::async_trait]
+impl indexer_common::systemd::HealthCheck for ActorsCheck {
+    fn name(&self) -> &'static str {
+        "actors"
+    }
+
+    async fn check(&self) -> anyhow::Result<()> {
+        let status = self.0.get_status();
+        if status != ActorStatus::Running {
+            return Err(anyhow::anyhow!("Sender accounts manager is {status:?}"));
+        }
+        call_t!(
+            self.0,
+            SenderAccountsManagerMessage::GetSnapshot,
+            SNAPSHOT_TIMEOUT_MS
+        )
+        .map_err(|e| anyhow::anyhow!("Sender accounts manager didn't answer: {e}"))?;
+        Ok(())
+    }
+}
+
+struct ActorTopologyState {
+    manager: ActorRef<SenderAccountsManagerMessage>,
+}
+
+async fn handler_actors(
+    State(state): State<Arc<ActorTopologyState>>,
+) -> Result<Json<ActorNode>, AdminError> {
+    Ok(Json(actor_tree(&state.manager).await))
+}
+
+/// Admin route dumping the actor tree, to be mounted on the tap-agent HTTP server.
+pub fn router(manager: ActorRef<SenderAccountsManagerMessage>) -> Router {
+    Router::new()
+        .route("/debug/actors", get(handler_actors))
+        .with_state(Arc::new(ActorTopologyState { manager }))
+}
@@ -0,0 +1,113 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Client of the admin routes of the running agent, for the CLI commands calling them on the
+//! metrics server, see [crate::config::metrics_url].
+
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Result};
+use indexer_config::{Config as IndexerConfig, ConfigPrefix, EXIT_CODE_CONFIG_ERROR};
+use reqwest::{header::CONTENT_TYPE, Method, Url};
+
+use crate::admin_signature::{self, SignatureScope};
+use crate::config::{metrics_url, Cli};
+
+static COMMAND_CONFIG: OnceLock<IndexerConfig> = OnceLock::new();
+
+/// Configuration of the CLI commands, loaded once as it can be read from stdin. Exits with
+/// [EXIT_CODE_CONFIG_ERROR] if it can't be loaded.
+pub fn load_config(cli: &Cli) -> &'static IndexerConfig {
+    COMMAND_CONFIG.get_or_init(|| {
+        IndexerConfig::parse(ConfigPrefix::Tap, cli.config()).unwrap_or_else(|e| {
+            eprintln!("Failed to load configuration: {e}");
+            std::process::exit(EXIT_CODE_CONFIG_ERROR.into())
+        })
+    })
+}
+
+/// Indexer and chain the admin actions sent by the CLI are signed for.
+fn signature_scope(config: &IndexerConfig) -> SignatureScope {
+    SignatureScope {
+        indexer_address: config.indexer.indexer_address,
+        chain_id: config.blockchain.chain_id.clone() as u64,
+    }
+}
+
+/// `path` followed by the percent-encoded `query`, if any.
+pub fn with_query(path: &str, query: &[(&str, String)]) -> String {
+    if query.is_empty() {
+        return path.to_string();
+    }
+    let mut url = Url::parse("http://localhost").expect("Invalid base URL");
+    url.set_path(path);
+    url.query_pairs_mut()
+        .extend_pairs(query.iter().map(|(name, value)| (name, value.as_str())));
+    format!("{}?{}", url.path(), url.query().unwrap_or_default())
+}
+
+/// Sends a request to an admin route of the running agent, with `tap.admin_auth_token` and,
+/// when `tap.require_signed_admin_actions` is set, signed by the operator key. Returns the body
+/// of the response, or fails with it if the request was refused.
+pub async fn admin_request(
+    cli: &Cli,
+    method: Method,
+    path: &str,
+    body: Option<String>,
+) -> Result<String> {
+    let config = load_config(cli);
+    let Some(admin_auth_token) = &config.tap.admin_auth_token else {
+        bail!("The admin API is disabled, `tap.admin_auth_token` isn't set");
+    };
+    let mut request = reqwest::Client::new()
+        .request(method.clone(), metrics_url(&config.metrics, path))
+        .bearer_auth(admin_auth_token);
+    if config.tap.require_signed_admin_actions {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        for (name, value) in admin_signature::sign(
+            &config.indexer.operator_mnemonic.to_string(),
+            signature_scope(config),
+            method.as_str(),
+            path,
+            body.as_deref().unwrap_or_default().as_bytes(),
+            now,
+        )? {
+            request = request.header(name, value);
+        }
+    }
+    if let Some(body) = body {
+        request = request.header(CONTENT_TYPE, "application/json").body(body);
+    }
+
+    let response = request.send().await?;
+    let status = response.status();
+    let body = response.text().await?;
+    if !status.is_success() {
+        bail!("{status}: {body}");
+    }
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::with_query;
+
+    #[test]
+    fn test_with_query() {
+        assert_eq!(
+            with_query("/admin/fee-overflows", &[]),
+            "/admin/fee-overflows"
+        );
+        assert_eq!(
+            with_query(
+                "/admin/invalid-receipts",
+                &[
+                    ("reason", "no signer & expired".to_string()),
+                    ("afterId", 10.to_string())
+                ]
+            ),
+            "/admin/invalid-receipts?reason=no+signer+%26+expired&afterId=10"
+        );
+    }
+}
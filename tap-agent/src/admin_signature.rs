@@ -0,0 +1,333 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Signed admin actions. When required, the admin requests changing how payments are handled
+//! must carry, on top of the bearer token, an EIP-191 signature of their indexer, chain, method,
+//! path, time, nonce and body by the operator key or one of the configured admin signers. Each
+//! nonce is accepted once, the used ones being stored in the database so that a signature can't be
+//! replayed to another instance or after a restart, nor to another indexer or chain. The signer
+//! and signature are recorded with the action in the audit log, so that every action can be
+//! attributed to a key rather than to whoever had the token.
+
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use alloy_primitives::{hex, hex::ToHex, keccak256};
+use anyhow::Result;
+use axum::{
+    body::{to_bytes, Body},
+    extract::{Request, State},
+    http::{HeaderMap, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use indexer_common::address::{recover_message_signer, sign_message};
+use rand::RngCore;
+use sqlx::PgPool;
+use thegraph::types::Address;
+use tracing::error;
+
+use crate::escrow_overrides::AdminError;
+
+pub const SIGNATURE_HEADER: &str = "x-admin-signature";
+/// Time of the signature, in seconds since the UNIX epoch.
+pub const TIMESTAMP_HEADER: &str = "x-admin-timestamp";
+/// Random value making each signature single-use.
+pub const NONCE_HEADER: &str = "x-admin-nonce";
+
+/// Signatures older than this, or this far ahead, are rejected, so that they can't be replayed.
+const MAX_SIGNATURE_AGE_SECS: u64 = 300;
+const MAX_SIGNED_BODY_SIZE: usize = 10 * 1024 * 1024;
+const MAX_NONCE_LEN: usize = 128;
+
+/// Signer and signature of an admin request, passed to its handler as a request extension.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AdminSignature {
+    pub signer: Address,
+    pub signature: String,
+    pub nonce: String,
+}
+
+/// Indexer and chain the admin actions are signed for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SignatureScope {
+    pub indexer_address: Address,
+    pub chain_id: u64,
+}
+
+/// Keys allowed to sign the admin actions of an indexer and chain, along with the database
+/// storing the used nonces. The signatures are only required when `signers` is set.
+#[derive(Clone, Debug)]
+pub struct AdminSigners {
+    pub signers: Option<Vec<Address>>,
+    pub scope: SignatureScope,
+    pub pgpool: PgPool,
+}
+
+/// Message signed for an admin request.
+pub fn message(
+    scope: SignatureScope,
+    method: &str,
+    path: &str,
+    timestamp: u64,
+    nonce: &str,
+    body: &[u8],
+) -> String {
+    format!(
+        "indexer admin action\nindexer {}\nchain {}\n{method} {path}\n{timestamp}\n{nonce}\n{}",
+        scope.indexer_address,
+        scope.chain_id,
+        keccak256(body)
+    )
+}
+
+/// Signature headers of an admin request, signed with the operator key or an admin key, with a
+/// new random nonce.
+pub fn sign(
+    key: &str,
+    scope: SignatureScope,
+    method: &str,
+    path: &str,
+    body: &[u8],
+    now: u64,
+) -> Result<[(&'static str, String); 3]> {
+    let mut nonce = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let nonce = hex::encode(nonce);
+    let signature = sign_message(
+        key,
+        message(scope, method, path, now, &nonce, body).as_bytes(),
+    )?;
+    Ok([
+        (SIGNATURE_HEADER, hex::encode_prefixed(signature)),
+        (TIMESTAMP_HEADER, now.to_string()),
+        (NONCE_HEADER, nonce),
+    ])
+}
+
+fn unauthorized(message: impl Into<String>) -> AdminError {
+    (StatusCode::UNAUTHORIZED, message.into())
+}
+
+/// Checks the signature of an admin request, at `now` in seconds since the UNIX epoch. The
+/// nonce isn't checked against the used ones, see [use_nonce].
+pub fn verify(
+    signers: &[Address],
+    scope: SignatureScope,
+    method: &str,
+    path: &str,
+    headers: &HeaderMap,
+    body: &[u8],
+    now: u64,
+) -> Result<AdminSignature, AdminError> {
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+    let (Some(signature), Some(timestamp), Some(nonce)) = (
+        header(SIGNATURE_HEADER),
+        header(TIMESTAMP_HEADER),
+        header(NONCE_HEADER),
+    ) else {
+        return Err(unauthorized(format!(
+            "Admin actions must be signed, with the `{SIGNATURE_HEADER}`, `{TIMESTAMP_HEADER}` \
+             and `{NONCE_HEADER}` headers"
+        )));
+    };
+    if nonce.is_empty() || nonce.len() > MAX_NONCE_LEN {
+        return Err(unauthorized("Invalid signature nonce"));
+    }
+    let timestamp: u64 = timestamp
+        .parse()
+        .map_err(|_| unauthorized("Invalid signature timestamp"))?;
+    if now.abs_diff(timestamp) > MAX_SIGNATURE_AGE_SECS {
+        return Err(unauthorized("The signature expired"));
+    }
+
+    let signature_bytes =
+        hex::decode(signature).map_err(|_| unauthorized("Invalid signature encoding"))?;
+    let signer = recover_message_signer(
+        message(scope, method, path, timestamp, nonce, body).as_bytes(),
+        &signature_bytes,
+    )
+    .map_err(|_| unauthorized("Invalid signature"))?;
+    if !signers.contains(&signer) {
+        return Err(unauthorized(format!(
+            "{signer} isn't allowed to sign admin actions"
+        )));
+    }
+    Ok(AdminSignature {
+        signer,
+        signature: hex::encode_prefixed(signature_bytes),
+        nonce: nonce.to_string(),
+    })
+}
+
+/// Marks the nonce of a valid signature as used, failing if it already was. The nonces of the
+/// expired signatures are pruned along the way, as they can't be replayed anymore.
+pub async fn use_nonce(pgpool: &PgPool, signature: &AdminSignature) -> Result<(), AdminError> {
+    let internal_error = |e: sqlx::Error| {
+        error!("Failed to store the admin signature nonce: {}", e);
+        (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+    };
+    sqlx::query(
+        r#"
+            DELETE FROM admin_signature_nonces
+            WHERE used_at < NOW() - make_interval(secs => $1)
+        "#,
+    )
+    .bind(2.0 * MAX_SIGNATURE_AGE_SECS as f64)
+    .execute(pgpool)
+    .await
+    .map_err(internal_error)?;
+    let inserted = sqlx::query(
+        r#"
+            INSERT INTO admin_signature_nonces (nonce, signer)
+            VALUES ($1, $2)
+            ON CONFLICT (nonce) DO NOTHING
+        "#,
+    )
+    .bind(&signature.nonce)
+    .bind(signature.signer.encode_hex::<String>())
+    .execute(pgpool)
+    .await
+    .map_err(internal_error)?
+    .rows_affected();
+    if inserted == 0 {
+        return Err(unauthorized("The signature was already used"));
+    }
+    Ok(())
+}
+
+/// Middleware of the admin routes making changes, rejecting the requests without a valid
+/// signature when the signatures are required.
+pub async fn require_signature(
+    State(admin_signers): State<Arc<AdminSigners>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AdminError> {
+    let Some(signers) = &admin_signers.signers else {
+        return Ok(next.run(request).await);
+    };
+    let (parts, body) = request.into_parts();
+    let body = to_bytes(body, MAX_SIGNED_BODY_SIZE)
+        .await
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    let path = parts
+        .uri
+        .path_and_query()
+        .map_or(parts.uri.path(), |path| path.as_str());
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs();
+    let signature = verify(
+        signers,
+        admin_signers.scope,
+        parts.method.as_str(),
+        path,
+        &parts.headers,
+        &body,
+        now,
+    )?;
+    use_nonce(&admin_signers.pgpool, &signature).await?;
+
+    let mut request = Request::from_parts(parts, Body::from(body));
+    request.extensions_mut().insert(signature);
+    Ok(next.run(request).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use indexer_common::{address::wallet_address, test_vectors::INDEXER_OPERATOR_MNEMONIC};
+
+    use super::*;
+    use axum::http::HeaderValue;
+
+    const PATH: &str = "/admin/escrow-overrides/0x9858effd232b4033e47d90003d41ec34ecaeda94";
+    const SCOPE: SignatureScope = SignatureScope {
+        indexer_address: Address::repeat_byte(0x11),
+        chain_id: 1337,
+    };
+
+    fn signed_headers(path: &str, body: &[u8], now: u64) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in
+            sign(&INDEXER_OPERATOR_MNEMONIC, SCOPE, "PUT", path, body, now).unwrap()
+        {
+            headers.insert(name, HeaderValue::from_str(&value).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn test_verify() {
+        let operator = wallet_address(&INDEXER_OPERATOR_MNEMONIC).unwrap();
+        let body = br#"{"reason":"outage"}"#;
+        let now = 1_700_000_000;
+        let headers = signed_headers(PATH, body, now);
+
+        let signature = verify(&[operator], SCOPE, "PUT", PATH, &headers, body, now + 10).unwrap();
+        assert_eq!(signature.signer, operator);
+        assert_eq!(
+            signature.signature,
+            headers[SIGNATURE_HEADER].to_str().unwrap()
+        );
+        assert_eq!(signature.nonce, headers[NONCE_HEADER].to_str().unwrap());
+
+        // Not an allowed signer
+        assert!(verify(&[Address::ZERO], SCOPE, "PUT", PATH, &headers, body, now).is_err());
+        // Not the signed request
+        assert!(verify(&[operator], SCOPE, "DELETE", PATH, &headers, body, now).is_err());
+        assert!(verify(
+            &[operator],
+            SCOPE,
+            "PUT",
+            "/admin/ravs/import",
+            &headers,
+            body,
+            now
+        )
+        .is_err());
+        assert!(verify(&[operator], SCOPE, "PUT", PATH, &headers, b"{}", now).is_err());
+        // Signed for another indexer or chain
+        let other_indexer = SignatureScope {
+            indexer_address: Address::repeat_byte(0x22),
+            ..SCOPE
+        };
+        assert!(verify(&[operator], other_indexer, "PUT", PATH, &headers, body, now).is_err());
+        let other_chain = SignatureScope {
+            chain_id: 1,
+            ..SCOPE
+        };
+        assert!(verify(&[operator], other_chain, "PUT", PATH, &headers, body, now).is_err());
+        // Another nonce
+        let mut other_nonce = headers.clone();
+        other_nonce.insert(NONCE_HEADER, HeaderValue::from_static("00"));
+        assert!(verify(&[operator], SCOPE, "PUT", PATH, &other_nonce, body, now).is_err());
+        // Expired
+        assert!(verify(&[operator], SCOPE, "PUT", PATH, &headers, body, now + 301).is_err());
+        // Unsigned
+        assert!(verify(
+            &[operator],
+            SCOPE,
+            "PUT",
+            PATH,
+            &HeaderMap::new(),
+            body,
+            now
+        )
+        .is_err());
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_use_nonce(pgpool: PgPool) {
+        let signature = |nonce: &str| AdminSignature {
+            signer: Address::repeat_byte(0x33),
+            signature: "0x1234".to_string(),
+            nonce: nonce.to_string(),
+        };
+        use_nonce(&pgpool, &signature("01")).await.unwrap();
+        use_nonce(&pgpool, &signature("02")).await.unwrap();
+        // Replayed
+        let (status, _) = use_nonce(&pgpool, &signature("01")).await.unwrap_err();
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+}
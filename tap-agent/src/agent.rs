@@ -1,36 +1,82 @@
 // Copyright 2023-, GraphOps and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
+use std::sync::Arc;
 use std::time::Duration;
 
+use axum::{middleware, Router};
+use eventuals::Eventual;
+use indexer_common::allocations::sources::{
+    registered_allocations, static_allocations, AllocationSource,
+};
+use indexer_common::allocations::virtual_allocations::virtual_allocations;
+use indexer_common::events::{spawn_event_bus, EventListener};
 use indexer_common::prelude::{
-    escrow_accounts, indexer_allocations, DeploymentDetails, SubgraphClient,
+    escrow_accounts, escrow_accounts_with_overrides, indexer_allocations,
+    indexer_allocations_with_quorum, majority_quorum, DeploymentDetails, SubgraphClient,
 };
+use indexer_common::readiness::Readiness;
+use indexer_common::schema_drift::{self, SCHEMA_CHECK_INTERVAL};
+use indexer_common::{http_proxy, monitor_backoff};
 use ractor::concurrency::JoinHandle;
 use ractor::{Actor, ActorRef};
 
+use crate::admin_signature::{AdminSigners, SignatureScope};
+use crate::agent::clock::TokioClock;
 use crate::agent::sender_accounts_manager::{
     SenderAccountsManagerArgs, SenderAccountsManagerMessage,
 };
+use crate::agreements::AgreementRavs;
 use crate::config::{
     Config, EscrowSubgraph, Ethereum, IndexerInfrastructure, NetworkSubgraph, Tap,
 };
+use crate::escrow_overrides::require_admin_token;
+use crate::rav_verification::RavVerification;
+use crate::redemption_advice::{self, LogRedemptionAdvice};
+use crate::scheduler::{AnalyzeTables, Job, PruneEvents, Scheduler};
+use crate::sender_statements::{self, SenderStatements};
+use crate::table_health::{self, MeasureTableHealth};
+use crate::trace_bundles::PruneTraceBundles;
+use crate::{
+    accounting, actor_topology, allocation_closures, allocation_registry, allocation_status,
+    close_timing, escrow_overrides, fee_overflows, invalid_receipts, poi_gate, rav_history,
+    rav_import, receipt_lifecycle, receivables, sharding, vanished_allocations,
+    virtual_allocations,
+};
 use crate::{database, CONFIG, EIP_712_DOMAIN};
 use sender_accounts_manager::SenderAccountsManager;
 
+pub mod aggregator_health;
+pub mod aggregator_response;
+pub mod checkpoint;
+pub mod clock;
+pub mod rav_schedule;
 pub mod sender_account;
 pub mod sender_accounts_manager;
 pub mod sender_allocation;
 pub mod sender_fee_tracker;
+pub mod shutdown;
+pub mod trigger_tuning;
 pub mod unaggregated_receipts;
 
-pub async fn start_agent() -> (ActorRef<SenderAccountsManagerMessage>, JoinHandle<()>) {
+/// Starts the agent, returning along with the manager actor the routes exposing its state, to be
+/// served by the metrics server.
+pub async fn start_agent() -> (
+    ActorRef<SenderAccountsManagerMessage>,
+    JoinHandle<()>,
+    Router,
+) {
     let Config {
-        ethereum: Ethereum { indexer_address },
+        ethereum: Ethereum {
+            indexer_address,
+            operator_key,
+        },
         indexer_infrastructure:
             IndexerInfrastructure {
                 graph_node_query_endpoint,
                 graph_node_status_endpoint,
+                monitor_alarms,
+                proxy,
                 ..
             },
         postgres,
@@ -41,6 +87,11 @@ pub async fn start_agent() -> (ActorRef<SenderAccountsManagerMessage>, JoinHandl
                 network_subgraph_auth_token,
                 allocation_syncing_interval_ms,
                 recently_closed_allocation_buffer_seconds,
+                network_subgraph_additional_endpoints,
+                network_subgraph_quorum,
+                allocation_source,
+                static_allocations: static_allocations_config,
+                ..
             },
         escrow_subgraph:
             EscrowSubgraph {
@@ -48,83 +99,346 @@ pub async fn start_agent() -> (ActorRef<SenderAccountsManagerMessage>, JoinHandl
                 escrow_subgraph_endpoint,
                 escrow_subgraph_auth_token,
                 escrow_syncing_interval_ms,
+                escrow_page_size,
             },
         tap:
             Tap {
                 // TODO: replace with a proper implementation once the gateway registry contract is ready
                 sender_aggregator_endpoints,
+                rav_request_timeout_secs,
+                rav_request_timestamp_buffer_ms,
+                scheduler,
+                admin_auth_token,
+                admin_signers,
+                aggregator_health,
+                virtual_allocations: virtual_allocations_config,
+                rav_verification_webhook_url,
+                trace_export,
+                redemption_advice,
+                indexing_agreements,
+                sharding: shard,
                 ..
             },
         ..
     } = &*CONFIG;
     let pgpool = database::connect(postgres).await;
+    // The RAVs and the receipt deletions are refused while the schema drifted
+    schema_drift::start(pgpool.clone(), SCHEMA_CHECK_INTERVAL).await;
+    if let Some(shard) = shard {
+        sharding::start(pgpool.clone(), *indexer_address, *shard)
+            .await
+            .expect("Failed to lease the shard of this instance");
+    }
+    // Logged and counted for now, subscribed to as the agent starts reacting to the events
+    spawn_event_bus(
+        EventListener::connect(&pgpool)
+            .await
+            .expect("Failed to listen to the indexer events"),
+    );
 
-    let http_client = reqwest::Client::new();
+    let http_client = http_proxy::client(proxy);
+    monitor_backoff::configure(monitor_alarms.clone(), http_client.clone());
 
-    let network_subgraph = Box::leak(Box::new(SubgraphClient::new(
-        http_client.clone(),
-        network_subgraph_deployment
-            .map(|deployment| {
-                DeploymentDetails::for_graph_node(
-                    graph_node_status_endpoint,
-                    graph_node_query_endpoint,
-                    deployment,
-                )
-            })
-            .transpose()
-            .expect("Failed to parse graph node query endpoint and network subgraph deployment"),
-        DeploymentDetails::for_query_url_with_token(
-            network_subgraph_endpoint,
-            network_subgraph_auth_token.clone(),
+    let network_subgraph: &'static SubgraphClient = Box::leak(Box::new(
+        SubgraphClient::new(
+            http_client.clone(),
+            network_subgraph_deployment
+                .map(|deployment| {
+                    DeploymentDetails::for_graph_node(
+                        graph_node_status_endpoint,
+                        graph_node_query_endpoint,
+                        deployment,
+                    )
+                })
+                .transpose()
+                .expect(
+                    "Failed to parse graph node query endpoint and network subgraph deployment",
+                ),
+            DeploymentDetails::for_query_url_with_token(
+                network_subgraph_endpoint,
+                network_subgraph_auth_token.clone(),
+            )
+            .expect("Failed to parse network subgraph endpoint"),
         )
-        .expect("Failed to parse network subgraph endpoint"),
-    )));
+        .with_name("network"),
+    ));
 
-    let indexer_allocations = indexer_allocations(
-        network_subgraph,
-        *indexer_address,
-        Duration::from_millis(*allocation_syncing_interval_ms),
-        Duration::from_secs(*recently_closed_allocation_buffer_seconds),
-    );
+    let indexer_allocations = match allocation_source {
+        AllocationSource::Static => static_allocations(*indexer_address, static_allocations_config),
+        AllocationSource::Api => registered_allocations(
+            pgpool.clone(),
+            *indexer_address,
+            Duration::from_millis(*allocation_syncing_interval_ms),
+            Duration::from_secs(*recently_closed_allocation_buffer_seconds),
+        ),
+        AllocationSource::Network if network_subgraph_additional_endpoints.is_empty() => {
+            indexer_allocations(
+                network_subgraph,
+                *indexer_address,
+                Duration::from_millis(*allocation_syncing_interval_ms),
+                Duration::from_secs(*recently_closed_allocation_buffer_seconds),
+            )
+        }
+        AllocationSource::Network => {
+            let mut network_subgraphs = vec![(network_subgraph_endpoint.clone(), network_subgraph)];
+            for endpoint in network_subgraph_additional_endpoints {
+                let client: &'static SubgraphClient = Box::leak(Box::new(
+                    SubgraphClient::new(
+                        http_client.clone(),
+                        None,
+                        // Mirrors of the same subgraph, behind the same gateway auth
+                        DeploymentDetails::for_query_url_with_token(
+                            endpoint,
+                            network_subgraph_auth_token.clone(),
+                        )
+                        .expect("Failed to parse additional network subgraph endpoint"),
+                    )
+                    .with_name("network"),
+                ));
+                network_subgraphs.push((endpoint.clone(), client));
+            }
+            let quorum =
+                network_subgraph_quorum.unwrap_or(majority_quorum(network_subgraphs.len()));
+            indexer_allocations_with_quorum(
+                network_subgraphs,
+                quorum,
+                *indexer_address,
+                Duration::from_millis(*allocation_syncing_interval_ms),
+                Duration::from_secs(*recently_closed_allocation_buffer_seconds),
+            )
+        }
+    };
 
-    let escrow_subgraph = Box::leak(Box::new(SubgraphClient::new(
-        http_client.clone(),
-        escrow_subgraph_deployment
-            .map(|deployment| {
-                DeploymentDetails::for_graph_node(
-                    graph_node_status_endpoint,
-                    graph_node_query_endpoint,
-                    deployment,
-                )
-            })
-            .transpose()
-            .expect("Failed to parse graph node query endpoint and escrow subgraph deployment"),
-        DeploymentDetails::for_query_url_with_token(
-            escrow_subgraph_endpoint,
-            escrow_subgraph_auth_token.clone(),
+    let virtual_allocations = match virtual_allocations_config {
+        Some(config) => virtual_allocations(
+            pgpool.clone(),
+            config.operator,
+            config.services.clone(),
+            Duration::from_millis(*allocation_syncing_interval_ms),
+        ),
+        None => Eventual::from_value(Default::default()),
+    };
+
+    let escrow_subgraph = Box::leak(Box::new(
+        SubgraphClient::new(
+            http_client.clone(),
+            escrow_subgraph_deployment
+                .map(|deployment| {
+                    DeploymentDetails::for_graph_node(
+                        graph_node_status_endpoint,
+                        graph_node_query_endpoint,
+                        deployment,
+                    )
+                })
+                .transpose()
+                .expect("Failed to parse graph node query endpoint and escrow subgraph deployment"),
+            DeploymentDetails::for_query_url_with_token(
+                escrow_subgraph_endpoint,
+                escrow_subgraph_auth_token.clone(),
+            )
+            .expect("Failed to parse escrow subgraph endpoint"),
         )
-        .expect("Failed to parse escrow subgraph endpoint"),
-    )));
+        .with_name("escrow"),
+    ));
 
     let escrow_accounts = escrow_accounts(
         escrow_subgraph,
         *indexer_address,
         Duration::from_millis(*escrow_syncing_interval_ms),
         false,
+        *escrow_page_size,
     );
+    let escrow_accounts = escrow_accounts_with_overrides(
+        escrow_accounts,
+        pgpool.clone(),
+        Duration::from_millis(*escrow_syncing_interval_ms),
+    );
+
+    let sender_statements = SenderStatements {
+        pgpool: pgpool.clone(),
+        indexer_address: *indexer_address,
+        operator_key: operator_key.clone(),
+        domain_separator: EIP_712_DOMAIN.clone(),
+        escrow_accounts: escrow_accounts.clone(),
+    };
+    let mut jobs: Vec<Arc<dyn Job>> = vec![
+        Arc::new(AnalyzeTables::new(pgpool.clone())),
+        Arc::new(PruneEvents::new(pgpool.clone())),
+        Arc::new(MeasureTableHealth::new(pgpool.clone())),
+        Arc::new(sender_statements.clone()),
+        Arc::new(RavVerification {
+            pgpool: pgpool.clone(),
+            escrow_accounts: escrow_accounts.clone(),
+            domain_separator: EIP_712_DOMAIN.clone(),
+            webhook_url: rav_verification_webhook_url.clone(),
+            http_client: http_client.clone(),
+        }),
+    ];
+    if *indexing_agreements {
+        jobs.push(Arc::new(AgreementRavs {
+            pgpool: pgpool.clone(),
+            escrow_accounts: escrow_accounts.clone(),
+            domain_separator: EIP_712_DOMAIN.clone(),
+            sender_aggregator_endpoints: sender_aggregator_endpoints.clone(),
+            http_client: http_client.clone(),
+            request_timeout: Duration::from_secs(*rav_request_timeout_secs),
+        }));
+    }
+    if let Some(trace_export) = trace_export {
+        jobs.push(Arc::new(PruneTraceBundles {
+            pgpool: pgpool.clone(),
+            trace_export: trace_export.clone(),
+            http_client: http_client.clone(),
+        }));
+    }
+    if let Some(redemption_advice) = redemption_advice {
+        jobs.push(Arc::new(LogRedemptionAdvice {
+            pgpool: pgpool.clone(),
+            config: redemption_advice.clone(),
+            http_client: http_client.clone(),
+        }));
+    }
+    // The jobs cover all the senders, they run on the first shard only
+    if shard.map_or(true, |shard| shard.index == 0) {
+        Scheduler::new(scheduler, jobs)
+            .expect("Failed to configure the scheduler")
+            .start();
+    }
 
+    if let Some(probing) = aggregator_health {
+        let endpoints = sender_aggregator_endpoints
+            .iter()
+            .map(|(sender, endpoint)| {
+                (
+                    *sender,
+                    CONFIG.tap.aggregator_endpoints_for(sender, endpoint),
+                )
+            })
+            .collect();
+        aggregator_health::start_prober(endpoints, probing.clone(), http_client.clone());
+    }
+
+    let readiness = Readiness::new();
+    readiness.wait_for("allocations", indexer_allocations.clone());
+    readiness.wait_for("escrow_accounts", escrow_accounts.clone());
+
+    let mut state_routes = close_timing::router(
+        pgpool.clone(),
+        network_subgraph,
+        indexer_allocations.clone(),
+    )
+    .merge(rav_history::router(pgpool.clone()))
+    .merge(receivables::router(pgpool.clone(), escrow_accounts.clone()))
+    .merge(allocation_closures::router(pgpool.clone()))
+    .merge(readiness.router());
+    if let Some(redemption_advice) = redemption_advice {
+        state_routes = state_routes.merge(redemption_advice::router(
+            pgpool.clone(),
+            redemption_advice.clone(),
+            http_client.clone(),
+        ));
+    }
+    // All the admin routes are behind the admin auth token, checked once for all of them
+    let mut admin_routes = Router::new();
+    if admin_auth_token.is_some() {
+        let admin_signers = Arc::new(AdminSigners {
+            signers: admin_signers.clone(),
+            scope: SignatureScope {
+                indexer_address: *indexer_address,
+                chain_id: CONFIG.receipts.receipts_verifier_chain_id,
+            },
+            pgpool: pgpool.clone(),
+        });
+        admin_routes = admin_routes
+            .merge(allocation_status::router(
+                pgpool.clone(),
+                indexer_allocations.clone(),
+                escrow_accounts.clone(),
+            ))
+            .merge(escrow_overrides::router(
+                pgpool.clone(),
+                admin_signers.clone(),
+            ))
+            .merge(poi_gate::router(pgpool.clone(), admin_signers.clone()))
+            .merge(accounting::router(pgpool.clone(), admin_signers.clone()))
+            .merge(fee_overflows::router(pgpool.clone(), admin_signers.clone()))
+            .merge(vanished_allocations::router(
+                pgpool.clone(),
+                admin_signers.clone(),
+            ))
+            .merge(table_health::router(pgpool.clone()))
+            .merge(sender_statements::router(
+                sender_statements,
+                admin_signers.clone(),
+            ))
+            .merge(rav_import::router(
+                pgpool.clone(),
+                admin_signers.clone(),
+                escrow_accounts.clone(),
+                EIP_712_DOMAIN.clone(),
+            ))
+            .merge(invalid_receipts::router(
+                pgpool.clone(),
+                admin_signers.clone(),
+                escrow_accounts.clone(),
+                EIP_712_DOMAIN.clone(),
+            ))
+            .merge(receipt_lifecycle::router(
+                pgpool.clone(),
+                escrow_accounts.clone(),
+                EIP_712_DOMAIN.clone(),
+                rav_request_timestamp_buffer_ms * 1_000_000,
+            ));
+        if *allocation_source == AllocationSource::Api {
+            admin_routes = admin_routes.merge(allocation_registry::router(
+                pgpool.clone(),
+                admin_signers.clone(),
+            ));
+        }
+        if let Some(config) = virtual_allocations_config {
+            admin_routes = admin_routes.merge(virtual_allocations::router(
+                pgpool.clone(),
+                admin_signers,
+                config.clone(),
+            ));
+        }
+    }
+
+    #[cfg(feature = "systemd")]
+    let database_check_pool = pgpool.clone();
     let args = SenderAccountsManagerArgs {
         config: &CONFIG,
         domain_separator: EIP_712_DOMAIN.clone(),
         pgpool,
         indexer_allocations,
+        virtual_allocations,
         escrow_accounts,
         escrow_subgraph,
+        network_subgraph,
         sender_aggregator_endpoints: sender_aggregator_endpoints.clone(),
         prefix: None,
+        clock: Arc::new(TokioClock),
     };
 
-    SenderAccountsManager::spawn(None, SenderAccountsManager, args)
+    let (manager, handler) = SenderAccountsManager::spawn(None, SenderAccountsManager, args)
         .await
-        .expect("Failed to start sender accounts manager actor.")
+        .expect("Failed to start sender accounts manager actor.");
+    #[cfg(feature = "systemd")]
+    indexer_common::systemd::start(
+        readiness.clone(),
+        vec![
+            Arc::new(indexer_common::systemd::DatabaseCheck(database_check_pool)),
+            Arc::new(indexer_common::systemd::ReadinessCheck(readiness)),
+            Arc::new(actor_topology::ActorsCheck(manager.clone())),
+        ],
+    );
+    if let Some(admin_auth_token) = admin_auth_token {
+        admin_routes = admin_routes.merge(actor_topology::router(manager.clone()));
+        state_routes =
+            state_routes.merge(admin_routes.route_layer(middleware::from_fn_with_state(
+                Arc::from(admin_auth_token.as_str()),
+                require_admin_token,
+            )));
+    }
+    (manager, handler, state_routes)
 }
@@ -0,0 +1,343 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Periodic probing of the senders' TAP aggregators. An aggregator endpoint failing several
+//! probes in a row is marked unhealthy, and skipped by the RAV requests while the sender has
+//! other endpoints. Once all the endpoints of a sender are unhealthy, its RAV requests are
+//! postponed until a probe succeeds again, rather than having every new receipt trigger a
+//! request to a dead aggregator.
+//!
+//! The RAV requests of a sender go round-robin over its healthy endpoints, see
+//! [endpoint_rotation].
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, RwLock};
+use std::time::Duration;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use futures_util::future::join_all;
+use jsonrpsee::rpc_params;
+use lazy_static::lazy_static;
+use prometheus::{
+    register_counter_vec, register_gauge_vec, register_histogram_vec, CounterVec, GaugeVec,
+    HistogramVec,
+};
+use serde::Serialize;
+use thegraph::types::Address;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+use crate::aggregator_client::AggregatorClient;
+use crate::config::AggregatorHealthProbing;
+
+lazy_static! {
+    static ref AGGREGATOR_HEALTHY: GaugeVec = register_gauge_vec!(
+        format!("tap_aggregator_healthy"),
+        "Whether the sender's TAP aggregator is healthy, according to the last probes",
+        &["sender"]
+    )
+    .unwrap();
+}
+
+lazy_static! {
+    static ref AGGREGATOR_ENDPOINT_HEALTHY: GaugeVec = register_gauge_vec!(
+        format!("tap_aggregator_endpoint_healthy"),
+        "Whether an endpoint of the sender's TAP aggregator is healthy, according to the last \
+        probes",
+        &["sender", "endpoint"]
+    )
+    .unwrap();
+}
+
+lazy_static! {
+    static ref AGGREGATOR_PROBE_LATENCY: HistogramVec = register_histogram_vec!(
+        format!("tap_aggregator_probe_latency_seconds"),
+        "Response time of the successful probes of the sender's TAP aggregator",
+        &["sender"]
+    )
+    .unwrap();
+}
+
+lazy_static! {
+    static ref AGGREGATOR_PROBE_FAILURES: CounterVec = register_counter_vec!(
+        format!("tap_aggregator_probe_failures"),
+        "Failed probes of the sender's TAP aggregator since the start of the program",
+        &["sender"]
+    )
+    .unwrap();
+}
+
+lazy_static! {
+    /// Health of the aggregator endpoints of every sender probed, served by the tap-agent HTTP
+    /// server.
+    pub static ref AGGREGATOR_HEALTH: RwLock<BTreeMap<Address, Vec<AggregatorHealth>>> =
+        RwLock::new(BTreeMap::new());
+    /// Next endpoint in turn, by sender.
+    static ref ROTATIONS: Mutex<HashMap<Address, usize>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AggregatorHealth {
+    pub endpoint: String,
+    pub healthy: bool,
+    pub consecutive_failures: u32,
+    pub last_latency_ms: Option<u64>,
+    pub last_error: Option<String>,
+    pub last_probed_at: DateTime<Utc>,
+}
+
+/// Whether RAV requests can be sent to the sender's aggregator, through at least one of its
+/// endpoints. Aggregators not probed are assumed healthy.
+pub fn is_healthy(sender: &Address) -> bool {
+    AGGREGATOR_HEALTH
+        .read()
+        .unwrap()
+        .get(sender)
+        .map_or(true, |endpoints| !all_unhealthy(endpoints))
+}
+
+fn all_unhealthy(endpoints: &[AggregatorHealth]) -> bool {
+    !endpoints.is_empty() && endpoints.iter().all(|health| !health.healthy)
+}
+
+/// The `endpoints` of the sender's aggregator, in the order a RAV request tries them: the
+/// healthy ones starting from the next one in turn, then the unhealthy ones as a last resort.
+/// Endpoints not probed are assumed healthy.
+pub fn endpoint_rotation(sender: &Address, endpoints: &[String]) -> Vec<String> {
+    let turn = {
+        let mut rotations = ROTATIONS.lock().unwrap();
+        let next = rotations.entry(*sender).or_default();
+        let turn = *next;
+        *next = next.wrapping_add(1);
+        turn
+    };
+    let statuses = AGGREGATOR_HEALTH.read().unwrap();
+    let probed = statuses.get(sender).map(Vec::as_slice).unwrap_or_default();
+    let (mut healthy, unhealthy): (Vec<_>, Vec<_>) =
+        endpoints.iter().cloned().partition(|endpoint| {
+            probed
+                .iter()
+                .find(|health| health.endpoint == *endpoint)
+                .map_or(true, |health| health.healthy)
+        });
+    if !healthy.is_empty() {
+        let len = healthy.len();
+        healthy.rotate_left(turn % len);
+    }
+    healthy.extend(unhealthy);
+    healthy
+}
+
+/// Probes the aggregator `endpoints`, by sender, every `probing.interval` through `http_client`.
+pub fn start_prober(
+    endpoints: HashMap<Address, Vec<String>>,
+    probing: AggregatorHealthProbing,
+    http_client: reqwest::Client,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(probing.interval);
+        loop {
+            interval.tick().await;
+            join_all(endpoints.iter().map(|(sender, endpoints)| {
+                probe_and_record(&http_client, *sender, endpoints, &probing)
+            }))
+            .await;
+        }
+    })
+}
+
+async fn probe_and_record(
+    http_client: &reqwest::Client,
+    sender: Address,
+    endpoints: &[String],
+    probing: &AggregatorHealthProbing,
+) {
+    let results = join_all(
+        endpoints
+            .iter()
+            .map(|endpoint| probe(http_client, endpoint, probing.timeout)),
+    )
+    .await;
+
+    let sender_label = sender.to_string();
+    let mut statuses = AGGREGATOR_HEALTH.write().unwrap();
+    let previous = statuses.remove(&sender).unwrap_or_default();
+    let mut healths = Vec::with_capacity(endpoints.len());
+    for (endpoint, result) in endpoints.iter().zip(results) {
+        match &result {
+            Ok(latency) => AGGREGATOR_PROBE_LATENCY
+                .with_label_values(&[&sender_label])
+                .observe(latency.as_secs_f64()),
+            Err(_) => AGGREGATOR_PROBE_FAILURES
+                .with_label_values(&[&sender_label])
+                .inc(),
+        }
+
+        let previous_health = previous.iter().find(|health| health.endpoint == *endpoint);
+        let health = next_health(
+            previous_health,
+            endpoint,
+            result.map_err(|e| e.to_string()),
+            probing.unhealthy_after_failures,
+        );
+        match (
+            previous_health.map_or(true, |previous| previous.healthy),
+            health.healthy,
+        ) {
+            (true, false) => warn!(
+                %sender,
+                endpoint,
+                error = health.last_error.as_deref(),
+                "Endpoint of the sender's TAP aggregator is unhealthy"
+            ),
+            (false, true) => info!(
+                %sender,
+                endpoint,
+                "Endpoint of the sender's TAP aggregator is healthy again"
+            ),
+            _ => {}
+        }
+        AGGREGATOR_ENDPOINT_HEALTHY
+            .with_label_values(&[&sender_label, endpoint])
+            .set(if health.healthy { 1.0 } else { 0.0 });
+        healths.push(health);
+    }
+
+    match (all_unhealthy(&previous), all_unhealthy(&healths)) {
+        (false, true) => warn!(
+            %sender,
+            "Sender's TAP aggregator is unhealthy, postponing its RAV requests"
+        ),
+        (true, false) => info!(%sender, "Sender's TAP aggregator is healthy again"),
+        _ => {}
+    }
+    AGGREGATOR_HEALTHY
+        .with_label_values(&[&sender_label])
+        .set(if all_unhealthy(&healths) { 0.0 } else { 1.0 });
+    statuses.insert(sender, healths);
+}
+
+async fn probe(
+    http_client: &reqwest::Client,
+    endpoint: &str,
+    timeout: Duration,
+) -> Result<Duration> {
+    let client = AggregatorClient::new(http_client.clone(), endpoint, timeout);
+    let start = Instant::now();
+    let _: serde_json::Value = client.request("api_versions", rpc_params!()).await?;
+    Ok(start.elapsed())
+}
+
+fn next_health(
+    previous: Option<&AggregatorHealth>,
+    endpoint: &str,
+    result: Result<Duration, String>,
+    unhealthy_after_failures: u32,
+) -> AggregatorHealth {
+    let last_probed_at = Utc::now();
+    match result {
+        Ok(latency) => AggregatorHealth {
+            endpoint: endpoint.to_string(),
+            healthy: true,
+            consecutive_failures: 0,
+            last_latency_ms: Some(latency.as_millis() as u64),
+            last_error: None,
+            last_probed_at,
+        },
+        Err(e) => {
+            let consecutive_failures =
+                previous.map_or(0, |previous| previous.consecutive_failures) + 1;
+            AggregatorHealth {
+                endpoint: endpoint.to_string(),
+                healthy: consecutive_failures < unhealthy_after_failures,
+                consecutive_failures,
+                last_latency_ms: previous.and_then(|previous| previous.last_latency_ms),
+                last_error: Some(e),
+                last_probed_at,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_health() {
+        let endpoint = "http://aggregator:7610";
+        let failure = || Err("connection refused".to_string());
+
+        let first = next_health(None, endpoint, failure(), 2);
+        assert!(first.healthy);
+        assert_eq!(first.consecutive_failures, 1);
+
+        let second = next_health(Some(&first), endpoint, failure(), 2);
+        assert!(!second.healthy);
+        assert_eq!(second.last_error.as_deref(), Some("connection refused"));
+
+        let recovered = next_health(Some(&second), endpoint, Ok(Duration::from_millis(12)), 2);
+        assert!(recovered.healthy);
+        assert_eq!(recovered.consecutive_failures, 0);
+        assert_eq!(recovered.last_latency_ms, Some(12));
+        assert_eq!(recovered.last_error, None);
+    }
+
+    #[test]
+    fn test_unprobed_aggregator_is_healthy() {
+        assert!(is_healthy(&Address::from([0x42u8; 20])));
+    }
+
+    #[test]
+    fn test_endpoint_rotation() {
+        let sender = Address::from([0x43u8; 20]);
+        let endpoints = ["http://a:7610", "http://b:7610", "http://c:7610"].map(String::from);
+        let firsts = (0..3)
+            .map(|_| endpoint_rotation(&sender, &endpoints)[0].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(firsts, endpoints);
+
+        // The unhealthy endpoint is tried last, the healthy ones still taking turns
+        let unhealthy = next_health(None, &endpoints[1], Err("timeout".to_string()), 1);
+        AGGREGATOR_HEALTH
+            .write()
+            .unwrap()
+            .insert(sender, vec![unhealthy.clone()]);
+        assert!(is_healthy(&sender));
+        let rotations = (0..2)
+            .map(|_| endpoint_rotation(&sender, &endpoints))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rotations,
+            vec![
+                vec![
+                    endpoints[2].clone(),
+                    endpoints[0].clone(),
+                    endpoints[1].clone()
+                ],
+                vec![
+                    endpoints[0].clone(),
+                    endpoints[2].clone(),
+                    endpoints[1].clone()
+                ],
+            ]
+        );
+
+        // Postponed once all the endpoints are unhealthy
+        AGGREGATOR_HEALTH.write().unwrap().insert(
+            sender,
+            endpoints
+                .iter()
+                .map(|endpoint| AggregatorHealth {
+                    endpoint: endpoint.clone(),
+                    ..unhealthy.clone()
+                })
+                .collect(),
+        );
+        assert!(!is_healthy(&sender));
+        assert_eq!(endpoint_rotation(&sender, &endpoints).len(), 3);
+    }
+}
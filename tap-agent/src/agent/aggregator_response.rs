@@ -0,0 +1,189 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Validation of the responses of the sender aggregators before any of their content is used.
+//! A response that doesn't match the expected schema, or whose RAV goes backwards from the
+//! previous one, is classified as an anomaly and rejected as a whole, so that a malformed
+//! response can't leave the allocation with a partially updated state.
+
+use lazy_static::lazy_static;
+use prometheus::{register_counter_vec, CounterVec};
+use serde_json::Value;
+use tap_aggregator::jsonrpsee_helpers::JsonRpcResponse;
+use tap_core::rav::{ReceiptAggregateVoucher, SignedRAV};
+use thegraph::types::Address;
+
+lazy_static! {
+    static ref RAV_RESPONSE_ANOMALIES: CounterVec = register_counter_vec!(
+        "rav_response_anomalies",
+        "Aggregator responses rejected before verifying their RAV, by class of anomaly",
+        &["sender", "kind"]
+    )
+    .unwrap();
+}
+
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum AggregatorAnomaly {
+    #[error("Malformed aggregator response: {0}")]
+    Malformed(String),
+    #[error("Malformed warnings in the aggregator response: {0}")]
+    InvalidWarnings(String),
+    #[error("RAV for allocation {received} instead of {expected}")]
+    WrongAllocation {
+        expected: Address,
+        received: Address,
+    },
+    #[error("RAV timestamp {received} isn't after the one of the previous RAV, {previous}")]
+    TimestampRegression { previous: u64, received: u64 },
+    #[error("RAV value {received} is lower than the one of the previous RAV, {previous}")]
+    ValueRegression { previous: u128, received: u128 },
+}
+
+impl AggregatorAnomaly {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AggregatorAnomaly::Malformed(_) => "malformed",
+            AggregatorAnomaly::InvalidWarnings(_) => "invalid_warnings",
+            AggregatorAnomaly::WrongAllocation { .. } => "wrong_allocation",
+            AggregatorAnomaly::TimestampRegression { .. } => "timestamp_regression",
+            AggregatorAnomaly::ValueRegression { .. } => "value_regression",
+        }
+    }
+
+    pub fn record(&self, sender: Address) {
+        RAV_RESPONSE_ANOMALIES
+            .with_label_values(&[&sender.to_string(), self.kind()])
+            .inc();
+    }
+}
+
+/// Checks the raw response to a RAV request for `allocation_id`, following `previous_rav`.
+pub fn validate(
+    raw: &Value,
+    allocation_id: Address,
+    previous_rav: Option<&ReceiptAggregateVoucher>,
+) -> Result<JsonRpcResponse<SignedRAV>, AggregatorAnomaly> {
+    let Some(response) = raw.as_object() else {
+        return Err(AggregatorAnomaly::Malformed(format!(
+            "Expected an object, got `{raw}`"
+        )));
+    };
+    match response.get("warnings") {
+        None | Some(Value::Null) => {}
+        Some(Value::Array(warnings)) => {
+            for warning in warnings {
+                let is_warning = warning.get("code").is_some_and(Value::is_i64)
+                    && warning.get("message").is_some_and(Value::is_string);
+                if !is_warning {
+                    return Err(AggregatorAnomaly::InvalidWarnings(format!(
+                        "Expected a code and a message, got `{warning}`"
+                    )));
+                }
+            }
+        }
+        Some(warnings) => {
+            return Err(AggregatorAnomaly::InvalidWarnings(format!(
+                "Expected an array, got `{warnings}`"
+            )))
+        }
+    }
+    if !response.contains_key("data") {
+        return Err(AggregatorAnomaly::Malformed("Missing `data`".to_string()));
+    }
+    // Rejects missing fields, and values out of their range, e.g. negative ones
+    let response: JsonRpcResponse<SignedRAV> = serde_json::from_value(raw.clone())
+        .map_err(|e| AggregatorAnomaly::Malformed(e.to_string()))?;
+
+    let rav = &response.data.message;
+    if rav.allocationId != allocation_id {
+        return Err(AggregatorAnomaly::WrongAllocation {
+            expected: allocation_id,
+            received: rav.allocationId,
+        });
+    }
+    if let Some(previous_rav) = previous_rav {
+        if rav.timestampNs <= previous_rav.timestampNs {
+            return Err(AggregatorAnomaly::TimestampRegression {
+                previous: previous_rav.timestampNs,
+                received: rav.timestampNs,
+            });
+        }
+        if rav.valueAggregate < previous_rav.valueAggregate {
+            return Err(AggregatorAnomaly::ValueRegression {
+                previous: previous_rav.valueAggregate,
+                received: rav.valueAggregate,
+            });
+        }
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::tap::test_utils::{create_rav, ALLOCATION_ID_0, ALLOCATION_ID_1, SIGNER};
+
+    fn response(allocation_id: Address, timestamp_ns: u64, value: u128) -> Value {
+        json!({
+            "data": create_rav(allocation_id, SIGNER.0.clone(), timestamp_ns, value),
+            "warnings": null,
+        })
+    }
+
+    #[test]
+    fn test_valid_response() {
+        let previous = create_rav(*ALLOCATION_ID_0, SIGNER.0.clone(), 10, 100).message;
+        let mut raw = response(*ALLOCATION_ID_0, 20, 150);
+        raw["warnings"] = json!([{ "code": -32000, "message": "deprecated API version" }]);
+
+        let response = validate(&raw, *ALLOCATION_ID_0, Some(&previous)).unwrap();
+        assert_eq!(response.data.message.valueAggregate, 150);
+        assert_eq!(response.warnings.unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_anomalies() {
+        let previous = create_rav(*ALLOCATION_ID_0, SIGNER.0.clone(), 10, 100).message;
+        let check = |raw: &Value| validate(raw, *ALLOCATION_ID_0, Some(&previous)).unwrap_err();
+
+        let mut raw = response(*ALLOCATION_ID_0, 20, 150);
+        raw["warnings"] = json!("deprecated API version");
+        assert_eq!(check(&raw).kind(), "invalid_warnings");
+        raw["warnings"] = json!([{ "message": "deprecated API version" }]);
+        assert_eq!(check(&raw).kind(), "invalid_warnings");
+
+        let mut raw = response(*ALLOCATION_ID_0, 20, 150);
+        raw["data"]["message"]
+            .as_object_mut()
+            .unwrap()
+            .remove("valueAggregate");
+        assert_eq!(check(&raw).kind(), "malformed");
+        raw["data"]["message"]["valueAggregate"] = json!(-1);
+        assert_eq!(check(&raw).kind(), "malformed");
+        assert_eq!(check(&json!([])).kind(), "malformed");
+
+        assert_eq!(
+            check(&response(*ALLOCATION_ID_1, 20, 150)),
+            AggregatorAnomaly::WrongAllocation {
+                expected: *ALLOCATION_ID_0,
+                received: *ALLOCATION_ID_1,
+            }
+        );
+        assert_eq!(
+            check(&response(*ALLOCATION_ID_0, 10, 150)),
+            AggregatorAnomaly::TimestampRegression {
+                previous: 10,
+                received: 10,
+            }
+        );
+        assert_eq!(
+            check(&response(*ALLOCATION_ID_0, 20, 50)),
+            AggregatorAnomaly::ValueRegression {
+                previous: 100,
+                received: 50,
+            }
+        );
+    }
+}
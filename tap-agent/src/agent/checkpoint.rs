@@ -0,0 +1,401 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! The indexer-service keeps storing receipts in `scalar_tap_receipts` while the tap-agent is
+//! down, which makes that table the journal of receipts pending processing by the agent. The
+//! checkpoint records the last receipt the agent has seen, so that on startup it knows which
+//! receipts came in while it was away. With [sharding](crate::sharding), each shard keeps its
+//! own checkpoint, identified by the [shard key](crate::config::Tap::shard_key).
+//!
+//! On shutdown, each sender allocation also saves its unaggregated fees along with the id of the
+//! last receipt they account for. On the next start, only the receipts stored after that id need
+//! to be summed, as long as the checkpoint is still valid.
+
+use std::str::FromStr;
+
+use anyhow::{ensure, Result};
+use indexer_common::schema_drift;
+use indexer_common::types::{AllocationIdHex, GrtWei, SenderAddress};
+use sqlx::{types::BigDecimal, PgPool, Row};
+use thegraph::types::Address;
+use tracing::warn;
+
+use crate::agent::unaggregated_receipts::UnaggregatedReceipts;
+
+/// Version of the checkpoint format. Checkpoints saved with another version are discarded.
+pub const CHECKPOINT_VERSION: i32 = 2;
+
+/// Receipts of a (signer, allocation) pair stored after the checkpoint.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ReceiptsSinceCheckpoint {
+    pub signer_address: Address,
+    pub allocation_id: Address,
+    pub count: u64,
+    pub last_id: u64,
+}
+
+/// Checkpoint of the unaggregated fees of a (sender, allocation) pair.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AllocationCheckpoint {
+    pub sender: Address,
+    pub allocation_id: Address,
+    /// Signers of the sender the fees were computed for, as returned by
+    /// [signers_trimmed](crate::tap::signers_trimmed).
+    pub signers: Vec<String>,
+    pub unaggregated_fees: UnaggregatedReceipts,
+    pub rav_timestamp_ns: Option<u64>,
+    pub rav_request_in_flight: bool,
+}
+
+impl AllocationCheckpoint {
+    /// Checks that the fees of the checkpoint are still the ones of the receipts up to its
+    /// `last_id`. That's no longer the case if a RAV was created since, possibly by a request
+    /// interrupted by the shutdown, or if the signers of the sender changed.
+    pub fn validate(
+        &self,
+        signers: &[String],
+        rav_timestamp_ns: Option<u64>,
+        latest_receipt_id: u64,
+    ) -> Result<()> {
+        ensure!(
+            !self.rav_request_in_flight,
+            "A RAV request was interrupted before completing"
+        );
+        ensure!(
+            self.rav_timestamp_ns == rav_timestamp_ns,
+            "The last RAV changed since the checkpoint"
+        );
+        let mut checkpoint_signers = self.signers.clone();
+        checkpoint_signers.sort();
+        let mut signers = signers.to_vec();
+        signers.sort();
+        ensure!(
+            checkpoint_signers == signers,
+            "The signers of the sender changed since the checkpoint"
+        );
+        ensure!(
+            self.unaggregated_fees.last_id <= latest_receipt_id,
+            "The checkpoint is ahead of the receipts in the database"
+        );
+        Ok(())
+    }
+}
+
+pub async fn load(pgpool: &PgPool, shard: &str) -> Result<Option<u64>> {
+    let row = sqlx::query(
+        r#"
+            SELECT last_receipt_id, version FROM scalar_tap_agent_checkpoint
+            WHERE COALESCE(indexer_address, '')
+                = COALESCE(current_setting('indexer.address', true), '')
+                AND shard = $1
+        "#,
+    )
+    .bind(shard)
+    .fetch_optional(pgpool)
+    .await?;
+    let Some(row) = row else {
+        return Ok(None);
+    };
+
+    let version: i32 = row.try_get("version")?;
+    if version != CHECKPOINT_VERSION {
+        warn!(
+            version,
+            expected_version = CHECKPOINT_VERSION,
+            "Discarding checkpoint saved with another format version."
+        );
+        return Ok(None);
+    }
+    Ok(Some(row.try_get::<i64, _>("last_receipt_id")?.try_into()?))
+}
+
+pub async fn save(pgpool: &PgPool, shard: &str, last_receipt_id: u64) -> Result<()> {
+    sqlx::query(
+        r#"
+            INSERT INTO scalar_tap_agent_checkpoint (last_receipt_id, version, shard)
+            VALUES ($1, $2, $3)
+            ON CONFLICT ((COALESCE(indexer_address, '')), shard) DO UPDATE
+            SET last_receipt_id = EXCLUDED.last_receipt_id,
+                version = EXCLUDED.version,
+                updated_at = NOW()
+        "#,
+    )
+    .bind(i64::try_from(last_receipt_id)?)
+    .bind(CHECKPOINT_VERSION)
+    .bind(shard)
+    .execute(pgpool)
+    .await?;
+
+    Ok(())
+}
+
+/// Loads the checkpoint of a (sender, allocation) pair. Checkpoints saved with another format
+/// version are ignored.
+pub async fn load_allocation(
+    pgpool: &PgPool,
+    sender: Address,
+    allocation_id: Address,
+) -> Result<Option<AllocationCheckpoint>> {
+    let row = sqlx::query(
+        r#"
+            SELECT signers, last_receipt_id, unaggregated_value, rav_timestamp_ns,
+                rav_request_in_flight
+            FROM scalar_tap_agent_allocation_checkpoints
+            WHERE sender_address = $1 AND allocation_id = $2 AND version = $3
+        "#,
+    )
+    .bind(SenderAddress(sender))
+    .bind(AllocationIdHex(allocation_id))
+    .bind(CHECKPOINT_VERSION)
+    .fetch_optional(pgpool)
+    .await?;
+    let Some(row) = row else {
+        return Ok(None);
+    };
+
+    Ok(Some(AllocationCheckpoint {
+        sender,
+        allocation_id,
+        signers: row.try_get("signers")?,
+        unaggregated_fees: UnaggregatedReceipts {
+            last_id: row.try_get::<i64, _>("last_receipt_id")?.try_into()?,
+            value: row.try_get::<GrtWei, _>("unaggregated_value")?.0,
+        },
+        rav_timestamp_ns: row
+            .try_get::<Option<BigDecimal>, _>("rav_timestamp_ns")?
+            .map(|timestamp_ns| timestamp_ns.to_string().parse::<u64>())
+            .transpose()?,
+        rav_request_in_flight: row.try_get("rav_request_in_flight")?,
+    }))
+}
+
+pub async fn save_allocation(pgpool: &PgPool, checkpoint: &AllocationCheckpoint) -> Result<()> {
+    schema_drift::ensure_writable()?;
+    sqlx::query(
+        r#"
+            INSERT INTO scalar_tap_agent_allocation_checkpoints (
+                sender_address, allocation_id, version, signers, last_receipt_id,
+                unaggregated_value, rav_timestamp_ns, rav_request_in_flight
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            ON CONFLICT (sender_address, allocation_id) DO UPDATE
+            SET version = EXCLUDED.version,
+                signers = EXCLUDED.signers,
+                last_receipt_id = EXCLUDED.last_receipt_id,
+                unaggregated_value = EXCLUDED.unaggregated_value,
+                rav_timestamp_ns = EXCLUDED.rav_timestamp_ns,
+                rav_request_in_flight = EXCLUDED.rav_request_in_flight,
+                updated_at = NOW()
+        "#,
+    )
+    .bind(SenderAddress(checkpoint.sender))
+    .bind(AllocationIdHex(checkpoint.allocation_id))
+    .bind(CHECKPOINT_VERSION)
+    .bind(&checkpoint.signers)
+    .bind(i64::try_from(checkpoint.unaggregated_fees.last_id)?)
+    .bind(GrtWei(checkpoint.unaggregated_fees.value))
+    .bind(checkpoint.rav_timestamp_ns.map(BigDecimal::from))
+    .bind(checkpoint.rav_request_in_flight)
+    .execute(pgpool)
+    .await?;
+
+    Ok(())
+}
+
+/// Marks the start and the end of a RAV request of a (sender, allocation) pair. If the agent
+/// stops in between, the RAV may have been stored without the checkpoint being updated, so the
+/// marker makes the next start recompute the fees from the receipts.
+pub async fn set_rav_request_in_flight(
+    pgpool: &PgPool,
+    sender: Address,
+    allocation_id: Address,
+    in_flight: bool,
+) -> Result<()> {
+    sqlx::query(
+        r#"
+            UPDATE scalar_tap_agent_allocation_checkpoints
+            SET rav_request_in_flight = $3, updated_at = NOW()
+            WHERE sender_address = $1 AND allocation_id = $2
+        "#,
+    )
+    .bind(SenderAddress(sender))
+    .bind(AllocationIdHex(allocation_id))
+    .bind(in_flight)
+    .execute(pgpool)
+    .await?;
+
+    Ok(())
+}
+
+/// Removes the checkpoint of a (sender, allocation) pair, once its last RAV is requested.
+pub async fn remove_allocation(
+    pgpool: &PgPool,
+    sender: Address,
+    allocation_id: Address,
+) -> Result<()> {
+    sqlx::query(
+        r#"
+            DELETE FROM scalar_tap_agent_allocation_checkpoints
+            WHERE sender_address = $1 AND allocation_id = $2
+        "#,
+    )
+    .bind(SenderAddress(sender))
+    .bind(AllocationIdHex(allocation_id))
+    .execute(pgpool)
+    .await?;
+
+    Ok(())
+}
+
+/// Id of the most recent receipt in the database, 0 if there is none.
+pub async fn latest_receipt_id(pgpool: &PgPool) -> Result<u64> {
+    let latest: Option<i64> = sqlx::query_scalar(
+        r#"
+            SELECT MAX(id) FROM scalar_tap_receipts
+        "#,
+    )
+    .fetch_one(pgpool)
+    .await?;
+
+    Ok(latest.unwrap_or(0).try_into()?)
+}
+
+pub async fn receipts_since(
+    pgpool: &PgPool,
+    last_receipt_id: u64,
+) -> Result<Vec<ReceiptsSinceCheckpoint>> {
+    let rows = sqlx::query(
+        r#"
+            SELECT signer_address, allocation_id, COUNT(*) AS count, MAX(id) AS last_id
+            FROM scalar_tap_receipts
+            WHERE id > $1
+            GROUP BY signer_address, allocation_id
+        "#,
+    )
+    .bind(i64::try_from(last_receipt_id)?)
+    .fetch_all(pgpool)
+    .await?;
+
+    rows.iter()
+        .map(|row| -> Result<ReceiptsSinceCheckpoint> {
+            Ok(ReceiptsSinceCheckpoint {
+                signer_address: Address::from_str(row.try_get("signer_address")?)?,
+                allocation_id: row.try_get::<AllocationIdHex, _>("allocation_id")?.0,
+                count: row.try_get::<i64, _>("count")?.try_into()?,
+                last_id: row.try_get::<i64, _>("last_id")?.try_into()?,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy_primitives::hex::ToHex;
+    use sqlx::PgPool;
+
+    use super::*;
+    use crate::tap::test_utils::{
+        create_received_receipt, store_receipt, ALLOCATION_ID_0, SENDER, SIGNER,
+    };
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_receipts_since_checkpoint(pgpool: PgPool) {
+        assert_eq!(load(&pgpool, "").await.unwrap(), None);
+
+        for i in 1..=10 {
+            let receipt = create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, i, i, i.into());
+            store_receipt(&pgpool, receipt.signed_receipt())
+                .await
+                .unwrap();
+        }
+        save(&pgpool, "", 4).await.unwrap();
+        assert_eq!(load(&pgpool, "").await.unwrap(), Some(4));
+        assert_eq!(latest_receipt_id(&pgpool).await.unwrap(), 10);
+
+        assert_eq!(
+            receipts_since(&pgpool, 4).await.unwrap(),
+            vec![ReceiptsSinceCheckpoint {
+                signer_address: SIGNER.1,
+                allocation_id: *ALLOCATION_ID_0,
+                count: 6,
+                last_id: 10,
+            }]
+        );
+        assert!(receipts_since(&pgpool, 10).await.unwrap().is_empty());
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_checkpoint_version(pgpool: PgPool) {
+        save(&pgpool, "", 4).await.unwrap();
+        sqlx::query("UPDATE scalar_tap_agent_checkpoint SET version = 1")
+            .execute(&pgpool)
+            .await
+            .unwrap();
+        assert_eq!(load(&pgpool, "").await.unwrap(), None);
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_checkpoint_per_shard(pgpool: PgPool) {
+        save(&pgpool, "", 4).await.unwrap();
+        save(&pgpool, "0/2", 6).await.unwrap();
+        save(&pgpool, "1/2", 8).await.unwrap();
+        save(&pgpool, "0/2", 7).await.unwrap();
+        assert_eq!(load(&pgpool, "").await.unwrap(), Some(4));
+        assert_eq!(load(&pgpool, "0/2").await.unwrap(), Some(7));
+        assert_eq!(load(&pgpool, "1/2").await.unwrap(), Some(8));
+        assert_eq!(load(&pgpool, "0/4").await.unwrap(), None);
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_allocation_checkpoint(pgpool: PgPool) {
+        let signers = vec![SIGNER.1.encode_hex::<String>()];
+        let mut checkpoint = AllocationCheckpoint {
+            sender: SENDER.1,
+            allocation_id: *ALLOCATION_ID_0,
+            signers: signers.clone(),
+            unaggregated_fees: UnaggregatedReceipts {
+                value: u128::MAX,
+                last_id: 10,
+            },
+            rav_timestamp_ns: Some(5),
+            rav_request_in_flight: false,
+        };
+        assert_eq!(
+            load_allocation(&pgpool, SENDER.1, *ALLOCATION_ID_0)
+                .await
+                .unwrap(),
+            None
+        );
+        save_allocation(&pgpool, &checkpoint).await.unwrap();
+        let loaded = load_allocation(&pgpool, SENDER.1, *ALLOCATION_ID_0)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(loaded, checkpoint);
+        assert!(loaded.validate(&signers, Some(5), 10).is_ok());
+        assert!(loaded.validate(&signers, Some(6), 10).is_err());
+        assert!(loaded.validate(&signers, Some(5), 9).is_err());
+        assert!(loaded.validate(&[], Some(5), 10).is_err());
+
+        set_rav_request_in_flight(&pgpool, SENDER.1, *ALLOCATION_ID_0, true)
+            .await
+            .unwrap();
+        checkpoint.rav_request_in_flight = true;
+        let loaded = load_allocation(&pgpool, SENDER.1, *ALLOCATION_ID_0)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(loaded, checkpoint);
+        assert!(loaded.validate(&signers, Some(5), 10).is_err());
+
+        remove_allocation(&pgpool, SENDER.1, *ALLOCATION_ID_0)
+            .await
+            .unwrap();
+        assert_eq!(
+            load_allocation(&pgpool, SENDER.1, *ALLOCATION_ID_0)
+                .await
+                .unwrap(),
+            None
+        );
+    }
+}
@@ -0,0 +1,96 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Source of time of the sender accounts and allocations, for their retries, backoffs and idle
+//! checks. Production uses [`TokioClock`], which follows `tokio::time::pause` and `advance`.
+//! Tests that also talk to the database, and can't pause tokio's time without firing its
+//! timeouts, use a [`VirtualClock`] that only moves when told to.
+
+use std::{fmt::Debug, future::Future, pin::Pin, time::Duration};
+
+use tokio::time::Instant;
+
+pub type Sleep = Pin<Box<dyn Future<Output = ()> + Send + 'static>>;
+
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> Instant;
+    fn sleep(&self, duration: Duration) -> Sleep;
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TokioClock;
+
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> Sleep {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+#[cfg(test)]
+pub use virtual_clock::VirtualClock;
+
+#[cfg(test)]
+mod virtual_clock {
+    use tokio::sync::watch;
+
+    use super::*;
+
+    /// Clock whose time only moves with [`VirtualClock::advance`], waking up the sleeps that are
+    /// due.
+    #[derive(Debug)]
+    pub struct VirtualClock {
+        start: Instant,
+        elapsed: watch::Sender<Duration>,
+    }
+
+    impl VirtualClock {
+        pub fn new() -> Self {
+            Self {
+                start: Instant::now(),
+                elapsed: watch::channel(Duration::ZERO).0,
+            }
+        }
+
+        pub fn advance(&self, duration: Duration) {
+            self.elapsed.send_modify(|elapsed| *elapsed += duration);
+        }
+    }
+
+    impl Clock for VirtualClock {
+        fn now(&self) -> Instant {
+            self.start + *self.elapsed.borrow()
+        }
+
+        fn sleep(&self, duration: Duration) -> Sleep {
+            let mut elapsed = self.elapsed.subscribe();
+            let deadline = *elapsed.borrow() + duration;
+            Box::pin(async move {
+                // The clock being dropped wakes up all sleeps
+                let _ = elapsed.wait_for(|elapsed| *elapsed >= deadline).await;
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_virtual_clock() {
+        let clock = VirtualClock::new();
+        let start = clock.now();
+        let mut sleep = tokio::spawn(clock.sleep(Duration::from_secs(30)));
+
+        clock.advance(Duration::from_secs(29));
+        assert!(tokio::time::timeout(Duration::from_millis(10), &mut sleep)
+            .await
+            .is_err());
+
+        clock.advance(Duration::from_secs(1));
+        tokio::time::timeout(Duration::from_millis(10), sleep)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(clock.now() - start, Duration::from_secs(30));
+    }
+}
@@ -0,0 +1,261 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Estimation of the next RAV request of a sender, from the rate its allocations accumulated
+//! unaggregated fees at recently. The RAV request is triggered once the sender's total reaches
+//! the trigger value, for its heaviest allocation then, so the estimate tells operators when and
+//! for which allocation their trigger settings should fire.
+
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::RwLock;
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use serde::Serialize;
+use thegraph::types::Address;
+use tokio::time::Instant;
+
+use crate::agent::sender_fee_tracker::SenderFeeTracker;
+
+lazy_static! {
+    /// Next RAV request estimate of every sender, served by the tap-agent HTTP server.
+    pub static ref NEXT_RAV_ESTIMATES: RwLock<BTreeMap<Address, NextRavEstimate>> =
+        RwLock::new(BTreeMap::new());
+}
+
+/// Window the fee rates are measured over.
+const RATE_WINDOW: Duration = Duration::from_secs(300);
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NextRavEstimate {
+    /// Trigger value in use, in GRT wei.
+    pub trigger_value: String,
+    pub unaggregated_fees: String,
+    /// Fees left to accumulate before the RAV request is triggered.
+    pub value_until_trigger: String,
+    /// `None` if no fees were accumulated over the last 5 minutes.
+    pub seconds_until_trigger: Option<u64>,
+    pub allocations: BTreeMap<Address, AllocationEstimate>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AllocationEstimate {
+    pub unaggregated_fees: String,
+    /// Fees accumulated per second over the last 5 minutes, in GRT wei.
+    pub fee_rate: String,
+    /// Unaggregated fees expected once the RAV request is triggered.
+    pub fees_at_trigger: String,
+    /// Whether the allocation is expected to be the heaviest then, the one the RAV is requested
+    /// for.
+    pub next_rav_target: bool,
+}
+
+/// Fee increments of a single allocation over the rate window.
+#[derive(Debug)]
+struct AllocationFees {
+    observed_since: Instant,
+    last_value: u128,
+    increments: VecDeque<(Instant, u128)>,
+}
+
+#[derive(Debug, Default)]
+pub struct FeeRates {
+    allocations: HashMap<Address, AllocationFees>,
+}
+
+impl FeeRates {
+    /// Records the unaggregated fees of an allocation. A decrease is a RAV, not a negative rate.
+    pub fn record(&mut self, now: Instant, allocation_id: Address, value: u128) {
+        let fees = self
+            .allocations
+            .entry(allocation_id)
+            .or_insert_with(|| AllocationFees {
+                observed_since: now,
+                last_value: value,
+                increments: VecDeque::new(),
+            });
+        if value > fees.last_value {
+            fees.increments.push_back((now, value - fees.last_value));
+        }
+        fees.last_value = value;
+    }
+
+    /// Forgets the allocations no longer tracked.
+    pub fn retain(&mut self, mut keep: impl FnMut(&Address) -> bool) {
+        self.allocations
+            .retain(|allocation_id, _| keep(allocation_id));
+    }
+
+    /// Fees per second accumulated by the allocation over the rate window.
+    fn rate(&mut self, now: Instant, allocation_id: &Address) -> f64 {
+        let Some(fees) = self.allocations.get_mut(allocation_id) else {
+            return 0.0;
+        };
+        let window_start = now.checked_sub(RATE_WINDOW).unwrap_or(now);
+        while fees
+            .increments
+            .front()
+            .is_some_and(|(at, _)| *at < window_start)
+        {
+            fees.increments.pop_front();
+        }
+        let observed = (now - fees.observed_since.max(window_start)).as_secs_f64();
+        if observed <= 0.0 {
+            return 0.0;
+        }
+        fees.increments
+            .iter()
+            .map(|(_, increment)| *increment as f64)
+            .sum::<f64>()
+            / observed
+    }
+
+    pub fn estimate(
+        &mut self,
+        now: Instant,
+        fee_tracker: &SenderFeeTracker,
+        trigger_value: u128,
+    ) -> NextRavEstimate {
+        let total_fee = fee_tracker.get_total_fee();
+        let value_until_trigger = trigger_value.saturating_sub(total_fee);
+
+        let mut allocations: Vec<_> = fee_tracker
+            .get_list_of_allocation_ids()
+            .into_iter()
+            .chain(self.allocations.keys().copied())
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .map(|allocation_id| {
+                let rate = self.rate(now, &allocation_id);
+                (allocation_id, fee_tracker.get_fee(&allocation_id), rate)
+            })
+            .collect();
+
+        let total_rate: f64 = allocations.iter().map(|(_, _, rate)| rate).sum();
+        let seconds_until_trigger = if value_until_trigger == 0 {
+            Some(0.0)
+        } else if total_rate > 0.0 {
+            Some(value_until_trigger as f64 / total_rate)
+        } else {
+            None
+        };
+
+        allocations.sort_by_key(|(allocation_id, _, _)| *allocation_id);
+        let fees_at_trigger: Vec<u128> = allocations
+            .iter()
+            .map(|(_, fee, rate)| {
+                fee + seconds_until_trigger.map_or(0, |seconds| (rate * seconds) as u128)
+            })
+            .collect();
+        // Allocations marked for finalization request their last RAV on their own
+        let next_rav_target = allocations
+            .iter()
+            .zip(&fees_at_trigger)
+            .filter(|((allocation_id, _, _), fees)| {
+                **fees > 0 && !fee_tracker.is_blocked(allocation_id)
+            })
+            .max_by_key(|(_, fees)| **fees)
+            .map(|((allocation_id, _, _), _)| *allocation_id);
+
+        NextRavEstimate {
+            trigger_value: trigger_value.to_string(),
+            unaggregated_fees: total_fee.to_string(),
+            value_until_trigger: value_until_trigger.to_string(),
+            seconds_until_trigger: seconds_until_trigger.map(|seconds| seconds.ceil() as u64),
+            allocations: allocations
+                .into_iter()
+                .zip(fees_at_trigger)
+                .map(|((allocation_id, fee, rate), fees_at_trigger)| {
+                    (
+                        allocation_id,
+                        AllocationEstimate {
+                            unaggregated_fees: fee.to_string(),
+                            fee_rate: (rate as u128).to_string(),
+                            fees_at_trigger: fees_at_trigger.to_string(),
+                            next_rav_target: next_rav_target == Some(allocation_id),
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+}
+
+pub fn publish(sender: Address, estimate: NextRavEstimate) {
+    NEXT_RAV_ESTIMATES.write().unwrap().insert(sender, estimate);
+}
+
+/// Stops reporting a sender that is no longer tracked.
+pub fn unpublish(sender: Address) {
+    NEXT_RAV_ESTIMATES.write().unwrap().remove(&sender);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALLOCATION_0: Address = Address::new([0xaa; 20]);
+    const ALLOCATION_1: Address = Address::new([0xbb; 20]);
+
+    #[test]
+    fn test_estimate_next_rav() {
+        let start = Instant::now() + RATE_WINDOW;
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut rates = FeeRates::default();
+        let mut tracker = SenderFeeTracker::default();
+        let mut record = |secs, allocation_id, value| {
+            rates.record(at(secs), allocation_id, value);
+            tracker.update(allocation_id, value).unwrap();
+        };
+
+        record(0, ALLOCATION_0, 0);
+        record(0, ALLOCATION_1, 500);
+        record(50, ALLOCATION_0, 200);
+        record(100, ALLOCATION_0, 400);
+
+        let estimate = rates.estimate(at(100), &tracker, 1200);
+        assert_eq!(estimate.unaggregated_fees, "900");
+        assert_eq!(estimate.value_until_trigger, "300");
+        // 4 wei per second on allocation 0 only
+        assert_eq!(estimate.seconds_until_trigger, Some(75));
+        assert_eq!(
+            estimate.allocations[&ALLOCATION_0],
+            AllocationEstimate {
+                unaggregated_fees: "400".to_string(),
+                fee_rate: "4".to_string(),
+                fees_at_trigger: "700".to_string(),
+                next_rav_target: true,
+            }
+        );
+        assert!(!estimate.allocations[&ALLOCATION_1].next_rav_target);
+
+        // Fees older than the window no longer count
+        let estimate = rates.estimate(at(1000), &tracker, 1200);
+        assert_eq!(estimate.seconds_until_trigger, None);
+        assert!(estimate.allocations[&ALLOCATION_1].next_rav_target);
+    }
+
+    #[test]
+    fn test_rav_is_not_a_negative_rate() {
+        let start = Instant::now() + RATE_WINDOW;
+        let mut rates = FeeRates::default();
+        let mut tracker = SenderFeeTracker::default();
+
+        rates.record(start, ALLOCATION_0, 0);
+        rates.record(start + Duration::from_secs(10), ALLOCATION_0, 100);
+        rates.record(start + Duration::from_secs(20), ALLOCATION_0, 0);
+        tracker.update(ALLOCATION_0, 0).unwrap();
+
+        let estimate = rates.estimate(start + Duration::from_secs(20), &tracker, 1000);
+        assert_eq!(estimate.allocations[&ALLOCATION_0].fee_rate, "5");
+        assert_eq!(estimate.seconds_until_trigger, Some(200));
+
+        rates.retain(|_| false);
+        assert!(rates
+            .estimate(start + Duration::from_secs(20), &tracker, 1000)
+            .allocations
+            .is_empty());
+    }
+}
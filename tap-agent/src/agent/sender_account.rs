@@ -5,6 +5,7 @@ use bigdecimal::num_bigint::ToBigInt;
 use bigdecimal::ToPrimitive;
 use std::collections::{HashMap, HashSet};
 use std::str::FromStr;
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::task::JoinHandle;
 
@@ -13,19 +14,29 @@ use alloy_sol_types::Eip712Domain;
 use anyhow::Result;
 use ethereum_types::U256;
 use eventuals::{Eventual, EventualExt, PipeHandle};
+use indexer_common::events::{self, Event};
 use indexer_common::subgraph_client::Query;
 use indexer_common::{escrow_accounts::EscrowAccounts, prelude::SubgraphClient};
-use ractor::{call, Actor, ActorProcessingErr, ActorRef, MessagingErr, SupervisionEvent};
+use ractor::{call, call_t, Actor, ActorProcessingErr, ActorRef, MessagingErr, SupervisionEvent};
 use serde::Deserialize;
 use sqlx::PgPool;
 use tap_core::rav::SignedRAV;
 use thegraph::types::Address;
 use tracing::{error, Level};
 
-use super::sender_allocation::{SenderAllocation, SenderAllocationArgs};
+use super::sender_allocation::{
+    SenderAllocation, SenderAllocationArgs, IDLE_EVICTION_REASON, VANISHED_REASON,
+};
+use crate::actor_topology::SenderAccountSnapshot;
+use crate::agent::aggregator_health;
+use crate::agent::clock::Clock;
+use crate::agent::rav_schedule::{self, FeeRates};
 use crate::agent::sender_allocation::SenderAllocationMessage;
-use crate::agent::sender_fee_tracker::SenderFeeTracker;
+use crate::agent::sender_fee_tracker::{SenderFeeTracker, TotalFeeOverflow};
+use crate::agent::trigger_tuning::{self, TriggerTuner};
 use crate::agent::unaggregated_receipts::UnaggregatedReceipts;
+use crate::fee_overflows;
+use crate::vanished_allocations;
 use crate::{
     config::{self},
     tap::escrow_adapter::EscrowAdapter,
@@ -33,6 +44,10 @@ use crate::{
 type RavMap = HashMap<Address, u128>;
 type Balance = U256;
 
+/// How long to wait for a sender allocation to save its checkpoint. A sender allocation busy with
+/// a RAV request keeps its checkpoint marked as in-flight.
+const ALLOCATION_CHECKPOINT_TIMEOUT_MS: u64 = 5000;
+
 #[derive(Debug)]
 pub enum SenderAccountMessage {
     UpdateBalanceAndLastRavs(Balance, RavMap),
@@ -41,6 +56,11 @@ pub enum SenderAccountMessage {
     UpdateReceiptFees(Address, UnaggregatedReceipts),
     UpdateInvalidReceiptFees(Address, UnaggregatedReceipts),
     UpdateRav(SignedRAV),
+    /// Whether the unaggregated fees of an allocation overflowed, pausing the sender until the
+    /// operator acknowledges it.
+    UpdateFeeOverflow(Address, bool),
+    SaveCheckpoints(ractor::RpcReplyPort<()>),
+    GetSnapshot(ractor::RpcReplyPort<SenderAccountSnapshot>),
     #[cfg(test)]
     GetSenderFeeTracker(ractor::RpcReplyPort<SenderFeeTracker>),
     #[cfg(test)]
@@ -65,12 +85,14 @@ pub struct SenderAccountArgs {
     pub escrow_accounts: Eventual<EscrowAccounts>,
     pub indexer_allocations: Eventual<HashSet<Address>>,
     pub escrow_subgraph: &'static SubgraphClient,
+    pub network_subgraph: &'static SubgraphClient,
     pub domain_separator: Eip712Domain,
     pub sender_aggregator_endpoint: String,
     pub allocation_ids: HashSet<Address>,
     pub prefix: Option<String>,
 
     pub retry_interval: Duration,
+    pub clock: Arc<dyn Clock>,
 }
 pub struct State {
     prefix: Option<String>,
@@ -81,18 +103,32 @@ pub struct State {
     _indexer_allocations_handle: PipeHandle,
     _escrow_account_monitor: PipeHandle,
     scheduled_rav_request: Option<JoinHandle<Result<(), MessagingErr<SenderAccountMessage>>>>,
+    messages_handled: u64,
 
     sender: Address,
 
     // Deny reasons
     denied: bool,
+    /// Whether the unaggregated fees reached the hard cap, or overflowed, pausing the sender in
+    /// the service.
+    paused: bool,
+    /// Allocations whose unaggregated fees overflowed, see [crate::fee_overflows].
+    fee_overflows: HashSet<Address>,
     sender_balance: U256,
     retry_interval: Duration,
+    clock: Arc<dyn Clock>,
+
+    /// Starts as the configured trigger value, and follows the tuner's suggestions if they are
+    /// applied automatically.
+    rav_request_trigger_value: u128,
+    trigger_tuner: Option<TriggerTuner>,
+    fee_rates: FeeRates,
 
     //Eventuals
     escrow_accounts: Eventual<EscrowAccounts>,
 
     escrow_subgraph: &'static SubgraphClient,
+    network_subgraph: &'static SubgraphClient,
     escrow_adapter: EscrowAdapter,
     domain_separator: Eip712Domain,
     config: &'static config::Config,
@@ -105,7 +141,12 @@ impl State {
         &self,
         sender_account_ref: ActorRef<SenderAccountMessage>,
         allocation_id: Address,
-    ) -> Result<()> {
+    ) -> Result<ActorRef<SenderAllocationMessage>> {
+        if let Some(sender_allocation) = ActorRef::<SenderAllocationMessage>::where_is(
+            self.format_sender_allocation(&allocation_id),
+        ) {
+            return Ok(sender_allocation);
+        }
         tracing::trace!(
             %self.sender,
             %allocation_id,
@@ -118,20 +159,27 @@ impl State {
             sender: self.sender,
             escrow_accounts: self.escrow_accounts.clone(),
             escrow_subgraph: self.escrow_subgraph,
+            network_subgraph: self.network_subgraph,
             escrow_adapter: self.escrow_adapter.clone(),
             domain_separator: self.domain_separator.clone(),
             sender_aggregator_endpoint: self.sender_aggregator_endpoint.clone(),
             sender_account_ref: sender_account_ref.clone(),
+            clock: self.clock.clone(),
         };
 
-        SenderAllocation::spawn_linked(
+        let (sender_allocation, _) = SenderAllocation::spawn_linked(
             Some(self.format_sender_allocation(&allocation_id)),
             SenderAllocation,
             args,
             sender_account_ref.get_cell(),
         )
         .await?;
-        Ok(())
+        Ok(sender_allocation)
+    }
+
+    /// Whether sender allocations are spawned on demand and stopped when idle.
+    fn lazy_sender_allocations(&self) -> bool {
+        self.config.tap.sender_allocation_idle_timeout.is_some()
     }
     fn format_sender_allocation(&self, allocation_id: &Address) -> String {
         let mut sender_allocation_id = String::new();
@@ -143,7 +191,10 @@ impl State {
         sender_allocation_id
     }
 
-    async fn rav_requester_single(&mut self) -> Result<()> {
+    async fn rav_requester_single(
+        &mut self,
+        sender_account_ref: ActorRef<SenderAccountMessage>,
+    ) -> Result<()> {
         let Some(allocation_id) = self.sender_fee_tracker.get_heaviest_allocation_id() else {
             anyhow::bail!(
                 "Error while getting the heaviest allocation because \
@@ -151,7 +202,15 @@ impl State {
             );
         };
         let sender_allocation_id = self.format_sender_allocation(&allocation_id);
-        let allocation = ActorRef::<SenderAllocationMessage>::where_is(sender_allocation_id);
+        let allocation = match ActorRef::<SenderAllocationMessage>::where_is(sender_allocation_id) {
+            Some(allocation) => Some(allocation),
+            // The allocation may have been stopped for being idle while still holding fees.
+            None if self.lazy_sender_allocations() => self
+                .create_sender_allocation(sender_account_ref, allocation_id)
+                .await
+                .ok(),
+            None => None,
+        };
 
         let Some(allocation) = allocation else {
             anyhow::bail!(
@@ -164,16 +223,75 @@ impl State {
         };
 
         // update rav tracker
-        self.rav_tracker.update(
-            allocation_id,
-            rav.map_or(0, |rav| rav.message.valueAggregate),
+        check_total(
+            self.sender,
+            "pending_ravs",
+            self.rav_tracker.update(
+                allocation_id,
+                rav.map_or(0, |rav| rav.message.valueAggregate),
+            ),
         );
 
         // update sender fee tracker
-        self.sender_fee_tracker.update(allocation_id, fees.value);
+        check_total(
+            self.sender,
+            "unaggregated_fees",
+            self.sender_fee_tracker.update(allocation_id, fees.value),
+        );
+        self.fee_rates
+            .record(self.clock.now(), allocation_id, fees.value);
+        if let Some(tuner) = &mut self.trigger_tuner {
+            tuner.record_rav_request(self.clock.now());
+        }
         Ok(())
     }
 
+    /// Feeds the unaggregated fees to the trigger tuner, reporting its suggestion and applying it
+    /// if configured to.
+    fn tune_trigger_value(&mut self) {
+        let Some(tuner) = &mut self.trigger_tuner else {
+            return;
+        };
+        let now = self.clock.now();
+        tuner.record_fees(now, self.sender_fee_tracker.get_total_fee());
+        let suggestion = tuner.suggest(now, self.rav_request_trigger_value);
+
+        if let Some(suggestion) = suggestion.filter(|_| tuner.auto_apply()) {
+            let trigger_value = tuner.bounded(suggestion.trigger_value);
+            if trigger_value != self.rav_request_trigger_value {
+                tracing::info!(
+                    sender = %self.sender,
+                    old_trigger_value = self.rav_request_trigger_value,
+                    new_trigger_value = trigger_value,
+                    "Applying the suggested RAV request trigger value."
+                );
+                self.rav_request_trigger_value = trigger_value;
+                tuner.reset();
+            }
+            trigger_tuning::publish(self.sender, self.rav_request_trigger_value, None, true);
+            return;
+        }
+        trigger_tuning::publish(
+            self.sender,
+            self.rav_request_trigger_value,
+            suggestion,
+            tuner.auto_apply(),
+        );
+    }
+
+    /// Reports the estimated time and value left until the next RAV request.
+    fn estimate_next_rav(&mut self) {
+        let allocation_ids = &self.allocation_ids;
+        self.fee_rates
+            .retain(|allocation_id| allocation_ids.contains(allocation_id));
+        let estimate = self.fee_rates.estimate(
+            self.clock.now(),
+            &self.sender_fee_tracker,
+            self.rav_request_trigger_value,
+        );
+        rav_schedule::publish(self.sender, estimate);
+    }
+
     fn deny_condition_reached(&self) -> bool {
         let pending_ravs = self.rav_tracker.get_total_fee();
         let unaggregated_fees = self.sender_fee_tracker.get_total_fee();
@@ -193,6 +311,50 @@ impl State {
         total_fee_over_max_value || pending_fees_over_balance
     }
 
+    fn hard_cap_reached(&self) -> bool {
+        self.config
+            .tap
+            .max_unaggregated_fees_for(&self.sender)
+            .is_some_and(|cap| self.sender_fee_tracker.get_total_fee() >= cap)
+    }
+
+    /// Pauses the sender once its unaggregated fees reach the hard cap, or overflow for one of
+    /// its allocations, and resumes it once they're back under it and the overflows are
+    /// acknowledged, through the shared event log followed by the service.
+    async fn sync_pause(&mut self) {
+        let reached = self.hard_cap_reached() || !self.fee_overflows.is_empty();
+        if reached == self.paused {
+            return;
+        }
+        let unaggregated_fees = self.sender_fee_tracker.get_total_fee();
+        let reason = if !self.fee_overflows.is_empty() {
+            let mut allocation_ids: Vec<_> = self.fee_overflows.iter().collect();
+            allocation_ids.sort();
+            format!("unaggregated fees of the allocations {allocation_ids:?} overflowed")
+        } else if reached {
+            format!("unaggregated fees of {unaggregated_fees} GRT wei reached the hard cap")
+        } else {
+            format!("unaggregated fees of {unaggregated_fees} GRT wei are under the hard cap")
+        };
+        let event = Event::SenderPaused {
+            sender: self.sender,
+            paused: reached,
+            reason: Some(reason.clone()),
+        };
+        match events::publish(&self.pgpool, "tap-agent", &event).await {
+            Ok(_) => {
+                if reached {
+                    tracing::warn!(sender = %self.sender, reason, "Pausing sender.");
+                } else {
+                    tracing::info!(sender = %self.sender, reason, "Resuming sender.");
+                }
+                self.paused = reached;
+            }
+            // Published again on the next update of the fees
+            Err(e) => error!(sender = %self.sender, "Failed to publish the sender pause: {}", e),
+        }
+    }
+
     /// Will update [`State::denied`], as well as the denylist table in the database.
     async fn add_to_denylist(&mut self) {
         tracing::warn!(
@@ -239,6 +401,19 @@ impl State {
     }
 }
 
+/// Logs and counts an overflow of the `total` fees of `sender`, refused by its tracker.
+fn check_total(sender: Address, total: &str, result: Result<(), TotalFeeOverflow>) {
+    if let Err(error) = result {
+        fee_overflows::count_total_overflow(sender, total);
+        error!(
+            %sender,
+            total,
+            %error,
+            "Overflow of the fee total of the sender, the allocation keeps its previous fee."
+        );
+    }
+}
+
 #[async_trait::async_trait]
 impl Actor for SenderAccount {
     type Msg = SenderAccountMessage;
@@ -255,11 +430,13 @@ impl Actor for SenderAccount {
             escrow_accounts,
             indexer_allocations,
             escrow_subgraph,
+            network_subgraph,
             domain_separator,
             sender_aggregator_endpoint,
             allocation_ids,
             prefix,
             retry_interval,
+            clock,
         }: Self::Arguments,
     ) -> std::result::Result<Self::State, ActorProcessingErr> {
         let myself_clone = myself.clone();
@@ -404,6 +581,8 @@ impl Actor for SenderAccount {
         .denied
         .expect("Deny status cannot be null");
 
+        let paused = events::paused_senders(&pgpool).await?.contains(&sender_id);
+
         let sender_balance = escrow_accounts
             .value()
             .await
@@ -421,6 +600,7 @@ impl Actor for SenderAccount {
             prefix,
             escrow_accounts,
             escrow_subgraph,
+            network_subgraph,
             escrow_adapter,
             domain_separator,
             sender_aggregator_endpoint,
@@ -428,9 +608,20 @@ impl Actor for SenderAccount {
             pgpool,
             sender: sender_id,
             denied,
+            paused,
+            fee_overflows: HashSet::new(),
             sender_balance,
             retry_interval,
+            clock,
+            rav_request_trigger_value: config.tap.rav_request_trigger_value,
+            trigger_tuner: config
+                .tap
+                .trigger_value_tuning
+                .clone()
+                .map(TriggerTuner::new),
+            fee_rates: FeeRates::default(),
             scheduled_rav_request: None,
+            messages_handled: 0,
         };
 
         for allocation_id in &allocation_ids {
@@ -444,6 +635,18 @@ impl Actor for SenderAccount {
         Ok(state)
     }
 
+    async fn post_stop(
+        &self,
+        _myself: ActorRef<Self::Msg>,
+        state: &mut Self::State,
+    ) -> std::result::Result<(), ActorProcessingErr> {
+        if state.trigger_tuner.is_some() {
+            trigger_tuning::unpublish(state.sender);
+        }
+        rav_schedule::unpublish(state.sender);
+        Ok(())
+    }
+
     async fn handle(
         &self,
         myself: ActorRef<Self::Msg>,
@@ -459,25 +662,36 @@ impl Actor for SenderAccount {
             message = ?message,
             "New SenderAccount message"
         );
+        state.messages_handled += 1;
         match message {
             SenderAccountMessage::UpdateRav(rav) => {
-                state
-                    .rav_tracker
-                    .update(rav.message.allocationId, rav.message.valueAggregate);
+                check_total(
+                    state.sender,
+                    "pending_ravs",
+                    state
+                        .rav_tracker
+                        .update(rav.message.allocationId, rav.message.valueAggregate),
+                );
                 let should_deny = !state.denied && state.deny_condition_reached();
                 if should_deny {
                     state.add_to_denylist().await;
                 }
             }
             SenderAccountMessage::UpdateInvalidReceiptFees(allocation_id, unaggregated_fees) => {
-                state
-                    .invalid_receipts_tracker
-                    .update(allocation_id, unaggregated_fees.value);
+                check_total(
+                    state.sender,
+                    "invalid_receipts",
+                    state
+                        .invalid_receipts_tracker
+                        .update(allocation_id, unaggregated_fees.value),
+                );
 
-                // invalid receipts can't go down
+                // invalid receipts only go down when promoted by the operator
                 let should_deny = !state.denied && state.deny_condition_reached();
                 if should_deny {
                     state.add_to_denylist().await;
+                } else if state.denied && !state.deny_condition_reached() {
+                    state.remove_from_denylist().await;
                 }
             }
             SenderAccountMessage::UpdateReceiptFees(allocation_id, unaggregated_fees) => {
@@ -486,9 +700,16 @@ impl Actor for SenderAccount {
                     scheduled_rav_request.abort();
                 }
 
+                check_total(
+                    state.sender,
+                    "unaggregated_fees",
+                    state
+                        .sender_fee_tracker
+                        .update(allocation_id, unaggregated_fees.value),
+                );
                 state
-                    .sender_fee_tracker
-                    .update(allocation_id, unaggregated_fees.value);
+                    .fee_rates
+                    .record(state.clock.now(), allocation_id, unaggregated_fees.value);
 
                 // Eagerly deny the sender (if needed), before the RAV request. To be sure not to
                 // delay the denial because of the RAV request, which could take some time.
@@ -498,67 +719,129 @@ impl Actor for SenderAccount {
                     state.add_to_denylist().await;
                 }
 
-                if state.sender_fee_tracker.get_total_fee()
-                    >= state.config.tap.rav_request_trigger_value
+                // Past the hard cap, the sender is paused until a RAV is requested right away
+                state.sync_pause().await;
+                if state.paused
+                    || state.sender_fee_tracker.get_total_fee() >= state.rav_request_trigger_value
                 {
-                    tracing::debug!(
-                        total_fee = state.sender_fee_tracker.get_total_fee(),
-                        trigger_value = state.config.tap.rav_request_trigger_value,
-                        "Total fee greater than the trigger value. Triggering RAV request"
-                    );
-                    // In case we fail, we want our actor to keep running
-                    if let Err(err) = state.rav_requester_single().await {
-                        tracing::error!(
-                            error = %err,
-                            "There was an error while requesting a RAV."
+                    if !aggregator_health::is_healthy(&state.sender) {
+                        tracing::debug!(
+                            total_fee = state.sender_fee_tracker.get_total_fee(),
+                            "Sender's TAP aggregator is unhealthy. Postponing the RAV request"
+                        );
+                    } else {
+                        tracing::debug!(
+                            total_fee = state.sender_fee_tracker.get_total_fee(),
+                            trigger_value = state.rav_request_trigger_value,
+                            "Total fee greater than the trigger value. Triggering RAV request"
                         );
+                        // In case we fail, we want our actor to keep running
+                        if let Err(err) = state.rav_requester_single(myself.clone()).await {
+                            tracing::error!(
+                                error = %err,
+                                "There was an error while requesting a RAV."
+                            );
+                        }
                     }
                 }
+                state.tune_trigger_value();
+                state.estimate_next_rav();
 
-                match (state.denied, state.deny_condition_reached()) {
-                    // Allow the sender right after the potential RAV request. This way, the
-                    // sender can be allowed again as soon as possible if the RAV was successful.
-                    (true, false) => state.remove_from_denylist().await,
-                    // if couldn't remove from denylist, resend the message in 30 seconds
-                    // this may trigger another rav request
-                    (true, true) => {
-                        // retry in a moment
-                        state.scheduled_rav_request =
-                            Some(myself.send_after(state.retry_interval, move || {
-                                SenderAccountMessage::UpdateReceiptFees(
-                                    allocation_id,
-                                    unaggregated_fees,
-                                )
-                            }));
-                    }
-                    _ => {}
+                // Allow the sender right after the potential RAV request. This way, the
+                // sender can be allowed again as soon as possible if the RAV was successful.
+                if state.denied && !state.deny_condition_reached() {
+                    state.remove_from_denylist().await;
+                }
+                state.sync_pause().await;
+                // if couldn't remove from denylist, or resume the sender, resend the message in
+                // 30 seconds. This may trigger another rav request. A paused sender has no new
+                // receipt to trigger it
+                if state.denied || state.paused {
+                    // retry in a moment
+                    let retry = state.clock.sleep(state.retry_interval);
+                    let myself = myself.clone();
+                    state.scheduled_rav_request = Some(tokio::spawn(async move {
+                        retry.await;
+                        myself.cast(SenderAccountMessage::UpdateReceiptFees(
+                            allocation_id,
+                            unaggregated_fees,
+                        ))
+                    }));
                 }
             }
             SenderAccountMessage::UpdateAllocationIds(allocation_ids) => {
-                // Create new sender allocations
+                // A vanished allocation showing up again can be aggregated again
                 for allocation_id in allocation_ids.difference(&state.allocation_ids) {
-                    if let Err(error) = state
-                        .create_sender_allocation(myself.clone(), *allocation_id)
-                        .await
-                    {
-                        error!(
-                            %error,
-                            %allocation_id,
-                            "There was an error while creating Sender Allocation."
-                        );
+                    state
+                        .sender_fee_tracker
+                        .unblock_allocation_id(*allocation_id);
+                }
+                // Create new sender allocations. When they are lazy, they are only created once
+                // their first receipt comes in.
+                if !state.lazy_sender_allocations() {
+                    for allocation_id in allocation_ids.difference(&state.allocation_ids) {
+                        if let Err(error) = state
+                            .create_sender_allocation(myself.clone(), *allocation_id)
+                            .await
+                        {
+                            error!(
+                                %error,
+                                %allocation_id,
+                                "There was an error while creating Sender Allocation."
+                            );
+                        }
                     }
                 }
 
                 // Remove sender allocations
                 for allocation_id in state.allocation_ids.difference(&allocation_ids) {
-                    if let Some(sender_handle) = ActorRef::<SenderAllocationMessage>::where_is(
+                    let mut sender_handle = ActorRef::<SenderAllocationMessage>::where_is(
                         state.format_sender_allocation(allocation_id),
-                    ) {
+                    );
+                    // An idle sender allocation that still has fees or a RAV to finalize needs
+                    // to be spawned again, so it can request the last RAV when stopped.
+                    if sender_handle.is_none()
+                        && state.lazy_sender_allocations()
+                        && (state
+                            .sender_fee_tracker
+                            .get_list_of_allocation_ids()
+                            .contains(allocation_id)
+                            || state
+                                .rav_tracker
+                                .get_list_of_allocation_ids()
+                                .contains(allocation_id))
+                    {
+                        sender_handle = state
+                            .create_sender_allocation(myself.clone(), *allocation_id)
+                            .await
+                            .inspect_err(|error| {
+                                error!(
+                                    %error,
+                                    %allocation_id,
+                                    "There was an error while creating Sender Allocation to close it."
+                                );
+                            })
+                            .ok();
+                    }
+                    if let Some(sender_handle) = sender_handle {
                         tracing::trace!(%allocation_id, "SenderAccount shutting down SenderAllocation");
                         // we can not send a rav request to this allocation
                         // because it's gonna trigger the last rav
                         state.sender_fee_tracker.block_allocation_id(*allocation_id);
-                        sender_handle.stop(None);
+                        if vanished_allocations::is_vanished(allocation_id) {
+                            // Closed conservatively, without marking the last RAV
+                            if let Err(error) =
+                                sender_handle.cast(SenderAllocationMessage::Vanished)
+                            {
+                                error!(
+                                    %error,
+                                    %allocation_id,
+                                    "There was an error while closing a vanished Sender Allocation."
+                                );
+                            }
+                        } else {
+                            sender_handle.stop(None);
+                        }
                     }
                 }
 
@@ -599,12 +882,23 @@ impl Actor for SenderAccount {
                 for allocation_id in tracked_allocation_ids.difference(&active_allocation_ids) {
                     // if it's being tracked and we didn't receive any update from the non_final_last_ravs
                     // remove from the tracker
-                    state.rav_tracker.update(*allocation_id, 0);
+                    check_total(
+                        state.sender,
+                        "pending_ravs",
+                        state.rav_tracker.update(*allocation_id, 0),
+                    );
                 }
 
                 for (allocation_id, value) in non_final_last_ravs {
-                    state.rav_tracker.update(allocation_id, value);
+                    check_total(
+                        state.sender,
+                        "pending_ravs",
+                        state.rav_tracker.update(allocation_id, value),
+                    );
                 }
+                // Balance updates are periodic, catching fees that stopped changing
+                state.tune_trigger_value();
+                state.estimate_next_rav();
                 // now that balance and rav tracker is updated, check
                 match (state.denied, state.deny_condition_reached()) {
                     (true, false) => state.remove_from_denylist().await,
@@ -612,6 +906,67 @@ impl Actor for SenderAccount {
                     (_, _) => {}
                 }
             }
+            SenderAccountMessage::UpdateFeeOverflow(allocation_id, overflowed) => {
+                if overflowed {
+                    state.fee_overflows.insert(allocation_id);
+                } else {
+                    state.fee_overflows.remove(&allocation_id);
+                }
+                state.sync_pause().await;
+            }
+            SenderAccountMessage::SaveCheckpoints(reply) => {
+                // Idle sender allocations already saved their checkpoint when they were stopped.
+                for allocation_id in &state.allocation_ids {
+                    let Some(sender_allocation) = ActorRef::<SenderAllocationMessage>::where_is(
+                        state.format_sender_allocation(allocation_id),
+                    ) else {
+                        continue;
+                    };
+                    if let Err(error) = call_t!(
+                        sender_allocation,
+                        SenderAllocationMessage::SaveCheckpoint,
+                        ALLOCATION_CHECKPOINT_TIMEOUT_MS
+                    ) {
+                        tracing::warn!(
+                            %error,
+                            %allocation_id,
+                            "Could not save the checkpoint of the Sender Allocation."
+                        );
+                    }
+                }
+                if !reply.is_closed() {
+                    let _ = reply.send(());
+                }
+            }
+            SenderAccountMessage::GetSnapshot(reply) => {
+                let mut allocation_ids: Vec<_> = state.allocation_ids.iter().collect();
+                allocation_ids.sort();
+                let snapshot = SenderAccountSnapshot {
+                    sender: state.sender,
+                    denied: state.denied,
+                    paused: state.paused,
+                    balance: state.sender_balance.to_string(),
+                    unaggregated_fees: state.sender_fee_tracker.get_total_fee().to_string(),
+                    pending_ravs: state.rav_tracker.get_total_fee().to_string(),
+                    invalid_receipts_fees: state
+                        .invalid_receipts_tracker
+                        .get_total_fee()
+                        .to_string(),
+                    rav_request_trigger_value: state.rav_request_trigger_value.to_string(),
+                    rav_request_retry_scheduled: state
+                        .scheduled_rav_request
+                        .as_ref()
+                        .is_some_and(|retry| !retry.is_finished()),
+                    messages_handled: state.messages_handled,
+                    sender_allocations: allocation_ids
+                        .into_iter()
+                        .map(|allocation_id| state.format_sender_allocation(allocation_id))
+                        .collect(),
+                };
+                if !reply.is_closed() {
+                    let _ = reply.send(snapshot);
+                }
+            }
             #[cfg(test)]
             SenderAccountMessage::GetSenderFeeTracker(reply) => {
                 if !reply.is_closed() {
@@ -643,6 +998,20 @@ impl Actor for SenderAccount {
         );
 
         match message {
+            SupervisionEvent::ActorTerminated(cell, _, reason)
+                if reason.as_deref() == Some(IDLE_EVICTION_REASON) =>
+            {
+                // Fees are kept in the trackers, the sender allocation is spawned again on its
+                // next receipt or RAV request.
+                tracing::debug!(sender_allocation = ?cell.get_name(), "SenderAllocation was idle and stopped");
+            }
+            SupervisionEvent::ActorTerminated(cell, _, reason)
+                if reason.as_deref() == Some(VANISHED_REASON) =>
+            {
+                // The fees left unaggregated are still owed by the sender, they stay counted,
+                // and the allocation blocked until it shows up again.
+                tracing::warn!(sender_allocation = ?cell.get_name(), "SenderAllocation of a vanished allocation was closed");
+            }
             SupervisionEvent::ActorTerminated(cell, _, _) => {
                 // what to do in case of termination or panic?
                 let sender_allocation = cell.get_name();
@@ -661,8 +1030,11 @@ impl Actor for SenderAccount {
                     return Ok(());
                 };
 
-                let tracker = &mut state.sender_fee_tracker;
-                tracker.update(allocation_id, 0);
+                check_total(
+                    state.sender,
+                    "unaggregated_fees",
+                    state.sender_fee_tracker.update(allocation_id, 0),
+                );
                 // clean up hashset
                 state
                     .sender_fee_tracker
@@ -709,6 +1081,7 @@ impl Actor for SenderAccount {
 #[cfg(test)]
 pub mod tests {
     use super::{SenderAccount, SenderAccountArgs, SenderAccountMessage};
+    use crate::agent::clock::{Clock, TokioClock, VirtualClock};
     use crate::agent::sender_accounts_manager::NewReceiptNotification;
     use crate::agent::sender_allocation::SenderAllocationMessage;
     use crate::agent::unaggregated_receipts::UnaggregatedReceipts;
@@ -748,6 +1121,9 @@ pub mod tests {
                     Self::UpdateInvalidReceiptFees(r0, r1),
                 ) => l0 == r0 && l1 == r1,
                 (Self::NewAllocationId(l0), Self::NewAllocationId(r0)) => l0 == r0,
+                (Self::UpdateFeeOverflow(l0, l1), Self::UpdateFeeOverflow(r0, r1)) => {
+                    l0 == r0 && l1 == r1
+                }
                 (a, b) => unimplemented!("PartialEq not implementated for {a:?} and {b:?}"),
             }
         }
@@ -769,11 +1145,36 @@ pub mod tests {
         tokio::task::JoinHandle<()>,
         String,
         EventualWriter<EscrowAccounts>,
+    ) {
+        create_sender_account_with_clock(
+            pgpool,
+            initial_allocation,
+            rav_request_trigger_value,
+            max_unnaggregated_fees_per_sender,
+            escrow_subgraph_endpoint,
+            Arc::new(TokioClock),
+        )
+        .await
+    }
+
+    async fn create_sender_account_with_clock(
+        pgpool: PgPool,
+        initial_allocation: HashSet<Address>,
+        rav_request_trigger_value: u128,
+        max_unnaggregated_fees_per_sender: u128,
+        escrow_subgraph_endpoint: &str,
+        clock: Arc<dyn Clock>,
+    ) -> (
+        ActorRef<SenderAccountMessage>,
+        tokio::task::JoinHandle<()>,
+        String,
+        EventualWriter<EscrowAccounts>,
     ) {
         let config = Box::leak(Box::new(config::Config {
             config: None,
             ethereum: config::Ethereum {
                 indexer_address: INDEXER.1,
+                ..Default::default()
             },
             tap: config::Tap {
                 rav_request_trigger_value,
@@ -785,7 +1186,7 @@ pub mod tests {
             ..Default::default()
         }));
 
-        let escrow_subgraph = Box::leak(Box::new(SubgraphClient::new(
+        let escrow_subgraph: &'static SubgraphClient = Box::leak(Box::new(SubgraphClient::new(
             reqwest::Client::new(),
             None,
             DeploymentDetails::for_query_url(escrow_subgraph_endpoint).unwrap(),
@@ -809,11 +1210,14 @@ pub mod tests {
             escrow_accounts: escrow_accounts_eventual,
             indexer_allocations: Eventual::from_value(initial_allocation),
             escrow_subgraph,
+            // Not queried, the POI gating is disabled
+            network_subgraph: escrow_subgraph,
             domain_separator: TAP_EIP712_DOMAIN_SEPARATOR.clone(),
             sender_aggregator_endpoint: DUMMY_URL.to_string(),
             allocation_ids: HashSet::new(),
             prefix: Some(prefix.clone()),
             retry_interval: Duration::from_millis(10),
+            clock,
         };
 
         let (sender, handle) = SenderAccount::spawn(Some(prefix.clone()), SenderAccount, args)
@@ -1159,17 +1563,42 @@ pub mod tests {
         assert!(deny);
     }
 
+    /// Waits for the sender account to have triggered `expected` RAV requests and to be done
+    /// handling the message that triggered the last one, so that its retry is scheduled before
+    /// the clock moves. Only yields, the time of the test is entirely driven by its clock.
+    async fn wait_for_rav_requests(
+        sender_account: &ActorRef<SenderAccountMessage>,
+        triggered_rav_request: &AtomicU32,
+        expected: u32,
+    ) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while triggered_rav_request.load(std::sync::atomic::Ordering::SeqCst) < expected {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("RAV request not triggered");
+        let snapshot = call!(sender_account, SenderAccountMessage::GetSnapshot).unwrap();
+        assert!(snapshot.rav_request_retry_scheduled);
+        assert_eq!(
+            triggered_rav_request.load(std::sync::atomic::Ordering::SeqCst),
+            expected
+        );
+    }
+
     #[sqlx::test(migrations = "../migrations")]
     async fn test_retry_unaggregated_fees(pgpool: PgPool) {
         // we set to zero to block the sender, no matter the fee
         let max_unaggregated_fees_per_sender: u128 = 0;
 
-        let (sender_account, handle, prefix, _) = create_sender_account(
+        let clock = Arc::new(VirtualClock::new());
+        let (sender_account, handle, prefix, _) = create_sender_account_with_clock(
             pgpool,
             HashSet::new(),
             TRIGGER_VALUE,
             max_unaggregated_fees_per_sender,
             DUMMY_URL,
+            clock.clone(),
         )
         .await;
 
@@ -1189,15 +1618,22 @@ pub mod tests {
                 },
             ))
             .unwrap();
-        tokio::time::sleep(Duration::from_millis(30)).await;
+        wait_for_rav_requests(&sender_account, &triggered_rav_request, 1).await;
 
-        let retry_value = triggered_rav_request.load(std::sync::atomic::Ordering::SeqCst);
-        assert!(retry_value > 1, "It didn't retry more than once");
-
-        tokio::time::sleep(Duration::from_millis(30)).await;
-
-        let new_value = triggered_rav_request.load(std::sync::atomic::Ordering::SeqCst);
-        assert!(new_value > retry_value, "It didn't retry anymore");
+        // no retry before the retry interval elapsed
+        clock.advance(Duration::from_millis(9));
+        for _ in 0..100 {
+            tokio::task::yield_now().await;
+        }
+        wait_for_rav_requests(&sender_account, &triggered_rav_request, 1).await;
+
+        // one retry per retry interval
+        clock.advance(Duration::from_millis(1));
+        wait_for_rav_requests(&sender_account, &triggered_rav_request, 2).await;
+        for expected in 3..=4 {
+            clock.advance(Duration::from_millis(10));
+            wait_for_rav_requests(&sender_account, &triggered_rav_request, expected).await;
+        }
 
         allocation.stop_and_wait(None, None).await.unwrap();
         allocation_handle.await.unwrap();
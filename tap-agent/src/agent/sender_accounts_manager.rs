@@ -2,23 +2,35 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use std::collections::HashSet;
-use std::time::Duration;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::{collections::HashMap, str::FromStr};
 
+use crate::actor_topology::ManagerSnapshot;
+use crate::agent::checkpoint::{self, ReceiptsSinceCheckpoint};
+use crate::agent::clock::Clock;
 use crate::agent::sender_allocation::SenderAllocationMessage;
 use crate::lazy_static;
+use crate::vanished_allocations::VanishedAllocationTracker;
 use alloy_sol_types::Eip712Domain;
 use anyhow::Result;
 use anyhow::{anyhow, bail};
-use eventuals::{Eventual, EventualExt, PipeHandle};
+use eventuals::{join, timer, Eventual, EventualExt, PipeHandle};
+use indexer_common::allocations::rollover::{allocation_rollovers, replaced_at};
+use indexer_common::allocations::virtual_allocations::VirtualAllocation;
 use indexer_common::escrow_accounts::EscrowAccounts;
+use indexer_common::notifications;
 use indexer_common::prelude::{Allocation, SubgraphClient};
-use ractor::{Actor, ActorCell, ActorProcessingErr, ActorRef, SupervisionEvent};
-use serde::Deserialize;
+use indexer_common::receipt_notifications::{self, ReceiptNotification};
+use ractor::{
+    call, Actor, ActorCell, ActorProcessingErr, ActorRef, RpcReplyPort, SupervisionEvent,
+};
 use sqlx::{postgres::PgListener, PgPool};
 use thegraph::types::Address;
 use tokio::select;
-use tracing::{error, warn};
+use tokio::sync::mpsc;
+use tracing::{debug, error, info, warn};
 
 use prometheus::{register_counter_vec, CounterVec};
 
@@ -34,20 +46,19 @@ lazy_static! {
     .unwrap();
 }
 
-#[derive(Deserialize, Debug)]
-pub struct NewReceiptNotification {
-    pub id: u64,
-    pub allocation_id: Address,
-    pub signer_address: Address,
-    pub timestamp_ns: u64,
-    pub value: u128,
-}
+/// How often the id of the last processed receipt is saved to the database.
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(30);
+const ROLLOVER_OVERLAP_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+pub type NewReceiptNotification = ReceiptNotification;
 
 pub struct SenderAccountsManager;
 
 #[derive(Debug)]
 pub enum SenderAccountsManagerMessage {
     UpdateSenderAccounts(HashSet<Address>),
+    SaveCheckpoint(RpcReplyPort<()>),
+    GetSnapshot(RpcReplyPort<ManagerSnapshot>),
 }
 
 pub struct SenderAccountsManagerArgs {
@@ -56,17 +67,24 @@ pub struct SenderAccountsManagerArgs {
 
     pub pgpool: PgPool,
     pub indexer_allocations: Eventual<HashMap<Address, Allocation>>,
+    /// Tracked along with the indexer allocations.
+    pub virtual_allocations: Eventual<HashMap<Address, VirtualAllocation>>,
     pub escrow_accounts: Eventual<EscrowAccounts>,
     pub escrow_subgraph: &'static SubgraphClient,
+    pub network_subgraph: &'static SubgraphClient,
     pub sender_aggregator_endpoints: HashMap<Address, String>,
 
     pub prefix: Option<String>,
+    pub clock: Arc<dyn Clock>,
 }
 
 pub struct State {
     sender_ids: HashSet<Address>,
     new_receipts_watcher_handle: Option<tokio::task::JoinHandle<()>>,
+    checkpoint_saver_handle: Option<tokio::task::JoinHandle<()>>,
+    last_receipt_id: Arc<AtomicU64>,
     _eligible_allocations_senders_pipe: PipeHandle,
+    messages_handled: u64,
 
     config: &'static config::Config,
     domain_separator: Eip712Domain,
@@ -74,8 +92,46 @@ pub struct State {
     indexer_allocations: Eventual<HashSet<Address>>,
     escrow_accounts: Eventual<EscrowAccounts>,
     escrow_subgraph: &'static SubgraphClient,
+    network_subgraph: &'static SubgraphClient,
     sender_aggregator_endpoints: HashMap<Address, String>,
     prefix: Option<String>,
+    clock: Arc<dyn Clock>,
+}
+
+/// The allocations the sender accounts track at `now`, in seconds since the UNIX epoch. An
+/// allocation replaced by a new one on the same deployment is left out, and its last RAV
+/// requested, once the service no longer accepts its receipts: after both the recently closed
+/// allocations buffer and the rollover overlap are over.
+fn tracked_allocation_ids(
+    allocations: &HashMap<Address, Allocation>,
+    recently_closed_buffer: Duration,
+    rollover_overlap: Option<Duration>,
+    now: u64,
+) -> HashSet<Address> {
+    let mut allocation_ids: HashSet<_> = allocations.keys().cloned().collect();
+    for rollover in allocation_rollovers(allocations) {
+        let Some(previous) = allocations.get(&rollover.previous) else {
+            continue;
+        };
+        let buffer_end = previous
+            .closed_at
+            .map(|closed_at| closed_at + recently_closed_buffer.as_secs());
+        let overlap_end = replaced_at(allocations, previous)
+            .map(|replaced_at| replaced_at + rollover_overlap.unwrap_or_default().as_secs());
+        // Left to the allocation monitor while its closing time isn't known
+        match buffer_end.max(overlap_end) {
+            Some(finalized_at) if now > finalized_at => {}
+            _ => continue,
+        }
+        debug!(
+            deployment = %rollover.deployment,
+            previous_allocation = %rollover.previous,
+            next_allocation = %rollover.next,
+            "Allocation replaced, finalizing it"
+        );
+        allocation_ids.remove(&rollover.previous);
+    }
+    allocation_ids
 }
 
 #[async_trait::async_trait]
@@ -91,15 +147,55 @@ impl Actor for SenderAccountsManager {
             config,
             domain_separator,
             indexer_allocations,
+            virtual_allocations,
             pgpool,
             escrow_accounts,
             escrow_subgraph,
+            network_subgraph,
             sender_aggregator_endpoints,
             prefix,
+            clock,
         }: Self::Arguments,
     ) -> std::result::Result<Self::State, ActorProcessingErr> {
-        let indexer_allocations = indexer_allocations.map(|allocations| async move {
-            allocations.keys().cloned().collect::<HashSet<Address>>()
+        let recently_closed_buffer = Duration::from_secs(
+            config
+                .network_subgraph
+                .recently_closed_allocation_buffer_seconds,
+        );
+        let rollover_overlap = config
+            .network_subgraph
+            .rollover_overlap_seconds
+            .map(Duration::from_secs);
+        let vanished_allocations = config
+            .tap
+            .vanished_allocation_grace_period
+            .map(|grace_period| Arc::new(Mutex::new(VanishedAllocationTracker::new(grace_period))));
+        // Re-evaluated periodically, for the rollover overlaps to end without allocation changes
+        let indexer_allocations = join((
+            indexer_allocations,
+            virtual_allocations,
+            timer(ROLLOVER_OVERLAP_CHECK_INTERVAL),
+        ))
+        .map(move |(allocations, virtual_allocations, _)| {
+            let vanished_allocations = vanished_allocations.clone();
+            async move {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .expect("Time went backwards")
+                    .as_secs();
+                let mut allocation_ids = tracked_allocation_ids(
+                    &allocations,
+                    recently_closed_buffer,
+                    rollover_overlap,
+                    now,
+                );
+                // Missing allocations stay tracked for a grace period, in case they show up again
+                if let Some(tracker) = vanished_allocations {
+                    allocation_ids.extend(tracker.lock().unwrap().update(&allocations, now));
+                }
+                allocation_ids.extend(virtual_allocations.keys());
+                allocation_ids
+            }
         });
         let mut pglistener = PgListener::connect_with(&pgpool.clone()).await.unwrap();
         pglistener
@@ -109,16 +205,28 @@ impl Actor for SenderAccountsManager {
                 "should be able to subscribe to Postgres Notify events on the channel \
                 'scalar_tap_receipt_notification'",
             );
+        // The indexer-service notifies the receipts through the socket while it's connected,
+        // and with PG NOTIFY otherwise
+        let direct_notifications = config
+            .postgres
+            .receipt_notification_socket
+            .as_deref()
+            .map(receipt_notifications::listen)
+            .transpose()?;
         let clone = myself.clone();
         let _eligible_allocations_senders_pipe =
             escrow_accounts.clone().pipe_async(move |escrow_accounts| {
                 let myself = clone.clone();
 
                 async move {
+                    // The senders of the other shards are managed by other instances
+                    let senders = escrow_accounts
+                        .get_senders()
+                        .into_iter()
+                        .filter(|sender| config.tap.owns_sender(sender))
+                        .collect();
                     myself
-                        .cast(SenderAccountsManagerMessage::UpdateSenderAccounts(
-                            escrow_accounts.get_senders(),
-                        ))
+                        .cast(SenderAccountsManagerMessage::UpdateSenderAccounts(senders))
                         .unwrap_or_else(|e| {
                             error!("Error while updating sender_accounts: {:?}", e);
                         });
@@ -130,13 +238,18 @@ impl Actor for SenderAccountsManager {
             domain_separator,
             sender_ids: HashSet::new(),
             new_receipts_watcher_handle: None,
+            checkpoint_saver_handle: None,
+            last_receipt_id: Arc::new(AtomicU64::new(0)),
             _eligible_allocations_senders_pipe,
+            messages_handled: 0,
             pgpool,
             indexer_allocations,
             escrow_accounts: escrow_accounts.clone(),
             escrow_subgraph,
+            network_subgraph,
             sender_aggregator_endpoints,
             prefix: prefix.clone(),
+            clock,
         };
         let sender_allocation = select! {
             sender_allocation = state.get_pending_sender_allocation_id() => sender_allocation,
@@ -152,12 +265,28 @@ impl Actor for SenderAccountsManager {
                 .await?;
         }
 
+        // Account for the receipts stored while the agent was down, before processing the new
+        // ones
+        let last_receipt_id = state.catch_up_from_checkpoint().await?;
+        state
+            .last_receipt_id
+            .store(last_receipt_id, Ordering::Relaxed);
+        state.checkpoint_saver_handle = Some(tokio::spawn(checkpoint_saver(
+            state.pgpool.clone(),
+            config.tap.shard_key(),
+            state.last_receipt_id.clone(),
+        )));
+
         // Start the new_receipts_watcher task that will consume from the `pglistener`
         // after starting all senders
         state.new_receipts_watcher_handle = Some(tokio::spawn(new_receipts_watcher(
             pglistener,
+            direct_notifications,
             escrow_accounts,
             prefix,
+            state.last_receipt_id.clone(),
+            config.postgres.tenant,
+            config.tap.sharding,
         )));
 
         tracing::info!("SenderAccountManager created!");
@@ -173,6 +302,9 @@ impl Actor for SenderAccountsManager {
         if let Some(handle) = &state.new_receipts_watcher_handle {
             handle.abort();
         }
+        if let Some(handle) = &state.checkpoint_saver_handle {
+            handle.abort();
+        }
         Ok(())
     }
 
@@ -187,6 +319,7 @@ impl Actor for SenderAccountsManager {
             "New SenderAccountManager message"
         );
 
+        state.messages_handled += 1;
         match msg {
             SenderAccountsManagerMessage::UpdateSenderAccounts(target_senders) => {
                 // Create new sender accounts
@@ -214,6 +347,50 @@ impl Actor for SenderAccountsManager {
 
                 state.sender_ids = target_senders;
             }
+            SenderAccountsManagerMessage::SaveCheckpoint(reply) => {
+                let last_receipt_id = state.last_receipt_id.load(Ordering::Relaxed);
+                if let Err(e) = checkpoint::save(
+                    &state.pgpool,
+                    &state.config.tap.shard_key(),
+                    last_receipt_id,
+                )
+                .await
+                {
+                    error!(error = %e, "There was an error while saving the checkpoint.");
+                }
+                for sender in &state.sender_ids {
+                    let Some(sender_account) = ActorRef::<SenderAccountMessage>::where_is(
+                        state.format_sender_account(sender),
+                    ) else {
+                        continue;
+                    };
+                    if let Err(e) = call!(sender_account, SenderAccountMessage::SaveCheckpoints) {
+                        error!(
+                            sender_address = %sender,
+                            error = %e,
+                            "There was an error while saving the checkpoints of the sender account."
+                        );
+                    }
+                }
+                if !reply.is_closed() {
+                    let _ = reply.send(());
+                }
+            }
+            SenderAccountsManagerMessage::GetSnapshot(reply) => {
+                let mut sender_ids: Vec<_> = state.sender_ids.iter().collect();
+                sender_ids.sort();
+                let snapshot = ManagerSnapshot {
+                    last_receipt_id: state.last_receipt_id.load(Ordering::Relaxed),
+                    messages_handled: state.messages_handled,
+                    sender_accounts: sender_ids
+                        .into_iter()
+                        .map(|sender| state.format_sender_account(sender))
+                        .collect(),
+                };
+                if !reply.is_closed() {
+                    let _ = reply.send(snapshot);
+                }
+            }
         }
         Ok(())
     }
@@ -308,6 +485,76 @@ impl State {
         Ok(())
     }
 
+    /// Makes sure the receipts stored since the last checkpoint, while the agent was not
+    /// running, get picked up by their sender allocation. Returns the id of the latest receipt
+    /// in the database.
+    async fn catch_up_from_checkpoint(&self) -> anyhow::Result<u64> {
+        let latest_receipt_id = checkpoint::latest_receipt_id(&self.pgpool).await?;
+        let Some(last_receipt_id) =
+            checkpoint::load(&self.pgpool, &self.config.tap.shard_key()).await?
+        else {
+            // First start, the sender allocations load all the pending receipts anyway.
+            return Ok(latest_receipt_id);
+        };
+
+        let escrow_accounts = self
+            .escrow_accounts
+            .value()
+            .await
+            .map_err(|e| anyhow!("Error while getting escrow accounts: {:?}", e))?;
+        let receipts_since_checkpoint =
+            checkpoint::receipts_since(&self.pgpool, last_receipt_id).await?;
+        let receipts_count: u64 = receipts_since_checkpoint
+            .iter()
+            .map(|receipts| receipts.count)
+            .sum();
+
+        for ReceiptsSinceCheckpoint {
+            signer_address,
+            allocation_id,
+            ..
+        } in receipts_since_checkpoint
+        {
+            let Ok(sender_id) = escrow_accounts.get_sender_for_signer(&signer_address) else {
+                warn!(
+                    %signer_address,
+                    %allocation_id,
+                    "No sender found for receipts stored since the last checkpoint."
+                );
+                continue;
+            };
+            if !self.config.tap.owns_sender(&sender_id) {
+                continue;
+            }
+            let Some(sender_account) =
+                ActorRef::<SenderAccountMessage>::where_is(self.format_sender_account(&sender_id))
+            else {
+                warn!(
+                    sender_address = %sender_id,
+                    %allocation_id,
+                    "No sender_account found for receipts stored since the last checkpoint."
+                );
+                continue;
+            };
+            // The sender allocation loads its unaggregated fees from the database when created,
+            // and the sender account triggers a RAV request if needed.
+            sender_account
+                .cast(SenderAccountMessage::NewAllocationId(allocation_id))
+                .unwrap_or_else(|e| {
+                    error!(
+                        "Error while sending new allocation id message to sender_account: {:?}",
+                        e
+                    );
+                });
+        }
+
+        info!(
+            receipts = receipts_count,
+            last_receipt_id, "Caught up on receipts stored since the last checkpoint."
+        );
+        Ok(latest_receipt_id)
+    }
+
     async fn get_pending_sender_allocation_id(&self) -> HashMap<Address, HashSet<Address>> {
         let escrow_accounts_snapshot = self
             .escrow_accounts
@@ -410,6 +657,8 @@ impl State {
                 .or_default()
                 .extend(allocation_ids);
         }
+        // The senders of the other shards are managed by other instances
+        unfinalized_sender_allocations_map.retain(|sender, _| self.config.tap.owns_sender(sender));
         unfinalized_sender_allocations_map
     }
     fn new_sender_account_args(
@@ -424,6 +673,7 @@ impl State {
             escrow_accounts: self.escrow_accounts.clone(),
             indexer_allocations: self.indexer_allocations.clone(),
             escrow_subgraph: self.escrow_subgraph,
+            network_subgraph: self.network_subgraph,
             domain_separator: self.domain_separator.clone(),
             sender_aggregator_endpoint: self
                 .sender_aggregator_endpoints
@@ -438,32 +688,87 @@ impl State {
             allocation_ids,
             prefix: self.prefix.clone(),
             retry_interval: Duration::from_secs(30),
+            clock: self.clock.clone(),
         })
     }
 }
 
-/// Continuously listens for new receipt notifications from Postgres and forwards them to the
-/// corresponding SenderAccount.
+/// Periodically saves the id of the last processed receipt, so that a restart only needs to
+/// catch up from there.
+async fn checkpoint_saver(pgpool: PgPool, shard: String, last_receipt_id: Arc<AtomicU64>) {
+    let mut interval = tokio::time::interval(CHECKPOINT_INTERVAL);
+    let mut saved_receipt_id = None;
+    loop {
+        interval.tick().await;
+        let receipt_id = last_receipt_id.load(Ordering::Relaxed);
+        if saved_receipt_id == Some(receipt_id) {
+            continue;
+        }
+        match checkpoint::save(&pgpool, &shard, receipt_id).await {
+            Ok(()) => saved_receipt_id = Some(receipt_id),
+            Err(e) => error!(error = %e, "There was an error while saving the checkpoint."),
+        }
+    }
+}
+
+/// Continuously listens for new receipt notifications from Postgres, and from the
+/// indexer-service through `direct_notifications` when set, and forwards them to the
+/// corresponding SenderAccount. With a `tenant`, the receipts of the other indexers sharing the
+/// database are ignored, and with `sharding`, the receipts of the senders of the other shards.
 async fn new_receipts_watcher(
     mut pglistener: PgListener,
+    mut direct_notifications: Option<mpsc::Receiver<String>>,
     escrow_accounts: Eventual<EscrowAccounts>,
     prefix: Option<String>,
+    last_receipt_id: Arc<AtomicU64>,
+    tenant: Option<Address>,
+    sharding: Option<config::Sharding>,
 ) {
     loop {
-        // TODO: recover from errors or shutdown the whole program?
-        let pg_notification = pglistener.recv().await.expect(
-            "should be able to receive Postgres Notify events on the channel \
-                'scalar_tap_receipt_notification'",
-        );
-        let new_receipt_notification: NewReceiptNotification =
-            serde_json::from_str(pg_notification.payload()).expect(
-                "should be able to deserialize the Postgres Notify event payload as a \
-                        NewReceiptNotification",
-            );
+        let new_receipt_notification: NewReceiptNotification = select! {
+            pg_notification = pglistener.recv() => {
+                // TODO: recover from errors or shutdown the whole program?
+                let pg_notification = pg_notification.expect(
+                    "should be able to receive Postgres Notify events on the channel \
+                        'scalar_tap_receipt_notification'",
+                );
+                // The receipt is accounted for once the allocation reloads its receipts
+                match notifications::parse(pg_notification.payload()) {
+                    Ok(new_receipt_notification) => new_receipt_notification,
+                    Err(e) => {
+                        warn!(
+                            payload = pg_notification.payload(),
+                            "Ignoring a receipt notification: {}", e
+                        );
+                        continue;
+                    }
+                }
+            }
+            Some(payload) = recv_direct_notification(&mut direct_notifications) => {
+                match notifications::parse(&payload) {
+                    Ok(new_receipt_notification) => new_receipt_notification,
+                    Err(e) => {
+                        warn!(
+                            %payload,
+                            "Ignoring a direct receipt notification: {}", e
+                        );
+                        continue;
+                    }
+                }
+            }
+        };
+        if tenant.is_some()
+            && new_receipt_notification.indexer_address.is_some()
+            && new_receipt_notification.indexer_address != tenant
+        {
+            continue;
+        }
+        last_receipt_id.fetch_max(new_receipt_notification.id, Ordering::Relaxed);
         if let Err(e) = handle_notification(
             new_receipt_notification,
             &escrow_accounts,
             prefix.as_deref(),
+            sharding.as_ref(),
         )
         .await
         {
@@ -472,10 +777,21 @@ async fn new_receipts_watcher(
     }
 }
 
+/// Never ready without a socket, leaving the receipts to PG NOTIFY.
+async fn recv_direct_notification(
+    direct_notifications: &mut Option<mpsc::Receiver<String>>,
+) -> Option<String> {
+    match direct_notifications {
+        Some(direct_notifications) => direct_notifications.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
 async fn handle_notification(
     new_receipt_notification: NewReceiptNotification,
     escrow_accounts: &Eventual<EscrowAccounts>,
     prefix: Option<&str>,
+    sharding: Option<&config::Sharding>,
 ) -> Result<()> {
     tracing::debug!(
         notification = ?new_receipt_notification,
@@ -496,6 +812,10 @@ async fn handle_notification(
         );
     };
 
+    if sharding.is_some_and(|sharding| !sharding.owns(&sender_address)) {
+        return Ok(());
+    }
+
     let allocation_id = &new_receipt_notification.allocation_id;
     let allocation_str = &allocation_id.to_string();
 
@@ -557,9 +877,10 @@ async fn handle_notification(
 #[cfg(test)]
 mod tests {
     use super::{
-        new_receipts_watcher, SenderAccountsManager, SenderAccountsManagerArgs,
-        SenderAccountsManagerMessage, State,
+        new_receipts_watcher, tracked_allocation_ids, SenderAccountsManager,
+        SenderAccountsManagerArgs, SenderAccountsManagerMessage, State,
     };
+    use crate::agent::clock::TokioClock;
     use crate::agent::sender_account::tests::{MockSenderAllocation, PREFIX_ID};
     use crate::agent::sender_account::SenderAccountMessage;
     use crate::agent::sender_accounts_manager::{handle_notification, NewReceiptNotification};
@@ -579,6 +900,7 @@ mod tests {
     use sqlx::postgres::PgListener;
     use sqlx::PgPool;
     use std::collections::{HashMap, HashSet};
+    use std::sync::atomic::AtomicU64;
     use std::sync::{Arc, Mutex};
     use std::time::Duration;
 
@@ -597,6 +919,7 @@ mod tests {
             config: None,
             ethereum: config::Ethereum {
                 indexer_address: INDEXER.1,
+                ..Default::default()
             },
             tap: config::Tap {
                 rav_request_trigger_value: 100,
@@ -633,13 +956,16 @@ mod tests {
             domain_separator: TAP_EIP712_DOMAIN_SEPARATOR.clone(),
             pgpool,
             indexer_allocations: indexer_allocations_eventual,
+            virtual_allocations: Eventual::from_value(HashMap::new()),
             escrow_accounts: escrow_accounts_eventual,
             escrow_subgraph,
+            network_subgraph: escrow_subgraph,
             sender_aggregator_endpoints: HashMap::from([
                 (SENDER.1, String::from("http://localhost:8000")),
                 (SENDER_2.1, String::from("http://localhost:8000")),
             ]),
             prefix: Some(prefix.clone()),
+            clock: Arc::new(TokioClock),
         };
         (
             prefix,
@@ -672,17 +998,22 @@ mod tests {
                 domain_separator: TAP_EIP712_DOMAIN_SEPARATOR.clone(),
                 sender_ids: HashSet::new(),
                 new_receipts_watcher_handle: None,
+                checkpoint_saver_handle: None,
+                last_receipt_id: Arc::new(AtomicU64::new(0)),
                 _eligible_allocations_senders_pipe: Eventual::from_value(())
                     .pipe_async(|_| async {}),
+                messages_handled: 0,
                 pgpool,
                 indexer_allocations: Eventual::from_value(HashSet::new()),
                 escrow_accounts: Eventual::from_value(escrow_accounts),
                 escrow_subgraph: get_subgraph_client(),
+                network_subgraph: get_subgraph_client(),
                 sender_aggregator_endpoints: HashMap::from([
                     (SENDER.1, String::from("http://localhost:8000")),
                     (SENDER_2.1, String::from("http://localhost:8000")),
                 ]),
                 prefix: Some(prefix),
+                clock: Arc::new(TokioClock),
             },
         )
     }
@@ -821,10 +1152,15 @@ mod tests {
         ));
 
         // Start the new_receipts_watcher task that will consume from the `pglistener`
+        let last_receipt_id = Arc::new(AtomicU64::new(0));
         let new_receipts_watcher_handle = tokio::spawn(new_receipts_watcher(
             pglistener,
+            None,
             escrow_accounts_eventual,
             Some(prefix.clone()),
+            last_receipt_id.clone(),
+            None,
+            None,
         ));
 
         // add receipts to the database
@@ -843,10 +1179,59 @@ mod tests {
         for (i, receipt) in receipts.iter().enumerate() {
             assert_eq!((i + 1) as u64, receipt.id);
         }
+        assert_eq!(
+            last_receipt_id.load(std::sync::atomic::Ordering::Relaxed),
+            10
+        );
 
         new_receipts_watcher_handle.abort();
     }
 
+    #[test]
+    fn test_tracked_allocation_ids() {
+        let mut previous = indexer_common::test_vectors::INDEXER_ALLOCATIONS
+            .values()
+            .next()
+            .unwrap()
+            .clone();
+        let mut next = previous.clone();
+        next.id = Address::from([0x22; 20]);
+        next.created_at_epoch = previous.created_at_epoch + 1;
+        next.created_at = Some(10_100);
+        next.closed_at_epoch = None;
+        next.closed_at = None;
+        previous.closed_at_epoch = Some(next.created_at_epoch);
+        previous.closed_at = Some(10_000);
+        let allocations = HashMap::from([(previous.id, previous.clone()), (next.id, next.clone())]);
+
+        // Finalized once the recently closed allocations buffer is over
+        let buffer = Duration::from_secs(3600);
+        assert_eq!(
+            tracked_allocation_ids(&allocations, buffer, None, 10_100),
+            HashSet::from([previous.id, next.id])
+        );
+        assert_eq!(
+            tracked_allocation_ids(&allocations, buffer, None, 13_600),
+            HashSet::from([previous.id, next.id])
+        );
+        assert_eq!(
+            tracked_allocation_ids(&allocations, buffer, None, 13_601),
+            HashSet::from([next.id])
+        );
+
+        // Or once the overlap is, if it ends later
+        let buffer = Duration::from_secs(60);
+        let overlap = Some(Duration::from_secs(300));
+        assert_eq!(
+            tracked_allocation_ids(&allocations, buffer, overlap, 10_400),
+            HashSet::from([previous.id, next.id])
+        );
+        assert_eq!(
+            tracked_allocation_ids(&allocations, buffer, overlap, 10_401),
+            HashSet::from([next.id])
+        );
+    }
+
     #[tokio::test]
     async fn test_create_allocation_id() {
         let senders_to_signers = vec![(SENDER.1, vec![SIGNER.1])].into_iter().collect();
@@ -876,11 +1261,17 @@ mod tests {
             signer_address: SIGNER.1,
             timestamp_ns: 1,
             value: 1,
+            indexer_address: None,
         };
 
-        handle_notification(new_receipt_notification, &escrow_accounts, Some(&prefix))
-            .await
-            .unwrap();
+        handle_notification(
+            new_receipt_notification.clone(),
+            &escrow_accounts,
+            Some(&prefix),
+            None,
+        )
+        .await
+        .unwrap();
 
         tokio::time::sleep(Duration::from_millis(10)).await;
 
@@ -888,6 +1279,25 @@ mod tests {
             last_message_emitted.lock().unwrap().last().unwrap(),
             &SenderAccountMessage::NewAllocationId(*ALLOCATION_ID_0)
         );
+
+        // The receipts of the senders of other shards are left to their instance
+        let other_shard = config::Sharding {
+            count: 2,
+            index: 1 - crate::sharding::shard_of(&SENDER.1, 2),
+            lease_duration: Duration::from_secs(30),
+        };
+        handle_notification(
+            new_receipt_notification,
+            &escrow_accounts,
+            Some(&prefix),
+            Some(&other_shard),
+        )
+        .await
+        .unwrap();
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        assert_eq!(last_message_emitted.lock().unwrap().len(), 1);
         sender_account.stop_and_wait(None, None).await.unwrap();
         join_handle.await.unwrap();
     }
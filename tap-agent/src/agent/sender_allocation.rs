@@ -1,25 +1,30 @@
 // Copyright 2023-, GraphOps and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::{
-    sync::Arc,
-    time::{Duration, Instant},
-};
+use std::{sync::Arc, time::Duration};
 
 use alloy_primitives::hex::ToHex;
 use alloy_sol_types::Eip712Domain;
-use anyhow::{anyhow, ensure, Result};
+use anyhow::{anyhow, bail, ensure, Result};
 use bigdecimal::num_bigint::BigInt;
 use eventuals::Eventual;
-use indexer_common::{escrow_accounts::EscrowAccounts, prelude::SubgraphClient};
-use jsonrpsee::{core::client::ClientT, http_client::HttpClientBuilder, rpc_params};
+use indexer_common::{
+    escrow_accounts::EscrowAccounts,
+    http_proxy,
+    prelude::SubgraphClient,
+    types::{AllocationIdHex, GrtWei, SenderAddress},
+};
+use jsonrpsee::{
+    http_client::transport,
+    rpc_params,
+    types::error::{OVERSIZED_REQUEST_CODE, OVERSIZED_RESPONSE_CODE},
+};
 use prometheus::{
     register_counter, register_counter_vec, register_gauge_vec, register_histogram_vec, Counter,
     CounterVec, GaugeVec, HistogramVec,
 };
-use ractor::{Actor, ActorProcessingErr, ActorRef, RpcReplyPort};
-use sqlx::{types::BigDecimal, PgPool};
-use tap_aggregator::jsonrpsee_helpers::JsonRpcResponse;
+use ractor::{concurrency::JoinHandle, Actor, ActorProcessingErr, ActorRef, RpcReplyPort};
+use sqlx::{types::BigDecimal, PgPool, Row};
 use tap_core::{
     manager::adapters::RAVRead,
     rav::{RAVRequest, ReceiptAggregateVoucher, SignedRAV},
@@ -30,13 +35,26 @@ use tap_core::{
     signed_message::EIP712SignedMessage,
 };
 use thegraph::types::Address;
+use tokio::time::Instant;
 use tracing::{error, warn};
 
 use crate::lazy_static;
+use indexer_common::{db_consistency, db_metrics, schema_drift, secrets};
 
+use crate::actor_topology::SenderAllocationSnapshot;
+use crate::agent::aggregator_health;
+use crate::agent::aggregator_response::{self, AggregatorAnomaly};
+use crate::agent::checkpoint::{self, AllocationCheckpoint};
+use crate::agent::clock::Clock;
 use crate::agent::sender_account::SenderAccountMessage;
 use crate::agent::sender_accounts_manager::NewReceiptNotification;
 use crate::agent::unaggregated_receipts::UnaggregatedReceipts;
+use crate::aggregator_client::AggregatorClient;
+use crate::allocation_closures::{self, ClosureProgress, ClosureState};
+use crate::fee_overflows;
+use crate::poi_gate;
+use crate::trace_bundles::{self, Anomaly, TraceKind};
+use crate::vanished_allocations;
 use crate::{
     config::{self},
     tap::context::{checks::Signature, TapAgentContext},
@@ -53,6 +71,15 @@ lazy_static! {
     .unwrap();
 }
 
+lazy_static! {
+    static ref UNAGGREGATED_FEES_DRIFT: GaugeVec = register_gauge_vec!(
+        format!("tap_unaggregated_fees_drift"),
+        "Receipts value in the database minus the unaggregated fees tracked, at the last reconciliation",
+        &["sender", "allocation"]
+    )
+    .unwrap();
+}
+
 lazy_static! {
     static ref RAV_VALUE: GaugeVec = register_gauge_vec!(
         format!("rav_value"),
@@ -88,6 +115,43 @@ lazy_static! {
     .unwrap();
 }
 
+lazy_static! {
+    static ref CHECKPOINT_WRITES_FAILED: CounterVec = register_counter_vec!(
+        format!("sender_allocation_checkpoint_writes_failed"),
+        "Checkpoint writes of a sender allocation that failed, without failing the RAV request",
+        &["sender", "allocation"]
+    )
+    .unwrap();
+}
+
+lazy_static! {
+    static ref RAVS_EXCEEDING_LOCAL_VALUE: CounterVec = register_counter_vec!(
+        format!("ravs_exceeding_local_value"),
+        "RAVs rejected because their value exceeds the value recomputed from the local receipts",
+        &["sender", "allocation"]
+    )
+    .unwrap();
+}
+
+lazy_static! {
+    static ref RAV_REQUEST_SIZE_ERRORS: CounterVec = register_counter_vec!(
+        format!("rav_request_size_errors"),
+        "RAV requests or responses exceeding a size limit, retried with fewer receipts",
+        &["sender", "allocation"]
+    )
+    .unwrap();
+}
+
+lazy_static! {
+    static ref AGGREGATOR_FAILOVERS: CounterVec = register_counter_vec!(
+        format!("tap_aggregator_failovers"),
+        "RAV requests sent to the next endpoint of the sender's aggregator, the previous one \
+        being unreachable",
+        &["sender", "endpoint"]
+    )
+    .unwrap();
+}
+
 lazy_static! {
     static ref RAV_RESPONSE_TIME: HistogramVec = register_histogram_vec!(
         format!("rav_response_time"),
@@ -99,6 +163,52 @@ lazy_static! {
 
 type TapManager = tap_core::manager::Manager<TapAgentContext>;
 
+/// A RAV request or its response exceeded a size limit, of tap-agent or of the aggregator. Also
+/// raised when the request of a chunk of the last RAV times out, as the aggregator is given
+/// fewer receipts to get through in time.
+#[derive(Debug, thiserror::Error)]
+#[error("RAV request of {receipts} receipts was too large: {source}")]
+struct RavSizeError {
+    receipts: usize,
+    source: jsonrpsee::core::Error,
+}
+
+fn is_size_error(error: &jsonrpsee::core::Error) -> bool {
+    match error {
+        // Over the limits of the client, or rejected by the aggregator's HTTP server
+        jsonrpsee::core::Error::Transport(e) => matches!(
+            e.downcast_ref::<transport::Error>(),
+            Some(transport::Error::RequestTooLarge)
+                | Some(transport::Error::RequestFailure { status_code: 413 })
+        ),
+        // Over the limits of the aggregator's JSON-RPC server
+        jsonrpsee::core::Error::Call(e) => {
+            matches!(e.code(), OVERSIZED_REQUEST_CODE | OVERSIZED_RESPONSE_CODE)
+        }
+        _ => false,
+    }
+}
+
+/// Whether the aggregator endpoint couldn't be reached, the RAV request being sent to the next
+/// endpoint of the sender's aggregator. The aggregator rejecting the request isn't, any other
+/// endpoint would reject it as well.
+fn is_unreachable_error(error: &jsonrpsee::core::Error) -> bool {
+    match error {
+        jsonrpsee::core::Error::Transport(_) => !is_size_error(error),
+        jsonrpsee::core::Error::RequestTimeout => true,
+        _ => false,
+    }
+}
+
+/// Stop reason used when a [SenderAllocation] is stopped because it was idle, as opposed to its
+/// allocation being closed. The [SenderAccount](super::sender_account::SenderAccount) keeps
+/// tracking its fees and spawns it again when needed.
+pub const IDLE_EVICTION_REASON: &str = "idle";
+
+/// Stop reason used when a [SenderAllocation] is stopped because its allocation vanished from
+/// the allocation source, see [vanished_allocations]. Its remaining fees stay unaggregated.
+pub const VANISHED_REASON: &str = "vanished";
+
 /// Manages unaggregated fees and the TAP lifecyle for a specific (allocation, sender) pair.
 pub struct SenderAllocation;
 
@@ -111,10 +221,32 @@ pub struct SenderAllocationState {
     allocation_id: Address,
     sender: Address,
     sender_aggregator_endpoint: String,
+    /// Sends the RAV requests through the configured proxies.
+    aggregator_http_client: reqwest::Client,
     config: &'static config::Config,
     escrow_accounts: Eventual<EscrowAccounts>,
+    network_subgraph: &'static SubgraphClient,
     domain_separator: Eip712Domain,
     sender_account_ref: ActorRef<SenderAccountMessage>,
+    clock: Arc<dyn Clock>,
+    /// Receipts per RAV request, lowered from the configured limit when the requests or
+    /// responses get too large for the aggregator.
+    rav_request_receipt_limit: u64,
+    /// RAV requests that failed in a row, after their retries.
+    rav_request_failures: u32,
+    /// Whether the allocation is closed and its last RAV is being aggregated, in chunks of
+    /// `final_rav_chunk_receipts`.
+    closing: bool,
+    /// Whether the unaggregated fees overflowed, see [fee_overflows].
+    fee_overflow: bool,
+    messages_handled: u64,
+
+    last_activity: Instant,
+    evicted: bool,
+    /// Whether the allocation vanished, its last RAV being flagged for review instead of marked.
+    vanished: bool,
+    idle_check_handle: Option<JoinHandle<()>>,
+    reconciliation_handle: Option<JoinHandle<()>>,
 }
 
 pub struct SenderAllocationArgs {
@@ -124,16 +256,31 @@ pub struct SenderAllocationArgs {
     pub sender: Address,
     pub escrow_accounts: Eventual<EscrowAccounts>,
     pub escrow_subgraph: &'static SubgraphClient,
+    pub network_subgraph: &'static SubgraphClient,
     pub escrow_adapter: EscrowAdapter,
     pub domain_separator: Eip712Domain,
     pub sender_aggregator_endpoint: String,
     pub sender_account_ref: ActorRef<SenderAccountMessage>,
+    pub clock: Arc<dyn Clock>,
 }
 
 #[derive(Debug)]
 pub enum SenderAllocationMessage {
     NewReceipt(NewReceiptNotification),
     TriggerRAVRequest(RpcReplyPort<(UnaggregatedReceipts, Option<SignedRAV>)>),
+    IdleCheck,
+    Reconcile,
+    SaveCheckpoint(RpcReplyPort<()>),
+    /// A RAV was imported by the operator, removing the receipts it covers.
+    RavImported(SignedRAV),
+    /// Invalid receipts were promoted by the operator, the new receipts being notified from the
+    /// database as any other.
+    InvalidReceiptsPromoted,
+    /// The overflow of the unaggregated fees was acknowledged by the operator.
+    FeeOverflowAcknowledged,
+    /// The allocation vanished from the allocation source without being closed.
+    Vanished,
+    GetSnapshot(RpcReplyPort<SenderAllocationSnapshot>),
     #[cfg(test)]
     GetUnaggregatedReceipts(RpcReplyPort<UnaggregatedReceipts>),
 }
@@ -146,7 +293,7 @@ impl Actor for SenderAllocation {
 
     async fn pre_start(
         &self,
-        _myself: ActorRef<Self::Msg>,
+        myself: ActorRef<Self::Msg>,
         args: Self::Arguments,
     ) -> std::result::Result<Self::State, ActorProcessingErr> {
         let sender_account_ref = args.sender_account_ref.clone();
@@ -162,8 +309,15 @@ impl Actor for SenderAllocation {
             ))?;
         }
 
-        // update unaggregated_fees
-        state.unaggregated_fees = state.calculate_unaggregated_fee().await?;
+        // update unaggregated_fees, unless they overflowed and the operator didn't acknowledge
+        // it yet
+        if fee_overflows::is_overflowed(&state.pgpool, state.sender, allocation_id).await? {
+            state.fee_overflow = true;
+            sender_account_ref
+                .cast(SenderAccountMessage::UpdateFeeOverflow(allocation_id, true))?;
+        } else {
+            state.unaggregated_fees = state.unaggregated_fee_from_checkpoint().await?;
+        }
         sender_account_ref.cast(SenderAccountMessage::UpdateReceiptFees(
             allocation_id,
             state.unaggregated_fees.clone(),
@@ -182,6 +336,36 @@ impl Actor for SenderAllocation {
                 .set(rav.message.valueAggregate as f64);
         }
 
+        if let Some(idle_timeout) = state.config.tap.sender_allocation_idle_timeout {
+            let clock = state.clock.clone();
+            let myself = myself.clone();
+            state.idle_check_handle = Some(tokio::spawn(async move {
+                loop {
+                    clock.sleep(idle_timeout).await;
+                    if myself.cast(SenderAllocationMessage::IdleCheck).is_err() {
+                        break;
+                    }
+                }
+            }));
+        }
+
+        if let Some(reconciliation) = &state.config.tap.reconciliation {
+            let interval = reconciliation.interval;
+            // Spreads the reconciliations of the allocations over the interval
+            let first_delay = interval.mul_f64(f64::from(state.allocation_id[19]) / 256.0);
+            let clock = state.clock.clone();
+            let myself = myself.clone();
+            state.reconciliation_handle = Some(tokio::spawn(async move {
+                clock.sleep(first_delay).await;
+                loop {
+                    clock.sleep(interval).await;
+                    if myself.cast(SenderAllocationMessage::Reconcile).is_err() {
+                        break;
+                    }
+                }
+            }));
+        }
+
         tracing::info!(
             sender = %state.sender,
             allocation_id = %state.allocation_id,
@@ -198,22 +382,98 @@ impl Actor for SenderAllocation {
         _myself: ActorRef<Self::Msg>,
         state: &mut Self::State,
     ) -> std::result::Result<(), ActorProcessingErr> {
+        if let Some(handle) = state.idle_check_handle.take() {
+            handle.abort();
+        }
+        if let Some(handle) = state.reconciliation_handle.take() {
+            handle.abort();
+        }
+        // The allocation is still open, the last RAV is requested once it gets closed. The
+        // checkpoint makes spawning it again cheap.
+        if state.evicted {
+            if let Err(err) = state.save_checkpoint().await {
+                error!(error = %err, "Error while saving the checkpoint of the allocation.");
+            }
+            tracing::debug!(
+                sender = %state.sender,
+                allocation_id = %state.allocation_id,
+                "Stopped idle SenderAllocation",
+            );
+            return Ok(());
+        }
+        if state.vanished {
+            state.close_vanished().await;
+            return Ok(());
+        }
+
         tracing::info!(
             sender = %state.sender,
             allocation_id = %state.allocation_id,
             "Closing SenderAllocation, triggering last rav",
         );
-        // Request a RAV and mark the allocation as final.
+        let closure =
+            ClosureProgress::start(state.pgpool.clone(), state.sender, state.allocation_id).await;
+        // Many allocations can be closed at once, only a few request their last RAV at a time
+        let _permit = allocation_closures::permits(state.config.tap.max_concurrent_closures)
+            .acquire()
+            .await;
+
+        // Request the last RAV in chunks, each one stored and resumed from by the next one,
+        // and mark it as last once all the fees are aggregated.
+        closure.set_state(ClosureState::RequestingRav).await;
+        state.closing = true;
+        state.rav_request_receipt_limit = state
+            .rav_request_receipt_limit
+            .min(state.max_rav_request_receipts());
         while state.unaggregated_fees.value > 0 {
-            if let Err(err) = state.request_rav().await {
-                error!(error = %err, "There was an error while requesting rav. Retrying in 30 seconds...");
-                tokio::time::sleep(Duration::from_secs(30)).await;
+            match state.request_rav().await {
+                Ok(()) => {
+                    let value = state
+                        .latest_rav
+                        .as_ref()
+                        .map_or(0, |rav| rav.message.valueAggregate);
+                    closure.record_chunk(value).await;
+                }
+                Err(err) => {
+                    error!(error = %err, "There was an error while requesting rav. Retrying in 30 seconds...");
+                    closure.record_failure(&err).await;
+                    state.clock.sleep(Duration::from_secs(30)).await;
+                }
             }
         }
 
+        // Held back until the close can't be rolled back, the last RAVs being redeemed once marked
+        if let Some(poi_gating) = &state.config.tap.poi_gating {
+            closure.set_state(ClosureState::WaitingForPoi).await;
+            poi_gate::wait_for_poi(
+                &state.pgpool,
+                state.network_subgraph,
+                state.allocation_id,
+                poi_gating,
+                state.clock.as_ref(),
+            )
+            .await;
+        }
+
+        closure.set_state(ClosureState::MarkingLast).await;
         while let Err(err) = state.mark_rav_last().await {
             error!(error = %err, %state.allocation_id, %state.sender,  "Error while marking allocation last. Retrying in 30 seconds...");
-            tokio::time::sleep(Duration::from_secs(30)).await;
+            closure.record_failure(&err).await;
+            state.clock.sleep(Duration::from_secs(30)).await;
+        }
+        closure
+            .finish(
+                state
+                    .latest_rav
+                    .as_ref()
+                    .map_or(0, |rav| rav.message.valueAggregate),
+            )
+            .await;
+
+        if let Err(err) =
+            checkpoint::remove_allocation(&state.pgpool, state.sender, state.allocation_id).await
+        {
+            error!(error = %err, "Error while removing the checkpoint of the allocation.");
         }
 
         // Since this is only triggered after allocation is closed will be counted here
@@ -224,7 +484,7 @@ impl Actor for SenderAllocation {
 
     async fn handle(
         &self,
-        _myself: ActorRef<Self::Msg>,
+        myself: ActorRef<Self::Msg>,
         message: Self::Msg,
         state: &mut Self::State,
     ) -> std::result::Result<(), ActorProcessingErr> {
@@ -234,31 +494,28 @@ impl Actor for SenderAllocation {
             ?message,
             "New SenderAllocation message"
         );
-        let unaggreated_fees = &mut state.unaggregated_fees;
+        state.messages_handled += 1;
         match message {
             SenderAllocationMessage::NewReceipt(NewReceiptNotification {
                 id, value: fees, ..
             }) => {
-                if id > unaggreated_fees.last_id {
-                    unaggreated_fees.last_id = id;
-                    unaggreated_fees.value =
-                        unaggreated_fees.value.checked_add(fees).unwrap_or_else(|| {
-                            // This should never happen, but if it does, we want to know about it.
-                            error!(
-                            "Overflow when adding receipt value {} to total unaggregated fees {} \
-                            for allocation {} and sender {}. Setting total unaggregated fees to \
-                            u128::MAX.",
-                            fees, unaggreated_fees.value, state.allocation_id, state.sender
-                        );
-                            u128::MAX
-                        });
-                    // it's fine to crash the actor, could not send a message to its parent
-                    state
-                        .sender_account_ref
-                        .cast(SenderAccountMessage::UpdateReceiptFees(
-                            state.allocation_id,
-                            unaggreated_fees.clone(),
-                        ))?;
+                state.last_activity = state.clock.now();
+                // Once overflowed, the receipts are summed from the database when acknowledged
+                if id > state.unaggregated_fees.last_id && !state.fee_overflow {
+                    match state.unaggregated_fees.value.checked_add(fees) {
+                        Some(value) => {
+                            state.unaggregated_fees.last_id = id;
+                            state.unaggregated_fees.value = value;
+                            // it's fine to crash the actor, could not send a message to its parent
+                            state.sender_account_ref.cast(
+                                SenderAccountMessage::UpdateReceiptFees(
+                                    state.allocation_id,
+                                    state.unaggregated_fees.clone(),
+                                ),
+                            )?;
+                        }
+                        None => state.fee_overflowed(fees).await?,
+                    }
                 }
 
                 UNAGGREGATED_FEES
@@ -270,7 +527,8 @@ impl Actor for SenderAllocation {
             }
             // we use a blocking call here to ensure that only one RAV request is running at a time.
             SenderAllocationMessage::TriggerRAVRequest(reply) => {
-                if state.unaggregated_fees.value > 0 {
+                state.last_activity = state.clock.now();
+                if state.unaggregated_fees.value > 0 && !state.fee_overflow {
                     // auto backoff retry, on error ignore
                     let _ = state.request_rav().await;
                 }
@@ -278,10 +536,142 @@ impl Actor for SenderAllocation {
                     let _ = reply.send((state.unaggregated_fees.clone(), state.latest_rav.clone()));
                 }
             }
+            SenderAllocationMessage::IdleCheck => {
+                let idle =
+                    state
+                        .config
+                        .tap
+                        .sender_allocation_idle_timeout
+                        .is_some_and(|idle_timeout| {
+                            state.clock.now() - state.last_activity >= idle_timeout
+                        });
+                if idle {
+                    // The unaggregated fees stay tracked by the sender account, and are
+                    // recomputed from the database when this actor is spawned again.
+                    state.evicted = true;
+                    myself.stop(Some(IDLE_EVICTION_REASON.to_string()));
+                }
+            }
+            SenderAllocationMessage::Vanished => {
+                state.vanished = true;
+                myself.stop(Some(VANISHED_REASON.to_string()));
+            }
+            SenderAllocationMessage::Reconcile if state.fee_overflow => {}
+            SenderAllocationMessage::Reconcile => {
+                if let Err(err) = state.reconcile().await {
+                    warn!(
+                        error = %err,
+                        sender = %state.sender,
+                        allocation_id = %state.allocation_id,
+                        "Error while reconciling the unaggregated fees with the database."
+                    );
+                }
+            }
+            SenderAllocationMessage::SaveCheckpoint(reply) => {
+                if let Err(err) = state.save_checkpoint().await {
+                    error!(
+                        error = %err,
+                        sender = %state.sender,
+                        allocation_id = %state.allocation_id,
+                        "Error while saving the checkpoint of the allocation."
+                    );
+                }
+                if !reply.is_closed() {
+                    let _ = reply.send(());
+                }
+            }
+            SenderAllocationMessage::RavImported(rav) => {
+                state.unaggregated_fees = state.calculate_unaggregated_fee().await?;
+                state.latest_rav = Some(rav.clone());
+                state
+                    .sender_account_ref
+                    .cast(SenderAccountMessage::UpdateReceiptFees(
+                        state.allocation_id,
+                        state.unaggregated_fees.clone(),
+                    ))?;
+                state
+                    .sender_account_ref
+                    .cast(SenderAccountMessage::UpdateRav(rav))?;
+                UNAGGREGATED_FEES
+                    .with_label_values(&[
+                        &state.sender.to_string(),
+                        &state.allocation_id.to_string(),
+                    ])
+                    .set(state.unaggregated_fees.value as f64);
+            }
+            SenderAllocationMessage::InvalidReceiptsPromoted => {
+                state.invalid_receipts_fees = state.calculate_invalid_receipts_fee().await?;
+                state
+                    .sender_account_ref
+                    .cast(SenderAccountMessage::UpdateInvalidReceiptFees(
+                        state.allocation_id,
+                        state.invalid_receipts_fees.clone(),
+                    ))?;
+            }
+            SenderAllocationMessage::FeeOverflowAcknowledged => {
+                match state.calculate_unaggregated_fee().await {
+                    Ok(unaggregated_fees) => {
+                        state.fee_overflow = false;
+                        state.unaggregated_fees = unaggregated_fees;
+                        state
+                            .sender_account_ref
+                            .cast(SenderAccountMessage::UpdateReceiptFees(
+                                state.allocation_id,
+                                state.unaggregated_fees.clone(),
+                            ))?;
+                        state
+                            .sender_account_ref
+                            .cast(SenderAccountMessage::UpdateFeeOverflow(
+                                state.allocation_id,
+                                false,
+                            ))?;
+                        UNAGGREGATED_FEES
+                            .with_label_values(&[
+                                &state.sender.to_string(),
+                                &state.allocation_id.to_string(),
+                            ])
+                            .set(state.unaggregated_fees.value as f64);
+                    }
+                    Err(err) => {
+                        error!(
+                            error = %err,
+                            sender = %state.sender,
+                            allocation_id = %state.allocation_id,
+                            "Error while summing the unaggregated fees after the acknowledged \
+                            overflow, the allocation stays overflowed."
+                        );
+                        state.fee_overflowed(0).await?;
+                    }
+                }
+            }
+            SenderAllocationMessage::GetSnapshot(reply) => {
+                let snapshot = SenderAllocationSnapshot {
+                    allocation_id: state.allocation_id,
+                    unaggregated_fees: state.unaggregated_fees.value.to_string(),
+                    last_receipt_id: state.unaggregated_fees.last_id,
+                    invalid_receipts_fees: state.invalid_receipts_fees.value.to_string(),
+                    last_rav_timestamp_ns: state
+                        .latest_rav
+                        .as_ref()
+                        .map(|rav| rav.message.timestampNs),
+                    last_rav_value: state
+                        .latest_rav
+                        .as_ref()
+                        .map(|rav| rav.message.valueAggregate.to_string()),
+                    rav_request_receipt_limit: state.rav_request_receipt_limit,
+                    rav_request_failures: state.rav_request_failures,
+                    fee_overflow: state.fee_overflow,
+                    idle_secs: (state.clock.now() - state.last_activity).as_secs(),
+                    messages_handled: state.messages_handled,
+                };
+                if !reply.is_closed() {
+                    let _ = reply.send(snapshot);
+                }
+            }
             #[cfg(test)]
             SenderAllocationMessage::GetUnaggregatedReceipts(reply) => {
                 if !reply.is_closed() {
-                    let _ = reply.send(unaggreated_fees.clone());
+                    let _ = reply.send(state.unaggregated_fees.clone());
                 }
             }
         }
@@ -299,10 +689,12 @@ impl SenderAllocationState {
             sender,
             escrow_accounts,
             escrow_subgraph,
+            network_subgraph,
             escrow_adapter,
             domain_separator,
             sender_aggregator_endpoint,
             sender_account_ref,
+            clock,
         }: SenderAllocationArgs,
     ) -> Self {
         let required_checks: Vec<Arc<dyn Check + Send + Sync>> = vec![
@@ -337,14 +729,58 @@ impl SenderAllocationState {
             allocation_id,
             sender,
             sender_aggregator_endpoint,
+            aggregator_http_client: http_proxy::client(&config.indexer_infrastructure.proxy),
             config,
             escrow_accounts,
+            network_subgraph,
             domain_separator,
             sender_account_ref: sender_account_ref.clone(),
             unaggregated_fees: UnaggregatedReceipts::default(),
             invalid_receipts_fees: UnaggregatedReceipts::default(),
             latest_rav,
+            last_activity: clock.now(),
+            clock,
+            rav_request_receipt_limit: config.tap.rav_request_receipt_limit,
+            rav_request_failures: 0,
+            closing: false,
+            fee_overflow: false,
+            messages_handled: 0,
+            evicted: false,
+            vanished: false,
+            idle_check_handle: None,
+            reconciliation_handle: None,
+        }
+    }
+
+    /// Stops counting the fees of the allocation, as they can't be trusted anymore, and pauses
+    /// the sender until the operator acknowledges the overflow.
+    async fn fee_overflowed(&mut self, value: u128) -> Result<()> {
+        error!(
+            sender = %self.sender,
+            allocation_id = %self.allocation_id,
+            unaggregated_fees = self.unaggregated_fees.value,
+            value,
+            "Overflow of the unaggregated fees of the allocation. They aren't counted anymore, \
+            and the sender is paused, until the overflow is acknowledged by the operator."
+        );
+        self.fee_overflow = true;
+        if let Err(err) = fee_overflows::record(
+            &self.pgpool,
+            self.sender,
+            self.allocation_id,
+            self.unaggregated_fees.value,
+            value,
+        )
+        .await
+        {
+            error!(error = %err, "Error while recording the fee overflow.");
         }
+        self.sender_account_ref
+            .cast(SenderAccountMessage::UpdateFeeOverflow(
+                self.allocation_id,
+                true,
+            ))?;
+        Ok(())
     }
 
     /// Delete obsolete receipts in the DB w.r.t. the last RAV in DB, then update the tap manager
@@ -352,12 +788,21 @@ impl SenderAllocationState {
     async fn calculate_unaggregated_fee(&self) -> Result<UnaggregatedReceipts> {
         tracing::trace!("calculate_unaggregated_fee()");
         self.tap_manager.remove_obsolete_receipts().await?;
+        if let Some(chunk_size) = self.config.tap.unaggregated_fees_chunk_size {
+            return self
+                .sum_unaggregated_fee_in_chunks(UnaggregatedReceipts::default(), chunk_size)
+                .await;
+        }
 
         let signers = signers_trimmed(&self.escrow_accounts, self.sender).await?;
+        // Must see the receipts stored so far, and the removal of the obsolete ones
+        let read_pool = db_consistency::read_pool(&self.pgpool, None).await;
 
         // TODO: Get `rav.timestamp_ns` from the TAP Manager's RAV storage adapter instead?
-        let res = sqlx::query!(
-            r#"
+        let res = db_metrics::timed(
+            "calculate_unaggregated_fee",
+            sqlx::query!(
+                r#"
             WITH rav AS (
                 SELECT
                     timestamp_ns
@@ -387,11 +832,12 @@ impl SenderAllocationState {
                         rav
                 ) ELSE TRUE END
             "#,
-            self.allocation_id.encode_hex::<String>(),
-            self.sender.encode_hex::<String>(),
-            &signers
+                self.allocation_id.encode_hex::<String>(),
+                self.sender.encode_hex::<String>(),
+                &signers
+            )
+            .fetch_one(&read_pool),
         )
-        .fetch_one(&self.pgpool)
         .await?;
 
         ensure!(
@@ -409,13 +855,288 @@ impl SenderAllocationState {
         })
     }
 
+    /// Resumes the unaggregated fees from the checkpoint saved on the last shutdown, only summing
+    /// the receipts stored after it. Falls back to [Self::calculate_unaggregated_fee] if there is
+    /// no valid checkpoint.
+    async fn unaggregated_fee_from_checkpoint(&self) -> Result<UnaggregatedReceipts> {
+        let Some(checkpoint) =
+            checkpoint::load_allocation(&self.pgpool, self.sender, self.allocation_id).await?
+        else {
+            return self.calculate_unaggregated_fee().await;
+        };
+
+        let signers = signers_trimmed(&self.escrow_accounts, self.sender).await?;
+        let rav_timestamp_ns = self.latest_rav.as_ref().map(|rav| rav.message.timestampNs);
+        let latest_receipt_id = checkpoint::latest_receipt_id(&self.pgpool).await?;
+        if let Err(err) = checkpoint.validate(&signers, rav_timestamp_ns, latest_receipt_id) {
+            warn!(
+                error = %err,
+                sender = %self.sender,
+                allocation_id = %self.allocation_id,
+                "Invalid checkpoint, computing the unaggregated fees from all the receipts."
+            );
+            return self.calculate_unaggregated_fee().await;
+        }
+
+        self.tap_manager.remove_obsolete_receipts().await?;
+        if let Some(chunk_size) = self.config.tap.unaggregated_fees_chunk_size {
+            return self
+                .sum_unaggregated_fee_in_chunks(checkpoint.unaggregated_fees, chunk_size)
+                .await;
+        }
+
+        let row = sqlx::query(
+            r#"
+                SELECT MAX(id) AS max, SUM(value) AS sum
+                FROM scalar_tap_receipts
+                WHERE allocation_id = $1
+                    AND signer_address IN (SELECT unnest($2::text[]))
+                    AND id > $3
+                    AND ($4::NUMERIC IS NULL OR timestamp_ns > $4::NUMERIC)
+            "#,
+        )
+        .bind(AllocationIdHex(self.allocation_id))
+        .bind(&signers)
+        .bind(i64::try_from(checkpoint.unaggregated_fees.last_id)?)
+        .bind(rav_timestamp_ns.map(BigDecimal::from))
+        .fetch_one(&self.pgpool)
+        .await?;
+        let max: Option<i64> = row.try_get("max")?;
+        let sum: Option<GrtWei> = row.try_get("sum")?;
+
+        ensure!(
+            sum.is_none() == max.is_none(),
+            "Exactly one of SUM(value) and MAX(id) is null. This should not happen."
+        );
+
+        let value = sum.unwrap_or_default().0;
+        Ok(UnaggregatedReceipts {
+            last_id: max.map_or(Ok(checkpoint.unaggregated_fees.last_id), u64::try_from)?,
+            value: checkpoint
+                .unaggregated_fees
+                .value
+                .checked_add(value)
+                .ok_or_else(|| anyhow!("Overflow when resuming unaggregated fees"))?,
+        })
+    }
+
+    /// Adds the receipts stored after `from` to it, summing `chunk_size` receipt ids at a time
+    /// so that no query scans the whole receipts table. The connection is released between the
+    /// chunks, and the progress is saved in the checkpoint of the allocation after each of them,
+    /// so that a restart resumes the recalculation instead of starting over.
+    async fn sum_unaggregated_fee_in_chunks(
+        &self,
+        from: UnaggregatedReceipts,
+        chunk_size: u64,
+    ) -> Result<UnaggregatedReceipts> {
+        let signers = signers_trimmed(&self.escrow_accounts, self.sender).await?;
+        // Must see the receipts stored so far, and the removal of the obsolete ones
+        let read_pool = db_consistency::read_pool(&self.pgpool, None).await;
+        // The one of the stored RAV, which may not be `latest_rav` yet
+        let rav_timestamp_ns = sqlx::query(
+            r#"
+                SELECT timestamp_ns
+                FROM scalar_tap_ravs
+                WHERE allocation_id = $1 AND sender_address = $2
+            "#,
+        )
+        .bind(AllocationIdHex(self.allocation_id))
+        .bind(SenderAddress(self.sender))
+        .fetch_optional(&read_pool)
+        .await?
+        .map(|row| row.try_get::<BigDecimal, _>("timestamp_ns"))
+        .transpose()?;
+        let latest_receipt_id = checkpoint::latest_receipt_id(&read_pool).await?;
+
+        let mut fees = from;
+        let mut cursor = fees.last_id;
+        while cursor < latest_receipt_id {
+            let chunk_end = cursor.saturating_add(chunk_size).min(latest_receipt_id);
+            let row = db_metrics::timed(
+                "calculate_unaggregated_fee_chunk",
+                sqlx::query(
+                    r#"
+                        SELECT MAX(id) AS max, SUM(value) AS sum
+                        FROM scalar_tap_receipts
+                        WHERE id > $1 AND id <= $2
+                            AND allocation_id = $3
+                            AND signer_address IN (SELECT unnest($4::text[]))
+                            AND ($5::NUMERIC IS NULL OR timestamp_ns > $5::NUMERIC)
+                    "#,
+                )
+                .bind(i64::try_from(cursor)?)
+                .bind(i64::try_from(chunk_end)?)
+                .bind(AllocationIdHex(self.allocation_id))
+                .bind(&signers)
+                .bind(&rav_timestamp_ns)
+                .fetch_one(&read_pool),
+            )
+            .await?;
+            cursor = chunk_end;
+
+            let Some(max) = row.try_get::<Option<i64>, _>("max")? else {
+                continue;
+            };
+            let sum: GrtWei = row.try_get("sum")?;
+            fees = UnaggregatedReceipts {
+                last_id: max.try_into()?,
+                value: fees
+                    .value
+                    .checked_add(sum.0)
+                    .ok_or_else(|| anyhow!("Overflow when summing unaggregated fees"))?,
+            };
+            let result = checkpoint::save_allocation(
+                &self.pgpool,
+                &AllocationCheckpoint {
+                    sender: self.sender,
+                    allocation_id: self.allocation_id,
+                    signers: signers.clone(),
+                    unaggregated_fees: fees.clone(),
+                    rav_timestamp_ns: rav_timestamp_ns
+                        .as_ref()
+                        .map(|timestamp_ns| timestamp_ns.to_string().parse::<u64>())
+                        .transpose()?,
+                    rav_request_in_flight: false,
+                },
+            )
+            .await;
+            // Only resumed from on restart, the fees are summed again without it
+            self.report_checkpoint_failure(result);
+            // Let the other actors use the pool between the chunks
+            tokio::task::yield_now().await;
+        }
+        Ok(fees)
+    }
+
+    /// Recomputes from the database the value of the receipts accounted for in the unaggregated
+    /// fees, i.e. up to their `last_id`, and corrects the fees if they drifted from it by more
+    /// than the configured threshold.
+    async fn reconcile(&mut self) -> Result<()> {
+        let Some(reconciliation) = &self.config.tap.reconciliation else {
+            return Ok(());
+        };
+        let signers = signers_trimmed(&self.escrow_accounts, self.sender).await?;
+
+        let row = sqlx::query(
+            r#"
+                WITH rav AS (
+                    SELECT timestamp_ns
+                    FROM scalar_tap_ravs
+                    WHERE allocation_id = $1 AND sender_address = $2
+                )
+                SELECT SUM(value) AS sum
+                FROM scalar_tap_receipts
+                WHERE allocation_id = $1
+                    AND signer_address IN (SELECT unnest($3::text[]))
+                    AND id <= $4
+                    AND timestamp_ns > COALESCE((SELECT timestamp_ns FROM rav), -1)
+            "#,
+        )
+        .bind(AllocationIdHex(self.allocation_id))
+        .bind(SenderAddress(self.sender))
+        .bind(&signers)
+        .bind(i64::try_from(self.unaggregated_fees.last_id)?)
+        .fetch_one(&self.pgpool)
+        .await?;
+        let database_value = row
+            .try_get::<Option<GrtWei>, _>("sum")?
+            .unwrap_or_default()
+            .0;
+
+        let tracked_value = self.unaggregated_fees.value;
+        let drift = database_value as f64 - tracked_value as f64;
+        UNAGGREGATED_FEES_DRIFT
+            .with_label_values(&[&self.sender.to_string(), &self.allocation_id.to_string()])
+            .set(drift);
+        if database_value.abs_diff(tracked_value) <= reconciliation.drift_threshold {
+            return Ok(());
+        }
+
+        warn!(
+            sender = %self.sender,
+            allocation_id = %self.allocation_id,
+            last_receipt_id = self.unaggregated_fees.last_id,
+            tracked_value,
+            database_value,
+            "Unaggregated fees drifted from the receipts in the database, correcting them."
+        );
+        self.unaggregated_fees.value = database_value;
+        self.sender_account_ref
+            .cast(SenderAccountMessage::UpdateReceiptFees(
+                self.allocation_id,
+                self.unaggregated_fees.clone(),
+            ))?;
+        UNAGGREGATED_FEES
+            .with_label_values(&[&self.sender.to_string(), &self.allocation_id.to_string()])
+            .set(self.unaggregated_fees.value as f64);
+        Ok(())
+    }
+
+    /// Closes the sender allocation of a vanished allocation. The last RAV is requested once,
+    /// with its retries, and flagged for review rather than marked as last, the allocation
+    /// possibly showing up again.
+    async fn close_vanished(&mut self) {
+        tracing::warn!(
+            sender = %self.sender,
+            allocation_id = %self.allocation_id,
+            "Allocation vanished, triggering last rav to be flagged for review",
+        );
+        let mut rav_request_error = None;
+        if self.unaggregated_fees.value > 0 {
+            self.closing = true;
+            self.rav_request_receipt_limit = self
+                .rav_request_receipt_limit
+                .min(self.max_rav_request_receipts());
+            if let Err(err) = self.request_rav_with_retries().await {
+                error!(error = %err, "Error while requesting the last rav of a vanished allocation.");
+                rav_request_error = Some(err.to_string());
+            }
+        }
+
+        if let Err(err) = vanished_allocations::flag(
+            &self.pgpool,
+            self.sender,
+            self.allocation_id,
+            self.latest_rav
+                .as_ref()
+                .map(|rav| rav.message.valueAggregate),
+            self.unaggregated_fees.value,
+            rav_request_error.as_deref(),
+        )
+        .await
+        {
+            error!(error = %err, "Error while flagging the vanished allocation for review.");
+        }
+        // Resumed from if the allocation shows up again
+        if let Err(err) = self.save_checkpoint().await {
+            error!(error = %err, "Error while saving the checkpoint of the allocation.");
+        }
+    }
+
+    async fn save_checkpoint(&self) -> Result<()> {
+        checkpoint::save_allocation(
+            &self.pgpool,
+            &AllocationCheckpoint {
+                sender: self.sender,
+                allocation_id: self.allocation_id,
+                signers: signers_trimmed(&self.escrow_accounts, self.sender).await?,
+                unaggregated_fees: self.unaggregated_fees.clone(),
+                rav_timestamp_ns: self.latest_rav.as_ref().map(|rav| rav.message.timestampNs),
+                rav_request_in_flight: false,
+            },
+        )
+        .await
+    }
+
     async fn calculate_invalid_receipts_fee(&self) -> Result<UnaggregatedReceipts> {
         tracing::trace!("calculate_invalid_receipts_fee()");
         let signers = signers_trimmed(&self.escrow_accounts, self.sender).await?;
 
         // TODO: Get `rav.timestamp_ns` from the TAP Manager's RAV storage adapter instead?
-        let res = sqlx::query!(
-            r#"
+        let res = db_metrics::timed(
+            "calculate_invalid_receipts_fee",
+            sqlx::query!(
+                r#"
             SELECT
                 MAX(id),
                 SUM(value)
@@ -425,10 +1146,11 @@ impl SenderAllocationState {
                 allocation_id = $1
                 AND signer_address IN (SELECT unnest($2::text[]))
             "#,
-            self.allocation_id.encode_hex::<String>(),
-            &signers
+                self.allocation_id.encode_hex::<String>(),
+                &signers
+            )
+            .fetch_one(&self.pgpool),
         )
-        .fetch_one(&self.pgpool)
         .await?;
 
         ensure!(
@@ -446,7 +1168,55 @@ impl SenderAllocationState {
         })
     }
 
+    /// Configured receipts per RAV request, in chunks of the last RAV once closing.
+    fn max_rav_request_receipts(&self) -> u64 {
+        match self.config.tap.final_rav_chunk_receipts {
+            Some(chunk) if self.closing => chunk.max(1),
+            _ => self.config.tap.rav_request_receipt_limit,
+        }
+    }
+
+    /// Requests a RAV, flagging it in flight in the checkpoint meanwhile. The outcome of the
+    /// request is returned even if the flag can't be written.
     async fn request_rav(&mut self) -> Result<()> {
+        let flagged = checkpoint::set_rav_request_in_flight(
+            &self.pgpool,
+            self.sender,
+            self.allocation_id,
+            true,
+        )
+        .await;
+        self.report_checkpoint_failure(flagged);
+        let result = self.request_rav_with_retries().await;
+        self.rav_request_failures = match result {
+            Ok(()) => 0,
+            Err(_) => self.rav_request_failures.saturating_add(1),
+        };
+        let cleared = checkpoint::set_rav_request_in_flight(
+            &self.pgpool,
+            self.sender,
+            self.allocation_id,
+            false,
+        )
+        .await;
+        self.report_checkpoint_failure(cleared);
+        result
+    }
+
+    fn report_checkpoint_failure(&self, result: Result<()>) {
+        if let Err(e) = result {
+            error!(
+                sender = %self.sender,
+                allocation_id = %self.allocation_id,
+                "Failed to write the checkpoint of the sender allocation: {}", e
+            );
+            CHECKPOINT_WRITES_FAILED
+                .with_label_values(&[&self.sender.to_string(), &self.allocation_id.to_string()])
+                .inc();
+        }
+    }
+
+    async fn request_rav_with_retries(&mut self) -> Result<()> {
         let mut retries = 0;
         const MAX_RETRIES: u32 = 3;
         while retries < MAX_RETRIES {
@@ -454,9 +1224,30 @@ impl SenderAllocationState {
                 Ok(rav) => {
                     self.unaggregated_fees = self.calculate_unaggregated_fee().await?;
                     self.latest_rav = Some(rav);
+                    // Work back up to the configured limit
+                    self.rav_request_receipt_limit =
+                        (self.rav_request_receipt_limit * 2).min(self.max_rav_request_receipts());
                     return Ok(());
                 }
                 Err(e) => {
+                    if let Some(RavSizeError { receipts, .. }) = e.downcast_ref::<RavSizeError>() {
+                        if *receipts > 1 {
+                            RAV_REQUEST_SIZE_ERRORS
+                                .with_label_values(&[
+                                    &self.sender.to_string(),
+                                    &self.allocation_id.to_string(),
+                                ])
+                                .inc();
+                            self.rav_request_receipt_limit = (*receipts as u64 / 2).max(1);
+                            warn!(
+                                "{}. Retrying with {} receipts for sender {} and allocation {}.",
+                                e, self.rav_request_receipt_limit, self.sender, self.allocation_id
+                            );
+                            retries += 1;
+                            continue;
+                        }
+                    }
+
                     error!(
                         "Error while requesting RAV for sender {} and allocation {}: {}",
                         self.sender, self.allocation_id, e
@@ -468,7 +1259,9 @@ impl SenderAllocationState {
                         ])
                         .inc();
                     // backoff = 100ms * 2 ^ retries
-                    tokio::time::sleep(Duration::from_millis(100) * 2u32.pow(retries)).await;
+                    self.clock
+                        .sleep(Duration::from_millis(100) * 2u32.pow(retries))
+                        .await;
                     retries += 1;
                 }
             }
@@ -489,7 +1282,7 @@ impl SenderAllocationState {
             .tap_manager
             .create_rav_request(
                 self.config.tap.rav_request_timestamp_buffer_ms * 1_000_000,
-                Some(self.config.tap.rav_request_receipt_limit),
+                Some(self.rav_request_receipt_limit),
             )
             .await
             .map_err(|e| match e {
@@ -513,36 +1306,138 @@ impl SenderAllocationState {
             // TODO: consider doing that in a spawned task?
             self.store_invalid_receipts(invalid_receipts.as_slice())
                 .await?;
+            if self
+                .config
+                .tap
+                .trace_export
+                .as_ref()
+                .is_some_and(|export| invalid_receipts.len() >= export.invalid_receipts_threshold)
+            {
+                self.export_trace(
+                    TraceKind::InvalidReceipts,
+                    format!("{} invalid receipts found", invalid_receipts.len()),
+                    &expected_rav,
+                    None,
+                );
+            }
         }
-        let client = HttpClientBuilder::default()
-            .request_timeout(Duration::from_secs(
-                self.config.tap.rav_request_timeout_secs,
-            ))
-            .build(&self.sender_aggregator_endpoint)?;
-        let rav_response_time_start = Instant::now();
-        let response: JsonRpcResponse<EIP712SignedMessage<ReceiptAggregateVoucher>> = client
-            .request(
-                "aggregate_receipts",
-                rpc_params!(
-                    "0.0", // TODO: Set the version in a smarter place.
-                    valid_receipts,
-                    previous_rav
-                ),
+        let size_limits = self.config.tap.aggregator_size_limits_for(&self.sender);
+        let receipts = valid_receipts.len();
+        let previous_rav_message = previous_rav.as_ref().map(|rav| rav.message.clone());
+        let endpoints = aggregator_health::endpoint_rotation(
+            &self.sender,
+            &self
+                .config
+                .tap
+                .aggregator_endpoints_for(&self.sender, &self.sender_aggregator_endpoint),
+        );
+        let mut endpoints = endpoints.iter().peekable();
+        let (raw_response, rav_response_time) = loop {
+            let Some(endpoint) = endpoints.next() else {
+                anyhow::bail!("The sender's TAP aggregator has no endpoint");
+            };
+            let client = AggregatorClient::new(
+                self.aggregator_http_client.clone(),
+                endpoint,
+                Duration::from_secs(self.config.tap.rav_request_timeout_secs),
             )
-            .await?;
+            .with_size_limits(size_limits.max_request_size, size_limits.max_response_size);
+            let rav_response_time_start = Instant::now();
+            // Validated before use, the aggregator can't be trusted to follow the schema
+            let result: Result<serde_json::Value, _> = client
+                .request(
+                    "aggregate_receipts",
+                    rpc_params!(
+                        "0.0", // TODO: Set the version in a smarter place.
+                        &valid_receipts,
+                        &previous_rav
+                    ),
+                )
+                .await;
+            let e = match result {
+                Ok(raw_response) => break (raw_response, rav_response_time_start.elapsed()),
+                Err(e) => e,
+            };
+            let final_chunk_timeout =
+                self.closing && matches!(e, jsonrpsee::core::Error::RequestTimeout);
+            if is_size_error(&e) || final_chunk_timeout {
+                return Err(anyhow::Error::new(RavSizeError {
+                    receipts,
+                    source: e,
+                }));
+            }
+            match endpoints.peek() {
+                Some(next_endpoint) if is_unreachable_error(&e) => {
+                    warn!(
+                        error = %e,
+                        %endpoint,
+                        %next_endpoint,
+                        sender = %self.sender,
+                        "Endpoint of the sender's TAP aggregator unreachable, failing over"
+                    );
+                    AGGREGATOR_FAILOVERS
+                        .with_label_values(&[&self.sender.to_string(), endpoint])
+                        .inc();
+                }
+                _ => return Err(e.into()),
+            }
+        };
 
-        let rav_response_time = rav_response_time_start.elapsed();
         RAV_RESPONSE_TIME
             .with_label_values(&[&self.sender.to_string()])
             .observe(rav_response_time.as_secs_f64());
 
+        let response = match aggregator_response::validate(
+            &raw_response,
+            self.allocation_id,
+            previous_rav_message.as_ref(),
+        ) {
+            Ok(response) => response,
+            Err(anomaly) => {
+                anomaly.record(self.sender);
+                self.store_anomalous_response(&expected_rav, &raw_response, &anomaly)
+                    .await?;
+                self.export_trace(
+                    TraceKind::AnomalousResponse,
+                    anomaly.to_string(),
+                    &expected_rav,
+                    Some(raw_response.clone()),
+                );
+                anyhow::bail!("Rejected the response of the sender's TAP aggregator: {anomaly}");
+            }
+        };
         if let Some(warnings) = response.warnings {
             warn!("Warnings from sender's TAP aggregator: {:?}", warnings);
         }
-        match self
-            .tap_manager
-            .verify_and_store_rav(expected_rav.clone(), response.data.clone())
-            .await
+
+        // The expected RAV is computed from the same receipts that were sent to the aggregator,
+        // double check the received RAV against the database before trusting it.
+        let local_value = self
+            .local_rav_value(response.data.message.timestampNs)
+            .await?;
+        if response.data.message.valueAggregate > local_value {
+            RAVS_EXCEEDING_LOCAL_VALUE
+                .with_label_values(&[&self.sender.to_string(), &self.allocation_id.to_string()])
+                .inc();
+            let reason = format!(
+                "RAV value {} exceeds the value {} recomputed from the local receipts",
+                response.data.message.valueAggregate, local_value
+            );
+            self.store_failed_rav(&expected_rav, &response.data, &reason)
+                .await?;
+            self.export_trace(
+                TraceKind::InvalidRav,
+                reason.clone(),
+                &expected_rav,
+                Some(raw_response.clone()),
+            );
+            anyhow::bail!("Invalid RAV, sender could be malicious: {}.", reason);
+        }
+
+        match self
+            .tap_manager
+            .verify_and_store_rav(expected_rav.clone(), response.data.clone())
+            .await
         {
             Ok(_) => {}
 
@@ -562,6 +1457,12 @@ impl SenderAllocationState {
                 | e @ tap_core::Error::InvalidRecoveredSigner { address: _ },
             ) => {
                 Self::store_failed_rav(self, &expected_rav, &response.data, &e.to_string()).await?;
+                self.export_trace(
+                    TraceKind::InvalidRav,
+                    e.to_string(),
+                    &expected_rav,
+                    Some(raw_response.clone()),
+                );
                 anyhow::bail!("Invalid RAV, sender could be malicious: {:?}.", e);
             }
 
@@ -584,7 +1485,48 @@ impl SenderAllocationState {
         Ok(response.data)
     }
 
+    /// Recomputes from the database the maximum value of a RAV up to `timestamp_ns`: the value
+    /// of the previous RAV plus the value of the receipts stored after it.
+    async fn local_rav_value(&self, timestamp_ns: u64) -> Result<u128> {
+        let signers = signers_trimmed(&self.escrow_accounts, self.sender).await?;
+
+        let row = sqlx::query(
+            r#"
+                WITH rav AS (
+                    SELECT timestamp_ns, value_aggregate
+                    FROM scalar_tap_ravs
+                    WHERE allocation_id = $1 AND sender_address = $2
+                )
+                SELECT
+                    (SELECT value_aggregate FROM rav) AS rav_value,
+                    (
+                        SELECT SUM(value)
+                        FROM scalar_tap_receipts
+                        WHERE allocation_id = $1
+                            AND signer_address IN (SELECT unnest($3::text[]))
+                            AND timestamp_ns > COALESCE((SELECT timestamp_ns FROM rav), -1)
+                            AND timestamp_ns <= $4
+                    ) AS receipts_value
+            "#,
+        )
+        .bind(AllocationIdHex(self.allocation_id))
+        .bind(SenderAddress(self.sender))
+        .bind(&signers)
+        .bind(BigDecimal::from(timestamp_ns))
+        .fetch_one(&self.pgpool)
+        .await?;
+
+        let rav_value = row.try_get::<Option<GrtWei>, _>("rav_value")?;
+        let receipts_value = row.try_get::<Option<GrtWei>, _>("receipts_value")?;
+        Ok(rav_value
+            .unwrap_or_default()
+            .checked_add(receipts_value.unwrap_or_default())
+            .ok_or_else(|| anyhow!("RAV value overflows u128"))?
+            .0)
+    }
+
     pub async fn mark_rav_last(&self) -> Result<()> {
+        schema_drift::ensure_writable()?;
         tracing::info!(
             sender = %self.sender,
             allocation_id = %self.allocation_id,
@@ -635,8 +1577,9 @@ impl SenderAllocationState {
                     error!("Failed to recover receipt signer: {}", e);
                     anyhow!(e)
                 })?;
+            let error_log = received_receipt.clone().error().to_string();
 
-            sqlx::query!(
+            sqlx::query(
                 r#"
                     INSERT INTO scalar_tap_receipts_invalid (
                         signer_address,
@@ -644,40 +1587,39 @@ impl SenderAllocationState {
                         allocation_id,
                         timestamp_ns,
                         nonce,
-                        value
+                        value,
+                        error_log
                     )
-                    VALUES ($1, $2, $3, $4, $5, $6)
+                    VALUES ($1, $2, $3, $4, $5, $6, $7)
                 "#,
-                receipt_signer.encode_hex::<String>(),
-                encoded_signature,
-                allocation_id.encode_hex::<String>(),
-                BigDecimal::from(receipt.message.timestamp_ns),
-                BigDecimal::from(receipt.message.nonce),
-                BigDecimal::from(BigInt::from(receipt.message.value)),
             )
+            .bind(receipt_signer.encode_hex::<String>())
+            .bind(encoded_signature)
+            .bind(allocation_id.encode_hex::<String>())
+            .bind(BigDecimal::from(receipt.message.timestamp_ns))
+            .bind(BigDecimal::from(receipt.message.nonce))
+            .bind(BigDecimal::from(BigInt::from(receipt.message.value)))
+            .bind(error_log)
             .execute(&self.pgpool)
             .await
             .map_err(|e| anyhow!("Failed to store invalid receipt: {:?}", e))?;
         }
         let fees = receipts
             .iter()
-            .map(|receipt| receipt.signed_receipt().message.value)
-            .sum();
-
-        self.invalid_receipts_fees.value = self
-            .invalid_receipts_fees
-            .value
-            .checked_add(fees)
-            .unwrap_or_else(|| {
-                // This should never happen, but if it does, we want to know about it.
-                error!(
-                    "Overflow when adding receipt value {} to invalid receipts fees {} \
-            for allocation {} and sender {}. Setting total unaggregated fees to \
-            u128::MAX.",
-                    fees, self.invalid_receipts_fees.value, self.allocation_id, self.sender
-                );
-                u128::MAX
+            .try_fold(self.invalid_receipts_fees.value, |fees, receipt| {
+                fees.checked_add(receipt.signed_receipt().message.value)
             });
+        let Some(fees) = fees else {
+            fee_overflows::count_total_overflow(self.sender, "invalid_receipts");
+            bail!(
+                "Overflow when adding the invalid receipts to the invalid receipts fees {} of \
+                allocation {} and sender {}",
+                self.invalid_receipts_fees.value,
+                self.allocation_id,
+                self.sender
+            );
+        };
+        self.invalid_receipts_fees.value = fees;
         self.sender_account_ref
             .cast(SenderAccountMessage::UpdateInvalidReceiptFees(
                 self.allocation_id,
@@ -693,27 +1635,99 @@ impl SenderAllocationState {
         rav: &EIP712SignedMessage<ReceiptAggregateVoucher>,
         reason: &str,
     ) -> Result<()> {
-        sqlx::query!(
+        self.insert_failed_rav_request(
+            serde_json::to_value(expected_rav)?,
+            serde_json::to_value(rav)?,
+            reason,
+            None,
+        )
+        .await
+        .map_err(|e| anyhow!("Failed to store failed RAV: {:?}", e))
+    }
+
+    /// Records the raw response rejected as anomalous, for forensics.
+    async fn store_anomalous_response(
+        &self,
+        expected_rav: &ReceiptAggregateVoucher,
+        raw_response: &serde_json::Value,
+        anomaly: &AggregatorAnomaly,
+    ) -> Result<()> {
+        self.insert_failed_rav_request(
+            serde_json::to_value(expected_rav)?,
+            raw_response.clone(),
+            &anomaly.to_string(),
+            Some(anomaly.kind()),
+        )
+        .await
+        .map_err(|e| anyhow!("Failed to store anomalous RAV response: {:?}", e))
+    }
+
+    /// Exports a bundle of the anomaly in the background, if enabled, see [trace_bundles].
+    fn export_trace(
+        &self,
+        kind: TraceKind,
+        reason: String,
+        expected_rav: &ReceiptAggregateVoucher,
+        aggregator_response: Option<serde_json::Value>,
+    ) {
+        trace_bundles::spawn_export(
+            self.config,
+            self.pgpool.clone(),
+            self.escrow_accounts.clone(),
+            Anomaly {
+                kind,
+                sender: self.sender,
+                allocation_id: self.allocation_id,
+                reason,
+                expected_rav: serde_json::to_value(expected_rav).ok(),
+                aggregator_response,
+            },
+        );
+    }
+
+    /// Inserts a failed RAV request, its payload encrypted with the database key if one is set,
+    /// see [indexer_common::secrets].
+    async fn insert_failed_rav_request(
+        &self,
+        expected_rav: serde_json::Value,
+        rav_response: serde_json::Value,
+        reason: &str,
+        anomaly: Option<&str>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
             r#"
                 INSERT INTO scalar_tap_rav_requests_failed (
                     allocation_id,
                     sender_address,
                     expected_rav,
                     rav_response,
-                    reason
+                    reason,
+                    anomaly,
+                    sealed_payload
+                )
+                VALUES (
+                    $1,
+                    $2,
+                    CASE WHEN $7::TEXT IS NULL THEN $3::JSON END,
+                    CASE WHEN $7::TEXT IS NULL THEN $4::JSON END,
+                    $5,
+                    $6,
+                    CASE WHEN $7::TEXT IS NOT NULL THEN pgp_sym_encrypt(
+                        json_build_object('expected_rav', $3::JSON, 'rav_response', $4::JSON)::TEXT,
+                        $7
+                    ) END
                 )
-                VALUES ($1, $2, $3, $4, $5)
             "#,
-            self.allocation_id.encode_hex::<String>(),
-            self.sender.encode_hex::<String>(),
-            serde_json::to_value(expected_rav)?,
-            serde_json::to_value(rav)?,
-            reason
         )
+        .bind(AllocationIdHex(self.allocation_id))
+        .bind(SenderAddress(self.sender))
+        .bind(expected_rav)
+        .bind(rav_response)
+        .bind(reason)
+        .bind(anomaly)
+        .bind(secrets::database_key())
         .execute(&self.pgpool)
-        .await
-        .map_err(|e| anyhow!("Failed to store failed RAV: {:?}", e))?;
-
+        .await?;
         Ok(())
     }
 }
@@ -725,19 +1739,23 @@ pub mod tests {
     };
     use crate::{
         agent::{
-            sender_account::SenderAccountMessage, sender_accounts_manager::NewReceiptNotification,
+            checkpoint::{self, AllocationCheckpoint},
+            clock::{TokioClock, VirtualClock},
+            sender_account::SenderAccountMessage,
+            sender_accounts_manager::NewReceiptNotification,
             unaggregated_receipts::UnaggregatedReceipts,
         },
-        config,
+        config, fee_overflows,
         tap::{
             escrow_adapter::EscrowAdapter,
             test_utils::{
                 create_rav, create_received_receipt, store_invalid_receipt, store_rav,
-                store_receipt, ALLOCATION_ID_0, INDEXER, SENDER, SIGNER,
+                store_receipt, ALLOCATION_ID_0, ALLOCATION_ID_1, INDEXER, SENDER, SIGNER,
                 TAP_EIP712_DOMAIN_SEPARATOR,
             },
         },
     };
+    use alloy_primitives::hex::ToHex;
     use eventuals::Eventual;
     use futures::future::join_all;
     use indexer_common::{
@@ -752,6 +1770,7 @@ pub mod tests {
     use std::{
         collections::HashMap,
         sync::{Arc, Mutex},
+        time::Duration,
     };
     use tap_aggregator::{jsonrpsee_helpers::JsonRpcResponse, server::run_server};
     use tap_core::receipt::{
@@ -823,6 +1842,7 @@ pub mod tests {
             config: None,
             ethereum: config::Ethereum {
                 indexer_address: INDEXER.1,
+                ..Default::default()
             },
             tap: config::Tap {
                 rav_request_trigger_value: 100,
@@ -834,7 +1854,7 @@ pub mod tests {
             ..Default::default()
         }));
 
-        let escrow_subgraph = Box::leak(Box::new(SubgraphClient::new(
+        let escrow_subgraph: &'static SubgraphClient = Box::leak(Box::new(SubgraphClient::new(
             reqwest::Client::new(),
             None,
             DeploymentDetails::for_query_url(escrow_subgraph_endpoint).unwrap(),
@@ -859,10 +1879,13 @@ pub mod tests {
             sender: SENDER.1,
             escrow_accounts: escrow_accounts_eventual,
             escrow_subgraph,
+            // Not queried, the POI gating is disabled
+            network_subgraph: escrow_subgraph,
             escrow_adapter,
             domain_separator: TAP_EIP712_DOMAIN_SEPARATOR.clone(),
             sender_aggregator_endpoint,
             sender_account_ref,
+            clock: Arc::new(TokioClock),
         }
     }
 
@@ -930,6 +1953,146 @@ pub mod tests {
         assert_eq!(total_unaggregated_fees.value, 55u128);
     }
 
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_snapshot(pgpool: PgPool) {
+        for i in 1..=10 {
+            let receipt = create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, i, i, i.into());
+            store_receipt(&pgpool, receipt.signed_receipt())
+                .await
+                .unwrap();
+        }
+        let sender_allocation =
+            create_sender_allocation(pgpool.clone(), DUMMY_URL.to_string(), DUMMY_URL, None).await;
+
+        let snapshot = call!(sender_allocation, SenderAllocationMessage::GetSnapshot).unwrap();
+        assert_eq!(snapshot.allocation_id, *ALLOCATION_ID_0);
+        assert_eq!(snapshot.unaggregated_fees, "55");
+        assert_eq!(snapshot.last_receipt_id, 10);
+        assert_eq!(snapshot.last_rav_timestamp_ns, None);
+        assert_eq!(snapshot.rav_request_failures, 0);
+        // The snapshot request itself
+        assert_eq!(snapshot.messages_handled, 1);
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_reconcile_unaggregated_fees(pgpool: PgPool) {
+        for i in 1..=10 {
+            let receipt = create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, i, i, i.into());
+            store_receipt(&pgpool, receipt.signed_receipt())
+                .await
+                .unwrap();
+        }
+
+        let mut args =
+            create_sender_allocation_args(pgpool.clone(), DUMMY_URL.to_string(), DUMMY_URL, None)
+                .await;
+        let mut config = args.config.clone();
+        config.tap.reconciliation = Some(config::Reconciliation {
+            interval: Duration::from_secs(3600),
+            drift_threshold: 5,
+        });
+        args.config = Box::leak(Box::new(config));
+        let (sender_allocation, _join_handle) =
+            SenderAllocation::spawn(None, SenderAllocation, args)
+                .await
+                .unwrap();
+
+        // Not notified yet, not accounted for
+        let receipt = create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, 11, 11, 100);
+        store_receipt(&pgpool, receipt.signed_receipt())
+            .await
+            .unwrap();
+
+        async fn edit_value(pgpool: &PgPool, delta: i64) {
+            sqlx::query("UPDATE scalar_tap_receipts SET value = value + $1 WHERE nonce = 1")
+                .bind(delta)
+                .execute(pgpool)
+                .await
+                .unwrap();
+        }
+        async fn reconciled_value(sender_allocation: &ActorRef<SenderAllocationMessage>) -> u128 {
+            cast!(sender_allocation, SenderAllocationMessage::Reconcile).unwrap();
+            call!(
+                sender_allocation,
+                SenderAllocationMessage::GetUnaggregatedReceipts
+            )
+            .unwrap()
+            .value
+        }
+
+        assert_eq!(reconciled_value(&sender_allocation).await, 55);
+
+        // Within the threshold
+        edit_value(&pgpool, 3).await;
+        assert_eq!(reconciled_value(&sender_allocation).await, 55);
+
+        edit_value(&pgpool, 10).await;
+        assert_eq!(reconciled_value(&sender_allocation).await, 68);
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn should_resume_unaggregated_fees_from_checkpoint(pgpool: PgPool) {
+        for i in 1..=10 {
+            let receipt = create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, i, i, i.into());
+            store_receipt(&pgpool, receipt.signed_receipt())
+                .await
+                .unwrap();
+        }
+        // Only the receipts after the checkpoint are summed, the value of the checkpoint is
+        // trusted for the ones before.
+        checkpoint::save_allocation(
+            &pgpool,
+            &AllocationCheckpoint {
+                sender: SENDER.1,
+                allocation_id: *ALLOCATION_ID_0,
+                signers: vec![SIGNER.1.encode_hex::<String>()],
+                unaggregated_fees: UnaggregatedReceipts {
+                    last_id: 5,
+                    value: 1000,
+                },
+                rav_timestamp_ns: None,
+                rav_request_in_flight: false,
+            },
+        )
+        .await
+        .unwrap();
+
+        let sender_allocation =
+            create_sender_allocation(pgpool.clone(), DUMMY_URL.to_string(), DUMMY_URL, None).await;
+        let unaggregated_fees = call!(
+            sender_allocation,
+            SenderAllocationMessage::GetUnaggregatedReceipts
+        )
+        .unwrap();
+        assert_eq!(
+            unaggregated_fees,
+            UnaggregatedReceipts {
+                last_id: 10,
+                value: 1040,
+            }
+        );
+
+        call!(sender_allocation, SenderAllocationMessage::SaveCheckpoint).unwrap();
+        let saved = checkpoint::load_allocation(&pgpool, SENDER.1, *ALLOCATION_ID_0)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(saved.unaggregated_fees, unaggregated_fees);
+
+        // An interrupted RAV request invalidates the checkpoint.
+        checkpoint::set_rav_request_in_flight(&pgpool, SENDER.1, *ALLOCATION_ID_0, true)
+            .await
+            .unwrap();
+        let sender_allocation =
+            create_sender_allocation(pgpool.clone(), DUMMY_URL.to_string(), DUMMY_URL, None).await;
+        let unaggregated_fees = call!(
+            sender_allocation,
+            SenderAllocationMessage::GetUnaggregatedReceipts
+        )
+        .unwrap();
+        assert_eq!(unaggregated_fees.value, 55);
+    }
+
     #[sqlx::test(migrations = "../migrations")]
     async fn should_return_invalid_receipts_on_startup(pgpool: PgPool) {
         let (last_message_emitted, sender_account, _join_handle) =
@@ -995,6 +2158,7 @@ pub mod tests {
                 allocation_id: *ALLOCATION_ID_0,
                 signer_address: SIGNER.1,
                 timestamp_ns: 0,
+                indexer_address: None,
             })
         )
         .unwrap();
@@ -1007,6 +2171,7 @@ pub mod tests {
                 allocation_id: *ALLOCATION_ID_0,
                 signer_address: SIGNER.1,
                 timestamp_ns: 0,
+                indexer_address: None,
             })
         )
         .unwrap();
@@ -1026,6 +2191,132 @@ pub mod tests {
         assert_eq!(last_message_emitted.last(), Some(&expected_message));
     }
 
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_fee_overflow(pgpool: PgPool) {
+        let (last_message_emitted, sender_account, _join_handle) =
+            create_mock_sender_account().await;
+        let sender_allocation = create_sender_allocation(
+            pgpool.clone(),
+            DUMMY_URL.to_string(),
+            DUMMY_URL,
+            Some(sender_account),
+        )
+        .await;
+
+        for (id, value) in [(1, u128::MAX - 10), (2, 20), (3, 5)] {
+            cast!(
+                sender_allocation,
+                SenderAllocationMessage::NewReceipt(NewReceiptNotification {
+                    id,
+                    value,
+                    allocation_id: *ALLOCATION_ID_0,
+                    signer_address: SIGNER.1,
+                    timestamp_ns: 0,
+                    indexer_address: None,
+                })
+            )
+            .unwrap();
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+
+        // Not saturated, and not counting the receipts anymore, even if they wouldn't overflow
+        let unaggregated_fees = call!(
+            sender_allocation,
+            SenderAllocationMessage::GetUnaggregatedReceipts
+        )
+        .unwrap();
+        assert_eq!(
+            unaggregated_fees,
+            UnaggregatedReceipts {
+                last_id: 1,
+                value: u128::MAX - 10,
+            }
+        );
+        assert_eq!(
+            last_message_emitted.lock().unwrap().last(),
+            Some(&SenderAccountMessage::UpdateFeeOverflow(
+                *ALLOCATION_ID_0,
+                true
+            ))
+        );
+        assert!(
+            fee_overflows::is_overflowed(&pgpool, SENDER.1, *ALLOCATION_ID_0)
+                .await
+                .unwrap()
+        );
+        let snapshot = call!(sender_allocation, SenderAllocationMessage::GetSnapshot).unwrap();
+        assert!(snapshot.fee_overflow);
+
+        // Summed from the database once acknowledged
+        fee_overflows::acknowledge(&pgpool, SENDER.1, *ALLOCATION_ID_0, "checked", None)
+            .await
+            .unwrap();
+        cast!(
+            sender_allocation,
+            SenderAllocationMessage::FeeOverflowAcknowledged
+        )
+        .unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert_eq!(
+            last_message_emitted.lock().unwrap().last(),
+            Some(&SenderAccountMessage::UpdateFeeOverflow(
+                *ALLOCATION_ID_0,
+                false
+            ))
+        );
+        let unaggregated_fees = call!(
+            sender_allocation,
+            SenderAllocationMessage::GetUnaggregatedReceipts
+        )
+        .unwrap();
+        assert_eq!(unaggregated_fees, UnaggregatedReceipts::default());
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_idle_eviction(pgpool: PgPool) {
+        let (last_message_emitted, sender_account, _join_handle) =
+            create_mock_sender_account().await;
+
+        let mut args = create_sender_allocation_args(
+            pgpool.clone(),
+            DUMMY_URL.to_string(),
+            DUMMY_URL,
+            Some(sender_account),
+        )
+        .await;
+        let mut config = args.config.clone();
+        config.tap.sender_allocation_idle_timeout = Some(Duration::from_secs(60));
+        args.config = Box::leak(Box::new(config));
+        let clock = Arc::new(VirtualClock::new());
+        args.clock = clock.clone();
+
+        let (sender_allocation, mut join_handle) =
+            SenderAllocation::spawn(None, SenderAllocation, args)
+                .await
+                .unwrap();
+
+        // still active before the idle timeout
+        clock.advance(Duration::from_secs(59));
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), &mut join_handle)
+                .await
+                .is_err()
+        );
+        assert_eq!(sender_allocation.get_status(), ActorStatus::Running);
+
+        // the actor should stop by itself once the idle timeout elapsed
+        clock.advance(Duration::from_secs(60));
+        tokio::time::timeout(Duration::from_secs(1), join_handle)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(sender_allocation.get_status(), ActorStatus::Stopped);
+
+        // only the initial fees were reported, no last RAV was requested
+        let last_message_emitted = last_message_emitted.lock().unwrap();
+        assert_eq!(last_message_emitted.len(), 1);
+    }
+
     #[sqlx::test(migrations = "../migrations")]
     async fn test_trigger_rav_request(pgpool: PgPool) {
         // Start a TAP aggregator server.
@@ -1109,6 +2400,139 @@ pub mod tests {
         handle.stopped().await;
     }
 
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_rav_request_checkpoint_failure(pgpool: PgPool) {
+        let (handle, aggregator_endpoint) = run_server(
+            0,
+            SIGNER.0.clone(),
+            vec![SIGNER.1].into_iter().collect(),
+            TAP_EIP712_DOMAIN_SEPARATOR.clone(),
+            100 * 1024,
+            100 * 1024,
+            1,
+        )
+        .await
+        .unwrap();
+        let mock_server = MockServer::start().await;
+        mock_server
+            .register(
+                Mock::given(method("POST"))
+                    .and(body_string_contains("transactions"))
+                    .respond_with(
+                        ResponseTemplate::new(200)
+                            .set_body_json(json!({ "data": { "transactions": []}})),
+                    ),
+            )
+            .await;
+        for i in 0..10 {
+            let receipt = create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, i, i + 1, i.into());
+            store_receipt(&pgpool, receipt.signed_receipt())
+                .await
+                .unwrap();
+        }
+        let sender_allocation = create_sender_allocation(
+            pgpool.clone(),
+            "http://".to_owned() + &aggregator_endpoint.to_string(),
+            &mock_server.uri(),
+            None,
+        )
+        .await;
+
+        // The checkpoint can't be written, the RAV is still requested and accounted for
+        sqlx::query("DROP TABLE scalar_tap_agent_allocation_checkpoints")
+            .execute(&pgpool)
+            .await
+            .unwrap();
+        let (total_unaggregated_fees, rav) = call!(
+            sender_allocation,
+            SenderAllocationMessage::TriggerRAVRequest
+        )
+        .unwrap();
+        assert_eq!(total_unaggregated_fees.value, 0u128);
+        assert_eq!(rav.unwrap().message.valueAggregate, 45u128);
+
+        handle.stop().unwrap();
+        handle.stopped().await;
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_aggregator_failover(pgpool: PgPool) {
+        let (handle, aggregator_endpoint) = run_server(
+            0,
+            SIGNER.0.clone(),
+            vec![SIGNER.1].into_iter().collect(),
+            TAP_EIP712_DOMAIN_SEPARATOR.clone(),
+            100 * 1024,
+            100 * 1024,
+            1,
+        )
+        .await
+        .unwrap();
+        let mock_server = MockServer::start().await;
+        mock_server
+            .register(
+                Mock::given(method("POST"))
+                    .and(body_string_contains("transactions"))
+                    .respond_with(
+                        ResponseTemplate::new(200)
+                            .set_body_json(json!({ "data": { "transactions": []}})),
+                    ),
+            )
+            .await;
+
+        // The configured endpoint is unreachable, the additional one is up
+        let mut args = create_sender_allocation_args(
+            pgpool.clone(),
+            "http://127.0.0.1:1".to_string(),
+            &mock_server.uri(),
+            None,
+        )
+        .await;
+        let mut config = args.config.clone();
+        config.tap.sender_aggregator_additional_endpoints = HashMap::from([(
+            SENDER.1,
+            vec!["http://".to_owned() + &aggregator_endpoint.to_string()],
+        )]);
+        args.config = Box::leak(Box::new(config));
+        let (sender_allocation, _join_handle) =
+            SenderAllocation::spawn(None, SenderAllocation, args)
+                .await
+                .unwrap();
+
+        // Each request starts from another endpoint
+        for batch in 0..2 {
+            for i in batch * 10..(batch + 1) * 10 {
+                let receipt =
+                    create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, i, i + 1, i.into());
+                store_receipt(&pgpool, receipt.signed_receipt())
+                    .await
+                    .unwrap();
+            }
+            sender_allocation
+                .cast(SenderAllocationMessage::NewReceipt(
+                    NewReceiptNotification {
+                        id: (batch + 1) * 10,
+                        allocation_id: *ALLOCATION_ID_0,
+                        signer_address: SIGNER.1,
+                        timestamp_ns: (batch + 1) * 10,
+                        value: 1,
+                        indexer_address: None,
+                    },
+                ))
+                .unwrap();
+            let (_, rav) = call!(
+                sender_allocation,
+                SenderAllocationMessage::TriggerRAVRequest
+            )
+            .unwrap();
+            let expected_value: u128 = (0..(batch + 1) * 10).map(u128::from).sum();
+            assert_eq!(rav.unwrap().message.valueAggregate, expected_value);
+        }
+
+        handle.stop().unwrap();
+        handle.stopped().await;
+    }
+
     #[sqlx::test(migrations = "../migrations")]
     async fn test_close_allocation_no_pending_fees(pgpool: PgPool) {
         let (last_message_emitted, sender_account, _join_handle) =
@@ -1307,6 +2731,119 @@ pub mod tests {
         assert_eq!(total_unaggregated_fees.value, 35u128);
     }
 
+    #[sqlx::test(migrations = "../migrations")]
+    async fn should_calculate_unaggregated_fees_in_chunks(pgpool: PgPool) {
+        let mut args =
+            create_sender_allocation_args(pgpool.clone(), DUMMY_URL.to_string(), DUMMY_URL, None)
+                .await;
+        let mut config = args.config.clone();
+        config.tap.unaggregated_fees_chunk_size = Some(2);
+        args.config = Box::leak(Box::new(config));
+        let state = SenderAllocationState::new(args).await;
+
+        let signed_rav = create_rav(*ALLOCATION_ID_0, SIGNER.0.clone(), 4, 10);
+        store_rav(&pgpool, signed_rav, SENDER.1).await.unwrap();
+        for i in 1..10 {
+            let receipt = create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, i, i, i.into());
+            store_receipt(&pgpool, receipt.signed_receipt())
+                .await
+                .unwrap();
+        }
+        // Of another allocation, in the same chunks
+        let receipt = create_received_receipt(&ALLOCATION_ID_1, &SIGNER.0, 10, 10, 100);
+        store_receipt(&pgpool, receipt.signed_receipt())
+            .await
+            .unwrap();
+
+        let total_unaggregated_fees = state.calculate_unaggregated_fee().await.unwrap();
+        assert_eq!(total_unaggregated_fees.value, 35u128);
+
+        // The progress is saved after each chunk
+        let saved = checkpoint::load_allocation(&pgpool, SENDER.1, *ALLOCATION_ID_0)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(saved.unaggregated_fees, total_unaggregated_fees);
+        assert_eq!(saved.rav_timestamp_ns, Some(4));
+
+        // Resumed from the checkpoint
+        let receipt = create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, 11, 11, 11);
+        store_receipt(&pgpool, receipt.signed_receipt())
+            .await
+            .unwrap();
+        let resumed = state
+            .sum_unaggregated_fee_in_chunks(saved.unaggregated_fees, 2)
+            .await
+            .unwrap();
+        assert_eq!(resumed.value, 46u128);
+    }
+
+    #[test]
+    fn test_is_size_error() {
+        use jsonrpsee::types::ErrorObject;
+
+        assert!(is_size_error(&jsonrpsee::core::Error::Transport(
+            transport::Error::RequestTooLarge.into()
+        )));
+        assert!(is_size_error(&jsonrpsee::core::Error::Transport(
+            transport::Error::RequestFailure { status_code: 413 }.into()
+        )));
+        assert!(is_size_error(&jsonrpsee::core::Error::Call(
+            ErrorObject::owned(OVERSIZED_RESPONSE_CODE, "Response is too big", None::<()>)
+        )));
+        assert!(!is_size_error(&jsonrpsee::core::Error::Transport(
+            transport::Error::RequestFailure { status_code: 500 }.into()
+        )));
+        assert!(!is_size_error(&jsonrpsee::core::Error::RequestTimeout));
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_local_rav_value(pgpool: PgPool) {
+        for i in 0..10 {
+            let receipt = create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, i, i + 1, i.into());
+            store_receipt(&pgpool, receipt.signed_receipt())
+                .await
+                .unwrap();
+        }
+        let args =
+            create_sender_allocation_args(pgpool.clone(), DUMMY_URL.to_string(), DUMMY_URL, None)
+                .await;
+        let state = SenderAllocationState::new(args).await;
+
+        // Receipts with timestamps 1 to 5.
+        assert_eq!(state.local_rav_value(5).await.unwrap(), 10);
+
+        // The previous RAV plus the receipts with timestamps 4 and 5.
+        store_rav(
+            &pgpool,
+            create_rav(*ALLOCATION_ID_0, SIGNER.0.clone(), 3, 100),
+            SENDER.1,
+        )
+        .await
+        .unwrap();
+        assert_eq!(state.local_rav_value(5).await.unwrap(), 107);
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_final_rav_chunks(pgpool: PgPool) {
+        let mut args =
+            create_sender_allocation_args(pgpool.clone(), DUMMY_URL.to_string(), DUMMY_URL, None)
+                .await;
+        args.config = Box::leak(Box::new(config::Config {
+            tap: config::Tap {
+                final_rav_chunk_receipts: Some(100),
+                ..args.config.tap.clone()
+            },
+            ..args.config.clone()
+        }));
+        let mut state = SenderAllocationState::new(args).await;
+        assert_eq!(state.max_rav_request_receipts(), 1000);
+
+        // The last RAV is requested in smaller chunks
+        state.closing = true;
+        assert_eq!(state.max_rav_request_receipts(), 100);
+    }
+
     #[sqlx::test(migrations = "../migrations")]
     async fn test_store_failed_rav(pgpool: PgPool) {
         let args =
@@ -1359,6 +2896,17 @@ pub mod tests {
 
         // we just store a few and make sure it doesn't fail
         assert!(result.is_ok());
+
+        // along with the reason they failed
+        let error_logs: Vec<String> =
+            sqlx::query_scalar("SELECT error_log FROM scalar_tap_receipts_invalid")
+                .fetch_all(&pgpool)
+                .await
+                .unwrap();
+        assert_eq!(error_logs.len(), 2);
+        assert!(error_logs
+            .iter()
+            .all(|error_log| error_log.contains("Failing check")));
     }
 
     #[sqlx::test(migrations = "../migrations")]
@@ -1407,4 +2955,54 @@ pub mod tests {
         // Check that the unaggregated fees return the same value
         assert_eq!(total_unaggregated_fees.value, 45u128);
     }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_anomalous_aggregator_response(pgpool: PgPool) {
+        // A RAV signed for another allocation
+        let aggregator_server = MockServer::start().await;
+        aggregator_server
+            .register(
+                Mock::given(method("POST"))
+                    .and(body_string_contains("aggregate_receipts"))
+                    .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                        "id": 0,
+                        "jsonrpc": "2.0",
+                        "result": JsonRpcResponse {
+                            data: create_rav(*ALLOCATION_ID_1, SIGNER.0.clone(), 10, 45),
+                            warnings: None,
+                        }
+                    }))),
+            )
+            .await;
+
+        for i in 0..10 {
+            let receipt = create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, i, i + 1, i.into());
+            store_receipt(&pgpool, receipt.signed_receipt())
+                .await
+                .unwrap();
+        }
+        let sender_allocation =
+            create_sender_allocation(pgpool.clone(), aggregator_server.uri(), DUMMY_URL, None)
+                .await;
+
+        let (total_unaggregated_fees, rav) = call!(
+            sender_allocation,
+            SenderAllocationMessage::TriggerRAVRequest
+        )
+        .unwrap();
+
+        // Nothing of the response was used
+        assert_eq!(total_unaggregated_fees.value, 45u128);
+        assert!(rav.is_none());
+        let anomalies: Vec<String> = sqlx::query_scalar(
+            "SELECT anomaly FROM scalar_tap_rav_requests_failed WHERE anomaly IS NOT NULL",
+        )
+        .fetch_all(&pgpool)
+        .await
+        .unwrap();
+        assert!(!anomalies.is_empty());
+        assert!(anomalies
+            .iter()
+            .all(|anomaly| anomaly == "wrong_allocation"));
+    }
 }
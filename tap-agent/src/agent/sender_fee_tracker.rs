@@ -3,7 +3,18 @@
 
 use alloy_primitives::Address;
 use std::collections::{HashMap, HashSet};
-use tracing::error;
+
+/// The fee of an allocation that would overflow the total fee, refused by the tracker.
+#[derive(Debug, PartialEq, thiserror::Error)]
+#[error(
+    "Overflow when adding fee {fee} of allocation {allocation_id} to the total fee {total_fee}"
+)]
+pub struct TotalFeeOverflow {
+    pub allocation_id: Address,
+    pub fee: u128,
+    /// Total fee of the other allocations.
+    pub total_fee: u128,
+}
 
 #[derive(Debug, Clone, Default)]
 pub struct SenderFeeTracker {
@@ -16,24 +27,23 @@ pub struct SenderFeeTracker {
 }
 
 impl SenderFeeTracker {
-    pub fn update(&mut self, id: Address, fee: u128) {
+    /// Sets the fee of the allocation `id`, removing it when 0. A fee overflowing the total is
+    /// refused, the allocation keeping its previous fee.
+    pub fn update(&mut self, id: Address, fee: u128) -> Result<(), TotalFeeOverflow> {
+        let old_fee = self.id_to_fee.get(&id).copied().unwrap_or_default();
+        // The total is the sum of the fees, it can't underflow
+        let other_fees = self.total_fee - old_fee;
+        self.total_fee = other_fees.checked_add(fee).ok_or(TotalFeeOverflow {
+            allocation_id: id,
+            fee,
+            total_fee: other_fees,
+        })?;
         if fee > 0 {
-            // insert or update, if update remove old fee from total
-            if let Some(old_fee) = self.id_to_fee.insert(id, fee) {
-                self.total_fee -= old_fee;
-            }
-            self.total_fee = self.total_fee.checked_add(fee).unwrap_or_else(|| {
-                // This should never happen, but if it does, we want to know about it.
-                error!(
-                    "Overflow when adding receipt value {} to total fee {}. \
-                        Setting total fee to u128::MAX.",
-                    fee, self.total_fee
-                );
-                u128::MAX
-            });
-        } else if let Some(old_fee) = self.id_to_fee.remove(&id) {
-            self.total_fee -= old_fee;
+            self.id_to_fee.insert(id, fee);
+        } else {
+            self.id_to_fee.remove(&id);
         }
+        Ok(())
     }
 
     pub fn block_allocation_id(&mut self, address: Address) {
@@ -44,6 +54,10 @@ impl SenderFeeTracker {
         self.blocked_addresses.remove(&address);
     }
 
+    pub fn is_blocked(&self, address: &Address) -> bool {
+        self.blocked_addresses.contains(address)
+    }
+
     pub fn get_heaviest_allocation_id(&self) -> Option<Address> {
         // just loop over and get the biggest fee
         self.id_to_fee
@@ -67,6 +81,10 @@ impl SenderFeeTracker {
         self.id_to_fee.keys().cloned().collect()
     }
 
+    pub fn get_fee(&self, id: &Address) -> u128 {
+        self.id_to_fee.get(id).copied().unwrap_or_default()
+    }
+
     pub fn get_total_fee(&self) -> u128 {
         self.total_fee
     }
@@ -74,7 +92,7 @@ impl SenderFeeTracker {
 
 #[cfg(test)]
 mod tests {
-    use super::SenderFeeTracker;
+    use super::{SenderFeeTracker, TotalFeeOverflow};
     use std::str::FromStr;
     use thegraph::types::Address;
 
@@ -91,7 +109,7 @@ mod tests {
         assert_eq!(tracker.get_heaviest_allocation_id(), None);
         assert_eq!(tracker.get_total_fee(), 0);
 
-        tracker.update(allocation_id_0, 10);
+        tracker.update(allocation_id_0, 10).unwrap();
         assert_eq!(tracker.get_heaviest_allocation_id(), Some(allocation_id_0));
         assert_eq!(tracker.get_total_fee(), 10);
 
@@ -102,7 +120,7 @@ mod tests {
         tracker.unblock_allocation_id(allocation_id_0);
         assert_eq!(tracker.get_heaviest_allocation_id(), Some(allocation_id_0));
 
-        tracker.update(allocation_id_2, 20);
+        tracker.update(allocation_id_2, 20).unwrap();
         assert_eq!(tracker.get_heaviest_allocation_id(), Some(allocation_id_2));
         assert_eq!(tracker.get_total_fee(), 30);
 
@@ -113,28 +131,55 @@ mod tests {
         tracker.unblock_allocation_id(allocation_id_2);
         assert_eq!(tracker.get_heaviest_allocation_id(), Some(allocation_id_2));
 
-        tracker.update(allocation_id_1, 30);
+        tracker.update(allocation_id_1, 30).unwrap();
         assert_eq!(tracker.get_heaviest_allocation_id(), Some(allocation_id_1));
         assert_eq!(tracker.get_total_fee(), 60);
 
-        tracker.update(allocation_id_2, 10);
+        tracker.update(allocation_id_2, 10).unwrap();
         assert_eq!(tracker.get_heaviest_allocation_id(), Some(allocation_id_1));
         assert_eq!(tracker.get_total_fee(), 50);
 
-        tracker.update(allocation_id_2, 40);
+        tracker.update(allocation_id_2, 40).unwrap();
         assert_eq!(tracker.get_heaviest_allocation_id(), Some(allocation_id_2));
         assert_eq!(tracker.get_total_fee(), 80);
 
-        tracker.update(allocation_id_1, 0);
+        tracker.update(allocation_id_1, 0).unwrap();
         assert_eq!(tracker.get_heaviest_allocation_id(), Some(allocation_id_2));
         assert_eq!(tracker.get_total_fee(), 50);
 
-        tracker.update(allocation_id_2, 0);
+        tracker.update(allocation_id_2, 0).unwrap();
         assert_eq!(tracker.get_heaviest_allocation_id(), Some(allocation_id_0));
         assert_eq!(tracker.get_total_fee(), 10);
 
-        tracker.update(allocation_id_0, 0);
+        tracker.update(allocation_id_0, 0).unwrap();
         assert_eq!(tracker.get_heaviest_allocation_id(), None);
         assert_eq!(tracker.get_total_fee(), 0);
     }
+
+    #[test]
+    fn test_total_fee_overflow() {
+        let allocation_id_0 = Address::from([0xab; 20]);
+        let allocation_id_1 = Address::from([0xbc; 20]);
+
+        let mut tracker = SenderFeeTracker::default();
+        tracker.update(allocation_id_0, u128::MAX - 10).unwrap();
+        tracker.update(allocation_id_1, 10).unwrap();
+        assert_eq!(tracker.get_total_fee(), u128::MAX);
+
+        assert_eq!(
+            tracker.update(allocation_id_1, 11),
+            Err(TotalFeeOverflow {
+                allocation_id: allocation_id_1,
+                fee: 11,
+                total_fee: u128::MAX - 10,
+            })
+        );
+        // The allocation keeps its previous fee
+        assert_eq!(tracker.get_fee(&allocation_id_1), 10);
+        assert_eq!(tracker.get_total_fee(), u128::MAX);
+
+        tracker.update(allocation_id_0, 0).unwrap();
+        tracker.update(allocation_id_1, 11).unwrap();
+        assert_eq!(tracker.get_total_fee(), 11);
+    }
 }
@@ -0,0 +1,257 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Graceful shutdown of the agent on SIGTERM and SIGINT. When configured, the running sender
+//! allocations holding enough unaggregated fees request a last RAV before the actors are
+//! stopped, rather than leaving their fees unaggregated until the agent starts again. The last
+//! RAV requests are bounded by a timeout, and cut short by a second signal. The checkpoints are
+//! then saved, and the actors stopped without running their shutdown logic.
+
+use futures_util::{stream, StreamExt};
+use ractor::{call, call_t, ActorRef, ActorStatus};
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::time::Instant;
+use tracing::{error, info, warn};
+
+use crate::actor_topology::SenderAllocationSnapshot;
+use crate::agent::sender_account::SenderAccountMessage;
+use crate::agent::sender_accounts_manager::SenderAccountsManagerMessage;
+use crate::agent::sender_allocation::SenderAllocationMessage;
+use crate::config::ShutdownRavFlush;
+
+const SNAPSHOT_TIMEOUT_MS: u64 = 2000;
+
+/// Last RAV requests sent at the same time, so that the aggregators aren't flooded.
+const MAX_CONCURRENT_RAV_REQUESTS: usize = 16;
+
+/// The signals stopping the agent.
+pub struct ShutdownSignals {
+    interrupt: Signal,
+    terminate: Signal,
+}
+
+impl ShutdownSignals {
+    /// Handles SIGINT and SIGTERM from now on, instead of being killed by them.
+    pub fn install() -> std::io::Result<Self> {
+        Ok(Self {
+            interrupt: signal(SignalKind::interrupt())?,
+            terminate: signal(SignalKind::terminate())?,
+        })
+    }
+
+    /// Waits for the next SIGINT or SIGTERM, returning its name.
+    pub async fn recv(&mut self) -> &'static str {
+        tokio::select! {
+            _ = self.interrupt.recv() => "SIGINT",
+            _ = self.terminate.recv() => "SIGTERM",
+        }
+    }
+}
+
+/// Outcome of the last RAV requests.
+#[derive(Debug, Default, PartialEq)]
+pub struct FlushReport {
+    /// Sender allocations that requested a last RAV.
+    pub requested: usize,
+    /// Of them, the ones that didn't answer.
+    pub failed: usize,
+    /// Unaggregated fees of the sender allocations before and after their last RAV, in GRT
+    /// wei, capped at `u128::MAX` as they are only reported. The fees of the receipts within the
+    /// timestamp buffer stay unaggregated.
+    pub unaggregated_fees_before: u128,
+    pub unaggregated_fees_after: u128,
+}
+
+/// Stops the agent under `manager`, once the last RAVs are requested if `flush` is set.
+pub async fn stop(
+    manager: ActorRef<SenderAccountsManagerMessage>,
+    flush: Option<&ShutdownRavFlush>,
+    signals: &mut ShutdownSignals,
+) {
+    if manager.get_status() != ActorStatus::Running {
+        return;
+    }
+    if let Some(flush) = flush {
+        tokio::select! {
+            _ = flush_ravs(&manager, flush) => {}
+            signal = signals.recv() => warn!(
+                "Received {} while requesting the last RAVs, stopping right away.",
+                signal
+            ),
+        }
+    }
+
+    // Save the last processed receipt and the state of each allocation so that the next start
+    // resumes from there.
+    if let Err(e) = call!(manager, SenderAccountsManagerMessage::SaveCheckpoint) {
+        error!("Failed to save checkpoint: {:?}", e);
+    }
+    manager
+        .kill_and_wait(None)
+        .await
+        .expect("Failed to kill manager.");
+}
+
+/// Requests a last RAV for the running sender allocations under `manager` holding at least
+/// `flush.min_unaggregated_fees`, waiting for them for up to `flush.timeout`.
+pub async fn flush_ravs(
+    manager: &ActorRef<SenderAccountsManagerMessage>,
+    flush: &ShutdownRavFlush,
+) -> Option<FlushReport> {
+    let start = Instant::now();
+    let flushed = tokio::time::timeout(flush.timeout, async {
+        let sender_allocations = sender_allocations(manager)
+            .await
+            .into_iter()
+            .filter(|(_, snapshot)| needs_last_rav(snapshot, flush.min_unaggregated_fees))
+            .collect::<Vec<_>>();
+        info!(
+            sender_allocations = sender_allocations.len(),
+            "Requesting the last RAVs before shutting down."
+        );
+        stream::iter(sender_allocations)
+            .map(|(sender_allocation, snapshot)| async move {
+                let fees_before = unaggregated_fees(&snapshot);
+                let result = call!(
+                    sender_allocation,
+                    SenderAllocationMessage::TriggerRAVRequest
+                );
+                (snapshot.allocation_id, fees_before, result)
+            })
+            .buffer_unordered(MAX_CONCURRENT_RAV_REQUESTS)
+            .fold(
+                FlushReport::default(),
+                |mut report, (allocation_id, fees_before, result)| async move {
+                    report.requested += 1;
+                    report.unaggregated_fees_before =
+                        report.unaggregated_fees_before.saturating_add(fees_before);
+                    match result {
+                        Ok((fees, _)) => {
+                            report.unaggregated_fees_after =
+                                report.unaggregated_fees_after.saturating_add(fees.value)
+                        }
+                        Err(e) => {
+                            warn!(
+                                %allocation_id,
+                                error = %e,
+                                "Failed to request the last RAV of the sender allocation."
+                            );
+                            report.failed += 1;
+                            report.unaggregated_fees_after =
+                                report.unaggregated_fees_after.saturating_add(fees_before);
+                        }
+                    }
+                    report
+                },
+            )
+            .await
+    })
+    .await;
+
+    match flushed {
+        Ok(report) => {
+            info!(
+                requested = report.requested,
+                failed = report.failed,
+                unaggregated_fees_before = %report.unaggregated_fees_before,
+                unaggregated_fees_after = %report.unaggregated_fees_after,
+                elapsed = ?start.elapsed(),
+                "Requested the last RAVs."
+            );
+            Some(report)
+        }
+        Err(_) => {
+            warn!(
+                timeout = ?flush.timeout,
+                "Timed out requesting the last RAVs, the remaining fees stay unaggregated until \
+                the next start."
+            );
+            None
+        }
+    }
+}
+
+/// The running sender allocations under `manager`, along with their snapshot. The actors not
+/// answering in time are skipped.
+async fn sender_allocations(
+    manager: &ActorRef<SenderAccountsManagerMessage>,
+) -> Vec<(ActorRef<SenderAllocationMessage>, SenderAllocationSnapshot)> {
+    let mut sender_allocations = Vec::new();
+    let Ok(manager_snapshot) = call_t!(
+        manager,
+        SenderAccountsManagerMessage::GetSnapshot,
+        SNAPSHOT_TIMEOUT_MS
+    ) else {
+        return sender_allocations;
+    };
+    for sender_account_name in manager_snapshot.sender_accounts {
+        let Some(sender_account) = ActorRef::<SenderAccountMessage>::where_is(sender_account_name)
+        else {
+            continue;
+        };
+        let Ok(account_snapshot) = call_t!(
+            sender_account,
+            SenderAccountMessage::GetSnapshot,
+            SNAPSHOT_TIMEOUT_MS
+        ) else {
+            continue;
+        };
+        for sender_allocation_name in account_snapshot.sender_allocations {
+            let Some(sender_allocation) =
+                ActorRef::<SenderAllocationMessage>::where_is(sender_allocation_name)
+            else {
+                continue;
+            };
+            if let Ok(snapshot) = call_t!(
+                sender_allocation,
+                SenderAllocationMessage::GetSnapshot,
+                SNAPSHOT_TIMEOUT_MS
+            ) {
+                sender_allocations.push((sender_allocation, snapshot));
+            }
+        }
+    }
+    sender_allocations
+}
+
+fn unaggregated_fees(snapshot: &SenderAllocationSnapshot) -> u128 {
+    snapshot.unaggregated_fees.parse().unwrap_or_default()
+}
+
+/// Whether the sender allocation requests a last RAV. The ones whose fees overflowed don't
+/// request RAVs until the overflow is acknowledged.
+fn needs_last_rav(snapshot: &SenderAllocationSnapshot, min_unaggregated_fees: u128) -> bool {
+    !snapshot.fee_overflow && unaggregated_fees(snapshot) >= min_unaggregated_fees
+}
+
+#[cfg(test)]
+mod tests {
+    use thegraph::types::Address;
+
+    use super::*;
+
+    fn snapshot(unaggregated_fees: u128, fee_overflow: bool) -> SenderAllocationSnapshot {
+        SenderAllocationSnapshot {
+            allocation_id: Address::from([0x01; 20]),
+            unaggregated_fees: unaggregated_fees.to_string(),
+            last_receipt_id: 0,
+            invalid_receipts_fees: "0".to_string(),
+            last_rav_timestamp_ns: None,
+            last_rav_value: None,
+            rav_request_receipt_limit: 1000,
+            rav_request_failures: 0,
+            fee_overflow,
+            idle_secs: 0,
+            messages_handled: 0,
+        }
+    }
+
+    #[test]
+    fn test_needs_last_rav() {
+        assert!(needs_last_rav(&snapshot(100, false), 100));
+        assert!(needs_last_rav(&snapshot(u128::MAX, false), 100));
+        assert!(!needs_last_rav(&snapshot(99, false), 100));
+        assert!(!needs_last_rav(&snapshot(0, false), 1));
+        assert!(!needs_last_rav(&snapshot(1000, true), 100));
+    }
+}
@@ -0,0 +1,306 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Analysis of a sender's unaggregated fees and RAV requests over a rolling window, suggesting a
+//! RAV request trigger value that better fits its traffic. Fees that keep sitting just below the
+//! trigger value are never aggregated, so a lower one is suggested. RAV requests that follow
+//! each other too closely load the aggregator for little value, so a higher one is suggested.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::RwLock;
+
+use lazy_static::lazy_static;
+use prometheus::{register_gauge_vec, GaugeVec};
+use serde::Serialize;
+use thegraph::types::Address;
+use tokio::time::Instant;
+
+use crate::config::TriggerValueTuning;
+
+lazy_static! {
+    static ref TRIGGER_VALUE_SUGGESTION: GaugeVec = register_gauge_vec!(
+        format!("tap_rav_trigger_value_suggestion"),
+        "RAV request trigger value suggested from the sender's unaggregated fees and RAV requests",
+        &["sender"]
+    )
+    .unwrap();
+}
+
+lazy_static! {
+    /// Trigger value of every sender analyzed, served by the tap-agent HTTP server.
+    pub static ref TRIGGER_VALUE_STATUSES: RwLock<BTreeMap<Address, TriggerValueStatus>> =
+        RwLock::new(BTreeMap::new());
+}
+
+/// Unaggregated fees at or above this share of the trigger value sit just below it.
+const NEAR_TRIGGER_RATIO: f64 = 0.8;
+/// Share of the window the fees must sit just below the trigger value to suggest a lower one.
+const PERSISTENT_SHARE: f64 = 0.5;
+/// RAV requests needed in the window before judging their frequency.
+const MIN_RAV_REQUESTS: usize = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SuggestionReason {
+    FeesBelowTrigger,
+    FrequentRavRequests,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Suggestion {
+    pub trigger_value: u128,
+    pub reason: SuggestionReason,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TriggerValueStatus {
+    /// Trigger value in use, in GRT wei.
+    pub trigger_value: String,
+    pub suggested_trigger_value: Option<String>,
+    pub reason: Option<SuggestionReason>,
+    /// Whether suggestions are applied automatically.
+    pub auto_apply: bool,
+}
+
+#[derive(Debug)]
+pub struct TriggerTuner {
+    config: TriggerValueTuning,
+    /// Total unaggregated fees, each value holding until the next one.
+    fees: VecDeque<(Instant, u128)>,
+    rav_requests: VecDeque<Instant>,
+}
+
+impl TriggerTuner {
+    pub fn new(config: TriggerValueTuning) -> Self {
+        Self {
+            config,
+            fees: VecDeque::new(),
+            rav_requests: VecDeque::new(),
+        }
+    }
+
+    pub fn auto_apply(&self) -> bool {
+        self.config.auto_apply
+    }
+
+    pub fn record_fees(&mut self, now: Instant, total_fee: u128) {
+        if self.fees.back().map(|(_, fee)| *fee) != Some(total_fee) {
+            self.fees.push_back((now, total_fee));
+        }
+    }
+
+    pub fn record_rav_request(&mut self, now: Instant) {
+        self.rav_requests.push_back(now);
+    }
+
+    /// Forgets the analyzed history, which no longer reflects a newly applied trigger value.
+    pub fn reset(&mut self) {
+        let last_fee = self.fees.pop_back();
+        self.fees.clear();
+        self.fees.extend(last_fee);
+        self.rav_requests.clear();
+    }
+
+    /// Clamps a suggested trigger value to the bounds it can be applied within.
+    pub fn bounded(&self, trigger_value: u128) -> u128 {
+        trigger_value.clamp(self.config.min_trigger_value, self.config.max_trigger_value)
+    }
+
+    pub fn suggest(&mut self, now: Instant, trigger_value: u128) -> Option<Suggestion> {
+        let window_start = now.checked_sub(self.config.window).unwrap_or(now);
+        while self.fees.len() > 1 && self.fees[1].0 <= window_start {
+            self.fees.pop_front();
+        }
+        while self
+            .rav_requests
+            .front()
+            .is_some_and(|requested_at| *requested_at < window_start)
+        {
+            self.rav_requests.pop_front();
+        }
+
+        if self.rav_requests.len() >= MIN_RAV_REQUESTS {
+            let first = self.rav_requests.front()?;
+            let last = self.rav_requests.back()?;
+            let average_interval =
+                (*last - *first).as_secs_f64() / (self.rav_requests.len() - 1) as f64;
+            let min_interval = self.config.min_rav_request_interval.as_secs_f64();
+            if average_interval < min_interval {
+                let ratio = min_interval / average_interval.max(f64::EPSILON);
+                return Some(Suggestion {
+                    trigger_value: (trigger_value as f64 * ratio) as u128,
+                    reason: SuggestionReason::FrequentRavRequests,
+                });
+            }
+            return None;
+        }
+        // Fees reaching the trigger value are aggregated
+        if !self.rav_requests.is_empty() {
+            return None;
+        }
+
+        let (first_change, _) = self.fees.front()?;
+        let observed_from = (*first_change).max(window_start);
+        let observed = now - observed_from;
+        if observed < self.config.window / 2 {
+            return None;
+        }
+        let near_trigger = (trigger_value as f64 * NEAR_TRIGGER_RATIO) as u128;
+        let mut time_near_trigger = 0.0;
+        let mut lowest_near_trigger = None;
+        for (i, (changed_at, fee)) in self.fees.iter().enumerate() {
+            if *fee < near_trigger || *fee >= trigger_value {
+                continue;
+            }
+            let until = self.fees.get(i + 1).map_or(now, |(next, _)| *next);
+            time_near_trigger += (until - (*changed_at).max(observed_from)).as_secs_f64();
+            lowest_near_trigger =
+                Some(lowest_near_trigger.map_or(*fee, |lowest: u128| lowest.min(*fee)));
+        }
+        if time_near_trigger / observed.as_secs_f64() < PERSISTENT_SHARE {
+            return None;
+        }
+        lowest_near_trigger.map(|trigger_value| Suggestion {
+            trigger_value,
+            reason: SuggestionReason::FeesBelowTrigger,
+        })
+    }
+}
+
+/// Reports the trigger value and suggestion of a sender, logging the suggestion when it changes.
+pub fn publish(
+    sender: Address,
+    trigger_value: u128,
+    suggestion: Option<Suggestion>,
+    auto_apply: bool,
+) {
+    let status = TriggerValueStatus {
+        trigger_value: trigger_value.to_string(),
+        suggested_trigger_value: suggestion.map(|suggestion| suggestion.trigger_value.to_string()),
+        reason: suggestion.map(|suggestion| suggestion.reason),
+        auto_apply,
+    };
+    let previous = TRIGGER_VALUE_STATUSES
+        .write()
+        .unwrap()
+        .insert(sender, status.clone());
+    if previous.as_ref() == Some(&status) {
+        return;
+    }
+
+    match suggestion {
+        Some(suggestion) => {
+            tracing::info!(
+                %sender,
+                trigger_value,
+                suggested_trigger_value = suggestion.trigger_value,
+                reason = ?suggestion.reason,
+                "Suggesting a new RAV request trigger value."
+            );
+            TRIGGER_VALUE_SUGGESTION
+                .with_label_values(&[&sender.to_string()])
+                .set(suggestion.trigger_value as f64);
+        }
+        None => {
+            let _ = TRIGGER_VALUE_SUGGESTION.remove_label_values(&[&sender.to_string()]);
+        }
+    }
+}
+
+/// Stops reporting a sender that is no longer tracked.
+pub fn unpublish(sender: Address) {
+    TRIGGER_VALUE_STATUSES.write().unwrap().remove(&sender);
+    let _ = TRIGGER_VALUE_SUGGESTION.remove_label_values(&[&sender.to_string()]);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    const TRIGGER_VALUE: u128 = 1000;
+
+    /// Leaves room for a whole window before the first instant of the tests.
+    fn start() -> Instant {
+        Instant::now() + Duration::from_secs(3600)
+    }
+
+    fn tuner() -> TriggerTuner {
+        TriggerTuner::new(TriggerValueTuning {
+            window: Duration::from_secs(100),
+            min_rav_request_interval: Duration::from_secs(10),
+            auto_apply: false,
+            min_trigger_value: 500,
+            max_trigger_value: 4000,
+        })
+    }
+
+    #[test]
+    fn test_fees_below_trigger() {
+        let start = start();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut tuner = tuner();
+
+        tuner.record_fees(at(0), 100);
+        tuner.record_fees(at(10), 850);
+        tuner.record_fees(at(40), 900);
+        // Not enough of the window observed yet
+        assert_eq!(tuner.suggest(at(40), TRIGGER_VALUE), None);
+
+        assert_eq!(
+            tuner.suggest(at(100), TRIGGER_VALUE),
+            Some(Suggestion {
+                trigger_value: 850,
+                reason: SuggestionReason::FeesBelowTrigger,
+            })
+        );
+
+        // The fees are aggregated again
+        tuner.record_rav_request(at(101));
+        tuner.record_fees(at(101), 0);
+        assert_eq!(tuner.suggest(at(102), TRIGGER_VALUE), None);
+    }
+
+    #[test]
+    fn test_fees_far_below_trigger() {
+        let start = start();
+        let mut tuner = tuner();
+
+        tuner.record_fees(start, 100);
+        tuner.record_fees(start + Duration::from_secs(80), 900);
+        assert_eq!(
+            tuner.suggest(start + Duration::from_secs(100), TRIGGER_VALUE),
+            None
+        );
+    }
+
+    #[test]
+    fn test_frequent_rav_requests() {
+        let start = start();
+        let mut tuner = tuner();
+
+        for secs in [0, 5, 10] {
+            tuner.record_rav_request(start + Duration::from_secs(secs));
+        }
+        assert_eq!(
+            tuner.suggest(start + Duration::from_secs(10), TRIGGER_VALUE),
+            Some(Suggestion {
+                trigger_value: 2000,
+                reason: SuggestionReason::FrequentRavRequests,
+            })
+        );
+        assert_eq!(tuner.bounded(10_000), 4000);
+
+        // Old requests leave the window
+        tuner.record_rav_request(start + Duration::from_secs(150));
+        assert_eq!(
+            tuner.suggest(start + Duration::from_secs(150), TRIGGER_VALUE),
+            None
+        );
+
+        tuner.reset();
+        assert!(tuner.rav_requests.is_empty());
+    }
+}
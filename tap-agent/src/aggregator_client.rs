@@ -0,0 +1,175 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! JSON-RPC requests to the senders' TAP aggregators, sent by an HTTP client of
+//! [indexer_common::http_proxy] so that they go through the configured proxies. They fail with
+//! the errors of jsonrpsee's HTTP client, within its size limits, for the callers to tell the
+//! oversized requests and the unreachable endpoints apart.
+
+use std::time::Duration;
+
+use jsonrpsee::{
+    core::{traits::ToRpcParams, Error},
+    http_client::transport,
+    types::ErrorObjectOwned,
+};
+use reqwest::header;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+
+/// Size limit of the requests and responses by default, the one of jsonrpsee.
+const DEFAULT_MAX_SIZE: u32 = 10 * 1024 * 1024;
+
+pub struct AggregatorClient {
+    http_client: reqwest::Client,
+    endpoint: String,
+    timeout: Duration,
+    max_request_size: u32,
+    max_response_size: u32,
+}
+
+impl AggregatorClient {
+    pub fn new(http_client: reqwest::Client, endpoint: &str, timeout: Duration) -> Self {
+        Self {
+            http_client,
+            endpoint: endpoint.to_string(),
+            timeout,
+            max_request_size: DEFAULT_MAX_SIZE,
+            max_response_size: DEFAULT_MAX_SIZE,
+        }
+    }
+
+    pub fn with_size_limits(mut self, max_request_size: u32, max_response_size: u32) -> Self {
+        self.max_request_size = max_request_size;
+        self.max_response_size = max_response_size;
+        self
+    }
+
+    pub async fn request<R, Params>(&self, method: &str, params: Params) -> Result<R, Error>
+    where
+        R: DeserializeOwned,
+        Params: ToRpcParams + Send,
+    {
+        let mut body = json!({ "jsonrpc": "2.0", "id": 0, "method": method });
+        if let Some(params) = params.to_rpc_params()? {
+            body["params"] = serde_json::from_str(params.get())?;
+        }
+        let body = body.to_string();
+        if body.len() > self.max_request_size as usize {
+            return Err(Error::Transport(transport::Error::RequestTooLarge.into()));
+        }
+
+        let mut response = self
+            .http_client
+            .post(&self.endpoint)
+            .timeout(self.timeout)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(body)
+            .send()
+            .await
+            .map_err(request_error)?;
+        if !response.status().is_success() {
+            return Err(Error::Transport(
+                transport::Error::RequestFailure {
+                    status_code: response.status().as_u16(),
+                }
+                .into(),
+            ));
+        }
+        let mut body = Vec::new();
+        while let Some(chunk) = response.chunk().await.map_err(request_error)? {
+            if body.len() + chunk.len() > self.max_response_size as usize {
+                return Err(Error::Transport(transport::Error::RequestTooLarge.into()));
+            }
+            body.extend_from_slice(&chunk);
+        }
+
+        let mut response: Value = serde_json::from_slice(&body)?;
+        if let Some(error) = response.get_mut("error") {
+            return Err(Error::Call(serde_json::from_value::<ErrorObjectOwned>(
+                error.take(),
+            )?));
+        }
+        match response.get_mut("result") {
+            Some(result) => Ok(serde_json::from_value(result.take())?),
+            None => Err(Error::Custom(
+                "Response of the aggregator has neither a result nor an error".to_string(),
+            )),
+        }
+    }
+}
+
+fn request_error(error: reqwest::Error) -> Error {
+    if error.is_timeout() {
+        Error::RequestTimeout
+    } else {
+        Error::Transport(error.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use jsonrpsee::rpc_params;
+    use reqwest::Proxy;
+    use wiremock::{
+        matchers::{body_partial_json, method},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_request_through_proxy() {
+        let proxy = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(body_partial_json(json!({ "method": "api_versions" })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(
+                json!({ "jsonrpc": "2.0", "id": 0, "result": { "versions_supported": ["0.0"] } }),
+            ))
+            .expect(1)
+            .mount(&proxy)
+            .await;
+        Mock::given(method("POST"))
+            .and(body_partial_json(json!({ "method": "aggregate_receipts" })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "jsonrpc": "2.0",
+                "id": 0,
+                "error": { "code": -32701, "message": "Request is too big" },
+            })))
+            .mount(&proxy)
+            .await;
+        let client = reqwest::Client::builder()
+            .proxy(Proxy::all(proxy.uri()).unwrap())
+            .build()
+            .unwrap();
+        // Only reachable through the proxy
+        let aggregator =
+            AggregatorClient::new(client, "http://aggregator.invalid/", Duration::from_secs(5));
+
+        let versions: Value = aggregator
+            .request("api_versions", rpc_params!())
+            .await
+            .unwrap();
+        assert_eq!(versions, json!({ "versions_supported": ["0.0"] }));
+
+        let error = aggregator
+            .request::<Value, _>("aggregate_receipts", rpc_params!("0.0"))
+            .await
+            .unwrap_err();
+        assert!(matches!(error, Error::Call(e) if e.code() == -32701));
+
+        let error = aggregator
+            .with_size_limits(16, DEFAULT_MAX_SIZE)
+            .request::<Value, _>("aggregate_receipts", rpc_params!("0.0"))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            Error::Transport(e)
+                if matches!(
+                    e.downcast_ref::<transport::Error>(),
+                    Some(transport::Error::RequestTooLarge)
+                )
+        ));
+    }
+}
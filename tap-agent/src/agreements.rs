@@ -0,0 +1,303 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Periodically requests RAVs for the fees accrued under indexing agreements, from the same
+//! sender aggregators used for query fees. The agreements and their accrued fees are recorded
+//! by the indexer-service, see [`indexer_common::agreements`].
+//!
+//! Experimental, only enabled by `tap.indexing_agreements`: the RAVs are requested with the
+//! `aggregate_indexing_fees` method, which the senders' aggregators must implement on top of
+//! the TAP aggregator API, as the TAP aggregator only aggregates receipts.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use alloy_primitives::hex::ToHex;
+use alloy_sol_types::Eip712Domain;
+use anyhow::{anyhow, bail, ensure, Result};
+use bigdecimal::ToPrimitive;
+use eventuals::Eventual;
+use indexer_common::agreements::{self, StoredAgreement};
+use indexer_common::escrow_accounts::EscrowAccounts;
+use indexer_common::types::{AllocationIdHex, GrtWei, SenderAddress};
+use jsonrpsee::rpc_params;
+use sqlx::{types::BigDecimal, PgPool, Row};
+use tap_aggregator::jsonrpsee_helpers::JsonRpcResponse;
+use tap_core::rav::{ReceiptAggregateVoucher, SignedRAV};
+use thegraph::types::Address;
+use tracing::{error, info, warn};
+
+use crate::aggregator_client::AggregatorClient;
+use crate::scheduler::Job;
+
+pub struct AgreementRavs {
+    pub pgpool: PgPool,
+    pub escrow_accounts: Eventual<EscrowAccounts>,
+    pub domain_separator: Eip712Domain,
+    pub sender_aggregator_endpoints: HashMap<Address, String>,
+    pub http_client: reqwest::Client,
+    pub request_timeout: Duration,
+}
+
+#[async_trait::async_trait]
+impl Job for AgreementRavs {
+    fn name(&self) -> &'static str {
+        "indexing_agreement_ravs"
+    }
+
+    fn default_schedule(&self) -> &'static str {
+        "*/10 * * * *"
+    }
+
+    async fn run(&self) -> Result<()> {
+        let escrow_accounts = self
+            .escrow_accounts
+            .value_immediate()
+            .ok_or_else(|| anyhow!("Escrow accounts are not available yet"))?;
+
+        let mut failures = 0;
+        for agreement in agreements::active_agreements(&self.pgpool).await? {
+            let previous_rav = self.latest_rav(&agreement).await?;
+            if previous_rav
+                .as_ref()
+                .is_some_and(|rav| rav.message.valueAggregate >= agreement.accrued_fees)
+            {
+                continue;
+            }
+            if let Err(e) = self
+                .request_rav(&escrow_accounts, &agreement, previous_rav)
+                .await
+            {
+                error!(
+                    agreement_id = %agreement.agreement.message.agreementId,
+                    error = %e,
+                    "Failed to request RAV for indexing agreement."
+                );
+                failures += 1;
+            }
+        }
+        if failures > 0 {
+            bail!("Failed to request RAVs for {failures} indexing agreements");
+        }
+        Ok(())
+    }
+}
+
+impl AgreementRavs {
+    async fn latest_rav(&self, agreement: &StoredAgreement) -> Result<Option<SignedRAV>> {
+        let row = sqlx::query(
+            r#"
+                SELECT signature, allocation_id, timestamp_ns, value_aggregate
+                FROM indexing_agreement_ravs
+                WHERE agreement_id = $1
+            "#,
+        )
+        .bind(
+            agreement
+                .agreement
+                .message
+                .agreementId
+                .encode_hex::<String>(),
+        )
+        .fetch_optional(&self.pgpool)
+        .await?;
+
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        Ok(Some(SignedRAV {
+            message: ReceiptAggregateVoucher {
+                allocationId: row.try_get::<AllocationIdHex, _>("allocation_id")?.0,
+                timestampNs: row
+                    .try_get::<BigDecimal, _>("timestamp_ns")?
+                    .to_u64()
+                    .ok_or_else(|| anyhow!("Invalid RAV timestamp"))?,
+                valueAggregate: row.try_get::<GrtWei, _>("value_aggregate")?.0,
+            },
+            signature: row
+                .try_get::<Vec<u8>, _>("signature")?
+                .as_slice()
+                .try_into()?,
+        }))
+    }
+
+    async fn request_rav(
+        &self,
+        escrow_accounts: &EscrowAccounts,
+        StoredAgreement {
+            agreement,
+            signer,
+            accrued_fees,
+        }: &StoredAgreement,
+        previous_rav: Option<SignedRAV>,
+    ) -> Result<()> {
+        let sender = escrow_accounts.get_sender_for_signer(signer)?;
+        let endpoint = self
+            .sender_aggregator_endpoints
+            .get(&sender)
+            .ok_or_else(|| anyhow!("No aggregator endpoint for sender {sender}"))?;
+
+        let client =
+            AggregatorClient::new(self.http_client.clone(), endpoint, self.request_timeout);
+        let response: JsonRpcResponse<SignedRAV> = client
+            .request(
+                "aggregate_indexing_fees",
+                rpc_params!("0.0", agreement, accrued_fees.to_string(), &previous_rav),
+            )
+            .await?;
+        if let Some(warnings) = response.warnings {
+            warn!("Warnings from sender's TAP aggregator: {:?}", warnings);
+        }
+        let rav = response.data;
+
+        ensure!(
+            rav.message.allocationId == agreement.message.allocationId,
+            "RAV is for allocation {} instead of {}",
+            rav.message.allocationId,
+            agreement.message.allocationId
+        );
+        ensure!(
+            rav.message.valueAggregate == *accrued_fees,
+            "RAV value aggregate is {} instead of {}",
+            rav.message.valueAggregate,
+            accrued_fees
+        );
+        if let Some(previous_rav) = &previous_rav {
+            ensure!(
+                rav.message.timestampNs > previous_rav.message.timestampNs,
+                "RAV is older than the previous one"
+            );
+        }
+        let rav_signer = rav.recover_signer(&self.domain_separator)?;
+        ensure!(
+            escrow_accounts.get_sender_for_signer(&rav_signer)? == sender,
+            "RAV is signed by {rav_signer}, which is not a signer of sender {sender}"
+        );
+
+        sqlx::query(
+            r#"
+                INSERT INTO indexing_agreement_ravs (
+                    agreement_id,
+                    sender_address,
+                    signature,
+                    allocation_id,
+                    timestamp_ns,
+                    value_aggregate
+                )
+                VALUES ($1, $2, $3, $4, $5, $6)
+                ON CONFLICT (agreement_id) DO UPDATE
+                SET
+                    signature = EXCLUDED.signature,
+                    timestamp_ns = EXCLUDED.timestamp_ns,
+                    value_aggregate = EXCLUDED.value_aggregate,
+                    updated_at = NOW()
+            "#,
+        )
+        .bind(agreement.message.agreementId.encode_hex::<String>())
+        .bind(SenderAddress(sender))
+        .bind(rav.signature.to_vec())
+        .bind(AllocationIdHex(rav.message.allocationId))
+        .bind(BigDecimal::from(rav.message.timestampNs))
+        .bind(GrtWei(rav.message.valueAggregate))
+        .execute(&self.pgpool)
+        .await?;
+
+        info!(
+            agreement_id = %agreement.message.agreementId,
+            %sender,
+            value_aggregate = rav.message.valueAggregate,
+            "Stored RAV for indexing agreement."
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use alloy_primitives::{FixedBytes, U256};
+    use eventuals::Eventual;
+    use indexer_common::agreements::{self, IndexingAgreement};
+    use indexer_common::escrow_accounts::EscrowAccounts;
+    use indexer_common::types::{AllocationIdHex, GrtWei, SenderAddress};
+    use serde_json::json;
+    use sqlx::PgPool;
+    use tap_aggregator::jsonrpsee_helpers::JsonRpcResponse;
+    use tap_core::signed_message::EIP712SignedMessage;
+    use wiremock::{
+        matchers::{body_string_contains, method},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::AgreementRavs;
+    use crate::scheduler::Job;
+    use crate::tap::test_utils::{
+        create_rav, ALLOCATION_ID_0, SENDER, SIGNER, TAP_EIP712_DOMAIN_SEPARATOR,
+    };
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_request_agreement_rav(pgpool: PgPool) {
+        let agreement = EIP712SignedMessage::new(
+            &TAP_EIP712_DOMAIN_SEPARATOR,
+            IndexingAgreement {
+                agreementId: FixedBytes::from([0x01u8; 32]),
+                allocationId: *ALLOCATION_ID_0,
+                deploymentId: FixedBytes::from([0x03u8; 32]),
+                pricePerEntity: 1,
+                pricePerBlock: 10,
+            },
+            &SIGNER.0,
+        )
+        .unwrap();
+        agreements::store(&pgpool, SIGNER.1, &agreement)
+            .await
+            .unwrap();
+        agreements::record_progress(&pgpool, agreement.message.agreementId, 5, 2)
+            .await
+            .unwrap();
+
+        let aggregator_server = MockServer::start().await;
+        aggregator_server
+            .register(
+                Mock::given(method("POST"))
+                    .and(body_string_contains("aggregate_indexing_fees"))
+                    .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                        "id": 0,
+                        "jsonrpc": "2.0",
+                        "result": JsonRpcResponse {
+                            data: create_rav(*ALLOCATION_ID_0, SIGNER.0.clone(), 1, 25),
+                            warnings: None,
+                        }
+                    })))
+                    .expect(1),
+            )
+            .await;
+
+        let job = AgreementRavs {
+            pgpool: pgpool.clone(),
+            escrow_accounts: Eventual::from_value(EscrowAccounts::new(
+                HashMap::from([(SENDER.1, U256::from(1000))]),
+                HashMap::from([(SENDER.1, vec![SIGNER.1])]),
+            )),
+            domain_separator: TAP_EIP712_DOMAIN_SEPARATOR.clone(),
+            sender_aggregator_endpoints: HashMap::from([(SENDER.1, aggregator_server.uri())]),
+            http_client: reqwest::Client::new(),
+            request_timeout: Duration::from_secs(5),
+        };
+        job.run().await.unwrap();
+
+        let stored_agreement = agreements::active_agreements(&pgpool)
+            .await
+            .unwrap()
+            .pop()
+            .unwrap();
+        let rav = job.latest_rav(&stored_agreement).await.unwrap().unwrap();
+        assert_eq!(rav.message.valueAggregate, 25);
+        assert_eq!(rav.message.allocationId, *ALLOCATION_ID_0);
+
+        // The RAV covers all the accrued fees, no new request is made.
+        job.run().await.unwrap();
+    }
+}
@@ -0,0 +1,554 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Coordination of the closures of allocations, when a sender allocation requests its last RAV
+//! and marks it as last. Unallocating many deployments at once closes many allocations, so only
+//! a bounded number of them request their last RAV at the same time. The progress of every
+//! closure is persisted, with its failed attempts, and closures started while others are running
+//! are grouped in a batch, summarized once all of them are done. A closure interrupted by a
+//! restart is started again by its sender account, and resumes in its batch.
+//!
+//! The last RAV of a large backlog is aggregated in chunks, successive RAV requests each
+//! resuming from the RAV of the previous one. The chunks are counted along with the value
+//! aggregated so far, and a closure resumed after a restart carries on from its latest RAV.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, OnceLock};
+
+use anyhow::Result;
+use axum::{extract::State, http::StatusCode, routing::get, Json, Router};
+use chrono::{DateTime, Utc};
+use indexer_common::types::{AllocationIdHex, GrtWei, SenderAddress};
+use serde::{Deserialize, Serialize};
+use sqlx::{types::BigDecimal, PgPool, Row};
+use thegraph::types::Address;
+use tokio::sync::Semaphore;
+use tracing::{error, info, warn};
+
+/// Batches served, from the most recent one.
+const BATCHES_PAGE_SIZE: i64 = 10;
+
+static PERMITS: OnceLock<Semaphore> = OnceLock::new();
+
+/// Permits of the closed allocations to request their last RAV.
+pub fn permits(max_concurrent_closures: usize) -> &'static Semaphore {
+    PERMITS.get_or_init(|| Semaphore::new(max_concurrent_closures.max(1)))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClosureState {
+    /// Waiting for a permit to request the last RAV.
+    Pending,
+    RequestingRav,
+    /// Waiting for the POI of the close to be posted, see [crate::poi_gate].
+    WaitingForPoi,
+    MarkingLast,
+    Closed,
+}
+
+impl ClosureState {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ClosureState::Pending => "pending",
+            ClosureState::RequestingRav => "requesting_rav",
+            ClosureState::WaitingForPoi => "waiting_for_poi",
+            ClosureState::MarkingLast => "marking_last",
+            ClosureState::Closed => "closed",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchSummary {
+    pub batch_id: i64,
+    pub allocations: u64,
+    /// Failed attempts to request the last RAV or to mark it as last, over all allocations.
+    pub failed_attempts: u64,
+    /// Sum of the values of the last RAVs, in GRT wei.
+    pub final_value: String,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Batch {
+    pub id: i64,
+    pub started_at: DateTime<Utc>,
+    /// `None` while closures of the batch are still running.
+    pub summary: Option<BatchSummary>,
+    /// Number of closures in each state.
+    pub progress: BTreeMap<String, u64>,
+    pub closures: Vec<Closure>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Closure {
+    pub sender: Address,
+    pub allocation_id: Address,
+    pub state: String,
+    pub failed_attempts: u64,
+    pub last_error: Option<String>,
+    /// RAV requests that succeeded, aggregating the last RAV.
+    pub chunks: u64,
+    /// Value of the latest RAV of the chunks, in GRT wei.
+    pub aggregated_value: Option<String>,
+    pub final_value: Option<String>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Progress of the closure of a (sender, allocation) pair. Failing to persist it is logged, and
+/// doesn't hold the closure back.
+pub struct ClosureProgress {
+    pgpool: PgPool,
+    sender: Address,
+    allocation_id: Address,
+}
+
+impl ClosureProgress {
+    /// Starts the closure in the running batch, or in a new one.
+    pub async fn start(pgpool: PgPool, sender: Address, allocation_id: Address) -> Self {
+        let progress = Self {
+            pgpool,
+            sender,
+            allocation_id,
+        };
+        match start_closure(&progress.pgpool, sender, allocation_id).await {
+            Ok(batch_id) => info!(%sender, %allocation_id, batch_id, "Closing allocation"),
+            Err(e) => progress.log_error(e),
+        }
+        progress
+    }
+
+    pub async fn set_state(&self, state: ClosureState) {
+        if let Err(e) =
+            set_closure_state(&self.pgpool, self.sender, self.allocation_id, state).await
+        {
+            self.log_error(e);
+        }
+    }
+
+    pub async fn record_failure(&self, error: &anyhow::Error) {
+        if let Err(e) = record_failure(
+            &self.pgpool,
+            self.sender,
+            self.allocation_id,
+            &error.to_string(),
+        )
+        .await
+        {
+            self.log_error(e);
+        }
+    }
+
+    /// Records a chunk of the last RAV, `value` being the value of its RAV.
+    pub async fn record_chunk(&self, value: u128) {
+        if let Err(e) = record_chunk(&self.pgpool, self.sender, self.allocation_id, value).await {
+            self.log_error(e);
+        }
+    }
+
+    /// Reports the batch if the closure was its last one running.
+    pub async fn finish(&self, final_value: u128) {
+        match finish_closure(&self.pgpool, self.sender, self.allocation_id, final_value).await {
+            Ok(Some(summary)) => info!(
+                batch_id = summary.batch_id,
+                allocations = summary.allocations,
+                failed_attempts = summary.failed_attempts,
+                final_value = summary.final_value,
+                duration_secs = (summary.finished_at - summary.started_at).num_seconds(),
+                "Finished closing a batch of allocations"
+            ),
+            Ok(None) => {}
+            Err(e) => self.log_error(e),
+        }
+    }
+
+    fn log_error(&self, error: anyhow::Error) {
+        warn!(
+            %error,
+            sender = %self.sender,
+            allocation_id = %self.allocation_id,
+            "Failed to record the progress of the allocation closure"
+        );
+    }
+}
+
+/// Returns the batch of the closure.
+async fn start_closure(pgpool: &PgPool, sender: Address, allocation_id: Address) -> Result<i64> {
+    let mut tx = pgpool.begin().await?;
+    // Serializes the closures starting or finishing, so that a single batch is running
+    sqlx::query("LOCK TABLE scalar_tap_allocation_closure_batches IN EXCLUSIVE MODE")
+        .execute(&mut *tx)
+        .await?;
+    let running_batch: Option<i64> = sqlx::query_scalar(
+        r#"
+            SELECT id FROM scalar_tap_allocation_closure_batches
+            WHERE finished_at IS NULL
+            ORDER BY id DESC
+            LIMIT 1
+        "#,
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    let batch_id = match running_batch {
+        Some(batch_id) => batch_id,
+        None => {
+            sqlx::query_scalar(
+                "INSERT INTO scalar_tap_allocation_closure_batches DEFAULT VALUES RETURNING id",
+            )
+            .fetch_one(&mut *tx)
+            .await?
+        }
+    };
+    // An unfinished closure resumes with its failed attempts
+    let batch_id = sqlx::query_scalar(
+        r#"
+            INSERT INTO scalar_tap_allocation_closures (
+                sender_address, allocation_id, batch_id, state
+            )
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (sender_address, allocation_id) DO UPDATE SET
+                state = EXCLUDED.state,
+                batch_id = CASE
+                    WHEN scalar_tap_allocation_closures.state = 'closed' THEN EXCLUDED.batch_id
+                    ELSE scalar_tap_allocation_closures.batch_id
+                END,
+                failed_attempts = CASE
+                    WHEN scalar_tap_allocation_closures.state = 'closed' THEN 0
+                    ELSE scalar_tap_allocation_closures.failed_attempts
+                END,
+                chunks = CASE
+                    WHEN scalar_tap_allocation_closures.state = 'closed' THEN 0
+                    ELSE scalar_tap_allocation_closures.chunks
+                END,
+                aggregated_value = CASE
+                    WHEN scalar_tap_allocation_closures.state = 'closed' THEN NULL
+                    ELSE scalar_tap_allocation_closures.aggregated_value
+                END,
+                final_value = NULL,
+                updated_at = NOW()
+            RETURNING batch_id
+        "#,
+    )
+    .bind(SenderAddress(sender))
+    .bind(AllocationIdHex(allocation_id))
+    .bind(batch_id)
+    .bind(ClosureState::Pending.as_str())
+    .fetch_one(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(batch_id)
+}
+
+async fn set_closure_state(
+    pgpool: &PgPool,
+    sender: Address,
+    allocation_id: Address,
+    state: ClosureState,
+) -> Result<()> {
+    sqlx::query(
+        r#"
+            UPDATE scalar_tap_allocation_closures
+            SET state = $3, updated_at = NOW()
+            WHERE sender_address = $1 AND allocation_id = $2
+        "#,
+    )
+    .bind(SenderAddress(sender))
+    .bind(AllocationIdHex(allocation_id))
+    .bind(state.as_str())
+    .execute(pgpool)
+    .await?;
+    Ok(())
+}
+
+async fn record_failure(
+    pgpool: &PgPool,
+    sender: Address,
+    allocation_id: Address,
+    error: &str,
+) -> Result<()> {
+    sqlx::query(
+        r#"
+            UPDATE scalar_tap_allocation_closures
+            SET failed_attempts = failed_attempts + 1, last_error = $3, updated_at = NOW()
+            WHERE sender_address = $1 AND allocation_id = $2
+        "#,
+    )
+    .bind(SenderAddress(sender))
+    .bind(AllocationIdHex(allocation_id))
+    .bind(error)
+    .execute(pgpool)
+    .await?;
+    Ok(())
+}
+
+async fn record_chunk(
+    pgpool: &PgPool,
+    sender: Address,
+    allocation_id: Address,
+    value: u128,
+) -> Result<()> {
+    sqlx::query(
+        r#"
+            UPDATE scalar_tap_allocation_closures
+            SET chunks = chunks + 1, aggregated_value = $3, updated_at = NOW()
+            WHERE sender_address = $1 AND allocation_id = $2
+        "#,
+    )
+    .bind(SenderAddress(sender))
+    .bind(AllocationIdHex(allocation_id))
+    .bind(GrtWei(value))
+    .execute(pgpool)
+    .await?;
+    Ok(())
+}
+
+/// Returns the summary of the batch if the closure was its last one running.
+async fn finish_closure(
+    pgpool: &PgPool,
+    sender: Address,
+    allocation_id: Address,
+    final_value: u128,
+) -> Result<Option<BatchSummary>> {
+    let mut tx = pgpool.begin().await?;
+    sqlx::query("LOCK TABLE scalar_tap_allocation_closure_batches IN EXCLUSIVE MODE")
+        .execute(&mut *tx)
+        .await?;
+    let batch_id: Option<i64> = sqlx::query_scalar(
+        r#"
+            UPDATE scalar_tap_allocation_closures
+            SET state = $3, final_value = $4, updated_at = NOW()
+            WHERE sender_address = $1 AND allocation_id = $2
+            RETURNING batch_id
+        "#,
+    )
+    .bind(SenderAddress(sender))
+    .bind(AllocationIdHex(allocation_id))
+    .bind(ClosureState::Closed.as_str())
+    .bind(GrtWei(final_value))
+    .fetch_optional(&mut *tx)
+    .await?;
+    let Some(batch_id) = batch_id else {
+        return Ok(None);
+    };
+
+    let row = sqlx::query(
+        r#"
+            SELECT
+                batches.started_at,
+                COUNT(*) AS allocations,
+                COUNT(*) FILTER (WHERE closures.state != 'closed') AS running,
+                SUM(closures.failed_attempts) AS failed_attempts,
+                COALESCE(SUM(closures.final_value), 0) AS final_value
+            FROM scalar_tap_allocation_closure_batches batches
+            JOIN scalar_tap_allocation_closures closures ON closures.batch_id = batches.id
+            WHERE batches.id = $1 AND batches.finished_at IS NULL
+            GROUP BY batches.started_at
+        "#,
+    )
+    .bind(batch_id)
+    .fetch_optional(&mut *tx)
+    .await?;
+    let summary = match row {
+        Some(row) if row.try_get::<i64, _>("running")? == 0 => {
+            let summary = BatchSummary {
+                batch_id,
+                allocations: row.try_get::<i64, _>("allocations")? as u64,
+                failed_attempts: row.try_get::<i64, _>("failed_attempts")? as u64,
+                final_value: row.try_get::<BigDecimal, _>("final_value")?.to_string(),
+                started_at: row.try_get("started_at")?,
+                finished_at: Utc::now(),
+            };
+            sqlx::query(
+                r#"
+                    UPDATE scalar_tap_allocation_closure_batches
+                    SET finished_at = $2, summary = $3
+                    WHERE id = $1
+                "#,
+            )
+            .bind(batch_id)
+            .bind(summary.finished_at)
+            .bind(serde_json::to_value(&summary)?)
+            .execute(&mut *tx)
+            .await?;
+            Some(summary)
+        }
+        _ => None,
+    };
+    tx.commit().await?;
+    Ok(summary)
+}
+
+/// The latest batches, from the most recent one.
+pub async fn batches(pgpool: &PgPool) -> Result<Vec<Batch>> {
+    let rows = sqlx::query(
+        r#"
+            SELECT id, started_at, summary
+            FROM scalar_tap_allocation_closure_batches
+            ORDER BY id DESC
+            LIMIT $1
+        "#,
+    )
+    .bind(BATCHES_PAGE_SIZE)
+    .fetch_all(pgpool)
+    .await?;
+    let mut batches = rows
+        .iter()
+        .map(|row| {
+            Ok(Batch {
+                id: row.try_get("id")?,
+                started_at: row.try_get("started_at")?,
+                summary: row
+                    .try_get::<Option<serde_json::Value>, _>("summary")?
+                    .map(serde_json::from_value)
+                    .transpose()?,
+                progress: BTreeMap::new(),
+                closures: Vec::new(),
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let rows = sqlx::query(
+        r#"
+            SELECT
+                batch_id, sender_address, allocation_id, state, failed_attempts, last_error,
+                chunks, aggregated_value, final_value, updated_at
+            FROM scalar_tap_allocation_closures
+            WHERE batch_id = ANY($1)
+            ORDER BY sender_address, allocation_id
+        "#,
+    )
+    .bind(batches.iter().map(|batch| batch.id).collect::<Vec<_>>())
+    .fetch_all(pgpool)
+    .await?;
+    for row in rows {
+        let batch_id: i64 = row.try_get("batch_id")?;
+        let Some(batch) = batches.iter_mut().find(|batch| batch.id == batch_id) else {
+            continue;
+        };
+        let closure = Closure {
+            sender: row.try_get::<SenderAddress, _>("sender_address")?.0,
+            allocation_id: row.try_get::<AllocationIdHex, _>("allocation_id")?.0,
+            state: row.try_get("state")?,
+            failed_attempts: row.try_get::<i32, _>("failed_attempts")? as u64,
+            last_error: row.try_get("last_error")?,
+            chunks: row.try_get::<i32, _>("chunks")? as u64,
+            aggregated_value: row
+                .try_get::<Option<BigDecimal>, _>("aggregated_value")?
+                .map(|value| value.to_string()),
+            final_value: row
+                .try_get::<Option<BigDecimal>, _>("final_value")?
+                .map(|value| value.to_string()),
+            updated_at: row.try_get("updated_at")?,
+        };
+        *batch.progress.entry(closure.state.clone()).or_default() += 1;
+        batch.closures.push(closure);
+    }
+    Ok(batches)
+}
+
+async fn handler_batches(
+    State(pgpool): State<Arc<PgPool>>,
+) -> Result<Json<Vec<Batch>>, (StatusCode, String)> {
+    batches(&pgpool).await.map(Json).map_err(|e| {
+        error!("Error while getting the allocation closures: {}", e);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Error while getting the allocation closures: {}", e),
+        )
+    })
+}
+
+/// Routes serving the progress of the allocation closures, to be mounted on the tap-agent HTTP
+/// server.
+pub fn router(pgpool: PgPool) -> Router {
+    Router::new()
+        .route("/state/allocation-closures", get(handler_batches))
+        .with_state(Arc::new(pgpool))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tap::test_utils::{ALLOCATION_ID_0, ALLOCATION_ID_1, SENDER};
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_closure_batches(pgpool: PgPool) {
+        let batch_id = start_closure(&pgpool, SENDER.1, *ALLOCATION_ID_0)
+            .await
+            .unwrap();
+        assert_eq!(
+            start_closure(&pgpool, SENDER.1, *ALLOCATION_ID_1)
+                .await
+                .unwrap(),
+            batch_id
+        );
+        record_failure(
+            &pgpool,
+            SENDER.1,
+            *ALLOCATION_ID_0,
+            "aggregator unreachable",
+        )
+        .await
+        .unwrap();
+        record_chunk(&pgpool, SENDER.1, *ALLOCATION_ID_0, 40)
+            .await
+            .unwrap();
+        record_chunk(&pgpool, SENDER.1, *ALLOCATION_ID_0, 70)
+            .await
+            .unwrap();
+        // Interrupted by a restart, resumes in its batch
+        assert_eq!(
+            start_closure(&pgpool, SENDER.1, *ALLOCATION_ID_0)
+                .await
+                .unwrap(),
+            batch_id
+        );
+
+        assert_eq!(
+            finish_closure(&pgpool, SENDER.1, *ALLOCATION_ID_0, 100)
+                .await
+                .unwrap(),
+            None
+        );
+        let running = batches(&pgpool).await.unwrap();
+        assert_eq!(
+            running[0].progress,
+            BTreeMap::from([("closed".to_string(), 1), ("pending".to_string(), 1)])
+        );
+        let closure = running[0]
+            .closures
+            .iter()
+            .find(|closure| closure.allocation_id == *ALLOCATION_ID_0)
+            .unwrap();
+        assert_eq!(closure.failed_attempts, 1);
+        assert_eq!(
+            closure.last_error.as_deref(),
+            Some("aggregator unreachable")
+        );
+        // Along with its chunks
+        assert_eq!(closure.chunks, 2);
+        assert_eq!(closure.aggregated_value.as_deref(), Some("70"));
+
+        let summary = finish_closure(&pgpool, SENDER.1, *ALLOCATION_ID_1, 50)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(summary.batch_id, batch_id);
+        assert_eq!(summary.allocations, 2);
+        assert_eq!(summary.failed_attempts, 1);
+        assert_eq!(summary.final_value, "150");
+        assert_eq!(batches(&pgpool).await.unwrap()[0].summary, Some(summary));
+
+        // The batch is over
+        assert_ne!(
+            start_closure(&pgpool, SENDER.1, *ALLOCATION_ID_0)
+                .await
+                .unwrap(),
+            batch_id
+        );
+    }
+}
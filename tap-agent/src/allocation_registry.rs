@@ -0,0 +1,350 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Admin API registering the allocations of the `api`
+//! [allocation source](indexer_common::allocations::sources), for the private deployments
+//! without a Graph network. Registrations and closures are recorded in the operator audit log
+//! like the other admin actions.
+//!
+//! A closed allocation keeps accepting receipts for the recently closed allocation buffer, after
+//! which its last RAV is requested, like for the allocations closed on chain.
+
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    middleware,
+    routing::{get, put},
+    Extension, Json, Router,
+};
+use chrono::{DateTime, Utc};
+use indexer_common::types::AllocationIdHex;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{PgPool, Row};
+use thegraph::types::{Address, DeploymentId};
+use tracing::{error, info};
+
+use crate::admin_signature::{require_signature, AdminSignature, AdminSigners};
+use crate::escrow_overrides::{record_action, AdminError};
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RegisterAllocation {
+    pub deployment: DeploymentId,
+    /// Epoch the allocation ID was derived from the operator mnemonic for.
+    pub created_at_epoch: u64,
+    pub reason: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CloseAllocation {
+    pub reason: String,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RegisteredAllocationEntry {
+    pub allocation_id: Address,
+    pub deployment: String,
+    pub created_at_epoch: i64,
+    pub created_at: DateTime<Utc>,
+    pub closed_at: Option<DateTime<Utc>>,
+}
+
+impl RegisterAllocation {
+    fn validate(&self) -> Result<()> {
+        if self.reason.trim().is_empty() {
+            return Err(anyhow!("A reason is required"));
+        }
+        if self.created_at_epoch == 0 {
+            return Err(anyhow!("The createdAtEpoch must be at least 1"));
+        }
+        Ok(())
+    }
+}
+
+/// Registers an allocation, or reopens a closed one.
+pub async fn register_allocation(
+    pgpool: &PgPool,
+    allocation_id: Address,
+    request: &RegisterAllocation,
+    signature: Option<&AdminSignature>,
+) -> Result<()> {
+    request.validate()?;
+
+    let mut tx = pgpool.begin().await?;
+    sqlx::query(
+        r#"
+            INSERT INTO registered_allocations (allocation_id, deployment, created_at_epoch)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (allocation_id) DO UPDATE SET
+                deployment = EXCLUDED.deployment,
+                created_at_epoch = EXCLUDED.created_at_epoch,
+                created_at = NOW(),
+                closed_at = NULL
+        "#,
+    )
+    .bind(AllocationIdHex(allocation_id))
+    .bind(request.deployment.to_string())
+    .bind(request.created_at_epoch as i64)
+    .execute(&mut *tx)
+    .await?;
+    record_action(
+        &mut tx,
+        "register_allocation",
+        allocation_id,
+        &request.reason,
+        json!({
+            "deployment": request.deployment.to_string(),
+            "createdAtEpoch": request.created_at_epoch,
+        }),
+        signature,
+    )
+    .await?;
+    tx.commit().await?;
+
+    info!(
+        %allocation_id,
+        deployment = %request.deployment,
+        reason = %request.reason,
+        "Allocation registered by the operator"
+    );
+    Ok(())
+}
+
+/// Returns whether there was an open allocation to close.
+pub async fn close_allocation(
+    pgpool: &PgPool,
+    allocation_id: Address,
+    reason: &str,
+    signature: Option<&AdminSignature>,
+) -> Result<bool> {
+    if reason.trim().is_empty() {
+        return Err(anyhow!("A reason is required"));
+    }
+
+    let mut tx = pgpool.begin().await?;
+    let closed = sqlx::query(
+        r#"
+            UPDATE registered_allocations
+            SET closed_at = NOW()
+            WHERE allocation_id = $1 AND closed_at IS NULL
+        "#,
+    )
+    .bind(AllocationIdHex(allocation_id))
+    .execute(&mut *tx)
+    .await?
+    .rows_affected()
+        > 0;
+    if closed {
+        record_action(
+            &mut tx,
+            "close_allocation",
+            allocation_id,
+            reason,
+            json!({}),
+            signature,
+        )
+        .await?;
+    }
+    tx.commit().await?;
+
+    if closed {
+        info!(%allocation_id, reason, "Allocation closed by the operator");
+    }
+    Ok(closed)
+}
+
+/// Every registered allocation, including the closed ones.
+pub async fn list_allocations(pgpool: &PgPool) -> Result<Vec<RegisteredAllocationEntry>> {
+    let rows = sqlx::query(
+        r#"
+            SELECT allocation_id, deployment, created_at_epoch, created_at, closed_at
+            FROM registered_allocations
+            ORDER BY created_at DESC
+        "#,
+    )
+    .fetch_all(pgpool)
+    .await?;
+
+    rows.iter()
+        .map(|row| {
+            Ok(RegisteredAllocationEntry {
+                allocation_id: row.try_get::<AllocationIdHex, _>("allocation_id")?.0,
+                deployment: row.try_get("deployment")?,
+                created_at_epoch: row.try_get("created_at_epoch")?,
+                created_at: row.try_get("created_at")?,
+                closed_at: row.try_get("closed_at")?,
+            })
+        })
+        .collect()
+}
+
+struct AdminState {
+    pgpool: PgPool,
+}
+
+fn internal_error(e: anyhow::Error) -> AdminError {
+    error!("Error while handling an allocation registry request: {}", e);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Error while handling an allocation registry request: {}", e),
+    )
+}
+
+async fn handler_allocations(
+    State(state): State<Arc<AdminState>>,
+) -> Result<Json<Vec<RegisteredAllocationEntry>>, AdminError> {
+    list_allocations(&state.pgpool)
+        .await
+        .map(Json)
+        .map_err(internal_error)
+}
+
+async fn handler_register_allocation(
+    State(state): State<Arc<AdminState>>,
+    Path(allocation_id): Path<Address>,
+    signature: Option<Extension<AdminSignature>>,
+    Json(request): Json<RegisterAllocation>,
+) -> Result<StatusCode, AdminError> {
+    request
+        .validate()
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    let signature = signature.map(|Extension(signature)| signature);
+    register_allocation(&state.pgpool, allocation_id, &request, signature.as_ref())
+        .await
+        .map_err(internal_error)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn handler_close_allocation(
+    State(state): State<Arc<AdminState>>,
+    Path(allocation_id): Path<Address>,
+    Query(request): Query<CloseAllocation>,
+    signature: Option<Extension<AdminSignature>>,
+) -> Result<StatusCode, AdminError> {
+    if request.reason.trim().is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "A reason is required".to_string()));
+    }
+    let signature = signature.map(|Extension(signature)| signature);
+    match close_allocation(
+        &state.pgpool,
+        allocation_id,
+        &request.reason,
+        signature.as_ref(),
+    )
+    .await
+    {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err((
+            StatusCode::NOT_FOUND,
+            "No open registered allocation with this ID".into(),
+        )),
+        Err(e) => Err(internal_error(e)),
+    }
+}
+
+/// Admin routes of the registered allocations, to be mounted on the tap-agent HTTP server when
+/// they are the allocation source.
+pub fn router(pgpool: PgPool, admin_signers: Arc<AdminSigners>) -> Router {
+    Router::new()
+        .route(
+            "/admin/allocations/:allocation_id",
+            put(handler_register_allocation).delete(handler_close_allocation),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            admin_signers,
+            require_signature,
+        ))
+        .route("/admin/allocations", get(handler_allocations))
+        .with_state(Arc::new(AdminState { pgpool }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    use indexer_common::allocations::sources::registered_allocations;
+    use indexer_common::allocations::AllocationStatus;
+
+    use super::*;
+    use crate::escrow_overrides::audit_log;
+    use crate::tap::test_utils::{ALLOCATION_ID_0, INDEXER};
+
+    fn request() -> RegisterAllocation {
+        RegisterAllocation {
+            deployment: DeploymentId::from_str("QmAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA")
+                .unwrap(),
+            created_at_epoch: 940,
+            reason: "private network launch".to_string(),
+        }
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_register_and_close_allocation(pgpool: PgPool) {
+        let loaded = |buffer| {
+            let pgpool = pgpool.clone();
+            async move {
+                registered_allocations(pgpool, INDEXER.1, Duration::from_secs(60), buffer)
+                    .value()
+                    .await
+                    .unwrap()
+            }
+        };
+
+        assert!(RegisterAllocation {
+            created_at_epoch: 0,
+            ..request()
+        }
+        .validate()
+        .is_err());
+        assert!(RegisterAllocation {
+            reason: " ".to_string(),
+            ..request()
+        }
+        .validate()
+        .is_err());
+
+        register_allocation(&pgpool, *ALLOCATION_ID_0, &request(), None)
+            .await
+            .unwrap();
+        let allocations = loaded(Duration::from_secs(3600)).await;
+        assert_eq!(
+            allocations[&*ALLOCATION_ID_0].status,
+            AllocationStatus::Active
+        );
+        assert_eq!(allocations[&*ALLOCATION_ID_0].indexer, INDEXER.1);
+
+        assert!(
+            close_allocation(&pgpool, *ALLOCATION_ID_0, "network sunset", None)
+                .await
+                .unwrap()
+        );
+        assert!(!close_allocation(&pgpool, *ALLOCATION_ID_0, "again", None)
+            .await
+            .unwrap());
+        // Still accepting receipts for the buffer
+        assert_eq!(
+            loaded(Duration::from_secs(3600)).await[&*ALLOCATION_ID_0].status,
+            AllocationStatus::Closed
+        );
+        assert!(loaded(Duration::ZERO).await.is_empty());
+
+        let entries = list_allocations(&pgpool).await.unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].created_at_epoch, 940);
+        assert!(entries[0].closed_at.is_some());
+
+        let log = audit_log(&pgpool).await.unwrap();
+        assert_eq!(
+            log.iter()
+                .map(|entry| entry.action.as_str())
+                .collect::<Vec<_>>(),
+            vec!["close_allocation", "register_allocation"]
+        );
+    }
+}
@@ -0,0 +1,452 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Status of the indexer's allocations, joining what the network subgraph knows about them with
+//! the receipts and RAVs stored by the agent, so that operators can get it in a single call.
+//! Served behind the admin auth token, as it discloses the fees of every sender.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use axum::{extract::State, http::StatusCode, routing::get, Json, Router};
+use eventuals::Eventual;
+use indexer_common::escrow_accounts::EscrowAccounts;
+use indexer_common::prelude::Allocation;
+use indexer_common::types::{AllocationIdHex, SenderAddress};
+use serde::Serialize;
+use sqlx::{types::BigDecimal, PgPool, Row};
+use thegraph::types::{Address, DeploymentId};
+use tracing::error;
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AllocationStatusReport {
+    pub allocation_id: Address,
+    pub deployment: Option<DeploymentId>,
+    /// `active` or `closed` according to the network subgraph, `unknown` if the allocation is
+    /// only found in the database.
+    pub status: &'static str,
+    pub created_at_epoch: Option<u64>,
+    pub closed_at_epoch: Option<u64>,
+    /// Receipts not aggregated into a RAV yet, by sender.
+    pub unaggregated_fees: Vec<UnaggregatedFees>,
+    pub ravs: Vec<RavStatus>,
+    /// Whether the last RAV was marked for redemption for all of the allocation's senders.
+    pub final_rav_marked: bool,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UnaggregatedFees {
+    /// `None` for the receipts whose signer isn't in the escrow accounts anymore.
+    pub sender: Option<Address>,
+    /// In GRT wei.
+    pub value: String,
+    pub receipts: u64,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RavStatus {
+    pub sender: Address,
+    pub value_aggregate: String,
+    pub timestamp_ns: String,
+    pub last: bool,
+    #[serde(rename = "final")]
+    pub is_final: bool,
+}
+
+impl AllocationStatusReport {
+    fn unknown(allocation_id: Address) -> Self {
+        Self {
+            allocation_id,
+            deployment: None,
+            status: "unknown",
+            created_at_epoch: None,
+            closed_at_epoch: None,
+            unaggregated_fees: Vec::new(),
+            ravs: Vec::new(),
+            final_rav_marked: false,
+        }
+    }
+
+    /// Value of the receipts not aggregated into a RAV yet of all the senders, in GRT wei.
+    pub fn total_unaggregated_fees(&self) -> Result<u128> {
+        self.unaggregated_fees
+            .iter()
+            .try_fold(0u128, |total, fees| {
+                total
+                    .checked_add(fees.value.parse()?)
+                    .ok_or_else(|| anyhow!("Overflow when summing unaggregated fees"))
+            })
+    }
+}
+
+pub async fn allocation_statuses(
+    pgpool: &PgPool,
+    allocations: &HashMap<Address, Allocation>,
+    escrow_accounts: &EscrowAccounts,
+) -> Result<Vec<AllocationStatusReport>> {
+    let mut reports: BTreeMap<Address, AllocationStatusReport> = allocations
+        .values()
+        .map(|allocation| {
+            (
+                allocation.id,
+                AllocationStatusReport {
+                    deployment: Some(allocation.subgraph_deployment.id),
+                    status: if allocation.closed_at_epoch.is_some() {
+                        "closed"
+                    } else {
+                        "active"
+                    },
+                    created_at_epoch: Some(allocation.created_at_epoch),
+                    closed_at_epoch: allocation.closed_at_epoch,
+                    ..AllocationStatusReport::unknown(allocation.id)
+                },
+            )
+        })
+        .collect();
+
+    let receipts = sqlx::query(
+        r#"
+            SELECT allocation_id, signer_address, COUNT(*) AS count, SUM(value) AS value
+            FROM scalar_tap_receipts
+            GROUP BY allocation_id, signer_address
+        "#,
+    )
+    .fetch_all(pgpool)
+    .await?;
+    // The signers of a sender are summed together
+    let mut unaggregated: BTreeMap<(Address, Option<Address>), (u128, u64)> = BTreeMap::new();
+    for row in receipts {
+        let allocation_id = row.try_get::<AllocationIdHex, _>("allocation_id")?.0;
+        let signer = row.try_get::<SenderAddress, _>("signer_address")?.0;
+        let sender = escrow_accounts.get_sender_for_signer(&signer).ok();
+        let (value, count) = unaggregated.entry((allocation_id, sender)).or_default();
+        *value = value
+            .checked_add(row.try_get::<BigDecimal, _>("value")?.to_string().parse()?)
+            .ok_or_else(|| anyhow!("Overflow when summing unaggregated fees"))?;
+        *count += u64::try_from(row.try_get::<i64, _>("count")?)?;
+    }
+    for ((allocation_id, sender), (value, receipts)) in unaggregated {
+        reports
+            .entry(allocation_id)
+            .or_insert_with(|| AllocationStatusReport::unknown(allocation_id))
+            .unaggregated_fees
+            .push(UnaggregatedFees {
+                sender,
+                value: value.to_string(),
+                receipts,
+            });
+    }
+
+    let ravs = sqlx::query(
+        r#"
+            SELECT allocation_id, sender_address, value_aggregate, timestamp_ns, last, final
+            FROM scalar_tap_ravs
+            ORDER BY allocation_id, sender_address
+        "#,
+    )
+    .fetch_all(pgpool)
+    .await?;
+    for row in ravs {
+        let allocation_id = row.try_get::<AllocationIdHex, _>("allocation_id")?.0;
+        let report = reports
+            .entry(allocation_id)
+            .or_insert_with(|| AllocationStatusReport::unknown(allocation_id));
+        report.ravs.push(RavStatus {
+            sender: row.try_get::<SenderAddress, _>("sender_address")?.0,
+            value_aggregate: row.try_get::<BigDecimal, _>("value_aggregate")?.to_string(),
+            timestamp_ns: row.try_get::<BigDecimal, _>("timestamp_ns")?.to_string(),
+            last: row.try_get("last")?,
+            is_final: row.try_get("final")?,
+        });
+    }
+
+    for report in reports.values_mut() {
+        report.final_rav_marked = !report.ravs.is_empty() && report.ravs.iter().all(|rav| rav.last);
+    }
+
+    Ok(reports.into_values().collect())
+}
+
+/// Fees of a deployment across the allocations that succeeded each other on it, so that fee
+/// dashboards don't show a gap when an allocation is replaced.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeploymentContinuity {
+    pub deployment: DeploymentId,
+    /// From the oldest allocation to the most recent one.
+    pub allocations: Vec<Address>,
+    pub active_allocation: Option<Address>,
+    /// Summed over the allocations and their senders, in GRT wei.
+    pub unaggregated_fees: String,
+    pub rav_value: String,
+}
+
+/// Groups the allocation reports by deployment. Allocations only found in the database, whose
+/// deployment is unknown, are left out.
+pub fn deployment_continuity(
+    reports: &[AllocationStatusReport],
+) -> Result<Vec<DeploymentContinuity>> {
+    let mut deployments: BTreeMap<DeploymentId, Vec<&AllocationStatusReport>> = BTreeMap::new();
+    for report in reports {
+        if let Some(deployment) = report.deployment {
+            deployments.entry(deployment).or_default().push(report);
+        }
+    }
+
+    deployments
+        .into_iter()
+        .map(|(deployment, mut reports)| {
+            reports.sort_by_key(|report| (report.created_at_epoch, report.allocation_id));
+            let mut unaggregated_fees = 0u128;
+            let mut rav_value = 0u128;
+            for report in &reports {
+                unaggregated_fees += report.total_unaggregated_fees()?;
+                for rav in &report.ravs {
+                    rav_value += rav.value_aggregate.parse::<u128>()?;
+                }
+            }
+            Ok(DeploymentContinuity {
+                deployment,
+                allocations: reports.iter().map(|report| report.allocation_id).collect(),
+                active_allocation: reports
+                    .iter()
+                    .rev()
+                    .find(|report| report.status == "active")
+                    .map(|report| report.allocation_id),
+                unaggregated_fees: unaggregated_fees.to_string(),
+                rav_value: rav_value.to_string(),
+            })
+        })
+        .collect()
+}
+
+#[derive(Clone)]
+struct AllocationStatusState {
+    pgpool: PgPool,
+    indexer_allocations: Eventual<HashMap<Address, Allocation>>,
+    escrow_accounts: Eventual<EscrowAccounts>,
+}
+
+async fn handler_allocations(
+    State(state): State<Arc<AllocationStatusState>>,
+) -> Result<Json<Vec<AllocationStatusReport>>, (StatusCode, String)> {
+    let allocations = state
+        .indexer_allocations
+        .value_immediate()
+        .unwrap_or_default();
+    let escrow_accounts = state.escrow_accounts.value_immediate().unwrap_or_default();
+    allocation_statuses(&state.pgpool, &allocations, &escrow_accounts)
+        .await
+        .map(Json)
+        .map_err(|e| {
+            error!("Error while getting the allocations status: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Error while getting the allocations status: {}", e),
+            )
+        })
+}
+
+async fn handler_deployments(
+    State(state): State<Arc<AllocationStatusState>>,
+) -> Result<Json<Vec<DeploymentContinuity>>, (StatusCode, String)> {
+    let allocations = state
+        .indexer_allocations
+        .value_immediate()
+        .unwrap_or_default();
+    let escrow_accounts = state.escrow_accounts.value_immediate().unwrap_or_default();
+    async {
+        let reports = allocation_statuses(&state.pgpool, &allocations, &escrow_accounts).await?;
+        deployment_continuity(&reports)
+    }
+    .await
+    .map(Json)
+    .map_err(|e| {
+        error!("Error while getting the deployments continuity: {}", e);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Error while getting the deployments continuity: {}", e),
+        )
+    })
+}
+
+/// Routes serving the allocations status, to be mounted with the admin routes of the tap-agent
+/// HTTP server.
+pub fn router(
+    pgpool: PgPool,
+    indexer_allocations: Eventual<HashMap<Address, Allocation>>,
+    escrow_accounts: Eventual<EscrowAccounts>,
+) -> Router {
+    Router::new()
+        .route("/state/allocations", get(handler_allocations))
+        .route("/state/deployments", get(handler_deployments))
+        .with_state(Arc::new(AllocationStatusState {
+            pgpool,
+            indexer_allocations,
+            escrow_accounts,
+        }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use indexer_common::prelude::{AllocationStatus, SubgraphDeployment};
+
+    use super::*;
+    use crate::tap::test_utils::{
+        create_rav, create_received_receipt, store_rav_with_options, store_receipt,
+        ALLOCATION_ID_0, ALLOCATION_ID_1, SENDER, SENDER_2, SIGNER,
+    };
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_allocation_statuses(pgpool: PgPool) {
+        let deployment =
+            DeploymentId::from_str("QmAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA").unwrap();
+        let allocations = HashMap::from([(
+            *ALLOCATION_ID_0,
+            Allocation {
+                id: *ALLOCATION_ID_0,
+                status: AllocationStatus::Null,
+                subgraph_deployment: SubgraphDeployment {
+                    id: deployment,
+                    denied_at: None,
+                    network: None,
+                },
+                indexer: Address::ZERO,
+                allocated_tokens: Default::default(),
+                created_at_epoch: 1,
+                created_at_block_hash: "".to_string(),
+                created_at: None,
+                closed_at_epoch: None,
+                closed_at: None,
+                closed_at_epoch_start_block_hash: None,
+                previous_epoch_start_block_hash: None,
+                poi: None,
+                query_fee_rebates: None,
+                query_fees_collected: None,
+            },
+        )]);
+
+        for i in 1..=3 {
+            let receipt = create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, i, i, 10);
+            store_receipt(&pgpool, receipt.signed_receipt())
+                .await
+                .unwrap();
+        }
+        // Of a signer not in the escrow accounts
+        let receipt = create_received_receipt(&ALLOCATION_ID_0, &SENDER_2.0, 4, 4, 5);
+        store_receipt(&pgpool, receipt.signed_receipt())
+            .await
+            .unwrap();
+        let escrow_accounts =
+            EscrowAccounts::new(HashMap::new(), HashMap::from([(SENDER.1, vec![SIGNER.1])]));
+        // A closed allocation that's not in the network subgraph anymore.
+        store_rav_with_options(
+            &pgpool,
+            create_rav(*ALLOCATION_ID_1, SIGNER.0.clone(), 5, 42),
+            SENDER.1,
+            true,
+            false,
+        )
+        .await
+        .unwrap();
+
+        let mut reports = allocation_statuses(&pgpool, &allocations, &escrow_accounts)
+            .await
+            .unwrap();
+        reports.sort_by_key(|report| report.allocation_id != *ALLOCATION_ID_0);
+
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[0].allocation_id, *ALLOCATION_ID_0);
+        assert_eq!(reports[0].deployment, Some(deployment));
+        assert_eq!(reports[0].status, "active");
+        assert_eq!(
+            reports[0].unaggregated_fees,
+            vec![
+                UnaggregatedFees {
+                    sender: None,
+                    value: "5".to_string(),
+                    receipts: 1,
+                },
+                UnaggregatedFees {
+                    sender: Some(SENDER.1),
+                    value: "30".to_string(),
+                    receipts: 3,
+                },
+            ]
+        );
+        assert_eq!(reports[0].total_unaggregated_fees().unwrap(), 35);
+        assert!(reports[0].ravs.is_empty());
+        assert!(!reports[0].final_rav_marked);
+
+        assert_eq!(reports[1].allocation_id, *ALLOCATION_ID_1);
+        assert_eq!(reports[1].status, "unknown");
+        assert!(reports[1].unaggregated_fees.is_empty());
+        assert_eq!(
+            reports[1].ravs,
+            vec![RavStatus {
+                sender: SENDER.1,
+                value_aggregate: "42".to_string(),
+                timestamp_ns: "5".to_string(),
+                last: true,
+                is_final: false,
+            }]
+        );
+        assert!(reports[1].final_rav_marked);
+    }
+
+    #[test]
+    fn test_deployment_continuity() {
+        let deployment =
+            DeploymentId::from_str("QmAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA").unwrap();
+        let reports = vec![
+            AllocationStatusReport {
+                deployment: Some(deployment),
+                status: "active",
+                created_at_epoch: Some(20),
+                unaggregated_fees: vec![UnaggregatedFees {
+                    sender: Some(SENDER.1),
+                    value: "5".to_string(),
+                    receipts: 1,
+                }],
+                ..AllocationStatusReport::unknown(*ALLOCATION_ID_1)
+            },
+            AllocationStatusReport {
+                deployment: Some(deployment),
+                status: "closed",
+                created_at_epoch: Some(10),
+                closed_at_epoch: Some(20),
+                unaggregated_fees: vec![UnaggregatedFees {
+                    sender: Some(SENDER.1),
+                    value: "3".to_string(),
+                    receipts: 1,
+                }],
+                ravs: vec![RavStatus {
+                    sender: SENDER.1,
+                    value_aggregate: "40".to_string(),
+                    timestamp_ns: "1".to_string(),
+                    last: true,
+                    is_final: false,
+                }],
+                ..AllocationStatusReport::unknown(*ALLOCATION_ID_0)
+            },
+            AllocationStatusReport::unknown(Address::ZERO),
+        ];
+
+        assert_eq!(
+            deployment_continuity(&reports).unwrap(),
+            vec![DeploymentContinuity {
+                deployment,
+                allocations: vec![*ALLOCATION_ID_0, *ALLOCATION_ID_1],
+                active_allocation: Some(*ALLOCATION_ID_1),
+                unaggregated_fees: "8".to_string(),
+                rav_value: "40".to_string(),
+            }]
+        );
+    }
+}
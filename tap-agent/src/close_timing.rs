@@ -0,0 +1,455 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Estimates the query fee outcome of closing an allocation now or in a later epoch, to help
+//! operators schedule closes. The fees collected so far (RAVs and unaggregated receipts) are
+//! extrapolated at the allocation's average fees per epoch, and run through the protocol's
+//! exponential rebates, protocol tax, curation and delegation cuts. As the rebates of an
+//! allocation saturate with its fees, the estimate compares the rebates of keeping the
+//! allocation open one more epoch with the ones a fresh allocation of the same stake would get.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    routing::get,
+    Json, Router,
+};
+use eventuals::Eventual;
+use indexer_common::escrow_accounts::EscrowAccounts;
+use indexer_common::prelude::{Allocation, Query, SubgraphClient};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use thegraph::types::Address;
+use tracing::error;
+
+use crate::allocation_status::allocation_statuses;
+
+const MAX_PPM: f64 = 1_000_000.0;
+/// Above this exponent, the protocol rebates all the fees.
+const MAX_EXPONENT: f64 = 15.0;
+
+/// Query fee parameters of the protocol.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RebateParameters {
+    pub alpha: f64,
+    pub lambda: f64,
+    /// Share of the fees burnt as protocol tax.
+    pub protocol_tax: f64,
+    /// Share of the fees left after the protocol tax that goes to curators.
+    pub curation_cut: f64,
+    /// Share of the rebates kept by the indexer, the rest going to its delegators.
+    pub indexer_cut: f64,
+}
+
+/// Rebates of an allocation of `stake` that collected `fees`, following `LibExponential` of the
+/// staking contract: `fees * (1 - alpha * exp(-lambda * stake / fees))`.
+pub fn exponential_rebates(fees: f64, stake: f64, alpha: f64, lambda: f64) -> f64 {
+    if alpha == 0.0 {
+        return fees;
+    }
+    if fees == 0.0 {
+        return 0.0;
+    }
+    let exponent = lambda * stake / fees;
+    if exponent.trunc() > MAX_EXPONENT {
+        return fees;
+    }
+    fees * (1.0 - alpha * (-exponent).exp())
+}
+
+/// Query fees going to the indexer out of `gross_fees` collected by an allocation of `stake`.
+pub fn indexer_rebates(gross_fees: f64, stake: f64, params: &RebateParameters) -> f64 {
+    let fees = gross_fees * (1.0 - params.protocol_tax) * (1.0 - params.curation_cut);
+    if stake == 0.0 || params.lambda == 0.0 {
+        return 0.0;
+    }
+    exponential_rebates(fees, stake, params.alpha, params.lambda).min(fees) * params.indexer_cut
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CloseTimingInput {
+    pub stake: f64,
+    pub fees_so_far: f64,
+    /// Epochs since the allocation was created.
+    pub epochs_open: u64,
+    /// Epochs the allocation can stay open, as per the maximum allocation epochs.
+    pub epochs_left: u64,
+    pub params: RebateParameters,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CloseOutcome {
+    /// Epochs from now.
+    pub epochs_from_now: u64,
+    /// Query fees collected by then, in GRT wei.
+    pub gross_fees: String,
+    /// Query fees going to the indexer, in GRT wei.
+    pub indexer_rebates: String,
+    /// Share of the gross fees going to the indexer.
+    pub rebate_ratio: f64,
+    /// Share of the next epoch's fees that would go to the indexer when keeping the
+    /// allocation open.
+    pub marginal_rebate_ratio: f64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CloseTimingEstimate {
+    /// Average query fees collected per epoch so far, in GRT wei.
+    pub fees_per_epoch: String,
+    pub outcomes: Vec<CloseOutcome>,
+    /// Share of the next epoch's fees that would go to the indexer on a fresh allocation of the
+    /// same stake.
+    pub fresh_allocation_rebate_ratio: f64,
+    /// Epochs from now at which closing is estimated best: the first epoch at which keeping the
+    /// allocation open rebates less than a fresh allocation would, or the last epoch allowed.
+    pub recommended_close_in_epochs: u64,
+}
+
+pub fn estimate_close_timing(input: &CloseTimingInput) -> CloseTimingEstimate {
+    let fees_per_epoch = input.fees_so_far / input.epochs_open.max(1) as f64;
+    let ratio = |fees: f64, rebates: f64| if fees > 0.0 { rebates / fees } else { 0.0 };
+    let fresh_allocation_rebate_ratio = ratio(
+        fees_per_epoch,
+        indexer_rebates(fees_per_epoch, input.stake, &input.params),
+    );
+
+    let outcomes: Vec<_> = (0..=input.epochs_left)
+        .map(|epochs_from_now| {
+            let gross_fees = input.fees_so_far + fees_per_epoch * epochs_from_now as f64;
+            let rebates = indexer_rebates(gross_fees, input.stake, &input.params);
+            let next_rebates =
+                indexer_rebates(gross_fees + fees_per_epoch, input.stake, &input.params);
+            CloseOutcome {
+                epochs_from_now,
+                gross_fees: format!("{:.0}", gross_fees),
+                indexer_rebates: format!("{:.0}", rebates),
+                rebate_ratio: ratio(gross_fees, rebates),
+                marginal_rebate_ratio: ratio(fees_per_epoch, next_rebates - rebates),
+            }
+        })
+        .collect();
+
+    let recommended_close_in_epochs = outcomes
+        .iter()
+        .find(|outcome| outcome.marginal_rebate_ratio < fresh_allocation_rebate_ratio)
+        .map_or(input.epochs_left, |outcome| outcome.epochs_from_now);
+
+    CloseTimingEstimate {
+        fees_per_epoch: format!("{:.0}", fees_per_epoch),
+        outcomes,
+        fresh_allocation_rebate_ratio,
+        recommended_close_in_epochs,
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AllocationCloseTiming {
+    pub allocation_id: Address,
+    pub current_epoch: u64,
+    pub created_at_epoch: u64,
+    pub max_allocation_epochs: u64,
+    pub allocated_tokens: String,
+    /// RAVs and unaggregated receipts, in GRT wei.
+    pub fees_so_far: String,
+    #[serde(flatten)]
+    pub estimate: CloseTimingEstimate,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct NetworkParameters {
+    graph_network: Option<GraphNetwork>,
+    allocation: Option<AllocationParameters>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GraphNetwork {
+    current_epoch: u64,
+    max_allocation_epochs: u64,
+    protocol_fee_percentage: u32,
+    curation_percentage: u32,
+    alpha_numerator: u32,
+    alpha_denominator: u32,
+    lambda_numerator: u32,
+    lambda_denominator: u32,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AllocationParameters {
+    subgraph_deployment: DeploymentParameters,
+    indexer: IndexerParameters,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DeploymentParameters {
+    signalled_tokens: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct IndexerParameters {
+    query_fee_cut: u32,
+    delegated_tokens: String,
+}
+
+async fn network_parameters(
+    network_subgraph: &SubgraphClient,
+    allocation_id: Address,
+) -> Result<(GraphNetwork, AllocationParameters)> {
+    let response = network_subgraph
+        .query::<NetworkParameters>(Query::new_with_variables(
+            r#"
+                query closeTimingParameters($allocation: String!) {
+                    graphNetwork(id: 1) {
+                        currentEpoch
+                        maxAllocationEpochs
+                        protocolFeePercentage
+                        curationPercentage
+                        alphaNumerator
+                        alphaDenominator
+                        lambdaNumerator
+                        lambdaDenominator
+                    }
+                    allocation(id: $allocation) {
+                        subgraphDeployment {
+                            signalledTokens
+                        }
+                        indexer {
+                            queryFeeCut
+                            delegatedTokens
+                        }
+                    }
+                }
+            "#,
+            [("allocation", format!("{:x?}", allocation_id).into())],
+        ))
+        .await?
+        .map_err(|e| anyhow!(e))?;
+    let network = response
+        .graph_network
+        .ok_or_else(|| anyhow!("Network 1 not found in network subgraph"))?;
+    let allocation = response
+        .allocation
+        .ok_or_else(|| anyhow!("Allocation {} not found in network subgraph", allocation_id))?;
+    Ok((network, allocation))
+}
+
+pub async fn allocation_close_timing(
+    pgpool: &PgPool,
+    network_subgraph: &SubgraphClient,
+    allocation: &Allocation,
+) -> Result<AllocationCloseTiming> {
+    let (network, parameters) = network_parameters(network_subgraph, allocation.id).await?;
+
+    // Only the total over the senders is needed, so the signers don't have to be resolved
+    let reports = allocation_statuses(
+        pgpool,
+        &HashMap::from([(allocation.id, allocation.clone())]),
+        &EscrowAccounts::default(),
+    )
+    .await?;
+    let mut fees_so_far = 0u128;
+    for report in reports.iter().filter(|r| r.allocation_id == allocation.id) {
+        fees_so_far += report.total_unaggregated_fees()?;
+        for rav in &report.ravs {
+            fees_so_far += rav.value_aggregate.parse::<u128>()?;
+        }
+    }
+
+    let curated = parameters.subgraph_deployment.signalled_tokens != "0";
+    let delegated = parameters.indexer.delegated_tokens != "0";
+    let params = RebateParameters {
+        alpha: network.alpha_numerator as f64 / network.alpha_denominator.max(1) as f64,
+        lambda: network.lambda_numerator as f64 / network.lambda_denominator.max(1) as f64,
+        protocol_tax: network.protocol_fee_percentage as f64 / MAX_PPM,
+        curation_cut: if curated {
+            network.curation_percentage as f64 / MAX_PPM
+        } else {
+            0.0
+        },
+        indexer_cut: if delegated {
+            parameters.indexer.query_fee_cut as f64 / MAX_PPM
+        } else {
+            1.0
+        },
+    };
+    let epochs_open = network
+        .current_epoch
+        .saturating_sub(allocation.created_at_epoch);
+    let input = CloseTimingInput {
+        stake: f64::from_str(&allocation.allocated_tokens.to_string())?,
+        fees_so_far: fees_so_far as f64,
+        epochs_open,
+        epochs_left: network.max_allocation_epochs.saturating_sub(epochs_open),
+        params,
+    };
+
+    Ok(AllocationCloseTiming {
+        allocation_id: allocation.id,
+        current_epoch: network.current_epoch,
+        created_at_epoch: allocation.created_at_epoch,
+        max_allocation_epochs: network.max_allocation_epochs,
+        allocated_tokens: allocation.allocated_tokens.to_string(),
+        fees_so_far: fees_so_far.to_string(),
+        estimate: estimate_close_timing(&input),
+    })
+}
+
+#[derive(Clone)]
+struct CloseTimingState {
+    pgpool: PgPool,
+    network_subgraph: &'static SubgraphClient,
+    indexer_allocations: Eventual<HashMap<Address, Allocation>>,
+}
+
+async fn handler_close_timing(
+    State(state): State<Arc<CloseTimingState>>,
+    Path(allocation_id): Path<Address>,
+) -> Result<Json<AllocationCloseTiming>, (StatusCode, String)> {
+    let allocation = state
+        .indexer_allocations
+        .value_immediate()
+        .unwrap_or_default()
+        .remove(&allocation_id)
+        .filter(|allocation| allocation.closed_at_epoch.is_none())
+        .ok_or_else(|| {
+            (
+                StatusCode::NOT_FOUND,
+                format!("No active allocation {}", allocation_id),
+            )
+        })?;
+    allocation_close_timing(&state.pgpool, state.network_subgraph, &allocation)
+        .await
+        .map(Json)
+        .map_err(|e| {
+            error!("Error while estimating the allocation close timing: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Error while estimating the allocation close timing: {}", e),
+            )
+        })
+}
+
+/// Routes serving the close timing estimates, to be mounted on the tap-agent HTTP server.
+pub fn router(
+    pgpool: PgPool,
+    network_subgraph: &'static SubgraphClient,
+    indexer_allocations: Eventual<HashMap<Address, Allocation>>,
+) -> Router {
+    Router::new()
+        .route(
+            "/state/allocations/:allocation_id/close-timing",
+            get(handler_close_timing),
+        )
+        .with_state(Arc::new(CloseTimingState {
+            pgpool,
+            network_subgraph,
+            indexer_allocations,
+        }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params() -> RebateParameters {
+        RebateParameters {
+            alpha: 1.0,
+            lambda: 0.6,
+            protocol_tax: 0.0,
+            curation_cut: 0.0,
+            indexer_cut: 1.0,
+        }
+    }
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9 * b.abs().max(1.0), "{} != {}", a, b);
+    }
+
+    #[test]
+    fn test_exponential_rebates() {
+        // fees * (1 - exp(-0.6))
+        assert_close(exponential_rebates(100.0, 100.0, 1.0, 0.6), 45.118836390597);
+        // fees * (1 - exp(-6))
+        assert_close(
+            exponential_rebates(100.0, 1000.0, 1.0, 0.6),
+            99.752124782333,
+        );
+        // The whole fees past the maximum exponent
+        assert_eq!(exponential_rebates(100.0, 100_000.0, 1.0, 0.6), 100.0);
+        // The whole fees without alpha, nothing without fees
+        assert_eq!(exponential_rebates(100.0, 1.0, 0.0, 0.6), 100.0);
+        assert_eq!(exponential_rebates(0.0, 100.0, 1.0, 0.6), 0.0);
+    }
+
+    #[test]
+    fn test_indexer_rebates() {
+        let params = RebateParameters {
+            protocol_tax: 0.01,
+            curation_cut: 0.1,
+            indexer_cut: 0.5,
+            ..params()
+        };
+        // 100 GRT less 1% of tax less 10% of curation fees, half of the rebates to delegators
+        let fees = 100.0 * 0.99 * 0.9;
+        assert_close(
+            indexer_rebates(100.0, 1000.0, &params),
+            fees * (1.0 - (-0.6f64 * 1000.0 / fees).exp()) * 0.5,
+        );
+        // No rebates without stake or lambda
+        assert_eq!(indexer_rebates(100.0, 0.0, &params), 0.0);
+        assert_eq!(
+            indexer_rebates(
+                100.0,
+                1000.0,
+                &RebateParameters {
+                    lambda: 0.0,
+                    ..params
+                }
+            ),
+            0.0
+        );
+    }
+
+    #[test]
+    fn test_estimate_close_timing() {
+        // 100 GRT of fees per epoch on 1000 GRT of stake: a fresh allocation rebates almost all
+        // the fees, while this one saturates
+        let estimate = estimate_close_timing(&CloseTimingInput {
+            stake: 1000.0,
+            fees_so_far: 1000.0,
+            epochs_open: 10,
+            epochs_left: 18,
+            params: params(),
+        });
+        assert_eq!(estimate.fees_per_epoch, "100");
+        assert_eq!(estimate.outcomes.len(), 19);
+        assert_eq!(estimate.outcomes[0].gross_fees, "1000");
+        assert!(
+            estimate.outcomes[0].marginal_rebate_ratio < estimate.fresh_allocation_rebate_ratio
+        );
+        assert_eq!(estimate.recommended_close_in_epochs, 0);
+
+        // Little fees for the stake, the rebates don't saturate before the last epoch allowed
+        let estimate = estimate_close_timing(&CloseTimingInput {
+            stake: 1_000_000.0,
+            fees_so_far: 10.0,
+            epochs_open: 10,
+            epochs_left: 18,
+            params: params(),
+        });
+        assert_eq!(estimate.recommended_close_in_epochs, 18);
+        assert_eq!(estimate.outcomes[18].indexer_rebates, "28");
+    }
+}
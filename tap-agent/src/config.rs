@@ -1,12 +1,28 @@
 // Copyright 2023-, GraphOps and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
-use clap::Parser;
-use indexer_config::{Config as IndexerConfig, ConfigPrefix};
+use bigdecimal::BigDecimal;
+use chrono::{DateTime, Utc};
+use clap::{Parser, Subcommand};
+use indexer_common::address::wallet_address;
+use indexer_common::allocations::sources::{AllocationSource, StaticAllocation};
+use indexer_common::db_pool::PoolSizing;
+use indexer_common::http_proxy::{Destination, ProxySettings};
+use indexer_common::monitor_backoff::MonitorAlarms;
+use indexer_common::secrets::{KeySource, SealedSecret};
+use indexer_config::{
+    Config as IndexerConfig, ConfigPrefix, DatabaseEncryptionConfig, MetricsConfig, NonZeroGRT,
+};
 use reqwest::Url;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::PathBuf;
-use std::{collections::HashMap, str::FromStr};
+use std::time::Duration;
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+};
 
+use crate::accounting::StatementFormat;
 use anyhow::Result;
 use thegraph::types::{Address, DeploymentId};
 use tracing::subscriber::{set_global_default, SetGlobalDefaultError};
@@ -16,28 +32,213 @@ use tracing_subscriber::{EnvFilter, FmtSubscriber};
 pub struct Cli {
     /// Path to the configuration file.
     /// See https://github.com/graphprotocol/indexer-rs/tree/main/tap-agent for examples.
-    #[arg(long, value_name = "FILE", verbatim_doc_comment)]
-    pub config: PathBuf,
+    /// Use `-` to read the configuration from stdin.
+    #[arg(
+        long,
+        value_name = "FILE",
+        verbatim_doc_comment,
+        required_unless_present = "config_schema"
+    )]
+    config: Option<PathBuf>,
+
+    /// Print the JSON Schema of the configuration file and exit, e.g. to validate the values of
+    /// a deployment before rolling it out.
+    #[arg(long, exclusive = true)]
+    pub config_schema: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+impl Cli {
+    /// Path to the configuration file, only missing with `--config-schema`.
+    pub fn config(&self) -> &PathBuf {
+        self.config
+            .as_ref()
+            .expect("`--config` is required unless `--config-schema` is set")
+    }
+}
+
+/// URL of `path` on the metrics server of the running agent, for the commands calling its
+/// admin routes.
+pub fn metrics_url(metrics: &MetricsConfig, path: &str) -> String {
+    let address = match metrics.address {
+        Some(address) if !address.is_unspecified() => address,
+        _ => Ipv4Addr::LOCALHOST.into(),
+    };
+    format!("http://{}{path}", SocketAddr::new(address, metrics.port))
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Check the connectivity to the database, graph-node, subgraphs and aggregators, the
+    /// operator key and the EIP-712 domain. Exits with 0 if all checks pass, 1 on warnings and
+    /// 2 on failures.
+    Doctor,
+    /// Export the accounting statement of a period, locking it so that later exports return the
+    /// same statement.
+    ExportStatement {
+        /// Start of the period, e.g. `2024-06-01T00:00:00Z`.
+        #[arg(long)]
+        from: DateTime<Utc>,
+        /// End of the period, excluded.
+        #[arg(long)]
+        to: DateTime<Utc>,
+        #[arg(long, value_enum, default_value_t = StatementFormat::Json)]
+        format: StatementFormat,
+        /// File to write the statement to, instead of stdout.
+        #[arg(long, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+    /// Import a RAV provided out-of-band by the operator of an aggregator, through the admin API
+    /// of the running tap-agent. The RAV is verified against the local receipts before it's
+    /// stored, and the receipts it covers are removed.
+    ImportRav {
+        /// JSON file of the signed RAV, as returned by the aggregator.
+        #[arg(long, value_name = "FILE")]
+        file: PathBuf,
+        #[arg(long)]
+        sender: Address,
+        /// Reason of the import, recorded in the audit log.
+        #[arg(long)]
+        reason: String,
+    },
+    /// Register a virtual allocation for a data service without on-chain allocations yet,
+    /// signed with the operator key, through the admin API of the running tap-agent.
+    RegisterVirtualAllocation {
+        #[arg(long)]
+        allocation_id: Address,
+        /// URL namespace of the data service, e.g. `sql`.
+        #[arg(long)]
+        service: String,
+        /// Reason of the registration, recorded in the audit log.
+        #[arg(long)]
+        reason: String,
+    },
+    /// List the failure reasons of the invalid receipts, or the receipts that failed for
+    /// `reason`, through the admin API of the running tap-agent.
+    InvalidReceipts {
+        #[arg(long)]
+        reason: Option<String>,
+        /// List the receipts after this ID.
+        #[arg(long)]
+        after_id: Option<i64>,
+    },
+    /// Validate invalid receipts again against the current escrow accounts and RAVs, and
+    /// optionally promote the ones passing back to the receipts to aggregate.
+    RevalidateReceipts {
+        /// IDs of the invalid receipts.
+        #[arg(long, value_delimiter = ',', required = true)]
+        ids: Vec<i64>,
+        #[arg(long)]
+        promote: bool,
+        /// Reason of the promotion, recorded in the audit log.
+        #[arg(long, required_if_eq("promote", "true"))]
+        reason: Option<String>,
+    },
+    /// Explain what happened to a receipt: when it was accepted, and whether it's covered by a
+    /// RAV, waiting for the next RAV request, or excluded and why, through the admin API of the
+    /// running tap-agent.
+    ExplainReceipt {
+        /// ID of the receipt to aggregate.
+        #[arg(long, required_unless_present_any = ["invalid_id", "signature"])]
+        id: Option<i64>,
+        /// ID of the invalid receipt.
+        #[arg(long, conflicts_with = "id")]
+        invalid_id: Option<i64>,
+        /// Hex encoded signature of the receipt.
+        #[arg(long, conflicts_with_all = ["id", "invalid_id"])]
+        signature: Option<String>,
+    },
+    /// Report the size, dead tuples and last vacuum of the TAP tables and their indexes, with the
+    /// suggested VACUUM and REINDEX statements, through the admin API of the running tap-agent.
+    TableHealth,
+    /// List the allocations whose fee accounting overflowed, pausing their sender, through the
+    /// admin API of the running tap-agent.
+    FeeOverflows,
+    /// Acknowledge the fee overflow of an allocation, summing its fees again from the database,
+    /// through the admin API of the running tap-agent.
+    AcknowledgeFeeOverflow {
+        #[arg(long)]
+        sender: Address,
+        #[arg(long)]
+        allocation_id: Address,
+        /// Reason of the acknowledgment, recorded in the audit log.
+        #[arg(long)]
+        reason: String,
+    },
+    /// Assign the rows written before the tenant isolation was enabled, i.e. without an owner,
+    /// to this indexer. To run once when enabling it, while only this indexer uses the database.
+    ClaimUnownedRows,
+    /// Advise which last RAVs are economical to redeem at the current gas price, and which ones
+    /// to batch later, through the running tap-agent.
+    RedemptionAdvice {
+        /// List the advice logged since this time instead, e.g. `2024-08-01T00:00:00Z`.
+        #[arg(long)]
+        history_since: Option<DateTime<Utc>>,
+    },
 }
 
 impl From<IndexerConfig> for Config {
     fn from(value: IndexerConfig) -> Self {
+        // The service accepts receipts for closed allocations for as long as the longest buffer
+        let recently_closed_allocation_buffer = value
+            .subgraphs
+            .network
+            .max_recently_closed_allocation_buffer();
+        let chain_id = value.blockchain.chain_id.clone() as u64;
+        let proxy = proxy_settings(&value);
         Self {
             ethereum: Ethereum {
                 indexer_address: value.indexer.indexer_address,
+                operator_key: OperatorKey(SealedSecret::new(
+                    value.indexer.operator_mnemonic.to_string(),
+                )),
             },
             receipts: Receipts {
                 receipts_verifier_chain_id: value.blockchain.chain_id as u64,
                 receipts_verifier_address: value.blockchain.receipts_verifier_address,
+                allow_escrow_network_mismatch: value.blockchain.allow_escrow_network_mismatch,
             },
             indexer_infrastructure: IndexerInfrastructure {
+                metrics_address: value.metrics.address,
                 metrics_port: value.metrics.port,
                 graph_node_query_endpoint: value.graph_node.query_url.into(),
                 graph_node_status_endpoint: value.graph_node.status_url.into(),
                 log_level: None,
+                monitor_alarms: MonitorAlarms {
+                    after_failures: value.metrics.monitor_alarm_after_failures,
+                    webhook_url: value.metrics.monitor_alarm_webhook_url.map(Into::into),
+                },
+                proxy,
             },
             postgres: Postgres {
                 postgres_url: value.database.postgres_url,
+                slow_query_threshold: value.database.slow_query_threshold_secs,
+                replica_postgres_url: value.database.replica_postgres_url,
+                replica_max_wait: value.database.replica_max_wait_secs,
+                tenant: value
+                    .database
+                    .tenant_isolation
+                    .then_some(value.indexer.indexer_address),
+                receipt_notification_socket: value.database.receipt_notification_socket,
+                pool: PoolSizing {
+                    min_connections: value.database.pool.min_connections,
+                    max_connections: value.database.pool.max_connections,
+                    target_acquire_wait: value.database.pool.target_acquire_wait_secs,
+                    idle_timeout: value.database.pool.idle_timeout_secs,
+                },
+                encryption: value
+                    .database
+                    .encryption
+                    .map(|encryption| match encryption {
+                        DatabaseEncryptionConfig::Env { key_variable } => KeySource::Env {
+                            variable: key_variable,
+                        },
+                        DatabaseEncryptionConfig::Command { key_command } => KeySource::Command {
+                            command: key_command,
+                        },
+                    }),
             },
             network_subgraph: NetworkSubgraph {
                 network_subgraph_deployment: value.subgraphs.network.config.deployment_id,
@@ -49,11 +250,37 @@ impl From<IndexerConfig> for Config {
                     .config
                     .syncing_interval_secs
                     .as_millis() as u64,
-                recently_closed_allocation_buffer_seconds: value
+                recently_closed_allocation_buffer_seconds: recently_closed_allocation_buffer
+                    .as_secs(),
+                network_subgraph_additional_endpoints: value
                     .subgraphs
                     .network
-                    .recently_closed_allocation_buffer_secs
-                    .as_secs(),
+                    .additional_query_urls
+                    .into_iter()
+                    .map(Into::into)
+                    .collect(),
+                network_subgraph_quorum: value.subgraphs.network.quorum,
+                rollover_overlap_seconds: value
+                    .subgraphs
+                    .network
+                    .rollover_overlap_secs
+                    .map(|overlap| overlap.as_secs()),
+                allocation_source: match value.subgraphs.network.allocation_source {
+                    indexer_config::AllocationSource::Network => AllocationSource::Network,
+                    indexer_config::AllocationSource::Static => AllocationSource::Static,
+                    indexer_config::AllocationSource::Api => AllocationSource::Api,
+                },
+                static_allocations: value
+                    .subgraphs
+                    .network
+                    .static_allocations
+                    .iter()
+                    .map(|allocation| StaticAllocation {
+                        id: allocation.id,
+                        deployment: allocation.deployment,
+                        created_at_epoch: allocation.created_at_epoch,
+                    })
+                    .collect(),
             },
             escrow_subgraph: EscrowSubgraph {
                 escrow_subgraph_deployment: value.subgraphs.escrow.config.deployment_id,
@@ -65,8 +292,24 @@ impl From<IndexerConfig> for Config {
                     .config
                     .syncing_interval_secs
                     .as_millis() as u64,
+                escrow_page_size: value.subgraphs.escrow.page_size,
             },
             tap: Tap {
+                trigger_value_tuning: value.tap.rav_request.trigger_value_tuning.as_ref().map(
+                    |tuning| TriggerValueTuning {
+                        window: tuning.window_secs,
+                        min_rav_request_interval: tuning.min_request_interval_secs,
+                        auto_apply: tuning.auto_apply,
+                        min_trigger_value: tuning
+                            .min_trigger_value_grt
+                            .as_ref()
+                            .map_or(value.tap.get_trigger_value() / 4, |min| min.get_value()),
+                        max_trigger_value: tuning.max_trigger_value_grt.as_ref().map_or(
+                            value.tap.max_amount_willing_to_lose_grt.get_value() / 2,
+                            |max| max.get_value(),
+                        ),
+                    },
+                ),
                 rav_request_trigger_value: value.tap.get_trigger_value(),
                 rav_request_timestamp_buffer_ms: value
                     .tap
@@ -80,11 +323,158 @@ impl From<IndexerConfig> for Config {
                     .into_iter()
                     .map(|(addr, url)| (addr, url.into()))
                     .collect(),
+                sender_aggregator_additional_endpoints: value
+                    .tap
+                    .sender_aggregator_additional_endpoints
+                    .into_iter()
+                    .map(|(addr, urls)| (addr, urls.into_iter().map(Into::into).collect()))
+                    .collect(),
                 rav_request_receipt_limit: value.tap.rav_request.max_receipts_per_request,
+                max_concurrent_closures: value.tap.rav_request.max_concurrent_closures,
+                final_rav_chunk_receipts: value.tap.rav_request.max_receipts_per_final_chunk,
+                aggregator_size_limits: AggregatorSizeLimits {
+                    max_request_size: value.tap.rav_request.max_request_size_bytes,
+                    max_response_size: value.tap.rav_request.max_response_size_bytes,
+                },
+                sender_aggregator_size_limits: value
+                    .tap
+                    .sender_aggregator_size_limits
+                    .iter()
+                    .map(|(sender, limits)| {
+                        (
+                            *sender,
+                            AggregatorSizeLimits {
+                                max_request_size: limits
+                                    .max_request_size_bytes
+                                    .unwrap_or(value.tap.rav_request.max_request_size_bytes),
+                                max_response_size: limits
+                                    .max_response_size_bytes
+                                    .unwrap_or(value.tap.rav_request.max_response_size_bytes),
+                            },
+                        )
+                    })
+                    .collect(),
                 max_unnaggregated_fees_per_sender: value
                     .tap
                     .max_amount_willing_to_lose_grt
                     .get_value(),
+                max_unaggregated_fees: value
+                    .tap
+                    .max_unaggregated_fees_grt
+                    .as_ref()
+                    .map(NonZeroGRT::get_value),
+                max_unaggregated_fees_per_sender: value
+                    .tap
+                    .max_unaggregated_fees_grt_per_sender
+                    .iter()
+                    .map(|(sender, cap)| (*sender, cap.get_value()))
+                    .collect(),
+                sender_allocation_idle_timeout: value.tap.sender_allocation_idle_timeout_secs,
+                aggregator_health: value.tap.aggregator_health.as_ref().map(|health| {
+                    AggregatorHealthProbing {
+                        interval: health.probe_interval_secs,
+                        timeout: health.probe_timeout_secs,
+                        unhealthy_after_failures: health.unhealthy_after_failures,
+                    }
+                }),
+                reconciliation: value.tap.reconciliation.as_ref().map(|reconciliation| {
+                    Reconciliation {
+                        interval: reconciliation.interval_secs,
+                        drift_threshold: reconciliation
+                            .drift_threshold_grt
+                            .as_ref()
+                            .map_or(0, NonZeroGRT::get_value),
+                    }
+                }),
+                poi_gating: value.tap.poi_gating.as_ref().map(|poi_gating| PoiGating {
+                    confirmations: poi_gating.confirmations,
+                    poll_interval: poi_gating.poll_interval_secs,
+                    timeout: poi_gating.timeout_secs,
+                }),
+                vanished_allocation_grace_period: value.tap.vanished_allocations.as_ref().map(
+                    |vanished| {
+                        value.subgraphs.network.config.syncing_interval_secs
+                            * vanished.refresh_cycles
+                    },
+                ),
+                sharding: value.tap.sharding.as_ref().map(|sharding| Sharding {
+                    count: sharding.shard_count,
+                    index: sharding.shard_index,
+                    lease_duration: sharding.lease_duration_secs,
+                }),
+                shutdown_rav_flush: value.tap.shutdown_rav_flush.as_ref().map(|flush| {
+                    ShutdownRavFlush {
+                        min_unaggregated_fees: flush.min_unaggregated_fees_grt.get_value(),
+                        timeout: flush.timeout_secs,
+                    }
+                }),
+                admin_auth_token: value.tap.admin_auth_token,
+                indexing_agreements: value.tap.indexing_agreements.is_some(),
+                rav_verification_webhook_url: value.tap.rav_verification_webhook_url,
+                unaggregated_fees_chunk_size: value.tap.unaggregated_fees_chunk_size,
+                redemption_advice: value
+                    .tap
+                    .redemption_advice
+                    .as_ref()
+                    .and_then(|advice| {
+                        advice
+                            .networks
+                            .iter()
+                            .find(|network| network.chain_id == chain_id)
+                    })
+                    .map(|network| RedemptionAdvice {
+                        chain_id,
+                        gas_oracle_url: network.gas_oracle_url.clone(),
+                        redeem_gas: network.redeem_gas,
+                        native_token_price_grt: network.native_token_price_grt.clone(),
+                        min_value_to_cost_ratio: network.min_value_to_cost_ratio.clone(),
+                        max_wait: network.max_wait_secs,
+                    }),
+                trace_export: value.tap.trace_export.map(|trace_export| TraceExport {
+                    endpoint: trace_export.endpoint,
+                    bucket: trace_export.bucket,
+                    region: trace_export.region,
+                    access_key_id: trace_export.access_key_id,
+                    secret_access_key: SealedSecret::new(trace_export.secret_access_key),
+                    prefix: trace_export.prefix,
+                    retention: trace_export.retention_secs,
+                    invalid_receipts_threshold: trace_export.invalid_receipts_threshold,
+                    min_interval: trace_export.min_interval_secs,
+                }),
+                admin_signers: value.tap.require_signed_admin_actions.then(|| {
+                    let operator = wallet_address(&value.indexer.operator_mnemonic.to_string())
+                        .expect("the operator mnemonic was validated with the configuration");
+                    std::iter::once(operator)
+                        .chain(value.tap.admin_signers.iter().copied())
+                        .collect()
+                }),
+                virtual_allocations: (!value.tap.virtual_allocation_services.is_empty()).then(
+                    || VirtualAllocations {
+                        operator: wallet_address(&value.indexer.operator_mnemonic.to_string())
+                            .expect("the operator mnemonic was validated with the configuration"),
+                        services: value
+                            .tap
+                            .virtual_allocation_services
+                            .iter()
+                            .cloned()
+                            .collect(),
+                    },
+                ),
+                scheduler: value
+                    .tap
+                    .scheduler
+                    .into_iter()
+                    .map(|(name, job)| {
+                        (
+                            name,
+                            ScheduledJob {
+                                enabled: job.enabled,
+                                schedule: job.schedule,
+                                jitter: job.jitter_secs,
+                            },
+                        )
+                    })
+                    .collect(),
             },
             config: None,
         }
@@ -106,31 +496,66 @@ pub struct Config {
 #[derive(Clone, Debug, Default)]
 pub struct Ethereum {
     pub indexer_address: Address,
+    /// Signs the sender statements.
+    pub operator_key: OperatorKey,
 }
 
+/// Operator mnemonic or private key, kept sealed in memory and never printed.
+#[derive(Clone, Debug, Default)]
+pub struct OperatorKey(pub SealedSecret);
+
 #[derive(Clone, Debug, Default)]
 pub struct Receipts {
     pub receipts_verifier_chain_id: u64,
     pub receipts_verifier_address: Address,
+    /// Only logs an error when the escrow subgraph indexes another network than the receipts
+    /// verifier.
+    pub allow_escrow_network_mismatch: bool,
 }
 
 #[derive(Clone, Debug, Default)]
 pub struct IndexerInfrastructure {
+    /// All interfaces if not set.
+    pub metrics_address: Option<IpAddr>,
     pub metrics_port: u16,
     pub graph_node_query_endpoint: String,
     pub graph_node_status_endpoint: String,
     pub log_level: Option<String>,
+    /// Alarms of the monitors failing repeatedly, see [indexer_common::monitor_backoff].
+    pub monitor_alarms: MonitorAlarms,
+    /// Outbound proxies of the HTTP requests, see [indexer_common::http_proxy].
+    pub proxy: ProxySettings,
 }
 
 #[derive(Clone, Debug)]
 pub struct Postgres {
     pub postgres_url: Url,
+    pub slow_query_threshold: Duration,
+    /// Replica serving the receipt reads, see [indexer_common::db_consistency].
+    pub replica_postgres_url: Option<Url>,
+    pub replica_max_wait: Duration,
+    /// Indexer owning the rows, when isolated from the other indexers sharing the database,
+    /// see [indexer_common::tenant].
+    pub tenant: Option<Address>,
+    /// Socket the indexer-service notifies the new receipts through besides PG NOTIFY, see
+    /// [indexer_common::receipt_notifications].
+    pub receipt_notification_socket: Option<PathBuf>,
+    pub pool: PoolSizing,
+    /// Key encrypting the failed RAV requests, see [indexer_common::secrets].
+    pub encryption: Option<KeySource>,
 }
 
 impl Default for Postgres {
     fn default() -> Self {
         Self {
             postgres_url: Url::from_str("postgres:://postgres@postgres/postgres").unwrap(),
+            slow_query_threshold: Duration::from_secs(1),
+            replica_postgres_url: None,
+            replica_max_wait: Duration::from_millis(500),
+            tenant: None,
+            receipt_notification_socket: None,
+            pool: PoolSizing::default(),
+            encryption: None,
         }
     }
 }
@@ -142,6 +567,15 @@ pub struct NetworkSubgraph {
     pub network_subgraph_auth_token: Option<String>,
     pub allocation_syncing_interval_ms: u64,
     pub recently_closed_allocation_buffer_seconds: u64,
+    pub network_subgraph_additional_endpoints: Vec<String>,
+    pub network_subgraph_quorum: Option<usize>,
+    /// Replaced allocations are finalized no sooner than this long after their successor was
+    /// created, nor before the end of the recently closed allocations buffer.
+    pub rollover_overlap_seconds: Option<u64>,
+    /// The network subgraph isn't queried for the allocations but with
+    /// [AllocationSource::Network].
+    pub allocation_source: AllocationSource,
+    pub static_allocations: Vec<StaticAllocation>,
 }
 
 #[derive(Clone, Debug, Default)]
@@ -150,6 +584,7 @@ pub struct EscrowSubgraph {
     pub escrow_subgraph_endpoint: String,
     pub escrow_subgraph_auth_token: Option<String>,
     pub escrow_syncing_interval_ms: u64,
+    pub escrow_page_size: usize,
 }
 
 #[derive(Clone, Debug, Default)]
@@ -158,8 +593,282 @@ pub struct Tap {
     pub rav_request_timestamp_buffer_ms: u64,
     pub rav_request_timeout_secs: u64,
     pub sender_aggregator_endpoints: HashMap<Address, String>,
+    /// Other endpoints of the senders' aggregators, failed over to.
+    pub sender_aggregator_additional_endpoints: HashMap<Address, Vec<String>>,
     pub rav_request_receipt_limit: u64,
+    /// Closed allocations requesting their last RAV at the same time.
+    pub max_concurrent_closures: usize,
+    /// Receipts per RAV request when aggregating the last RAV of a closed allocation, instead
+    /// of `rav_request_receipt_limit`.
+    pub final_rav_chunk_receipts: Option<u64>,
+    pub aggregator_size_limits: AggregatorSizeLimits,
+    /// Overrides of `aggregator_size_limits`, by sender.
+    pub sender_aggregator_size_limits: HashMap<Address, AggregatorSizeLimits>,
     pub max_unnaggregated_fees_per_sender: u128,
+    /// Hard cap on the unaggregated fees of a sender, which is paused once it's reached.
+    pub max_unaggregated_fees: Option<u128>,
+    /// Overrides of `max_unaggregated_fees`, by sender.
+    pub max_unaggregated_fees_per_sender: HashMap<Address, u128>,
+    /// When set, sender allocations are spawned on their first receipt and stopped after
+    /// being idle for this long.
+    pub sender_allocation_idle_timeout: Option<Duration>,
+    /// Overrides for the periodic maintenance jobs, by job name.
+    pub scheduler: HashMap<String, ScheduledJob>,
+    pub trigger_value_tuning: Option<TriggerValueTuning>,
+    /// When set, the senders' aggregators are probed and RAV requests are postponed while
+    /// they are unhealthy.
+    pub aggregator_health: Option<AggregatorHealthProbing>,
+    /// When set, the unaggregated fees of the sender allocations are periodically compared
+    /// with the receipts in the database.
+    pub reconciliation: Option<Reconciliation>,
+    /// When set, the last RAVs of a closed allocation are marked once the POI of the close is
+    /// posted, see [crate::poi_gate].
+    pub poi_gating: Option<PoiGating>,
+    /// When set, the sender allocations of the allocations missing from the allocation source
+    /// for this long are closed, see [crate::vanished_allocations].
+    pub vanished_allocation_grace_period: Option<Duration>,
+    /// When set, only the senders of this shard are managed, see [crate::sharding].
+    pub sharding: Option<Sharding>,
+    /// When set, the last RAVs are requested on SIGTERM and SIGINT, see
+    /// [crate::agent::shutdown].
+    pub shutdown_rav_flush: Option<ShutdownRavFlush>,
+    /// Bearer token of the admin API. The admin API is disabled when not set.
+    pub admin_auth_token: Option<String>,
+    /// When set, the admin actions making changes must be signed by one of these keys, the
+    /// operator's and the configured admin signers.
+    pub admin_signers: Option<Vec<Address>>,
+    /// When set, the virtual allocations are tracked along with the on-chain ones.
+    pub virtual_allocations: Option<VirtualAllocations>,
+    /// Where the RAVs failing verification are posted, see [crate::rav_verification].
+    pub rav_verification_webhook_url: Option<Url>,
+    /// When set, the unaggregated fees are recalculated from this many receipt ids at a time.
+    pub unaggregated_fees_chunk_size: Option<u64>,
+    /// When set, the anomalous RAV and receipt flows are exported, see [crate::trace_bundles].
+    pub trace_export: Option<TraceExport>,
+    /// When set, the last RAVs are advised to be redeemed or batched later, see
+    /// [crate::redemption_advice].
+    pub redemption_advice: Option<RedemptionAdvice>,
+    /// Whether RAVs are requested for the fees of the indexing agreements, see
+    /// [crate::agreements].
+    pub indexing_agreements: bool,
+}
+
+impl Tap {
+    /// Hard cap on the unaggregated fees of `sender`, if any.
+    pub fn max_unaggregated_fees_for(&self, sender: &Address) -> Option<u128> {
+        self.max_unaggregated_fees_per_sender
+            .get(sender)
+            .copied()
+            .or(self.max_unaggregated_fees)
+    }
+
+    /// Endpoints of the aggregator of `sender`, `endpoint` being the one it was configured with.
+    pub fn aggregator_endpoints_for(&self, sender: &Address, endpoint: &str) -> Vec<String> {
+        std::iter::once(endpoint.to_string())
+            .chain(
+                self.sender_aggregator_additional_endpoints
+                    .get(sender)
+                    .into_iter()
+                    .flatten()
+                    .cloned(),
+            )
+            .collect()
+    }
+
+    /// Whether `sender` is managed by this instance, which is always the case without sharding.
+    pub fn owns_sender(&self, sender: &Address) -> bool {
+        self.sharding
+            .as_ref()
+            .map_or(true, |sharding| sharding.owns(sender))
+    }
+
+    /// Key of the shard of this instance in the checkpoints, empty without sharding.
+    pub fn shard_key(&self) -> String {
+        self.sharding
+            .as_ref()
+            .map(Sharding::key)
+            .unwrap_or_default()
+    }
+
+    pub fn aggregator_size_limits_for(&self, sender: &Address) -> AggregatorSizeLimits {
+        self.sender_aggregator_size_limits
+            .get(sender)
+            .copied()
+            .unwrap_or(self.aggregator_size_limits)
+    }
+}
+
+/// Virtual allocations accepted by the indexer-service, see
+/// [indexer_common::allocations::virtual_allocations].
+#[derive(Clone, Debug)]
+pub struct VirtualAllocations {
+    /// Address of the operator key, signing the virtual allocations.
+    pub operator: Address,
+    /// URL namespaces of the data services accepting them.
+    pub services: HashSet<String>,
+}
+
+/// Maximum sizes, in bytes, of the requests sent to an aggregator and of its responses.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AggregatorSizeLimits {
+    pub max_request_size: u32,
+    pub max_response_size: u32,
+}
+
+impl Default for AggregatorSizeLimits {
+    fn default() -> Self {
+        // Defaults of jsonrpsee
+        Self {
+            max_request_size: 10 * 1024 * 1024,
+            max_response_size: 10 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Reconciliation {
+    pub interval: Duration,
+    /// Drifts up to this value, in GRT wei, are not corrected.
+    pub drift_threshold: u128,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Sharding {
+    pub count: u32,
+    pub index: u32,
+    pub lease_duration: Duration,
+}
+
+impl Sharding {
+    /// Whether `sender` belongs to the shard of this instance.
+    pub fn owns(&self, sender: &Address) -> bool {
+        crate::sharding::shard_of(sender, self.count) == self.index
+    }
+
+    /// Key of the shard in the checkpoints, e.g. `1/4`.
+    pub fn key(&self) -> String {
+        format!("{}/{}", self.index, self.count)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PoiGating {
+    /// Blocks the close of the allocation must be confirmed by.
+    pub confirmations: u64,
+    pub poll_interval: Duration,
+    /// How long to wait for the POI before marking the last RAVs anyway.
+    pub timeout: Duration,
+}
+
+#[derive(Clone, Debug)]
+pub struct ShutdownRavFlush {
+    /// Unaggregated fees of a sender allocation requesting a last RAV, in GRT wei.
+    pub min_unaggregated_fees: u128,
+    /// How long to wait for the last RAVs before stopping anyway.
+    pub timeout: Duration,
+}
+
+/// Thresholds of the redemption advice on the network of the receipts.
+#[derive(Clone, Debug)]
+pub struct RedemptionAdvice {
+    pub chain_id: u64,
+    /// JSON-RPC endpoint the gas price is read from.
+    pub gas_oracle_url: Url,
+    /// Gas used by the transaction redeeming a RAV.
+    pub redeem_gas: u64,
+    pub native_token_price_grt: BigDecimal,
+    /// Value of a RAV over the cost of redeeming it from which it's economical to redeem.
+    pub min_value_to_cost_ratio: BigDecimal,
+    /// Age of the last receipt of a RAV from which it's redeemed anyway.
+    pub max_wait: Duration,
+}
+
+/// S3 compatible storage the anomalous flows are exported to, see [crate::trace_bundles].
+#[derive(Clone, Debug)]
+pub struct TraceExport {
+    pub endpoint: Url,
+    pub bucket: String,
+    pub region: String,
+    pub access_key_id: String,
+    pub secret_access_key: SealedSecret,
+    /// Prefix of the object keys.
+    pub prefix: String,
+    pub retention: Duration,
+    /// Invalid receipts found by a single RAV request from which they are exported.
+    pub invalid_receipts_threshold: usize,
+    /// Minimum interval between two bundles of the same allocation and kind.
+    pub min_interval: Duration,
+}
+
+#[derive(Clone, Debug)]
+pub struct AggregatorHealthProbing {
+    pub interval: Duration,
+    pub timeout: Duration,
+    pub unhealthy_after_failures: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct TriggerValueTuning {
+    pub window: Duration,
+    pub min_rav_request_interval: Duration,
+    /// Apply the suggested trigger values, clamped to the bounds below.
+    pub auto_apply: bool,
+    pub min_trigger_value: u128,
+    pub max_trigger_value: u128,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ScheduledJob {
+    pub enabled: bool,
+    pub schedule: Option<String>,
+    pub jitter: Duration,
+}
+
+/// Proxies of the HTTP requests, the destinations being told apart by their endpoints.
+pub fn proxy_settings(config: &IndexerConfig) -> ProxySettings {
+    let Some(proxy) = &config.proxy else {
+        return ProxySettings::default();
+    };
+    let graph_node = &config.graph_node;
+    let network = &config.subgraphs.network;
+    let tap = &config.tap;
+    let endpoints = [&graph_node.query_url, &graph_node.status_url]
+        .into_iter()
+        .chain(&graph_node.additional_query_urls)
+        .map(|url| (Destination::GraphNode, url.to_string()))
+        .chain(
+            [
+                &network.config.query_url,
+                &config.subgraphs.escrow.config.query_url,
+            ]
+            .into_iter()
+            .chain(&network.additional_query_urls)
+            .map(|url| (Destination::Subgraphs, url.to_string())),
+        )
+        .chain(
+            tap.sender_aggregator_endpoints
+                .values()
+                .chain(
+                    tap.sender_aggregator_additional_endpoints
+                        .values()
+                        .flatten(),
+                )
+                .map(|url| (Destination::Aggregators, url.to_string())),
+        )
+        .collect();
+    ProxySettings {
+        url: proxy.url.as_ref().map(ToString::to_string),
+        destinations: [
+            (Destination::GraphNode, &proxy.graph_node_url),
+            (Destination::Subgraphs, &proxy.subgraphs_url),
+            (Destination::Aggregators, &proxy.aggregators_url),
+        ]
+        .into_iter()
+        .filter_map(|(destination, url)| Some((destination, url.as_ref()?.to_string())))
+        .collect(),
+        endpoints,
+        no_proxy: proxy.no_proxy.clone(),
+    }
 }
 
 /// Sets up tracing, allows log level to be set from the environment variables
@@ -181,8 +890,8 @@ fn init_tracing(format: String) -> Result<(), SetGlobalDefaultError> {
 impl Config {
     pub fn from_cli() -> Result<Self> {
         let cli = Cli::parse();
-        let indexer_config =
-            IndexerConfig::parse(ConfigPrefix::Tap, &cli.config).map_err(|e| anyhow::anyhow!(e))?;
+        let indexer_config = IndexerConfig::parse(ConfigPrefix::Tap, cli.config())
+            .map_err(|e| anyhow::anyhow!(e))?;
         let config: Config = indexer_config.into();
 
         // Enables tracing under RUST_LOG variable
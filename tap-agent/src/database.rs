@@ -3,7 +3,8 @@
 
 use std::time::Duration;
 
-use sqlx::{postgres::PgPoolOptions, PgPool};
+use indexer_common::{db_consistency, db_metrics, db_pool, secrets, tenant};
+use sqlx::PgPool;
 use tracing::debug;
 
 use crate::config;
@@ -16,10 +17,40 @@ pub async fn connect(config: &config::Postgres) -> PgPool {
         postgres_database = tracing::field::debug(&url.path()),
         "Connecting to database"
     );
-    PgPoolOptions::new()
-        .max_connections(50)
-        .acquire_timeout(Duration::from_secs(3))
+    db_metrics::set_slow_query_threshold(config.slow_query_threshold);
+    db_pool::configure(config.pool.clone());
+    if let Some(encryption) = &config.encryption {
+        secrets::set_database_key(&*encryption.provider())
+            .await
+            .expect("Could not get the database encryption key");
+    }
+    let pool_options = || {
+        let options = config
+            .pool
+            .pool_options()
+            .acquire_timeout(Duration::from_secs(3));
+        match config.tenant {
+            Some(indexer_address) => tenant::isolate(options, indexer_address),
+            None => options,
+        }
+    };
+
+    if let Some(replica_url) = &config.replica_postgres_url {
+        debug!(
+            postgres_host = tracing::field::debug(&replica_url.host()),
+            "Connecting to database replica"
+        );
+        let replica = pool_options()
+            .connect(replica_url.as_str())
+            .await
+            .expect("Could not connect to the database replica");
+        db_consistency::set_replica(replica, config.replica_max_wait);
+    }
+
+    let pgpool = pool_options()
         .connect(url.as_str())
         .await
-        .expect("Could not connect to DATABASE_URL")
+        .expect("Could not connect to DATABASE_URL");
+    db_pool::monitor("tap_agent", &pgpool);
+    pgpool
 }
@@ -0,0 +1,503 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Admin API overriding the escrow balance or signers of a sender, for emergencies like an
+//! escrow subgraph outage once the operator has verified the balances out-of-band. Overrides
+//! expire, and every change is recorded with its reason in the operator audit log, along with
+//! its signature when [signed admin actions](crate::admin_signature) are required. The
+//! overrides are applied by both the indexer-service and the tap-agent.
+
+use std::str::FromStr;
+use std::sync::Arc;
+
+use alloy_primitives::hex::ToHex;
+use anyhow::{anyhow, Result};
+use axum::{
+    extract::{Path, Query, Request, State},
+    http::{header, StatusCode},
+    middleware::{self, Next},
+    response::Response,
+    routing::{get, put},
+    Extension, Json, Router,
+};
+use chrono::{DateTime, Duration, Utc};
+use indexer_common::types::SenderAddress;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{types::BigDecimal, PgPool, Row};
+use subtle::ConstantTimeEq;
+use thegraph::types::Address;
+use tracing::{error, warn};
+
+use crate::admin_signature::{require_signature, AdminSignature, AdminSigners};
+
+/// Overrides are meant to bridge an outage, not to replace the escrow subgraph.
+const MAX_OVERRIDE_DURATION: Duration = Duration::days(7);
+const AUDIT_LOG_PAGE_SIZE: i64 = 100;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SetEscrowOverride {
+    /// Escrow balance of the sender, in GRT wei.
+    pub balance: Option<String>,
+    pub signers: Option<Vec<Address>>,
+    pub expires_at: DateTime<Utc>,
+    pub reason: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RemoveEscrowOverride {
+    pub reason: String,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EscrowOverride {
+    pub sender: Address,
+    pub balance: Option<String>,
+    pub signers: Option<Vec<Address>>,
+    pub reason: String,
+    pub expires_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditLogEntry {
+    pub id: i64,
+    pub action: String,
+    pub subject: String,
+    pub reason: String,
+    pub details: serde_json::Value,
+    /// Key that signed the action, when signatures are required.
+    pub signer: Option<Address>,
+    pub signature: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl SetEscrowOverride {
+    fn validate(&self, now: DateTime<Utc>) -> Result<Option<BigDecimal>> {
+        if self.reason.trim().is_empty() {
+            return Err(anyhow!("A reason is required"));
+        }
+        if self.balance.is_none() && self.signers.is_none() {
+            return Err(anyhow!("Nothing to override, set a balance or signers"));
+        }
+        if self.expires_at <= now {
+            return Err(anyhow!("The override expires in the past"));
+        }
+        if self.expires_at > now + MAX_OVERRIDE_DURATION {
+            return Err(anyhow!(
+                "Overrides can't last more than {} days",
+                MAX_OVERRIDE_DURATION.num_days()
+            ));
+        }
+        self.balance
+            .as_ref()
+            .map(|balance| {
+                let balance = BigDecimal::from_str(balance)?;
+                if !balance.is_integer() || balance < BigDecimal::from(0) {
+                    return Err(anyhow!("The balance must be a positive amount of GRT wei"));
+                }
+                Ok(balance)
+            })
+            .transpose()
+    }
+}
+
+pub async fn set_escrow_override(
+    pgpool: &PgPool,
+    sender: Address,
+    request: &SetEscrowOverride,
+    signature: Option<&AdminSignature>,
+) -> Result<()> {
+    let balance = request.validate(Utc::now())?;
+    let signers = request.signers.as_ref().map(|signers| {
+        signers
+            .iter()
+            .map(|signer| signer.encode_hex::<String>())
+            .collect::<Vec<_>>()
+    });
+
+    let mut tx = pgpool.begin().await?;
+    sqlx::query(
+        r#"
+            INSERT INTO escrow_account_overrides (
+                sender_address, balance, signers, reason, expires_at
+            )
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (sender_address, (COALESCE(indexer_address, ''))) DO UPDATE SET
+                balance = EXCLUDED.balance,
+                signers = EXCLUDED.signers,
+                reason = EXCLUDED.reason,
+                expires_at = EXCLUDED.expires_at,
+                created_at = NOW()
+        "#,
+    )
+    .bind(SenderAddress(sender))
+    .bind(balance)
+    .bind(signers)
+    .bind(&request.reason)
+    .bind(request.expires_at)
+    .execute(&mut *tx)
+    .await?;
+    record_action(
+        &mut tx,
+        "set_escrow_override",
+        sender,
+        &request.reason,
+        json!({
+            "balance": request.balance,
+            "signers": request.signers,
+            "expiresAt": request.expires_at,
+        }),
+        signature,
+    )
+    .await?;
+    tx.commit().await?;
+
+    warn!(
+        %sender,
+        balance = ?request.balance,
+        signers = ?request.signers,
+        expires_at = %request.expires_at,
+        reason = %request.reason,
+        "Escrow account overridden by the operator"
+    );
+    Ok(())
+}
+
+/// Returns whether there was an override to remove.
+pub async fn remove_escrow_override(
+    pgpool: &PgPool,
+    sender: Address,
+    reason: &str,
+    signature: Option<&AdminSignature>,
+) -> Result<bool> {
+    if reason.trim().is_empty() {
+        return Err(anyhow!("A reason is required"));
+    }
+
+    let mut tx = pgpool.begin().await?;
+    let removed = sqlx::query(
+        r#"
+            DELETE FROM escrow_account_overrides
+            WHERE sender_address = $1
+        "#,
+    )
+    .bind(SenderAddress(sender))
+    .execute(&mut *tx)
+    .await?
+    .rows_affected()
+        > 0;
+    if removed {
+        record_action(
+            &mut tx,
+            "remove_escrow_override",
+            sender,
+            reason,
+            json!({}),
+            signature,
+        )
+        .await?;
+    }
+    tx.commit().await?;
+
+    if removed {
+        warn!(%sender, reason, "Escrow account override removed by the operator");
+    }
+    Ok(removed)
+}
+
+/// Every override, including the expired ones that no longer apply.
+pub async fn escrow_overrides(pgpool: &PgPool) -> Result<Vec<EscrowOverride>> {
+    let rows = sqlx::query(
+        r#"
+            SELECT sender_address, balance, signers, reason, expires_at, created_at
+            FROM escrow_account_overrides
+            ORDER BY sender_address
+        "#,
+    )
+    .fetch_all(pgpool)
+    .await?;
+
+    rows.iter()
+        .map(|row| {
+            Ok(EscrowOverride {
+                sender: row.try_get::<SenderAddress, _>("sender_address")?.0,
+                balance: row
+                    .try_get::<Option<BigDecimal>, _>("balance")?
+                    .map(|balance| balance.to_string()),
+                signers: row
+                    .try_get::<Option<Vec<String>>, _>("signers")?
+                    .map(|signers| {
+                        signers
+                            .iter()
+                            .map(|signer| Address::from_str(signer))
+                            .collect::<Result<Vec<_>, _>>()
+                    })
+                    .transpose()?,
+                reason: row.try_get("reason")?,
+                expires_at: row.try_get("expires_at")?,
+                created_at: row.try_get("created_at")?,
+            })
+        })
+        .collect()
+}
+
+/// The latest operator actions, from the most recent one.
+pub async fn audit_log(pgpool: &PgPool) -> Result<Vec<AuditLogEntry>> {
+    let rows = sqlx::query(
+        r#"
+            SELECT id, action, subject, reason, details, signer, signature, created_at
+            FROM operator_audit_log
+            ORDER BY id DESC
+            LIMIT $1
+        "#,
+    )
+    .bind(AUDIT_LOG_PAGE_SIZE)
+    .fetch_all(pgpool)
+    .await?;
+
+    rows.iter()
+        .map(|row| {
+            Ok(AuditLogEntry {
+                id: row.try_get("id")?,
+                action: row.try_get("action")?,
+                subject: row.try_get("subject")?,
+                reason: row.try_get("reason")?,
+                details: row.try_get("details")?,
+                signer: row
+                    .try_get::<Option<String>, _>("signer")?
+                    .map(|signer| Address::from_str(&signer))
+                    .transpose()?,
+                signature: row.try_get("signature")?,
+                created_at: row.try_get("created_at")?,
+            })
+        })
+        .collect()
+}
+
+/// Records an action of the operator in the audit log, within the transaction making it.
+pub(crate) async fn record_action(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    action: &str,
+    subject: Address,
+    reason: &str,
+    details: serde_json::Value,
+    signature: Option<&AdminSignature>,
+) -> Result<()> {
+    sqlx::query(
+        r#"
+            INSERT INTO operator_audit_log (action, subject, reason, details, signer, signature)
+            VALUES ($1, $2, $3, $4, $5, $6)
+        "#,
+    )
+    .bind(action)
+    .bind(subject.encode_hex::<String>())
+    .bind(reason)
+    .bind(details)
+    .bind(signature.map(|signature| signature.signer.encode_hex::<String>()))
+    .bind(signature.map(|signature| &signature.signature))
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+struct AdminState {
+    pgpool: PgPool,
+}
+
+pub(crate) type AdminError = (StatusCode, String);
+
+/// Middleware of all the admin routes, rejecting the requests without the admin auth token. The
+/// tokens are compared in constant time.
+pub async fn require_admin_token(
+    State(admin_auth_token): State<Arc<str>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AdminError> {
+    let token = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|s| s.strip_prefix("Bearer "));
+    let authorized =
+        token.is_some_and(|token| bool::from(token.as_bytes().ct_eq(admin_auth_token.as_bytes())));
+    if !authorized {
+        return Err((StatusCode::UNAUTHORIZED, "Unauthorized".to_string()));
+    }
+    Ok(next.run(request).await)
+}
+
+fn internal_error(e: anyhow::Error) -> AdminError {
+    error!("Error while handling an admin request: {}", e);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Error while handling an admin request: {}", e),
+    )
+}
+
+async fn handler_escrow_overrides(
+    State(state): State<Arc<AdminState>>,
+) -> Result<Json<Vec<EscrowOverride>>, AdminError> {
+    escrow_overrides(&state.pgpool)
+        .await
+        .map(Json)
+        .map_err(internal_error)
+}
+
+async fn handler_set_escrow_override(
+    State(state): State<Arc<AdminState>>,
+    Path(sender): Path<Address>,
+    signature: Option<Extension<AdminSignature>>,
+    Json(request): Json<SetEscrowOverride>,
+) -> Result<StatusCode, AdminError> {
+    request
+        .validate(Utc::now())
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    let signature = signature.map(|Extension(signature)| signature);
+    set_escrow_override(&state.pgpool, sender, &request, signature.as_ref())
+        .await
+        .map_err(internal_error)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn handler_remove_escrow_override(
+    State(state): State<Arc<AdminState>>,
+    Path(sender): Path<Address>,
+    Query(request): Query<RemoveEscrowOverride>,
+    signature: Option<Extension<AdminSignature>>,
+) -> Result<StatusCode, AdminError> {
+    if request.reason.trim().is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "A reason is required".to_string()));
+    }
+    let signature = signature.map(|Extension(signature)| signature);
+    match remove_escrow_override(&state.pgpool, sender, &request.reason, signature.as_ref()).await {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err((StatusCode::NOT_FOUND, "No override for this sender".into())),
+        Err(e) => Err(internal_error(e)),
+    }
+}
+
+async fn handler_audit_log(
+    State(state): State<Arc<AdminState>>,
+) -> Result<Json<Vec<AuditLogEntry>>, AdminError> {
+    audit_log(&state.pgpool)
+        .await
+        .map(Json)
+        .map_err(internal_error)
+}
+
+/// Admin routes, to be mounted on the tap-agent HTTP server behind [require_admin_token]. The
+/// ones making changes require a signature from one of `admin_signers` if set.
+pub fn router(pgpool: PgPool, admin_signers: Arc<AdminSigners>) -> Router {
+    Router::new()
+        .route(
+            "/admin/escrow-overrides/:sender",
+            put(handler_set_escrow_override).delete(handler_remove_escrow_override),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            admin_signers,
+            require_signature,
+        ))
+        .route("/admin/escrow-overrides", get(handler_escrow_overrides))
+        .route("/admin/audit-log", get(handler_audit_log))
+        .with_state(Arc::new(AdminState { pgpool }))
+}
+
+#[cfg(test)]
+mod tests {
+    use ethereum_types::U256;
+    use indexer_common::escrow_accounts::{active_escrow_account_overrides, EscrowAccountOverride};
+
+    use super::*;
+    use crate::tap::test_utils::{SENDER, SIGNER};
+
+    fn request(reason: &str) -> SetEscrowOverride {
+        SetEscrowOverride {
+            balance: Some("1000".to_string()),
+            signers: Some(vec![SIGNER.1]),
+            expires_at: Utc::now() + Duration::hours(1),
+            reason: reason.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        let now = Utc::now();
+        assert!(request("outage").validate(now).is_ok());
+        assert!(request(" ").validate(now).is_err());
+        assert!(SetEscrowOverride {
+            expires_at: now + Duration::days(8),
+            ..request("outage")
+        }
+        .validate(now)
+        .is_err());
+        assert!(SetEscrowOverride {
+            balance: Some("-1".to_string()),
+            ..request("outage")
+        }
+        .validate(now)
+        .is_err());
+        assert!(SetEscrowOverride {
+            balance: None,
+            signers: None,
+            ..request("outage")
+        }
+        .validate(now)
+        .is_err());
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_set_and_remove_escrow_override(pgpool: PgPool) {
+        let signature = AdminSignature {
+            signer: SIGNER.1,
+            signature: "0x1234".to_string(),
+            nonce: "00".to_string(),
+        };
+        set_escrow_override(
+            &pgpool,
+            SENDER.1,
+            &request("escrow subgraph outage"),
+            Some(&signature),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            active_escrow_account_overrides(&pgpool).await.unwrap(),
+            vec![EscrowAccountOverride {
+                sender: SENDER.1,
+                balance: Some(U256::from(1000)),
+                signers: Some(vec![SIGNER.1]),
+            }]
+        );
+
+        assert!(
+            remove_escrow_override(&pgpool, SENDER.1, "subgraph is back", None)
+                .await
+                .unwrap()
+        );
+        assert!(escrow_overrides(&pgpool).await.unwrap().is_empty());
+        assert!(!remove_escrow_override(&pgpool, SENDER.1, "again", None)
+            .await
+            .unwrap());
+
+        let log = audit_log(&pgpool).await.unwrap();
+        assert_eq!(
+            log.iter()
+                .map(|entry| (entry.action.as_str(), entry.reason.as_str()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("remove_escrow_override", "subgraph is back"),
+                ("set_escrow_override", "escrow subgraph outage"),
+            ]
+        );
+        assert_eq!(log[0].subject, SENDER.1.encode_hex::<String>());
+        assert_eq!(
+            (log[0].signer, log[1].signer),
+            (None, Some(signature.signer))
+        );
+        assert_eq!(log[1].signature, Some(signature.signature));
+    }
+}
@@ -0,0 +1,356 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Overflows of the fee accounting of the allocations. Saturating the unaggregated fees would
+//! silently corrupt the totals, so an allocation whose fees overflow stops counting them, and
+//! its sender is paused, until the operator acknowledges the overflow through the admin API.
+//! The allocation then sums its fees again from the database. Overflows are recorded in the
+//! database, to survive restarts, and exported as an alerting metric.
+//!
+//! The other fee totals, e.g. of a sender or of its invalid receipts, are checked as well, their
+//! overflows counted by [count_total_overflow] and surfaced as errors.
+
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    middleware,
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use chrono::{DateTime, Utc};
+use indexer_common::types::{AllocationIdHex, GrtWei, SenderAddress};
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, register_int_gauge_vec, IntCounterVec, IntGaugeVec};
+use ractor::ActorRef;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{PgPool, Row};
+use thegraph::types::Address;
+use tracing::{error, warn};
+
+use crate::admin_signature::{require_signature, AdminSignature, AdminSigners};
+use crate::agent::sender_account::SenderAccountMessage;
+use crate::agent::sender_allocation::SenderAllocationMessage;
+use crate::escrow_overrides::{record_action, AdminError};
+
+lazy_static! {
+    static ref FEE_OVERFLOWS: IntGaugeVec = register_int_gauge_vec!(
+        format!("tap_fee_overflows"),
+        "1 while the fee accounting of an allocation overflowed, until acknowledged",
+        &["sender", "allocation"]
+    )
+    .unwrap();
+    static ref FEE_TOTAL_OVERFLOWS: IntCounterVec = register_int_counter_vec!(
+        format!("tap_fee_total_overflows"),
+        "Overflows of the fee totals of a sender, refused by the fee accounting",
+        &["sender", "total"]
+    )
+    .unwrap();
+}
+
+/// Counts the overflow of the `total` fees of `sender`, e.g. `invalid_receipts`.
+pub fn count_total_overflow(sender: Address, total: &str) {
+    FEE_TOTAL_OVERFLOWS
+        .with_label_values(&[&sender.to_string(), total])
+        .inc();
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FeeOverflow {
+    pub sender: Address,
+    pub allocation_id: Address,
+    /// Unaggregated fees of the allocation when it overflowed, in GRT wei.
+    pub unaggregated_fees: GrtWei,
+    /// Value that couldn't be added to them, in GRT wei.
+    pub value: GrtWei,
+    pub detected_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AcknowledgeFeeOverflow {
+    pub reason: String,
+}
+
+/// Records the overflow of the fees of an allocation, replacing an acknowledged one.
+pub async fn record(
+    pgpool: &PgPool,
+    sender: Address,
+    allocation_id: Address,
+    unaggregated_fees: u128,
+    value: u128,
+) -> Result<()> {
+    FEE_OVERFLOWS
+        .with_label_values(&[&sender.to_string(), &allocation_id.to_string()])
+        .set(1);
+    sqlx::query(
+        r#"
+            INSERT INTO scalar_tap_fee_overflows
+                (sender_address, allocation_id, unaggregated_fees, value)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (sender_address, allocation_id) DO UPDATE SET
+                unaggregated_fees = EXCLUDED.unaggregated_fees,
+                value = EXCLUDED.value,
+                detected_at = NOW(),
+                acknowledged_at = NULL
+        "#,
+    )
+    .bind(SenderAddress(sender))
+    .bind(AllocationIdHex(allocation_id))
+    .bind(GrtWei(unaggregated_fees))
+    .bind(GrtWei(value))
+    .execute(pgpool)
+    .await?;
+    Ok(())
+}
+
+/// Whether the fees of the allocation overflowed, and the operator didn't acknowledge it yet.
+pub async fn is_overflowed(
+    pgpool: &PgPool,
+    sender: Address,
+    allocation_id: Address,
+) -> Result<bool> {
+    let overflowed = sqlx::query_scalar(
+        r#"
+            SELECT EXISTS (
+                SELECT 1 FROM scalar_tap_fee_overflows
+                WHERE sender_address = $1 AND allocation_id = $2 AND acknowledged_at IS NULL
+            )
+        "#,
+    )
+    .bind(SenderAddress(sender))
+    .bind(AllocationIdHex(allocation_id))
+    .fetch_one(pgpool)
+    .await?;
+    if overflowed {
+        FEE_OVERFLOWS
+            .with_label_values(&[&sender.to_string(), &allocation_id.to_string()])
+            .set(1);
+    }
+    Ok(overflowed)
+}
+
+/// The overflows not acknowledged yet.
+pub async fn fee_overflows(pgpool: &PgPool) -> Result<Vec<FeeOverflow>> {
+    let rows = sqlx::query(
+        r#"
+            SELECT sender_address, allocation_id, unaggregated_fees, value, detected_at
+            FROM scalar_tap_fee_overflows
+            WHERE acknowledged_at IS NULL
+            ORDER BY detected_at
+        "#,
+    )
+    .fetch_all(pgpool)
+    .await?;
+    rows.iter()
+        .map(|row| {
+            Ok(FeeOverflow {
+                sender: row.try_get::<SenderAddress, _>("sender_address")?.0,
+                allocation_id: row.try_get::<AllocationIdHex, _>("allocation_id")?.0,
+                unaggregated_fees: row.try_get("unaggregated_fees")?,
+                value: row.try_get("value")?,
+                detected_at: row.try_get("detected_at")?,
+            })
+        })
+        .collect()
+}
+
+/// Returns whether there was an overflow to acknowledge.
+pub async fn acknowledge(
+    pgpool: &PgPool,
+    sender: Address,
+    allocation_id: Address,
+    reason: &str,
+    signature: Option<&AdminSignature>,
+) -> Result<bool> {
+    if reason.trim().is_empty() {
+        bail!("A reason is required");
+    }
+
+    let mut tx = pgpool.begin().await?;
+    let acknowledged = sqlx::query(
+        r#"
+            UPDATE scalar_tap_fee_overflows
+            SET acknowledged_at = NOW()
+            WHERE sender_address = $1 AND allocation_id = $2 AND acknowledged_at IS NULL
+        "#,
+    )
+    .bind(SenderAddress(sender))
+    .bind(AllocationIdHex(allocation_id))
+    .execute(&mut *tx)
+    .await?
+    .rows_affected()
+        > 0;
+    if acknowledged {
+        record_action(
+            &mut tx,
+            "acknowledge_fee_overflow",
+            allocation_id,
+            reason,
+            json!({ "sender": sender }),
+            signature,
+        )
+        .await?;
+    }
+    tx.commit().await?;
+
+    if acknowledged {
+        FEE_OVERFLOWS
+            .with_label_values(&[&sender.to_string(), &allocation_id.to_string()])
+            .set(0);
+        warn!(%sender, %allocation_id, reason, "Fee overflow acknowledged by the operator");
+    }
+    Ok(acknowledged)
+}
+
+struct AdminState {
+    pgpool: PgPool,
+}
+
+fn internal_error(e: anyhow::Error) -> AdminError {
+    error!("Error while handling an admin request: {}", e);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Error while handling an admin request: {}", e),
+    )
+}
+
+async fn handler_fee_overflows(
+    State(state): State<Arc<AdminState>>,
+) -> Result<Json<Vec<FeeOverflow>>, AdminError> {
+    fee_overflows(&state.pgpool)
+        .await
+        .map(Json)
+        .map_err(internal_error)
+}
+
+async fn handler_acknowledge(
+    State(state): State<Arc<AdminState>>,
+    Path((sender, allocation_id)): Path<(Address, Address)>,
+    signature: Option<Extension<AdminSignature>>,
+    Json(request): Json<AcknowledgeFeeOverflow>,
+) -> Result<StatusCode, AdminError> {
+    if request.reason.trim().is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "A reason is required".to_string()));
+    }
+    let signature = signature.map(|Extension(signature)| signature);
+    let acknowledged = acknowledge(
+        &state.pgpool,
+        sender,
+        allocation_id,
+        &request.reason,
+        signature.as_ref(),
+    )
+    .await
+    .map_err(internal_error)?;
+    if !acknowledged {
+        return Err((
+            StatusCode::NOT_FOUND,
+            "No fee overflow to acknowledge for this allocation".into(),
+        ));
+    }
+
+    // The running allocation sums its fees again, otherwise the sender account stops counting
+    // it as overflowed, the fees being summed when the allocation is spawned again
+    let actor_name = format!("{sender}:{allocation_id}");
+    let notified = match ActorRef::<SenderAllocationMessage>::where_is(actor_name) {
+        Some(sender_allocation) => sender_allocation
+            .cast(SenderAllocationMessage::FeeOverflowAcknowledged)
+            .is_ok(),
+        None => ActorRef::<SenderAccountMessage>::where_is(sender.to_string()).is_some_and(
+            |sender_account| {
+                sender_account
+                    .cast(SenderAccountMessage::UpdateFeeOverflow(
+                        allocation_id,
+                        false,
+                    ))
+                    .is_ok()
+            },
+        ),
+    };
+    if !notified {
+        warn!(%sender, %allocation_id, "No running actor to notify of the acknowledged fee overflow");
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Admin routes listing and acknowledging the fee overflows, to be mounted on the tap-agent
+/// HTTP server.
+pub fn router(pgpool: PgPool, admin_signers: Arc<AdminSigners>) -> Router {
+    Router::new()
+        .route(
+            "/admin/fee-overflows/:sender/:allocation/acknowledge",
+            post(handler_acknowledge),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            admin_signers,
+            require_signature,
+        ))
+        .route("/admin/fee-overflows", get(handler_fee_overflows))
+        .with_state(Arc::new(AdminState { pgpool }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tap::test_utils::{ALLOCATION_ID_0, ALLOCATION_ID_1, SENDER};
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_fee_overflows(pgpool: PgPool) {
+        assert!(!is_overflowed(&pgpool, SENDER.1, *ALLOCATION_ID_0)
+            .await
+            .unwrap());
+        assert!(
+            !acknowledge(&pgpool, SENDER.1, *ALLOCATION_ID_0, "nothing", None)
+                .await
+                .unwrap()
+        );
+
+        record(&pgpool, SENDER.1, *ALLOCATION_ID_0, u128::MAX - 1, 2)
+            .await
+            .unwrap();
+        assert!(is_overflowed(&pgpool, SENDER.1, *ALLOCATION_ID_0)
+            .await
+            .unwrap());
+        assert!(!is_overflowed(&pgpool, SENDER.1, *ALLOCATION_ID_1)
+            .await
+            .unwrap());
+        let overflows = fee_overflows(&pgpool).await.unwrap();
+        assert_eq!(overflows.len(), 1);
+        assert_eq!(overflows[0].allocation_id, *ALLOCATION_ID_0);
+        assert_eq!(overflows[0].unaggregated_fees, GrtWei(u128::MAX - 1));
+        assert_eq!(overflows[0].value, GrtWei(2));
+
+        // A reason is required
+        assert!(acknowledge(&pgpool, SENDER.1, *ALLOCATION_ID_0, " ", None)
+            .await
+            .is_err());
+        assert!(
+            acknowledge(&pgpool, SENDER.1, *ALLOCATION_ID_0, "checked", None)
+                .await
+                .unwrap()
+        );
+        assert!(!is_overflowed(&pgpool, SENDER.1, *ALLOCATION_ID_0)
+            .await
+            .unwrap());
+        assert!(fee_overflows(&pgpool).await.unwrap().is_empty());
+        let audited: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM operator_audit_log WHERE action = 'acknowledge_fee_overflow'",
+        )
+        .fetch_one(&pgpool)
+        .await
+        .unwrap();
+        assert_eq!(audited, 1);
+
+        // Overflowing again needs another acknowledgment
+        record(&pgpool, SENDER.1, *ALLOCATION_ID_0, u128::MAX, 1)
+            .await
+            .unwrap();
+        assert!(is_overflowed(&pgpool, SENDER.1, *ALLOCATION_ID_0)
+            .await
+            .unwrap());
+    }
+}
@@ -0,0 +1,602 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Review of the receipts that failed their checks when requesting a RAV, kept in
+//! `scalar_tap_receipts_invalid` along with the reason they failed. Once the cause is fixed,
+//! e.g. an escrow subgraph that was out of sync, the receipts can be validated again against
+//! the current state, and the ones passing promoted back to the receipts to aggregate. The
+//! promotions are recorded in the operator audit log.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+
+use alloy_sol_types::Eip712Domain;
+use anyhow::{anyhow, Result};
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    middleware,
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use eventuals::Eventual;
+use indexer_common::escrow_accounts::EscrowAccounts;
+use indexer_common::types::{AllocationIdHex, GrtWei, SenderAddress};
+use ractor::ActorRef;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{postgres::PgRow, types::BigDecimal, PgPool, Row};
+use tap_core::receipt::{Receipt, SignedReceipt};
+use thegraph::types::Address;
+use tracing::{error, info, warn};
+
+use crate::admin_signature::{require_signature, AdminSignature, AdminSigners};
+use crate::agent::sender_allocation::SenderAllocationMessage;
+use crate::escrow_overrides::{record_action, AdminError};
+use crate::fee_overflows;
+
+const DEFAULT_PAGE_SIZE: i64 = 100;
+const MAX_PAGE_SIZE: i64 = 1000;
+/// Receipts validated again in a single request.
+const MAX_REVALIDATED_RECEIPTS: usize = 1000;
+
+/// Invalid receipts that failed for the same reason.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FailureReason {
+    /// Empty for the receipts stored before the reasons were recorded.
+    pub reason: String,
+    pub receipts: i64,
+    /// In GRT wei.
+    pub value: String,
+    pub first_id: i64,
+    pub last_id: i64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InvalidReceiptsQuery {
+    pub reason: Option<String>,
+    /// Receipts after this ID, to page through them.
+    pub after_id: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InvalidReceipt {
+    pub id: i64,
+    pub signer: Address,
+    pub allocation_id: Address,
+    pub timestamp_ns: u64,
+    pub nonce: u64,
+    /// In GRT wei.
+    pub value: String,
+    pub reason: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RevalidateRequest {
+    pub ids: Vec<i64>,
+    /// Moves the receipts passing the checks back to the receipts to aggregate.
+    #[serde(default)]
+    pub promote: bool,
+    /// Required to promote receipts, recorded in the audit log.
+    #[serde(default)]
+    pub reason: String,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Revalidation {
+    pub id: i64,
+    pub sender: Option<Address>,
+    pub allocation_id: Address,
+    /// Why the receipt still fails, if it does.
+    pub error: Option<String>,
+    pub promoted: bool,
+}
+
+/// The failure reasons of the invalid receipts, from the most common one.
+pub async fn failure_reasons(pgpool: &PgPool) -> Result<Vec<FailureReason>> {
+    let rows = sqlx::query(
+        r#"
+            SELECT
+                error_log,
+                COUNT(*) AS receipts,
+                SUM(value) AS value,
+                MIN(id) AS first_id,
+                MAX(id) AS last_id
+            FROM scalar_tap_receipts_invalid
+            GROUP BY error_log
+            ORDER BY receipts DESC, error_log
+        "#,
+    )
+    .fetch_all(pgpool)
+    .await?;
+
+    rows.iter()
+        .map(|row| {
+            Ok(FailureReason {
+                reason: row.try_get("error_log")?,
+                receipts: row.try_get("receipts")?,
+                value: row.try_get::<BigDecimal, _>("value")?.to_string(),
+                first_id: row.try_get("first_id")?,
+                last_id: row.try_get("last_id")?,
+            })
+        })
+        .collect()
+}
+
+fn decode_u64(row: &PgRow, column: &str) -> Result<u64> {
+    row.try_get::<BigDecimal, _>(column)?
+        .to_string()
+        .parse()
+        .map_err(|e| anyhow!("Error decoding `{column}` of an invalid receipt: {e}"))
+}
+
+fn decode_receipt(row: &PgRow) -> Result<(InvalidReceipt, SignedReceipt)> {
+    let receipt = InvalidReceipt {
+        id: row.try_get("id")?,
+        signer: row.try_get::<SenderAddress, _>("signer_address")?.0,
+        allocation_id: row.try_get::<AllocationIdHex, _>("allocation_id")?.0,
+        timestamp_ns: decode_u64(row, "timestamp_ns")?,
+        nonce: decode_u64(row, "nonce")?,
+        value: row.try_get::<GrtWei, _>("value")?.0.to_string(),
+        reason: row.try_get("error_log")?,
+    };
+    let signature = row.try_get::<Vec<u8>, _>("signature")?;
+    let signed_receipt = SignedReceipt {
+        message: Receipt {
+            allocation_id: receipt.allocation_id,
+            timestamp_ns: receipt.timestamp_ns,
+            nonce: receipt.nonce,
+            value: row.try_get::<GrtWei, _>("value")?.0,
+        },
+        signature: signature
+            .as_slice()
+            .try_into()
+            .map_err(|e| anyhow!("Error decoding the signature of an invalid receipt: {e}"))?,
+    };
+    Ok((receipt, signed_receipt))
+}
+
+/// Invalid receipts by increasing ID, of a single failure reason if set.
+pub async fn invalid_receipts(
+    pgpool: &PgPool,
+    query: &InvalidReceiptsQuery,
+) -> Result<Vec<InvalidReceipt>> {
+    let rows = sqlx::query(
+        r#"
+            SELECT id, signer_address, signature, allocation_id, timestamp_ns, nonce, value,
+                error_log
+            FROM scalar_tap_receipts_invalid
+            WHERE ($1::TEXT IS NULL OR error_log = $1)
+                AND id > COALESCE($2, 0)
+            ORDER BY id
+            LIMIT $3
+        "#,
+    )
+    .bind(&query.reason)
+    .bind(query.after_id)
+    .bind(
+        query
+            .limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE),
+    )
+    .fetch_all(pgpool)
+    .await?;
+
+    rows.iter()
+        .map(|row| decode_receipt(row).map(|(receipt, _)| receipt))
+        .collect()
+}
+
+/// Why `receipt` would still fail if it was aggregated now, if it would. Its signer must belong
+/// to a sender with a positive escrow balance, and it must not be covered by the last RAV of its
+/// allocation, nor be already stored with the valid receipts.
+pub(crate) async fn check_receipt(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    escrow_accounts: &EscrowAccounts,
+    domain_separator: &Eip712Domain,
+    receipt: &SignedReceipt,
+) -> Result<Address, String> {
+    let signer = receipt
+        .recover_signer(domain_separator)
+        .map_err(|e| format!("Invalid signature: {e}"))?;
+    let sender = escrow_accounts
+        .get_sender_for_signer(&signer)
+        .map_err(|e| e.to_string())?;
+    let balance = escrow_accounts
+        .get_balance_for_sender(&sender)
+        .map_err(|e| e.to_string())?;
+    if balance.is_zero() {
+        return Err(format!(
+            "Balance for sender {sender}, signer {signer} is not positive"
+        ));
+    }
+
+    let database_error = |e: sqlx::Error| format!("Database error: {e}");
+    let rav = sqlx::query(
+        r#"
+            SELECT timestamp_ns, last, final
+            FROM scalar_tap_ravs
+            WHERE allocation_id = $1 AND sender_address = $2
+        "#,
+    )
+    .bind(AllocationIdHex(receipt.message.allocation_id))
+    .bind(SenderAddress(sender))
+    .fetch_optional(&mut **tx)
+    .await
+    .map_err(database_error)?;
+    if let Some(rav) = rav {
+        if rav.try_get::<bool, _>("last").map_err(database_error)?
+            || rav.try_get::<bool, _>("final").map_err(database_error)?
+        {
+            return Err("The last RAV of the allocation was already requested".to_string());
+        }
+        let rav_timestamp_ns = decode_u64(&rav, "timestamp_ns").map_err(|e| e.to_string())?;
+        if receipt.message.timestamp_ns <= rav_timestamp_ns {
+            return Err(format!(
+                "Covered by the RAV of the allocation, at timestamp {rav_timestamp_ns}"
+            ));
+        }
+    }
+
+    let duplicate: bool = sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM scalar_tap_receipts WHERE signature = $1)",
+    )
+    .bind(receipt.signature.to_vec())
+    .fetch_one(&mut **tx)
+    .await
+    .map_err(database_error)?;
+    if duplicate {
+        return Err("Already stored with the valid receipts".to_string());
+    }
+    Ok(sender)
+}
+
+/// Validates the invalid receipts `ids` again against the current escrow accounts and RAVs, and
+/// if `promote` is set moves the ones passing back to the receipts to aggregate.
+pub async fn revalidate(
+    pgpool: &PgPool,
+    escrow_accounts: &EscrowAccounts,
+    domain_separator: &Eip712Domain,
+    request: &RevalidateRequest,
+    signature: Option<&AdminSignature>,
+) -> Result<Vec<Revalidation>> {
+    if request.promote && request.reason.trim().is_empty() {
+        return Err(anyhow!("A reason is required to promote receipts"));
+    }
+    if request.ids.len() > MAX_REVALIDATED_RECEIPTS {
+        return Err(anyhow!(
+            "At most {MAX_REVALIDATED_RECEIPTS} receipts can be validated at once"
+        ));
+    }
+
+    let mut tx = pgpool.begin().await?;
+    let rows = sqlx::query(
+        r#"
+            SELECT id, signer_address, signature, allocation_id, timestamp_ns, nonce, value,
+                error_log
+            FROM scalar_tap_receipts_invalid
+            WHERE id = ANY($1)
+            ORDER BY id
+            FOR UPDATE
+        "#,
+    )
+    .bind(&request.ids)
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let mut revalidations = Vec::with_capacity(rows.len());
+    // Promoted receipt IDs and value, by sender
+    let mut promoted: BTreeMap<Address, (Vec<i64>, u128)> = BTreeMap::new();
+    for row in &rows {
+        let (invalid_receipt, receipt) = decode_receipt(row)?;
+        let result = check_receipt(&mut tx, escrow_accounts, domain_separator, &receipt).await;
+        let promote = request.promote && result.is_ok();
+        if let (true, Ok(sender)) = (promote, &result) {
+            sqlx::query(
+                r#"
+                    WITH promoted AS (
+                        DELETE FROM scalar_tap_receipts_invalid
+                        WHERE id = $1
+                        RETURNING signer_address, signature, allocation_id, timestamp_ns,
+                            nonce, value
+                    )
+                    INSERT INTO scalar_tap_receipts (
+                        signer_address, signature, allocation_id, timestamp_ns, nonce, value
+                    )
+                    SELECT * FROM promoted
+                "#,
+            )
+            .bind(invalid_receipt.id)
+            .execute(&mut *tx)
+            .await?;
+            let (ids, value) = promoted.entry(*sender).or_default();
+            ids.push(invalid_receipt.id);
+            *value = value.checked_add(receipt.message.value).ok_or_else(|| {
+                fee_overflows::count_total_overflow(*sender, "promoted_receipts");
+                anyhow!("Overflow of the value of the receipts promoted for sender {sender}")
+            })?;
+        }
+        revalidations.push(Revalidation {
+            id: invalid_receipt.id,
+            sender: result.as_ref().ok().copied(),
+            allocation_id: invalid_receipt.allocation_id,
+            error: result.err(),
+            promoted: promote,
+        });
+    }
+    for (sender, (ids, value)) in &promoted {
+        record_action(
+            &mut tx,
+            "promote_invalid_receipts",
+            *sender,
+            &request.reason,
+            json!({ "ids": ids, "value": value.to_string() }),
+            signature,
+        )
+        .await?;
+    }
+    tx.commit().await?;
+
+    for (sender, (ids, value)) in &promoted {
+        info!(
+            %sender,
+            receipts = ids.len(),
+            value,
+            reason = %request.reason,
+            "Invalid receipts promoted by the operator"
+        );
+    }
+    Ok(revalidations)
+}
+
+struct InvalidReceiptsState {
+    pgpool: PgPool,
+    escrow_accounts: Eventual<EscrowAccounts>,
+    domain_separator: Eip712Domain,
+}
+
+fn internal_error(e: anyhow::Error) -> AdminError {
+    error!("Error while reviewing the invalid receipts: {}", e);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Error while reviewing the invalid receipts: {}", e),
+    )
+}
+
+async fn handler_failure_reasons(
+    State(state): State<Arc<InvalidReceiptsState>>,
+) -> Result<Json<Vec<FailureReason>>, AdminError> {
+    failure_reasons(&state.pgpool)
+        .await
+        .map(Json)
+        .map_err(internal_error)
+}
+
+async fn handler_invalid_receipts(
+    State(state): State<Arc<InvalidReceiptsState>>,
+    Query(query): Query<InvalidReceiptsQuery>,
+) -> Result<Json<Vec<InvalidReceipt>>, AdminError> {
+    invalid_receipts(&state.pgpool, &query)
+        .await
+        .map(Json)
+        .map_err(internal_error)
+}
+
+async fn handler_revalidate(
+    State(state): State<Arc<InvalidReceiptsState>>,
+    signature: Option<Extension<AdminSignature>>,
+    Json(request): Json<RevalidateRequest>,
+) -> Result<Json<Vec<Revalidation>>, AdminError> {
+    if request.promote && request.reason.trim().is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "A reason is required to promote receipts".to_string(),
+        ));
+    }
+    let escrow_accounts = state.escrow_accounts.value_immediate().ok_or_else(|| {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "The escrow accounts aren't synced yet".to_string(),
+        )
+    })?;
+    let signature = signature.map(|Extension(signature)| signature);
+    let revalidations = revalidate(
+        &state.pgpool,
+        &escrow_accounts,
+        &state.domain_separator,
+        &request,
+        signature.as_ref(),
+    )
+    .await
+    .map_err(internal_error)?;
+
+    // The running allocations, if any, track the fees of the receipts no longer invalid
+    let allocations: BTreeSet<_> = revalidations
+        .iter()
+        .filter(|revalidation| revalidation.promoted)
+        .filter_map(|revalidation| Some((revalidation.sender?, revalidation.allocation_id)))
+        .collect();
+    for (sender, allocation_id) in allocations {
+        let actor_name = format!("{sender}:{allocation_id}");
+        if let Some(sender_allocation) = ActorRef::<SenderAllocationMessage>::where_is(actor_name) {
+            if let Err(e) = sender_allocation.cast(SenderAllocationMessage::InvalidReceiptsPromoted)
+            {
+                warn!(
+                    "Error while notifying the allocation of the promoted receipts: {:?}",
+                    e
+                );
+            }
+        }
+    }
+    Ok(Json(revalidations))
+}
+
+/// Admin routes reviewing the invalid receipts, to be mounted on the tap-agent HTTP server.
+pub fn router(
+    pgpool: PgPool,
+    admin_signers: Arc<AdminSigners>,
+    escrow_accounts: Eventual<EscrowAccounts>,
+    domain_separator: Eip712Domain,
+) -> Router {
+    Router::new()
+        .route(
+            "/admin/invalid-receipts/revalidate",
+            post(handler_revalidate),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            admin_signers,
+            require_signature,
+        ))
+        .route("/admin/invalid-receipts", get(handler_invalid_receipts))
+        .route(
+            "/admin/invalid-receipts/reasons",
+            get(handler_failure_reasons),
+        )
+        .with_state(Arc::new(InvalidReceiptsState {
+            pgpool,
+            escrow_accounts,
+            domain_separator,
+        }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use ethereum_types::U256;
+
+    use super::*;
+    use crate::tap::test_utils::{
+        create_rav, create_received_receipt, store_invalid_receipt, store_rav, ALLOCATION_ID_0,
+        SENDER, SIGNER, TAP_EIP712_DOMAIN_SEPARATOR,
+    };
+
+    async fn store_failed_receipt(pgpool: &PgPool, receipt: &SignedReceipt, reason: &str) {
+        let id = store_invalid_receipt(pgpool, receipt).await.unwrap();
+        sqlx::query("UPDATE scalar_tap_receipts_invalid SET error_log = $1 WHERE id = $2")
+            .bind(reason)
+            .bind(id as i64)
+            .execute(pgpool)
+            .await
+            .unwrap();
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_review_and_promote(pgpool: PgPool) {
+        let escrow_accounts = EscrowAccounts::new(
+            HashMap::from([(SENDER.1, U256::from(1000))]),
+            HashMap::from([(SENDER.1, vec![SIGNER.1])]),
+        );
+        // Covered by the RAV of the allocation, at timestamp 10
+        store_rav(
+            &pgpool,
+            create_rav(*ALLOCATION_ID_0, SIGNER.0.clone(), 10, 5),
+            SENDER.1,
+        )
+        .await
+        .unwrap();
+        for (nonce, timestamp_ns, reason) in [
+            (1, 5, "No sender found for signer"),
+            (2, 20, "No sender found for signer"),
+            (3, 30, "Balance for sender is not positive"),
+        ] {
+            let receipt =
+                create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, nonce, timestamp_ns, 10);
+            store_failed_receipt(&pgpool, receipt.signed_receipt(), reason).await;
+        }
+
+        let reasons = failure_reasons(&pgpool).await.unwrap();
+        assert_eq!(
+            reasons
+                .iter()
+                .map(|reason| (
+                    reason.reason.as_str(),
+                    reason.receipts,
+                    reason.value.as_str()
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                ("No sender found for signer", 2, "20"),
+                ("Balance for sender is not positive", 1, "10"),
+            ]
+        );
+        let receipts = invalid_receipts(
+            &pgpool,
+            &InvalidReceiptsQuery {
+                reason: Some("No sender found for signer".to_string()),
+                after_id: None,
+                limit: None,
+            },
+        )
+        .await
+        .unwrap();
+        let ids: Vec<_> = receipts.iter().map(|receipt| receipt.id).collect();
+        assert_eq!(ids.len(), 2);
+
+        let request = |promote| RevalidateRequest {
+            ids: ids.clone(),
+            promote,
+            reason: "escrow subgraph resynced".to_string(),
+        };
+        let revalidations = revalidate(
+            &pgpool,
+            &escrow_accounts,
+            &TAP_EIP712_DOMAIN_SEPARATOR,
+            &request(false),
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(revalidations[0].error.is_some());
+        assert_eq!(revalidations[1].error, None);
+        assert!(revalidations
+            .iter()
+            .all(|revalidation| !revalidation.promoted));
+
+        let revalidations = revalidate(
+            &pgpool,
+            &escrow_accounts,
+            &TAP_EIP712_DOMAIN_SEPARATOR,
+            &request(true),
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            revalidations
+                .iter()
+                .map(|revalidation| revalidation.promoted)
+                .collect::<Vec<_>>(),
+            vec![false, true]
+        );
+        let promoted: Vec<BigDecimal> = sqlx::query_scalar("SELECT nonce FROM scalar_tap_receipts")
+            .fetch_all(&pgpool)
+            .await
+            .unwrap();
+        assert_eq!(promoted, vec![BigDecimal::from(2)]);
+        assert_eq!(failure_reasons(&pgpool).await.unwrap()[0].receipts, 1);
+
+        // Without a sender for the signer anymore
+        let revalidations = revalidate(
+            &pgpool,
+            &EscrowAccounts::default(),
+            &TAP_EIP712_DOMAIN_SEPARATOR,
+            &RevalidateRequest {
+                ids: vec![ids[0]],
+                ..request(true)
+            },
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(revalidations[0].sender, None);
+        assert!(!revalidations[0].promoted);
+    }
+}
@@ -2,12 +2,17 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use alloy_sol_types::{eip712_domain, Eip712Domain};
+use indexer_config::EXIT_CODE_CONFIG_ERROR;
 use lazy_static::lazy_static;
 
 use crate::config::Config;
 
 lazy_static! {
-    pub static ref CONFIG: Config = Config::from_cli().expect("Failed to load configuration");
+    pub static ref CONFIG: Config = Config::from_cli().unwrap_or_else(|e| {
+        // Logging is set up by the configuration, it can't be used yet
+        eprintln!("Failed to load configuration: {e}");
+        std::process::exit(EXIT_CODE_CONFIG_ERROR.into())
+    });
     pub static ref EIP_712_DOMAIN: Eip712Domain = eip712_domain! {
         name: "TAP",
         version: "1",
@@ -16,8 +21,36 @@ lazy_static! {
     };
 }
 
+pub mod accounting;
+pub mod actor_topology;
+pub mod admin_client;
+pub mod admin_signature;
 pub mod agent;
+pub mod aggregator_client;
+pub mod agreements;
+pub mod allocation_closures;
+pub mod allocation_registry;
+pub mod allocation_status;
+pub mod close_timing;
 pub mod config;
 pub mod database;
+pub mod doctor;
+pub mod escrow_overrides;
+pub mod fee_overflows;
+pub mod invalid_receipts;
 pub mod metrics;
+pub mod poi_gate;
+pub mod rav_history;
+pub mod rav_import;
+pub mod rav_verification;
+pub mod receipt_lifecycle;
+pub mod receivables;
+pub mod redemption_advice;
+pub mod scheduler;
+pub mod sender_statements;
+pub mod sharding;
+pub mod table_health;
 pub mod tap;
+pub mod trace_bundles;
+pub mod vanished_allocations;
+pub mod virtual_allocations;
@@ -1,45 +1,298 @@
 // Copyright 2023-, GraphOps and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
-use anyhow::Result;
-use ractor::ActorStatus;
-use tokio::signal::unix::{signal, SignalKind};
+use std::net::{Ipv4Addr, SocketAddr};
+
+use anyhow::{bail, Context, Result};
 use tracing::{debug, error, info};
 
-use indexer_tap_agent::{agent, metrics, CONFIG};
+use clap::Parser;
+use indexer_common::doctor::{check_escrow_network, startup_check};
+use indexer_common::tenant;
+use indexer_tap_agent::{
+    accounting,
+    admin_client::{admin_request, load_config, with_query},
+    agent::{self, shutdown, shutdown::ShutdownSignals},
+    config::{metrics_url, Cli, Command},
+    doctor, invalid_receipts, metrics, rav_import, sharding, virtual_allocations, CONFIG,
+};
+use reqwest::Method;
+use sqlx::postgres::PgPoolOptions;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Parse basic configurations, also initializes logging.
+    let cli = Cli::parse();
+    if cli.config_schema {
+        println!("{:#}", indexer_config::config_schema());
+        return Ok(());
+    }
+    if let Some(command) = &cli.command {
+        match command {
+            Command::Doctor => {
+                let report = doctor::run(load_config(&cli)).await;
+                println!("{report}");
+                std::process::exit(report.exit_code());
+            }
+            Command::ExportStatement {
+                from,
+                to,
+                format,
+                output,
+            } => {
+                let config = load_config(&cli);
+                let mut pool_options = PgPoolOptions::new();
+                if config.database.tenant_isolation {
+                    pool_options = tenant::isolate(pool_options, config.indexer.indexer_address);
+                }
+                let pgpool = pool_options
+                    .connect(config.database.postgres_url.as_str())
+                    .await?;
+                let statement = accounting::export_statement(&pgpool, *from, *to).await?;
+                let rendered = format.render(&statement)?;
+                match output {
+                    Some(path) => std::fs::write(path, rendered)?,
+                    None => print!("{rendered}"),
+                }
+            }
+            Command::ClaimUnownedRows => {
+                let config = load_config(&cli);
+                // Not isolated, the rows without an owner are hidden from the sessions of an
+                // indexer
+                let pgpool = PgPoolOptions::new()
+                    .connect(config.database.postgres_url.as_str())
+                    .await?;
+                let claimed =
+                    tenant::claim_unowned_rows(&pgpool, config.indexer.indexer_address).await?;
+                println!(
+                    "Assigned {claimed} rows to {}",
+                    config.indexer.indexer_address
+                );
+            }
+            Command::ImportRav {
+                file,
+                sender,
+                reason,
+            } => {
+                // Imported by the running agent, so that its allocation tracks the removed
+                // receipts
+                let request = rav_import::ImportRavRequest {
+                    sender: *sender,
+                    rav: serde_json::from_slice(&std::fs::read(file)?)?,
+                    reason: reason.clone(),
+                };
+                let body = admin_request(
+                    &cli,
+                    Method::POST,
+                    "/admin/ravs/import",
+                    Some(serde_json::to_string(&request)?),
+                )
+                .await
+                .context("Failed to import the RAV")?;
+                println!("{body}");
+            }
+            Command::RegisterVirtualAllocation {
+                allocation_id,
+                service,
+                reason,
+            } => {
+                let request = virtual_allocations::RegisterVirtualAllocation {
+                    service: service.clone(),
+                    signature: indexer_common::allocations::virtual_allocations::sign(
+                        &load_config(&cli).indexer.operator_mnemonic.to_string(),
+                        *allocation_id,
+                        service,
+                    )?,
+                    reason: reason.clone(),
+                };
+                admin_request(
+                    &cli,
+                    Method::PUT,
+                    &format!("/admin/virtual-allocations/{allocation_id}"),
+                    Some(serde_json::to_string(&request)?),
+                )
+                .await
+                .context("Failed to register the virtual allocation")?;
+                println!(
+                    "Registered virtual allocation {allocation_id} for the `{service}` service"
+                );
+            }
+            Command::InvalidReceipts { reason, after_id } => {
+                let mut query = Vec::new();
+                let path = match reason {
+                    Some(reason) => {
+                        query.push(("reason", reason.clone()));
+                        "/admin/invalid-receipts"
+                    }
+                    None => "/admin/invalid-receipts/reasons",
+                };
+                if let Some(after_id) = after_id {
+                    query.push(("afterId", after_id.to_string()));
+                }
+                let body = admin_request(&cli, Method::GET, &with_query(path, &query), None)
+                    .await
+                    .context("Failed to list the invalid receipts")?;
+                println!("{body}");
+            }
+            Command::RevalidateReceipts {
+                ids,
+                promote,
+                reason,
+            } => {
+                // Promoted by the running agent, so that its allocations track the promoted
+                // receipts
+                let request = invalid_receipts::RevalidateRequest {
+                    ids: ids.clone(),
+                    promote: *promote,
+                    reason: reason.clone().unwrap_or_default(),
+                };
+                let body = admin_request(
+                    &cli,
+                    Method::POST,
+                    "/admin/invalid-receipts/revalidate",
+                    Some(serde_json::to_string(&request)?),
+                )
+                .await
+                .context("Failed to revalidate the invalid receipts")?;
+                println!("{body}");
+            }
+            Command::ExplainReceipt {
+                id,
+                invalid_id,
+                signature,
+            } => {
+                let mut query = Vec::new();
+                if let Some(id) = id {
+                    query.push(("id", id.to_string()));
+                }
+                if let Some(invalid_id) = invalid_id {
+                    query.push(("invalidId", invalid_id.to_string()));
+                }
+                if let Some(signature) = signature {
+                    query.push(("signature", signature.clone()));
+                }
+                let path = with_query("/admin/receipts/explain", &query);
+                let body = admin_request(&cli, Method::GET, &path, None)
+                    .await
+                    .context("Failed to explain the receipt")?;
+                println!("{body}");
+            }
+            Command::TableHealth => {
+                let body = admin_request(&cli, Method::GET, "/admin/table-health", None)
+                    .await
+                    .context("Failed to report the table health")?;
+                println!("{body}");
+            }
+            Command::FeeOverflows => {
+                let body = admin_request(&cli, Method::GET, "/admin/fee-overflows", None)
+                    .await
+                    .context("Failed to list the fee overflows")?;
+                println!("{body}");
+            }
+            Command::AcknowledgeFeeOverflow {
+                sender,
+                allocation_id,
+                reason,
+            } => {
+                admin_request(
+                    &cli,
+                    Method::POST,
+                    &format!("/admin/fee-overflows/{sender}/{allocation_id}/acknowledge"),
+                    Some(serde_json::to_string(
+                        &serde_json::json!({ "reason": reason }),
+                    )?),
+                )
+                .await
+                .context("Failed to acknowledge the fee overflow")?;
+                println!("Acknowledged the fee overflow of allocation {allocation_id}");
+            }
+            Command::RedemptionAdvice { history_since } => {
+                // Served with the state of the agent, not behind the admin token
+                let config = load_config(&cli);
+                if config.tap.redemption_advice.is_none() {
+                    bail!("The redemption advice is disabled, `tap.redemption_advice` isn't set");
+                }
+                let mut url =
+                    reqwest::Url::parse(&metrics_url(&config.metrics, "/state/redemption-advice"))?;
+                if let Some(since) = history_since {
+                    url.set_path("/state/redemption-advice/history");
+                    url.query_pairs_mut()
+                        .append_pair("since", &since.to_rfc3339());
+                }
+                let response = reqwest::Client::new().get(url).send().await?;
+                let status = response.status();
+                let body = response.text().await?;
+                if !status.is_success() {
+                    bail!("Failed to get the redemption advice ({status}): {body}");
+                }
+                println!("{body}");
+            }
+        }
+        return Ok(());
+    }
+
+    // Running as PID 1 in a container, the kernel ignores the signals that have no handler, so
+    // they are handled before starting up, which can be stuck waiting on e.g. the database.
+    let mut signals = ShutdownSignals::install()?;
+
+    // Parse basic configurations, also initializes logging. Exits on invalid configurations.
     lazy_static::initialize(&CONFIG);
     debug!("Config: {:?}", *CONFIG);
 
-    let (manager, handler) = agent::start_agent().await;
+    // Receipts checked against the escrow accounts of another network are all rejected
+    startup_check(
+        check_escrow_network(
+            &reqwest::Client::new(),
+            Some(&CONFIG.indexer_infrastructure.graph_node_status_endpoint),
+            &CONFIG.escrow_subgraph.escrow_subgraph_endpoint,
+            CONFIG.escrow_subgraph.escrow_subgraph_auth_token.as_deref(),
+            CONFIG.escrow_subgraph.escrow_subgraph_deployment,
+            CONFIG.receipts.receipts_verifier_chain_id,
+        )
+        .await,
+        CONFIG.receipts.allow_escrow_network_mismatch,
+    )?;
+
+    let (manager, handler, state_routes) = tokio::select! {
+        agent = agent::start_agent() => agent,
+        signal = signals.recv() => {
+            info!("Received {} while starting up, exiting.", signal);
+            return Ok(());
+        }
+    };
     info!("TAP Agent started.");
 
     tokio::spawn(metrics::run_server(
-        CONFIG.indexer_infrastructure.metrics_port,
+        SocketAddr::new(
+            CONFIG
+                .indexer_infrastructure
+                .metrics_address
+                .unwrap_or(Ipv4Addr::UNSPECIFIED.into()),
+            CONFIG.indexer_infrastructure.metrics_port,
+        ),
+        state_routes,
     ));
     info!("Metrics port opened");
 
     // Have tokio wait for SIGTERM or SIGINT.
-    let mut signal_sigint = signal(SignalKind::interrupt())?;
-    let mut signal_sigterm = signal(SignalKind::terminate())?;
     tokio::select! {
         _ = handler => error!("SenderAccountsManager stopped"),
-        _ = signal_sigint.recv() => debug!("Received SIGINT."),
-        _ = signal_sigterm.recv() => debug!("Received SIGTERM."),
+        signal = signals.recv() => debug!("Received {}.", signal),
     }
     // If we're here, we've received a signal to exit.
     info!("Shutting down...");
+    #[cfg(feature = "systemd")]
+    indexer_common::systemd::notify_stopping();
 
-    // We don't want our actor to run any shutdown logic, so we kill it.
-    if manager.get_status() == ActorStatus::Running {
-        manager
-            .kill_and_wait(None)
-            .await
-            .expect("Failed to kill manager.");
-    }
+    // We don't want our actor to run any shutdown logic, so we kill it, once the last RAVs are
+    // requested and the checkpoints saved.
+    shutdown::stop(
+        manager,
+        CONFIG.tap.shutdown_rav_flush.as_ref(),
+        &mut signals,
+    )
+    .await;
+    // Once the checkpoints are saved, so that the next holder of the shard resumes from them
+    sharding::release().await;
 
     // Stop the server and wait for it to finish gracefully.
     debug!("Goodbye!");
@@ -1,14 +1,23 @@
 // Copyright 2023-, Semiotic AI, Inc.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::{net::SocketAddr, panic};
+use std::{collections::BTreeMap, net::SocketAddr, panic};
 
-use axum::{http::StatusCode, response::IntoResponse, routing::get, Router};
+use axum::{http::StatusCode, response::IntoResponse, routing::get, Json, Router};
 use futures_util::FutureExt;
+use indexer_common::db_metrics::{self, SlowQuery};
+use indexer_common::schema_drift::{self, Drift};
 use log::{debug, info};
 use prometheus::TextEncoder;
+use thegraph::types::Address;
 use tracing::error;
 
+use crate::agent::aggregator_health::{AggregatorHealth, AGGREGATOR_HEALTH};
+use crate::agent::rav_schedule::{NextRavEstimate, NEXT_RAV_ESTIMATES};
+use crate::agent::trigger_tuning::{TriggerValueStatus, TRIGGER_VALUE_STATUSES};
+use crate::scheduler::{JobStatus, JOB_STATUSES};
+use crate::vanished_allocations::{self, VanishedAllocationsStatus};
+
 async fn handler_metrics() -> (StatusCode, String) {
     let metric_families = prometheus::gather();
     let encoder = TextEncoder::new();
@@ -25,15 +34,53 @@ async fn handler_metrics() -> (StatusCode, String) {
     }
 }
 
+async fn handler_scheduler_state() -> Json<BTreeMap<String, JobStatus>> {
+    Json(JOB_STATUSES.read().unwrap().clone())
+}
+
+async fn handler_trigger_values() -> Json<BTreeMap<Address, TriggerValueStatus>> {
+    Json(TRIGGER_VALUE_STATUSES.read().unwrap().clone())
+}
+
+async fn handler_next_ravs() -> Json<BTreeMap<Address, NextRavEstimate>> {
+    Json(NEXT_RAV_ESTIMATES.read().unwrap().clone())
+}
+
+async fn handler_aggregators() -> Json<BTreeMap<Address, Vec<AggregatorHealth>>> {
+    Json(AGGREGATOR_HEALTH.read().unwrap().clone())
+}
+
+async fn handler_vanished_allocations() -> Json<VanishedAllocationsStatus> {
+    Json(vanished_allocations::status())
+}
+
+async fn handler_schema_drift() -> Json<Vec<Drift>> {
+    Json(schema_drift::drifts())
+}
+
+async fn handler_slow_queries() -> Json<Vec<SlowQuery>> {
+    Json(db_metrics::slow_queries())
+}
+
 async fn handler_404() -> impl IntoResponse {
     (StatusCode::NOT_FOUND, "404 Not Found")
 }
 
-async fn _run_server(port: u16) {
+async fn _run_server(addr: SocketAddr, state_routes: Router) {
     let app = Router::new()
         .route("/metrics", get(handler_metrics))
+        .route("/state/scheduler", get(handler_scheduler_state))
+        .route("/state/trigger-values", get(handler_trigger_values))
+        .route("/state/next-ravs", get(handler_next_ravs))
+        .route("/state/aggregators", get(handler_aggregators))
+        .route(
+            "/state/vanished-allocations",
+            get(handler_vanished_allocations),
+        )
+        .route("/state/schema-drift", get(handler_schema_drift))
+        .route("/debug/slow-queries", get(handler_slow_queries))
+        .merge(state_routes)
         .fallback(handler_404);
-    let addr = SocketAddr::from(([0, 0, 0, 0], port));
     let listener = tokio::net::TcpListener::bind(addr)
         .await
         .expect("Failed to Bind metrics address`");
@@ -50,10 +97,10 @@ async fn _run_server(port: u16) {
     };
 }
 
-pub async fn run_server(port: u16) {
+pub async fn run_server(addr: SocketAddr, state_routes: Router) {
     // Code here is to abort program if there is a panic in _run_server
     // Otherwise, when spawning the task, the panic will be silently ignored
-    let res = panic::AssertUnwindSafe(_run_server(port))
+    let res = panic::AssertUnwindSafe(_run_server(addr, state_routes))
         .catch_unwind()
         .await;
     if res.is_err() {
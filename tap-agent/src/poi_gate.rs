@@ -0,0 +1,575 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Gate holding back the last RAVs of a closed allocation until the POI of its close is posted
+//! on-chain, as seen by the network subgraph, and confirmed by enough blocks. Once marked as
+//! last, the RAVs are redeemed by the indexer-agent, so marking them for a close that is then
+//! rolled back, e.g. by a reorg, would leave an open allocation without its RAVs. The gate
+//! gives up after a timeout, and the operator can release or hold an allocation through the
+//! admin API, with the reason recorded in the operator audit log.
+
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Result};
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    middleware,
+    routing::{get, put},
+    Extension, Json, Router,
+};
+use chrono::{DateTime, Utc};
+use indexer_common::prelude::{Query as GraphQuery, SubgraphClient};
+use indexer_common::types::AllocationIdHex;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{PgPool, Row};
+use thegraph::types::Address;
+use tracing::{error, info, warn};
+
+use crate::admin_signature::{require_signature, AdminSignature, AdminSigners};
+use crate::agent::clock::Clock;
+use crate::config::PoiGating;
+use crate::escrow_overrides::{record_action, AdminError};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PoiGateAction {
+    /// Marks the last RAVs without waiting for the POI.
+    Release,
+    /// Keeps waiting for the POI past the timeout.
+    Hold,
+}
+
+impl PoiGateAction {
+    fn as_str(&self) -> &'static str {
+        match self {
+            PoiGateAction::Release => "release",
+            PoiGateAction::Hold => "hold",
+        }
+    }
+
+    fn parse(action: &str) -> Result<Self> {
+        match action {
+            "release" => Ok(PoiGateAction::Release),
+            "hold" => Ok(PoiGateAction::Hold),
+            _ => bail!("Unknown POI gate action `{action}`"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetPoiGateOverride {
+    pub action: PoiGateAction,
+    pub reason: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RemovePoiGateOverride {
+    pub reason: String,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PoiGateOverride {
+    pub allocation_id: Address,
+    pub action: PoiGateAction,
+    pub reason: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Status of the close of an allocation in the network subgraph.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PoiStatus {
+    /// Closed with a POI, confirmed by enough blocks.
+    Posted { poi: String },
+    /// Still open, or closed by too few blocks ago.
+    Pending,
+    /// Not in the network subgraph, e.g. a virtual allocation, there is no POI to wait for.
+    NotOnChain,
+}
+
+/// How the wait for the POI ended.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum GateOutcome {
+    Posted { poi: String },
+    NotOnChain,
+    Released,
+    TimedOut,
+}
+
+#[derive(Deserialize)]
+struct PoiGateResponse {
+    #[serde(rename = "_meta")]
+    meta: Meta,
+    allocation: Option<AllocationClose>,
+}
+
+#[derive(Deserialize)]
+struct Meta {
+    block: Block,
+}
+
+#[derive(Deserialize)]
+struct Block {
+    number: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AllocationClose {
+    status: String,
+    poi: Option<String>,
+    closed_at_block_number: Option<u64>,
+}
+
+pub async fn poi_status(
+    network_subgraph: &SubgraphClient,
+    allocation_id: Address,
+    confirmations: u64,
+) -> Result<PoiStatus> {
+    let response = network_subgraph
+        .query::<PoiGateResponse>(GraphQuery::new_with_variables(
+            r#"
+                query poiGate($allocation: String!) {
+                    _meta {
+                        block {
+                            number
+                        }
+                    }
+                    allocation(id: $allocation) {
+                        status
+                        poi
+                        closedAtBlockNumber
+                    }
+                }
+            "#,
+            [("allocation", format!("{:x?}", allocation_id).into())],
+        ))
+        .await?
+        .map_err(|e| anyhow!(e))?;
+    let Some(allocation) = response.allocation else {
+        return Ok(PoiStatus::NotOnChain);
+    };
+    // Closed, then possibly finalized and claimed
+    if allocation.status == "Active" {
+        return Ok(PoiStatus::Pending);
+    }
+    match (allocation.poi, allocation.closed_at_block_number) {
+        (Some(poi), Some(closed_at)) if response.meta.block.number >= closed_at + confirmations => {
+            Ok(PoiStatus::Posted { poi })
+        }
+        _ => Ok(PoiStatus::Pending),
+    }
+}
+
+/// Waits for the POI of the close of `allocation_id`, for the override of the operator, or for
+/// the timeout, whichever comes first. Failing to check either is logged and retried.
+pub async fn wait_for_poi(
+    pgpool: &PgPool,
+    network_subgraph: &SubgraphClient,
+    allocation_id: Address,
+    config: &PoiGating,
+    clock: &dyn Clock,
+) -> GateOutcome {
+    let started = clock.now();
+    loop {
+        let held = match poi_gate_override(pgpool, allocation_id).await {
+            Ok(Some(PoiGateAction::Release)) => {
+                info!(%allocation_id, "POI gate released by the operator");
+                return GateOutcome::Released;
+            }
+            Ok(action) => action == Some(PoiGateAction::Hold),
+            Err(e) => {
+                warn!(%allocation_id, error = %e, "Failed to check the POI gate override");
+                false
+            }
+        };
+        match poi_status(network_subgraph, allocation_id, config.confirmations).await {
+            Ok(PoiStatus::Posted { poi }) => {
+                info!(%allocation_id, poi, "POI of the allocation close posted");
+                return GateOutcome::Posted { poi };
+            }
+            Ok(PoiStatus::NotOnChain) => {
+                info!(%allocation_id, "Allocation not in the network subgraph, no POI to wait for");
+                return GateOutcome::NotOnChain;
+            }
+            Ok(PoiStatus::Pending) => {}
+            Err(e) => {
+                warn!(%allocation_id, error = %e, "Failed to check the POI of the allocation")
+            }
+        }
+        if !held && clock.now().duration_since(started) >= config.timeout {
+            warn!(
+                %allocation_id,
+                timeout_secs = config.timeout.as_secs(),
+                "POI of the allocation close not posted in time, marking its last RAVs anyway"
+            );
+            return GateOutcome::TimedOut;
+        }
+        clock.sleep(config.poll_interval).await;
+    }
+}
+
+pub async fn poi_gate_override(
+    pgpool: &PgPool,
+    allocation_id: Address,
+) -> Result<Option<PoiGateAction>> {
+    let action: Option<String> = sqlx::query_scalar(
+        r#"
+            SELECT action FROM scalar_tap_poi_gate_overrides
+            WHERE allocation_id = $1
+        "#,
+    )
+    .bind(AllocationIdHex(allocation_id))
+    .fetch_optional(pgpool)
+    .await?;
+    action.as_deref().map(PoiGateAction::parse).transpose()
+}
+
+pub async fn set_poi_gate_override(
+    pgpool: &PgPool,
+    allocation_id: Address,
+    request: &SetPoiGateOverride,
+    signature: Option<&AdminSignature>,
+) -> Result<()> {
+    if request.reason.trim().is_empty() {
+        bail!("A reason is required");
+    }
+
+    let mut tx = pgpool.begin().await?;
+    sqlx::query(
+        r#"
+            INSERT INTO scalar_tap_poi_gate_overrides (allocation_id, action, reason)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (allocation_id) DO UPDATE SET
+                action = EXCLUDED.action,
+                reason = EXCLUDED.reason,
+                created_at = NOW()
+        "#,
+    )
+    .bind(AllocationIdHex(allocation_id))
+    .bind(request.action.as_str())
+    .bind(&request.reason)
+    .execute(&mut *tx)
+    .await?;
+    record_action(
+        &mut tx,
+        "set_poi_gate_override",
+        allocation_id,
+        &request.reason,
+        json!({ "action": request.action }),
+        signature,
+    )
+    .await?;
+    tx.commit().await?;
+
+    warn!(
+        %allocation_id,
+        action = request.action.as_str(),
+        reason = %request.reason,
+        "POI gate of the allocation overridden by the operator"
+    );
+    Ok(())
+}
+
+/// Returns whether there was an override to remove.
+pub async fn remove_poi_gate_override(
+    pgpool: &PgPool,
+    allocation_id: Address,
+    reason: &str,
+    signature: Option<&AdminSignature>,
+) -> Result<bool> {
+    if reason.trim().is_empty() {
+        bail!("A reason is required");
+    }
+
+    let mut tx = pgpool.begin().await?;
+    let removed = sqlx::query(
+        r#"
+            DELETE FROM scalar_tap_poi_gate_overrides
+            WHERE allocation_id = $1
+        "#,
+    )
+    .bind(AllocationIdHex(allocation_id))
+    .execute(&mut *tx)
+    .await?
+    .rows_affected()
+        > 0;
+    if removed {
+        record_action(
+            &mut tx,
+            "remove_poi_gate_override",
+            allocation_id,
+            reason,
+            json!({}),
+            signature,
+        )
+        .await?;
+    }
+    tx.commit().await?;
+
+    if removed {
+        warn!(%allocation_id, reason, "POI gate override removed by the operator");
+    }
+    Ok(removed)
+}
+
+pub async fn poi_gate_overrides(pgpool: &PgPool) -> Result<Vec<PoiGateOverride>> {
+    let rows = sqlx::query(
+        r#"
+            SELECT allocation_id, action, reason, created_at
+            FROM scalar_tap_poi_gate_overrides
+            ORDER BY allocation_id
+        "#,
+    )
+    .fetch_all(pgpool)
+    .await?;
+
+    rows.iter()
+        .map(|row| {
+            Ok(PoiGateOverride {
+                allocation_id: row.try_get::<AllocationIdHex, _>("allocation_id")?.0,
+                action: PoiGateAction::parse(row.try_get("action")?)?,
+                reason: row.try_get("reason")?,
+                created_at: row.try_get("created_at")?,
+            })
+        })
+        .collect()
+}
+
+struct AdminState {
+    pgpool: PgPool,
+}
+
+fn internal_error(e: anyhow::Error) -> AdminError {
+    error!("Error while handling an admin request: {}", e);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Error while handling an admin request: {}", e),
+    )
+}
+
+async fn handler_poi_gate_overrides(
+    State(state): State<Arc<AdminState>>,
+) -> Result<Json<Vec<PoiGateOverride>>, AdminError> {
+    poi_gate_overrides(&state.pgpool)
+        .await
+        .map(Json)
+        .map_err(internal_error)
+}
+
+async fn handler_set_poi_gate_override(
+    State(state): State<Arc<AdminState>>,
+    Path(allocation_id): Path<Address>,
+    signature: Option<Extension<AdminSignature>>,
+    Json(request): Json<SetPoiGateOverride>,
+) -> Result<StatusCode, AdminError> {
+    if request.reason.trim().is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "A reason is required".to_string()));
+    }
+    let signature = signature.map(|Extension(signature)| signature);
+    set_poi_gate_override(&state.pgpool, allocation_id, &request, signature.as_ref())
+        .await
+        .map_err(internal_error)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn handler_remove_poi_gate_override(
+    State(state): State<Arc<AdminState>>,
+    Path(allocation_id): Path<Address>,
+    Query(request): Query<RemovePoiGateOverride>,
+    signature: Option<Extension<AdminSignature>>,
+) -> Result<StatusCode, AdminError> {
+    if request.reason.trim().is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "A reason is required".to_string()));
+    }
+    let signature = signature.map(|Extension(signature)| signature);
+    match remove_poi_gate_override(
+        &state.pgpool,
+        allocation_id,
+        &request.reason,
+        signature.as_ref(),
+    )
+    .await
+    {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err((
+            StatusCode::NOT_FOUND,
+            "No override for this allocation".into(),
+        )),
+        Err(e) => Err(internal_error(e)),
+    }
+}
+
+/// Admin routes overriding the POI gate, to be mounted on the tap-agent HTTP server.
+pub fn router(pgpool: PgPool, admin_signers: Arc<AdminSigners>) -> Router {
+    Router::new()
+        .route(
+            "/admin/poi-gate/:allocation",
+            put(handler_set_poi_gate_override).delete(handler_remove_poi_gate_override),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            admin_signers,
+            require_signature,
+        ))
+        .route("/admin/poi-gate", get(handler_poi_gate_overrides))
+        .with_state(Arc::new(AdminState { pgpool }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use indexer_common::prelude::DeploymentDetails;
+    use wiremock::{
+        matchers::{body_string_contains, method},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::*;
+    use crate::agent::clock::TokioClock;
+    use crate::tap::test_utils::ALLOCATION_ID_0;
+
+    async fn network_subgraph(
+        block: u64,
+        allocation: serde_json::Value,
+    ) -> (MockServer, SubgraphClient) {
+        let server = MockServer::start().await;
+        server
+            .register(
+                Mock::given(method("POST"))
+                    .and(body_string_contains("poiGate"))
+                    .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                        "data": {
+                            "_meta": { "block": { "number": block } },
+                            "allocation": allocation,
+                        }
+                    }))),
+            )
+            .await;
+        let client = SubgraphClient::new(
+            reqwest::Client::new(),
+            None,
+            DeploymentDetails::for_query_url(&server.uri()).unwrap(),
+        );
+        (server, client)
+    }
+
+    fn config(timeout: Duration) -> PoiGating {
+        PoiGating {
+            confirmations: 10,
+            poll_interval: Duration::from_millis(10),
+            timeout,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_poi_status() {
+        let closed = json!({ "status": "Closed", "poi": "0x01", "closedAtBlockNumber": 100 });
+
+        let (_server, client) = network_subgraph(110, closed.clone()).await;
+        assert_eq!(
+            poi_status(&client, *ALLOCATION_ID_0, 10).await.unwrap(),
+            PoiStatus::Posted {
+                poi: "0x01".to_string()
+            }
+        );
+
+        // Not confirmed by enough blocks yet
+        let (_server, client) = network_subgraph(105, closed).await;
+        assert_eq!(
+            poi_status(&client, *ALLOCATION_ID_0, 10).await.unwrap(),
+            PoiStatus::Pending
+        );
+
+        let (_server, client) = network_subgraph(
+            110,
+            json!({ "status": "Active", "poi": null, "closedAtBlockNumber": null }),
+        )
+        .await;
+        assert_eq!(
+            poi_status(&client, *ALLOCATION_ID_0, 10).await.unwrap(),
+            PoiStatus::Pending
+        );
+
+        let (_server, client) = network_subgraph(110, serde_json::Value::Null).await;
+        assert_eq!(
+            poi_status(&client, *ALLOCATION_ID_0, 10).await.unwrap(),
+            PoiStatus::NotOnChain
+        );
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_wait_for_poi(pgpool: PgPool) {
+        let (_server, client) = network_subgraph(
+            110,
+            json!({ "status": "Active", "poi": null, "closedAtBlockNumber": null }),
+        )
+        .await;
+
+        assert_eq!(
+            wait_for_poi(
+                &pgpool,
+                &client,
+                *ALLOCATION_ID_0,
+                &config(Duration::from_millis(50)),
+                &TokioClock,
+            )
+            .await,
+            GateOutcome::TimedOut
+        );
+
+        // Held past the timeout, until released
+        set_poi_gate_override(
+            &pgpool,
+            *ALLOCATION_ID_0,
+            &SetPoiGateOverride {
+                action: PoiGateAction::Hold,
+                reason: "close being investigated".to_string(),
+            },
+            None,
+        )
+        .await
+        .unwrap();
+        let wait = tokio::spawn({
+            let pgpool = pgpool.clone();
+            async move {
+                wait_for_poi(
+                    &pgpool,
+                    &client,
+                    *ALLOCATION_ID_0,
+                    &config(Duration::ZERO),
+                    &TokioClock,
+                )
+                .await
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!wait.is_finished());
+
+        set_poi_gate_override(
+            &pgpool,
+            *ALLOCATION_ID_0,
+            &SetPoiGateOverride {
+                action: PoiGateAction::Release,
+                reason: "close confirmed on the explorer".to_string(),
+            },
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(wait.await.unwrap(), GateOutcome::Released);
+
+        assert_eq!(
+            poi_gate_overrides(&pgpool).await.unwrap()[0].action,
+            PoiGateAction::Release
+        );
+        assert!(
+            remove_poi_gate_override(&pgpool, *ALLOCATION_ID_0, "closed", None)
+                .await
+                .unwrap()
+        );
+        assert!(poi_gate_overrides(&pgpool).await.unwrap().is_empty());
+    }
+}
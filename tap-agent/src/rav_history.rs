@@ -0,0 +1,425 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! History of the RAVs received and of the failed RAV requests, filtered by sender, allocation
+//! and time range, and paginated with a cursor, for accounting exports.
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    routing::get,
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use indexer_common::{
+    secrets,
+    types::{AllocationIdHex, SenderAddress},
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{types::BigDecimal, PgPool, Row};
+use thegraph::types::Address;
+use tracing::error;
+
+const DEFAULT_PAGE_SIZE: i64 = 100;
+const MAX_PAGE_SIZE: i64 = 1000;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryFilter {
+    pub sender: Option<Address>,
+    pub allocation: Option<Address>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    /// `nextCursor` of the previous page.
+    pub cursor: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+impl HistoryFilter {
+    fn limit(&self) -> i64 {
+        self.limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE)
+    }
+}
+
+/// Entries are returned from the most recent one.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// Cursor of the next page, `None` on the last one.
+    pub next_cursor: Option<i64>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RavHistoryEntry {
+    pub id: i64,
+    pub sender: Address,
+    pub allocation_id: Address,
+    pub timestamp_ns: String,
+    pub value_aggregate: String,
+    /// The RAV was the last one of the allocation, and is to be redeemed.
+    pub last: bool,
+    /// The RAV was redeemed.
+    #[serde(rename = "final")]
+    pub is_final: bool,
+    pub recorded_at: DateTime<Utc>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FailedRavRequest {
+    pub id: i64,
+    pub sender: Address,
+    pub allocation_id: Address,
+    /// `null`, like `ravResponse`, if stored encrypted and the database key isn't set.
+    pub expected_rav: serde_json::Value,
+    pub rav_response: serde_json::Value,
+    pub reason: String,
+    /// Class of the anomaly if the response was rejected before verifying the RAV, in which case
+    /// `ravResponse` is the raw response.
+    pub anomaly: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+pub async fn rav_history(pgpool: &PgPool, filter: &HistoryFilter) -> Result<Page<RavHistoryEntry>> {
+    let rows = sqlx::query(
+        r#"
+            SELECT
+                id, sender_address, allocation_id, timestamp_ns, value_aggregate, last, final,
+                recorded_at
+            FROM scalar_tap_rav_history
+            WHERE ($1::CHAR(40) IS NULL OR sender_address = $1)
+                AND ($2::CHAR(40) IS NULL OR allocation_id = $2)
+                AND ($3::TIMESTAMPTZ IS NULL OR recorded_at >= $3)
+                AND ($4::TIMESTAMPTZ IS NULL OR recorded_at < $4)
+                AND ($5::BIGINT IS NULL OR id < $5)
+            ORDER BY id DESC
+            LIMIT $6
+        "#,
+    )
+    .bind(filter.sender.map(SenderAddress))
+    .bind(filter.allocation.map(AllocationIdHex))
+    .bind(filter.from)
+    .bind(filter.to)
+    .bind(filter.cursor)
+    .bind(filter.limit())
+    .fetch_all(pgpool)
+    .await?;
+
+    let items = rows
+        .iter()
+        .map(|row| {
+            Ok(RavHistoryEntry {
+                id: row.try_get("id")?,
+                sender: row.try_get::<SenderAddress, _>("sender_address")?.0,
+                allocation_id: row.try_get::<AllocationIdHex, _>("allocation_id")?.0,
+                timestamp_ns: row.try_get::<BigDecimal, _>("timestamp_ns")?.to_string(),
+                value_aggregate: row.try_get::<BigDecimal, _>("value_aggregate")?.to_string(),
+                last: row.try_get("last")?,
+                is_final: row.try_get("final")?,
+                recorded_at: row.try_get("recorded_at")?,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(page(items, filter.limit(), |entry| entry.id))
+}
+
+pub async fn failed_rav_requests(
+    pgpool: &PgPool,
+    filter: &HistoryFilter,
+) -> Result<Page<FailedRavRequest>> {
+    failed_rav_requests_with_key(pgpool, filter, secrets::database_key()).await
+}
+
+/// Decrypts the requests stored encrypted with `database_key`.
+async fn failed_rav_requests_with_key(
+    pgpool: &PgPool,
+    filter: &HistoryFilter,
+    database_key: Option<String>,
+) -> Result<Page<FailedRavRequest>> {
+    let rows = sqlx::query(
+        r#"
+            SELECT
+                id, sender_address, allocation_id, reason, anomaly, created_at,
+                COALESCE(expected_rav, sealed->'expected_rav', 'null') AS expected_rav,
+                COALESCE(rav_response, sealed->'rav_response', 'null') AS rav_response
+            FROM (
+                SELECT
+                    *,
+                    pgp_sym_decrypt(sealed_payload, $7)::JSON AS sealed
+                FROM scalar_tap_rav_requests_failed
+            ) failed
+            WHERE ($1::CHAR(40) IS NULL OR sender_address = $1)
+                AND ($2::CHAR(40) IS NULL OR allocation_id = $2)
+                AND ($3::TIMESTAMPTZ IS NULL OR created_at >= $3)
+                AND ($4::TIMESTAMPTZ IS NULL OR created_at < $4)
+                AND ($5::BIGINT IS NULL OR id < $5)
+            ORDER BY id DESC
+            LIMIT $6
+        "#,
+    )
+    .bind(filter.sender.map(SenderAddress))
+    .bind(filter.allocation.map(AllocationIdHex))
+    .bind(filter.from)
+    .bind(filter.to)
+    .bind(filter.cursor)
+    .bind(filter.limit())
+    .bind(database_key)
+    .fetch_all(pgpool)
+    .await?;
+
+    let items = rows
+        .iter()
+        .map(|row| {
+            Ok(FailedRavRequest {
+                id: row.try_get("id")?,
+                sender: row.try_get::<SenderAddress, _>("sender_address")?.0,
+                allocation_id: row.try_get::<AllocationIdHex, _>("allocation_id")?.0,
+                expected_rav: row.try_get("expected_rav")?,
+                rav_response: row.try_get("rav_response")?,
+                reason: row.try_get("reason")?,
+                anomaly: row.try_get("anomaly")?,
+                created_at: row.try_get("created_at")?,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(page(items, filter.limit(), |request| request.id))
+}
+
+fn page<T>(items: Vec<T>, limit: i64, id: impl Fn(&T) -> i64) -> Page<T> {
+    let next_cursor = if items.len() as i64 == limit {
+        items.last().map(id)
+    } else {
+        None
+    };
+    Page { items, next_cursor }
+}
+
+fn internal_error(e: anyhow::Error) -> (StatusCode, String) {
+    error!("Error while getting the RAV history: {}", e);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Error while getting the RAV history: {}", e),
+    )
+}
+
+async fn handler_rav_history(
+    State(pgpool): State<Arc<PgPool>>,
+    Query(filter): Query<HistoryFilter>,
+) -> Result<Json<Page<RavHistoryEntry>>, (StatusCode, String)> {
+    rav_history(&pgpool, &filter)
+        .await
+        .map(Json)
+        .map_err(internal_error)
+}
+
+async fn handler_failed_rav_requests(
+    State(pgpool): State<Arc<PgPool>>,
+    Query(filter): Query<HistoryFilter>,
+) -> Result<Json<Page<FailedRavRequest>>, (StatusCode, String)> {
+    failed_rav_requests(&pgpool, &filter)
+        .await
+        .map(Json)
+        .map_err(internal_error)
+}
+
+/// Routes serving the RAV history, to be mounted on the tap-agent HTTP server.
+pub fn router(pgpool: PgPool) -> Router {
+    Router::new()
+        .route("/state/ravs", get(handler_rav_history))
+        .route("/state/ravs/failed", get(handler_failed_rav_requests))
+        .with_state(Arc::new(pgpool))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tap::test_utils::{
+        create_rav, store_rav, store_rav_with_options, ALLOCATION_ID_0, ALLOCATION_ID_1, SENDER,
+        SIGNER,
+    };
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_rav_history(pgpool: PgPool) {
+        store_rav(
+            &pgpool,
+            create_rav(*ALLOCATION_ID_0, SIGNER.0.clone(), 1, 10),
+            SENDER.1,
+        )
+        .await
+        .unwrap();
+        // Each new RAV of an allocation replaces the previous one in `scalar_tap_ravs`.
+        for (timestamp, value) in [(2, 20), (3, 30)] {
+            sqlx::query(
+                r#"
+                    UPDATE scalar_tap_ravs
+                    SET timestamp_ns = $1, value_aggregate = $2
+                    WHERE allocation_id = $3 AND sender_address = $4
+                "#,
+            )
+            .bind(BigDecimal::from(timestamp))
+            .bind(BigDecimal::from(value))
+            .bind(AllocationIdHex(*ALLOCATION_ID_0))
+            .bind(SenderAddress(SENDER.1))
+            .execute(&pgpool)
+            .await
+            .unwrap();
+        }
+        store_rav_with_options(
+            &pgpool,
+            create_rav(*ALLOCATION_ID_1, SIGNER.0.clone(), 4, 40),
+            SENDER.1,
+            true,
+            true,
+        )
+        .await
+        .unwrap();
+
+        let first_page = rav_history(
+            &pgpool,
+            &HistoryFilter {
+                allocation: Some(*ALLOCATION_ID_0),
+                limit: Some(2),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            first_page
+                .items
+                .iter()
+                .map(|entry| entry.value_aggregate.as_str())
+                .collect::<Vec<_>>(),
+            vec!["30", "20"]
+        );
+        assert!(first_page.next_cursor.is_some());
+
+        let second_page = rav_history(
+            &pgpool,
+            &HistoryFilter {
+                allocation: Some(*ALLOCATION_ID_0),
+                cursor: first_page.next_cursor,
+                limit: Some(2),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(second_page.items.len(), 1);
+        assert_eq!(second_page.items[0].value_aggregate, "10");
+        assert_eq!(second_page.next_cursor, None);
+
+        let all = rav_history(
+            &pgpool,
+            &HistoryFilter {
+                sender: Some(SENDER.1),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(all.items.len(), 4);
+        assert_eq!(all.items[0].allocation_id, *ALLOCATION_ID_1);
+        assert!(all.items[0].last && all.items[0].is_final);
+
+        let future = rav_history(
+            &pgpool,
+            &HistoryFilter {
+                from: Some(Utc::now() + chrono::Duration::hours(1)),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        assert!(future.items.is_empty());
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_failed_rav_requests(pgpool: PgPool) {
+        sqlx::query(
+            r#"
+                INSERT INTO scalar_tap_rav_requests_failed (
+                    allocation_id, sender_address, expected_rav, rav_response, reason
+                )
+                VALUES ($1, $2, '{}', '{}', 'mismatch')
+            "#,
+        )
+        .bind(AllocationIdHex(*ALLOCATION_ID_0))
+        .bind(SenderAddress(SENDER.1))
+        .execute(&pgpool)
+        .await
+        .unwrap();
+
+        let page = failed_rav_requests(
+            &pgpool,
+            &HistoryFilter {
+                sender: Some(SENDER.1),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(page.items.len(), 1);
+        assert_eq!(page.items[0].allocation_id, *ALLOCATION_ID_0);
+        assert_eq!(page.items[0].reason, "mismatch");
+
+        let other_allocation = failed_rav_requests(
+            &pgpool,
+            &HistoryFilter {
+                allocation: Some(*ALLOCATION_ID_1),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        assert!(other_allocation.items.is_empty());
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_sealed_failed_rav_requests(pgpool: PgPool) {
+        sqlx::query(
+            r#"
+                INSERT INTO scalar_tap_rav_requests_failed (
+                    allocation_id, sender_address, reason, sealed_payload
+                )
+                VALUES (
+                    $1,
+                    $2,
+                    'mismatch',
+                    pgp_sym_encrypt('{"expected_rav": {"a": 1}, "rav_response": {"b": 2}}', 'key')
+                )
+            "#,
+        )
+        .bind(AllocationIdHex(*ALLOCATION_ID_0))
+        .bind(SenderAddress(SENDER.1))
+        .execute(&pgpool)
+        .await
+        .unwrap();
+
+        let filter = HistoryFilter::default();
+        let page = failed_rav_requests_with_key(&pgpool, &filter, Some("key".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(page.items[0].expected_rav, serde_json::json!({"a": 1}));
+        assert_eq!(page.items[0].rav_response, serde_json::json!({"b": 2}));
+
+        // Listed without the payload without the key, and not at all with another one
+        let page = failed_rav_requests_with_key(&pgpool, &filter, None)
+            .await
+            .unwrap();
+        assert_eq!(page.items[0].reason, "mismatch");
+        assert_eq!(page.items[0].expected_rav, serde_json::Value::Null);
+        assert!(
+            failed_rav_requests_with_key(&pgpool, &filter, Some("other".to_string()))
+                .await
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,425 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Import of a RAV provided out-of-band by the operator of an aggregator, e.g. after a prolonged
+//! outage of the aggregator. The RAV goes through the same verification as the ones received
+//! from the aggregator: it must be signed by a signer of the sender, follow the stored RAV, and
+//! not be worth more than the receipts it covers. Once stored, the receipts it covers are
+//! obsolete and removed, and the import is recorded in the operator audit log.
+
+use std::sync::Arc;
+
+use alloy_primitives::hex::ToHex;
+use alloy_sol_types::Eip712Domain;
+use axum::{extract::State, http::StatusCode, middleware, routing::post, Extension, Json, Router};
+use eventuals::Eventual;
+use indexer_common::escrow_accounts::EscrowAccounts;
+use indexer_common::types::{AllocationIdHex, GrtWei, SenderAddress};
+use ractor::ActorRef;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{types::BigDecimal, PgPool, Row};
+use tap_core::rav::SignedRAV;
+use thegraph::types::Address;
+use tracing::{error, info, warn};
+
+use crate::admin_signature::{require_signature, AdminSignature, AdminSigners};
+use crate::agent::sender_allocation::SenderAllocationMessage;
+use crate::escrow_overrides::{record_action, AdminError};
+use crate::tap::signers_trimmed;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ImportError {
+    #[error("A reason is required")]
+    MissingReason,
+    #[error("Invalid RAV signature: {0}")]
+    InvalidSignature(String),
+    #[error("RAV signed by {0}, which isn't a signer of the sender")]
+    UnknownSigner(Address),
+    #[error("The last RAV of the allocation is final")]
+    FinalRav,
+    #[error("A RAV request to the aggregator is in flight for this allocation")]
+    RavRequestInFlight,
+    #[error("RAV timestamp {received} isn't after the one of the stored RAV, {stored}")]
+    TimestampRegression { stored: u64, received: u64 },
+    #[error("RAV value {received} is lower than the one of the stored RAV, {stored}")]
+    ValueRegression { stored: u128, received: u128 },
+    #[error("RAV value {received} exceeds the value of the local receipts it covers, {local}")]
+    ExceedsLocalValue { local: u128, received: u128 },
+    #[error(transparent)]
+    Database(#[from] sqlx::Error),
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImportRavRequest {
+    pub sender: Address,
+    pub rav: SignedRAV,
+    pub reason: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportedRav {
+    pub allocation_id: Address,
+    pub signer: Address,
+    pub timestamp_ns: u64,
+    pub value_aggregate: String,
+    /// Value of the stored RAV along with the local receipts the imported one covers.
+    pub local_value: String,
+    pub receipts_removed: u64,
+}
+
+/// Verifies `rav` and stores it as the last RAV of its allocation for `sender`, removing the
+/// receipts it covers.
+pub async fn import_rav(
+    pgpool: &PgPool,
+    escrow_accounts: &Eventual<EscrowAccounts>,
+    domain_separator: &Eip712Domain,
+    sender: Address,
+    rav: &SignedRAV,
+    reason: &str,
+    signature: Option<&AdminSignature>,
+) -> Result<ImportedRav, ImportError> {
+    if reason.trim().is_empty() {
+        return Err(ImportError::MissingReason);
+    }
+    let signer = rav
+        .recover_signer(domain_separator)
+        .map_err(|e| ImportError::InvalidSignature(e.to_string()))?;
+    let signers = signers_trimmed(escrow_accounts, sender).await?;
+    if !signers.contains(&signer.encode_hex::<String>()) {
+        return Err(ImportError::UnknownSigner(signer));
+    }
+    let allocation_id = rav.message.allocationId;
+
+    let mut tx = pgpool.begin().await?;
+    // Locks the stored RAV, if any, against the RAV requests of the agent
+    let stored = sqlx::query(
+        r#"
+            SELECT timestamp_ns, value_aggregate, final
+            FROM scalar_tap_ravs
+            WHERE allocation_id = $1 AND sender_address = $2
+            FOR UPDATE
+        "#,
+    )
+    .bind(AllocationIdHex(allocation_id))
+    .bind(SenderAddress(sender))
+    .fetch_optional(&mut *tx)
+    .await?;
+    let in_flight: Option<bool> = sqlx::query_scalar(
+        r#"
+            SELECT rav_request_in_flight
+            FROM scalar_tap_agent_allocation_checkpoints
+            WHERE sender_address = $1 AND allocation_id = $2
+        "#,
+    )
+    .bind(SenderAddress(sender))
+    .bind(AllocationIdHex(allocation_id))
+    .fetch_optional(&mut *tx)
+    .await?;
+    if in_flight == Some(true) {
+        return Err(ImportError::RavRequestInFlight);
+    }
+
+    let (stored_timestamp_ns, stored_value) = match &stored {
+        Some(row) => {
+            if row.try_get::<bool, _>("final")? {
+                return Err(ImportError::FinalRav);
+            }
+            let timestamp_ns: BigDecimal = row.try_get("timestamp_ns")?;
+            let value: GrtWei = row.try_get("value_aggregate")?;
+            let timestamp_ns = timestamp_ns.to_string().parse::<u64>().map_err(|e| {
+                anyhow::anyhow!("Error decoding the timestamp of the stored RAV: {e}")
+            })?;
+            if rav.message.timestampNs <= timestamp_ns {
+                return Err(ImportError::TimestampRegression {
+                    stored: timestamp_ns,
+                    received: rav.message.timestampNs,
+                });
+            }
+            if rav.message.valueAggregate < value.0 {
+                return Err(ImportError::ValueRegression {
+                    stored: value.0,
+                    received: rav.message.valueAggregate,
+                });
+            }
+            (Some(timestamp_ns), value)
+        }
+        None => (None, GrtWei::default()),
+    };
+
+    let receipts_value = sqlx::query_scalar::<_, Option<GrtWei>>(
+        r#"
+            SELECT SUM(value)
+            FROM scalar_tap_receipts
+            WHERE allocation_id = $1
+                AND signer_address IN (SELECT unnest($2::text[]))
+                AND timestamp_ns > COALESCE($3, -1)
+                AND timestamp_ns <= $4
+        "#,
+    )
+    .bind(AllocationIdHex(allocation_id))
+    .bind(&signers)
+    .bind(stored_timestamp_ns.map(BigDecimal::from))
+    .bind(BigDecimal::from(rav.message.timestampNs))
+    .fetch_one(&mut *tx)
+    .await?
+    .unwrap_or_default();
+    let local_value = stored_value
+        .checked_add(receipts_value)
+        .ok_or_else(|| anyhow::anyhow!("Local RAV value overflows u128"))?
+        .0;
+    if rav.message.valueAggregate > local_value {
+        return Err(ImportError::ExceedsLocalValue {
+            local: local_value,
+            received: rav.message.valueAggregate,
+        });
+    }
+
+    sqlx::query(
+        r#"
+            INSERT INTO scalar_tap_ravs (
+                sender_address,
+                signature,
+                allocation_id,
+                timestamp_ns,
+                value_aggregate,
+                created_at,
+                updated_at
+            )
+            VALUES ($1, $2, $3, $4, $5, NOW(), NOW())
+            ON CONFLICT (allocation_id, sender_address)
+            DO UPDATE SET
+                signature = $2,
+                timestamp_ns = $4,
+                value_aggregate = $5,
+                updated_at = NOW()
+        "#,
+    )
+    .bind(SenderAddress(sender))
+    .bind(rav.signature.to_vec())
+    .bind(AllocationIdHex(allocation_id))
+    .bind(BigDecimal::from(rav.message.timestampNs))
+    .bind(GrtWei(rav.message.valueAggregate))
+    .execute(&mut *tx)
+    .await?;
+
+    let receipts_removed = sqlx::query(
+        r#"
+            DELETE FROM scalar_tap_receipts
+            WHERE allocation_id = $1
+                AND signer_address IN (SELECT unnest($2::text[]))
+                AND timestamp_ns <= $3
+        "#,
+    )
+    .bind(AllocationIdHex(allocation_id))
+    .bind(&signers)
+    .bind(BigDecimal::from(rav.message.timestampNs))
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+
+    let imported = ImportedRav {
+        allocation_id,
+        signer,
+        timestamp_ns: rav.message.timestampNs,
+        value_aggregate: rav.message.valueAggregate.to_string(),
+        local_value: local_value.to_string(),
+        receipts_removed,
+    };
+    record_action(
+        &mut tx,
+        "import_rav",
+        sender,
+        reason,
+        json!({
+            "allocation_id": allocation_id.encode_hex::<String>(),
+            "signer": signer.encode_hex::<String>(),
+            "timestamp_ns": imported.timestamp_ns.to_string(),
+            "value_aggregate": imported.value_aggregate,
+            "local_value": imported.local_value,
+            "receipts_removed": receipts_removed,
+        }),
+        signature,
+    )
+    .await?;
+    tx.commit().await?;
+
+    info!(
+        %sender,
+        %allocation_id,
+        value_aggregate = rav.message.valueAggregate,
+        receipts_removed,
+        reason,
+        "Imported a RAV provided out-of-band"
+    );
+    Ok(imported)
+}
+
+struct RavImportState {
+    pgpool: PgPool,
+    escrow_accounts: Eventual<EscrowAccounts>,
+    domain_separator: Eip712Domain,
+}
+
+async fn handler_import_rav(
+    State(state): State<Arc<RavImportState>>,
+    signature: Option<Extension<AdminSignature>>,
+    Json(request): Json<ImportRavRequest>,
+) -> Result<Json<ImportedRav>, AdminError> {
+    let signature = signature.map(|Extension(signature)| signature);
+    let imported = import_rav(
+        &state.pgpool,
+        &state.escrow_accounts,
+        &state.domain_separator,
+        request.sender,
+        &request.rav,
+        &request.reason,
+        signature.as_ref(),
+    )
+    .await
+    .map_err(|e| match e {
+        ImportError::Database(_) | ImportError::Other(_) => {
+            error!("Error while importing a RAV: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Error while importing a RAV: {}", e),
+            )
+        }
+        ImportError::FinalRav | ImportError::RavRequestInFlight => {
+            (StatusCode::CONFLICT, e.to_string())
+        }
+        e => (StatusCode::UNPROCESSABLE_ENTITY, e.to_string()),
+    })?;
+
+    // The running allocation, if any, tracks the fees of the removed receipts
+    let actor_name = format!("{}:{}", request.sender, imported.allocation_id);
+    if let Some(sender_allocation) = ActorRef::<SenderAllocationMessage>::where_is(actor_name) {
+        if let Err(e) = sender_allocation.cast(SenderAllocationMessage::RavImported(request.rav)) {
+            warn!(
+                "Error while notifying the allocation of the imported RAV: {:?}",
+                e
+            );
+        }
+    }
+    Ok(Json(imported))
+}
+
+/// Admin route importing RAVs, to be mounted on the tap-agent HTTP server.
+pub fn router(
+    pgpool: PgPool,
+    admin_signers: Arc<AdminSigners>,
+    escrow_accounts: Eventual<EscrowAccounts>,
+    domain_separator: Eip712Domain,
+) -> Router {
+    Router::new()
+        .route("/admin/ravs/import", post(handler_import_rav))
+        .route_layer(middleware::from_fn_with_state(
+            admin_signers,
+            require_signature,
+        ))
+        .with_state(Arc::new(RavImportState {
+            pgpool,
+            escrow_accounts,
+            domain_separator,
+        }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use ethereum_types::U256;
+
+    use super::*;
+    use crate::tap::test_utils::{
+        create_rav, create_received_receipt, store_rav, store_receipt, wallet, ALLOCATION_ID_0,
+        SENDER, SIGNER, TAP_EIP712_DOMAIN_SEPARATOR,
+    };
+
+    async fn receipts_count(pgpool: &PgPool) -> i64 {
+        sqlx::query_scalar("SELECT COUNT(*) FROM scalar_tap_receipts")
+            .fetch_one(pgpool)
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_import_rav(pgpool: PgPool) {
+        let escrow_accounts = Eventual::from_value(EscrowAccounts::new(
+            HashMap::from([(SENDER.1, U256::from(1000))]),
+            HashMap::from([(SENDER.1, vec![SIGNER.1])]),
+        ));
+        let import = |rav: SignedRAV| {
+            let pgpool = pgpool.clone();
+            let escrow_accounts = escrow_accounts.clone();
+            async move {
+                import_rav(
+                    &pgpool,
+                    &escrow_accounts,
+                    &TAP_EIP712_DOMAIN_SEPARATOR,
+                    SENDER.1,
+                    &rav,
+                    "aggregator outage",
+                    None,
+                )
+                .await
+            }
+        };
+
+        store_rav(
+            &pgpool,
+            create_rav(*ALLOCATION_ID_0, SIGNER.0.clone(), 10, 50),
+            SENDER.1,
+        )
+        .await
+        .unwrap();
+        for i in 11..=20 {
+            let receipt = create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, i, i, 10);
+            store_receipt(&pgpool, receipt.signed_receipt())
+                .await
+                .unwrap();
+        }
+
+        let unknown_signer = wallet(4);
+        assert!(matches!(
+            import(create_rav(*ALLOCATION_ID_0, unknown_signer.0, 15, 100)).await,
+            Err(ImportError::UnknownSigner(signer)) if signer == unknown_signer.1
+        ));
+        assert!(matches!(
+            import(create_rav(*ALLOCATION_ID_0, SIGNER.0.clone(), 10, 100)).await,
+            Err(ImportError::TimestampRegression { .. })
+        ));
+        // The stored RAV and the 5 receipts up to the timestamp are worth 100
+        assert!(matches!(
+            import(create_rav(*ALLOCATION_ID_0, SIGNER.0.clone(), 15, 101)).await,
+            Err(ImportError::ExceedsLocalValue {
+                local: 100,
+                received: 101
+            })
+        ));
+        assert_eq!(receipts_count(&pgpool).await, 10);
+
+        let imported = import(create_rav(*ALLOCATION_ID_0, SIGNER.0.clone(), 15, 100))
+            .await
+            .unwrap();
+        assert_eq!(imported.receipts_removed, 5);
+        assert_eq!(receipts_count(&pgpool).await, 5);
+        let value: GrtWei = sqlx::query_scalar(
+            "SELECT value_aggregate FROM scalar_tap_ravs WHERE sender_address = $1",
+        )
+        .bind(SenderAddress(SENDER.1))
+        .fetch_one(&pgpool)
+        .await
+        .unwrap();
+        assert_eq!(value.0, 100);
+        let audited: String =
+            sqlx::query_scalar("SELECT action FROM operator_audit_log ORDER BY id DESC LIMIT 1")
+                .fetch_one(&pgpool)
+                .await
+                .unwrap();
+        assert_eq!(audited, "import_rav");
+    }
+}
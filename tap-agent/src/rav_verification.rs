@@ -0,0 +1,267 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Periodic verification of the stored RAVs under the current EIP-712 domain. The RAVs are
+//! verified once, when received, under the domain of the time. A change of the chain id or of
+//! the verifier address since then makes the RAVs signed under the previous domain fail to
+//! redeem, which would otherwise only be noticed once the allocations are closed. The RAVs not
+//! redeemed yet are recovered under the current domain, and the ones not signed by a signer of
+//! their sender are reported.
+
+use alloy_sol_types::Eip712Domain;
+use anyhow::{anyhow, bail, Result};
+use bigdecimal::ToPrimitive;
+use eventuals::Eventual;
+use indexer_common::escrow_accounts::EscrowAccounts;
+use indexer_common::types::{AllocationIdHex, GrtWei, SenderAddress};
+use lazy_static::lazy_static;
+use prometheus::{register_int_gauge_vec, IntGaugeVec};
+use reqwest::Url;
+use serde::Serialize;
+use serde_json::json;
+use sqlx::{types::BigDecimal, PgPool, Row};
+use tap_core::rav::{ReceiptAggregateVoucher, SignedRAV};
+use thegraph::types::Address;
+use tracing::{error, info};
+
+use crate::scheduler::Job;
+
+lazy_static! {
+    static ref RAVS_FAILING_VERIFICATION: IntGaugeVec = register_int_gauge_vec!(
+        format!("tap_ravs_failing_verification"),
+        "RAVs not redeemed yet that don't verify under the current EIP-712 domain",
+        &["sender"]
+    )
+    .unwrap();
+}
+
+/// A stored RAV that wouldn't redeem under the current domain.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UnverifiableRav {
+    pub sender: Address,
+    pub allocation_id: Address,
+    /// In GRT wei, as a string for the clients parsing JSON numbers as doubles.
+    pub value_aggregate: String,
+    pub reason: String,
+}
+
+pub struct RavVerification {
+    pub pgpool: PgPool,
+    pub escrow_accounts: Eventual<EscrowAccounts>,
+    pub domain_separator: Eip712Domain,
+    pub webhook_url: Option<Url>,
+    pub http_client: reqwest::Client,
+}
+
+#[async_trait::async_trait]
+impl Job for RavVerification {
+    fn name(&self) -> &'static str {
+        "rav_signature_verification"
+    }
+
+    fn default_schedule(&self) -> &'static str {
+        "17 * * * *"
+    }
+
+    async fn run(&self) -> Result<()> {
+        let unverifiable = self.verify().await?;
+
+        RAVS_FAILING_VERIFICATION.reset();
+        for rav in &unverifiable {
+            RAVS_FAILING_VERIFICATION
+                .with_label_values(&[&rav.sender.to_string()])
+                .inc();
+            error!(
+                sender = %rav.sender,
+                allocation_id = %rav.allocation_id,
+                value_aggregate = rav.value_aggregate,
+                reason = rav.reason,
+                "Stored RAV doesn't verify under the current EIP-712 domain, it won't be redeemable."
+            );
+        }
+        if unverifiable.is_empty() {
+            return Ok(());
+        }
+
+        if let Some(webhook_url) = &self.webhook_url {
+            let body = json!({
+                "chainId": self.domain_separator.chain_id.map(|id| id.to_string()),
+                "verifyingContract": self.domain_separator.verifying_contract,
+                "ravs": unverifiable,
+            });
+            let response = self
+                .http_client
+                .post(webhook_url.clone())
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body.to_string())
+                .send()
+                .await?;
+            if !response.status().is_success() {
+                bail!(
+                    "The RAV verification webhook responded with {}",
+                    response.status()
+                );
+            }
+        }
+        info!(
+            count = unverifiable.len(),
+            "Reported the RAVs failing verification."
+        );
+        Ok(())
+    }
+}
+
+impl RavVerification {
+    /// The RAVs not redeemed yet that aren't signed by a signer of their sender under the
+    /// current domain.
+    pub async fn verify(&self) -> Result<Vec<UnverifiableRav>> {
+        let escrow_accounts = self
+            .escrow_accounts
+            .value_immediate()
+            .ok_or_else(|| anyhow!("Escrow accounts are not available yet"))?;
+
+        let rows = sqlx::query(
+            r#"
+                SELECT sender_address, allocation_id, signature, timestamp_ns, value_aggregate
+                FROM scalar_tap_ravs
+                WHERE NOT final
+                ORDER BY sender_address, allocation_id
+            "#,
+        )
+        .fetch_all(&self.pgpool)
+        .await?;
+
+        let mut unverifiable = Vec::new();
+        for row in rows {
+            let sender = row.try_get::<SenderAddress, _>("sender_address")?.0;
+            let rav = SignedRAV {
+                message: ReceiptAggregateVoucher {
+                    allocationId: row.try_get::<AllocationIdHex, _>("allocation_id")?.0,
+                    timestampNs: row
+                        .try_get::<BigDecimal, _>("timestamp_ns")?
+                        .to_u64()
+                        .ok_or_else(|| anyhow!("Invalid RAV timestamp"))?,
+                    valueAggregate: row.try_get::<GrtWei, _>("value_aggregate")?.0,
+                },
+                signature: row
+                    .try_get::<Vec<u8>, _>("signature")?
+                    .as_slice()
+                    .try_into()?,
+            };
+
+            // Under another domain, the recovered signer is an unrelated address
+            let reason = match rav.recover_signer(&self.domain_separator) {
+                Err(e) => Some(format!("Failed to recover the signer: {e}")),
+                Ok(signer) => match escrow_accounts.get_sender_for_signer(&signer) {
+                    Ok(signer_sender) if signer_sender == sender => None,
+                    Ok(signer_sender) => Some(format!(
+                        "Recovered signer {signer} belongs to {signer_sender}"
+                    )),
+                    Err(_) => Some(format!(
+                        "Recovered signer {signer} isn't a signer of any sender"
+                    )),
+                },
+            };
+            if let Some(reason) = reason {
+                unverifiable.push(UnverifiableRav {
+                    sender,
+                    allocation_id: rav.message.allocationId,
+                    value_aggregate: rav.message.valueAggregate.to_string(),
+                    reason,
+                });
+            }
+        }
+        Ok(unverifiable)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use alloy_primitives::U256;
+    use alloy_sol_types::eip712_domain;
+    use eventuals::Eventual;
+    use indexer_common::escrow_accounts::EscrowAccounts;
+    use sqlx::PgPool;
+    use thegraph::types::Address;
+    use wiremock::{
+        matchers::{body_string_contains, method},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::RavVerification;
+    use crate::scheduler::Job;
+    use crate::tap::test_utils::{
+        create_rav, store_rav, store_rav_with_options, ALLOCATION_ID_0, ALLOCATION_ID_1, SENDER,
+        SIGNER, TAP_EIP712_DOMAIN_SEPARATOR,
+    };
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_verify_stored_ravs(pgpool: PgPool) {
+        store_rav(
+            &pgpool,
+            create_rav(*ALLOCATION_ID_0, SIGNER.0.clone(), 1, 10),
+            SENDER.1,
+        )
+        .await
+        .unwrap();
+        // Already redeemed, not verified again
+        store_rav_with_options(
+            &pgpool,
+            create_rav(*ALLOCATION_ID_1, SIGNER.0.clone(), 1, 20),
+            SENDER.1,
+            true,
+            true,
+        )
+        .await
+        .unwrap();
+
+        let escrow_accounts = Eventual::from_value(EscrowAccounts::new(
+            HashMap::from([(SENDER.1, U256::from(1000))]),
+            HashMap::from([(SENDER.1, vec![SIGNER.1])]),
+        ));
+        let job = RavVerification {
+            pgpool: pgpool.clone(),
+            escrow_accounts: escrow_accounts.clone(),
+            domain_separator: TAP_EIP712_DOMAIN_SEPARATOR.clone(),
+            webhook_url: None,
+            http_client: reqwest::Client::new(),
+        };
+        assert!(job.verify().await.unwrap().is_empty());
+
+        let webhook_server = MockServer::start().await;
+        webhook_server
+            .register(
+                Mock::given(method("POST"))
+                    .and(body_string_contains(
+                        ALLOCATION_ID_0.to_string().to_lowercase(),
+                    ))
+                    .respond_with(ResponseTemplate::new(200))
+                    .expect(1),
+            )
+            .await;
+
+        // Chain id changed since the RAV was stored
+        let job = RavVerification {
+            pgpool: pgpool.clone(),
+            escrow_accounts,
+            domain_separator: eip712_domain! {
+                name: "TAP",
+                version: "1",
+                chain_id: 42161,
+                verifying_contract: Address::from([0x11u8; 20]),
+            },
+            webhook_url: Some(webhook_server.uri().parse().unwrap()),
+            http_client: reqwest::Client::new(),
+        };
+        let unverifiable = job.verify().await.unwrap();
+        assert_eq!(unverifiable.len(), 1);
+        assert_eq!(unverifiable[0].sender, SENDER.1);
+        assert_eq!(unverifiable[0].allocation_id, *ALLOCATION_ID_0);
+        assert_eq!(unverifiable[0].value_aggregate, "10");
+
+        job.run().await.unwrap();
+    }
+}
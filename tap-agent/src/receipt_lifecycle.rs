@@ -0,0 +1,556 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Explains what happened to a receipt, e.g. for a sender asking why a receipt wasn't
+//! aggregated: when it was accepted, and whether it's covered by a RAV, waiting for the next
+//! RAV request, or excluded and why. The answer is assembled from the receipts, the invalid
+//! receipts, the RAVs and their history, and the query stats of the receipt if recorded.
+//! Receipts are removed once covered by a RAV, so a receipt aggregated a while ago can't be
+//! told apart from one never received.
+
+use std::sync::Arc;
+
+use alloy_primitives::hex;
+use alloy_sol_types::Eip712Domain;
+use anyhow::{anyhow, bail, Result};
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    routing::get,
+    Json, Router,
+};
+use bigdecimal::ToPrimitive;
+use chrono::{DateTime, Utc};
+use eventuals::Eventual;
+use indexer_common::escrow_accounts::EscrowAccounts;
+use indexer_common::types::{AllocationIdHex, GrtWei, SenderAddress};
+use serde::{Deserialize, Serialize};
+use sqlx::{postgres::PgRow, types::BigDecimal, PgPool, Row};
+use tap_core::receipt::{Receipt, SignedReceipt};
+use thegraph::types::Address;
+use tracing::error;
+
+use crate::escrow_overrides::AdminError;
+use crate::invalid_receipts::check_receipt;
+
+/// How to find the receipt, by one of them.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReceiptKey {
+    /// ID in the receipts to aggregate.
+    pub id: Option<i64>,
+    /// ID in the invalid receipts.
+    pub invalid_id: Option<i64>,
+    /// Hex encoded signature, looked up in both.
+    pub signature: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReceiptStatus {
+    /// Never received, or aggregated and removed since.
+    NotFound,
+    /// Covered by a RAV, to be removed with the next RAV request.
+    CoveredByRav,
+    /// Aggregated by the next RAV request.
+    Pending,
+    /// Too recent to be aggregated yet, see `rav_request_timestamp_buffer_ms`.
+    WithinTimestampBuffer,
+    /// Newer than the last RAV of its allocation, which was already requested.
+    AfterLastRav,
+    /// Doesn't verify under the current EIP-712 domain.
+    InvalidSignature,
+    /// Its signer isn't a signer of any sender in escrow.
+    UnknownSigner,
+    /// Failed its checks when requesting a RAV.
+    Invalid,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReceiptDetails {
+    pub id: i64,
+    pub signer: Address,
+    pub allocation_id: Address,
+    pub timestamp_ns: u64,
+    pub nonce: u64,
+    /// In GRT wei.
+    pub value: String,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LifecycleEvent {
+    pub at: Option<DateTime<Utc>>,
+    pub event: String,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReceiptLifecycle {
+    pub status: ReceiptStatus,
+    pub explanation: String,
+    pub receipt: Option<ReceiptDetails>,
+    pub sender: Option<Address>,
+    pub events: Vec<LifecycleEvent>,
+}
+
+impl ReceiptLifecycle {
+    fn not_found() -> Self {
+        Self {
+            status: ReceiptStatus::NotFound,
+            explanation: "No such receipt. Either it was never received, or it was covered by a \
+                RAV and removed since, receipts being removed once aggregated."
+                .to_string(),
+            receipt: None,
+            sender: None,
+            events: Vec::new(),
+        }
+    }
+}
+
+fn decode_u64(row: &PgRow, column: &str) -> Result<u64> {
+    row.try_get::<BigDecimal, _>(column)?
+        .to_u64()
+        .ok_or_else(|| anyhow!("Invalid `{column}` of a receipt"))
+}
+
+fn decode_receipt(row: &PgRow) -> Result<(ReceiptDetails, SignedReceipt)> {
+    let value = row.try_get::<GrtWei, _>("value")?.0;
+    let details = ReceiptDetails {
+        id: row.try_get("id")?,
+        signer: row.try_get::<SenderAddress, _>("signer_address")?.0,
+        allocation_id: row.try_get::<AllocationIdHex, _>("allocation_id")?.0,
+        timestamp_ns: decode_u64(row, "timestamp_ns")?,
+        nonce: decode_u64(row, "nonce")?,
+        value: value.to_string(),
+    };
+    let receipt = SignedReceipt {
+        message: Receipt {
+            allocation_id: details.allocation_id,
+            timestamp_ns: details.timestamp_ns,
+            nonce: details.nonce,
+            value,
+        },
+        signature: row
+            .try_get::<Vec<u8>, _>("signature")?
+            .as_slice()
+            .try_into()?,
+    };
+    Ok((details, receipt))
+}
+
+fn datetime(timestamp_ns: u64) -> Option<DateTime<Utc>> {
+    i64::try_from(timestamp_ns)
+        .ok()
+        .map(DateTime::from_timestamp_nanos)
+}
+
+/// Explains the lifecycle of the receipt of `key`, as of `now_ns`.
+pub async fn explain_receipt(
+    pgpool: &PgPool,
+    escrow_accounts: &EscrowAccounts,
+    domain_separator: &Eip712Domain,
+    timestamp_buffer_ns: u64,
+    key: &ReceiptKey,
+    now_ns: u64,
+) -> Result<ReceiptLifecycle> {
+    let signature = key
+        .signature
+        .as_deref()
+        .map(|signature| hex::decode(signature).map_err(|e| anyhow!("Invalid signature: {e}")))
+        .transpose()?;
+    let (valid_id, invalid_id) = match (key.id, key.invalid_id, &signature) {
+        (Some(id), None, None) => (Some(id), None),
+        (None, Some(id), None) => (None, Some(id)),
+        (None, None, Some(_)) => (None, None),
+        _ => bail!("Exactly one of `id`, `invalidId` and `signature` is required"),
+    };
+
+    let mut row = None;
+    if invalid_id.is_none() {
+        row = sqlx::query(
+            r#"
+                SELECT id, signer_address, signature, allocation_id, timestamp_ns, nonce, value
+                FROM scalar_tap_receipts
+                WHERE id = $1 OR signature = $2
+                LIMIT 1
+            "#,
+        )
+        .bind(valid_id)
+        .bind(&signature)
+        .fetch_optional(pgpool)
+        .await?;
+    }
+    let mut invalid_row = None;
+    if row.is_none() && valid_id.is_none() {
+        invalid_row = sqlx::query(
+            r#"
+                SELECT id, signer_address, signature, allocation_id, timestamp_ns, nonce, value,
+                    error_log
+                FROM scalar_tap_receipts_invalid
+                WHERE id = $1 OR signature = $2
+                ORDER BY id DESC
+                LIMIT 1
+            "#,
+        )
+        .bind(invalid_id)
+        .bind(&signature)
+        .fetch_optional(pgpool)
+        .await?;
+    }
+    let (details, receipt, error_log) = match (row, invalid_row) {
+        (Some(row), _) => {
+            let (details, receipt) = decode_receipt(&row)?;
+            (details, receipt, None)
+        }
+        (None, Some(row)) => {
+            let (details, receipt) = decode_receipt(&row)?;
+            (
+                details,
+                receipt,
+                Some(row.try_get::<String, _>("error_log")?),
+            )
+        }
+        (None, None) => return Ok(ReceiptLifecycle::not_found()),
+    };
+
+    let accepted_at: Option<DateTime<Utc>> = sqlx::query_scalar(
+        r#"
+            SELECT created_at FROM scalar_tap_receipt_query_stats
+            WHERE allocation_id = $1 AND timestamp_ns = $2 AND nonce = $3
+            LIMIT 1
+        "#,
+    )
+    .bind(AllocationIdHex(details.allocation_id))
+    .bind(BigDecimal::from(details.timestamp_ns))
+    .bind(BigDecimal::from(details.nonce))
+    .fetch_optional(pgpool)
+    .await?;
+    let mut events = vec![LifecycleEvent {
+        at: accepted_at.or_else(|| datetime(details.timestamp_ns)),
+        event: format!(
+            "Accepted, {} GRT wei for allocation {} signed by {}",
+            details.value, details.allocation_id, details.signer
+        ),
+    }];
+    let sender = escrow_accounts.get_sender_for_signer(&details.signer).ok();
+
+    if let Some(error_log) = error_log {
+        events.push(LifecycleEvent {
+            at: None,
+            event: format!("Failed its checks when requesting a RAV: {error_log}"),
+        });
+        let mut tx = pgpool.begin().await?;
+        let recheck = check_receipt(&mut tx, escrow_accounts, domain_separator, &receipt).await;
+        tx.rollback().await?;
+        let explanation = match recheck {
+            Ok(_) => format!(
+                "Excluded from the RAVs: {error_log}. It passes its checks now, and can be \
+                promoted back to the receipts to aggregate."
+            ),
+            Err(e) => format!("Excluded from the RAVs: {error_log}. It still fails now: {e}"),
+        };
+        return Ok(ReceiptLifecycle {
+            status: ReceiptStatus::Invalid,
+            explanation,
+            receipt: Some(details),
+            sender,
+            events,
+        });
+    }
+
+    let (status, explanation) = receipt_status(
+        pgpool,
+        domain_separator,
+        timestamp_buffer_ns,
+        now_ns,
+        &details,
+        &receipt,
+        sender,
+        &mut events,
+    )
+    .await?;
+    Ok(ReceiptLifecycle {
+        status,
+        explanation,
+        receipt: Some(details),
+        sender,
+        events,
+    })
+}
+
+/// Status of a receipt still to aggregate, adding the RAV covering it to `events` if any.
+#[allow(clippy::too_many_arguments)]
+async fn receipt_status(
+    pgpool: &PgPool,
+    domain_separator: &Eip712Domain,
+    timestamp_buffer_ns: u64,
+    now_ns: u64,
+    details: &ReceiptDetails,
+    receipt: &SignedReceipt,
+    sender: Option<Address>,
+    events: &mut Vec<LifecycleEvent>,
+) -> Result<(ReceiptStatus, String)> {
+    match receipt.recover_signer(domain_separator) {
+        Ok(signer) if signer == details.signer => {}
+        Ok(signer) => {
+            return Ok((
+                ReceiptStatus::InvalidSignature,
+                format!(
+                    "Under the current EIP-712 domain the receipt recovers to {signer}, it was \
+                    likely signed for another chain id or verifier address."
+                ),
+            ))
+        }
+        Err(e) => {
+            return Ok((
+                ReceiptStatus::InvalidSignature,
+                format!("The signature of the receipt doesn't verify: {e}"),
+            ))
+        }
+    }
+    let Some(sender) = sender else {
+        return Ok((
+            ReceiptStatus::UnknownSigner,
+            "Its signer isn't a signer of any sender in escrow, it will fail its checks when \
+            requesting a RAV, unless the signer is authorized again by then."
+                .to_string(),
+        ));
+    };
+
+    let covering_rav = sqlx::query(
+        r#"
+            SELECT timestamp_ns, value_aggregate, recorded_at
+            FROM scalar_tap_rav_history
+            WHERE allocation_id = $1 AND sender_address = $2 AND timestamp_ns >= $3
+            ORDER BY timestamp_ns, id
+            LIMIT 1
+        "#,
+    )
+    .bind(AllocationIdHex(details.allocation_id))
+    .bind(SenderAddress(sender))
+    .bind(BigDecimal::from(details.timestamp_ns))
+    .fetch_optional(pgpool)
+    .await?;
+    if let Some(rav) = covering_rav {
+        let rav_timestamp_ns = decode_u64(&rav, "timestamp_ns")?;
+        events.push(LifecycleEvent {
+            at: rav.try_get("recorded_at")?,
+            event: format!(
+                "Covered by the RAV at timestamp {rav_timestamp_ns}, of {} GRT wei",
+                rav.try_get::<GrtWei, _>("value_aggregate")?.0
+            ),
+        });
+        return Ok((
+            ReceiptStatus::CoveredByRav,
+            format!(
+                "Aggregated into the RAV at timestamp {rav_timestamp_ns}, the receipt is removed \
+                with the next RAV request of its allocation."
+            ),
+        ));
+    }
+
+    let last_rav_requested: Option<bool> = sqlx::query_scalar(
+        r#"
+            SELECT last OR final FROM scalar_tap_ravs
+            WHERE allocation_id = $1 AND sender_address = $2
+        "#,
+    )
+    .bind(AllocationIdHex(details.allocation_id))
+    .bind(SenderAddress(sender))
+    .fetch_optional(pgpool)
+    .await?;
+    Ok(if last_rav_requested == Some(true) {
+        (
+            ReceiptStatus::AfterLastRav,
+            "Received after the last RAV of its allocation was requested, it won't be aggregated."
+                .to_string(),
+        )
+    } else if details.timestamp_ns.saturating_add(timestamp_buffer_ns) > now_ns {
+        (
+            ReceiptStatus::WithinTimestampBuffer,
+            format!(
+                "Too recent to be aggregated, receipts are aggregated once older than the \
+                timestamp buffer of {} ms.",
+                timestamp_buffer_ns / 1_000_000
+            ),
+        )
+    } else {
+        (
+            ReceiptStatus::Pending,
+            "Not aggregated yet, it's included in the next RAV request of its allocation."
+                .to_string(),
+        )
+    })
+}
+
+struct ReceiptLifecycleState {
+    pgpool: PgPool,
+    escrow_accounts: Eventual<EscrowAccounts>,
+    domain_separator: Eip712Domain,
+    timestamp_buffer_ns: u64,
+}
+
+async fn handler_explain_receipt(
+    State(state): State<Arc<ReceiptLifecycleState>>,
+    Query(key): Query<ReceiptKey>,
+) -> Result<Json<ReceiptLifecycle>, AdminError> {
+    let escrow_accounts = state.escrow_accounts.value_immediate().ok_or((
+        StatusCode::SERVICE_UNAVAILABLE,
+        "Escrow accounts are not available yet".to_string(),
+    ))?;
+    let now_ns = Utc::now().timestamp_nanos_opt().unwrap_or_default() as u64;
+    explain_receipt(
+        &state.pgpool,
+        &escrow_accounts,
+        &state.domain_separator,
+        state.timestamp_buffer_ns,
+        &key,
+        now_ns,
+    )
+    .await
+    .map(Json)
+    .map_err(|e| {
+        error!("Error while explaining a receipt: {}", e);
+        (
+            StatusCode::BAD_REQUEST,
+            format!("Error while explaining a receipt: {}", e),
+        )
+    })
+}
+
+/// Admin route explaining the lifecycle of a receipt, to be mounted on the tap-agent HTTP
+/// server.
+pub fn router(
+    pgpool: PgPool,
+    escrow_accounts: Eventual<EscrowAccounts>,
+    domain_separator: Eip712Domain,
+    timestamp_buffer_ns: u64,
+) -> Router {
+    Router::new()
+        .route("/admin/receipts/explain", get(handler_explain_receipt))
+        .with_state(Arc::new(ReceiptLifecycleState {
+            pgpool,
+            escrow_accounts,
+            domain_separator,
+            timestamp_buffer_ns,
+        }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use alloy_primitives::U256;
+
+    use super::*;
+    use crate::tap::test_utils::{
+        create_rav, create_received_receipt, store_invalid_receipt, store_rav,
+        store_rav_with_options, store_receipt, ALLOCATION_ID_0, ALLOCATION_ID_1, SENDER, SIGNER,
+        TAP_EIP712_DOMAIN_SEPARATOR,
+    };
+
+    const SECOND: u64 = 1_000_000_000;
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_explain_receipt(pgpool: PgPool) {
+        let escrow_accounts = EscrowAccounts::new(
+            HashMap::from([(SENDER.1, U256::from(1000))]),
+            HashMap::from([(SENDER.1, vec![SIGNER.1])]),
+        );
+        let explain = |key: ReceiptKey| {
+            let pgpool = pgpool.clone();
+            let escrow_accounts = escrow_accounts.clone();
+            async move {
+                explain_receipt(
+                    &pgpool,
+                    &escrow_accounts,
+                    &TAP_EIP712_DOMAIN_SEPARATOR,
+                    30 * SECOND,
+                    &key,
+                    100 * SECOND,
+                )
+                .await
+                .unwrap()
+            }
+        };
+        let by_id = |id: u64| ReceiptKey {
+            id: Some(id as i64),
+            ..Default::default()
+        };
+
+        let covered = create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, 1, 10 * SECOND, 5);
+        let covered_id = store_receipt(&pgpool, covered.signed_receipt())
+            .await
+            .unwrap();
+        let pending = create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, 2, 50 * SECOND, 5);
+        let pending_id = store_receipt(&pgpool, pending.signed_receipt())
+            .await
+            .unwrap();
+        let recent = create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, 3, 90 * SECOND, 5);
+        let recent_id = store_receipt(&pgpool, recent.signed_receipt())
+            .await
+            .unwrap();
+        store_rav(
+            &pgpool,
+            create_rav(*ALLOCATION_ID_0, SIGNER.0.clone(), 20 * SECOND, 5),
+            SENDER.1,
+        )
+        .await
+        .unwrap();
+
+        let lifecycle = explain(by_id(covered_id)).await;
+        assert_eq!(lifecycle.status, ReceiptStatus::CoveredByRav);
+        assert_eq!(lifecycle.sender, Some(SENDER.1));
+        assert_eq!(lifecycle.events.len(), 2);
+        assert_eq!(
+            explain(by_id(pending_id)).await.status,
+            ReceiptStatus::Pending
+        );
+        assert_eq!(
+            explain(by_id(recent_id)).await.status,
+            ReceiptStatus::WithinTimestampBuffer
+        );
+
+        // Found by signature as well
+        let lifecycle = explain(ReceiptKey {
+            signature: Some(hex::encode(pending.signed_receipt().signature.to_vec())),
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(lifecycle.receipt.unwrap().id, pending_id as i64);
+
+        // Newer than the last RAV of its allocation
+        let late = create_received_receipt(&ALLOCATION_ID_1, &SIGNER.0, 4, 50 * SECOND, 5);
+        let late_id = store_receipt(&pgpool, late.signed_receipt()).await.unwrap();
+        store_rav_with_options(
+            &pgpool,
+            create_rav(*ALLOCATION_ID_1, SIGNER.0.clone(), 20 * SECOND, 5),
+            SENDER.1,
+            true,
+            false,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            explain(by_id(late_id)).await.status,
+            ReceiptStatus::AfterLastRav
+        );
+
+        let invalid = create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, 5, 5 * SECOND, 5);
+        let invalid_id = store_invalid_receipt(&pgpool, invalid.signed_receipt())
+            .await
+            .unwrap();
+        let lifecycle = explain(ReceiptKey {
+            invalid_id: Some(invalid_id as i64),
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(lifecycle.status, ReceiptStatus::Invalid);
+        // Covered by the RAV of its allocation by now
+        assert!(lifecycle.explanation.contains("still fails"));
+
+        assert_eq!(explain(by_id(1000)).await.status, ReceiptStatus::NotFound);
+    }
+}
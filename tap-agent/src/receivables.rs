@@ -0,0 +1,292 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Receivables and escrow exposure of each sender as of any point in time, e.g. to answer "what
+//! was owed by this sender at month end". The RAVs are taken from the RAV history as they were
+//! at that time, and the receipts by their timestamp. Receipts are deleted once aggregated, so
+//! for a past point the unaggregated fees only include the receipts not aggregated since.
+
+use std::sync::Arc;
+
+use alloy_primitives::hex::ToHex;
+use anyhow::{anyhow, Result};
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    routing::get,
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use eventuals::Eventual;
+use indexer_common::escrow_accounts::EscrowAccounts;
+use indexer_common::types::SenderAddress;
+use serde::{Deserialize, Serialize};
+use sqlx::{types::BigDecimal, PgPool, Row};
+use thegraph::types::Address;
+use tracing::error;
+
+use crate::tap::signers_cache::cached_signers;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReceivablesQuery {
+    /// Defaults to now.
+    pub as_of: Option<DateTime<Utc>>,
+    pub sender: Option<Address>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Receivables {
+    pub as_of: DateTime<Utc>,
+    pub senders: Vec<SenderReceivables>,
+}
+
+/// Amounts are in GRT wei.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SenderReceivables {
+    pub sender: Address,
+    /// Value of the latest RAVs of all the allocations, redeemed or not.
+    pub fees_aggregated: String,
+    pub value_redeemed: String,
+    /// Value of the RAVs not redeemed yet.
+    pub outstanding_ravs: String,
+    /// Value of the receipts not covered by a RAV yet.
+    pub unaggregated_fees: String,
+    /// Fees owed by the sender and to be paid from its escrow, outstanding RAVs and
+    /// unaggregated fees.
+    pub exposure: String,
+}
+
+/// Receivables of the senders at `as_of`, of `sender` only if set. The signers of the receipts
+/// are mapped to their senders with the current escrow accounts.
+pub async fn receivables_as_of(
+    pgpool: &PgPool,
+    escrow_accounts: &EscrowAccounts,
+    as_of: DateTime<Utc>,
+    sender: Option<Address>,
+) -> Result<Receivables> {
+    let as_of_ns = as_of
+        .timestamp_nanos_opt()
+        .ok_or_else(|| anyhow!("{as_of} is out of range"))?;
+    let (signers, senders): (Vec<String>, Vec<String>) = escrow_accounts
+        .get_senders()
+        .into_iter()
+        .filter(|escrow_sender| sender.is_none() || sender == Some(*escrow_sender))
+        .flat_map(|sender| {
+            let signers = cached_signers(escrow_accounts, sender);
+            let sender: String = sender.encode_hex();
+            signers
+                .trimmed
+                .iter()
+                .map(|signer| (signer.clone(), sender.clone()))
+                .collect::<Vec<_>>()
+        })
+        .unzip();
+
+    // The latest RAV of each allocation recorded by then, and the receipts timestamped by then
+    // and after that RAV.
+    let rows = sqlx::query(
+        r#"
+            WITH ravs AS (
+                SELECT DISTINCT ON (sender_address, allocation_id)
+                    sender_address, allocation_id, timestamp_ns, value_aggregate, final
+                FROM scalar_tap_rav_history
+                WHERE recorded_at <= $1
+                    AND ($3::CHAR(40) IS NULL OR sender_address = $3)
+                ORDER BY sender_address, allocation_id, id DESC
+            ),
+            rav_totals AS (
+                SELECT
+                    sender_address,
+                    SUM(value_aggregate) AS fees_aggregated,
+                    COALESCE(SUM(value_aggregate) FILTER (WHERE final), 0) AS value_redeemed,
+                    COALESCE(SUM(value_aggregate) FILTER (WHERE NOT final), 0) AS outstanding_ravs
+                FROM ravs
+                GROUP BY sender_address
+            ),
+            signers AS (
+                SELECT *
+                FROM UNNEST($4::CHAR(40)[], $5::CHAR(40)[]) AS signers(signer_address, sender_address)
+            ),
+            unaggregated AS (
+                SELECT signers.sender_address, SUM(receipts.value) AS unaggregated_fees
+                FROM scalar_tap_receipts receipts
+                JOIN signers ON signers.signer_address = receipts.signer_address
+                LEFT JOIN ravs
+                    ON ravs.sender_address = signers.sender_address
+                    AND ravs.allocation_id = receipts.allocation_id
+                WHERE receipts.timestamp_ns <= $2
+                    AND (ravs.timestamp_ns IS NULL OR receipts.timestamp_ns > ravs.timestamp_ns)
+                GROUP BY signers.sender_address
+            )
+            SELECT
+                sender_address,
+                COALESCE(fees_aggregated, 0) AS fees_aggregated,
+                COALESCE(value_redeemed, 0) AS value_redeemed,
+                COALESCE(outstanding_ravs, 0) AS outstanding_ravs,
+                COALESCE(unaggregated_fees, 0) AS unaggregated_fees
+            FROM rav_totals
+            FULL JOIN unaggregated USING (sender_address)
+            ORDER BY sender_address
+        "#,
+    )
+    .bind(as_of)
+    .bind(BigDecimal::from(as_of_ns))
+    .bind(sender.map(SenderAddress))
+    .bind(signers)
+    .bind(senders)
+    .fetch_all(pgpool)
+    .await?;
+
+    let senders = rows
+        .iter()
+        .map(|row| {
+            let outstanding_ravs: BigDecimal = row.try_get("outstanding_ravs")?;
+            let unaggregated_fees: BigDecimal = row.try_get("unaggregated_fees")?;
+            Ok(SenderReceivables {
+                sender: row.try_get::<SenderAddress, _>("sender_address")?.0,
+                fees_aggregated: row.try_get::<BigDecimal, _>("fees_aggregated")?.to_string(),
+                value_redeemed: row.try_get::<BigDecimal, _>("value_redeemed")?.to_string(),
+                exposure: (&outstanding_ravs + &unaggregated_fees).to_string(),
+                outstanding_ravs: outstanding_ravs.to_string(),
+                unaggregated_fees: unaggregated_fees.to_string(),
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(Receivables { as_of, senders })
+}
+
+struct ReceivablesState {
+    pgpool: PgPool,
+    escrow_accounts: Eventual<EscrowAccounts>,
+}
+
+async fn handler_receivables(
+    State(state): State<Arc<ReceivablesState>>,
+    Query(query): Query<ReceivablesQuery>,
+) -> Result<Json<Receivables>, (StatusCode, String)> {
+    let now = Utc::now();
+    let as_of = query.as_of.unwrap_or(now);
+    if as_of > now {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "`asOf` must not be in the future".to_string(),
+        ));
+    }
+    let escrow_accounts = state.escrow_accounts.value_immediate().unwrap_or_default();
+    receivables_as_of(&state.pgpool, &escrow_accounts, as_of, query.sender)
+        .await
+        .map(Json)
+        .map_err(|e| {
+            error!("Error while getting the receivables: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Error while getting the receivables: {}", e),
+            )
+        })
+}
+
+pub fn router(pgpool: PgPool, escrow_accounts: Eventual<EscrowAccounts>) -> Router {
+    Router::new()
+        .route("/state/receivables", get(handler_receivables))
+        .with_state(Arc::new(ReceivablesState {
+            pgpool,
+            escrow_accounts,
+        }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use chrono::Duration;
+    use ethereum_types::U256;
+    use indexer_common::types::AllocationIdHex;
+
+    use super::*;
+    use crate::tap::test_utils::{
+        create_rav, create_received_receipt, store_rav, store_receipt, ALLOCATION_ID_0,
+        ALLOCATION_ID_1, SENDER, SIGNER,
+    };
+
+    /// Moves the history of the RAV of `allocation_id` to `at`.
+    async fn record_at(pgpool: &PgPool, allocation_id: Address, at: DateTime<Utc>) {
+        sqlx::query("UPDATE scalar_tap_rav_history SET recorded_at = $1 WHERE allocation_id = $2")
+            .bind(at)
+            .bind(AllocationIdHex(allocation_id))
+            .execute(pgpool)
+            .await
+            .unwrap();
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_receivables_as_of(pgpool: PgPool) {
+        let escrow_accounts = EscrowAccounts::new(
+            HashMap::from([(SENDER.1, U256::from(1000))]),
+            HashMap::from([(SENDER.1, vec![SIGNER.1])]),
+        );
+        let now = Utc::now();
+        let month_end = now - Duration::days(10);
+        let ns = |at: DateTime<Utc>| at.timestamp_nanos_opt().unwrap() as u64;
+
+        // RAV of allocation 0 before the month end, covering the first receipt
+        store_rav(
+            &pgpool,
+            create_rav(*ALLOCATION_ID_0, SIGNER.0.clone(), ns(month_end) - 2, 100),
+            SENDER.1,
+        )
+        .await
+        .unwrap();
+        record_at(&pgpool, *ALLOCATION_ID_0, month_end - Duration::days(1)).await;
+        // Receipts before and after the month end, and a RAV after it
+        for (allocation_id, nonce, timestamp_ns) in [
+            (*ALLOCATION_ID_0, 1, ns(month_end) - 3),
+            (*ALLOCATION_ID_0, 2, ns(month_end) - 1),
+            (*ALLOCATION_ID_1, 3, ns(month_end) - 1),
+            (*ALLOCATION_ID_1, 4, ns(now) - 1),
+        ] {
+            let receipt =
+                create_received_receipt(&allocation_id, &SIGNER.0, nonce, timestamp_ns, 10);
+            store_receipt(&pgpool, receipt.signed_receipt())
+                .await
+                .unwrap();
+        }
+        store_rav(
+            &pgpool,
+            create_rav(*ALLOCATION_ID_1, SIGNER.0.clone(), ns(now) - 1, 20),
+            SENDER.1,
+        )
+        .await
+        .unwrap();
+
+        let receivables = receivables_as_of(&pgpool, &escrow_accounts, month_end, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            receivables.senders,
+            vec![SenderReceivables {
+                sender: SENDER.1,
+                fees_aggregated: "100".to_string(),
+                value_redeemed: "0".to_string(),
+                outstanding_ravs: "100".to_string(),
+                unaggregated_fees: "20".to_string(),
+                exposure: "120".to_string(),
+            }]
+        );
+
+        let receivables = receivables_as_of(&pgpool, &escrow_accounts, Utc::now(), Some(SENDER.1))
+            .await
+            .unwrap();
+        assert_eq!(receivables.senders[0].outstanding_ravs, "120");
+        assert_eq!(receivables.senders[0].unaggregated_fees, "10");
+        assert!(
+            receivables_as_of(&pgpool, &escrow_accounts, Utc::now(), Some(Address::ZERO))
+                .await
+                .unwrap()
+                .senders
+                .is_empty()
+        );
+    }
+}
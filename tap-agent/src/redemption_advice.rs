@@ -0,0 +1,545 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Advice on which last RAVs are economical to redeem now, and which ones to batch later, for
+//! the automation redeeming them, e.g. indexer-agent. Redeeming a RAV costs gas, so a RAV whose
+//! value is not well above the cost of its redemption at the current gas price is better left to
+//! grow, or redeemed once gas is cheaper. A RAV waiting for too long is advised to be redeemed
+//! anyway, before the escrow of its sender could thaw.
+//!
+//! The gas price is read from the network with `eth_gasPrice`, and converted to GRT with the
+//! configured price of the native token. The advice is logged periodically by
+//! [`LogRedemptionAdvice`], so that it can be compared with the redemptions made afterwards.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    routing::get,
+    Json, Router,
+};
+use bigdecimal::{num_bigint::BigInt, BigDecimal, ToPrimitive};
+use chrono::{DateTime, Utc};
+use indexer_common::types::{AllocationIdHex, GrtWei, SenderAddress};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{PgPool, Row};
+use thegraph::types::Address;
+use tracing::{error, info};
+
+use crate::config::RedemptionAdvice;
+use crate::scheduler::Job;
+
+/// Advice entries served by the history, from the most recent one.
+const HISTORY_PAGE_SIZE: i64 = 500;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    RedeemNow,
+    BatchLater,
+}
+
+impl Action {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Action::RedeemNow => "redeem_now",
+            Action::BatchLater => "batch_later",
+        }
+    }
+}
+
+/// A last RAV not redeemed yet.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PendingRav {
+    pub sender: Address,
+    pub allocation_id: Address,
+    pub value_aggregate: u128,
+    pub timestamp_ns: u64,
+}
+
+/// Amounts are in GRT wei, as strings for the clients parsing JSON numbers as doubles.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RavAdvice {
+    pub sender: Address,
+    pub allocation_id: Address,
+    pub value_aggregate: String,
+    pub redemption_cost: String,
+    pub action: Action,
+    pub reason: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AdviceReport {
+    pub chain_id: u64,
+    /// In wei of the native token of the network.
+    pub gas_price: String,
+    /// Cost of redeeming a RAV, in GRT wei.
+    pub redemption_cost: String,
+    /// Value a RAV must reach to be economical to redeem, in GRT wei.
+    pub min_value: String,
+    pub generated_at: DateTime<Utc>,
+    /// Value of the RAVs advised to be redeemed now, in GRT wei.
+    pub redeem_now_value: String,
+    pub ravs: Vec<RavAdvice>,
+}
+
+/// Advice on `ravs` at `gas_price`, in wei of the native token, at `now`.
+pub fn advise(
+    config: &RedemptionAdvice,
+    gas_price: u128,
+    ravs: &[PendingRav],
+    now: DateTime<Utc>,
+) -> AdviceReport {
+    let cost = BigDecimal::from(BigInt::from(gas_price))
+        * BigDecimal::from(config.redeem_gas)
+        * &config.native_token_price_grt;
+    let min_value = &cost * &config.min_value_to_cost_ratio;
+    let now_ns = now.timestamp_nanos_opt().unwrap_or(i64::MAX) as u64;
+    let max_wait_ns = config.max_wait.as_nanos() as u64;
+
+    // Summed exactly, the RAVs to redeem can be worth more than a u128 together
+    let mut redeem_now_value = BigInt::from(0);
+    let ravs = ravs
+        .iter()
+        .map(|rav| {
+            let value = BigDecimal::from(BigInt::from(rav.value_aggregate));
+            let waited_ns = now_ns.saturating_sub(rav.timestamp_ns);
+            let (action, reason) = if value >= min_value {
+                (
+                    Action::RedeemNow,
+                    format!(
+                        "Worth at least {} times its redemption cost",
+                        config.min_value_to_cost_ratio
+                    ),
+                )
+            } else if waited_ns >= max_wait_ns {
+                (
+                    Action::RedeemNow,
+                    format!(
+                        "Last receipt {}s ago, redeemed before the escrow could thaw",
+                        Duration::from_nanos(waited_ns).as_secs()
+                    ),
+                )
+            } else if value <= cost {
+                (
+                    Action::BatchLater,
+                    "Worth less than its redemption cost".to_string(),
+                )
+            } else {
+                (
+                    Action::BatchLater,
+                    format!(
+                        "Worth less than {} times its redemption cost",
+                        config.min_value_to_cost_ratio
+                    ),
+                )
+            };
+            if action == Action::RedeemNow {
+                redeem_now_value += rav.value_aggregate;
+            }
+            RavAdvice {
+                sender: rav.sender,
+                allocation_id: rav.allocation_id,
+                value_aggregate: rav.value_aggregate.to_string(),
+                redemption_cost: round(&cost),
+                action,
+                reason,
+            }
+        })
+        .collect();
+
+    AdviceReport {
+        chain_id: config.chain_id,
+        gas_price: gas_price.to_string(),
+        redemption_cost: round(&cost),
+        min_value: round(&min_value),
+        generated_at: now,
+        redeem_now_value: redeem_now_value.to_string(),
+        ravs,
+    }
+}
+
+/// GRT wei, rounded up to a whole wei.
+fn round(value: &BigDecimal) -> String {
+    let whole = value.with_scale(0);
+    if &whole < value {
+        (whole + BigDecimal::from(1)).to_string()
+    } else {
+        whole.to_string()
+    }
+}
+
+/// The last RAVs not redeemed yet.
+pub async fn pending_ravs(pgpool: &PgPool) -> Result<Vec<PendingRav>> {
+    let rows = sqlx::query(
+        r#"
+            SELECT sender_address, allocation_id, value_aggregate, timestamp_ns
+            FROM scalar_tap_ravs
+            WHERE last AND NOT final
+            ORDER BY value_aggregate DESC
+        "#,
+    )
+    .fetch_all(pgpool)
+    .await?;
+    rows.iter()
+        .map(|row| {
+            Ok(PendingRav {
+                sender: row.try_get::<SenderAddress, _>("sender_address")?.0,
+                allocation_id: row.try_get::<AllocationIdHex, _>("allocation_id")?.0,
+                value_aggregate: row.try_get::<GrtWei, _>("value_aggregate")?.0,
+                timestamp_ns: row
+                    .try_get::<BigDecimal, _>("timestamp_ns")?
+                    .to_u64()
+                    .ok_or_else(|| anyhow!("Invalid RAV timestamp"))?,
+            })
+        })
+        .collect()
+}
+
+/// Current gas price of the network, in wei.
+pub async fn gas_price(
+    http_client: &reqwest::Client,
+    gas_oracle_url: &reqwest::Url,
+) -> Result<u128> {
+    let response = http_client
+        .post(gas_oracle_url.clone())
+        .timeout(Duration::from_secs(10))
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(
+            json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "method": "eth_gasPrice",
+                "params": [],
+            })
+            .to_string(),
+        )
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        bail!("The gas oracle responded with {}", response.status());
+    }
+    let body: serde_json::Value = serde_json::from_str(&response.text().await?)?;
+    let result = body
+        .get("result")
+        .and_then(|result| result.as_str())
+        .ok_or_else(|| anyhow!("Invalid response of the gas oracle: {body}"))?;
+    Ok(u128::from_str_radix(result.trim_start_matches("0x"), 16)?)
+}
+
+/// Advice on the pending last RAVs at the current gas price.
+pub async fn report(
+    pgpool: &PgPool,
+    config: &RedemptionAdvice,
+    http_client: &reqwest::Client,
+) -> Result<AdviceReport> {
+    let gas_price = gas_price(http_client, &config.gas_oracle_url).await?;
+    let ravs = pending_ravs(pgpool).await?;
+    Ok(advise(config, gas_price, &ravs, Utc::now()))
+}
+
+/// Logs `report` in the advice history.
+pub async fn log(pgpool: &PgPool, report: &AdviceReport) -> Result<()> {
+    let mut tx = pgpool.begin().await?;
+    for rav in &report.ravs {
+        sqlx::query(
+            r#"
+                INSERT INTO scalar_tap_redemption_advice (
+                    chain_id,
+                    sender_address,
+                    allocation_id,
+                    value_aggregate,
+                    gas_price,
+                    redemption_cost,
+                    action,
+                    reason,
+                    advised_at
+                )
+                VALUES ($1, $2, $3, $4::NUMERIC, $5::NUMERIC, $6::NUMERIC, $7, $8, $9)
+            "#,
+        )
+        .bind(report.chain_id as i64)
+        .bind(SenderAddress(rav.sender))
+        .bind(AllocationIdHex(rav.allocation_id))
+        .bind(&rav.value_aggregate)
+        .bind(&report.gas_price)
+        .bind(&rav.redemption_cost)
+        .bind(rav.action.as_str())
+        .bind(&rav.reason)
+        .bind(report.generated_at)
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryQuery {
+    pub since: Option<DateTime<Utc>>,
+    pub allocation_id: Option<Address>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LoggedAdvice {
+    pub chain_id: u64,
+    pub sender: Address,
+    pub allocation_id: Address,
+    pub value_aggregate: String,
+    pub gas_price: String,
+    pub redemption_cost: String,
+    pub action: String,
+    pub reason: String,
+    pub advised_at: DateTime<Utc>,
+}
+
+/// The advice logged since `since`, of `allocation_id` only if set, from the most recent one.
+pub async fn history(pgpool: &PgPool, query: &HistoryQuery) -> Result<Vec<LoggedAdvice>> {
+    let rows = sqlx::query(
+        r#"
+            SELECT
+                chain_id, sender_address, allocation_id, value_aggregate, gas_price,
+                redemption_cost, action, reason, advised_at
+            FROM scalar_tap_redemption_advice
+            WHERE ($1::TIMESTAMPTZ IS NULL OR advised_at >= $1)
+                AND ($2::CHAR(40) IS NULL OR allocation_id = $2)
+            ORDER BY advised_at DESC, id DESC
+            LIMIT $3
+        "#,
+    )
+    .bind(query.since)
+    .bind(query.allocation_id.map(AllocationIdHex))
+    .bind(HISTORY_PAGE_SIZE)
+    .fetch_all(pgpool)
+    .await?;
+    rows.iter()
+        .map(|row| {
+            Ok(LoggedAdvice {
+                chain_id: row.try_get::<i64, _>("chain_id")? as u64,
+                sender: row.try_get::<SenderAddress, _>("sender_address")?.0,
+                allocation_id: row.try_get::<AllocationIdHex, _>("allocation_id")?.0,
+                value_aggregate: row.try_get::<BigDecimal, _>("value_aggregate")?.to_string(),
+                gas_price: row.try_get::<BigDecimal, _>("gas_price")?.to_string(),
+                redemption_cost: row.try_get::<BigDecimal, _>("redemption_cost")?.to_string(),
+                action: row.try_get("action")?,
+                reason: row.try_get("reason")?,
+                advised_at: row.try_get("advised_at")?,
+            })
+        })
+        .collect()
+}
+
+/// Logs the advice on the pending last RAVs.
+pub struct LogRedemptionAdvice {
+    pub pgpool: PgPool,
+    pub config: RedemptionAdvice,
+    pub http_client: reqwest::Client,
+}
+
+#[async_trait::async_trait]
+impl Job for LogRedemptionAdvice {
+    fn name(&self) -> &'static str {
+        "log_redemption_advice"
+    }
+
+    fn default_schedule(&self) -> &'static str {
+        "*/30 * * * *"
+    }
+
+    async fn run(&self) -> Result<()> {
+        let report = report(&self.pgpool, &self.config, &self.http_client).await?;
+        if report.ravs.is_empty() {
+            return Ok(());
+        }
+        log(&self.pgpool, &report).await?;
+        let redeem_now = report
+            .ravs
+            .iter()
+            .filter(|rav| rav.action == Action::RedeemNow)
+            .count();
+        info!(
+            gas_price = report.gas_price,
+            redeem_now,
+            batch_later = report.ravs.len() - redeem_now,
+            redeem_now_value = report.redeem_now_value,
+            "Logged the redemption advice."
+        );
+        Ok(())
+    }
+}
+
+struct AdviceState {
+    pgpool: PgPool,
+    config: RedemptionAdvice,
+    http_client: reqwest::Client,
+}
+
+fn internal_error(e: anyhow::Error) -> (StatusCode, String) {
+    error!("Error while advising the redemptions: {}", e);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Error while advising the redemptions: {}", e),
+    )
+}
+
+async fn handler_advice(
+    State(state): State<Arc<AdviceState>>,
+) -> Result<Json<AdviceReport>, (StatusCode, String)> {
+    report(&state.pgpool, &state.config, &state.http_client)
+        .await
+        .map(Json)
+        .map_err(internal_error)
+}
+
+async fn handler_history(
+    State(state): State<Arc<AdviceState>>,
+    Query(query): Query<HistoryQuery>,
+) -> Result<Json<Vec<LoggedAdvice>>, (StatusCode, String)> {
+    history(&state.pgpool, &query)
+        .await
+        .map(Json)
+        .map_err(internal_error)
+}
+
+/// Routes serving the current advice and its history, to be mounted on the tap-agent HTTP
+/// server.
+pub fn router(pgpool: PgPool, config: RedemptionAdvice, http_client: reqwest::Client) -> Router {
+    Router::new()
+        .route("/state/redemption-advice", get(handler_advice))
+        .route("/state/redemption-advice/history", get(handler_history))
+        .with_state(Arc::new(AdviceState {
+            pgpool,
+            config,
+            http_client,
+        }))
+}
+
+#[cfg(test)]
+mod tests {
+    use wiremock::{
+        matchers::{body_string_contains, method},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::*;
+    use crate::tap::test_utils::{ALLOCATION_ID_0, ALLOCATION_ID_1, SENDER};
+
+    const GWEI: u128 = 1_000_000_000;
+    const GRT: u128 = 1_000_000_000_000_000_000;
+
+    fn config(gas_oracle_url: &str) -> RedemptionAdvice {
+        RedemptionAdvice {
+            chain_id: 42161,
+            gas_oracle_url: gas_oracle_url.parse().unwrap(),
+            redeem_gas: 200_000,
+            native_token_price_grt: BigDecimal::from(10_000),
+            min_value_to_cost_ratio: BigDecimal::from(10),
+            max_wait: Duration::from_secs(3600),
+        }
+    }
+
+    #[test]
+    fn test_advise() {
+        let now = Utc::now();
+        let now_ns = now.timestamp_nanos_opt().unwrap() as u64;
+        let rav = |allocation_id, value_aggregate, age: Duration| PendingRav {
+            sender: SENDER.1,
+            allocation_id,
+            value_aggregate,
+            timestamp_ns: now_ns - age.as_nanos() as u64,
+        };
+        let ravs = [
+            rav(*ALLOCATION_ID_0, 100 * GRT, Duration::from_secs(60)),
+            rav(*ALLOCATION_ID_1, 10 * GRT, Duration::from_secs(60)),
+            rav(Address::from([0x22; 20]), GRT, Duration::from_secs(7200)),
+        ];
+
+        // 10 gwei * 200k gas * 10k GRT = 20 GRT
+        let report = advise(&config("http://localhost"), 10 * GWEI, &ravs, now);
+        assert_eq!(report.redemption_cost, (20 * GRT).to_string());
+        assert_eq!(report.min_value, (200 * GRT).to_string());
+        let actions: Vec<_> = report.ravs.iter().map(|rav| rav.action).collect();
+        assert_eq!(
+            actions,
+            vec![Action::BatchLater, Action::BatchLater, Action::RedeemNow]
+        );
+        assert_eq!(report.ravs[1].reason, "Worth less than its redemption cost");
+        assert_eq!(report.redeem_now_value, GRT.to_string());
+
+        // Cheaper gas
+        let report = advise(&config("http://localhost"), GWEI, &ravs, now);
+        assert_eq!(report.ravs[0].action, Action::RedeemNow);
+        assert_eq!(report.ravs[1].action, Action::BatchLater);
+        assert_eq!(report.redeem_now_value, (101 * GRT).to_string());
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_report_and_history(pgpool: PgPool) {
+        let oracle = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(body_string_contains("eth_gasPrice"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "result": "0x989680",
+            })))
+            .mount(&oracle)
+            .await;
+        let config = config(&oracle.uri());
+
+        for (allocation_id, last) in [(*ALLOCATION_ID_0, true), (*ALLOCATION_ID_1, false)] {
+            sqlx::query(
+                r#"
+                    INSERT INTO scalar_tap_ravs (
+                        sender_address, signature, allocation_id, timestamp_ns,
+                        value_aggregate, last, final
+                    )
+                    VALUES ($1, $2, $3, $4, $5, $6, false)
+                "#,
+            )
+            .bind(SenderAddress(SENDER.1))
+            .bind(vec![0u8; 65])
+            .bind(AllocationIdHex(allocation_id))
+            .bind(BigDecimal::from(1))
+            .bind(GrtWei(GRT))
+            .bind(last)
+            .execute(&pgpool)
+            .await
+            .unwrap();
+        }
+
+        let report = report(&pgpool, &config, &reqwest::Client::new())
+            .await
+            .unwrap();
+        assert_eq!(report.gas_price, (GWEI / 100).to_string());
+        // Only the last RAVs can be redeemed
+        assert_eq!(report.ravs.len(), 1);
+        assert_eq!(report.ravs[0].allocation_id, *ALLOCATION_ID_0);
+        // Worth 50 times its redemption cost
+        assert_eq!(report.ravs[0].action, Action::RedeemNow);
+
+        log(&pgpool, &report).await.unwrap();
+        let logged = history(&pgpool, &HistoryQuery::default()).await.unwrap();
+        assert_eq!(logged.len(), 1);
+        assert_eq!(logged[0].action, "redeem_now");
+        assert_eq!(logged[0].value_aggregate, GRT.to_string());
+        assert!(history(
+            &pgpool,
+            &HistoryQuery {
+                since: None,
+                allocation_id: Some(*ALLOCATION_ID_1),
+            }
+        )
+        .await
+        .unwrap()
+        .is_empty());
+    }
+}
@@ -0,0 +1,442 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Runs periodic maintenance jobs on cron-like schedules.
+//!
+//! Jobs are registered in code with a default schedule, which can be overridden (or the job
+//! disabled) in the `[tap.scheduler.<job name>]` section of the configuration. A job never runs
+//! twice at the same time: if a run is still in progress when the next one is due, the next one
+//! is skipped.
+
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{BuildHasher, Hasher};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use indexer_common::events;
+use lazy_static::lazy_static;
+use serde::Serialize;
+use sqlx::PgPool;
+use tokio::task::JoinHandle;
+use tracing::{error, warn};
+
+use crate::config::ScheduledJob;
+
+lazy_static! {
+    /// Status of every registered job, served by the tap-agent HTTP server.
+    pub static ref JOB_STATUSES: RwLock<BTreeMap<String, JobStatus>> =
+        RwLock::new(BTreeMap::new());
+}
+
+#[async_trait::async_trait]
+pub trait Job: Send + Sync + 'static {
+    fn name(&self) -> &'static str;
+
+    /// Cron expression used when the job's schedule is not configured.
+    fn default_schedule(&self) -> &'static str;
+
+    async fn run(&self) -> Result<()>;
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct JobStatus {
+    pub enabled: bool,
+    pub schedule: String,
+    pub running: bool,
+    pub next_run_at: Option<String>,
+    pub last_started_at: Option<String>,
+    pub last_duration_ms: Option<u64>,
+    pub last_error: Option<String>,
+    pub runs: u64,
+    pub failures: u64,
+    pub skipped_overlapping: u64,
+}
+
+/// A cron expression with 5 fields: minute, hour, day of month, month and day of week. Each
+/// field supports `*`, values, ranges (`1-5`), steps (`*/15`, `0-30/10`) and lists (`1,15`).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CronSchedule {
+    minutes: u64,
+    hours: u64,
+    days_of_month: u64,
+    months: u64,
+    days_of_week: u64,
+    any_day_of_month: bool,
+    any_day_of_week: bool,
+}
+
+impl FromStr for CronSchedule {
+    type Err = anyhow::Error;
+
+    fn from_str(expression: &str) -> Result<Self> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        let [minutes, hours, days_of_month, months, days_of_week] = fields[..] else {
+            bail!("Cron expression `{expression}` must have 5 fields");
+        };
+
+        // Both 0 and 7 are sunday.
+        let mut days_of_week_bits = parse_field(days_of_week, 0, 7)?;
+        if days_of_week_bits & (1 << 7) != 0 {
+            days_of_week_bits = (days_of_week_bits | 1) & !(1 << 7);
+        }
+
+        Ok(Self {
+            minutes: parse_field(minutes, 0, 59)?,
+            hours: parse_field(hours, 0, 23)?,
+            days_of_month: parse_field(days_of_month, 1, 31)?,
+            months: parse_field(months, 1, 12)?,
+            days_of_week: days_of_week_bits,
+            any_day_of_month: days_of_month == "*",
+            any_day_of_week: days_of_week == "*",
+        })
+    }
+}
+
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64> {
+    let parse_value = |value: &str| {
+        value
+            .parse::<u32>()
+            .map_err(|_| anyhow!("Invalid value `{value}` in `{field}`"))
+    };
+
+    let mut bits = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, parse_value(step)?),
+            None => (part, 1),
+        };
+        if step == 0 {
+            bail!("Step can't be 0 in `{field}`");
+        }
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (parse_value(start)?, parse_value(end)?),
+            // `5/10` means from 5 to the end of the range, every 10.
+            None if step > 1 => (parse_value(range)?, max),
+            None => {
+                let value = parse_value(range)?;
+                (value, value)
+            }
+        };
+        if start < min || end > max || start > end {
+            bail!("`{part}` is out of the {min}-{max} range");
+        }
+        for value in (start..=end).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+impl CronSchedule {
+    pub fn matches(&self, time: &DateTime<Utc>) -> bool {
+        self.matches_day(time)
+            && (self.minutes & (1 << time.minute())) != 0
+            && (self.hours & (1 << time.hour())) != 0
+    }
+
+    fn matches_day(&self, time: &DateTime<Utc>) -> bool {
+        let day_of_month = (self.days_of_month & (1 << time.day())) != 0;
+        let day_of_week = (self.days_of_week & (1 << time.weekday().num_days_from_sunday())) != 0;
+        // Like cron, when both day fields are restricted, matching either of them is enough.
+        let day = if self.any_day_of_month || self.any_day_of_week {
+            day_of_month && day_of_week
+        } else {
+            day_of_month || day_of_week
+        };
+
+        day && (self.months & (1 << time.month())) != 0
+    }
+
+    /// First minute strictly after `time` matching the schedule, if any within 8 years.
+    pub fn next_after(&self, time: &DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let mut next = time.with_second(0)?.with_nanosecond(0)? + chrono::Duration::minutes(1);
+        // Leap days can be 8 years apart, e.g. 2096-02-29 and 2104-02-29.
+        let end = next + chrono::Duration::days(8 * 366);
+        while next < end {
+            if !self.matches_day(&next) {
+                next = next
+                    .date_naive()
+                    .succ_opt()?
+                    .and_hms_opt(0, 0, 0)?
+                    .and_utc();
+                continue;
+            }
+            if self.matches(&next) {
+                return Some(next);
+            }
+            next += chrono::Duration::minutes(1);
+        }
+        None
+    }
+}
+
+struct ScheduledJobEntry {
+    job: Arc<dyn Job>,
+    schedule: CronSchedule,
+    jitter: Duration,
+}
+
+pub struct Scheduler {
+    jobs: Vec<ScheduledJobEntry>,
+}
+
+impl Scheduler {
+    pub fn new(config: &HashMap<String, ScheduledJob>, jobs: Vec<Arc<dyn Job>>) -> Result<Self> {
+        for name in config.keys() {
+            if !jobs.iter().any(|job| job.name() == name) {
+                warn!(job = %name, "Unknown job in the scheduler configuration, ignoring it.");
+            }
+        }
+
+        let mut entries = Vec::new();
+        for job in jobs {
+            let job_config = config.get(job.name());
+            let enabled = job_config.map_or(true, |job_config| job_config.enabled);
+            let expression = job_config
+                .and_then(|job_config| job_config.schedule.clone())
+                .unwrap_or_else(|| job.default_schedule().to_string());
+            let schedule = CronSchedule::from_str(&expression)
+                .map_err(|e| anyhow!("Invalid schedule for job `{}`: {}", job.name(), e))?;
+
+            JOB_STATUSES.write().unwrap().insert(
+                job.name().to_string(),
+                JobStatus {
+                    enabled,
+                    schedule: expression,
+                    ..Default::default()
+                },
+            );
+            if enabled {
+                entries.push(ScheduledJobEntry {
+                    jitter: job_config.map_or(Duration::ZERO, |job_config| job_config.jitter),
+                    job,
+                    schedule,
+                });
+            }
+        }
+        Ok(Self { jobs: entries })
+    }
+
+    pub fn start(self) -> Vec<JoinHandle<()>> {
+        self.jobs
+            .into_iter()
+            .map(|entry| tokio::spawn(job_loop(entry)))
+            .collect()
+    }
+}
+
+async fn job_loop(
+    ScheduledJobEntry {
+        job,
+        schedule,
+        jitter,
+    }: ScheduledJobEntry,
+) {
+    let running = Arc::new(AtomicBool::new(false));
+    loop {
+        let now = Utc::now();
+        let Some(next_run) = schedule.next_after(&now) else {
+            warn!(
+                job = job.name(),
+                "Schedule never matches, the job will not run."
+            );
+            return;
+        };
+        update_status(job.name(), |status| {
+            status.next_run_at = Some(next_run.to_rfc3339())
+        });
+        tokio::time::sleep((next_run - now).to_std().unwrap_or_default() + random_jitter(jitter))
+            .await;
+
+        if running.swap(true, Ordering::SeqCst) {
+            warn!(
+                job = job.name(),
+                "Previous run of the job is still in progress, skipping this one."
+            );
+            update_status(job.name(), |status| status.skipped_overlapping += 1);
+            continue;
+        }
+
+        let job_run = job.clone();
+        let running_run = running.clone();
+        tokio::spawn(async move {
+            update_status(job_run.name(), |status| {
+                status.running = true;
+                status.last_started_at = Some(Utc::now().to_rfc3339());
+            });
+            let start = Instant::now();
+            let result = job_run.run().await;
+            update_status(job_run.name(), |status| {
+                status.running = false;
+                status.runs += 1;
+                status.last_duration_ms = Some(start.elapsed().as_millis() as u64);
+                status.last_error = result.as_ref().err().map(|e| e.to_string());
+                if result.is_err() {
+                    status.failures += 1;
+                }
+            });
+            if let Err(e) = result {
+                error!(job = job_run.name(), error = %e, "Scheduled job failed.");
+            }
+            running_run.store(false, Ordering::SeqCst);
+        });
+    }
+}
+
+fn update_status(job: &str, update: impl FnOnce(&mut JobStatus)) {
+    if let Some(status) = JOB_STATUSES.write().unwrap().get_mut(job) {
+        update(status);
+    }
+}
+
+fn random_jitter(max: Duration) -> Duration {
+    if max.is_zero() {
+        return Duration::ZERO;
+    }
+    let random = RandomState::new().build_hasher().finish();
+    max.mul_f64(random as f64 / u64::MAX as f64)
+}
+
+/// Keeps the planner statistics of the TAP tables up to date, as they grow and shrink quickly.
+pub struct AnalyzeTables {
+    pgpool: PgPool,
+}
+
+impl AnalyzeTables {
+    pub fn new(pgpool: PgPool) -> Self {
+        Self { pgpool }
+    }
+}
+
+#[async_trait::async_trait]
+impl Job for AnalyzeTables {
+    fn name(&self) -> &'static str {
+        "analyze_tables"
+    }
+
+    fn default_schedule(&self) -> &'static str {
+        "0 * * * *"
+    }
+
+    async fn run(&self) -> Result<()> {
+        sqlx::query("ANALYZE scalar_tap_receipts, scalar_tap_receipts_invalid, scalar_tap_ravs")
+            .execute(&self.pgpool)
+            .await?;
+        Ok(())
+    }
+}
+
+/// Keeps the `indexer_events` table from growing forever, see [indexer_common::events::prune].
+pub struct PruneEvents {
+    pgpool: PgPool,
+}
+
+impl PruneEvents {
+    pub fn new(pgpool: PgPool) -> Self {
+        Self { pgpool }
+    }
+}
+
+#[async_trait::async_trait]
+impl Job for PruneEvents {
+    fn name(&self) -> &'static str {
+        "prune_events"
+    }
+
+    fn default_schedule(&self) -> &'static str {
+        "17 4 * * *"
+    }
+
+    async fn run(&self) -> Result<()> {
+        events::prune(&self.pgpool, events::RETENTION).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use chrono::{DateTime, TimeZone, Utc};
+
+    use super::CronSchedule;
+
+    fn at(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(year, month, day, hour, minute, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_parse_cron_schedule() {
+        assert!(CronSchedule::from_str("* * * * *").is_ok());
+        assert!(CronSchedule::from_str("*/15 0-6,22 1 */2 1-5").is_ok());
+        assert!(CronSchedule::from_str("0 0 * * 7").is_ok());
+
+        assert!(CronSchedule::from_str("* * * *").is_err());
+        assert!(CronSchedule::from_str("60 * * * *").is_err());
+        assert!(CronSchedule::from_str("*/0 * * * *").is_err());
+        assert!(CronSchedule::from_str("5-1 * * * *").is_err());
+        assert!(CronSchedule::from_str("a * * * *").is_err());
+    }
+
+    #[test]
+    fn test_next_after() {
+        let hourly = CronSchedule::from_str("0 * * * *").unwrap();
+        assert_eq!(
+            hourly.next_after(&at(2024, 1, 1, 10, 0)),
+            Some(at(2024, 1, 1, 11, 0))
+        );
+        assert_eq!(
+            hourly.next_after(&at(2024, 1, 1, 23, 30)),
+            Some(at(2024, 1, 2, 0, 0))
+        );
+
+        let every_20_minutes = CronSchedule::from_str("*/20 * * * *").unwrap();
+        assert_eq!(
+            every_20_minutes.next_after(&at(2024, 1, 1, 10, 41)),
+            Some(at(2024, 1, 1, 11, 0))
+        );
+
+        // 2024-01-07 is a sunday
+        let sundays = CronSchedule::from_str("30 2 * * 7").unwrap();
+        assert_eq!(
+            sundays.next_after(&at(2024, 1, 1, 0, 0)),
+            Some(at(2024, 1, 7, 2, 30))
+        );
+
+        // either the 15th or mondays
+        let either = CronSchedule::from_str("0 0 15 * 1").unwrap();
+        assert_eq!(
+            either.next_after(&at(2024, 1, 9, 0, 0)),
+            Some(at(2024, 1, 15, 0, 0))
+        );
+        assert_eq!(
+            either.next_after(&at(2024, 1, 15, 0, 0)),
+            Some(at(2024, 1, 22, 0, 0))
+        );
+
+        let leap_day = CronSchedule::from_str("0 0 29 2 *").unwrap();
+        assert_eq!(
+            leap_day.next_after(&at(2023, 3, 1, 0, 0)),
+            Some(at(2024, 2, 29, 0, 0))
+        );
+        assert_eq!(
+            leap_day.next_after(&at(2024, 3, 1, 0, 0)),
+            Some(at(2028, 2, 29, 0, 0))
+        );
+        // 2100 is not a leap year
+        assert_eq!(
+            leap_day.next_after(&at(2097, 3, 1, 0, 0)),
+            Some(at(2104, 2, 29, 0, 0))
+        );
+
+        let never = CronSchedule::from_str("0 0 31 2 *").unwrap();
+        assert_eq!(never.next_after(&at(2024, 1, 1, 0, 0)), None);
+    }
+}
@@ -0,0 +1,498 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Monthly statements of each sender: the fees earned from its receipts once aggregated into
+//! RAVs, its receipts of the month not aggregated yet, the RAVs issued and redeemed, and the
+//! balance still outstanding at the end of the month. The statements are signed by the operator
+//! key with EIP-712, under the TAP domain, for the senders to verify them against the indexer's
+//! operator, e.g. for their own accounting and tax reporting. The statements of a month are
+//! archived once generated, generating them again returns the same statements.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use alloy_sol_types::{sol, Eip712Domain};
+use anyhow::{anyhow, bail, Result};
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    middleware,
+    routing::{get, post},
+    Json, Router,
+};
+use chrono::{DateTime, Datelike, Months, NaiveDate, Utc};
+use eventuals::Eventual;
+use indexer_common::{
+    address::build_wallet, escrow_accounts::EscrowAccounts, types::SenderAddress,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{types::BigDecimal, PgPool, Row};
+use tap_core::signed_message::EIP712SignedMessage;
+use thegraph::types::Address;
+use tracing::{error, info, warn};
+
+use crate::accounting::{build_statement, SenderSummary};
+use crate::admin_signature::{require_signature, AdminSigners};
+use crate::config::OperatorKey;
+use crate::escrow_overrides::AdminError;
+use crate::scheduler::Job;
+
+// The period is in Unix seconds, its end excluded, and the amounts are in GRT wei. The
+// outstanding balance is the value of the RAVs not redeemed at the end of the period, and of the
+// pending receipts.
+sol! {
+    #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+    struct SenderStatement {
+        address indexer;
+        address sender;
+        uint64 periodStart;
+        uint64 periodEnd;
+        uint128 feesEarned;
+        uint64 pendingReceipts;
+        uint128 pendingReceiptsValue;
+        uint64 ravsIssued;
+        uint64 ravsRedeemed;
+        uint128 valueRedeemed;
+        uint128 outstandingBalance;
+    }
+}
+
+pub type SignedSenderStatement = EIP712SignedMessage<SenderStatement>;
+
+/// Period of a month formatted as `YYYY-MM`, from its first day to the first day of the next.
+pub fn month_period(month: &str) -> Result<(DateTime<Utc>, DateTime<Utc>)> {
+    let start = NaiveDate::parse_from_str(&format!("{month}-01"), "%Y-%m-%d")
+        .map_err(|_| anyhow!("Invalid month `{month}`, expected `YYYY-MM`"))?;
+    let end = start
+        .checked_add_months(Months::new(1))
+        .ok_or_else(|| anyhow!("Invalid month `{month}`"))?;
+    Ok((
+        start.and_time(Default::default()).and_utc(),
+        end.and_time(Default::default()).and_utc(),
+    ))
+}
+
+/// The month before the one of `now`, formatted as `YYYY-MM`.
+fn previous_month(now: DateTime<Utc>) -> String {
+    let previous = now.date_naive() - Months::new(1);
+    format!("{:04}-{:02}", previous.year(), previous.month())
+}
+
+fn wei(amount: &str) -> Result<u128> {
+    amount
+        .parse()
+        .map_err(|_| anyhow!("Amount `{amount}` isn't a GRT wei value"))
+}
+
+/// Verifies that `statement` was signed by `operator`.
+pub fn verify(
+    statement: &SignedSenderStatement,
+    domain: &Eip712Domain,
+    operator: Address,
+) -> Result<()> {
+    let signer = statement.recover_signer(domain)?;
+    if signer != operator {
+        bail!("Statement signed by {signer}, not by the operator {operator}");
+    }
+    Ok(())
+}
+
+#[derive(Clone)]
+pub struct SenderStatements {
+    pub pgpool: PgPool,
+    pub indexer_address: Address,
+    pub operator_key: OperatorKey,
+    pub domain_separator: Eip712Domain,
+    pub escrow_accounts: Eventual<EscrowAccounts>,
+}
+
+#[async_trait::async_trait]
+impl Job for SenderStatements {
+    fn name(&self) -> &'static str {
+        "sender_statements"
+    }
+
+    /// The statements of the previous month, early on the first day of the month.
+    fn default_schedule(&self) -> &'static str {
+        "0 1 1 * *"
+    }
+
+    async fn run(&self) -> Result<()> {
+        self.generate(&previous_month(Utc::now())).await?;
+        Ok(())
+    }
+}
+
+impl SenderStatements {
+    /// Generates and archives the statements of `month` formatted as `YYYY-MM`, or returns the
+    /// archived ones if they were already generated.
+    pub async fn generate(&self, month: &str) -> Result<Vec<SignedSenderStatement>> {
+        let (from, to) = month_period(month)?;
+        // RAVs can still be received for a month that isn't over
+        if to > Utc::now() {
+            bail!("The month {month} isn't over");
+        }
+
+        let mut tx = self.pgpool.begin().await?;
+        sqlx::query("LOCK TABLE sender_statements IN EXCLUSIVE MODE")
+            .execute(&mut *tx)
+            .await?;
+        let archived = sqlx::query(
+            r#"
+                SELECT statement
+                FROM sender_statements
+                WHERE period_start = $1
+                ORDER BY sender_address
+            "#,
+        )
+        .bind(from)
+        .fetch_all(&mut *tx)
+        .await?;
+        if !archived.is_empty() {
+            return archived
+                .iter()
+                .map(|row| Ok(serde_json::from_value(row.try_get("statement")?)?))
+                .collect();
+        }
+
+        let escrow_accounts = self
+            .escrow_accounts
+            .value_immediate()
+            .ok_or_else(|| anyhow!("Escrow accounts are not available yet"))?;
+        let mut pending = BTreeMap::<Address, (u64, u128)>::new();
+        for (signer, count, value) in pending_receipts(&mut tx, from, to).await? {
+            match escrow_accounts.get_sender_for_signer(&signer) {
+                Ok(sender) => {
+                    let totals = pending.entry(sender).or_default();
+                    totals.0 += count;
+                    totals.1 += value;
+                }
+                Err(e) => warn!(%signer, error = %e, "Leaving receipts out of the statements"),
+            }
+        }
+
+        let mut summaries: HashMap<Address, SenderSummary> = build_statement(&mut tx, from, to)
+            .await?
+            .senders
+            .into_iter()
+            .map(|summary| (summary.sender, summary))
+            .collect();
+        let mut senders: Vec<Address> = summaries.keys().chain(pending.keys()).copied().collect();
+        senders.sort();
+        senders.dedup();
+
+        let wallet = self.operator_key.0.expose(build_wallet)?;
+        let mut statements = Vec::new();
+        for sender in senders {
+            let (pending_receipts, pending_value) = pending.remove(&sender).unwrap_or_default();
+            let (fees_earned, ravs_issued, ravs_redeemed, value_redeemed, outstanding) =
+                match summaries.remove(&sender) {
+                    Some(summary) => (
+                        wei(&summary.fees_earned)?,
+                        summary.ravs_issued,
+                        summary.ravs_redeemed,
+                        wei(&summary.value_redeemed)?,
+                        wei(&summary.outstanding_receivables)?,
+                    ),
+                    None => (0, 0, 0, 0, 0),
+                };
+            let statement = SenderStatement {
+                indexer: self.indexer_address,
+                sender,
+                periodStart: from.timestamp() as u64,
+                periodEnd: to.timestamp() as u64,
+                feesEarned: fees_earned,
+                pendingReceipts: pending_receipts,
+                pendingReceiptsValue: pending_value,
+                ravsIssued: ravs_issued,
+                ravsRedeemed: ravs_redeemed,
+                valueRedeemed: value_redeemed,
+                outstandingBalance: outstanding + pending_value,
+            };
+            let statement = EIP712SignedMessage::new(&self.domain_separator, statement, &wallet)?;
+            sqlx::query(
+                r#"
+                    INSERT INTO sender_statements (
+                        sender_address, period_start, period_end, statement
+                    )
+                    VALUES ($1, $2, $3, $4)
+                "#,
+            )
+            .bind(SenderAddress(sender))
+            .bind(from)
+            .bind(to)
+            .bind(serde_json::to_value(&statement)?)
+            .execute(&mut *tx)
+            .await?;
+            statements.push(statement);
+        }
+        tx.commit().await?;
+
+        info!(
+            month,
+            senders = statements.len(),
+            "Generated the sender statements"
+        );
+        Ok(statements)
+    }
+}
+
+/// Receipts of the period not aggregated yet, with their count and value, by signer.
+async fn pending_receipts(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<Vec<(Address, u64, u128)>> {
+    let to_ns = |at: DateTime<Utc>| BigDecimal::from(at.timestamp_nanos_opt().unwrap_or(i64::MAX));
+    let rows = sqlx::query(
+        r#"
+            SELECT signer_address, COUNT(*) AS receipts, SUM(value) AS value
+            FROM scalar_tap_receipts
+            WHERE timestamp_ns >= $1 AND timestamp_ns < $2
+            GROUP BY signer_address
+        "#,
+    )
+    .bind(to_ns(from))
+    .bind(to_ns(to))
+    .fetch_all(&mut **tx)
+    .await?;
+    rows.iter()
+        .map(|row| {
+            Ok((
+                row.try_get::<SenderAddress, _>("signer_address")?.0,
+                row.try_get::<i64, _>("receipts")? as u64,
+                wei(&row.try_get::<BigDecimal, _>("value")?.to_string())?,
+            ))
+        })
+        .collect()
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ArchivedStatement {
+    pub sender: Address,
+    pub period_start: DateTime<Utc>,
+    pub period_end: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+    pub statement: SignedSenderStatement,
+}
+
+/// The archived statements of `sender`, or of all the senders, from the most recent month.
+pub async fn archived_statements(
+    pgpool: &PgPool,
+    sender: Option<Address>,
+) -> Result<Vec<ArchivedStatement>> {
+    let rows = sqlx::query(
+        r#"
+            SELECT sender_address, period_start, period_end, created_at, statement
+            FROM sender_statements
+            WHERE $1::CHAR(40) IS NULL OR sender_address = $1
+            ORDER BY period_start DESC, sender_address
+        "#,
+    )
+    .bind(sender.map(SenderAddress))
+    .fetch_all(pgpool)
+    .await?;
+    rows.iter()
+        .map(|row| {
+            Ok(ArchivedStatement {
+                sender: row.try_get::<SenderAddress, _>("sender_address")?.0,
+                period_start: row.try_get("period_start")?,
+                period_end: row.try_get("period_end")?,
+                created_at: row.try_get("created_at")?,
+                statement: serde_json::from_value(row.try_get("statement")?)?,
+            })
+        })
+        .collect()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StatementsParams {
+    pub sender: Option<Address>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GenerateRequest {
+    /// `YYYY-MM`
+    pub month: String,
+}
+
+struct StatementsState {
+    statements: SenderStatements,
+}
+
+fn internal_error(e: impl std::fmt::Display) -> AdminError {
+    error!("Error while serving the sender statements: {}", e);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Error while serving the sender statements: {}", e),
+    )
+}
+
+async fn handler_statements(
+    State(state): State<Arc<StatementsState>>,
+    Query(params): Query<StatementsParams>,
+) -> Result<Json<Vec<ArchivedStatement>>, AdminError> {
+    archived_statements(&state.statements.pgpool, params.sender)
+        .await
+        .map(Json)
+        .map_err(internal_error)
+}
+
+/// The signed statement of a sender for a month, to hand over to the sender.
+async fn handler_statement(
+    State(state): State<Arc<StatementsState>>,
+    Path((sender, month)): Path<(Address, String)>,
+) -> Result<Json<SignedSenderStatement>, AdminError> {
+    let (from, _) = month_period(&month).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    let row = sqlx::query(
+        r#"
+            SELECT statement
+            FROM sender_statements
+            WHERE sender_address = $1 AND period_start = $2
+        "#,
+    )
+    .bind(SenderAddress(sender))
+    .bind(from)
+    .fetch_optional(&state.statements.pgpool)
+    .await
+    .map_err(internal_error)?
+    .ok_or_else(|| {
+        (
+            StatusCode::NOT_FOUND,
+            format!("No statement of {sender} for {month}"),
+        )
+    })?;
+    let statement = row.try_get("statement").map_err(internal_error)?;
+    serde_json::from_value(statement)
+        .map(Json)
+        .map_err(internal_error)
+}
+
+async fn handler_generate(
+    State(state): State<Arc<StatementsState>>,
+    Json(request): Json<GenerateRequest>,
+) -> Result<Json<Vec<SignedSenderStatement>>, AdminError> {
+    month_period(&request.month).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    state
+        .statements
+        .generate(&request.month)
+        .await
+        .map(Json)
+        .map_err(internal_error)
+}
+
+/// Admin routes serving the sender statements, to be mounted on the tap-agent HTTP server.
+/// Generating the statements requires a signature from one of `admin_signers` if set.
+pub fn router(statements: SenderStatements, admin_signers: Arc<AdminSigners>) -> Router {
+    Router::new()
+        .route("/admin/sender-statements/generate", post(handler_generate))
+        .route_layer(middleware::from_fn_with_state(
+            admin_signers,
+            require_signature,
+        ))
+        .route("/admin/sender-statements", get(handler_statements))
+        .route(
+            "/admin/sender-statements/:sender/:month",
+            get(handler_statement),
+        )
+        .with_state(Arc::new(StatementsState { statements }))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+    use indexer_common::address::wallet_address;
+    use indexer_common::secrets::SealedSecret;
+
+    use super::*;
+    use crate::tap::test_utils::{
+        create_rav, create_received_receipt, store_rav, store_receipt, ALLOCATION_ID_0, INDEXER,
+        SENDER, SIGNER, TAP_EIP712_DOMAIN_SEPARATOR,
+    };
+
+    const OPERATOR_MNEMONIC: &str =
+        "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+
+    #[test]
+    fn test_month_period() {
+        let (from, to) = month_period("2024-12").unwrap();
+        assert_eq!(from.to_rfc3339(), "2024-12-01T00:00:00+00:00");
+        assert_eq!(to.to_rfc3339(), "2025-01-01T00:00:00+00:00");
+        assert!(month_period("2024-13").is_err());
+        assert_eq!(
+            previous_month("2024-01-15T00:00:00Z".parse().unwrap()),
+            "2023-12"
+        );
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_generate(pgpool: PgPool) {
+        let month_start = (Utc::now() - Months::new(1)).date_naive();
+        let month = format!("{:04}-{:02}", month_start.year(), month_start.month());
+        let (from, _) = month_period(&month).unwrap();
+
+        store_rav(
+            &pgpool,
+            create_rav(*ALLOCATION_ID_0, SIGNER.0.clone(), 1, 100),
+            SENDER.1,
+        )
+        .await
+        .unwrap();
+        sqlx::query("UPDATE scalar_tap_rav_history SET recorded_at = $1")
+            .bind(from + Duration::days(2))
+            .execute(&pgpool)
+            .await
+            .unwrap();
+        let timestamp_ns = (from + Duration::days(3)).timestamp_nanos_opt().unwrap() as u64;
+        store_receipt(
+            &pgpool,
+            create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, 1, timestamp_ns, 30)
+                .signed_receipt(),
+        )
+        .await
+        .unwrap();
+
+        let statements = SenderStatements {
+            pgpool: pgpool.clone(),
+            indexer_address: INDEXER.1,
+            operator_key: OperatorKey(SealedSecret::new(OPERATOR_MNEMONIC.to_string())),
+            domain_separator: TAP_EIP712_DOMAIN_SEPARATOR.clone(),
+            escrow_accounts: Eventual::from_value(EscrowAccounts::new(
+                HashMap::from([(SENDER.1, 1000.into())]),
+                HashMap::from([(SENDER.1, vec![SIGNER.1])]),
+            )),
+        };
+        let generated = statements.generate(&month).await.unwrap();
+        assert_eq!(generated.len(), 1);
+        let statement = &generated[0].message;
+        assert_eq!(statement.sender, SENDER.1);
+        assert_eq!(statement.feesEarned, 100);
+        assert_eq!(statement.pendingReceipts, 1);
+        assert_eq!(statement.pendingReceiptsValue, 30);
+        assert_eq!(statement.ravsIssued, 1);
+        assert_eq!(statement.outstandingBalance, 130);
+
+        let operator = wallet_address(OPERATOR_MNEMONIC).unwrap();
+        assert!(verify(&generated[0], &TAP_EIP712_DOMAIN_SEPARATOR, operator).is_ok());
+        assert!(verify(&generated[0], &TAP_EIP712_DOMAIN_SEPARATOR, SENDER.1).is_err());
+
+        // Archived, later changes don't alter the statements
+        sqlx::query("DELETE FROM scalar_tap_receipts")
+            .execute(&pgpool)
+            .await
+            .unwrap();
+        assert_eq!(statements.generate(&month).await.unwrap(), generated);
+        assert_eq!(
+            archived_statements(&pgpool, Some(SENDER.1)).await.unwrap()[0].statement,
+            generated[0]
+        );
+
+        // The current month isn't over
+        let now = Utc::now().date_naive();
+        assert!(statements
+            .generate(&format!("{:04}-{:02}", now.year(), now.month()))
+            .await
+            .is_err());
+    }
+}
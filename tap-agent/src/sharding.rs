@@ -0,0 +1,353 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Static sharding of the senders between several tap-agent instances, for the indexers with
+//! more senders than a single instance keeps up with. Each sender belongs to the shard its
+//! address hashes into, see [shard_of], and each instance only manages the senders of its own
+//! shard: their sender accounts, their receipt notifications and the catch up on their receipts.
+//!
+//! An instance leases its shard in the database before starting, and keeps renewing the lease
+//! while it runs. Another instance claiming the same shard, or configured with another shard
+//! count, fails to start until the lease expires. An instance that can no longer renew its lease
+//! exits, rather than risking the senders of its shard being managed twice.
+
+use std::sync::OnceLock;
+
+use alloy_primitives::{hex, keccak256};
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use prometheus::{register_counter, register_gauge, Counter, Gauge};
+use sqlx::{PgPool, Row};
+use thegraph::types::Address;
+use thiserror::Error;
+use tokio::time::Instant;
+use tracing::{error, info, warn};
+
+use crate::config::Sharding;
+
+lazy_static! {
+    static ref SHARD_LEASE_HELD: Gauge = register_gauge!(
+        format!("tap_shard_lease_held"),
+        "Whether this tap-agent instance holds the lease of its shard"
+    )
+    .unwrap();
+}
+
+lazy_static! {
+    static ref SHARD_LEASE_RENEWAL_FAILURES: Counter = register_counter!(
+        format!("tap_shard_lease_renewal_failures"),
+        "Failed renewals of the lease of the shard since the start of the program"
+    )
+    .unwrap();
+}
+
+/// Lease of this instance, once acquired by [start].
+static LEASE: OnceLock<ShardLease> = OnceLock::new();
+
+/// Shard of `sender`, out of `count` shards.
+pub fn shard_of(sender: &Address, count: u32) -> u32 {
+    let hash = keccak256(sender);
+    let prefix = u64::from_be_bytes(hash[..8].try_into().unwrap());
+    (prefix % u64::from(count.max(1))) as u32
+}
+
+#[derive(Debug, Error)]
+pub enum LeaseError {
+    #[error(
+        "Shard {shard_index} of {shard_count} is leased by `{holder}` until {expires_at}, \
+        conflicting with shard {expected}"
+    )]
+    Conflict {
+        holder: String,
+        shard_index: u32,
+        shard_count: u32,
+        expires_at: DateTime<Utc>,
+        expected: String,
+    },
+    #[error(transparent)]
+    Database(#[from] sqlx::Error),
+}
+
+/// Identifies this instance in the leases. The host name tells the operators which instance
+/// holds a shard, the process ID and start time tell the restarts of an instance apart.
+pub fn holder_id() -> String {
+    let host = std::env::var("HOSTNAME")
+        .ok()
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "tap-agent".to_string());
+    let started_at = Utc::now().timestamp_millis();
+    format!("{host}/{}/{started_at}", std::process::id())
+}
+
+/// The lease of the shard of an instance, for the senders of `indexer`.
+pub struct ShardLease {
+    pgpool: PgPool,
+    indexer: Address,
+    sharding: Sharding,
+    holder: String,
+}
+
+impl ShardLease {
+    pub fn new(pgpool: PgPool, indexer: Address, sharding: Sharding, holder: String) -> Self {
+        Self {
+            pgpool,
+            indexer,
+            sharding,
+            holder,
+        }
+    }
+
+    /// Acquires or renews the lease, for another `lease_duration`. Fails while another holder
+    /// leases the same shard, or any shard with another shard count.
+    pub async fn acquire(&self) -> Result<(), LeaseError> {
+        let indexer = hex::encode(self.indexer);
+        let mut tx = self.pgpool.begin().await?;
+        // Serializes the claims, so that two instances can't both see the shard available
+        sqlx::query("SELECT pg_advisory_xact_lock(hashtext('scalar_tap_agent_shard_leases'))")
+            .execute(&mut *tx)
+            .await?;
+        let conflict = sqlx::query(
+            r#"
+                SELECT holder, shard_index, shard_count, expires_at
+                FROM scalar_tap_agent_shard_leases
+                WHERE indexer_address = $1
+                    AND holder <> $2
+                    AND expires_at > NOW()
+                    AND (shard_index = $3 OR shard_count <> $4)
+                ORDER BY shard_index
+                LIMIT 1
+            "#,
+        )
+        .bind(&indexer)
+        .bind(&self.holder)
+        .bind(self.sharding.index as i32)
+        .bind(self.sharding.count as i32)
+        .fetch_optional(&mut *tx)
+        .await?;
+        if let Some(row) = conflict {
+            return Err(LeaseError::Conflict {
+                holder: row.try_get("holder")?,
+                shard_index: row.try_get::<i32, _>("shard_index")? as u32,
+                shard_count: row.try_get::<i32, _>("shard_count")? as u32,
+                expires_at: row.try_get("expires_at")?,
+                expected: self.sharding.key(),
+            });
+        }
+        sqlx::query(
+            r#"
+                INSERT INTO scalar_tap_agent_shard_leases (
+                    indexer_address, shard_index, shard_count, holder, expires_at
+                )
+                VALUES ($1, $2, $3, $4, NOW() + make_interval(secs => $5))
+                ON CONFLICT (indexer_address, shard_index) DO UPDATE
+                SET shard_count = EXCLUDED.shard_count,
+                    holder = EXCLUDED.holder,
+                    acquired_at = CASE
+                        WHEN scalar_tap_agent_shard_leases.holder = EXCLUDED.holder
+                        THEN scalar_tap_agent_shard_leases.acquired_at
+                        ELSE NOW()
+                    END,
+                    expires_at = EXCLUDED.expires_at
+            "#,
+        )
+        .bind(&indexer)
+        .bind(self.sharding.index as i32)
+        .bind(self.sharding.count as i32)
+        .bind(&self.holder)
+        .bind(self.sharding.lease_duration.as_secs_f64())
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Gives the shard up for another instance to claim right away.
+    pub async fn release(&self) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+                DELETE FROM scalar_tap_agent_shard_leases
+                WHERE indexer_address = $1 AND shard_index = $2 AND holder = $3
+            "#,
+        )
+        .bind(hex::encode(self.indexer))
+        .bind(self.sharding.index as i32)
+        .bind(&self.holder)
+        .execute(&self.pgpool)
+        .await?;
+        Ok(())
+    }
+
+    /// Renews the lease three times per `lease_duration`, riding out the database errors as
+    /// long as the lease lasts. Exits the process once the lease is lost.
+    async fn keep_renewed(&self) {
+        let lease_duration = self.sharding.lease_duration;
+        let mut interval = tokio::time::interval(lease_duration / 3);
+        // Acquired right before
+        interval.tick().await;
+        let mut renewed_at = Instant::now();
+        loop {
+            interval.tick().await;
+            match self.acquire().await {
+                Ok(()) => renewed_at = Instant::now(),
+                Err(LeaseError::Database(e)) if renewed_at.elapsed() < lease_duration => {
+                    SHARD_LEASE_RENEWAL_FAILURES.inc();
+                    warn!(
+                        shard = %self.sharding.key(),
+                        error = %e,
+                        "Failed to renew the lease of the shard, retrying."
+                    );
+                }
+                Err(e) => {
+                    SHARD_LEASE_RENEWAL_FAILURES.inc();
+                    SHARD_LEASE_HELD.set(0.0);
+                    error!(
+                        shard = %self.sharding.key(),
+                        error = %e,
+                        "Lost the lease of the shard, exiting so that its senders aren't \
+                        managed by two instances."
+                    );
+                    std::process::exit(indexer_config::EXIT_CODE_RUNTIME_ERROR.into());
+                }
+            }
+        }
+    }
+}
+
+/// Acquires the lease of the shard of this instance, and keeps it renewed until [release].
+pub async fn start(pgpool: PgPool, indexer: Address, sharding: Sharding) -> Result<(), LeaseError> {
+    let lease = ShardLease::new(pgpool, indexer, sharding, holder_id());
+    lease.acquire().await?;
+    SHARD_LEASE_HELD.set(1.0);
+    info!(
+        shard = %sharding.key(),
+        holder = %lease.holder,
+        lease_duration = ?sharding.lease_duration,
+        "Acquired the lease of the shard."
+    );
+    let lease = LEASE.get_or_init(|| lease);
+    tokio::spawn(lease.keep_renewed());
+    Ok(())
+}
+
+/// Releases the lease of this instance on shutdown, if any.
+pub async fn release() {
+    let Some(lease) = LEASE.get() else {
+        return;
+    };
+    match lease.release().await {
+        Ok(()) => {
+            SHARD_LEASE_HELD.set(0.0);
+            info!(
+                shard = %lease.sharding.key(),
+                "Released the lease of the shard."
+            );
+        }
+        Err(e) => warn!(
+            shard = %lease.sharding.key(),
+            error = %e,
+            "Failed to release the lease of the shard, it expires in {:?}.",
+            lease.sharding.lease_duration
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::tap::test_utils::INDEXER;
+
+    /// Holder of the unexpired lease of the shard, if any.
+    async fn holder(pgpool: &PgPool, indexer: Address, shard_index: u32) -> Option<String> {
+        sqlx::query_scalar(
+            r#"
+                SELECT holder FROM scalar_tap_agent_shard_leases
+                WHERE indexer_address = $1 AND shard_index = $2 AND expires_at > NOW()
+            "#,
+        )
+        .bind(hex::encode(indexer))
+        .bind(shard_index as i32)
+        .fetch_optional(pgpool)
+        .await
+        .unwrap()
+    }
+
+    fn sharding(index: u32, count: u32) -> Sharding {
+        Sharding {
+            count,
+            index,
+            lease_duration: Duration::from_secs(30),
+        }
+    }
+
+    #[test]
+    fn test_shard_of() {
+        let senders: Vec<Address> = (0..=255u8).map(|i| Address::from([i; 20])).collect();
+        let mut counts = [0; 4];
+        for sender in &senders {
+            let shard = shard_of(sender, 4);
+            assert_eq!(shard_of(sender, 4), shard);
+            assert_eq!(shard_of(sender, 1), 0);
+            counts[shard as usize] += 1;
+        }
+        assert!(counts.iter().all(|count| *count > 32), "{counts:?}");
+
+        let owned = |index| {
+            senders
+                .iter()
+                .filter(|s| sharding(index, 4).owns(s))
+                .count()
+        };
+        assert_eq!((0..4).map(owned).sum::<usize>(), senders.len());
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_lease(pgpool: PgPool) {
+        let lease = |index, count, holder: &str| {
+            ShardLease::new(
+                pgpool.clone(),
+                INDEXER.1,
+                sharding(index, count),
+                holder.to_string(),
+            )
+        };
+
+        lease(0, 2, "a").acquire().await.unwrap();
+        lease(1, 2, "b").acquire().await.unwrap();
+        // Renewed by its holder only
+        lease(0, 2, "a").acquire().await.unwrap();
+        assert!(matches!(
+            lease(0, 2, "c").acquire().await,
+            Err(LeaseError::Conflict { shard_index: 0, .. })
+        ));
+        // Another shard count overlaps the shards of the others
+        assert!(matches!(
+            lease(2, 3, "c").acquire().await,
+            Err(LeaseError::Conflict { shard_count: 2, .. })
+        ));
+        // Leases of other indexers are independent
+        ShardLease::new(
+            pgpool.clone(),
+            Address::from([0x42; 20]),
+            sharding(0, 3),
+            "c".to_string(),
+        )
+        .acquire()
+        .await
+        .unwrap();
+
+        lease(0, 2, "a").release().await.unwrap();
+        assert_eq!(holder(&pgpool, INDEXER.1, 0).await, None);
+        lease(0, 2, "c").acquire().await.unwrap();
+        assert_eq!(holder(&pgpool, INDEXER.1, 0).await, Some("c".to_string()));
+
+        // Expired leases can be claimed
+        sqlx::query("UPDATE scalar_tap_agent_shard_leases SET expires_at = NOW()")
+            .execute(&pgpool)
+            .await
+            .unwrap();
+        lease(1, 4, "d").acquire().await.unwrap();
+        assert_eq!(holder(&pgpool, INDEXER.1, 1).await, Some("d".to_string()));
+    }
+}
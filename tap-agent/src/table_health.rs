@@ -0,0 +1,381 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Health of the TAP tables: their size, the ratio of dead tuples, how long ago they were last
+//! vacuumed and the size of their indexes. Measured periodically and exported as metrics, with
+//! a warning when a table or index crosses a threshold, and served on the admin API along with
+//! the maintenance suggested to the operator.
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use axum::{extract::State, routing::get, Json, Router};
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use prometheus::{register_gauge_vec, register_int_gauge_vec, GaugeVec, IntGaugeVec};
+use serde::Serialize;
+use sqlx::{FromRow, PgPool};
+use tracing::{error, warn};
+
+use crate::escrow_overrides::AdminError;
+use crate::scheduler::Job;
+
+/// Above this ratio of dead tuples, a table should be vacuumed.
+pub const DEAD_TUPLE_RATIO_THRESHOLD: f64 = 0.2;
+/// Tables with dead tuples that weren't vacuumed for this long are likely missed by autovacuum.
+pub const VACUUM_AGE_THRESHOLD_SECS: i64 = 24 * 60 * 60;
+/// The TAP indexes are on narrow columns, an index larger than the data of its table is bloated.
+pub const INDEX_TO_TABLE_SIZE_THRESHOLD: f64 = 1.0;
+/// Below this size, bloat isn't worth the maintenance.
+const MIN_SIZE_BYTES: i64 = 64 * 1024 * 1024;
+
+lazy_static! {
+    static ref TABLE_SIZE: IntGaugeVec = register_int_gauge_vec!(
+        format!("tap_table_size_bytes"),
+        "Size of the data of the TAP tables, without their indexes",
+        &["table"]
+    )
+    .unwrap();
+    static ref TABLE_DEAD_TUPLE_RATIO: GaugeVec = register_gauge_vec!(
+        format!("tap_table_dead_tuple_ratio"),
+        "Ratio of dead tuples in the TAP tables",
+        &["table"]
+    )
+    .unwrap();
+    static ref TABLE_LAST_VACUUM_AGE: IntGaugeVec = register_int_gauge_vec!(
+        format!("tap_table_last_vacuum_age_seconds"),
+        "Time since the TAP tables were last vacuumed, manually or by autovacuum",
+        &["table"]
+    )
+    .unwrap();
+    static ref INDEX_SIZE: IntGaugeVec = register_int_gauge_vec!(
+        format!("tap_index_size_bytes"),
+        "Size of the indexes of the TAP tables",
+        &["table", "index"]
+    )
+    .unwrap();
+    static ref TABLE_HEALTH_ALERT: IntGaugeVec = register_int_gauge_vec!(
+        format!("tap_table_health_alert"),
+        "1 if a TAP table or one of its indexes crossed a health threshold",
+        &["table"]
+    )
+    .unwrap();
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct TableHealth {
+    pub table: String,
+    pub size_bytes: i64,
+    pub live_tuples: i64,
+    pub dead_tuples: i64,
+    /// Latest of the last manual vacuum and autovacuum, `None` if never vacuumed.
+    pub last_vacuum: Option<DateTime<Utc>>,
+    #[sqlx(skip)]
+    pub indexes: Vec<IndexHealth>,
+}
+
+impl TableHealth {
+    pub fn dead_tuple_ratio(&self) -> f64 {
+        let total = self.live_tuples + self.dead_tuples;
+        if total == 0 {
+            return 0.0;
+        }
+        self.dead_tuples as f64 / total as f64
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct IndexHealth {
+    #[serde(skip)]
+    pub table: String,
+    pub index: String,
+    pub size_bytes: i64,
+    pub scans: i64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Suggestion {
+    pub table: String,
+    pub reason: String,
+    /// Statement to run, outside of a transaction.
+    pub statement: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HealthReport {
+    pub tables: Vec<TableHealth>,
+    pub suggestions: Vec<Suggestion>,
+}
+
+/// Measures the health of the TAP tables and their indexes.
+pub async fn measure(pgpool: &PgPool) -> Result<Vec<TableHealth>, sqlx::Error> {
+    let mut tables: Vec<TableHealth> = sqlx::query_as(
+        r#"
+            SELECT
+                relname::TEXT AS "table",
+                pg_table_size(relid) AS size_bytes,
+                n_live_tup AS live_tuples,
+                n_dead_tup AS dead_tuples,
+                GREATEST(last_vacuum, last_autovacuum) AS last_vacuum
+            FROM pg_stat_user_tables
+            WHERE relname LIKE 'scalar\_tap\_%'
+            ORDER BY relname
+        "#,
+    )
+    .fetch_all(pgpool)
+    .await?;
+    let indexes: Vec<IndexHealth> = sqlx::query_as(
+        r#"
+            SELECT
+                relname::TEXT AS "table",
+                indexrelname::TEXT AS "index",
+                pg_relation_size(indexrelid) AS size_bytes,
+                idx_scan AS scans
+            FROM pg_stat_user_indexes
+            WHERE relname LIKE 'scalar\_tap\_%'
+            ORDER BY indexrelname
+        "#,
+    )
+    .fetch_all(pgpool)
+    .await?;
+    for index in indexes {
+        if let Some(table) = tables.iter_mut().find(|table| table.table == index.table) {
+            table.indexes.push(index);
+        }
+    }
+    Ok(tables)
+}
+
+/// Maintenance suggested for the tables crossing a health threshold.
+pub fn suggestions(tables: &[TableHealth], now: DateTime<Utc>) -> Vec<Suggestion> {
+    let mut suggestions = Vec::new();
+    for table in tables {
+        let ratio = table.dead_tuple_ratio();
+        let vacuum_age = table
+            .last_vacuum
+            .map(|last_vacuum| (now - last_vacuum).num_seconds());
+        if ratio > DEAD_TUPLE_RATIO_THRESHOLD {
+            suggestions.push(Suggestion {
+                table: table.table.clone(),
+                reason: format!("{:.0}% of the tuples are dead", ratio * 100.0),
+                statement: format!("VACUUM (ANALYZE) {}", table.table),
+            });
+        } else if table.dead_tuples > 0
+            && vacuum_age.map_or(true, |age| age > VACUUM_AGE_THRESHOLD_SECS)
+        {
+            let reason = match vacuum_age {
+                Some(age) => format!("Not vacuumed for {} hours", age / 3600),
+                None => "Never vacuumed".to_string(),
+            };
+            suggestions.push(Suggestion {
+                table: table.table.clone(),
+                reason,
+                statement: format!("VACUUM (ANALYZE) {}", table.table),
+            });
+        }
+        for index in &table.indexes {
+            if index.size_bytes >= MIN_SIZE_BYTES
+                && index.size_bytes as f64 > table.size_bytes as f64 * INDEX_TO_TABLE_SIZE_THRESHOLD
+            {
+                suggestions.push(Suggestion {
+                    table: table.table.clone(),
+                    reason: format!(
+                        "Index {} ({} bytes) is larger than its table ({} bytes)",
+                        index.index, index.size_bytes, table.size_bytes
+                    ),
+                    statement: format!("REINDEX INDEX CONCURRENTLY {}", index.index),
+                });
+            }
+        }
+    }
+    suggestions
+}
+
+pub async fn report(pgpool: &PgPool) -> Result<HealthReport, sqlx::Error> {
+    let tables = measure(pgpool).await?;
+    let suggestions = suggestions(&tables, Utc::now());
+    Ok(HealthReport {
+        tables,
+        suggestions,
+    })
+}
+
+/// Exports the health of the TAP tables as metrics, warning about the tables needing maintenance.
+pub struct MeasureTableHealth {
+    pgpool: PgPool,
+}
+
+impl MeasureTableHealth {
+    pub fn new(pgpool: PgPool) -> Self {
+        Self { pgpool }
+    }
+}
+
+#[async_trait::async_trait]
+impl Job for MeasureTableHealth {
+    fn name(&self) -> &'static str {
+        "table_health"
+    }
+
+    fn default_schedule(&self) -> &'static str {
+        "*/15 * * * *"
+    }
+
+    async fn run(&self) -> Result<()> {
+        let now = Utc::now();
+        let tables = measure(&self.pgpool).await?;
+        for table in &tables {
+            TABLE_SIZE
+                .with_label_values(&[&table.table])
+                .set(table.size_bytes);
+            TABLE_DEAD_TUPLE_RATIO
+                .with_label_values(&[&table.table])
+                .set(table.dead_tuple_ratio());
+            match table.last_vacuum {
+                Some(last_vacuum) => TABLE_LAST_VACUUM_AGE
+                    .with_label_values(&[&table.table])
+                    .set((now - last_vacuum).num_seconds()),
+                None => {
+                    let _ = TABLE_LAST_VACUUM_AGE.remove_label_values(&[&table.table]);
+                }
+            }
+            for index in &table.indexes {
+                INDEX_SIZE
+                    .with_label_values(&[&table.table, &index.index])
+                    .set(index.size_bytes);
+            }
+        }
+        let suggestions = suggestions(&tables, now);
+        for table in &tables {
+            let alert = suggestions
+                .iter()
+                .any(|suggestion| suggestion.table == table.table);
+            TABLE_HEALTH_ALERT
+                .with_label_values(&[&table.table])
+                .set(alert as i64);
+        }
+        for suggestion in suggestions {
+            warn!(
+                table = suggestion.table,
+                reason = suggestion.reason,
+                statement = suggestion.statement,
+                "TAP table needs maintenance"
+            );
+        }
+        Ok(())
+    }
+}
+
+struct TableHealthState {
+    pgpool: PgPool,
+}
+
+async fn handler_table_health(
+    State(state): State<Arc<TableHealthState>>,
+) -> Result<Json<HealthReport>, AdminError> {
+    report(&state.pgpool).await.map(Json).map_err(|e| {
+        error!("Error while measuring the table health: {}", e);
+        (
+            axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Error while measuring the table health: {}", e),
+        )
+    })
+}
+
+/// Admin route reporting the health of the TAP tables and the suggested maintenance, to be
+/// mounted on the tap-agent HTTP server.
+pub fn router(pgpool: PgPool) -> Router {
+    Router::new()
+        .route("/admin/table-health", get(handler_table_health))
+        .with_state(Arc::new(TableHealthState { pgpool }))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use super::*;
+
+    fn table(
+        live_tuples: i64,
+        dead_tuples: i64,
+        last_vacuum: Option<DateTime<Utc>>,
+    ) -> TableHealth {
+        TableHealth {
+            table: "scalar_tap_receipts".to_string(),
+            size_bytes: 1 << 30,
+            live_tuples,
+            dead_tuples,
+            last_vacuum,
+            indexes: vec![],
+        }
+    }
+
+    #[test]
+    fn test_suggestions() {
+        let now = Utc::now();
+
+        // Healthy
+        assert!(suggestions(&[table(100, 1, Some(now - Duration::hours(1)))], now).is_empty());
+        assert!(suggestions(&[table(100, 0, None)], now).is_empty());
+
+        // Dead tuples
+        let suggested = suggestions(&[table(70, 30, Some(now - Duration::hours(1)))], now);
+        assert_eq!(suggested.len(), 1);
+        assert_eq!(
+            suggested[0].statement,
+            "VACUUM (ANALYZE) scalar_tap_receipts"
+        );
+
+        // Not vacuumed recently
+        let suggested = suggestions(&[table(100, 1, Some(now - Duration::days(2)))], now);
+        assert_eq!(suggested.len(), 1);
+        assert_eq!(suggested[0].reason, "Not vacuumed for 48 hours");
+        assert_eq!(
+            suggestions(&[table(100, 1, None)], now)[0].reason,
+            "Never vacuumed"
+        );
+
+        // Bloated index
+        let mut bloated = table(100, 0, None);
+        bloated.indexes = vec![
+            IndexHealth {
+                table: bloated.table.clone(),
+                index: "scalar_tap_receipts_allocation_id_idx".to_string(),
+                size_bytes: 2 << 30,
+                scans: 10,
+            },
+            IndexHealth {
+                table: bloated.table.clone(),
+                index: "scalar_tap_receipts_timestamp_ns_idx".to_string(),
+                size_bytes: 1 << 20,
+                scans: 10,
+            },
+        ];
+        let suggested = suggestions(&[bloated], now);
+        assert_eq!(suggested.len(), 1);
+        assert_eq!(
+            suggested[0].statement,
+            "REINDEX INDEX CONCURRENTLY scalar_tap_receipts_allocation_id_idx"
+        );
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_measure(pgpool: PgPool) {
+        let tables = measure(&pgpool).await.unwrap();
+        let receipts = tables
+            .iter()
+            .find(|table| table.table == "scalar_tap_receipts")
+            .unwrap();
+        assert!(!receipts.indexes.is_empty());
+        assert!(tables
+            .iter()
+            .all(|table| table.table.starts_with("scalar_tap_")));
+
+        MeasureTableHealth::new(pgpool).run().await.unwrap();
+    }
+}
@@ -6,12 +6,12 @@ use std::time::Duration;
 use alloy_primitives::Address;
 use anyhow::anyhow;
 use eventuals::{Eventual, EventualExt};
+use indexer_common::monitor_backoff::Backoff;
 use indexer_common::subgraph_client::{Query, SubgraphClient};
 use tap_core::receipt::{
     checks::{Check, CheckResult},
     Checking, ReceiptWithState,
 };
-use tokio::time::sleep;
 use tracing::error;
 
 use crate::config;
@@ -73,23 +73,31 @@ fn tap_allocation_redeemed_eventual(
     escrow_subgraph: &'static SubgraphClient,
     escrow_subgraph_polling_interval_ms: u64,
 ) -> Eventual<bool> {
-    eventuals::timer(Duration::from_millis(escrow_subgraph_polling_interval_ms)).map_with_retry(
-        move |_| async move {
-            query_escrow_check_transactions(
-                allocation_id,
-                sender_address,
-                indexer_address,
-                escrow_subgraph,
-            )
-            .await
-            .map_err(|e| e.to_string())
+    let interval = Duration::from_millis(escrow_subgraph_polling_interval_ms);
+    let backoff = Backoff::new("allocation_redeemed", interval);
+    let retry_backoff = backoff.clone();
+    eventuals::timer(interval).map_with_retry(
+        move |_| {
+            let backoff = backoff.clone();
+            async move {
+                backoff.track(
+                    query_escrow_check_transactions(
+                        allocation_id,
+                        sender_address,
+                        indexer_address,
+                        escrow_subgraph,
+                    )
+                    .await
+                    .map_err(|e| e.to_string()),
+                )
+            }
         },
         move |error: String| {
             error!(
                 "Failed to check the escrow redeem status for allocation {} and sender {}: {}",
                 allocation_id, sender_address, error
             );
-            sleep(Duration::from_millis(escrow_subgraph_polling_interval_ms).div_f32(2.))
+            retry_backoff.failed(&error)
         },
     )
 }
@@ -7,6 +7,7 @@ use super::{error::AdapterError, TapAgentContext};
 use alloy_primitives::{hex::ToHex, Address};
 use bigdecimal::num_bigint::{BigInt, ToBigInt};
 use bigdecimal::ToPrimitive;
+use indexer_common::{db_metrics, schema_drift};
 use sqlx::types::{chrono, BigDecimal};
 use tap_core::{
     manager::adapters::{RAVRead, RAVStore},
@@ -18,16 +19,19 @@ impl RAVRead for TapAgentContext {
     type AdapterError = AdapterError;
 
     async fn last_rav(&self) -> Result<Option<SignedRAV>, Self::AdapterError> {
-        let row = sqlx::query!(
-            r#"
+        let row = db_metrics::timed(
+            "last_rav",
+            sqlx::query!(
+                r#"
                 SELECT signature, allocation_id, timestamp_ns, value_aggregate
                 FROM scalar_tap_ravs
                 WHERE allocation_id = $1 AND sender_address = $2
             "#,
-            self.allocation_id.encode_hex::<String>(),
-            self.sender.encode_hex::<String>()
+                self.allocation_id.encode_hex::<String>(),
+                self.sender.encode_hex::<String>()
+            )
+            .fetch_optional(&self.pgpool),
         )
-        .fetch_optional(&self.pgpool)
         .await
         .map_err(|e| AdapterError::RavRead {
             error: e.to_string(),
@@ -87,10 +91,15 @@ impl RAVStore for TapAgentContext {
     type AdapterError = AdapterError;
 
     async fn update_last_rav(&self, rav: SignedRAV) -> Result<(), Self::AdapterError> {
+        schema_drift::ensure_writable().map_err(|e| AdapterError::RavStore {
+            error: e.to_string(),
+        })?;
         let signature_bytes: Vec<u8> = rav.signature.to_vec();
 
-        let _fut = sqlx::query!(
-            r#"
+        let _fut = db_metrics::timed(
+            "update_last_rav",
+            sqlx::query!(
+                r#"
                 INSERT INTO scalar_tap_ravs (
                     sender_address,
                     signature,
@@ -109,14 +118,15 @@ impl RAVStore for TapAgentContext {
                     value_aggregate = $5,
                     updated_at = $6
             "#,
-            self.sender.encode_hex::<String>(),
-            signature_bytes,
-            self.allocation_id.encode_hex::<String>(),
-            BigDecimal::from(rav.message.timestampNs),
-            BigDecimal::from(BigInt::from(rav.message.valueAggregate)),
-            chrono::Utc::now()
+                self.sender.encode_hex::<String>(),
+                signature_bytes,
+                self.allocation_id.encode_hex::<String>(),
+                BigDecimal::from(rav.message.timestampNs),
+                BigDecimal::from(BigInt::from(rav.message.valueAggregate)),
+                chrono::Utc::now()
+            )
+            .execute(&self.pgpool),
         )
-        .execute(&self.pgpool)
         .await
         .map_err(|e| AdapterError::RavStore {
             error: e.to_string(),
@@ -9,6 +9,7 @@ use std::{
 
 use alloy_primitives::hex::ToHex;
 use bigdecimal::{num_bigint::ToBigInt, ToPrimitive};
+use indexer_common::{db_consistency, db_metrics, schema_drift};
 use sqlx::{postgres::types::PgRange, types::BigDecimal};
 use tap_core::{
     manager::adapters::{safe_truncate_receipts, ReceiptDelete, ReceiptRead},
@@ -88,9 +89,13 @@ impl ReceiptRead for TapAgentContext {
             })?;
 
         let receipts_limit = receipts_limit.map_or(1000, |limit| limit);
+        // Every receipt stored so far must be aggregated
+        let read_pool = db_consistency::read_pool(&self.pgpool, None).await;
 
-        let records = sqlx::query!(
-            r#"
+        let records = db_metrics::timed(
+            "retrieve_receipts",
+            sqlx::query!(
+                r#"
                 SELECT id, signature, allocation_id, timestamp_ns, nonce, value
                 FROM scalar_tap_receipts
                 WHERE allocation_id = $1 AND signer_address IN (SELECT unnest($2::text[]))
@@ -98,12 +103,13 @@ impl ReceiptRead for TapAgentContext {
                 ORDER BY timestamp_ns ASC
                 LIMIT $4
             "#,
-            self.allocation_id.encode_hex::<String>(),
-            &signers,
-            rangebounds_to_pgrange(timestamp_range_ns),
-            (receipts_limit + 1) as i64,
+                self.allocation_id.encode_hex::<String>(),
+                &signers,
+                rangebounds_to_pgrange(timestamp_range_ns),
+                (receipts_limit + 1) as i64,
+            )
+            .fetch_all(&read_pool),
         )
-        .fetch_all(&self.pgpool)
         .await?;
         let mut receipts = records
             .into_iter()
@@ -168,23 +174,29 @@ impl ReceiptDelete for TapAgentContext {
         &self,
         timestamp_ns: R,
     ) -> Result<(), Self::AdapterError> {
+        schema_drift::ensure_writable().map_err(|e| AdapterError::ReceiptDelete {
+            error: e.to_string(),
+        })?;
         let signers = signers_trimmed(&self.escrow_accounts, self.sender)
             .await
             .map_err(|e| AdapterError::ReceiptDelete {
                 error: format!("{:?}.", e),
             })?;
 
-        sqlx::query!(
-            r#"
+        db_metrics::timed(
+            "remove_receipts",
+            sqlx::query!(
+                r#"
                 DELETE FROM scalar_tap_receipts
                 WHERE allocation_id = $1 AND signer_address IN (SELECT unnest($2::text[]))
                     AND $3::numrange @> timestamp_ns
             "#,
-            self.allocation_id.encode_hex::<String>(),
-            &signers,
-            rangebounds_to_pgrange(timestamp_ns)
+                self.allocation_id.encode_hex::<String>(),
+                &signers,
+                rangebounds_to_pgrange(timestamp_ns)
+            )
+            .execute(&self.pgpool),
         )
-        .execute(&self.pgpool)
         .await?;
         Ok(())
     }
@@ -204,6 +216,7 @@ mod test {
     use ethers_signers::LocalWallet;
     use eventuals::Eventual;
     use indexer_common::escrow_accounts::EscrowAccounts;
+    use indexer_common::tap::{store_payment, QueuedReceipt};
     use lazy_static::lazy_static;
     use sqlx::PgPool;
     use std::collections::HashMap;
@@ -431,6 +444,68 @@ mod test {
         Ok(())
     }
 
+    /// The receipts paying for a query together are aggregated like any other, their link to
+    /// the request aside.
+    #[sqlx::test(migrations = "../migrations")]
+    async fn retrieve_receipts_paid_together(pgpool: PgPool) {
+        let escrow_accounts = Eventual::from_value(EscrowAccounts::new(
+            HashMap::from([(SENDER.1, 1000.into())]),
+            HashMap::from([(SENDER.1, vec![SIGNER.1])]),
+        ));
+        let storage_adapter = TapAgentContext::new(
+            pgpool.clone(),
+            *ALLOCATION_ID_0,
+            SENDER.1,
+            escrow_accounts.clone(),
+            EscrowAdapter::mock(),
+        );
+
+        let received_receipts = (0..3)
+            .map(|i| create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, i, i + 42, 10))
+            .collect::<Vec<_>>();
+        let queued_receipts = received_receipts
+            .iter()
+            .map(|receipt| {
+                let receipt = receipt.signed_receipt();
+                QueuedReceipt {
+                    signer: SIGNER.1,
+                    signature: receipt.signature.to_vec(),
+                    allocation_id: receipt.message.allocation_id,
+                    timestamp_ns: receipt.message.timestamp_ns,
+                    nonce: receipt.message.nonce,
+                    value: receipt.message.value,
+                    request_id: None,
+                }
+            })
+            .collect();
+        store_payment(&pgpool, None, "request-1", queued_receipts)
+            .await
+            .unwrap();
+
+        let retrieved_receipts = storage_adapter
+            .retrieve_receipts_in_timestamp_range(.., None)
+            .await
+            .unwrap();
+        assert_eq!(
+            retrieved_receipts
+                .iter()
+                .map(|receipt| receipt.signed_receipt().unique_hash())
+                .collect::<Vec<_>>(),
+            received_receipts
+                .iter()
+                .map(|receipt| receipt.signed_receipt().unique_hash())
+                .collect::<Vec<_>>()
+        );
+
+        let linked: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM scalar_tap_receipts WHERE request_id = 'request-1'",
+        )
+        .fetch_one(&pgpool)
+        .await
+        .unwrap();
+        assert_eq!(linked, 3);
+    }
+
     #[sqlx::test(migrations = "../migrations")]
     async fn retrieve_receipts_with_limit(pgpool: PgPool) {
         let escrow_accounts = Eventual::from_value(EscrowAccounts::new(
@@ -0,0 +1,136 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Signers of each sender, hex encoded as stored in the database, shared by the checks and the
+//! queries of all the allocations of the sender instead of being read again from the escrow
+//! accounts on every fee calculation.
+//!
+//! Entries are stamped with the version of the escrow accounts they were read from. When the
+//! accounts are updated, only the senders whose signers changed are encoded again, the entries
+//! of the others are stamped with the new version.
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use alloy_primitives::hex::ToHex;
+use anyhow::anyhow;
+use eventuals::Eventual;
+use indexer_common::escrow_accounts::EscrowAccounts;
+use lazy_static::lazy_static;
+use thegraph::types::Address;
+
+lazy_static! {
+    static ref SIGNERS: RwLock<HashMap<Address, Arc<SenderSigners>>> = RwLock::new(HashMap::new());
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SenderSigners {
+    /// Version of the escrow accounts the signers were read from.
+    pub version: u64,
+    pub signers: Vec<Address>,
+    /// The signers hex encoded, without the `0x` prefix.
+    pub trimmed: Vec<String>,
+}
+
+impl SenderSigners {
+    pub fn contains(&self, signer: &Address) -> bool {
+        self.signers.contains(signer)
+    }
+}
+
+/// Signers of `sender` in the current escrow accounts.
+pub async fn sender_signers(
+    escrow_accounts: &Eventual<EscrowAccounts>,
+    sender: Address,
+) -> Result<Arc<SenderSigners>, anyhow::Error> {
+    let escrow_accounts = escrow_accounts
+        .value()
+        .await
+        .map_err(|e| anyhow!("Error while getting escrow accounts: {:?}", e))?;
+    Ok(cached_signers(&escrow_accounts, sender))
+}
+
+/// Signers of `sender` in `escrow_accounts`, from the cache if they were read from the same
+/// version of the accounts.
+pub fn cached_signers(escrow_accounts: &EscrowAccounts, sender: Address) -> Arc<SenderSigners> {
+    let version = escrow_accounts.version();
+    let cached = SIGNERS.read().unwrap().get(&sender).cloned();
+    if let Some(cached) = &cached {
+        if cached.version == version {
+            return cached.clone();
+        }
+    }
+
+    let signers = escrow_accounts.get_signers_for_sender(&sender);
+    let trimmed = match cached {
+        Some(cached) if cached.signers == signers => cached.trimmed.clone(),
+        _ => signers
+            .iter()
+            .map(|signer| signer.encode_hex::<String>())
+            .collect(),
+    };
+    let entry = Arc::new(SenderSigners {
+        version,
+        signers,
+        trimmed,
+    });
+    SIGNERS.write().unwrap().insert(sender, entry.clone());
+    entry
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use ethereum_types::U256;
+
+    use super::*;
+
+    #[test]
+    fn test_cached_signers() {
+        let sender = Address::from([0x51; 20]);
+        let other_sender = Address::from([0x52; 20]);
+        let signer = Address::from([0x61; 20]);
+        let other_signer = Address::from([0x62; 20]);
+        let accounts = |signers: Vec<Address>| {
+            EscrowAccounts::new(
+                HashMap::from([(sender, U256::from(1)), (other_sender, U256::from(1))]),
+                HashMap::from([(sender, signers), (other_sender, vec![other_signer])]),
+            )
+        };
+
+        let escrow_accounts = accounts(vec![signer]);
+        let signers = cached_signers(&escrow_accounts, sender);
+        assert_eq!(signers.version, escrow_accounts.version());
+        assert_eq!(signers.trimmed, vec![signer.encode_hex::<String>()]);
+        assert!(signers.contains(&signer));
+        assert!(!signers.contains(&other_signer));
+        // Cached for the same version
+        assert!(Arc::ptr_eq(
+            &signers,
+            &cached_signers(&escrow_accounts, sender)
+        ));
+
+        // Stamped with the new version if the signers didn't change
+        let updated = accounts(vec![signer]);
+        let restamped = cached_signers(&updated, sender);
+        assert_eq!(restamped.version, updated.version());
+        assert_eq!(restamped.trimmed, signers.trimmed);
+
+        // Read again if they changed
+        let updated = accounts(vec![signer, other_signer]);
+        let changed = cached_signers(&updated, sender);
+        assert_eq!(changed.version, updated.version());
+        assert_eq!(
+            changed.trimmed,
+            vec![
+                signer.encode_hex::<String>(),
+                other_signer.encode_hex::<String>()
+            ]
+        );
+        assert_eq!(
+            cached_signers(&updated, other_sender).signers,
+            vec![other_signer]
+        );
+    }
+}
@@ -0,0 +1,563 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Allocations vanishing from the allocation source without being closed, e.g. after a reorg or
+//! a rollback of the network subgraph. Their sender allocations would otherwise be stopped as if
+//! the allocations were closed, marking their last RAVs, or linger forever when the source
+//! doesn't come back.
+//!
+//! An active allocation missing from the source is kept tracked for a grace period of a few
+//! allocation refreshes, in case the source comes back with it. Once vanished, its sender
+//! allocations request one last RAV, which is flagged for review in the database instead of
+//! being marked as last. The operator then marks it as last or dismisses it through the admin
+//! API, with the reason recorded in the operator audit log. An allocation showing up again is
+//! tracked as any other.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    middleware,
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use chrono::{DateTime, Utc};
+use indexer_common::allocations::{Allocation, AllocationStatus};
+use indexer_common::types::{AllocationIdHex, GrtWei, SenderAddress};
+use lazy_static::lazy_static;
+use prometheus::{
+    register_int_counter, register_int_counter_vec, register_int_gauge, IntCounter, IntCounterVec,
+    IntGauge,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{PgPool, Row};
+use thegraph::types::Address;
+use tracing::{error, info, warn};
+
+use crate::admin_signature::{require_signature, AdminSignature, AdminSigners};
+use crate::escrow_overrides::{record_action, AdminError};
+
+lazy_static! {
+    static ref VANISHED_ALLOCATIONS: IntCounter = register_int_counter!(
+        format!("tap_vanished_allocations_total"),
+        "Allocations that vanished from the allocation source without being closed"
+    )
+    .unwrap();
+    static ref MISSING_ALLOCATIONS: IntGauge = register_int_gauge!(
+        format!("tap_missing_allocations"),
+        "Allocations missing from the allocation source, still tracked for the grace period"
+    )
+    .unwrap();
+    static ref VANISHED_SENDER_ALLOCATIONS: IntCounterVec = register_int_counter_vec!(
+        format!("tap_vanished_sender_allocations_closed_total"),
+        "Sender allocations of vanished allocations closed and flagged for review, by outcome \
+        of their last RAV request",
+        &["sender", "allocation", "outcome"]
+    )
+    .unwrap();
+    static ref STATUS: RwLock<VanishedAllocationsStatus> = RwLock::new(Default::default());
+}
+
+/// Allocations missing from the allocation source, served at `/state/vanished-allocations`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VanishedAllocationsStatus {
+    /// Still tracked, by when they went missing, in seconds since the UNIX epoch.
+    pub missing: BTreeMap<Address, u64>,
+    /// Past the grace period, their sender allocations closed.
+    pub vanished: HashSet<Address>,
+}
+
+/// Whether the allocation vanished from the allocation source, rather than being closed.
+pub fn is_vanished(allocation_id: &Address) -> bool {
+    STATUS.read().unwrap().vanished.contains(allocation_id)
+}
+
+pub fn status() -> VanishedAllocationsStatus {
+    STATUS.read().unwrap().clone()
+}
+
+/// Tracks the allocations of the allocation source, to tell the ones that vanished from the
+/// ones that were closed.
+pub struct VanishedAllocationTracker {
+    grace_period: Duration,
+    /// Allocations last seen active, including the missing ones.
+    active: HashSet<Address>,
+    missing_since: HashMap<Address, u64>,
+}
+
+impl VanishedAllocationTracker {
+    pub fn new(grace_period: Duration) -> Self {
+        Self {
+            grace_period,
+            active: HashSet::new(),
+            missing_since: HashMap::new(),
+        }
+    }
+
+    /// Updates the tracker with the `allocations` of the source at `now`, in seconds since the
+    /// UNIX epoch. Returns the missing allocations to keep tracking for the grace period.
+    pub fn update(
+        &mut self,
+        allocations: &HashMap<Address, Allocation>,
+        now: u64,
+    ) -> HashSet<Address> {
+        let mut status = STATUS.write().unwrap();
+        for (allocation_id, allocation) in allocations {
+            let was_missing = self.missing_since.remove(allocation_id).is_some();
+            if status.vanished.remove(allocation_id) || was_missing {
+                info!(%allocation_id, "Missing allocation showed up again");
+            }
+            // Closed allocations leave the source after the recently closed allocation buffer
+            if allocation.status == AllocationStatus::Active {
+                self.active.insert(*allocation_id);
+            } else {
+                self.active.remove(allocation_id);
+            }
+        }
+
+        let grace_period = self.grace_period.as_secs();
+        let mut missing = HashSet::new();
+        self.active.retain(|allocation_id| {
+            if allocations.contains_key(allocation_id) {
+                return true;
+            }
+            let missing_since = *self.missing_since.entry(*allocation_id).or_insert_with(|| {
+                warn!(%allocation_id, "Active allocation missing from the allocation source");
+                now
+            });
+            if now < missing_since + grace_period {
+                missing.insert(*allocation_id);
+                return true;
+            }
+            warn!(
+                %allocation_id,
+                missing_since,
+                "Allocation vanished from the allocation source without being closed, closing \
+                its sender allocations and flagging their last RAVs for review"
+            );
+            VANISHED_ALLOCATIONS.inc();
+            self.missing_since.remove(allocation_id);
+            status.vanished.insert(*allocation_id);
+            false
+        });
+
+        MISSING_ALLOCATIONS.set(missing.len() as i64);
+        status.missing = self
+            .missing_since
+            .iter()
+            .map(|(allocation_id, missing_since)| (*allocation_id, *missing_since))
+            .collect();
+        missing
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FlaggedAllocation {
+    pub sender: Address,
+    pub allocation_id: Address,
+    /// Value of the last RAV, in GRT wei, if any.
+    pub rav_value: Option<GrtWei>,
+    /// Fees left unaggregated by the last RAV request, in GRT wei.
+    pub unaggregated_fees: GrtWei,
+    /// Error of the last RAV request, if it failed.
+    pub error: Option<String>,
+    pub flagged_at: DateTime<Utc>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReviewDecision {
+    /// Marks the RAVs of the sender allocation as last, to be redeemed.
+    MarkLast,
+    /// Leaves the RAVs unmarked, e.g. when the allocation is expected to show up again.
+    Dismiss,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReviewVanishedAllocation {
+    pub decision: ReviewDecision,
+    pub reason: String,
+}
+
+/// Flags the last RAV of a sender allocation closed because its allocation vanished, replacing
+/// a reviewed flag.
+pub async fn flag(
+    pgpool: &PgPool,
+    sender: Address,
+    allocation_id: Address,
+    rav_value: Option<u128>,
+    unaggregated_fees: u128,
+    rav_request_error: Option<&str>,
+) -> Result<()> {
+    let outcome = match (rav_request_error, unaggregated_fees) {
+        (Some(_), _) => "failed",
+        (None, 0) => "aggregated",
+        (None, _) => "partial",
+    };
+    VANISHED_SENDER_ALLOCATIONS
+        .with_label_values(&[&sender.to_string(), &allocation_id.to_string(), outcome])
+        .inc();
+    sqlx::query(
+        r#"
+            INSERT INTO scalar_tap_vanished_allocations
+                (sender_address, allocation_id, rav_value, unaggregated_fees, error)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (sender_address, allocation_id) DO UPDATE SET
+                rav_value = EXCLUDED.rav_value,
+                unaggregated_fees = EXCLUDED.unaggregated_fees,
+                error = EXCLUDED.error,
+                flagged_at = NOW(),
+                reviewed_at = NULL
+        "#,
+    )
+    .bind(SenderAddress(sender))
+    .bind(AllocationIdHex(allocation_id))
+    .bind(rav_value.map(GrtWei))
+    .bind(GrtWei(unaggregated_fees))
+    .bind(rav_request_error)
+    .execute(pgpool)
+    .await?;
+    Ok(())
+}
+
+/// The flagged sender allocations not reviewed yet.
+pub async fn flagged_allocations(pgpool: &PgPool) -> Result<Vec<FlaggedAllocation>> {
+    let rows = sqlx::query(
+        r#"
+            SELECT sender_address, allocation_id, rav_value, unaggregated_fees, error, flagged_at
+            FROM scalar_tap_vanished_allocations
+            WHERE reviewed_at IS NULL
+            ORDER BY flagged_at
+        "#,
+    )
+    .fetch_all(pgpool)
+    .await?;
+    rows.iter()
+        .map(|row| {
+            Ok(FlaggedAllocation {
+                sender: row.try_get::<SenderAddress, _>("sender_address")?.0,
+                allocation_id: row.try_get::<AllocationIdHex, _>("allocation_id")?.0,
+                rav_value: row.try_get("rav_value")?,
+                unaggregated_fees: row.try_get("unaggregated_fees")?,
+                error: row.try_get("error")?,
+                flagged_at: row.try_get("flagged_at")?,
+            })
+        })
+        .collect()
+}
+
+/// Returns whether there was a flagged sender allocation to review.
+pub async fn review(
+    pgpool: &PgPool,
+    sender: Address,
+    allocation_id: Address,
+    decision: ReviewDecision,
+    reason: &str,
+    signature: Option<&AdminSignature>,
+) -> Result<bool> {
+    if reason.trim().is_empty() {
+        bail!("A reason is required");
+    }
+
+    let mut tx = pgpool.begin().await?;
+    let reviewed = sqlx::query(
+        r#"
+            UPDATE scalar_tap_vanished_allocations
+            SET reviewed_at = NOW()
+            WHERE sender_address = $1 AND allocation_id = $2 AND reviewed_at IS NULL
+        "#,
+    )
+    .bind(SenderAddress(sender))
+    .bind(AllocationIdHex(allocation_id))
+    .execute(&mut *tx)
+    .await?
+    .rows_affected()
+        > 0;
+    if !reviewed {
+        return Ok(false);
+    }
+
+    let action = match decision {
+        ReviewDecision::MarkLast => {
+            sqlx::query(
+                r#"
+                    UPDATE scalar_tap_ravs
+                    SET last = true
+                    WHERE sender_address = $1 AND allocation_id = $2
+                "#,
+            )
+            .bind(SenderAddress(sender))
+            .bind(AllocationIdHex(allocation_id))
+            .execute(&mut *tx)
+            .await?;
+            "mark_vanished_allocation_last"
+        }
+        ReviewDecision::Dismiss => "dismiss_vanished_allocation",
+    };
+    record_action(
+        &mut tx,
+        action,
+        allocation_id,
+        reason,
+        json!({ "sender": sender }),
+        signature,
+    )
+    .await?;
+    tx.commit().await?;
+
+    info!(%sender, %allocation_id, ?decision, reason, "Vanished allocation reviewed by the operator");
+    Ok(true)
+}
+
+struct AdminState {
+    pgpool: PgPool,
+}
+
+fn internal_error(e: anyhow::Error) -> AdminError {
+    error!("Error while handling an admin request: {}", e);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Error while handling an admin request: {}", e),
+    )
+}
+
+async fn handler_flagged_allocations(
+    State(state): State<Arc<AdminState>>,
+) -> Result<Json<Vec<FlaggedAllocation>>, AdminError> {
+    flagged_allocations(&state.pgpool)
+        .await
+        .map(Json)
+        .map_err(internal_error)
+}
+
+async fn handler_review(
+    State(state): State<Arc<AdminState>>,
+    Path((sender, allocation_id)): Path<(Address, Address)>,
+    signature: Option<Extension<AdminSignature>>,
+    Json(request): Json<ReviewVanishedAllocation>,
+) -> Result<StatusCode, AdminError> {
+    if request.reason.trim().is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "A reason is required".to_string()));
+    }
+    let signature = signature.map(|Extension(signature)| signature);
+    match review(
+        &state.pgpool,
+        sender,
+        allocation_id,
+        request.decision,
+        &request.reason,
+        signature.as_ref(),
+    )
+    .await
+    {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err((
+            StatusCode::NOT_FOUND,
+            "No vanished allocation to review for this sender".into(),
+        )),
+        Err(e) => Err(internal_error(e)),
+    }
+}
+
+/// Admin routes listing and reviewing the sender allocations of the vanished allocations, to be
+/// mounted on the tap-agent HTTP server.
+pub fn router(pgpool: PgPool, admin_signers: Arc<AdminSigners>) -> Router {
+    Router::new()
+        .route(
+            "/admin/vanished-allocations/:sender/:allocation/review",
+            post(handler_review),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            admin_signers,
+            require_signature,
+        ))
+        .route(
+            "/admin/vanished-allocations",
+            get(handler_flagged_allocations),
+        )
+        .with_state(Arc::new(AdminState { pgpool }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use indexer_common::allocations::SubgraphDeployment;
+    use thegraph::types::DeploymentId;
+
+    use super::*;
+    use crate::tap::test_utils::{
+        create_rav, store_rav, ALLOCATION_ID_0, ALLOCATION_ID_1, SENDER, SIGNER,
+    };
+
+    fn allocation(id: Address, status: AllocationStatus) -> (Address, Allocation) {
+        (
+            id,
+            Allocation {
+                id,
+                status,
+                subgraph_deployment: SubgraphDeployment {
+                    id: DeploymentId::from_str("QmAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA")
+                        .unwrap(),
+                    denied_at: None,
+                    network: None,
+                },
+                indexer: Address::ZERO,
+                allocated_tokens: Default::default(),
+                created_at_epoch: 1,
+                created_at_block_hash: String::new(),
+                created_at: None,
+                closed_at_epoch: None,
+                closed_at: None,
+                closed_at_epoch_start_block_hash: None,
+                previous_epoch_start_block_hash: None,
+                poi: None,
+                query_fee_rebates: None,
+                query_fees_collected: None,
+            },
+        )
+    }
+
+    #[test]
+    fn test_tracker() {
+        // Allocations the other tests don't use, the status being global
+        let vanishing = Address::from([0x51; 20]);
+        let closing = Address::from([0x52; 20]);
+        let mut tracker = VanishedAllocationTracker::new(Duration::from_secs(60));
+
+        let both_active = HashMap::from([
+            allocation(vanishing, AllocationStatus::Active),
+            allocation(closing, AllocationStatus::Active),
+        ]);
+        assert!(tracker.update(&both_active, 1_000).is_empty());
+
+        // A closed allocation leaving the source isn't missing
+        let closed = HashMap::from([allocation(closing, AllocationStatus::Closed)]);
+        assert_eq!(tracker.update(&closed, 1_010), HashSet::from([vanishing]));
+        assert_eq!(status().missing.get(&vanishing), Some(&1_010));
+        assert_eq!(
+            tracker.update(&HashMap::new(), 1_020),
+            HashSet::from([vanishing])
+        );
+        assert!(!is_vanished(&vanishing));
+
+        // Vanished once missing for the grace period
+        assert!(tracker.update(&HashMap::new(), 1_070).is_empty());
+        assert!(is_vanished(&vanishing));
+        assert!(!is_vanished(&closing));
+        assert!(!status().missing.contains_key(&vanishing));
+        assert!(tracker.update(&HashMap::new(), 1_200).is_empty());
+
+        // Showing up again
+        assert!(tracker.update(&both_active, 1_300).is_empty());
+        assert!(!is_vanished(&vanishing));
+        assert_eq!(
+            tracker.update(&HashMap::new(), 1_310),
+            HashSet::from([vanishing, closing])
+        );
+        assert!(tracker.update(&both_active, 1_320).is_empty());
+        assert!(status().missing.is_empty());
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_flag_and_review(pgpool: PgPool) {
+        store_rav(
+            &pgpool,
+            create_rav(*ALLOCATION_ID_0, SIGNER.0.clone(), 4, 10),
+            SENDER.1,
+        )
+        .await
+        .unwrap();
+        flag(&pgpool, SENDER.1, *ALLOCATION_ID_0, Some(10), 0, None)
+            .await
+            .unwrap();
+        flag(
+            &pgpool,
+            SENDER.1,
+            *ALLOCATION_ID_1,
+            None,
+            5,
+            Some("aggregator down"),
+        )
+        .await
+        .unwrap();
+        let flagged = flagged_allocations(&pgpool).await.unwrap();
+        assert_eq!(flagged.len(), 2);
+        assert_eq!(flagged[0].rav_value, Some(GrtWei(10)));
+        assert_eq!(flagged[1].unaggregated_fees, GrtWei(5));
+        assert_eq!(flagged[1].error.as_deref(), Some("aggregator down"));
+
+        // Not marked as last until reviewed
+        let last = || async {
+            sqlx::query_scalar::<_, bool>("SELECT last FROM scalar_tap_ravs")
+                .fetch_one(&pgpool)
+                .await
+                .unwrap()
+        };
+        assert!(!last().await);
+
+        // A reason is required
+        assert!(review(
+            &pgpool,
+            SENDER.1,
+            *ALLOCATION_ID_0,
+            ReviewDecision::MarkLast,
+            " ",
+            None
+        )
+        .await
+        .is_err());
+        assert!(review(
+            &pgpool,
+            SENDER.1,
+            *ALLOCATION_ID_0,
+            ReviewDecision::MarkLast,
+            "reorg confirmed the close",
+            None
+        )
+        .await
+        .unwrap());
+        assert!(last().await);
+        assert!(!review(
+            &pgpool,
+            SENDER.1,
+            *ALLOCATION_ID_0,
+            ReviewDecision::Dismiss,
+            "again",
+            None
+        )
+        .await
+        .unwrap());
+        assert!(review(
+            &pgpool,
+            SENDER.1,
+            *ALLOCATION_ID_1,
+            ReviewDecision::Dismiss,
+            "expected back",
+            None
+        )
+        .await
+        .unwrap());
+        assert!(flagged_allocations(&pgpool).await.unwrap().is_empty());
+
+        let audited: Vec<String> =
+            sqlx::query_scalar("SELECT action FROM operator_audit_log ORDER BY action")
+                .fetch_all(&pgpool)
+                .await
+                .unwrap();
+        assert_eq!(
+            audited,
+            vec![
+                "dismiss_vanished_allocation",
+                "mark_vanished_allocation_last"
+            ]
+        );
+    }
+}
@@ -0,0 +1,399 @@
+// Copyright 2023-, GraphOps and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Admin API registering the [virtual allocations](indexer_common::allocations::virtual_allocations)
+//! of the data services without on-chain allocations yet. A registration must carry the
+//! signature of the allocation by the operator key, e.g. made by the `register-virtual-allocation`
+//! command, and is recorded in the operator audit log like the other admin actions.
+//!
+//! The receipts of a virtual allocation are aggregated like the others, and its last RAV is
+//! requested once it's revoked. Its RAVs can't be redeemed until the protocol supports these
+//! services.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    middleware,
+    routing::{get, put},
+    Extension, Json, Router,
+};
+use chrono::{DateTime, Utc};
+use indexer_common::allocations::virtual_allocations::VirtualAllocation;
+use indexer_common::types::AllocationIdHex;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{PgPool, Row};
+use thegraph::types::Address;
+use tracing::{error, info};
+
+use crate::admin_signature::{require_signature, AdminSignature, AdminSigners};
+use crate::config::VirtualAllocations;
+use crate::escrow_overrides::{record_action, AdminError};
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RegisterVirtualAllocation {
+    /// URL namespace of the data service, e.g. `sql`.
+    pub service: String,
+    /// Signature of the allocation by the operator key.
+    pub signature: String,
+    pub reason: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RevokeVirtualAllocation {
+    pub reason: String,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VirtualAllocationEntry {
+    pub allocation_id: Address,
+    pub service: String,
+    pub signature: String,
+    pub created_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+impl RegisterVirtualAllocation {
+    fn validate(&self, allocation_id: Address, config: &VirtualAllocations) -> Result<()> {
+        if self.reason.trim().is_empty() {
+            return Err(anyhow!("A reason is required"));
+        }
+        if !config.services.contains(&self.service) {
+            return Err(anyhow!(
+                "Virtual allocations aren't enabled for the `{}` service",
+                self.service
+            ));
+        }
+        VirtualAllocation {
+            id: allocation_id,
+            service: self.service.clone(),
+            signature: self.signature.clone(),
+        }
+        .verify(config.operator)
+    }
+}
+
+/// Registers a virtual allocation, or replaces a revoked one.
+pub async fn register_virtual_allocation(
+    pgpool: &PgPool,
+    config: &VirtualAllocations,
+    allocation_id: Address,
+    request: &RegisterVirtualAllocation,
+    signature: Option<&AdminSignature>,
+) -> Result<()> {
+    request.validate(allocation_id, config)?;
+
+    let mut tx = pgpool.begin().await?;
+    sqlx::query(
+        r#"
+            INSERT INTO virtual_allocations (allocation_id, service, signature)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (allocation_id) DO UPDATE SET
+                service = EXCLUDED.service,
+                signature = EXCLUDED.signature,
+                created_at = NOW(),
+                revoked_at = NULL
+        "#,
+    )
+    .bind(AllocationIdHex(allocation_id))
+    .bind(&request.service)
+    .bind(&request.signature)
+    .execute(&mut *tx)
+    .await?;
+    record_action(
+        &mut tx,
+        "register_virtual_allocation",
+        allocation_id,
+        &request.reason,
+        json!({
+            "service": request.service,
+            "signature": request.signature,
+        }),
+        signature,
+    )
+    .await?;
+    tx.commit().await?;
+
+    info!(
+        %allocation_id,
+        service = %request.service,
+        reason = %request.reason,
+        "Virtual allocation registered by the operator"
+    );
+    Ok(())
+}
+
+/// Returns whether there was an active virtual allocation to revoke.
+pub async fn revoke_virtual_allocation(
+    pgpool: &PgPool,
+    allocation_id: Address,
+    reason: &str,
+    signature: Option<&AdminSignature>,
+) -> Result<bool> {
+    if reason.trim().is_empty() {
+        return Err(anyhow!("A reason is required"));
+    }
+
+    let mut tx = pgpool.begin().await?;
+    let revoked = sqlx::query(
+        r#"
+            UPDATE virtual_allocations
+            SET revoked_at = NOW()
+            WHERE allocation_id = $1 AND revoked_at IS NULL
+        "#,
+    )
+    .bind(AllocationIdHex(allocation_id))
+    .execute(&mut *tx)
+    .await?
+    .rows_affected()
+        > 0;
+    if revoked {
+        record_action(
+            &mut tx,
+            "revoke_virtual_allocation",
+            allocation_id,
+            reason,
+            json!({}),
+            signature,
+        )
+        .await?;
+    }
+    tx.commit().await?;
+
+    if revoked {
+        info!(%allocation_id, reason, "Virtual allocation revoked by the operator");
+    }
+    Ok(revoked)
+}
+
+/// Every virtual allocation, including the revoked ones.
+pub async fn list_virtual_allocations(pgpool: &PgPool) -> Result<Vec<VirtualAllocationEntry>> {
+    let rows = sqlx::query(
+        r#"
+            SELECT allocation_id, service, signature, created_at, revoked_at
+            FROM virtual_allocations
+            ORDER BY created_at DESC
+        "#,
+    )
+    .fetch_all(pgpool)
+    .await?;
+
+    rows.iter()
+        .map(|row| {
+            Ok(VirtualAllocationEntry {
+                allocation_id: row.try_get::<AllocationIdHex, _>("allocation_id")?.0,
+                service: row.try_get("service")?,
+                signature: row.try_get("signature")?,
+                created_at: row.try_get("created_at")?,
+                revoked_at: row.try_get("revoked_at")?,
+            })
+        })
+        .collect()
+}
+
+struct AdminState {
+    pgpool: PgPool,
+    config: VirtualAllocations,
+}
+
+fn internal_error(e: anyhow::Error) -> AdminError {
+    error!("Error while handling a virtual allocation request: {}", e);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Error while handling a virtual allocation request: {}", e),
+    )
+}
+
+async fn handler_virtual_allocations(
+    State(state): State<Arc<AdminState>>,
+) -> Result<Json<Vec<VirtualAllocationEntry>>, AdminError> {
+    list_virtual_allocations(&state.pgpool)
+        .await
+        .map(Json)
+        .map_err(internal_error)
+}
+
+async fn handler_register_virtual_allocation(
+    State(state): State<Arc<AdminState>>,
+    Path(allocation_id): Path<Address>,
+    signature: Option<Extension<AdminSignature>>,
+    Json(request): Json<RegisterVirtualAllocation>,
+) -> Result<StatusCode, AdminError> {
+    request
+        .validate(allocation_id, &state.config)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    let signature = signature.map(|Extension(signature)| signature);
+    register_virtual_allocation(
+        &state.pgpool,
+        &state.config,
+        allocation_id,
+        &request,
+        signature.as_ref(),
+    )
+    .await
+    .map_err(internal_error)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn handler_revoke_virtual_allocation(
+    State(state): State<Arc<AdminState>>,
+    Path(allocation_id): Path<Address>,
+    Query(request): Query<RevokeVirtualAllocation>,
+    signature: Option<Extension<AdminSignature>>,
+) -> Result<StatusCode, AdminError> {
+    if request.reason.trim().is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "A reason is required".to_string()));
+    }
+    let signature = signature.map(|Extension(signature)| signature);
+    match revoke_virtual_allocation(
+        &state.pgpool,
+        allocation_id,
+        &request.reason,
+        signature.as_ref(),
+    )
+    .await
+    {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err((
+            StatusCode::NOT_FOUND,
+            "No active virtual allocation with this ID".into(),
+        )),
+        Err(e) => Err(internal_error(e)),
+    }
+}
+
+/// Admin routes of the virtual allocations, to be mounted on the tap-agent HTTP server when
+/// they are enabled for any service.
+pub fn router(
+    pgpool: PgPool,
+    admin_signers: Arc<AdminSigners>,
+    config: VirtualAllocations,
+) -> Router {
+    Router::new()
+        .route(
+            "/admin/virtual-allocations/:allocation_id",
+            put(handler_register_virtual_allocation).delete(handler_revoke_virtual_allocation),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            admin_signers,
+            require_signature,
+        ))
+        .route(
+            "/admin/virtual-allocations",
+            get(handler_virtual_allocations),
+        )
+        .with_state(Arc::new(AdminState { pgpool, config }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use indexer_common::address::wallet_address;
+    use indexer_common::allocations::virtual_allocations::{sign, virtual_allocations};
+    use indexer_common::test_vectors::INDEXER_OPERATOR_MNEMONIC;
+
+    use super::*;
+    use crate::escrow_overrides::audit_log;
+    use crate::tap::test_utils::ALLOCATION_ID_0;
+
+    fn config() -> VirtualAllocations {
+        VirtualAllocations {
+            operator: wallet_address(&INDEXER_OPERATOR_MNEMONIC).unwrap(),
+            services: HashSet::from(["sql".to_string()]),
+        }
+    }
+
+    fn request(service: &str, signed_service: &str) -> RegisterVirtualAllocation {
+        RegisterVirtualAllocation {
+            service: service.to_string(),
+            signature: sign(&INDEXER_OPERATOR_MNEMONIC, *ALLOCATION_ID_0, signed_service).unwrap(),
+            reason: "sql service launch".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        let config = config();
+        assert!(request("sql", "sql")
+            .validate(*ALLOCATION_ID_0, &config)
+            .is_ok());
+        // Not enabled
+        assert!(request("files", "files")
+            .validate(*ALLOCATION_ID_0, &config)
+            .is_err());
+        // Signed for another service, or another allocation
+        assert!(request("sql", "files")
+            .validate(*ALLOCATION_ID_0, &config)
+            .is_err());
+        assert!(request("sql", "sql")
+            .validate(Address::ZERO, &config)
+            .is_err());
+        assert!(RegisterVirtualAllocation {
+            reason: " ".to_string(),
+            ..request("sql", "sql")
+        }
+        .validate(*ALLOCATION_ID_0, &config)
+        .is_err());
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_register_and_revoke_virtual_allocation(pgpool: PgPool) {
+        let config = config();
+        let loaded = || async {
+            virtual_allocations(
+                pgpool.clone(),
+                config.operator,
+                config.services.clone(),
+                Duration::from_secs(60),
+            )
+            .value()
+            .await
+            .unwrap()
+        };
+
+        register_virtual_allocation(
+            &pgpool,
+            &config,
+            *ALLOCATION_ID_0,
+            &request("sql", "sql"),
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            loaded().await.keys().collect::<Vec<_>>(),
+            vec![&*ALLOCATION_ID_0]
+        );
+
+        assert!(
+            revoke_virtual_allocation(&pgpool, *ALLOCATION_ID_0, "sql service sunset", None)
+                .await
+                .unwrap()
+        );
+        assert!(
+            !revoke_virtual_allocation(&pgpool, *ALLOCATION_ID_0, "again", None)
+                .await
+                .unwrap()
+        );
+        assert_eq!(loaded().await, HashMap::new());
+        let entries = list_virtual_allocations(&pgpool).await.unwrap();
+        assert_eq!(entries.len(), 1);
+        assert!(entries[0].revoked_at.is_some());
+
+        let log = audit_log(&pgpool).await.unwrap();
+        assert_eq!(
+            log.iter()
+                .map(|entry| entry.action.as_str())
+                .collect::<Vec<_>>(),
+            vec!["revoke_virtual_allocation", "register_virtual_allocation"]
+        );
+    }
+}